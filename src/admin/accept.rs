@@ -20,22 +20,45 @@ use serde_json::{
     Value::Null,
 };
 
+use tracing::{
+    info,
+    warn,
+};
+
 use std::{
     convert::Infallible,
-    sync::{
-        Arc,
-        RwLock,
-    },
+    sync::Arc,
     time::Instant,
 };
 
 use sled::Db;
 
 use crate::{
-    admin::methods::execute_method,
-    balancer::format::incoming_to_value,
-    Rpc,
-    Settings,
+    admin::{
+        chains::{
+            AdminChain,
+            AdminChains,
+        },
+        methods::execute_method,
+        metrics::{
+            Metrics,
+            METRICS_CONTENT_TYPE,
+        },
+    },
+    balancer::{
+        admission::AdmissionControl,
+        auth::{
+            is_authorized,
+            unauthorized_response,
+        },
+        body_limit::{
+            bad_body_response,
+            exceeds_content_length,
+            payload_too_large_response,
+        },
+        format::incoming_to_value,
+        memory_cache::MemoryCache,
+    },
 };
 
 // use tokio::net::TcpListener;
@@ -63,18 +86,23 @@ macro_rules! get_response {
     (
         $tx:expr,
         $id:expr,
-        $rpc_list_rwlock:expr,
-        $poverty_list_rwlock:expr,
-        $config:expr,
+        $chain:expr,
         $cache:expr,
+        $memory_caches:expr,
+        $metrics:expr,
     ) => {{
         // Execute the request and store it into rx
         let mut rx = match execute_method(
             $tx,
-            $rpc_list_rwlock,
-            $poverty_list_rwlock,
-            Arc::clone(&$config),
+            &$chain.rpc_list,
+            &$chain.poverty_list,
+            Arc::clone(&$chain.config),
             Arc::clone(&$cache),
+            &$chain.incoming_tx,
+            $memory_caches,
+            $metrics,
+            &$chain.health_trigger,
+            &$chain.cache_args,
         ).await {
             Ok(rx) => rx,
             Err(err) => json!({
@@ -96,10 +124,10 @@ macro_rules! get_response {
 // Execute request and construct a HTTP response
 async fn forward_body(
     mut tx: Value,
-    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
-    poverty_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    chain: &AdminChain,
     cache: Arc<Db>,
-    config: Arc<RwLock<Settings>>,
+    memory_caches: &[Arc<MemoryCache>],
+    metrics: &Arc<Metrics>,
 ) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
     // Get the id of the request and set it to 0 for caching
     //
@@ -109,7 +137,7 @@ async fn forward_body(
     let id = tx["id"].take().as_u64().unwrap_or(0);
 
     // Get the response from either the DB or from a RPC. If it timeouts, retry.
-    let rax = get_response!(tx, id, rpc_list_rwlock, poverty_list_rwlock, config, cache,);
+    let rax = get_response!(tx, id, chain, cache, memory_caches, metrics,);
 
     // Convert rx to bytes and but it in a Buf
     let body = hyper::body::Bytes::from(rax);
@@ -123,15 +151,88 @@ async fn forward_body(
     Ok(res)
 }
 
+fn probe_response(ok: bool) -> hyper::Response<Full<Bytes>> {
+    let (status, body) = match ok {
+        true => (200, "OK"),
+        false => (503, "Unavailable"),
+    };
+    hyper::Response::builder()
+        .status(status)
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
 // Accept admin request, self explanatory
+//
+// Admin settings come from the config of the default chain.
 pub async fn accept_admin_request(
     tx: Request<hyper::body::Incoming>,
-    rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
-    poverty_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    chains: Arc<AdminChains>,
     cache: Arc<Db>,
-    config: Arc<RwLock<Settings>>,
+    metrics: Arc<Metrics>,
+    admission: Arc<AdmissionControl>,
 ) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
-    let mut tx = incoming_to_value(tx).await.unwrap();
+    let config = Arc::clone(&chains.default.config);
+
+    // Liveness and readiness probes for orchestrators. Unauthenticated, and kept
+    // cheap so they can be polled often. We're live as long as we can answer, and
+    // ready by the same rules as `/ready` on the main port, for every chain.
+    if tx.method() == hyper::Method::GET {
+        match tx.uri().path() {
+            "/live" => return Ok(probe_response(true)),
+            "/ready" => return Ok(probe_response(chains.is_ready())),
+            _ => {}
+        }
+    }
+
+    // Reject requests without the right bearer token before anything else
+    let authorized = match &config.read().unwrap().admin.auth_token {
+        Some(auth_token) => {
+            is_authorized(tx.headers().get(hyper::header::AUTHORIZATION), auth_token)
+        }
+        None => true,
+    };
+    if !authorized {
+        return Ok(unauthorized_response());
+    }
+
+    // Prometheus scrapes
+    if tx.method() == hyper::Method::GET && tx.uri().path() == "/metrics" {
+        if !config.read().unwrap().admin.metrics_enabled {
+            return Ok(hyper::Response::builder()
+                .status(404)
+                .body(Full::new(Bytes::from("Metrics are disabled")))
+                .unwrap());
+        }
+
+        let body = metrics.render(&chains, &admission);
+        return Ok(hyper::Response::builder()
+            .status(200)
+            .header(hyper::header::CONTENT_TYPE, METRICS_CONTENT_TYPE)
+            .body(Full::new(Bytes::from(body)))
+            .unwrap());
+    }
+
+    // Manage the chain the path is for
+    let chain = match chains.route(tx.uri().path()) {
+        Some(chain) => chain,
+        None => {
+            return Ok(hyper::Response::builder()
+                .status(404)
+                .body(Full::new(Bytes::from("Unknown chain")))
+                .unwrap())
+        }
+    };
+
+    let max_request_bytes = config.read().unwrap().max_request_bytes;
+    if exceeds_content_length(tx.headers(), max_request_bytes) {
+        return Ok(payload_too_large_response());
+    }
+
+    let mut tx = match incoming_to_value(tx, max_request_bytes).await {
+        Ok(tx) => tx,
+        Err(err) => return Ok(bad_body_response(err)),
+    };
 
     // If we have JWT enabled check that tx is valid
     if config.read().unwrap().admin.jwt {
@@ -145,7 +246,7 @@ pub async fn accept_admin_request(
         ) {
             Ok(token) => token,
             Err(err) => {
-                println!("\x1b[31mJWT Auth error:\x1b[0m {}", err);
+                warn!("JWT Auth error: {}", err);
                 return Ok(hyper::Response::builder()
                     .status(401)
                     .body(Full::new(Bytes::from("Unauthorized or invalid token")))
@@ -154,7 +255,7 @@ pub async fn accept_admin_request(
         };
 
         // Reconstruct the TX as a normal json rpc request
-        println!("\x1b[35mInfo:\x1b[0m JWT claims: {:?}", token);
+        info!("JWT claims: {:?}", token);
 
         tx = json!({
             "id": token.claims.id,
@@ -166,9 +267,9 @@ pub async fn accept_admin_request(
 
     // Send the request off to be processed
     let time = Instant::now();
-    let response = forward_body(tx, &rpc_list_rwlock, &poverty_list_rwlock, cache, config).await;
+    let response = forward_body(tx, chain, cache, &chains.memory_caches(), &metrics).await;
     let time = time.elapsed();
-    println!("\x1b[35mInfo:\x1b[0m Request time: {:?}", time);
+    info!("Request time: {:?}", time);
 
     response
 }
@@ -176,12 +277,24 @@ pub async fn accept_admin_request(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        balancer::processing::CacheArgs,
+        health::{
+            readiness::Readiness,
+            trigger::HealthTrigger,
+        },
+        Settings,
+    };
     use jsonwebtoken::DecodingKey;
+    use std::sync::RwLock;
+    use tokio::sync::mpsc;
 
     // Helper function to create a test Settings config
     fn create_test_settings() -> Arc<RwLock<Settings>> {
-        let mut config = Settings::default();
-        config.do_clear = true;
+        let mut config = Settings {
+            do_clear: true,
+            ..Default::default()
+        };
         config.admin.key = DecodingKey::from_secret(b"some-key");
         Arc::new(RwLock::new(config))
     }
@@ -194,12 +307,25 @@ mod tests {
         Arc::new(db)
     }
 
+    fn create_test_chain(config: Arc<RwLock<Settings>>) -> AdminChain {
+        AdminChain {
+            rpc_list: Arc::new(RwLock::new(vec![])),
+            poverty_list: Arc::new(RwLock::new(vec![])),
+            health_trigger: Arc::new(HealthTrigger::default()),
+            incoming_tx: mpsc::channel(16).0,
+            cache_args: CacheArgs {
+                cache: CacheArgs::temporary_cache(),
+                ..CacheArgs::default()
+            },
+            readiness: Arc::new(Readiness::new(false)),
+            config,
+        }
+    }
+
     #[tokio::test]
     async fn test_forward_body() {
         let settings = create_test_settings();
         let cache = create_test_cache();
-        let rpc_list = Arc::new(RwLock::new(vec![]));
-        let poverty_list = Arc::new(RwLock::new(vec![]));
 
         // Create a test request (use the actual request format here)
         let tx = json!({
@@ -212,10 +338,10 @@ mod tests {
         // Call forward_body with the test data
         let result = forward_body(
             tx.clone(),
-            &rpc_list,
-            &poverty_list,
+            &create_test_chain(settings),
             cache.clone(),
-            settings,
+            &[Arc::new(MemoryCache::new(16))],
+            &Arc::new(Metrics::default()),
         )
         .await;
 
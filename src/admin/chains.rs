@@ -0,0 +1,95 @@
+// Chains the admin namespace manages.
+//
+// Admin requests go to the chain their path is for, the same way requests on
+// the main port do: `/<name>` for other chains and `/` for the default chain.
+// Probes and metrics cover every chain.
+use crate::{
+    balancer::{
+        memory_cache::MemoryCache,
+        processing::CacheArgs,
+    },
+    health::{
+        readiness::Readiness,
+        trigger::HealthTrigger,
+    },
+    websocket::types::WsconnMessage,
+    Rpc,
+    Settings,
+};
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+};
+
+use tokio::sync::mpsc;
+
+// Everything the admin namespace needs to manage a chain we serve
+#[derive(Clone)]
+pub struct AdminChain {
+    pub rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    pub poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    // Wakes the health check loop up for `blutgang_runHealthCheck`
+    pub health_trigger: Arc<HealthTrigger>,
+    pub incoming_tx: mpsc::Sender<WsconnMessage>,
+    // For caching responses outside of the request path, like `blutgang_warm`
+    pub cache_args: CacheArgs,
+    pub readiness: Arc<Readiness>,
+    pub config: Arc<RwLock<Settings>>,
+}
+
+#[derive(Clone)]
+pub struct AdminChains {
+    // Served at `/`. Its config has the admin settings.
+    pub default: AdminChain,
+    // Other chains, by the path prefix they're served at
+    pub chains: BTreeMap<String, AdminChain>,
+}
+
+impl AdminChains {
+    pub fn new(default: AdminChain) -> Self {
+        AdminChains {
+            default,
+            chains: BTreeMap::new(),
+        }
+    }
+
+    // Get the chain admin requests to `path` are for, or `None` for unknown chains
+    pub fn route(&self, path: &str) -> Option<&AdminChain> {
+        let name = path
+            .trim_start_matches('/')
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        if name.is_empty() {
+            return Some(&self.default);
+        }
+
+        self.chains.get(name)
+    }
+
+    // Every chain along with its name, starting with the default chain, named ""
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &AdminChain)> {
+        std::iter::once(("", &self.default)).chain(
+            self.chains
+                .iter()
+                .map(|(name, chain)| (name.as_str(), chain)),
+        )
+    }
+
+    // Returns true if every chain is ready to take traffic
+    pub fn is_ready(&self) -> bool {
+        self.iter()
+            .all(|(_, chain)| chain.readiness.is_ready(&chain.rpc_list))
+    }
+
+    // In-memory caches in front of the cache of every chain
+    pub fn memory_caches(&self) -> Vec<Arc<MemoryCache>> {
+        self.iter()
+            .map(|(_, chain)| Arc::clone(&chain.cache_args.memory_cache))
+            .collect()
+    }
+}
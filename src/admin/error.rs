@@ -13,7 +13,11 @@ pub enum AdminError {
     RwError,
     Inaccessible,
     OutOfBounds,
+    RpcExists,
+    RpcNotFound,
     InvalidResponse(String),
+    HealthCheckDisabled,
+    DumpsDisabled,
 }
 
 impl std::fmt::Display for AdminError {
@@ -30,7 +34,13 @@ impl std::fmt::Display for AdminError {
             AdminError::OutOfBounds => {
                 write!(f, "Request out of bounds.")
             }
+            AdminError::RpcExists => write!(f, "An RPC with this url already exists"),
+            AdminError::RpcNotFound => write!(f, "No RPC with this url exists"),
             AdminError::InvalidResponse(reason) => write!(f, "Invalid RPC response: {}", reason),
+            AdminError::HealthCheckDisabled => write!(f, "Health checks are disabled"),
+            AdminError::DumpsDisabled => {
+                write!(f, "Cache dumps are disabled, set `admin.dump_dir` to enable them")
+            }
         }
     }
 }
@@ -1,30 +1,47 @@
-use std::sync::{
-    Arc,
-    RwLock,
+use tracing::{
+    error,
+    info,
+    warn,
+};
+
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    time::Duration,
 };
 
 use sled::Db;
 
 use crate::{
-    admin::accept::accept_admin_request,
-    Rpc,
-    Settings,
+    admin::{
+        accept::accept_admin_request,
+        chains::AdminChains,
+        metrics::Metrics,
+    },
+    balancer::admission::AdmissionControl,
 };
 
+use http_body_util::Full;
 use hyper::{
+    body::Bytes,
     server::conn::http1,
     service::service_fn,
+    Request,
 };
 use hyper_util_blutgang::rt::TokioIo;
-use tokio::net::TcpListener;
+use tokio::{
+    net::TcpListener,
+    sync::Semaphore,
+    time::timeout,
+};
 
 macro_rules! accept_admin {
     (
         $io:expr,
-        $rpc_list_rwlock:expr,
-        $poverty_list_rwlock:expr,
+        $chains:expr,
         $cache:expr,
-        $config:expr,
+        $metrics:expr,
+        $admission:expr,
     ) => {
         // Bind the incoming connection to our service
         if let Err(err) = http1::Builder::new()
@@ -32,64 +49,356 @@ macro_rules! accept_admin {
             .serve_connection(
                 $io,
                 service_fn(|req| {
-                    let response = accept_admin_request(
+                    let response = accept_admin_request_with_timeout(
                         req,
-                        Arc::clone($rpc_list_rwlock),
-                        Arc::clone($poverty_list_rwlock),
+                        Arc::clone($chains),
                         Arc::clone($cache),
-                        Arc::clone($config),
+                        Arc::clone($metrics),
+                        Arc::clone($admission),
                     );
                     response
                 }),
             )
             .await
         {
-            println!("error serving admin connection: {:?}", err);
+            error!("Error serving admin connection: {:?}", err);
         }
     };
 }
 
+// Gives up on admin requests that take longer than `admin.request_timeout_ms`
+async fn accept_admin_request_with_timeout(
+    tx: Request<hyper::body::Incoming>,
+    chains: Arc<AdminChains>,
+    cache: Arc<Db>,
+    metrics: Arc<Metrics>,
+    admission: Arc<AdmissionControl>,
+) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    let request_timeout = chains.default.config.read().unwrap().admin.request_timeout_ms;
+    let response = accept_admin_request(tx, chains, cache, metrics, admission);
+
+    if request_timeout == 0 {
+        return response.await;
+    }
+
+    match timeout(Duration::from_millis(request_timeout), response).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("Admin request timed out!");
+            Ok(hyper::Response::builder()
+                .status(504)
+                .body(Full::new(Bytes::from("Admin request timed out")))
+                .unwrap())
+        }
+    }
+}
+
 // Used for listening to admin requests as its own tokio task.
 //
 // Similar to what you'd find in main/balancer
 pub async fn listen_for_admin_requests(
-    rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
-    poverty_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    chains: Arc<AdminChains>,
     cache: Arc<Db>,
-    config: Arc<RwLock<Settings>>,
+    metrics: Arc<Metrics>,
+    admission: Arc<AdmissionControl>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let address;
     {
-        let config_guard = config.read().unwrap();
+        let config_guard = chains.default.config.read().unwrap();
         address = config_guard.admin.address;
     }
 
     // Create a listener and bind to it
     let listener = TcpListener::bind(address).await?;
-    println!("\x1b[35mInfo:\x1b[0m Bound admin to: {}", address);
+    info!("Bound admin to: {}", address);
+
+    serve_admin(listener, chains, cache, metrics, admission).await
+}
+
+// Accepts admin connections from `listener`.
+//
+// Connections over `admin.max_connections` get closed right away. This is kept
+// separate from the main port so admin traffic can't starve regular requests.
+async fn serve_admin(
+    listener: TcpListener,
+    chains: Arc<AdminChains>,
+    cache: Arc<Db>,
+    metrics: Arc<Metrics>,
+    admission: Arc<AdmissionControl>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let max_connections = chains.default.config.read().unwrap().admin.max_connections;
+    let connections = if max_connections == 0 {
+        None
+    } else {
+        Some(Arc::new(Semaphore::new(max_connections)))
+    };
 
     loop {
         let (stream, socketaddr) = listener.accept().await?;
-        println!("\x1b[35mInfo:\x1b[0m Admin connection from: {}", socketaddr);
+        info!("Admin connection from: {}", socketaddr);
+
+        // Hold a permit for as long as we're serving the connection
+        let permit = match &connections {
+            Some(connections) => {
+                match Arc::clone(connections).try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        warn!("Too many admin connections, dropping: {}", socketaddr);
+                        continue;
+                    }
+                }
+            }
+            None => None,
+        };
 
         // Use an adapter to access something implementing `tokio::io` traits as if they implement
         // `hyper::rt` IO traits.
         let io = TokioIo::new(stream);
 
-        let rpc_list_rwlock_clone = Arc::clone(&rpc_list_rwlock);
-        let poverty_list_rwlock_clone = Arc::clone(&poverty_list_rwlock);
+        let chains_clone = Arc::clone(&chains);
         let cache_clone = Arc::clone(&cache);
-        let config_clone = Arc::clone(&config);
+        let metrics_clone = Arc::clone(&metrics);
+        let admission_clone = Arc::clone(&admission);
 
         // Spawn a tokio task to serve multiple connections concurrently
         tokio::task::spawn(async move {
+            let _permit = permit;
             accept_admin!(
                 io,
-                &rpc_list_rwlock_clone,
-                &poverty_list_rwlock_clone,
+                &chains_clone,
                 &cache_clone,
-                &config_clone,
+                &metrics_clone,
+                &admission_clone,
             );
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        admin::chains::AdminChain,
+        balancer::{
+            memory_cache::MemoryCache,
+            processing::CacheArgs,
+        },
+        health::{
+            readiness::Readiness,
+            trigger::HealthTrigger,
+        },
+        Rpc,
+        Settings,
+    };
+    use std::sync::RwLock;
+    use tokio::{
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt,
+        },
+        net::TcpStream,
+        sync::mpsc,
+    };
+
+    async fn spawn_admin(config: Settings) -> std::net::SocketAddr {
+        spawn_admin_with(config, Vec::new(), Arc::new(Readiness::new(false))).await
+    }
+
+    fn admin_chain(config: Settings, rpc_list: Vec<Rpc>, readiness: Arc<Readiness>) -> AdminChain {
+        AdminChain {
+            rpc_list: Arc::new(RwLock::new(rpc_list)),
+            poverty_list: Arc::new(RwLock::new(Vec::new())),
+            health_trigger: Arc::new(HealthTrigger::default()),
+            incoming_tx: mpsc::channel(16).0,
+            cache_args: CacheArgs {
+                cache: CacheArgs::temporary_cache(),
+                memory_cache: Arc::new(MemoryCache::new(16)),
+                ..CacheArgs::default()
+            },
+            readiness,
+            config: Arc::new(RwLock::new(config)),
+        }
+    }
+
+    async fn spawn_admin_with(
+        config: Settings,
+        rpc_list: Vec<Rpc>,
+        readiness: Arc<Readiness>,
+    ) -> std::net::SocketAddr {
+        spawn_admin_chains(AdminChains::new(admin_chain(config, rpc_list, readiness))).await
+    }
+
+    async fn spawn_admin_chains(chains: AdminChains) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let cache = Arc::new(sled::Config::new().temporary(true).open().unwrap());
+
+        tokio::spawn(async move {
+            let _ = serve_admin(
+                listener,
+                Arc::new(chains),
+                cache,
+                Arc::new(Metrics::default()),
+                Arc::new(AdmissionControl::default()),
+            )
+            .await;
+        });
+
+        address
+    }
+
+    async fn admin_request(stream: &mut TcpStream) -> String {
+        let body = r#"{"id":1,"jsonrpc":"2.0","method":"blutgang_ttl","params":[]}"#;
+        let request = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut buf = vec![0; 4096];
+        let n = timeout(Duration::from_secs(1), stream.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap_or(0);
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_admin_max_connections() {
+        let mut config = Settings::default();
+        config.admin.max_connections = 1;
+        let address = spawn_admin(config).await;
+
+        // Hold the only admin connection open
+        let mut first = TcpStream::connect(address).await.unwrap();
+        assert!(admin_request(&mut first).await.starts_with("HTTP/1.1 200"));
+
+        // Connections over the limit get closed without being served
+        let mut second = TcpStream::connect(address).await.unwrap();
+        assert!(admin_request(&mut second).await.is_empty());
+
+        // Once the first connection is gone we can connect again
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut third = TcpStream::connect(address).await.unwrap();
+        assert!(admin_request(&mut third).await.starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_unbounded_connections() {
+        let address = spawn_admin(Settings::default()).await;
+
+        let mut streams = Vec::new();
+        for _ in 0..4 {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            assert!(admin_request(&mut stream).await.starts_with("HTTP/1.1 200"));
+            streams.push(stream);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_live_ready() {
+        // Probes don't need the admin token
+        let mut config = Settings::default();
+        config.admin.auth_token = Some("secret".to_string());
+        let status = |address: std::net::SocketAddr, path: &'static str| {
+            async move {
+                reqwest::get(format!("http://{}{}", address, path))
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+
+        // No RPCs and no health check yet
+        let address = spawn_admin(config.clone()).await;
+        assert_eq!(status(address, "/live").await, 200);
+        assert_eq!(status(address, "/ready").await, 503);
+
+        // An RPC, but no health check yet
+        let readiness = Arc::new(Readiness::new(false));
+        let address = spawn_admin_with(config.clone(), vec![Rpc::default()], readiness.clone()).await;
+        assert_eq!(status(address, "/ready").await, 503);
+
+        readiness.mark_health_checked();
+        assert_eq!(status(address, "/ready").await, 200);
+        assert_eq!(status(address, "/live").await, 200);
+
+        // Waits for the cache warmup like the main port does
+        let readiness = Arc::new(Readiness::new(true));
+        readiness.mark_health_checked();
+        let address = spawn_admin_with(config.clone(), vec![Rpc::default()], readiness.clone()).await;
+        assert_eq!(status(address, "/ready").await, 503);
+        readiness.mark_warmed_up();
+        assert_eq!(status(address, "/ready").await, 200);
+
+        // Not ready with an empty active pool, whatever happened before
+        let address = spawn_admin_with(config, Vec::new(), readiness).await;
+        assert_eq!(status(address, "/ready").await, 503);
+    }
+
+    #[tokio::test]
+    async fn test_admin_metrics() {
+        let mut config = Settings::default();
+        config.admin.metrics_enabled = true;
+        let address = spawn_admin(config).await;
+
+        let response = reqwest::get(format!("http://{}/metrics", address))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; version=0.0.4"
+        );
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("blutgang_requests_total 0"));
+
+        // Not served unless enabled
+        let address = spawn_admin(Settings::default()).await;
+        let response = reqwest::get(format!("http://{}/metrics", address))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_admin_auth_token() {
+        let mut config = Settings {
+            auth_token: Some("main".to_string()),
+            ..Default::default()
+        };
+        config.admin.auth_token = Some("admin".to_string());
+        let address = spawn_admin(config).await;
+        let url = format!("http://{}", address);
+        let tx =
+            serde_json::json!({"id": 1, "jsonrpc": "2.0", "method": "blutgang_ttl", "params": []});
+        let client = reqwest::Client::new();
+
+        let unauthorized = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(unauthorized.status(), 401);
+
+        // The admin port has its own token
+        let main_token = client
+            .post(&url)
+            .bearer_auth("main")
+            .json(&tx)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(main_token.status(), 401);
+
+        let authorized = client
+            .post(&url)
+            .bearer_auth("admin")
+            .json(&tx)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(authorized.status(), 200);
+    }
+}
@@ -1,15 +1,50 @@
 use crate::{
-    admin::error::AdminError,
+    admin::{
+        error::AdminError,
+        metrics::Metrics,
+    },
+    balancer::{
+        memory_cache::MemoryCache,
+        processing::{
+            purge_errors,
+            purge_method,
+            CacheArgs,
+            CacheWriteState,
+        },
+        warmup::warm_cache,
+    },
+    config::cache_setup::{
+        cache_trees,
+        clear_cache,
+        export_cache,
+        import_cache,
+    },
+    health::trigger::HealthTrigger,
+    rpc::types::ClientSettings,
+    websocket::types::WsconnMessage,
     Rpc,
     Settings,
 };
 
+use tracing::{
+    debug,
+    error,
+    info,
+};
+
 use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
     sync::{
         Arc,
         RwLock,
     },
-    time::Instant,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 use serde_json::{
@@ -19,17 +54,24 @@ use serde_json::{
 };
 
 use sled::Db;
+use tokio::sync::mpsc;
 
 // Extract the method, call the appropriate function and return the response
+#[allow(clippy::too_many_arguments)]
 pub async fn execute_method(
     tx: Value,
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     config: Arc<RwLock<Settings>>,
     cache: Arc<Db>,
+    incoming_tx: &mpsc::Sender<WsconnMessage>,
+    memory_caches: &[Arc<MemoryCache>],
+    metrics: &Arc<Metrics>,
+    health_trigger: &Arc<HealthTrigger>,
+    cache_args: &CacheArgs,
 ) -> Result<Value, AdminError> {
     let method = tx["method"].as_str();
-    println!("Method: {:?}", method.unwrap_or("None"));
+    debug!("Method: {:?}", method.unwrap_or("None"));
 
     // Check if write protection is enabled
     let write_protection_enabled = config.read().unwrap().admin.readonly;
@@ -50,8 +92,77 @@ pub async fn execute_method(
                 admin_flush_cache(cache).await
             }
         }
+        Some("blutgang_purgeErrors") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_purge_errors(cache, memory_caches)
+            }
+        }
+        Some("blutgang_flushCache") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_clear_cache(cache, memory_caches).await
+            }
+        }
+        Some("blutgang_purgeMethod") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_purge_method(cache, memory_caches, tx["params"].as_array()).await
+            }
+        }
+        Some("blutgang_exportCache") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                let dump_dir = config.read().unwrap().admin.dump_dir.clone();
+                admin_export_cache(cache, tx["params"].as_array(), dump_dir.as_deref()).await
+            }
+        }
+        Some("blutgang_importCache") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                let dump_dir = config.read().unwrap().admin.dump_dir.clone();
+                admin_import_cache(cache, tx["params"].as_array(), dump_dir.as_deref()).await
+            }
+        }
+        Some("blutgang_warm") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                // `[method_cache]` can be reloaded, so don't use the one from startup
+                let (ttl, method_cache) = {
+                    let config_guard = config.read().unwrap();
+                    (config_guard.ttl, config_guard.method_cache.clone())
+                };
+                let cache_args = CacheArgs {
+                    method_cache,
+                    ..cache_args.clone()
+                };
+                admin_warm(rpc_list, &cache_args, ttl, tx["params"].as_array()).await
+            }
+        }
         Some("blutgang_config") => admin_config(config),
         Some("blutgang_poverty_list") => admin_list_rpc(poverty_list),
+        Some("blutgang_saturation") => admin_saturation(rpc_list),
+        Some("blutgang_fleet") => admin_fleet(rpc_list, poverty_list),
+        Some("blutgang_health") => {
+            admin_health(
+                rpc_list,
+                poverty_list,
+                health_trigger,
+                &cache_args.cache_state,
+            )
+        }
+        Some("blutgang_runHealthCheck") => {
+            admin_run_health_check(rpc_list, poverty_list, health_trigger).await
+        }
+        Some("blutgang_stats") => {
+            admin_stats(metrics, &cache_args.cache_state, tx["params"].as_array())
+        }
         Some("blutgang_ttl") => admin_blutgang_ttl(config),
         Some("blutgang_health_check_ttl") => admin_blutgang_health_check_ttl(config),
         Some("blutgang_set_ttl") => {
@@ -96,6 +207,53 @@ pub async fn execute_method(
                 admin_remove_rpc(poverty_list, tx["params"].as_array())
             }
         }
+        Some("blutgang_addRpc") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_add_rpc_endpoint(
+                    rpc_list,
+                    poverty_list,
+                    &config,
+                    incoming_tx,
+                    tx["params"].as_array(),
+                )
+            }
+        }
+        Some("blutgang_removeRpc") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_remove_rpc_endpoint(
+                    rpc_list,
+                    poverty_list,
+                    &config,
+                    incoming_tx,
+                    tx["params"].as_array(),
+                )
+            }
+        }
+        Some("blutgang_setWeight") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_set_weight(rpc_list, poverty_list, tx["params"].as_array())
+            }
+        }
+        Some("blutgang_pauseRpc") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_pause_rpc(rpc_list, poverty_list, tx["params"].as_array())
+            }
+        }
+        Some("blutgang_resumeRpc") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_resume_rpc(rpc_list, poverty_list, tx["params"].as_array())
+            }
+        }
         Some(_) => Err(AdminError::InvalidMethod),
         _ => Ok(().into()),
     }
@@ -132,6 +290,239 @@ async fn admin_flush_cache(cache: Arc<Db>) -> Result<Value, AdminError> {
     Ok(rx)
 }
 
+// Remove every cached JSON-RPC error of every chain, leaving responses alone
+fn admin_purge_errors(
+    cache: Arc<Db>,
+    memory_caches: &[Arc<MemoryCache>],
+) -> Result<Value, AdminError> {
+    let purged = cache_trees(&cache)
+        .and_then(|trees| {
+            trees
+                .iter()
+                .try_fold(0, |purged, (_, tree)| Ok(purged + purge_errors(tree)?))
+        })
+        .map_err(|err| {
+            error!("Could not purge cached errors: {}", err);
+            AdminError::RwError
+        })?;
+    // We don't know which keys in memory are errors
+    for memory_cache in memory_caches {
+        memory_cache.clear();
+    }
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": format!("Purged {} cached errors", purged),
+    });
+
+    Ok(rx)
+}
+
+// Cache hit rates and upstream latency percentiles over the last few minutes.
+//
+// Pass `[true]` to reset the stats after reading them. Head cache churn is
+// counted since startup and isn't reset.
+fn admin_stats(
+    metrics: &Arc<Metrics>,
+    cache_state: &CacheWriteState,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let reset = match params.map(|params| params.as_slice()) {
+        None | Some([]) => false,
+        Some([reset]) => reset.as_bool().ok_or(AdminError::ParseError)?,
+        Some(_) => return Err(AdminError::InvalidLen),
+    };
+
+    let mut stats = metrics.stats(reset);
+    stats["deduped_head_writes"] = cache_state.deduped_head_writes().into();
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": stats,
+    });
+
+    Ok(rx)
+}
+
+// Remove every cached response and error of every chain, leaving metadata alone
+async fn admin_clear_cache(
+    cache: Arc<Db>,
+    memory_caches: &[Arc<MemoryCache>],
+) -> Result<Value, AdminError> {
+    let cleared = tokio::task::spawn_blocking(move || clear_cache(&cache))
+        .await
+        .map_err(|_| AdminError::Inaccessible)?
+        .map_err(|err| {
+            error!("Could not clear cache: {}", err);
+            AdminError::RwError
+        })?;
+    for memory_cache in memory_caches {
+        memory_cache.clear();
+    }
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": cleared,
+    });
+
+    Ok(rx)
+}
+
+// Remove every cached response and error of the method in the params from
+// every chain, e.g. after an RPC poisoned the cache with bad responses
+async fn admin_purge_method(
+    cache: Arc<Db>,
+    memory_caches: &[Arc<MemoryCache>],
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let params = params.ok_or(AdminError::InvalidParams)?;
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+    let method = params[0]
+        .as_str()
+        .ok_or(AdminError::ParseError)?
+        .to_string();
+
+    let purged = tokio::task::spawn_blocking(move || {
+        cache_trees(&cache)?
+            .iter()
+            .try_fold(0, |purged, (_, tree)| {
+                Ok(purged + purge_method(tree, &method)?)
+            })
+    })
+    .await
+    .map_err(|_| AdminError::Inaccessible)?
+    .map_err(|err: sled::Error| {
+        error!("Could not purge cached method: {}", err);
+        AdminError::RwError
+    })?;
+    // We don't know which keys in memory belong to the method
+    for memory_cache in memory_caches {
+        memory_cache.clear();
+    }
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": purged,
+    });
+
+    Ok(rx)
+}
+
+// Resolve the cache dump named in the params to a file in `dump_dir`.
+//
+// Only plain file names are accepted, so dumps can't be read or written anywhere else.
+fn dump_path(params: Option<&Vec<Value>>, dump_dir: Option<&str>) -> Result<PathBuf, AdminError> {
+    let dump_dir = dump_dir.ok_or(AdminError::DumpsDisabled)?;
+    let params = params.ok_or(AdminError::InvalidParams)?;
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let name = params[0].as_str().ok_or(AdminError::ParseError)?;
+    match Path::new(name).file_name() {
+        Some(file_name) if file_name == name => Ok(Path::new(dump_dir).join(name)),
+        _ => Err(AdminError::InvalidParams),
+    }
+}
+
+// Dump all immutable cache entries to the file in the params, so the cache
+// can be shipped to other instances
+async fn admin_export_cache(
+    cache: Arc<Db>,
+    params: Option<&Vec<Value>>,
+    dump_dir: Option<&str>,
+) -> Result<Value, AdminError> {
+    let path = dump_path(params, dump_dir)?;
+
+    let time = Instant::now();
+    let dump_path = path.clone();
+    let exported = tokio::task::spawn_blocking(move || export_cache(&cache, &dump_path))
+        .await
+        .map_err(|_| AdminError::Inaccessible)?
+        .map_err(|err| {
+            error!("Could not export cache: {}", err);
+            AdminError::RwError
+        })?;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": format!("Exported {} entries to {} in {:?}", exported, path.display(), time.elapsed()),
+    });
+
+    Ok(rx)
+}
+
+// Load a cache dump made with `blutgang_exportCache` into the cache
+async fn admin_import_cache(
+    cache: Arc<Db>,
+    params: Option<&Vec<Value>>,
+    dump_dir: Option<&str>,
+) -> Result<Value, AdminError> {
+    let path = dump_path(params, dump_dir)?;
+
+    let time = Instant::now();
+    let dump_path = path.clone();
+    let imported = tokio::task::spawn_blocking(move || import_cache(&cache, &dump_path))
+        .await
+        .map_err(|_| AdminError::Inaccessible)?
+        .map_err(|err| {
+            error!("Could not import cache: {}", err);
+            AdminError::RwError
+        })?;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": format!("Imported {} entries from {} in {:?}", imported, path.display(), time.elapsed()),
+    });
+
+    Ok(rx)
+}
+
+// Cache the responses to a list of requests.
+//
+// Requests that are already cached get fetched and cached again, so calling
+// this repeatedly with the same list is harmless.
+async fn admin_warm(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    cache_args: &CacheArgs,
+    ttl: u128,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let requests = params.ok_or(AdminError::InvalidParams)?;
+    if requests.is_empty() {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let results = warm_cache(requests, rpc_list, cache_args, ttl).await;
+    let warmed = results.iter().filter(|result| result.is_ok()).count();
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "warmed": warmed,
+            "failed": results.len() - warmed,
+            "results": results
+                .into_iter()
+                .map(|result| match result {
+                    Ok(()) => json!({ "ok": true }),
+                    Err(err) => json!({ "ok": false, "error": err }),
+                })
+                .collect::<Vec<Value>>(),
+        },
+    });
+
+    Ok(rx)
+}
+
 // Respond with the config we started blutgang with
 fn admin_config(config: Arc<RwLock<Settings>>) -> Result<Value, AdminError> {
     let guard = config.read().unwrap();
@@ -185,6 +576,176 @@ fn admin_list_rpc(rpc_list: &Arc<RwLock<Vec<Rpc>>>) -> Result<Value, AdminError>
     Ok(rx)
 }
 
+// Returns how saturated the active pool is as a 0-1 figure, along with
+// the utilization of every node. Meant as a scaling signal for external controllers.
+//
+// Only RPCs with a configured `max_concurrency` count towards the aggregate capacity.
+fn admin_saturation(rpc_list: &Arc<RwLock<Vec<Rpc>>>) -> Result<Value, AdminError> {
+    let rpc_list = rpc_list.read().map_err(|_| AdminError::Inaccessible)?;
+
+    let mut in_flight = 0;
+    let mut capacity = 0;
+    let mut nodes = Vec::with_capacity(rpc_list.len());
+
+    for rpc in rpc_list.iter() {
+        if rpc.max_concurrency != 0 {
+            in_flight += rpc.in_flight().min(rpc.max_concurrency) as u64;
+            capacity += rpc.max_concurrency as u64;
+        }
+
+        nodes.push(json!({
+            "url": rpc.url,
+            "in_flight": rpc.in_flight(),
+            "capacity": rpc.max_concurrency,
+            "utilization": rpc.utilization(),
+        }));
+    }
+
+    let saturation = if capacity == 0 {
+        0.0
+    } else {
+        in_flight as f64 / capacity as f64
+    };
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "saturation": saturation,
+            "nodes": nodes,
+        },
+    });
+
+    Ok(rx)
+}
+
+// Reports the metadata collected about every node during health checks,
+// along with whether it's in the active pool or the poverty list.
+fn admin_fleet(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> Result<Value, AdminError> {
+    let rpc_list = rpc_list.read().map_err(|_| AdminError::Inaccessible)?;
+    let poverty_list = poverty_list.read().map_err(|_| AdminError::Inaccessible)?;
+
+    let active = rpc_list.iter().map(|rpc| (rpc, "active"));
+    let poverty = poverty_list.iter().map(|rpc| (rpc, "poverty"));
+
+    let nodes: Vec<Value> = active
+        .chain(poverty)
+        .map(|(rpc, health)| {
+            json!({
+                "url": rpc.url,
+                "health": health,
+                "client_version": rpc.metadata.client_version,
+                "peer_count": rpc.metadata.peer_count,
+                "chain_id": rpc.metadata.chain_id,
+                "syncing": rpc.metadata.syncing,
+                "archive": rpc.metadata.archive,
+                "latency": rpc.status.latency,
+            })
+        })
+        .collect();
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": nodes,
+    });
+
+    Ok(rx)
+}
+
+// Returns the health check state of every RPC, split by active and poverty,
+// and whether the cache is read-only
+fn admin_health(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    health_trigger: &Arc<HealthTrigger>,
+    cache_state: &CacheWriteState,
+) -> Result<Value, AdminError> {
+    let health = |rpc: &Rpc| {
+        json!({
+            "url": rpc.url,
+            "is_erroring": rpc.status.is_erroring,
+            "ws_erroring": rpc.status.ws_erroring,
+            "banned": rpc.status.banned,
+            "paused": rpc.status.paused,
+            "is_syncing": rpc.status.is_syncing,
+            "chain_id": rpc.status.chain_id,
+            "last_head": rpc.status.last_head,
+            "head_lag": rpc.status.head_lag,
+            "max_observed_lag": rpc.max_observed_lag(),
+            "last_latency_ms": rpc.status.last_latency_ms,
+            "probes": rpc.status.probe_results,
+            "failed_probe": rpc.status.failed_probe,
+            "consecutive_failures": rpc.status.consecutive_failures,
+            "cooling_down": rpc.is_cooling_down(),
+            "ms_since_last_healthy_check": rpc
+                .status
+                .last_healthy_check
+                .map(|check| check.elapsed().as_millis() as u64),
+            "in_flight": rpc.in_flight(),
+            "max_concurrency": rpc.max_concurrency,
+        })
+    };
+
+    let active: Vec<Value> = rpc_list
+        .read()
+        .map_err(|_| AdminError::Inaccessible)?
+        .iter()
+        .map(health)
+        .collect();
+    let poverty: Vec<Value> = poverty_list
+        .read()
+        .map_err(|_| AdminError::Inaccessible)?
+        .iter()
+        .map(health)
+        .collect();
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "no_consensus": health_trigger.no_consensus(),
+            "cache_read_only": cache_state.is_read_only(),
+            "active": active,
+            "poverty": poverty,
+        },
+    });
+
+    Ok(rx)
+}
+
+// Runs a health check right away and returns once it's done,
+// with how many RPCs are in the active and poverty lists after it
+async fn admin_run_health_check(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    health_trigger: &Arc<HealthTrigger>,
+) -> Result<Value, AdminError> {
+    if !health_trigger.run().await {
+        return Err(AdminError::HealthCheckDisabled);
+    }
+
+    let active = rpc_list.read().map_err(|_| AdminError::Inaccessible)?.len();
+    let poverty = poverty_list
+        .read()
+        .map_err(|_| AdminError::Inaccessible)?
+        .len();
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "active": active,
+            "poverty": poverty,
+        },
+    });
+
+    Ok(rx)
+}
+
 // Pushes an RPC to the end of the list
 //
 // param[0] - RPC url
@@ -236,64 +797,368 @@ fn admin_add_rpc(
         .parse::<f64>()
         .unwrap_or(0.0);
 
-    if delta != 0 {
-        delta = 1_000_000 / delta;
+    delta = 1_000_000u64.checked_div(delta).unwrap_or(0);
+
+    let mut rpc_list = rpc_list.write().map_err(|_| AdminError::Inaccessible)?;
+
+    rpc_list.push(Rpc::new(
+        rpc.to_string(),
+        ws_url,
+        max_consecutive,
+        delta.into(),
+        ma_len,
+    ));
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": format!("RPC: {}, max_consecutive: {}, ma: {}", rpc, max_consecutive, ma_len),
+    });
+
+    Ok(rx)
+}
+
+// Remove RPC at a specified index, return the url of the removed RPC
+//
+// param[0] - RPC index
+fn admin_remove_rpc(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let index = match params[0].to_string().replace('\"', "").parse::<u64>() {
+        Ok(index) => index,
+        Err(_) => return Err(AdminError::ParseError),
+    };
+
+    let mut rpc_list = rpc_list.write().map_err(|_| AdminError::Inaccessible)?;
+
+    // Check if index exists before removing
+    if index as usize >= rpc_list.len() {
+        return Err(AdminError::OutOfBounds);
+    }
+
+    // Finally, remove the index
+    let removed: Rpc = rpc_list.remove(index as usize);
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": removed.url,
+    });
+
+    Ok(rx)
+}
+
+// Add a new RPC to the active pool, opening a WS connection to it if needed.
+// Unlike `blutgang_add_to_rpc_list`, RPCs are identified by their url.
+//
+// param[0] - RPC url
+// param[1] - WS url, can be null if WebSockets are disabled
+// param[2] - max_consecutive
+// param[3] - weight, optional
+fn admin_add_rpc_endpoint(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    config: &Arc<RwLock<Settings>>,
+    incoming_tx: &mpsc::Sender<WsconnMessage>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 3 && params.len() != 4 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let url = match params[0].as_str() {
+        Some(url) => url,
+        None => return Err(AdminError::ParseError),
+    };
+
+    let ws_url = match params[1].is_null() {
+        true => None,
+        false => {
+            match params[1].as_str().map(|s| s.to_string()) {
+                Some(ws_url) => Some(ws_url),
+                None => return Err(AdminError::ParseError),
+            }
+        }
+    };
+
+    let max_consecutive = match params[2].to_string().replace('\"', "").parse::<u32>() {
+        Ok(max_consecutive) => max_consecutive,
+        Err(_) => return Err(AdminError::ParseError),
+    };
+
+    let weight = match params.get(3) {
+        Some(weight) => {
+            match weight.to_string().replace('\"', "").parse::<u32>() {
+                Ok(weight) => weight,
+                Err(_) => return Err(AdminError::ParseError),
+            }
+        }
+        None => 1,
+    };
+
+    let (is_ws, ma_length, max_response_bytes, head_method, client_settings) = {
+        let config = config.read().map_err(|_| AdminError::Inaccessible)?;
+        let client_settings = ClientSettings {
+            proxy_url: config.proxy_url.clone(),
+            no_proxy: config.no_proxy.clone(),
+            ..Default::default()
+        };
+        (
+            config.is_ws,
+            config.ma_length,
+            config.max_response_bytes,
+            config.head_method.clone(),
+            client_settings,
+        )
+    };
+
+    // Every RPC needs a WS endpoint when WebSockets are enabled
+    if is_ws && ws_url.is_none() {
+        return Err(AdminError::InvalidParams);
+    }
+
+    if poverty_list
+        .read()
+        .map_err(|_| AdminError::Inaccessible)?
+        .iter()
+        .any(|rpc| rpc.url == url)
+    {
+        return Err(AdminError::RpcExists);
+    }
+
+    {
+        let mut rpc_list = rpc_list.write().map_err(|_| AdminError::Inaccessible)?;
+        if rpc_list.iter().any(|rpc| rpc.url == url) {
+            return Err(AdminError::RpcExists);
+        }
+
+        let mut rpc = Rpc::new(url.to_string(), ws_url, max_consecutive, 0, ma_length);
+        rpc.weight = weight;
+        rpc.max_response_bytes = max_response_bytes;
+        rpc.head_method = head_method;
+        if client_settings != ClientSettings::default() {
+            rpc.set_client_settings(client_settings);
+        }
+        rpc_list.push(rpc);
+    }
+
+    // Have `ws_conn_manager` open a connection to the new RPC, keeping the others
+    if is_ws {
+        incoming_tx
+            .try_send(WsconnMessage::Sync())
+            .map_err(|_| AdminError::Inaccessible)?;
+    }
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": format!("RPC: {}, max_consecutive: {}, weight: {}", url, max_consecutive, weight),
+    });
+
+    Ok(rx)
+}
+
+// Remove an RPC from both the active pool and the poverty list,
+// closing its WS connection if there is one.
+//
+// param[0] - RPC url
+fn admin_remove_rpc_endpoint(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    config: &Arc<RwLock<Settings>>,
+    incoming_tx: &mpsc::Sender<WsconnMessage>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let url = match params[0].as_str() {
+        Some(url) => url,
+        None => return Err(AdminError::ParseError),
+    };
+
+    let mut removed = 0;
+    for list in [rpc_list, poverty_list] {
+        let mut list = list.write().map_err(|_| AdminError::Inaccessible)?;
+        let len = list.len();
+        list.retain(|rpc| rpc.url != url);
+        removed += len - list.len();
+    }
+
+    if removed == 0 {
+        return Err(AdminError::RpcNotFound);
+    }
+
+    // Syncing the connections closes the one to the removed RPC, keeping the others
+    if config.read().map_err(|_| AdminError::Inaccessible)?.is_ws {
+        incoming_tx
+            .try_send(WsconnMessage::Sync())
+            .map_err(|_| AdminError::Inaccessible)?;
+    }
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": url,
+    });
+
+    Ok(rx)
+}
+
+// Set the weight of an RPC used by the `weighted` selection strategy.
+//
+// RPCs in the poverty list keep their new weight for when they recover.
+//
+// param[0] - RPC url
+// param[1] - weight
+fn admin_set_weight(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 2 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let url = match params[0].as_str() {
+        Some(url) => url,
+        None => return Err(AdminError::ParseError),
+    };
+
+    let weight = match params[1].to_string().replace('\"', "").parse::<u32>() {
+        Ok(weight) => weight,
+        Err(_) => return Err(AdminError::ParseError),
+    };
+
+    let mut updated = 0;
+    for list in [rpc_list, poverty_list] {
+        let mut list = list.write().map_err(|_| AdminError::Inaccessible)?;
+        for rpc in list.iter_mut().filter(|rpc| rpc.url == url) {
+            rpc.weight = weight;
+            updated += 1;
+        }
+    }
+
+    if updated == 0 {
+        return Err(AdminError::RpcNotFound);
+    }
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": format!("RPC: {}, weight: {}", url, weight),
+    });
+
+    Ok(rx)
+}
+
+// Get the url in `params` of pause and resume
+fn url_param(params: Option<&Vec<Value>>) -> Result<&str, AdminError> {
+    let params = params.ok_or(AdminError::InvalidParams)?;
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
     }
 
+    params[0].as_str().ok_or(AdminError::ParseError)
+}
+
+// Take an RPC out of the active pool, e.g. ahead of maintenance.
+//
+// It waits in the poverty list without being health checked, so it can't
+// come back on its own until it's resumed.
+fn admin_pause_rpc(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let url = url_param(params)?;
+
     let mut rpc_list = rpc_list.write().map_err(|_| AdminError::Inaccessible)?;
+    let mut poverty_list = poverty_list.write().map_err(|_| AdminError::Inaccessible)?;
+
+    if let Some(index) = rpc_list.iter().position(|rpc| rpc.url == url) {
+        let mut rpc = rpc_list.remove(index);
+        rpc.status.is_erroring = true;
+        poverty_list.push(rpc);
+    } else if !poverty_list.iter().any(|rpc| rpc.url == url) {
+        return Err(AdminError::RpcNotFound);
+    }
 
-    rpc_list.push(Rpc::new(
-        rpc.to_string(),
-        ws_url,
-        max_consecutive,
-        delta.into(),
-        ma_len,
-    ));
+    for rpc in poverty_list.iter_mut().filter(|rpc| rpc.url == url) {
+        rpc.status.paused = true;
+    }
+    info!("Paused {}", url);
 
     let rx = json!({
         "id": Null,
         "jsonrpc": "2.0",
-        "result": format!("RPC: {}, max_consecutive: {}, ma: {}", rpc, max_consecutive, ma_len),
+        "result": format!("Paused RPC: {}", url),
     });
 
     Ok(rx)
 }
 
-// Remove RPC at a specified index, return the url of the removed RPC
+// Put a paused RPC back in the active pool.
 //
-// param[0] - RPC index
-fn admin_remove_rpc(
+// If it isn't healthy, the next health check moves it back to the poverty list.
+// Banned RPCs stay where they are.
+fn admin_resume_rpc(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     params: Option<&Vec<Value>>,
 ) -> Result<Value, AdminError> {
-    let params = match params {
-        Some(params) => params,
-        None => return Err(AdminError::InvalidParams),
-    };
-
-    if params.len() != 1 {
-        return Err(AdminError::InvalidLen);
-    }
-
-    let index = match params[0].to_string().replace('\"', "").parse::<u64>() {
-        Ok(index) => index,
-        Err(_) => return Err(AdminError::ParseError),
-    };
+    let url = url_param(params)?;
 
     let mut rpc_list = rpc_list.write().map_err(|_| AdminError::Inaccessible)?;
-
-    // Check if index exists before removing
-    if index as usize >= rpc_list.len() {
-        return Err(AdminError::OutOfBounds);
+    let mut poverty_list = poverty_list.write().map_err(|_| AdminError::Inaccessible)?;
+
+    let index = poverty_list
+        .iter()
+        .position(|rpc| rpc.url == url && rpc.status.paused)
+        .ok_or(AdminError::RpcNotFound)?;
+
+    if poverty_list[index].status.banned {
+        poverty_list[index].status.paused = false;
+    } else {
+        let mut rpc = poverty_list.remove(index);
+        rpc.status.paused = false;
+        rpc.status.is_erroring = false;
+        rpc.status.next_retry_at = None;
+        rpc.status.retry_backoff = Duration::ZERO;
+        rpc_list.push(rpc);
     }
-
-    // Finally, remove the index
-    let removed: Rpc = rpc_list.remove(index as usize);
+    info!("Resumed {}", url);
 
     let rx = json!({
         "id": Null,
         "jsonrpc": "2.0",
-        "result": removed.url,
+        "result": format!("Resumed RPC: {}", url),
     });
 
     Ok(rx)
@@ -394,7 +1259,23 @@ fn admin_blutgang_set_ttl(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        balancer::{
+            processing::{
+                method_index_key,
+                EXPIRY_PREFIX,
+            },
+            selection::cache_rules::MethodCache,
+        },
+        config::cache_setup::setup_data,
+        rpc::{
+            mock::MockRpc,
+            types::NodeMetadata,
+        },
+    };
     use jsonwebtoken::DecodingKey;
+    use sled::IVec;
+    use tokio::sync::watch;
 
     // Helper function to create a test RPC list
     fn create_test_rpc_list() -> Arc<RwLock<Vec<Rpc>>> {
@@ -420,12 +1301,19 @@ mod tests {
 
     // Helper function to create a test Settings config
     fn create_test_settings_config() -> Arc<RwLock<Settings>> {
-        let mut config = Settings::default();
-        config.do_clear = true;
+        let mut config = Settings {
+            do_clear: true,
+            ..Default::default()
+        };
         config.admin.key = DecodingKey::from_secret(b"some-key");
         Arc::new(RwLock::new(config))
     }
 
+    // Helper function to create a sender for `ws_conn_manager` messages
+    fn create_test_incoming_tx() -> mpsc::Sender<WsconnMessage> {
+        mpsc::channel(16).0
+    }
+
     // Helper function to create a test cache
     fn create_test_cache() -> Arc<Db> {
         let db = sled::Config::new().temporary(true);
@@ -434,6 +1322,25 @@ mod tests {
         Arc::new(db)
     }
 
+    fn create_test_memory_cache() -> Arc<MemoryCache> {
+        Arc::new(MemoryCache::new(16))
+    }
+
+    fn create_test_metrics() -> Arc<Metrics> {
+        Arc::new(Metrics::default())
+    }
+
+    fn create_test_health_trigger() -> Arc<HealthTrigger> {
+        Arc::new(HealthTrigger::default())
+    }
+
+    fn create_test_cache_args() -> CacheArgs {
+        CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            ..CacheArgs::default()
+        }
+    }
+
     #[tokio::test]
     async fn test_execute_method_blutgang_rpc_list() {
         // Arrange
@@ -447,6 +1354,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -467,6 +1379,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -487,6 +1404,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -507,6 +1429,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -527,6 +1454,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -547,6 +1479,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -554,6 +1491,225 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_execute_method_blutgang_saturation() {
+        // Arrange
+        let cache = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        rpc_list.write().unwrap()[0].max_concurrency = 4;
+        let rpc = rpc_list.read().unwrap()[0].clone();
+
+        let saturation = |rpc_list: &Arc<RwLock<Vec<Rpc>>>| {
+            let rx = admin_saturation(rpc_list).unwrap();
+            rx["result"]["saturation"].as_f64().unwrap()
+        };
+
+        // Act & Assert
+        assert_eq!(saturation(&rpc_list), 0.0);
+
+        let mut guards = Vec::new();
+        let mut last = 0.0;
+        for _ in 0..4 {
+            guards.push(rpc.track_in_flight());
+            let current = saturation(&rpc_list);
+            assert!(current > last);
+            last = current;
+        }
+        assert_eq!(last, 1.0);
+
+        // Going over capacity should not push saturation past 1
+        guards.push(rpc.track_in_flight());
+        assert_eq!(saturation(&rpc_list), 1.0);
+
+        let tx = json!({ "id":1,"method": "blutgang_saturation" });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result["result"]["nodes"][0]["in_flight"], 5);
+        assert_eq!(result["result"]["nodes"][0]["utilization"], 1.0);
+
+        // Dropping the guards frees up capacity
+        drop(guards);
+        assert_eq!(saturation(&rpc_list), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_fleet() {
+        // Arrange
+        let cache = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        rpc_list.write().unwrap()[0].metadata = NodeMetadata {
+            client_version: Some("Geth/v1.13.0".to_string()),
+            peer_count: Some(25),
+            chain_id: Some(1),
+            syncing: Some(false),
+            archive: Some(true),
+        };
+        let tx = json!({ "id":1,"method": "blutgang_fleet" });
+
+        // Act
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        let nodes = result["result"].as_array().unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0]["url"], "http://example.com");
+        assert_eq!(nodes[0]["health"], "active");
+        assert_eq!(nodes[0]["client_version"], "Geth/v1.13.0");
+        assert_eq!(nodes[0]["peer_count"], 25);
+        assert_eq!(nodes[0]["chain_id"], 1);
+        assert_eq!(nodes[0]["syncing"], false);
+        assert_eq!(nodes[0]["archive"], true);
+
+        // Nodes we have no metadata about still show up
+        assert_eq!(nodes[1]["url"], "http://poverty.com");
+        assert_eq!(nodes[1]["health"], "poverty");
+        assert_eq!(nodes[1]["client_version"], Null);
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_health() {
+        // Arrange
+        let cache = create_test_cache();
+        let poverty_list = create_test_poverty_list();
+        {
+            let mut poverty_list = poverty_list.write().unwrap();
+            poverty_list[0].status.is_erroring = true;
+            poverty_list[0].status.last_head = 100;
+            poverty_list[0].status.consecutive_failures = 3;
+            poverty_list[0].status.last_healthy_check = Some(Instant::now());
+        }
+        let tx = json!({ "id":1,"method": "blutgang_health" });
+
+        let rpc_list = create_test_rpc_list();
+        rpc_list.write().unwrap()[0].set_max_concurrency(4);
+        let _in_flight = rpc_list.read().unwrap()[0].track_in_flight();
+        poverty_list.read().unwrap()[0].start_cooldown();
+
+        // Act
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            create_test_settings_config(),
+            cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        assert_eq!(result["result"]["no_consensus"], false);
+        assert_eq!(result["result"]["cache_read_only"], false);
+        let active = result["result"]["active"].as_array().unwrap();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0]["url"], "http://example.com");
+        assert_eq!(active[0]["is_erroring"], false);
+        assert_eq!(active[0]["ms_since_last_healthy_check"], Null);
+        assert_eq!(active[0]["in_flight"], 1);
+        assert_eq!(active[0]["max_concurrency"], 4);
+        assert_eq!(active[0]["cooling_down"], false);
+        assert_eq!(active[0]["chain_id"], Null);
+
+        let poverty = result["result"]["poverty"].as_array().unwrap();
+        assert_eq!(poverty.len(), 1);
+        assert_eq!(poverty[0]["url"], "http://poverty.com");
+        assert_eq!(poverty[0]["is_erroring"], true);
+        assert_eq!(poverty[0]["last_head"], 100);
+        assert_eq!(poverty[0]["consecutive_failures"], 3);
+        assert_eq!(poverty[0]["cooling_down"], true);
+        assert!(poverty[0]["ms_since_last_healthy_check"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_run_health_check() {
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let health_trigger = create_test_health_trigger();
+        let tx = json!({ "id":1,"method": "blutgang_runHealthCheck" });
+
+        // Without a health check loop
+        let result = execute_method(
+            tx.clone(),
+            &rpc_list,
+            &poverty_list,
+            create_test_settings_config(),
+            create_test_cache(),
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &health_trigger,
+            &create_test_cache_args(),
+        )
+        .await;
+        assert!(matches!(result, Err(AdminError::HealthCheckDisabled)));
+
+        // A loop whose checks move every RPC to poverty
+        tokio::spawn({
+            let health_trigger = Arc::clone(&health_trigger);
+            let rpc_list = Arc::clone(&rpc_list);
+            let poverty_list = Arc::clone(&poverty_list);
+            async move {
+                let _attached = health_trigger.attach();
+                loop {
+                    health_trigger
+                        .wait(std::time::Duration::from_secs(3600))
+                        .await;
+                    health_trigger.start();
+                    let mut rpcs = std::mem::take(&mut *rpc_list.write().unwrap());
+                    poverty_list.write().unwrap().append(&mut rpcs);
+                    health_trigger.finish();
+                }
+            }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            create_test_settings_config(),
+            create_test_cache(),
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &health_trigger,
+            &create_test_cache_args(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result["result"]["active"], 0);
+        assert_eq!(result["result"]["poverty"], 2);
+    }
+
     #[tokio::test]
     async fn test_execute_method_invalid_method() {
         // Arrange
@@ -567,6 +1723,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -590,6 +1751,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -614,6 +1780,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -622,6 +1793,318 @@ mod tests {
         assert!(rpc_list.read().unwrap().len() == len + 1);
     }
 
+    #[tokio::test]
+    async fn test_execute_method_add_rpc() {
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let config = create_test_settings_config();
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(16);
+
+        let tx = json!({ "id":1,"method": "blutgang_addRpc", "params": ["http://new.com", "ws://new.com", 5, 70] });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            config.clone(),
+            create_test_cache(),
+            &incoming_tx,
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        let added = rpc_list.read().unwrap().last().unwrap().clone();
+        assert_eq!(added.url, "http://new.com");
+        assert_eq!(added.ws_url.as_deref(), Some("ws://new.com"));
+        assert_eq!(added.max_consecutive, 5);
+        assert_eq!(added.weight, 70);
+
+        // `ws_conn_manager` is told to open a connection to it
+        assert!(matches!(incoming_rx.try_recv(), Ok(WsconnMessage::Sync())));
+
+        // Urls already in either list are rejected
+        for url in ["http://new.com", "http://poverty.com"] {
+            let tx =
+                json!({ "id":1,"method": "blutgang_addRpc", "params": [url, "ws://new.com", 5] });
+            let result = execute_method(
+                tx,
+                &rpc_list,
+                &poverty_list,
+                config.clone(),
+                create_test_cache(),
+                &incoming_tx,
+                &[create_test_memory_cache()],
+                &create_test_metrics(),
+                &create_test_health_trigger(),
+                &create_test_cache_args(),
+            )
+            .await;
+            assert!(matches!(result, Err(AdminError::RpcExists)));
+        }
+
+        // A WS endpoint is required when WebSockets are enabled
+        let tx =
+            json!({ "id":1,"method": "blutgang_addRpc", "params": ["http://other.com", Null, 5] });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            config,
+            create_test_cache(),
+            &incoming_tx,
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
+        )
+        .await;
+        assert!(matches!(result, Err(AdminError::InvalidParams)));
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_remove_rpc() {
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let config = create_test_settings_config();
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(16);
+
+        for url in ["http://example.com", "http://poverty.com"] {
+            let tx = json!({ "id":1,"method": "blutgang_removeRpc", "params": [url] });
+            let result = execute_method(
+                tx,
+                &rpc_list,
+                &poverty_list,
+                config.clone(),
+                create_test_cache(),
+                &incoming_tx,
+                &[create_test_memory_cache()],
+                &create_test_metrics(),
+                &create_test_health_trigger(),
+                &create_test_cache_args(),
+            )
+            .await;
+
+            assert_eq!(result.unwrap()["result"], url);
+            assert!(matches!(incoming_rx.try_recv(), Ok(WsconnMessage::Sync())));
+        }
+        assert!(rpc_list.read().unwrap().is_empty());
+        assert!(poverty_list.read().unwrap().is_empty());
+
+        let tx = json!({ "id":1,"method": "blutgang_removeRpc", "params": ["http://example.com"] });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            config,
+            create_test_cache(),
+            &incoming_tx,
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
+        )
+        .await;
+        assert!(matches!(result, Err(AdminError::RpcNotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_set_weight() {
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let config = create_test_settings_config();
+        let incoming_tx = create_test_incoming_tx();
+
+        for (url, weight) in [("http://example.com", 70), ("http://poverty.com", 30)] {
+            let tx = json!({ "id":1,"method": "blutgang_setWeight", "params": [url, weight] });
+            let result = execute_method(
+                tx,
+                &rpc_list,
+                &poverty_list,
+                config.clone(),
+                create_test_cache(),
+                &incoming_tx,
+                &[create_test_memory_cache()],
+                &create_test_metrics(),
+                &create_test_health_trigger(),
+                &create_test_cache_args(),
+            )
+            .await;
+            assert!(result.is_ok());
+        }
+        assert_eq!(rpc_list.read().unwrap()[0].weight, 70);
+        assert_eq!(poverty_list.read().unwrap()[0].weight, 30);
+
+        let tx =
+            json!({ "id":1,"method": "blutgang_setWeight", "params": ["http://other.com", 5] });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            config.clone(),
+            create_test_cache(),
+            &incoming_tx,
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
+        )
+        .await;
+        assert!(matches!(result, Err(AdminError::RpcNotFound)));
+
+        let tx =
+            json!({ "id":1,"method": "blutgang_setWeight", "params": ["http://example.com", -1] });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            config,
+            create_test_cache(),
+            &incoming_tx,
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
+        )
+        .await;
+        assert!(matches!(result, Err(AdminError::ParseError)));
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_pause_resume_rpc() {
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let config = create_test_settings_config();
+        let incoming_tx = create_test_incoming_tx();
+
+        macro_rules! execute {
+            ($method:expr, $url:expr) => {
+                execute_method(
+                    json!({ "id":1,"method": $method, "params": [$url] }),
+                    &rpc_list,
+                    &poverty_list,
+                    config.clone(),
+                    create_test_cache(),
+                    &incoming_tx,
+                    &[create_test_memory_cache()],
+                    &create_test_metrics(),
+                    &create_test_health_trigger(),
+                    &create_test_cache_args(),
+                )
+                .await
+            };
+        }
+
+        // Paused RPCs wait in the poverty list
+        assert!(execute!("blutgang_pauseRpc", "http://example.com").is_ok());
+        assert!(rpc_list.read().unwrap().is_empty());
+        let paused = poverty_list.read().unwrap()[1].clone();
+        assert_eq!(paused.url, "http://example.com");
+        assert!(paused.status.paused);
+
+        let health = admin_health(
+            &rpc_list,
+            &poverty_list,
+            &create_test_health_trigger(),
+            &CacheWriteState::default(),
+        )
+        .unwrap();
+        assert_eq!(health["result"]["poverty"][1]["paused"], true);
+        assert_eq!(health["result"]["poverty"][0]["paused"], false);
+
+        // Only paused RPCs can be resumed
+        assert!(matches!(
+            execute!("blutgang_resumeRpc", "http://poverty.com"),
+            Err(AdminError::RpcNotFound)
+        ));
+        assert!(execute!("blutgang_resumeRpc", "http://example.com").is_ok());
+        assert_eq!(rpc_list.read().unwrap()[0].url, "http://example.com");
+        assert!(!rpc_list.read().unwrap()[0].status.paused);
+        assert!(!rpc_list.read().unwrap()[0].status.is_erroring);
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+
+        assert!(matches!(
+            execute!("blutgang_pauseRpc", "http://other.com"),
+            Err(AdminError::RpcNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_export_import_cache() {
+        let name = format!("blutgang_admin_dump_{}.zst", std::process::id());
+        let dump_dir = std::env::temp_dir();
+        let config = create_test_settings_config();
+        config.write().unwrap().admin.dump_dir = Some(dump_dir.to_str().unwrap().to_string());
+
+        let execute = |method: &str, param: &str, config: Arc<RwLock<Settings>>, cache: Arc<Db>| {
+            let tx = json!({ "id":1,"method": method, "params": [param] });
+            async move {
+                execute_method(
+                    tx,
+                    &create_test_rpc_list(),
+                    &create_test_poverty_list(),
+                    config,
+                    cache,
+                    &create_test_incoming_tx(),
+                    &[create_test_memory_cache()],
+                    &create_test_metrics(),
+                    &create_test_health_trigger(),
+                    &create_test_cache_args(),
+                )
+                .await
+            }
+        };
+
+        let cache = create_test_cache();
+        cache.insert(b"key", b"value").unwrap();
+
+        let result = execute("blutgang_exportCache", &name, config.clone(), cache.clone()).await;
+        assert!(result.unwrap()["result"]
+            .as_str()
+            .unwrap()
+            .starts_with("Exported 1 entries"));
+
+        // Existing dumps don't get overwritten
+        let result = execute("blutgang_exportCache", &name, config.clone(), cache).await;
+        assert!(matches!(result, Err(AdminError::RwError)));
+
+        let fresh = create_test_cache();
+        let result = execute("blutgang_importCache", &name, config.clone(), fresh.clone()).await;
+        assert!(result.is_ok());
+        assert_eq!(fresh.get(b"key").unwrap().unwrap(), b"value");
+
+        // Missing files are reported instead of panicking
+        let result = execute(
+            "blutgang_importCache",
+            "nonexistent.zst",
+            config.clone(),
+            fresh.clone(),
+        )
+        .await;
+        assert!(matches!(result, Err(AdminError::RwError)));
+
+        // Dumps stay inside `dump_dir`
+        for path in ["/etc/passwd", "../dump.zst", ""] {
+            let result =
+                execute("blutgang_importCache", path, config.clone(), fresh.clone()).await;
+            assert!(matches!(result, Err(AdminError::InvalidParams)));
+        }
+
+        // And are disabled without it
+        let result = execute(
+            "blutgang_exportCache",
+            &name,
+            create_test_settings_config(),
+            fresh,
+        )
+        .await;
+        assert!(matches!(result, Err(AdminError::DumpsDisabled)));
+
+        std::fs::remove_file(dump_dir.join(name)).unwrap();
+    }
+
     #[tokio::test]
     async fn test_execute_method_remove_from_rpc_list() {
         // Arrange
@@ -647,6 +2130,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache.clone(),
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -664,6 +2152,11 @@ mod tests {
             &binding,
             create_test_settings_config(),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -688,6 +2181,11 @@ mod tests {
             &create_test_poverty_list(),
             Arc::clone(&config),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -713,6 +2211,11 @@ mod tests {
             &create_test_poverty_list(),
             Arc::clone(&config),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -738,6 +2241,11 @@ mod tests {
             &create_test_poverty_list(),
             Arc::clone(&config),
             cache.clone(),
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
@@ -752,10 +2260,191 @@ mod tests {
             &create_test_poverty_list(),
             Arc::clone(&config),
             cache,
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
         )
         .await;
 
         // Assert
         assert!(result.is_ok());
     }
+    #[tokio::test]
+    async fn test_execute_method_flush_and_purge_method() {
+        // Arrange
+        let cache = create_test_cache();
+        setup_data(Arc::clone(&cache), 0);
+        let metadata = cache.len();
+        cache.insert(b"response", b"0x1").unwrap();
+        cache
+            .insert(method_index_key("eth_call", b"response"), b"")
+            .unwrap();
+        cache.insert(b"other", b"0x2").unwrap();
+        let memory_cache = create_test_memory_cache();
+        memory_cache.insert(b"response", IVec::from(b"0x1"), None);
+
+        // Act
+        let purged = execute_method(
+            json!({ "id":1,"method": "blutgang_purgeMethod", "params": ["eth_call"] }),
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            Arc::clone(&cache),
+            &create_test_incoming_tx(),
+            std::slice::from_ref(&memory_cache),
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
+        )
+        .await
+        .unwrap();
+        let flushed = execute_method(
+            json!({ "id":1,"method": "blutgang_flushCache" }),
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            Arc::clone(&cache),
+            &create_test_incoming_tx(),
+            std::slice::from_ref(&memory_cache),
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &create_test_cache_args(),
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        assert_eq!(purged["result"], 1);
+        assert_eq!(memory_cache.entries(), 0);
+        assert_eq!(flushed["result"], 1);
+        assert_eq!(cache.len(), metadata);
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_stats() {
+        // Arrange
+        let metrics = create_test_metrics();
+        metrics.count_cache_hit("eth_call");
+        metrics.count_cache_miss("eth_call");
+        let cache_args = create_test_cache_args();
+        cache_args.cache_state.count_deduped_head_write();
+
+        async fn stats(
+            metrics: &Arc<Metrics>,
+            cache_args: &CacheArgs,
+            params: Value,
+        ) -> Result<Value, AdminError> {
+            execute_method(
+                json!({ "id":1,"method": "blutgang_stats", "params": params }),
+                &create_test_rpc_list(),
+                &create_test_poverty_list(),
+                create_test_settings_config(),
+                create_test_cache(),
+                &create_test_incoming_tx(),
+                &[create_test_memory_cache()],
+                metrics,
+                &create_test_health_trigger(),
+                cache_args,
+            )
+            .await
+        }
+
+        // Act & Assert
+        let result = stats(&metrics, &cache_args, json!([])).await.unwrap();
+        assert_eq!(result["result"]["cache_hit_rate"], 0.5);
+        assert_eq!(result["result"]["deduped_head_writes"], 1);
+        let result = stats(&metrics, &cache_args, json!([true])).await.unwrap();
+        assert_eq!(result["result"]["cache_hits"], 1);
+        let result = stats(&metrics, &cache_args, json!([])).await.unwrap();
+        assert_eq!(result["result"]["cache_hits"], 0);
+        assert!(matches!(
+            stats(&metrics, &cache_args, json!(["yes"])).await,
+            Err(AdminError::ParseError)
+        ));
+        assert!(matches!(
+            stats(&metrics, &cache_args, json!([true, false])).await,
+            Err(AdminError::InvalidLen)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_warm() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x6080"}),
+        )
+        .await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            mock.url.clone(),
+            None,
+            0,
+            0,
+            1.0,
+        )]));
+        let (_finalized_tx, finalized_rx) = watch::channel(10);
+        let cache_args = CacheArgs {
+            finalized_rx,
+            ..create_test_cache_args()
+        };
+
+        let tx = json!({
+            "id": 1,
+            "method": "blutgang_warm",
+            "params": [
+                {"method": "eth_getCode", "params": ["0x01", "0xa"]},
+                "not a request",
+            ],
+        });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            create_test_cache(),
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &cache_args,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(result["result"]["warmed"], 1);
+        assert_eq!(result["result"]["failed"], 1);
+        assert_eq!(result["result"]["results"][0], json!({ "ok": true }));
+        assert_eq!(result["result"]["results"][1]["ok"], false);
+        assert!(!cache_args.cache.is_empty());
+
+        // Uses the `[method_cache]` in the config, which can be reloaded after startup
+        let config = create_test_settings_config();
+        config.write().unwrap().method_cache = Arc::new(MethodCache::new(
+            [("eth_gasPrice".to_string(), 60)].into(),
+            Default::default(),
+        ));
+        let tx = json!({
+            "id": 1,
+            "method": "blutgang_warm",
+            "params": [{"method": "eth_gasPrice", "params": []}],
+        });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &create_test_poverty_list(),
+            config,
+            create_test_cache(),
+            &create_test_incoming_tx(),
+            &[create_test_memory_cache()],
+            &create_test_metrics(),
+            &create_test_health_trigger(),
+            &cache_args,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(result["result"]["warmed"], 1);
+        assert_eq!(cache_args.cache.scan_prefix(EXPIRY_PREFIX).count(), 1);
+    }
 }
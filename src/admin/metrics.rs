@@ -0,0 +1,723 @@
+// Prometheus metrics served at `/metrics` on the admin port.
+//
+// Request counters live here and get bumped by the balancer. Everything
+// about individual RPCs is read from the RPC lists of every chain when we get
+// scraped, cache counters from their cache write state and the queue depth from
+// admission control.
+//
+// We also keep windowed stats (hit rates and upstream latency histograms)
+// for `blutgang_stats`, so you can get a quick snapshot without Prometheus.
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+use crate::{
+    admin::chains::{
+        AdminChain,
+        AdminChains,
+    },
+    balancer::admission::AdmissionControl,
+    Rpc,
+};
+
+// Content type of the Prometheus text exposition format
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// Stats are kept over two consecutive windows of this length, so snapshots
+// always cover between one and two windows worth of traffic.
+const STATS_WINDOW: Duration = Duration::from_secs(300);
+
+// Methods come from clients, so only this many get their own stats per window.
+// Methods past that are counted under `OTHER_METHODS`.
+const MAX_STATS_METHODS: usize = 256;
+const OTHER_METHODS: &str = "other";
+
+// Upper bounds of the latency histogram buckets, in ms. Anything slower
+// lands in an overflow bucket.
+const LATENCY_BUCKETS_MS: [u64; 14] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000, 30000,
+];
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    // Requests that got their response from an identical in-flight request
+    coalesced_requests: AtomicU64,
+    shadow_requests: AtomicU64,
+    shadow_mismatches: AtomicU64,
+    stats: Mutex<Stats>,
+}
+
+impl Metrics {
+    pub fn count_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_cache_hit(&self, method: &str) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        self.stats.lock().unwrap().current().count(method, true);
+    }
+
+    pub fn count_cache_miss(&self, method: &str) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        self.stats.lock().unwrap().current().count(method, false);
+    }
+
+    pub fn count_coalesced(&self) {
+        self.coalesced_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Count a shadow request, and whether its response didn't match the primary's
+    pub fn count_shadow(&self, mismatch: bool) {
+        self.shadow_requests.fetch_add(1, Ordering::Relaxed);
+        if mismatch {
+            self.shadow_mismatches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Record how long a request to the RPC at `url` took
+    pub fn record_latency(&self, url: &str, time: Duration) {
+        self.stats
+            .lock()
+            .unwrap()
+            .current()
+            .latency
+            .entry(url.to_string())
+            .or_default()
+            .record(time);
+    }
+
+    // Snapshot of the hit rates and latency percentiles for `blutgang_stats`.
+    //
+    // If `reset` is set, the next snapshot only covers what happens after this one.
+    pub fn stats(&self, reset: bool) -> Value {
+        let mut stats = self.stats.lock().unwrap();
+        stats.rotate();
+
+        let mut window = stats.previous.clone();
+        window.merge(&stats.current);
+
+        let methods: serde_json::Map<String, Value> = window
+            .methods
+            .iter()
+            .map(|(method, (hits, misses))| {
+                (
+                    method.clone(),
+                    json!({
+                        "hits": hits,
+                        "misses": misses,
+                        "hit_rate": hit_rate(*hits, *misses),
+                    }),
+                )
+            })
+            .collect();
+        let latency: serde_json::Map<String, Value> = window
+            .latency
+            .iter()
+            .map(|(url, histogram)| {
+                (
+                    url.clone(),
+                    json!({
+                        "count": histogram.count(),
+                        "p50_ms": histogram.percentile(0.50),
+                        "p95_ms": histogram.percentile(0.95),
+                        "p99_ms": histogram.percentile(0.99),
+                    }),
+                )
+            })
+            .collect();
+
+        let rx = json!({
+            "window_secs": stats.started.elapsed().as_secs(),
+            "cache_hits": window.hits,
+            "cache_misses": window.misses,
+            "cache_hit_rate": hit_rate(window.hits, window.misses),
+            "methods": methods,
+            "latency": latency,
+        });
+
+        if reset {
+            *stats = Stats::default();
+        }
+
+        rx
+    }
+
+    // Render all metrics of every chain in the Prometheus text format.
+    //
+    // Gauges and RPC metrics of other chains get a `chain` label. The default
+    // chain goes without one, which Prometheus treats the same as an empty label.
+    pub fn render(&self, chains: &AdminChains, admission: &AdmissionControl) -> String {
+        let mut out = String::new();
+
+        metric(
+            &mut out,
+            "blutgang_requests_total",
+            "counter",
+            "Requests received.",
+            self.requests.load(Ordering::Relaxed),
+        );
+        metric(
+            &mut out,
+            "blutgang_cache_hits_total",
+            "counter",
+            "Requests answered from the cache.",
+            self.cache_hits.load(Ordering::Relaxed),
+        );
+        metric(
+            &mut out,
+            "blutgang_cache_misses_total",
+            "counter",
+            "Requests not found in the cache.",
+            self.cache_misses.load(Ordering::Relaxed),
+        );
+        metric(
+            &mut out,
+            "blutgang_coalesced_requests_total",
+            "counter",
+            "Requests that waited on an identical in-flight request instead of being forwarded.",
+            self.coalesced_requests.load(Ordering::Relaxed),
+        );
+        metric(
+            &mut out,
+            "blutgang_shadow_requests_total",
+            "counter",
+            "Requests also sent to a second RPC to compare responses.",
+            self.shadow_requests.load(Ordering::Relaxed),
+        );
+        metric(
+            &mut out,
+            "blutgang_shadow_mismatches_total",
+            "counter",
+            "Shadow requests whose response didn't match the primary response.",
+            self.shadow_mismatches.load(Ordering::Relaxed),
+        );
+        per_chain(
+            &mut out,
+            "blutgang_head_cache_deduped_writes_total",
+            "counter",
+            "Head cache writes skipped because the same response was already cached.",
+            chains,
+            |chain| chain.cache_args.cache_state.deduped_head_writes(),
+        );
+        per_chain(
+            &mut out,
+            "blutgang_cache_read_only",
+            "gauge",
+            "1 if cache writes are stopped because the disk is full.",
+            chains,
+            |chain| chain.cache_args.cache_state.is_read_only() as u64,
+        );
+        metric(
+            &mut out,
+            "blutgang_admission_queued",
+            "gauge",
+            "Requests waiting for a slot under max_concurrent_requests.",
+            admission.queued() as u64,
+        );
+        per_chain(
+            &mut out,
+            "blutgang_active_rpcs",
+            "gauge",
+            "RPCs in the active pool.",
+            chains,
+            |chain| chain.rpc_list.read().unwrap().len() as u64,
+        );
+        per_chain(
+            &mut out,
+            "blutgang_poverty_rpcs",
+            "gauge",
+            "RPCs in the poverty list.",
+            chains,
+            |chain| chain.poverty_list.read().unwrap().len() as u64,
+        );
+
+        // RPCs move between the lists, so whether they're in poverty is its own
+        // gauge instead of a label that would split their counters
+        let lists: Vec<_> = chains
+            .iter()
+            .map(|(name, chain)| {
+                (
+                    name,
+                    chain.rpc_list.read().unwrap(),
+                    chain.poverty_list.read().unwrap(),
+                )
+            })
+            .collect();
+        let rpcs: Vec<(&str, &Rpc, bool)> = lists
+            .iter()
+            .flat_map(|(name, rpc_list, poverty_list)| {
+                rpc_list
+                    .iter()
+                    .map(|rpc| (*name, rpc, false))
+                    .chain(poverty_list.iter().map(|rpc| (*name, rpc, true)))
+            })
+            .collect();
+
+        per_rpc(
+            &mut out,
+            "blutgang_rpc_requests_total",
+            "counter",
+            "Requests forwarded to an RPC.",
+            &rpcs,
+            |rpc, _| rpc.requests(),
+        );
+        per_rpc(
+            &mut out,
+            "blutgang_rpc_errors_total",
+            "counter",
+            "Forwarded requests that failed or timed out.",
+            &rpcs,
+            |rpc, _| rpc.errors(),
+        );
+        per_rpc(
+            &mut out,
+            "blutgang_rpc_head_lag",
+            "gauge",
+            "Blocks behind the agreed head at the last health check.",
+            &rpcs,
+            |rpc, _| rpc.status.head_lag,
+        );
+        per_rpc(
+            &mut out,
+            "blutgang_rpc_in_poverty",
+            "gauge",
+            "1 if the RPC is in the poverty list.",
+            &rpcs,
+            |_, in_poverty| in_poverty as u64,
+        );
+
+        out
+    }
+}
+
+#[derive(Debug)]
+struct Stats {
+    // When `previous` started, or `current` if there is no `previous` yet
+    started: Instant,
+    // When `current` started
+    current_started: Instant,
+    previous: Window,
+    current: Window,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        let now = Instant::now();
+        Stats {
+            started: now,
+            current_started: now,
+            previous: Window::default(),
+            current: Window::default(),
+        }
+    }
+}
+
+impl Stats {
+    // Get the current window, rotating it out first if it's full
+    fn current(&mut self) -> &mut Window {
+        self.rotate();
+        &mut self.current
+    }
+
+    fn rotate(&mut self) {
+        let elapsed = self.current_started.elapsed();
+        if elapsed < STATS_WINDOW {
+            return;
+        }
+
+        // We haven't rotated in over a window, so the current one is stale too
+        if elapsed >= STATS_WINDOW * 2 {
+            *self = Stats::default();
+            return;
+        }
+
+        self.previous = std::mem::take(&mut self.current);
+        self.started = self.current_started;
+        self.current_started = Instant::now();
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Window {
+    hits: u64,
+    misses: u64,
+    // Method -> (hits, misses), up to `MAX_STATS_METHODS` methods
+    methods: HashMap<String, (u64, u64)>,
+    // RPC URL -> upstream latency
+    latency: HashMap<String, Histogram>,
+}
+
+impl Window {
+    fn count(&mut self, method: &str, hit: bool) {
+        let method = match self.methods.contains_key(method)
+            || self.methods.len() < MAX_STATS_METHODS
+        {
+            true => method,
+            false => OTHER_METHODS,
+        };
+        let counts = self.methods.entry(method.to_string()).or_default();
+
+        if hit {
+            self.hits += 1;
+            counts.0 += 1;
+        } else {
+            self.misses += 1;
+            counts.1 += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &Window) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        for (method, (hits, misses)) in &other.methods {
+            let counts = self.methods.entry(method.clone()).or_default();
+            counts.0 += hits;
+            counts.1 += misses;
+        }
+        for (url, histogram) in &other.latency {
+            self.latency
+                .entry(url.clone())
+                .or_default()
+                .merge(histogram);
+        }
+    }
+}
+
+// Bucketed latency histogram. Percentiles are reported as the upper bound
+// of the bucket they fall in, which is plenty for a quick look.
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+impl Histogram {
+    fn record(&mut self, time: Duration) {
+        let ms = time.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+    }
+
+    fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    // Returns `None` if nothing was recorded. Requests slower than the
+    // last bucket report its bound.
+    fn percentile(&self, percentile: f64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((count as f64 * percentile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, bucket_count) in self.buckets.iter().enumerate() {
+            seen += bucket_count;
+            if seen >= rank {
+                return Some(LATENCY_BUCKETS_MS[bucket.min(LATENCY_BUCKETS_MS.len() - 1)]);
+            }
+        }
+
+        LATENCY_BUCKETS_MS.last().copied()
+    }
+}
+
+fn hit_rate(hits: u64, misses: u64) -> Option<f64> {
+    match hits + misses {
+        0 => None,
+        total => Some(hits as f64 / total as f64),
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Labels of a metric of the chain named `chain`, starting with `{` if there are any
+fn labels(chain: &str, url: Option<&str>) -> String {
+    let mut labels = Vec::new();
+    if !chain.is_empty() {
+        labels.push(format!("chain=\"{}\"", escape_label(chain)));
+    }
+    if let Some(url) = url {
+        labels.push(format!("url=\"{}\"", escape_label(url)));
+    }
+
+    match labels.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", labels.join(",")),
+    }
+}
+
+fn per_chain(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    chains: &AdminChains,
+    value: impl Fn(&AdminChain) -> u64,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (chain_name, chain) in chains.iter() {
+        let _ = writeln!(out, "{}{} {}", name, labels(chain_name, None), value(chain));
+    }
+}
+
+fn per_rpc(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    rpcs: &[(&str, &Rpc, bool)],
+    value: impl Fn(&Rpc, bool) -> u64,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (chain, rpc, in_poverty) in rpcs {
+        let _ = writeln!(
+            out,
+            "{}{} {}",
+            name,
+            labels(chain, Some(&rpc.url)),
+            value(rpc, *in_poverty)
+        );
+    }
+}
+
+// Label values need backslashes, quotes and newlines escaped
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        balancer::processing::CacheArgs,
+        health::{
+            readiness::Readiness,
+            trigger::HealthTrigger,
+        },
+        Settings,
+    };
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            Arc,
+            RwLock,
+        },
+    };
+    use tokio::sync::mpsc;
+
+    fn test_chain(rpc_list: Vec<Rpc>, poverty_list: Vec<Rpc>) -> AdminChain {
+        AdminChain {
+            rpc_list: Arc::new(RwLock::new(rpc_list)),
+            poverty_list: Arc::new(RwLock::new(poverty_list)),
+            health_trigger: Arc::new(HealthTrigger::default()),
+            incoming_tx: mpsc::channel(16).0,
+            cache_args: CacheArgs::default(),
+            readiness: Arc::new(Readiness::new(false)),
+            config: Arc::new(RwLock::new(Settings::default())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_render_metrics() {
+        let metrics = Metrics::default();
+        metrics.count_request();
+        metrics.count_request();
+        metrics.count_cache_hit("eth_call");
+        metrics.count_cache_miss("eth_call");
+        metrics.count_coalesced();
+        metrics.count_shadow(false);
+        metrics.count_shadow(true);
+
+        let active = Rpc::new("http://active".to_string(), None, 0, 0, 1.0);
+        active.count_request();
+        active.count_request();
+        active.count_error();
+        let mut poor = Rpc::new("http://poor".to_string(), None, 0, 0, 1.0);
+        poor.status.head_lag = 12;
+
+        let chain = test_chain(vec![active], vec![poor]);
+        chain.cache_args.cache_state.count_deduped_head_write();
+        let chains = AdminChains {
+            default: chain,
+            chains: BTreeMap::from([(
+                "base".to_string(),
+                test_chain(
+                    vec![
+                        Rpc::new("http://base".to_string(), None, 0, 0, 1.0),
+                        Rpc::new("http://base2".to_string(), None, 0, 0, 1.0),
+                    ],
+                    vec![],
+                ),
+            )]),
+        };
+
+        // Hold the only slot so the next request has to queue
+        let admission = Arc::new(AdmissionControl::new(1));
+        let _permit = admission.acquire(Default::default()).await;
+        let queued = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.acquire(Default::default()).await }
+        });
+        while admission.queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let rendered = metrics.render(&chains, &admission);
+        queued.abort();
+
+        for line in [
+            "# TYPE blutgang_requests_total counter",
+            "blutgang_requests_total 2",
+            "blutgang_cache_hits_total 1",
+            "blutgang_cache_misses_total 1",
+            "blutgang_coalesced_requests_total 1",
+            "blutgang_shadow_requests_total 2",
+            "blutgang_shadow_mismatches_total 1",
+            "blutgang_head_cache_deduped_writes_total 1",
+            "blutgang_cache_read_only 0",
+            "blutgang_admission_queued 1",
+            "blutgang_active_rpcs 1",
+            "blutgang_poverty_rpcs 1",
+            "blutgang_rpc_requests_total{url=\"http://active\"} 2",
+            "blutgang_rpc_errors_total{url=\"http://active\"} 1",
+            "blutgang_rpc_head_lag{url=\"http://poor\"} 12",
+            "blutgang_rpc_in_poverty{url=\"http://active\"} 0",
+            "blutgang_rpc_in_poverty{url=\"http://poor\"} 1",
+            // Other chains are labeled with their name
+            "blutgang_head_cache_deduped_writes_total{chain=\"base\"} 0",
+            "blutgang_active_rpcs{chain=\"base\"} 2",
+            "blutgang_poverty_rpcs{chain=\"base\"} 0",
+            "blutgang_rpc_requests_total{chain=\"base\",url=\"http://base2\"} 0",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "missing `{}`", line);
+        }
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.percentile(0.5), None);
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_millis(3));
+        }
+        for _ in 0..9 {
+            histogram.record(Duration::from_millis(150));
+        }
+        histogram.record(Duration::from_secs(60));
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.percentile(0.50), Some(5));
+        assert_eq!(histogram.percentile(0.95), Some(200));
+        assert_eq!(histogram.percentile(0.99), Some(200));
+        assert_eq!(histogram.percentile(1.0), Some(30000));
+    }
+
+    #[test]
+    fn test_stats() {
+        let metrics = Metrics::default();
+        metrics.count_cache_hit("eth_call");
+        metrics.count_cache_hit("eth_call");
+        metrics.count_cache_hit("eth_getBlockByNumber");
+        metrics.count_cache_miss("eth_call");
+        metrics.record_latency("http://a", Duration::from_millis(40));
+
+        let stats = metrics.stats(false);
+        assert_eq!(stats["cache_hits"], 3);
+        assert_eq!(stats["cache_misses"], 1);
+        assert_eq!(stats["cache_hit_rate"], 0.75);
+        assert_eq!(stats["methods"]["eth_call"]["hits"], 2);
+        assert_eq!(stats["methods"]["eth_call"]["misses"], 1);
+        assert_eq!(stats["methods"]["eth_getBlockByNumber"]["hit_rate"], 1.0);
+        assert_eq!(stats["latency"]["http://a"]["count"], 1);
+        assert_eq!(stats["latency"]["http://a"]["p99_ms"], 50);
+
+        // Only resetting drops what we had, after returning it
+        assert_eq!(metrics.stats(false)["cache_hits"], 3);
+        assert_eq!(metrics.stats(true)["cache_hits"], 3);
+
+        let stats = metrics.stats(false);
+        assert_eq!(stats["cache_hits"], 0);
+        assert_eq!(stats["cache_hit_rate"], Value::Null);
+        assert_eq!(stats["latency"], json!({}));
+
+        // Prometheus counters aren't windowed
+        assert_eq!(metrics.cache_hits.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_stats_methods_bounded() {
+        let mut window = Window::default();
+        for i in 0..MAX_STATS_METHODS + 10 {
+            window.count(&format!("method_{}", i), true);
+        }
+        window.count("method_0", false);
+
+        // Methods past the limit share a bucket, ones we know keep their own
+        assert_eq!(window.methods.len(), MAX_STATS_METHODS + 1);
+        assert_eq!(window.methods[OTHER_METHODS], (10, 0));
+        assert_eq!(window.methods["method_0"], (1, 1));
+    }
+
+    #[test]
+    fn test_stats_window_rotation() {
+        let mut stats = Stats::default();
+        stats.current().count("eth_call", true);
+
+        // One window ago, the current window becomes the previous one
+        stats.current_started -= STATS_WINDOW;
+        stats.current().count("eth_call", false);
+        assert_eq!(stats.previous.hits, 1);
+        assert_eq!(stats.current.misses, 1);
+
+        // Two windows ago, everything is stale
+        stats.current_started -= STATS_WINDOW * 2;
+        stats.rotate();
+        assert_eq!(stats.previous.hits + stats.previous.misses, 0);
+        assert_eq!(stats.current.hits + stats.current.misses, 0);
+    }
+}
@@ -1,4 +1,6 @@
 mod accept;
+pub mod chains;
 mod error;
 pub mod listener;
 mod methods;
+pub mod metrics;
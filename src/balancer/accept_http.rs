@@ -1,24 +1,123 @@
 use crate::{
+    admin::metrics::Metrics,
     balancer::{
+        admission::{
+            request_priority,
+            AdmissionControl,
+            Priority,
+            PRIORITY_HEADER,
+        },
+        auth::{
+            is_authorized,
+            unauthorized_response,
+        },
+        body_limit::{
+            bad_body_response,
+            exceeds_content_length,
+            payload_too_large_response,
+        },
+        compression::{
+            compress_response,
+            negotiate_encoding,
+        },
+        cors::{
+            add_cors_headers,
+            allowed_origin,
+            preflight_response,
+        },
         format::{
+            cache_key,
+            get_block_number_from_request,
             incoming_to_value,
+            is_future_block,
+            reads_state,
             replace_block_tags,
+            BlockParams,
+        },
+        hedge::{
+            is_idempotent,
+            send_hedged,
+            HedgeWinner,
         },
+        logs_shards::sharded_logs,
+        memory_cache::MemoryCache,
+        method_filter::MethodFilter,
+        method_routing::MethodRouting,
         processing::{
+            cache_lookup_or_miss,
+            cache_lookup_stale,
             cache_querry,
+            jsonrpc_error,
+            logs_chunk_count,
+            logs_range_to_split,
+            mark_rpc_error,
+            split_logs,
             update_rpc_latency,
+            update_rpc_latency_ema,
+            upstream_error_object,
+            validate_request,
             CacheArgs,
+            CacheWriteState,
+            ReorgWindow,
+            CACHE_SCHEMA_VERSION,
+        },
+        rate_limit::{
+            rate_limited_response,
+            RateLimiter,
+        },
+        selection::{
+            cache_rules::MethodCache,
+            select::{
+                pick_filtered,
+                pick_keyed,
+            },
+        },
+        single_flight::{
+            Flight,
+            FlightOutcome,
+            SingleFlight,
+        },
+        sticky::StickySessions,
+        tx_broadcast::{
+            broadcast_tx,
+            is_known_tx_error,
+        },
+        tx_replay::{
+            Reservation,
+            TxReplayCache,
         },
-        selection::select::pick,
+        validation::validate_response,
     },
-    cache_error,
+    config::types::{
+        FutureBlockBehavior,
+        SelectionStrategy,
+    },
+    future_block,
+    health::{
+        head_cache::HeadCache,
+        readiness::Readiness,
+    },
+    invalid_request,
+    log::access::{
+        params_hash,
+        AccessEntry,
+        AccessLog,
+    },
+    no_archive_rpc,
+    no_routed_rpc,
     no_rpc_available,
-    print_cache_error,
-    rpc::types::Rpc,
+    response_too_large,
+    rpc::{
+        error::RpcError,
+        types::Rpc,
+    },
     rpc_response,
     timed_out,
+    upstream_error,
+    warn_limited,
     websocket::{
         server::serve_websocket,
+        subscription_manager::SubscriptionQueue,
         types::{
             IncomingResponse,
             SubscriptionData,
@@ -35,7 +134,10 @@ use tokio::sync::{
     watch,
 };
 
-use serde_json::Value;
+use serde_json::{
+    json,
+    Value,
+};
 use simd_json;
 
 // Select either blake3 or xxhash based on the features
@@ -47,10 +149,22 @@ use xxhash_rust::xxh3::xxh3_64;
 #[cfg(feature = "xxhash")]
 use zerocopy::AsBytes; // Impls AsBytes trait for u64
 
-use http_body_util::Full;
+use futures::stream::{
+    self,
+    StreamExt,
+};
+use http_body_util::{
+    BodyExt,
+    Full,
+};
 use hyper::{
     body::Bytes,
-    header::HeaderValue,
+    header::{
+        HeaderValue,
+        ACCEPT_ENCODING,
+        AUTHORIZATION,
+        ORIGIN,
+    },
     Request,
 };
 use hyper_tungstenite::{
@@ -58,14 +172,29 @@ use hyper_tungstenite::{
     upgrade,
 };
 
-use sled::Db;
+use rand::Rng;
+
+use sled::Tree;
 
 use tokio::time::timeout;
 
+use tracing::{
+    error,
+    field,
+    info,
+    info_span,
+    warn,
+    Instrument,
+    Span,
+};
+
 use std::{
-    collections::BTreeMap,
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
     convert::Infallible,
-    println,
+    net::SocketAddr,
     sync::{
         Arc,
         RwLock,
@@ -76,25 +205,59 @@ use std::{
     },
 };
 
+// Tells clients if their response was a cache `hit`, `miss` or served `local`ly
+pub const CACHE_STATUS_HEADER: &str = "x-blutgang-cache";
+// Tells clients which RPC served a cache miss, if `expose_node_header` is enabled
+pub const NODE_HEADER: &str = "x-blutgang-node";
+// Lets clients set a shorter `request_deadline_ms` for a request
+pub const TIMEOUT_HEADER: &str = "x-blutgang-timeout-ms";
+
 #[derive(Debug, Clone)]
 pub struct ConnectionParams {
     pub rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     pub channels: RequestChannels,
     pub named_numbers: Arc<RwLock<NamedBlocknumbers>>,
-    pub head_cache: Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    pub head_cache: Arc<HeadCache>,
+    pub memory_cache: Arc<MemoryCache>,
     pub sub_data: Arc<SubscriptionData>,
-    pub cache: Arc<Db>,
+    pub cache: Arc<Tree>,
+    pub cache_state: Arc<CacheWriteState>,
+    pub reorg_window: Arc<ReorgWindow>,
+    pub tx_replay: Arc<TxReplayCache>,
+    pub sticky: Arc<StickySessions>,
+    pub admission: Arc<AdmissionControl>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub readiness: Arc<Readiness>,
+    pub single_flight: Arc<SingleFlight>,
+    pub metrics: Arc<Metrics>,
     pub config: Arc<RwLock<Settings>>,
+    // Address of the client, if known
+    pub peer: Option<SocketAddr>,
+    // Other chains we serve, by the path prefix they're served at
+    pub chains: Arc<BTreeMap<String, ConnectionParams>>,
+    // Shared by every chain
+    pub access_log: Arc<AccessLog>,
 }
 
 impl ConnectionParams {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
         channels: RequestChannels,
         named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
-        head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+        head_cache: &Arc<HeadCache>,
+        memory_cache: &Arc<MemoryCache>,
         sub_data: &Arc<SubscriptionData>,
-        cache: &Arc<Db>,
+        cache: &Arc<Tree>,
+        cache_state: &Arc<CacheWriteState>,
+        reorg_window: &Arc<ReorgWindow>,
+        tx_replay: &Arc<TxReplayCache>,
+        sticky: &Arc<StickySessions>,
+        admission: &Arc<AdmissionControl>,
+        rate_limiter: &Arc<RateLimiter>,
+        readiness: &Arc<Readiness>,
+        single_flight: &Arc<SingleFlight>,
+        metrics: &Arc<Metrics>,
         config: &Arc<RwLock<Settings>>,
     ) -> Self {
         ConnectionParams {
@@ -102,29 +265,122 @@ impl ConnectionParams {
             channels,
             named_numbers: named_numbers.clone(),
             head_cache: head_cache.clone(),
+            memory_cache: memory_cache.clone(),
             sub_data: sub_data.clone(),
             cache: cache.clone(),
+            cache_state: cache_state.clone(),
+            reorg_window: reorg_window.clone(),
+            tx_replay: tx_replay.clone(),
+            sticky: sticky.clone(),
+            admission: admission.clone(),
+            rate_limiter: rate_limiter.clone(),
+            readiness: readiness.clone(),
+            single_flight: single_flight.clone(),
+            metrics: metrics.clone(),
             config: config.clone(),
+            peer: None,
+            chains: Arc::new(BTreeMap::new()),
+            access_log: Arc::new(AccessLog::default()),
+        }
+    }
+
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    pub fn with_chains(mut self, chains: &Arc<BTreeMap<String, ConnectionParams>>) -> Self {
+        self.chains = chains.clone();
+        self
+    }
+
+    pub fn with_access_log(mut self, access_log: &Arc<AccessLog>) -> Self {
+        self.access_log = access_log.clone();
+        self
+    }
+
+    // Get the params of the chain serving requests to `path`.
+    //
+    // Chains are served at `/<name>`, and the default chain at `/`. Without any
+    // chains, every path goes to the default chain. Returns `None` for unknown chains.
+    pub fn route(self, path: &str) -> Option<ConnectionParams> {
+        if self.chains.is_empty() {
+            return Some(self);
+        }
+
+        let name = path
+            .trim_start_matches('/')
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        if name.is_empty() {
+            return Some(self);
         }
+
+        self.chains.get(name).map(|chain| {
+            ConnectionParams {
+                peer: self.peer,
+                access_log: self.access_log.clone(),
+                ..chain.clone()
+            }
+        })
     }
 }
 
 struct RequestParams {
     ttl: u128,
+    method_timeouts: Arc<HashMap<String, u128>>,
     max_retries: u32,
+    future_blocks: FutureBlockBehavior,
+    block_params: Arc<BlockParams>,
+    method_cache: Arc<MethodCache>,
+    method_filter: Arc<MethodFilter>,
+    method_routing: Arc<MethodRouting>,
+    priority: Priority,
+    hedge_delay: Duration,
+    selection_strategy: SelectionStrategy,
+    validate_responses: bool,
+    serve_stale_on_error: bool,
+    shadow_sample_rate: f64,
+    shard_logs_cache: bool,
+    max_logs_range: u64,
+    max_logs_chunks: u64,
+    broadcast_transactions: bool,
+    serve_block_number_locally: bool,
+    serve_named_blocks_locally: bool,
+    archive_threshold: u64,
+    no_archive_message: Arc<str>,
+    max_request_bytes: usize,
+    max_batch_size: usize,
+    batch_concurrency: usize,
+    strict_jsonrpc: bool,
+    // Session to keep on the same RPC, if sticky sessions are enabled
+    session: Option<String>,
+    // When to give up on the request, if it has a deadline
+    deadline: Option<Instant>,
+}
+
+impl RequestParams {
+    // How long to wait for an RPC to respond to `method`
+    fn ttl(&self, method: &str) -> u128 {
+        self.method_timeouts
+            .get(method)
+            .copied()
+            .unwrap_or(self.ttl)
+    }
 }
 
 #[derive(Debug)]
 pub struct RequestChannels {
     pub finalized_rx: Arc<watch::Receiver<u64>>,
-    pub incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
+    pub incoming_tx: mpsc::Sender<WsconnMessage>,
     pub outgoing_rx: broadcast::Receiver<IncomingResponse>,
 }
 
 impl RequestChannels {
     pub fn new(
         finalized_rx: Arc<watch::Receiver<u64>>,
-        incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
+        incoming_tx: mpsc::Sender<WsconnMessage>,
         outgoing_rx: broadcast::Receiver<IncomingResponse>,
     ) -> Self {
         Self {
@@ -150,10 +406,12 @@ impl Clone for RequestChannels {
 macro_rules! accept {
     (
         $io:expr,
-        $connection_params:expr
+        $http1_builder:expr,
+        $connection_params:expr,
+        $shutdown_rx:expr
     ) => {
         // Bind the incoming connection to our service
-        if let Err(err) = http1::Builder::new()
+        let connection = $http1_builder
             // `service_fn` converts our function in a `Service`
             .serve_connection(
                 $io,
@@ -162,10 +420,44 @@ macro_rules! accept {
                     response
                 }),
             )
-            .with_upgrades()
-            .await
-        {
-            println!("\x1b[31mErr:\x1b[0m Error serving connection: {:?}", err);
+            .with_upgrades();
+        tokio::pin!(connection);
+
+        // When shutting down, finish the request being served and close the connection
+        let mut shutdown_rx: tokio::sync::watch::Receiver<bool> = $shutdown_rx;
+        let result = tokio::select! {
+            result = connection.as_mut() => result,
+            Ok(()) = async { shutdown_rx.wait_for(|shutdown| *shutdown).await.map(|_| ()) } => {
+                connection.as_mut().graceful_shutdown();
+                connection.await
+            }
+        };
+
+        if let Err(err) = result {
+            error!("Error serving connection: {:?}", err);
+        }
+    };
+}
+
+// Serve the last cached response to `$tx`, even if it expired, instead of the
+// error `$response` when every RPC failed it, if `serve_stale_on_error` is enabled
+macro_rules! or_stale {
+    (
+        $response:expr,
+        $serve_stale_on_error:expr,
+        $tx:expr,
+        $tx_hash:expr,
+        $cache_args:expr,
+        $id:expr
+    ) => {
+        match $serve_stale_on_error {
+            true => {
+                match stale_response(&$tx, $tx_hash.as_bytes(), &$cache_args, &$id) {
+                    Some(stale) => (stale, None),
+                    None => $response,
+                }
+            }
+            false => $response,
         }
     };
 }
@@ -174,95 +466,344 @@ macro_rules! accept {
 macro_rules! get_response {
     (
         $tx:expr,
-        $cache:expr,
+        $cache_args:expr,
         $tx_hash:expr,
         $rpc_position:expr,
+        $rpc_url:expr,
+        $cache_status:expr,
         $id:expr,
         $rpc_list_rwlock:expr,
-        $finalized_rx:expr,
-        $named_numbers:expr,
-        $head_cache:expr,
+        $admission:expr,
         $ttl:expr,
-        $max_retries:expr
+        $max_retries:expr,
+        $hedge_delay:expr,
+        $selection_strategy:expr,
+        $archive_only:expr,
+        $no_archive_message:expr,
+        $routed_group:expr,
+        $eligible:expr,
+        $validate_responses:expr,
+        $serve_stale_on_error:expr,
+        $single_flight:expr,
+        $metrics:expr,
+        $sticky:expr,
+        $session:expr,
+        $record_latency:expr
     ) => {
-        match $cache.get($tx_hash.as_bytes()) {
-            Ok(Some(mut rax)) => {
-                $rpc_position = None;
+        match cache_lookup_or_miss(&$tx, $tx_hash.as_bytes(), &$cache_args) {
+            Some(mut rax) => {
+                $metrics.count_cache_hit($tx["method"].as_str().unwrap_or_default());
+                $rpc_url = None;
+                $cache_status = CacheStatus::Hit;
                 // Reconstruct ID
                 let mut cached: Value = simd_json::serde::from_slice(&mut rax).unwrap();
 
-                cached["id"] = $id.into();
+                cached["id"] = $id.clone();
                 cached.to_string()
             },
-            Ok(None) => {
+            None => {
+                $metrics.count_cache_miss($tx["method"].as_str().unwrap_or_default());
                 // Kinda jank but set the id back to what it was before
-                $tx["id"] = $id.into();
-
-                // Loop until we get a response
-                let mut rx;
-                let mut retries = 0;
-                loop {
-                    // Get the next Rpc in line.
-                    let mut rpc;
-                    {
-                        let mut rpc_list = $rpc_list_rwlock.write().unwrap();
-                        (rpc, $rpc_position) = pick(&mut rpc_list);
-                    }
-                    println!("\x1b[35mInfo:\x1b[0m Forwarding to: {}", rpc.url);
+                $tx["id"] = $id.clone();
 
-                    // Check if we have any RPCs in the list, if not return error
-                    if $rpc_position == None {
-                        return (no_rpc_available!(), None);
-                    }
+                // Wait for an identical request that's already in-flight instead of sending our own
+                let mut flight = None;
+                let mut coalesced = None;
+                match $single_flight.join(
+                    $tx_hash.as_bytes(),
+                    is_idempotent($tx["method"].as_str().unwrap_or_default()),
+                ) {
+                    Flight::Leader(guard) => flight = Some(guard),
+                    Flight::Follower(mut outcome) => {
+                        $metrics.count_coalesced();
+                        coalesced = outcome.recv().await.ok();
+                    },
+                    Flight::Disabled => {},
+                }
 
-                    // Send the request. And return a timeout if it takes too long
-                    //
-                    // Check if it contains any errors or if its `latest` and insert it if it isn't
-                    match timeout(
-                        Duration::from_millis($ttl.try_into().unwrap()),
-                        rpc.send_request($tx.clone()),
-                    )
-                    .await
-                    {
-                        Ok(rxa) => {
-                            rx = rxa.unwrap();
-                            break;
-                        },
-                        Err(_) => {
-                            println!("\x1b[93mWrn:\x1b[0m An RPC request has timed out, picking new RPC and retrying.");
-                            rpc.update_latency($ttl as f64);
-                            retries += 1;
-                        },
-                    };
+                match coalesced {
+                    Some(FlightOutcome::Response(rax)) => {
+                        $rpc_url = None;
+                        // Reconstruct ID
+                        match serde_json::from_str::<Value>(&rax) {
+                            Ok(mut rax) => {
+                                rax["id"] = $id.clone();
+                                rax.to_string()
+                            },
+                            Err(_) => rax,
+                        }
+                    },
+                    Some(FlightOutcome::NoRpcAvailable) if $routed_group.is_some() => return or_stale!((no_routed_rpc!($id, $tx["method"].as_str().unwrap_or_default(), $routed_group.unwrap_or_default()), None), $serve_stale_on_error, $tx, $tx_hash, $cache_args, $id),
+                    Some(FlightOutcome::NoRpcAvailable) if $archive_only => return or_stale!((no_archive_rpc!($id, &$no_archive_message), None), $serve_stale_on_error, $tx, $tx_hash, $cache_args, $id),
+                    Some(FlightOutcome::NoRpcAvailable) => return or_stale!((no_rpc_available!($id), None), $serve_stale_on_error, $tx, $tx_hash, $cache_args, $id),
+                    Some(FlightOutcome::TimedOut) => return or_stale!((timed_out!($id), None), $serve_stale_on_error, $tx, $tx_hash, $cache_args, $id),
+                    Some(FlightOutcome::Error(error)) => return or_stale!((upstream_error!($id, error), None), $serve_stale_on_error, $tx, $tx_hash, $cache_args, $id),
+                    // We're sending the request ourselves
+                    None => {
+                        // Wait for our turn if there are too many requests in-flight
+                        let _admission = $admission.await;
 
-                    if retries == $max_retries {
-                        return (timed_out!(), $rpc_position,);
-                    }
-                }
+                        // Loop until we get a response
+                        let mut rx;
+                        let mut retries = 0;
+                        // RPCs that failed this request, retries go to other ones first
+                        let mut tried: Vec<String> = Vec::new();
+                        // Transport error of the last attempt, if it didn't time out
+                        let mut last_error: Option<RpcError>;
+                        loop {
+                            // Get the next Rpc in line.
+                            let mut rpc;
+                            {
+                                let mut rpc_list = $rpc_list_rwlock.write().unwrap();
+                                // Keep sessions on the RPC that served them, for as long
+                                // as it's in the active pool and hasn't failed this request
+                                let sticky_url = $session
+                                    .as_deref()
+                                    .and_then(|session| $sticky.get(session))
+                                    .filter(|url| {
+                                        rpc_list.iter().any(|rpc| {
+                                            $eligible(rpc) && !tried.contains(&rpc.url) && rpc.url == *url
+                                        })
+                                    });
+                                (rpc, $rpc_position) = match sticky_url {
+                                    Some(url) => pick_filtered(
+                                        &mut rpc_list,
+                                        $selection_strategy,
+                                        |rpc| rpc.url == url,
+                                    ),
+                                    // Prefer RPCs with spare capacity
+                                    None => pick_keyed(
+                                        &mut rpc_list,
+                                        $selection_strategy,
+                                        $tx_hash.as_bytes(),
+                                        |rpc| $eligible(rpc) && !tried.contains(&rpc.url) && rpc.has_capacity(),
+                                    ),
+                                };
 
-                let cache_args = CacheArgs {
-                    finalized_rx: $finalized_rx,
-                    named_numbers: $named_numbers,
-                    cache: $cache,
-                    head_cache: $head_cache,
-                };
-
-                // Don't cache responses that contain errors or missing trie nodes
-                cache_querry(
-                    &mut rx,
-                    $tx,
-                    $tx_hash,
-                    &cache_args,
-                );
-
-                rx
-            }
-            Err(_) => {
-                // If anything errors send an rpc request and see if it works, if not then gg
-                print_cache_error!();
-                $rpc_position = None;
-                return (cache_error!(), $rpc_position);
-            }
+                                // Every RPC is saturated, queue on one of them
+                                if $rpc_position == None {
+                                    (rpc, $rpc_position) = pick_keyed(
+                                        &mut rpc_list,
+                                        $selection_strategy,
+                                        $tx_hash.as_bytes(),
+                                        |rpc| $eligible(rpc) && !tried.contains(&rpc.url),
+                                    );
+                                }
+
+                                // Every RPC failed once already, give them another go
+                                if $rpc_position == None && !tried.is_empty() {
+                                    tried.clear();
+                                    (rpc, $rpc_position) = pick_keyed(
+                                        &mut rpc_list,
+                                        $selection_strategy,
+                                        $tx_hash.as_bytes(),
+                                        |rpc| $eligible(rpc),
+                                    );
+                                }
+                            }
+                            // Positions shift as RPCs move in and out of the active pool,
+                            // so remember who we picked by URL
+                            $rpc_url = $rpc_position.map(|_| rpc.url.clone());
+                            info!("Forwarding to: {}", rpc.url);
+
+                            // Check if we have any RPCs in the list, if not return error
+                            if $rpc_position == None {
+                                if let Some(flight) = flight.take() {
+                                    flight.finish(FlightOutcome::NoRpcAvailable);
+                                }
+                                if let Some(group) = $routed_group {
+                                    return or_stale!((no_routed_rpc!($id, $tx["method"].as_str().unwrap_or_default(), group), None), $serve_stale_on_error, $tx, $tx_hash, $cache_args, $id);
+                                }
+                                if $archive_only {
+                                    return or_stale!((no_archive_rpc!($id, &$no_archive_message), None), $serve_stale_on_error, $tx, $tx_hash, $cache_args, $id);
+                                }
+                                return or_stale!((no_rpc_available!($id), None), $serve_stale_on_error, $tx, $tx_hash, $cache_args, $id);
+                            }
+
+                            // Wait for the RPC to drop below `max_concurrency`, for as long
+                            // as we'd wait for it to respond
+                            let _capacity = match timeout(
+                                Duration::from_millis($ttl.try_into().unwrap()),
+                                rpc.acquire_capacity(),
+                            )
+                            .await
+                            {
+                                Ok(capacity) => capacity,
+                                Err(_) => {
+                                    warn!("{} is saturated, picking new RPC and retrying.", rpc.url);
+                                    tried.push(rpc.url.clone());
+                                    retries += 1;
+                                    if retries >= $max_retries {
+                                        if let Some(flight) = flight.take() {
+                                            flight.finish(FlightOutcome::TimedOut);
+                                        }
+                                        return or_stale!((timed_out!($id), None), $serve_stale_on_error, $tx, $tx_hash, $cache_args, $id);
+                                    }
+                                    continue;
+                                },
+                            };
+
+                            // Count the request against the RPCs capacity while it's in-flight
+                            let _in_flight = rpc.track_in_flight();
+                            rpc.count_request();
+
+                            // Pick a second RPC to hedge slow idempotent requests to.
+                            // Hedges that fire count against the retry budget.
+                            let mut hedge = None;
+                            if !$hedge_delay.is_zero()
+                                && retries + 1 < $max_retries
+                                && is_idempotent($tx["method"].as_str().unwrap_or_default())
+                            {
+                                let mut rpc_list = $rpc_list_rwlock.write().unwrap();
+                                if let (hedge_rpc, Some(position)) = pick_filtered(
+                                    &mut rpc_list,
+                                    $selection_strategy,
+                                    |rpc| $eligible(rpc) && rpc.has_capacity(),
+                                ) {
+                                    if Some(position) != $rpc_position {
+                                        hedge = Some((hedge_rpc, position));
+                                    }
+                                }
+                            }
+
+                            // Send the request. And return a timeout if it takes too long
+                            //
+                            // Check if it contains any errors or if its `latest` and insert it if it isn't
+                            let sent = Instant::now();
+                            let response = timeout(
+                                Duration::from_millis($ttl.try_into().unwrap()),
+                                send_hedged(
+                                    &rpc,
+                                    hedge.as_ref().map(|(hedge_rpc, _)| hedge_rpc),
+                                    $tx.clone(),
+                                    $hedge_delay,
+                                ),
+                            )
+                            .await;
+
+                            // Blame or credit whoever the response came from
+                            let mut responder = (rpc.url.clone(), $rpc_position);
+                            let mut latency = sent.elapsed();
+                            if let Ok(response) = &response {
+                                if response.hedged {
+                                    retries += 1;
+                                }
+                                if let (HedgeWinner::Hedge, Some((hedge_rpc, position))) = (response.winner, &hedge) {
+                                    responder = (hedge_rpc.url.clone(), Some(*position));
+                                    // The hedge was only sent once the delay was up
+                                    latency = latency.saturating_sub($hedge_delay);
+                                }
+                            }
+                            let (responder_url, responder_position) = responder;
+
+                            // Only the upstream call counts towards the latency of the RPC that
+                            // answered. Transport errors can be instant, so they don't count.
+                            if let (Ok(Ok(_)), Some(position)) = (response.as_ref().map(|response| &response.rx), responder_position) {
+                                $record_latency(position, latency);
+                            }
+
+                            match response.map(|response| response.rx) {
+                                // Retrying won't make the response any smaller, and it's
+                                // not something we want in the cache either
+                                Ok(Err(RpcError::ResponseTooLarge(max))) => {
+                                    warn!("Response from {} is larger than {} bytes, dropping it.", responder_url, max);
+                                    let rax = response_too_large!($id);
+                                    if let Some(flight) = flight.take() {
+                                        flight.finish(FlightOutcome::Response(rax.clone()));
+                                    }
+                                    return (with_cache_status(json_response(rax), $cache_status), Some(responder_url));
+                                },
+                                // Connection errors and 5xx responses without a JSON-RPC error
+                                Ok(Err(err)) => {
+                                    warn!("Request to {} failed: {}, picking new RPC and retrying.", responder_url, err);
+                                    if let Some(position) = responder_position {
+                                        mark_rpc_error(&$rpc_list_rwlock, position);
+                                    }
+                                    tried.push(responder_url);
+                                    last_error = Some(err);
+                                    retries += 1;
+                                },
+                                Ok(Ok(rxa)) => {
+                                    // Keep the session on whoever responded
+                                    if let Some(session) = $session.as_deref() {
+                                        $sticky.insert(session, &responder_url);
+                                    }
+
+                                    // Credit the latency to whoever responded
+                                    $rpc_position = responder_position;
+                                    $rpc_url = Some(responder_url.clone());
+
+                                    // Treat malformed responses as node errors and retry.
+                                    // Requests that change state are never sent twice.
+                                    let method = $tx["method"].as_str().unwrap_or_default();
+                                    let validation = match $validate_responses && is_idempotent(method) {
+                                        true => validate_response(&$tx, &rxa),
+                                        false => Ok(()),
+                                    };
+
+                                    match validation {
+                                        Ok(()) => {
+                                            rx = rxa;
+                                            break;
+                                        },
+                                        Err(err) => {
+                                            warn!("Invalid response to {}: {}, picking new RPC and retrying.", method, err);
+                                            if let Some(position) = $rpc_position {
+                                                mark_rpc_error(&$rpc_list_rwlock, position);
+                                            }
+                                            tried.push(responder_url);
+                                            last_error = None;
+                                            retries += 1;
+                                        },
+                                    }
+                                },
+                                Err(_) => {
+                                    warn!("An RPC request has timed out, picking new RPC and retrying.");
+                                    // Hung RPCs should look slow to `least_latency`
+                                    if let Some(position) = $rpc_position {
+                                        $record_latency(position, Duration::from_millis($ttl as u64));
+                                    }
+                                    rpc.count_error();
+                                    tried.push(rpc.url.clone());
+                                    last_error = None;
+                                    retries += 1;
+                                },
+                            };
+
+                            if retries >= $max_retries {
+                                // Tell the client, and whoever is waiting on us, why the last attempt failed if we know
+                                let error = last_error.as_ref().map(upstream_error_object);
+                                if let Some(flight) = flight.take() {
+                                    flight.finish(match &error {
+                                        Some(error) => FlightOutcome::Error(error.clone()),
+                                        None => FlightOutcome::TimedOut,
+                                    });
+                                }
+                                return match error {
+                                    Some(error) => or_stale!((upstream_error!($id, error), $rpc_url), $serve_stale_on_error, $tx, $tx_hash, $cache_args, $id),
+                                    None => or_stale!((timed_out!($id), $rpc_url), $serve_stale_on_error, $tx, $tx_hash, $cache_args, $id),
+                                };
+                            }
+                        }
+
+                        rx = $cache_args.response_transform.apply(rx);
+
+                        // Don't cache responses that contain errors or missing trie nodes
+                        cache_querry(
+                            &mut rx,
+                            $tx,
+                            $tx_hash,
+                            &$cache_args,
+                        );
+
+                        if let Some(flight) = flight.take() {
+                            flight.finish(FlightOutcome::Response(rx.clone()));
+                        }
+
+                        rx
+                    },
+                }
+            },
         }
     };
 }
@@ -271,15 +812,12 @@ macro_rules! get_response {
 // read and return from the cache.
 async fn forward_body(
     tx: Request<hyper::body::Incoming>,
-    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
-    finalized_rx: &watch::Receiver<u64>,
-    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
-    cache: Arc<Db>,
+    connection_params: &ConnectionParams,
     params: RequestParams,
+    access: Option<&mut AccessEntry>,
 ) -> (
     Result<hyper::Response<Full<Bytes>>, Infallible>,
-    Option<usize>,
+    Option<String>,
 ) {
     // Check if body has application/json
     if tx.headers().get("content-type") != Some(&HeaderValue::from_static("application/json")) {
@@ -292,162 +830,2974 @@ async fn forward_body(
         );
     }
 
-    // Convert incoming body to serde value
-    let mut tx = incoming_to_value(tx).await.unwrap();
+    // Don't start buffering bodies we know are too large
+    if exceeds_content_length(tx.headers(), params.max_request_bytes) {
+        return (Ok(payload_too_large_response()), None);
+    }
 
-    // Get the id of the request and set it to 0 for caching
-    //
-    // We're doing this ID gymnastics because we're hashing the
-    // whole request and we don't want the ID as it's arbitrary
-    // and does not impact the request result.
-    let id = tx["id"].take().as_u64().unwrap_or(0);
+    // Convert incoming body to serde value
+    let tx = match incoming_to_value(tx, params.max_request_bytes).await {
+        Ok(tx) => tx,
+        Err(err) => return (Ok(bad_body_response(err)), None),
+    };
 
-    // Hash the request with either blake3 or xxhash depending on the enabled feature
-    let tx_hash;
-    #[cfg(not(feature = "xxhash"))]
-    {
-        tx_hash = hash(tx.to_string().as_bytes());
+    let method = match &tx {
+        Value::Array(_) => "batch",
+        tx => tx["method"].as_str().unwrap_or_default(),
+    };
+    Span::current().record("method", method);
+    if let Some(access) = access {
+        access.method = method.to_string();
+        access.params_hash = match &tx {
+            Value::Array(_) => params_hash(&tx),
+            tx => params_hash(&tx["params"]),
+        };
     }
-    #[cfg(feature = "xxhash")]
-    {
-        tx_hash = xxh3_64(tx.to_string().as_bytes());
+
+    if let Value::Array(calls) = &tx {
+        if params.max_batch_size != 0 && calls.len() > params.max_batch_size {
+            let rax = jsonrpc_error(
+                &Value::Null,
+                -32600,
+                &format!("Batch too large, max {} calls", params.max_batch_size),
+                None,
+            );
+            return (json_response(rax.to_string()), None);
+        }
+
+        // Every call in a batch counts against the rate limit, the request
+        // itself already took a token
+        if let Some(peer) = connection_params.peer {
+            let calls = calls.len().saturating_sub(1) as u64;
+            if let Err(retry_after) = connection_params.rate_limiter.check_n(peer.ip(), calls) {
+                return (Ok(rate_limited_response(retry_after)), None);
+            }
+        }
     }
 
-    // RPC used to get the response, we use it to update the latency for it later.
-    let mut rpc_position;
+    let id = match &tx {
+        Value::Array(_) => Value::Null,
+        tx => tx["id"].clone(),
+    };
+    let served = async {
+        // Split batches up and serve every call on its own
+        if let Value::Array(calls) = tx {
+            return (forward_batch(calls, connection_params, &params).await, None);
+        }
 
-    // Rewrite named block parameters if possible
-    let mut tx = replace_block_tags(&mut tx, named_numbers);
+        forward_call(tx, connection_params, &params).await
+    };
 
-    // Get the response from either the DB or from a RPC. If it timeouts, retry.
-    let rax = get_response!(
-        tx,
-        cache,
-        tx_hash,
-        rpc_position,
-        id,
-        rpc_list_rwlock,
-        finalized_rx.clone(),
-        named_numbers.clone(),
-        head_cache.clone(),
-        params.ttl,
-        params.max_retries
-    );
+    // Stop waiting on RPCs and retries once the deadline passes
+    match params.deadline {
+        Some(deadline) => {
+            match timeout(deadline.saturating_duration_since(Instant::now()), served).await {
+                Ok(served) => served,
+                Err(_) => (timed_out!(id), None),
+            }
+        }
+        None => served.await,
+    }
+}
 
-    // Convert rx to bytes and but it in a Buf
-    let body = hyper::body::Bytes::from(rax);
+// Block tag of methods asking for the number of a named block
+fn named_block_tag(method: &str) -> Option<&'static str> {
+    match method {
+        "blutgang_finalizedBlockNumber" => Some("finalized"),
+        "blutgang_safeBlockNumber" => Some("safe"),
+        _ => None,
+    }
+}
 
-    // Put it in a http_body_util::Full
-    let body = Full::new(body);
+// Number of the block we're tracking for `tag`, 0 if we don't know it yet
+fn tracked_block_number(
+    tag: &str,
+    finalized_rx: &watch::Receiver<u64>,
+    named_numbers: &RwLock<NamedBlocknumbers>,
+) -> u64 {
+    match tag {
+        "finalized" => *finalized_rx.borrow(),
+        _ => named_numbers.read().unwrap().safe,
+    }
+}
 
-    // Build the response
-    let res = hyper::Response::builder()
-        .status(200)
-        .header("Content-Type", "application/json")
-        .header("Access-Control-Allow-Origin", "*")
-        .body(body)
-        .unwrap();
+// How long the client wants the request to take at most, falling back to `default_ms`.
+// Clients can shorten `default_ms` but not go past it. Returns `None` if it has no deadline.
+fn request_deadline(header: Option<&HeaderValue>, default_ms: u64) -> Option<Duration> {
+    let deadline_ms = header
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.trim().parse::<u64>().ok())
+        .filter(|deadline_ms| *deadline_ms != 0)
+        .map_or(default_ms, |deadline_ms| {
+            match default_ms {
+                0 => deadline_ms,
+                _ => deadline_ms.min(default_ms),
+            }
+        });
 
-    (Ok(res), rpc_position)
+    (deadline_ms != 0).then(|| Duration::from_millis(deadline_ms))
 }
 
-// Forward the request to *a* RPC picked by the algo set by the user.
-// Measures the time needed for a request, and updates the respective
-// RPC lself.
-// In case of a timeout, returns an error.
-pub async fn accept_request(
-    mut tx: Request<hyper::body::Incoming>,
-    connection_params: ConnectionParams,
-) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
-    // Check if the request is a websocket upgrade request.
-    if is_upgrade_request(&tx) {
-        println!("\x1b[35mInfo:\x1b[0m Received WS upgrade request");
+// Get the response to a single JSON-RPC call
+async fn forward_call(
+    mut tx: Value,
+    connection_params: &ConnectionParams,
+    params: &RequestParams,
+) -> (
+    Result<hyper::Response<Full<Bytes>>, Infallible>,
+    Option<String>,
+) {
+    let rpc_list_rwlock = &connection_params.rpc_list_rwlock;
+    let named_numbers = &connection_params.named_numbers;
+    let tx_replay = &connection_params.tx_replay;
 
-        if !connection_params.config.read().unwrap().is_ws {
-            return rpc_response!(
-                500,
-                Full::new(Bytes::from(
-                    "{code:-32005, message:\"error: WebSockets are disabled!\"}".to_string(),
-                ))
+    // Reject anything that isn't a JSON-RPC request before we index into it
+    if let Err(reason) = validate_request(&mut tx, params.strict_jsonrpc) {
+        let id = tx.get("id").cloned().unwrap_or(Value::Null);
+        return (
+            with_cache_status(
+                json_response(invalid_request!(id, reason)),
+                CacheStatus::Local,
+            ),
+            None,
+        );
+    }
+
+    // Take the id out of the request. It's not part of the cache key, so we
+    // put the client's id back on every response ourselves.
+    let id = tx["id"].take();
+
+    // Reject methods we don't serve before touching the cache or any RPC
+    let method = tx["method"].as_str().unwrap_or_default();
+    if !params.method_filter.is_allowed(method) {
+        let rax = params.method_filter.blocked(&id, method);
+        return (
+            with_cache_status(json_response(rax.to_string()), CacheStatus::Local),
+            None,
+        );
+    }
+
+    // Answer requests for the finalized or safe block number with the ones we're tracking.
+    //
+    // If we don't know it yet, the request becomes one for the block, which gets
+    // served like any other request, and we only return its number.
+    let named_block = named_block_tag(method).filter(|_| params.serve_named_blocks_locally);
+    if let Some(tag) = named_block {
+        let number = tracked_block_number(
+            tag,
+            &connection_params.channels.finalized_rx,
+            named_numbers,
+        );
+        if number != 0 {
+            let rax = json!({"jsonrpc": "2.0", "id": id, "result": format!("0x{:x}", number)});
+            return (
+                with_cache_status(json_response(rax.to_string()), CacheStatus::Local),
+                None,
             );
         }
 
-        let (response, websocket) = match upgrade(&mut tx, None) {
-            Ok((response, websocket)) => (response, websocket),
-            Err(e) => {
-                println!("\x1b[31mErr:\x1b[0m Websocket upgrade error: {e}");
-                return rpc_response!(500, Full::new(Bytes::from(
-                    "{code:-32004, message:\"error: Websocket upgrade error! Try again later...\"}"
-                        .to_string(),
-                )));
-            }
-        };
+        tx = json!({"jsonrpc": "2.0", "method": "eth_getBlockByNumber", "params": [tag, false]});
+    }
 
-        let cache_args = CacheArgs {
-            finalized_rx: connection_params.channels.finalized_rx.as_ref().clone(),
-            named_numbers: connection_params.named_numbers.clone(),
-            cache: connection_params.cache,
-            head_cache: connection_params.head_cache.clone(),
-        };
+    // Slow methods can be given more time than the global `ttl`
+    let ttl = params.ttl(tx["method"].as_str().unwrap_or_default());
 
-        // Spawn a task to handle the websocket connection.
-        tokio::task::spawn(async move {
-            if let Err(e) = serve_websocket(
-                websocket,
-                connection_params.channels.incoming_tx,
-                connection_params.channels.outgoing_rx,
-                connection_params.sub_data.clone(),
-                cache_args,
-            )
-            .await
-            {
-                println!("\x1b[31mErr:\x1b[0m Websocket connection error: {e}");
-            }
-        });
+    // Rewrite named block parameters if possible.
+    //
+    // Done before hashing, so requests get cached by the block they resolve to
+    // and later requests for that block number are a hit.
+    let mut tx = replace_block_tags(&mut tx, named_numbers, &params.block_params);
 
-        // Return the response so the spawned future can continue.
-        return Ok(response);
+    // Hash the request with either blake3 or xxhash depending on the enabled feature
+    let key = cache_key(&tx, &params.block_params);
+    let tx_hash;
+    #[cfg(not(feature = "xxhash"))]
+    {
+        tx_hash = hash(key.as_bytes());
+    }
+    #[cfg(feature = "xxhash")]
+    {
+        tx_hash = xxh3_64(key.as_bytes());
     }
 
-    // Send request and measure time
-    let response: Result<hyper::Response<Full<Bytes>>, Infallible>;
-    let rpc_position: Option<usize>;
+    // URL of the RPC that served the request, None if we answered it ourselves
+    let mut rpc_url = None;
+    let mut cache_status = CacheStatus::Miss;
 
-    // RequestParams from config
-    let params = {
-        let config_guard = connection_params.config.read().unwrap();
-        RequestParams {
-            ttl: config_guard.ttl,
-            max_retries: config_guard.max_retries,
+    // Respond consistently to requests for blocks we haven't seen yet
+    if params.future_blocks == FutureBlockBehavior::Error
+        && is_future_block(&tx, named_numbers, &params.block_params)
+    {
+        return (
+            with_cache_status(future_block!(id), CacheStatus::Local),
+            None,
+        );
+    }
+
+    // Answer `eth_blockNumber` with the head we're tracking, if we know it
+    if params.serve_block_number_locally && tx["method"] == "eth_blockNumber" {
+        let latest = named_numbers.read().unwrap().latest;
+        if latest != 0 {
+            let rax = json!({"jsonrpc": "2.0", "id": id, "result": format!("0x{:x}", latest)});
+            return (
+                with_cache_status(json_response(rax.to_string()), CacheStatus::Local),
+                None,
+            );
+        }
+    }
+
+    // Only archive nodes can serve state older than `archive_threshold` blocks
+    let archive_only = params.archive_threshold != 0
+        && reads_state(tx["method"].as_str().unwrap_or_default())
+        && {
+            let latest = named_numbers.read().unwrap().latest;
+            latest != 0
+                && get_block_number_from_request(tx.clone(), named_numbers, &params.block_params)
+                    .is_some_and(|num| num < latest.saturating_sub(params.archive_threshold))
+        };
+
+    // Heavy methods can be pinned to a group of RPCs
+    let routed_group = params
+        .method_routing
+        .group(tx["method"].as_str().unwrap_or_default())
+        .map(str::to_string);
+    let eligible = |rpc: &Rpc| {
+        (!archive_only || rpc.archive)
+            && routed_group
+                .as_deref()
+                .map_or(true, |group| rpc.in_group(group))
+    };
+
+    // Don't submit the same raw transaction more than once within the replay window
+    let mut replay_guard = None;
+    let replayed = match tx_replay.raw_tx(&tx) {
+        Some(raw_tx) => {
+            match tx_replay.reserve(raw_tx).await {
+                Reservation::Submitted(replayed) => Some(replayed),
+                Reservation::Reserved(guard) => {
+                    replay_guard = Some(guard);
+                    None
+                }
+            }
         }
+        None => None,
     };
 
-    // Check if we have the response hashed, and if not forward it
-    // to the best available RPC.
-    //
-    // Also handle cache insertions.
-    let time = Instant::now();
-    (response, rpc_position) = forward_body(
-        tx,
-        &connection_params.rpc_list_rwlock,
-        &connection_params.channels.finalized_rx,
-        &connection_params.named_numbers,
-        &connection_params.head_cache,
-        connection_params.cache,
-        params,
-    )
-    .await;
-    let time = time.elapsed();
-    println!("\x1b[35mInfo:\x1b[0m Request time: {:?}", time);
+    let rax = match replayed {
+        Some(mut replayed) => {
+            cache_status = CacheStatus::Hit;
+            // Reconstruct ID
+            replayed["id"] = id.clone();
+            replayed.to_string()
+        }
+        None => {
+            // Get the response from either the DB or from a RPC. If it timeouts, retry.
+            let cache_args = CacheArgs {
+                finalized_rx: connection_params.channels.finalized_rx.as_ref().clone(),
+                named_numbers: named_numbers.clone(),
+                cache: connection_params.cache.clone(),
+                head_cache: connection_params.head_cache.clone(),
+                head_cache_enabled: connection_params.config.read().unwrap().head_cache_enabled,
+                block_params: params.block_params.clone(),
+                cache_state: connection_params.cache_state.clone(),
+                reorg_window: connection_params.reorg_window.clone(),
+                schema_version: CACHE_SCHEMA_VERSION,
+                cache_version: connection_params.config.read().unwrap().cache_version,
+                migrate_cache: connection_params
+                    .config
+                    .read()
+                    .unwrap()
+                    .lazy_cache_migration,
+                keep_expired: params.serve_stale_on_error,
+                method_cache: params.method_cache.clone(),
+                method_filter: params.method_filter.clone(),
+                memory_cache: connection_params.memory_cache.clone(),
+                response_transform: connection_params
+                    .config
+                    .read()
+                    .unwrap()
+                    .response_transform
+                    .clone(),
+            };
 
-    // `rpc_position` is an Option<> that either contains the index of the RPC
-    // we forwarded our request to, or is None if the result was cached.
-    //
-    // Here, we update the latency of the RPC that was used to process the request
-    // if `rpc_position` is Some.
-    if let Some(rpc_position) = rpc_position {
-        update_rpc_latency(&connection_params.rpc_list_rwlock, rpc_position, time);
-    }
+            // Compose logs over finalized ranges from the sharded logs cache, if enabled
+            let sharded = match params.shard_logs_cache {
+                true => {
+                    sharded_logs(
+                        &tx,
+                        rpc_list_rwlock,
+                        &cache_args,
+                        connection_params.admission.acquire(params.priority),
+                        params.selection_strategy,
+                        ttl,
+                        params.max_retries,
+                    )
+                    .await
+                }
+                false => None,
+            };
+
+            // Split `eth_getLogs` over large ranges into chunks, if enabled
+            let logs_range = match sharded {
+                Some(_) => None,
+                None => logs_range_to_split(&tx, params.max_logs_range),
+            };
+
+            let rax = match sharded {
+                Some(logs) => {
+                    cache_status = CacheStatus::Hit;
+                    json!({"jsonrpc": "2.0", "id": id, "result": logs}).to_string()
+                }
+                None if logs_range.is_some() => {
+                    let (from_block, to_block) = logs_range.unwrap();
 
-    response
+                    // Don't fan out into more chunks than we allow
+                    if params.max_logs_chunks != 0
+                        && logs_chunk_count(from_block, to_block, params.max_logs_range)
+                            > params.max_logs_chunks
+                    {
+                        let rax = jsonrpc_error(
+                            &id,
+                            -32005,
+                            &format!(
+                                "Block range too large, at most {} blocks per request",
+                                params.max_logs_chunks.saturating_mul(params.max_logs_range)
+                            ),
+                            None,
+                        );
+                        return (
+                            with_cache_status(json_response(rax.to_string()), CacheStatus::Local),
+                            None,
+                        );
+                    }
+
+                    match cache_lookup_or_miss(&tx, tx_hash.as_bytes(), &cache_args) {
+                        Some(rax) => {
+                            connection_params
+                                .metrics
+                                .count_cache_hit(tx["method"].as_str().unwrap_or_default());
+                            cache_status = CacheStatus::Hit;
+                            // Reconstruct ID
+                            let mut cached: Value = serde_json::from_slice(&rax).unwrap();
+                            cached["id"] = id.clone();
+                            cached.to_string()
+                        }
+                        _ => {
+                            connection_params
+                                .metrics
+                                .count_cache_miss(tx["method"].as_str().unwrap_or_default());
+                            let _admission =
+                                connection_params.admission.acquire(params.priority).await;
+
+                            match split_logs(
+                                &tx,
+                                from_block,
+                                to_block,
+                                params.max_logs_range,
+                                params.max_logs_chunks,
+                                rpc_list_rwlock,
+                                params.selection_strategy,
+                                ttl,
+                            )
+                            .await
+                            {
+                                Ok(logs) => {
+                                    let mut rax = cache_args.response_transform.apply(
+                                        json!({"jsonrpc": "2.0", "id": id, "result": logs})
+                                            .to_string(),
+                                    );
+                                    cache_querry(&mut rax, tx.clone(), tx_hash, &cache_args);
+                                    rax
+                                }
+                                Err(Some(mut rax)) => {
+                                    rax["id"] = id.clone();
+                                    rax.to_string()
+                                }
+                                Err(None) => return (timed_out!(id), None),
+                            }
+                        }
+                    }
+                }
+                // Submit transactions to every RPC at once, if enabled
+                None if params.broadcast_transactions
+                    && tx["method"] == "eth_sendRawTransaction" =>
+                {
+                    tx["id"] = id.clone();
+
+                    let rpcs = rpc_list_rwlock.read().unwrap().clone();
+                    if rpcs.is_empty() {
+                        return (no_rpc_available!(id), None);
+                    }
+
+                    let _admission = connection_params.admission.acquire(params.priority).await;
+                    match broadcast_tx(&rpcs, tx, Duration::from_millis(ttl as u64)).await {
+                        Some(rax) => rax,
+                        None => return (timed_out!(id), None),
+                    }
+                }
+                None => {
+                    // Also send a sample of reads to a second RPC to compare responses
+                    let shadow_tx = match params.shadow_sample_rate > 0.0
+                        && is_idempotent(tx["method"].as_str().unwrap_or_default())
+                        && rand::thread_rng().gen_bool(params.shadow_sample_rate)
+                    {
+                        true => Some(tx.clone()),
+                        false => None,
+                    };
+
+                    let mut rpc_position;
+                    let rax = get_response!(
+                        tx,
+                        cache_args,
+                        tx_hash,
+                        rpc_position,
+                        rpc_url,
+                        cache_status,
+                        id,
+                        rpc_list_rwlock,
+                        connection_params.admission.acquire(params.priority),
+                        ttl,
+                        params.max_retries,
+                        params.hedge_delay,
+                        params.selection_strategy,
+                        archive_only,
+                        params.no_archive_message,
+                        routed_group.as_deref(),
+                        eligible,
+                        params.validate_responses,
+                        params.serve_stale_on_error,
+                        connection_params.single_flight,
+                        connection_params.metrics,
+                        connection_params.sticky,
+                        params.session,
+                        |position, time| update_latency(connection_params, position, time)
+                    );
+
+                    // Only compare fresh responses from an RPC, not cached or coalesced ones
+                    if let (Some(shadow_tx), Some(primary)) = (shadow_tx, &rpc_url) {
+                        shadow_request(
+                            rpc_list_rwlock,
+                            primary,
+                            shadow_tx,
+                            &rax,
+                            eligible,
+                            ttl,
+                            &connection_params.metrics,
+                        );
+                    }
+
+                    rax
+                }
+            };
+
+            // Only remember successful submissions so failed ones can be retried
+            if let Some(replay_guard) = replay_guard {
+                if !rax.contains("\"error\"") || is_known_tx_error(&rax) {
+                    if let Ok(response) = serde_json::from_str(&rax) {
+                        replay_guard.finish(response);
+                    }
+                }
+            }
+
+            rax
+        }
+    };
+
+    // Only return the number of the block we asked for in place of a named block number
+    let rax = match named_block {
+        Some(_) => block_number_only(rax),
+        None => rax,
+    };
+
+    (
+        with_cache_status(json_response(rax), cache_status),
+        rpc_url,
+    )
+}
+
+// Replace the block in a response to `eth_getBlockByNumber` with its number.
+// Errors and responses without a block are returned as is.
+fn block_number_only(rax: String) -> String {
+    match serde_json::from_str::<Value>(&rax) {
+        Ok(mut response) if response["result"]["number"].is_string() => {
+            response["result"] = response["result"]["number"].take();
+            response.to_string()
+        }
+        _ => rax,
+    }
+}
+
+// Serve up to `batch_concurrency` calls of a batch at once and reassemble the
+// responses in order.
+//
+// Every call goes through the cache on its own, so only uncached calls get
+// forwarded, and those get spread over RPCs like separate requests would.
+async fn forward_batch(
+    calls: Vec<Value>,
+    connection_params: &ConnectionParams,
+    params: &RequestParams,
+) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    let responses: Vec<_> = stream::iter(calls.into_iter().map(|call| {
+        async move {
+            // Ids can be anything in batches, and can repeat. We put each one back
+            // on its response ourselves, so responses match their call no matter what.
+            let id = call.get("id").cloned().unwrap_or(Value::Null);
+            if !call.is_object() {
+                let rax = jsonrpc_error(&Value::Null, -32600, "Invalid Request", None);
+                return (rax, CacheStatus::Local);
+            }
+
+            let (response, _) = forward_call(call, connection_params, params).await;
+
+            let response = match response {
+                Ok(response) => response,
+                Err(err) => match err {},
+            };
+            let cache_status =
+                CacheStatus::from_header(response.headers().get(CACHE_STATUS_HEADER));
+            // `Full` can't fail
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+
+            let mut rax = match serde_json::from_slice::<Value>(&body) {
+                Ok(rax) if rax.is_object() => rax,
+                // Some of our errors aren't JSON-RPC responses, so wrap them in one
+                _ => jsonrpc_error(&Value::Null, -32603, &String::from_utf8_lossy(&body), None),
+            };
+            rax["id"] = id;
+
+            (rax, cache_status)
+        }
+    }))
+    .buffered(params.batch_concurrency.max(1))
+    .collect()
+    .await;
+
+    // The batch is only a hit if no call had to be forwarded,
+    // and stale if any call got a stale response
+    let cache_status = responses
+        .iter()
+        .map(|(_, cache_status)| *cache_status)
+        .fold(CacheStatus::Local, |batch, call| {
+            match (batch, call) {
+                (CacheStatus::Stale, _) | (_, CacheStatus::Stale) => CacheStatus::Stale,
+                (CacheStatus::Miss, _) | (_, CacheStatus::Miss) => CacheStatus::Miss,
+                (CacheStatus::Hit, _) | (_, CacheStatus::Hit) => CacheStatus::Hit,
+                _ => CacheStatus::Local,
+            }
+        });
+
+    let rax = Value::Array(responses.into_iter().map(|(rax, _)| rax).collect());
+    with_cache_status(json_response(rax.to_string()), cache_status)
+}
+
+// Where the response to a request came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CacheStatus {
+    // Served from the cache
+    Hit,
+    // Forwarded to an RPC
+    Miss,
+    // Answered by us without the cache or an RPC, like `eth_blockNumber`
+    Local,
+    // Served from the cache after it expired, because every RPC failed the request
+    Stale,
+}
+
+impl CacheStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Miss => "miss",
+            CacheStatus::Local => "local",
+            CacheStatus::Stale => "stale",
+        }
+    }
+
+    // Responses without the header weren't answered by the cache or by us
+    fn from_header(header: Option<&HeaderValue>) -> Self {
+        match header.and_then(|header| header.to_str().ok()) {
+            Some("hit") => CacheStatus::Hit,
+            Some("local") => CacheStatus::Local,
+            Some("stale") => CacheStatus::Stale,
+            _ => CacheStatus::Miss,
+        }
+    }
+}
+
+// Update the latency of the RPC at `rpc_position` with how long it took to respond
+// to a request, or the ttl if it didn't
+fn update_latency(connection_params: &ConnectionParams, rpc_position: usize, time: Duration) {
+    update_rpc_latency(&connection_params.rpc_list_rwlock, rpc_position, time);
+
+    let url = {
+        let rpc_list = connection_params.rpc_list_rwlock.read().unwrap();
+        rpc_list
+            .get(rpc_position)
+            .map(|rpc| rpc.url.clone())
+    };
+    if let Some(url) = url {
+        connection_params.metrics.record_latency(&url, time);
+    }
+
+    let ema_alpha = connection_params.config.read().unwrap().latency_ema_alpha;
+    update_rpc_latency_ema(
+        &connection_params.rpc_list_rwlock,
+        rpc_position,
+        time,
+        ema_alpha,
+    );
+}
+
+// Send `tx` to an RPC other than `primary` in the background, and
+// compare its response with `response`, the one we got from `primary`.
+//
+// Mismatches get logged and counted. Doesn't do anything if there's no other RPC.
+fn shadow_request(
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    primary: &str,
+    tx: Value,
+    response: &str,
+    eligible: impl Fn(&Rpc) -> bool,
+    ttl: u128,
+    metrics: &Arc<Metrics>,
+) {
+    let shadow = {
+        let rpc_list = rpc_list_rwlock.read().unwrap();
+        let candidates: Vec<&Rpc> = rpc_list
+            .iter()
+            .filter(|rpc| rpc.url != primary && eligible(rpc))
+            .collect();
+        if candidates.is_empty() {
+            return;
+        }
+        candidates[rand::thread_rng().gen_range(0..candidates.len())].clone()
+    };
+    let primary = primary.to_string();
+
+    let response = response.to_string();
+    let metrics = metrics.clone();
+    tokio::spawn(async move {
+        // Shadows are extra load, so skip them for RPCs at their `max_concurrency`
+        let Ok(_capacity) = shadow.try_acquire_capacity() else {
+            return;
+        };
+        let _in_flight = shadow.track_in_flight();
+        shadow.count_request();
+        let method = tx["method"].as_str().unwrap_or_default().to_string();
+        let rax = match timeout(Duration::from_millis(ttl as u64), shadow.send_request(tx)).await {
+            Ok(Ok(rax)) => rax,
+            // Failing to respond isn't returning different data
+            _ => return,
+        };
+
+        let mismatch = !responses_match(&response, &rax);
+        metrics.count_shadow(mismatch);
+        if mismatch {
+            warn!(
+                "Shadow request mismatch for {}: {} responded {}, {} responded {}",
+                method, primary, response, shadow.url, rax
+            );
+        }
+    });
+}
+
+// Compare two responses, ignoring their id
+fn responses_match(a: &str, b: &str) -> bool {
+    match (
+        serde_json::from_str::<Value>(a),
+        serde_json::from_str::<Value>(b),
+    ) {
+        (Ok(mut a), Ok(mut b)) => {
+            for response in [&mut a, &mut b] {
+                if let Some(response) = response.as_object_mut() {
+                    response.remove("id");
+                }
+            }
+            a == b
+        }
+        _ => a == b,
+    }
+}
+
+// Build the response to `tx` out of its stale cache entry, if there is one
+fn stale_response(
+    tx: &Value,
+    tx_hash: &[u8],
+    cache_args: &CacheArgs,
+    id: &Value,
+) -> Option<Result<hyper::Response<Full<Bytes>>, Infallible>> {
+    let rax = cache_lookup_stale(tx, tx_hash, cache_args).ok()??;
+    let mut rax: Value = serde_json::from_slice(&rax).ok()?;
+    warn!(
+        "Every RPC failed {}, serving a stale response from the cache.",
+        tx["method"].as_str().unwrap_or_default()
+    );
+
+    rax["id"] = id.clone();
+    Some(with_cache_status(
+        json_response(rax.to_string()),
+        CacheStatus::Stale,
+    ))
+}
+
+// Tell the client where the response came from with `X-Blutgang-Cache`
+fn with_cache_status(
+    response: Result<hyper::Response<Full<Bytes>>, Infallible>,
+    cache_status: CacheStatus,
+) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    response.map(|mut response| {
+        response.headers_mut().insert(
+            CACHE_STATUS_HEADER,
+            HeaderValue::from_static(cache_status.as_str()),
+        );
+        response
+    })
+}
+
+// Build the HTTP response for a JSON-RPC response body
+fn json_response(rax: String) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    // Convert rx to bytes and but it in a Buf
+    let body = hyper::body::Bytes::from(rax);
+
+    // Put it in a http_body_util::Full
+    let body = Full::new(body);
+
+    // Build the response
+    let res = hyper::Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(body)
+        .unwrap();
+
+    Ok(res)
+}
+
+// Forward the request to *a* RPC picked by the algo set by the user.
+// Measures the time needed for a request, and updates the respective
+// RPC lself.
+// In case of a timeout, returns an error.
+pub async fn accept_request(
+    mut tx: Request<hyper::body::Incoming>,
+    connection_params: ConnectionParams,
+) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    let received = Instant::now();
+
+    // Readiness probe, ready once we passed a health check (and warmed up, if enabled),
+    // for as long as there's an RPC in the active pool. Covers every chain we serve.
+    if tx.method() == hyper::Method::GET && tx.uri().path() == "/ready" {
+        let ready = std::iter::once(&connection_params)
+            .chain(connection_params.chains.values())
+            .all(|chain| chain.readiness.is_ready(&chain.rpc_list_rwlock));
+        return match ready {
+            true => rpc_response!(200, Full::new(Bytes::from("OK"))),
+            false => rpc_response!(503, Full::new(Bytes::from("Not ready"))),
+        };
+    }
+
+    // Serve the request from the chain its path is for
+    let connection_params = match connection_params.route(tx.uri().path()) {
+        Some(connection_params) => connection_params,
+        None => return rpc_response!(404, Full::new(Bytes::from("Unknown chain"))),
+    };
+
+    // Answer CORS preflights ourselves if CORS is enabled
+    let (cors_enabled, allow_origin) = {
+        let config_guard = connection_params.config.read().unwrap();
+        (
+            !config_guard.allowed_origins.is_empty(),
+            allowed_origin(&config_guard.allowed_origins, tx.headers().get(ORIGIN)),
+        )
+    };
+    if cors_enabled && tx.method() == hyper::Method::OPTIONS {
+        return Ok(preflight_response(allow_origin));
+    }
+
+    // Compress responses above this size if the client accepts it
+    let compression = {
+        let config_guard = connection_params.config.read().unwrap();
+        config_guard
+            .compression
+            .then(|| negotiate_encoding(tx.headers().get(ACCEPT_ENCODING)))
+            .flatten()
+            .map(|encoding| (encoding, config_guard.compression_min_size))
+    };
+
+    // Reject requests without the right bearer token before touching the body
+    let authorized = match &connection_params.config.read().unwrap().auth_token {
+        Some(auth_token) => is_authorized(tx.headers().get(AUTHORIZATION), auth_token),
+        None => true,
+    };
+    if !authorized {
+        let mut response = unauthorized_response();
+        if cors_enabled {
+            add_cors_headers(&mut response, allow_origin);
+        }
+        return Ok(response);
+    }
+
+    // Clients over their budget have to come back later
+    if let Some(peer) = connection_params.peer {
+        if let Err(retry_after) = connection_params.rate_limiter.check(peer.ip()) {
+            warn_limited!(
+                key = format!("rate limited {}", peer.ip()),
+                "Rate limiting {}",
+                peer.ip()
+            );
+            let mut response = rate_limited_response(retry_after);
+            if cors_enabled {
+                add_cors_headers(&mut response, allow_origin);
+            }
+            return Ok(response);
+        }
+    }
+
+    // Check if the request is a websocket upgrade request.
+    if is_upgrade_request(&tx) {
+        info!("Received WS upgrade request");
+
+        if !connection_params.config.read().unwrap().is_ws {
+            return rpc_response!(
+                500,
+                Full::new(Bytes::from(
+                    "{code:-32005, message:\"error: WebSockets are disabled!\"}".to_string(),
+                ))
+            );
+        }
+
+        let (response, websocket) = match upgrade(&mut tx, None) {
+            Ok((response, websocket)) => (response, websocket),
+            Err(e) => {
+                error!("Websocket upgrade error: {e}");
+                return rpc_response!(500, Full::new(Bytes::from(
+                    "{code:-32004, message:\"error: Websocket upgrade error! Try again later...\"}"
+                        .to_string(),
+                )));
+            }
+        };
+
+        let cache_args = CacheArgs {
+            finalized_rx: connection_params.channels.finalized_rx.as_ref().clone(),
+            named_numbers: connection_params.named_numbers.clone(),
+            cache: connection_params.cache.clone(),
+            head_cache: connection_params.head_cache.clone(),
+            head_cache_enabled: connection_params.config.read().unwrap().head_cache_enabled,
+            block_params: connection_params
+                .config
+                .read()
+                .unwrap()
+                .block_params
+                .clone(),
+            cache_state: connection_params.cache_state.clone(),
+            reorg_window: connection_params.reorg_window.clone(),
+            schema_version: CACHE_SCHEMA_VERSION,
+            cache_version: connection_params.config.read().unwrap().cache_version,
+            migrate_cache: connection_params
+                .config
+                .read()
+                .unwrap()
+                .lazy_cache_migration,
+            keep_expired: connection_params.config.read().unwrap().serve_stale_on_error,
+            method_cache: connection_params
+                .config
+                .read()
+                .unwrap()
+                .method_cache
+                .clone(),
+            method_filter: connection_params
+                .config
+                .read()
+                .unwrap()
+                .method_filter
+                .clone(),
+            memory_cache: connection_params.memory_cache.clone(),
+            response_transform: connection_params
+                .config
+                .read()
+                .unwrap()
+                .response_transform
+                .clone(),
+        };
+
+        let max_subscription_lifetime = Duration::from_millis(
+            connection_params
+                .config
+                .read()
+                .unwrap()
+                .max_subscription_lifetime,
+        );
+
+        let notification_buffer = connection_params
+            .config
+            .read()
+            .unwrap()
+            .ws_notification_buffer;
+
+        let sub_queue = SubscriptionQueue::new(
+            &connection_params.rpc_list_rwlock,
+            Duration::from_millis(
+                connection_params
+                    .config
+                    .read()
+                    .unwrap()
+                    .subscription_queue_timeout,
+            ),
+        )
+        .with_unknown_subscriptions(
+            connection_params
+                .config
+                .read()
+                .unwrap()
+                .unknown_subscriptions,
+        )
+        .with_local_newheads(connection_params.config.read().unwrap().local_newheads);
+
+        // Spawn a task to handle the websocket connection.
+        tokio::task::spawn(async move {
+            if let Err(e) = serve_websocket(
+                websocket,
+                connection_params.channels.incoming_tx,
+                connection_params.channels.outgoing_rx,
+                connection_params.sub_data.clone(),
+                sub_queue,
+                cache_args,
+                max_subscription_lifetime,
+                notification_buffer,
+            )
+            .await
+            {
+                error!("Websocket connection error: {e}");
+            }
+        });
+
+        // Return the response so the spawned future can continue.
+        return Ok(response);
+    }
+
+    // Send request and measure time
+    let response: Result<hyper::Response<Full<Bytes>>, Infallible>;
+    let url: Option<String>;
+
+    // RequestParams from config
+    let params = {
+        let config_guard = connection_params.config.read().unwrap();
+
+        // Clients are clamped to `normal` priority unless trusted with more
+        let max_priority = connection_params
+            .peer
+            .and_then(|peer| config_guard.priority_clients.get(&peer.ip()).copied())
+            .unwrap_or_default();
+
+        RequestParams {
+            ttl: config_guard.ttl,
+            method_timeouts: config_guard.method_timeouts.clone(),
+            max_retries: config_guard.max_retries,
+            future_blocks: config_guard.future_blocks,
+            block_params: config_guard.block_params.clone(),
+            method_cache: config_guard.method_cache.clone(),
+            method_filter: config_guard.method_filter.clone(),
+            method_routing: config_guard.method_routing.clone(),
+            hedge_delay: Duration::from_millis(config_guard.hedge_delay),
+            selection_strategy: config_guard.selection_strategy,
+            validate_responses: config_guard.validate_responses,
+            serve_stale_on_error: config_guard.serve_stale_on_error,
+            shadow_sample_rate: config_guard.shadow_sample_rate,
+            shard_logs_cache: config_guard.shard_logs_cache,
+            max_logs_range: config_guard.max_logs_range,
+            max_logs_chunks: config_guard.max_logs_chunks,
+            broadcast_transactions: config_guard.broadcast_transactions,
+            serve_block_number_locally: config_guard.serve_block_number_locally,
+            serve_named_blocks_locally: config_guard.serve_named_blocks_locally,
+            archive_threshold: config_guard.archive_threshold,
+            no_archive_message: config_guard.no_archive_message.clone(),
+            max_request_bytes: config_guard.max_request_bytes,
+            max_batch_size: config_guard.max_batch_size,
+            batch_concurrency: config_guard.batch_concurrency,
+            strict_jsonrpc: config_guard.strict_jsonrpc,
+            priority: request_priority(tx.headers().get(PRIORITY_HEADER), max_priority),
+            session: connection_params
+                .sticky
+                .session(tx.headers(), connection_params.peer),
+            deadline: request_deadline(
+                tx.headers().get(TIMEOUT_HEADER),
+                config_guard.request_deadline_ms,
+            )
+            .map(|deadline| received + deadline),
+        }
+    };
+
+    // Check if we have the response hashed, and if not forward it
+    // to the best available RPC.
+    //
+    // Also handle cache insertions.
+    connection_params.metrics.count_request();
+    let span = info_span!("request", method = field::Empty);
+    let mut access = connection_params
+        .access_log
+        .sample()
+        .then(AccessEntry::default);
+    // `url` is an Option<> that either contains the URL of the RPC we forwarded
+    // our request to, or is None if the result was cached.
+    // Its latency was already updated with how long the upstream call took.
+    let time = Instant::now();
+    (response, url) = forward_body(tx, &connection_params, params, access.as_mut())
+        .instrument(span.clone())
+        .await;
+    let time = time.elapsed();
+
+    let mut response = match response {
+        Ok(response) => response,
+        Err(err) => match err {},
+    };
+
+    // Errors we return before getting a response are misses too
+    if !response.headers().contains_key(CACHE_STATUS_HEADER) {
+        response.headers_mut().insert(
+            CACHE_STATUS_HEADER,
+            HeaderValue::from_static(CacheStatus::Miss.as_str()),
+        );
+    }
+
+    let cache = response.headers()[CACHE_STATUS_HEADER]
+        .to_str()
+        .unwrap_or_default();
+    span.in_scope(|| {
+        info!(
+            rpc = url.as_deref().unwrap_or("none"),
+            latency_ms = time.as_secs_f64() * 1000.0,
+            cache,
+            "Request served"
+        );
+    });
+
+    if let Some(mut access) = access {
+        access.client_ip = connection_params.peer.map(|peer| peer.ip());
+        access.cache = cache.to_string();
+        access.rpc = url.clone();
+        access.status = response.status().as_u16();
+        access.latency = time;
+        connection_params.access_log.record(&access);
+    }
+
+    // Name the RPC that served the request. Off by default since it leaks our backends
+    if connection_params.config.read().unwrap().expose_node_header {
+        if let Some(url) = url.and_then(|url| HeaderValue::from_str(&url).ok()) {
+            response.headers_mut().insert(NODE_HEADER, url);
+        }
+    }
+
+    if cors_enabled {
+        add_cors_headers(&mut response, allow_origin);
+    }
+
+    if let Some((encoding, min_size)) = compression {
+        response = compress_response(response, encoding, min_size).await;
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        balancer::{
+            processing::ResponseTransform,
+            sticky::SESSION_HEADER,
+        },
+        rpc::mock::MockRpc,
+    };
+    use hyper::{
+        server::conn::http1,
+        service::service_fn,
+    };
+    use hyper_util_blutgang::rt::TokioIo;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    // Serve blutgang on a random port and return its URL
+    async fn spawn_blutgang(rpc_list: Vec<Rpc>, config: Settings) -> String {
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+
+        spawn_blutgang_with(rpc_list, config, readiness, named_numbers).await
+    }
+
+    async fn spawn_blutgang_with(
+        rpc_list: Vec<Rpc>,
+        config: Settings,
+        readiness: Arc<Readiness>,
+        named_numbers: Arc<RwLock<NamedBlocknumbers>>,
+    ) -> String {
+        spawn_blutgang_with_chains(
+            Arc::new(RwLock::new(rpc_list)),
+            Vec::new(),
+            config,
+            readiness,
+            named_numbers,
+        )
+        .await
+    }
+
+    async fn spawn_blutgang_with_chains(
+        rpc_list: Arc<RwLock<Vec<Rpc>>>,
+        chains: Vec<(&str, Vec<Rpc>)>,
+        config: Settings,
+        readiness: Arc<Readiness>,
+        named_numbers: Arc<RwLock<NamedBlocknumbers>>,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let (incoming_tx, _incoming_rx) = mpsc::channel(16);
+        let (_outgoing_tx, outgoing_rx) = broadcast::channel(16);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let admission = Arc::new(AdmissionControl::new(config.max_concurrent_requests));
+        let rate_limiter = Arc::new(RateLimiter::new(
+            config.rate_limit_rps,
+            config.rate_limit_burst,
+        ));
+        let tx_replay_window = Duration::from_millis(config.tx_replay_window);
+        let sticky_ttl = match config.sticky_sessions {
+            true => Duration::from_millis(config.sticky_session_ttl),
+            false => Duration::ZERO,
+        };
+        let channels = RequestChannels::new(Arc::new(finalized_rx), incoming_tx, outgoing_rx);
+        let config = Arc::new(RwLock::new(config));
+
+        // Every chain gets its own RPCs and cache
+        let params = |rpc_list: Arc<RwLock<Vec<Rpc>>>,
+                      named_numbers: Arc<RwLock<NamedBlocknumbers>>| {
+            ConnectionParams::new(
+                &rpc_list,
+                channels.clone(),
+                &named_numbers,
+                &Arc::new(RwLock::new(BTreeMap::new())),
+                &Arc::new(MemoryCache::default()),
+                &Arc::new(SubscriptionData::new()),
+                &CacheArgs::temporary_cache(),
+                &Arc::new(CacheWriteState::default()),
+                &Arc::new(ReorgWindow::default()),
+                &Arc::new(TxReplayCache::new(tx_replay_window)),
+                &Arc::new(StickySessions::new(sticky_ttl)),
+                &admission,
+                &rate_limiter,
+                &readiness,
+                &Arc::new(SingleFlight::default()),
+                &Arc::new(Metrics::default()),
+                &config,
+            )
+        };
+        let chains = Arc::new(
+            chains
+                .into_iter()
+                .map(|(name, rpc_list)| {
+                    let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+                    (
+                        name.to_string(),
+                        params(Arc::new(RwLock::new(rpc_list)), named_numbers),
+                    )
+                })
+                .collect(),
+        );
+        let default_chain = params(rpc_list, named_numbers).with_chains(&chains);
+
+        tokio::spawn(async move {
+            // Keep the channels open for as long as we're serving
+            let _keep = (_finalized_tx, _incoming_rx, _outgoing_tx, _shutdown_tx);
+            loop {
+                let (stream, peer) = listener.accept().await.unwrap();
+                let io = TokioIo::new(stream);
+
+                let connection_params = default_chain.clone().with_peer(peer);
+
+                let shutdown_rx = shutdown_rx.clone();
+                tokio::spawn(async move {
+                    accept!(
+                        io,
+                        http1::Builder::new(),
+                        connection_params.clone(),
+                        shutdown_rx
+                    );
+                });
+            }
+        });
+
+        url
+    }
+
+    async fn post(url: &str, body: Value) -> Value {
+        reqwest::Client::new()
+            .post(url)
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_future_block_response() {
+        let response: Result<hyper::Response<Full<Bytes>>, Infallible> = future_block!(json!(7));
+        let body = response
+            .unwrap()
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["id"], 7);
+        assert_eq!(body["error"]["code"], -32000);
+    }
+
+    #[tokio::test]
+    async fn test_serve_block_number_locally() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let url = spawn_blutgang_with(
+            vec![rpc],
+            Settings::default(),
+            readiness,
+            named_numbers.clone(),
+        )
+        .await;
+
+        // Forwarded while we don't know the head
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+        let response = post(&url, tx.clone()).await;
+        assert_eq!(response["result"], "0x1");
+        assert_eq!(mock.requests(), 1);
+
+        named_numbers.write().unwrap().latest = 255;
+        let tx = json!({"jsonrpc": "2.0", "id": 2, "method": "eth_blockNumber", "params": []});
+        let response = post(&url, tx).await;
+        assert_eq!(response["result"], "0xff");
+        assert_eq!(response["id"], 2);
+        assert_eq!(mock.requests(), 1);
+    }
+
+    #[test]
+    fn test_tracked_block_number() {
+        let (finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = RwLock::new(NamedBlocknumbers::default());
+        assert_eq!(
+            tracked_block_number("finalized", &finalized_rx, &named_numbers),
+            0
+        );
+
+        finalized_tx.send_replace(100);
+        assert_eq!(
+            tracked_block_number("finalized", &finalized_rx, &named_numbers),
+            100
+        );
+        finalized_tx.send_replace(164);
+        assert_eq!(
+            tracked_block_number("finalized", &finalized_rx, &named_numbers),
+            164
+        );
+
+        named_numbers.write().unwrap().safe = 170;
+        assert_eq!(
+            tracked_block_number("safe", &finalized_rx, &named_numbers),
+            170
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_named_blocks_locally() {
+        let mock = MockRpc::spawn(Duration::ZERO, |request| {
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": {"number": "0x20", "hash": "0xabc"}})
+        })
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let url = spawn_blutgang_with(
+            vec![rpc],
+            Settings::default(),
+            readiness,
+            named_numbers.clone(),
+        )
+        .await;
+        let safe =
+            json!({"jsonrpc": "2.0", "id": 1, "method": "blutgang_safeBlockNumber", "params": []});
+
+        // Asks an RPC for the block while we don't know the safe block
+        let response = post(&url, safe.clone()).await;
+        assert_eq!(response["result"], "0x20");
+        assert_eq!(response["id"], 1);
+        assert_eq!(mock.requests(), 1);
+
+        named_numbers.write().unwrap().safe = 255;
+        let response = post(&url, safe).await;
+        assert_eq!(response["result"], "0xff");
+        assert_eq!(response["id"], 1);
+        assert_eq!(mock.requests(), 1);
+
+        // The whole block always comes from an RPC
+        let tx = json!({"jsonrpc": "2.0", "id": 2, "method": "eth_getBlockByNumber", "params": ["safe", false]});
+        assert_eq!(post(&url, tx).await["result"]["hash"], "0xabc");
+        assert_eq!(mock.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_block_tags_resolved_before_caching() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x64"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        named_numbers.write().unwrap().latest = 16;
+        let url =
+            spawn_blutgang_with(vec![rpc], Settings::default(), readiness, named_numbers).await;
+
+        let address = "0x407d73d8a49eeb85d32cf465507dd71d507100c1";
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [address, "latest"]});
+        assert_eq!(post(&url, tx).await["result"], "0x64");
+        assert_eq!(mock.requests(), 1);
+
+        // Same block, by number
+        let tx = json!({"jsonrpc": "2.0", "id": 2, "method": "eth_getBalance", "params": [address, "0x10"]});
+        let response = post(&url, tx).await;
+        assert_eq!(response["result"], "0x64");
+        assert_eq!(response["id"], 2);
+        assert_eq!(mock.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_archive_routing() {
+        let full = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let archive = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let mut archive_rpc = Rpc::new(archive.url.clone(), None, 0, 0, 1.0);
+        archive_rpc.archive = true;
+        let config = Settings {
+            archive_threshold: 128,
+            ..Default::default()
+        };
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        named_numbers.write().unwrap().latest = 1000;
+        let full_rpc = Rpc::new(full.url.clone(), None, 0, 0, 1.0);
+        let url = spawn_blutgang_with(
+            vec![full_rpc.clone(), archive_rpc],
+            config.clone(),
+            readiness.clone(),
+            named_numbers.clone(),
+        )
+        .await;
+
+        let address = "0x407d73d8a49eeb85d32cf465507dd71d507100c1";
+        for id in 0..5 {
+            let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_getBalance", "params": [address, format!("0x{:x}", 100 + id)]});
+            post(&url, tx).await;
+        }
+        assert_eq!(full.requests(), 0);
+        assert_eq!(archive.requests(), 5);
+
+        // Without an archive node we get an error instead
+        let url = spawn_blutgang_with(vec![full_rpc], config, readiness, named_numbers).await;
+        let tx = json!({"jsonrpc": "2.0", "id": 7, "method": "eth_getBalance", "params": [address, "0x10"]});
+        let response = post(&url, tx).await;
+        assert_eq!(response["error"]["code"], -32002);
+        assert_eq!(response["id"], 7);
+        assert_eq!(full.requests(), 0);
+
+        // Recent state can be served by any node
+        let tx = json!({"jsonrpc": "2.0", "id": 8, "method": "eth_getBalance", "params": [address, "0x3e0"]});
+        assert_eq!(post(&url, tx).await["result"], "0x1");
+        assert_eq!(full.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_method_routing() {
+        let respond =
+            |request: Value| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"});
+        let full = MockRpc::spawn(Duration::ZERO, respond).await;
+        let tracing = MockRpc::spawn(Duration::ZERO, respond).await;
+        let full_rpc = Rpc::new(full.url.clone(), None, 0, 0, 1.0);
+        let mut tracing_rpc = Rpc::new(tracing.url.clone(), None, 0, 0, 1.0);
+        tracing_rpc.groups = Arc::from(["tracing".to_string()]);
+        let config = Settings {
+            method_routing: Arc::new(MethodRouting::new(HashMap::from([(
+                "trace_".to_string(),
+                "tracing".to_string(),
+            )]))),
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![full_rpc.clone(), tracing_rpc], config.clone()).await;
+
+        for id in 0..5 {
+            let tx = json!({"jsonrpc": "2.0", "id": id, "method": "trace_block", "params": [format!("0x{:x}", id)]});
+            assert_eq!(post(&url, tx).await["result"], "0x1");
+        }
+        assert_eq!(full.requests(), 0);
+        assert_eq!(tracing.requests(), 5);
+
+        // Without an RPC in the group we get an error instead
+        let url = spawn_blutgang(vec![full_rpc], config).await;
+        let tx = json!({"jsonrpc": "2.0", "id": 7, "method": "trace_block", "params": ["0x10"]});
+        let response = post(&url, tx).await;
+        assert_eq!(response["error"]["code"], -32002);
+        assert_eq!(response["id"], 7);
+        assert_eq!(full.requests(), 0);
+
+        // Other methods go to any RPC
+        let tx = json!({"jsonrpc": "2.0", "id": 8, "method": "eth_chainId", "params": []});
+        assert_eq!(post(&url, tx).await["result"], "0x1");
+        assert_eq!(full.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_strip_response_fields() {
+        let mock = MockRpc::spawn(Duration::ZERO, |request| {
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": {"balance": "0x10", "providerExtra": 1}})
+        })
+        .await;
+        let config = Settings {
+            response_transform: Arc::new(
+                ResponseTransform::new(&["result.providerExtra".to_string()]).unwrap(),
+            ),
+            ..Default::default()
+        };
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        named_numbers.write().unwrap().latest = 16;
+        let url = spawn_blutgang_with(
+            vec![Rpc::new(mock.url.clone(), None, 0, 0, 1.0)],
+            config,
+            readiness,
+            named_numbers,
+        )
+        .await;
+
+        // Stripped whether it comes from the RPC or the cache
+        for id in 0..2 {
+            let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_getBalance", "params": ["0x01", "0x10"]});
+            let response = post(&url, tx).await;
+            assert_eq!(response["id"], id);
+            assert_eq!(response["result"], json!({"balance": "0x10"}));
+        }
+        assert_eq!(mock.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_full_transactions_cached_separately() {
+        // Full transactions or just their hashes, depending on the flag
+        let mock = MockRpc::spawn(Duration::ZERO, |request| {
+            let transaction = match request["params"][1].as_bool() {
+                Some(true) => json!({"hash": "0xaa", "from": "0xbb"}),
+                _ => json!("0xaa"),
+            };
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": {"number": "0x10", "transactions": [transaction]}})
+        })
+        .await;
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        named_numbers.write().unwrap().latest = 16;
+        let url = spawn_blutgang_with(
+            vec![Rpc::new(mock.url.clone(), None, 0, 0, 1.0)],
+            Settings::default(),
+            readiness,
+            named_numbers,
+        )
+        .await;
+
+        // Each variant gets its own cache entry, so neither gets served the other's shape
+        for id in 0..2 {
+            for full in [false, true, false] {
+                let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_getBlockByNumber", "params": ["0x10", full]});
+                let transaction = &post(&url, tx).await["result"]["transactions"][0];
+                assert_eq!(transaction.is_object(), full);
+            }
+        }
+        assert_eq!(mock.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_no_healthy_upstreams() {
+        // Every RPC got moved to the poverty list
+        let url = spawn_blutgang(Vec::new(), Settings::default()).await;
+
+        for id in 0..2 {
+            let response = reqwest::Client::new()
+                .post(&url)
+                .json(&json!({"jsonrpc": "2.0", "id": id, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "latest"]}))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 503);
+
+            let response: Value = response.json().await.unwrap();
+            assert_eq!(response["id"], id);
+            assert_eq!(response["error"]["code"], -32002);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ready_after_warmup() {
+        let readiness = Arc::new(Readiness::new(true));
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let url = spawn_blutgang_with(
+            vec![Rpc::new("http://127.0.0.1:1".to_string(), None, 0, 0, 1.0)],
+            Settings::default(),
+            readiness.clone(),
+            named_numbers,
+        )
+        .await;
+        let ready = || {
+            async {
+                reqwest::get(format!("{}/ready", url))
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+
+        assert_eq!(ready().await, 503);
+
+        // Still warming up
+        readiness.mark_health_checked();
+        assert_eq!(ready().await, 503);
+
+        readiness.mark_warmed_up();
+        assert_eq!(ready().await, 200);
+    }
+
+    #[tokio::test]
+    async fn test_ready_every_chain() {
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let rpc = || Rpc::new("http://127.0.0.1:1".to_string(), None, 0, 0, 1.0);
+        let ready = |chains| {
+            let readiness = readiness.clone();
+            async move {
+                let url = spawn_blutgang_with_chains(
+                    Arc::new(RwLock::new(vec![rpc()])),
+                    chains,
+                    Settings::default(),
+                    readiness,
+                    Arc::new(RwLock::new(NamedBlocknumbers::default())),
+                )
+                .await;
+                reqwest::get(format!("{}/ready", url))
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+
+        assert_eq!(ready(vec![("arbitrum", vec![rpc()])]).await, 200);
+        // Every RPC of a chain is in poverty
+        assert_eq!(ready(vec![("arbitrum", Vec::new())]).await, 503);
+    }
+
+    #[tokio::test]
+    async fn test_least_latency_prefers_fast_rpc() {
+        let respond =
+            |request: Value| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"});
+        let fast = MockRpc::spawn(Duration::ZERO, respond).await;
+        let slow = MockRpc::spawn(Duration::from_millis(50), respond).await;
+        let rpc_list = vec![
+            Rpc::new(fast.url.clone(), None, 0, 0, 1.0),
+            Rpc::new(slow.url.clone(), None, 0, 0, 1.0),
+        ];
+        let config = Settings {
+            selection_strategy: SelectionStrategy::LeastLatency,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(rpc_list, config).await;
+
+        for id in 0..40 {
+            let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_blockNumber", "params": []});
+            post(&url, tx).await;
+        }
+
+        assert_eq!(fast.requests() + slow.requests(), 40);
+        assert!(
+            fast.requests() > slow.requests() * 2,
+            "fast: {}, slow: {}",
+            fast.requests(),
+            slow.requests()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_responses_are_retried() {
+        // Responds with blocks that have no hash
+        let bad = MockRpc::spawn(Duration::ZERO, |request| {
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": {"number": "0x10", "transactions": []}})
+        })
+        .await;
+        let good = MockRpc::spawn(Duration::ZERO, |request| {
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": {
+                "number": "0x10",
+                "hash": "0xabcd",
+                "parentHash": "0x1234",
+                "transactions": [],
+            }})
+        })
+        .await;
+        let rpc_list = vec![
+            Rpc::new(bad.url.clone(), None, 0, 0, 1.0),
+            Rpc::new(good.url.clone(), None, 0, 0, 1.0),
+        ];
+        let config = Settings {
+            validate_responses: true,
+            selection_strategy: SelectionStrategy::Random,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(rpc_list, config).await;
+
+        for id in 0..5 {
+            let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_getBlockByHash", "params": [format!("0x{:064x}", id), false]});
+            let response = post(&url, tx).await;
+            assert_eq!(response["result"]["hash"], "0xabcd");
+        }
+        assert_eq!(good.requests(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_identical_requests_are_coalesced() {
+        let mock = MockRpc::spawn(Duration::from_millis(200), |request| {
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": {
+                "number": "0x10",
+                "hash": "0xabcd",
+                "parentHash": "0x1234",
+                "transactions": [],
+            }})
+        })
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let url = spawn_blutgang(vec![rpc], Settings::default()).await;
+
+        let requests = (0..5).map(|id| {
+            let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_getBlockByHash", "params": ["0xabcd", false]});
+            post(&url, tx)
+        });
+        let responses = futures::future::join_all(requests).await;
+
+        for (id, response) in responses.iter().enumerate() {
+            assert_eq!(response["result"]["hash"], "0xabcd");
+            assert_eq!(response["id"], id);
+        }
+        assert_eq!(mock.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_requests_share_errors() {
+        let mock = MockRpc::spawn(
+            Duration::from_millis(300),
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            ttl: 50,
+            max_retries: 1,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+
+        let client = reqwest::Client::new();
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x0000000000000000000000000000000000000000", "0x1"]});
+        let requests = (0..5).map(|_| client.post(&url).json(&tx).send());
+        for response in futures::future::join_all(requests).await {
+            assert_eq!(response.unwrap().status(), 408);
+        }
+        assert_eq!(mock.requests(), 1);
+
+        // The failed flight is gone, so the next request goes upstream again
+        let response = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(response.status(), 408);
+        assert_eq!(mock.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_latency_is_upstream_time() {
+        let mock = MockRpc::spawn(
+            Duration::from_millis(300),
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            mock.url.clone(),
+            None,
+            0,
+            0,
+            1.0,
+        )]));
+        let config = Settings {
+            ttl: 50,
+            max_retries: 1,
+            ..Default::default()
+        };
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let url = spawn_blutgang_with_chains(
+            rpc_list.clone(),
+            Vec::new(),
+            config,
+            readiness,
+            Arc::new(RwLock::new(NamedBlocknumbers::default())),
+        )
+        .await;
+
+        // Timeouts count as taking the whole ttl
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x0000000000000000000000000000000000000000", "0x1"]});
+        let response = reqwest::Client::new()
+            .post(&url)
+            .json(&tx)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 408);
+        assert_eq!(rpc_list.read().unwrap()[0].status.latency_ms, 50.0);
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_requests_share_upstream_errors() {
+        let mock = MockRpc::spawn(
+            Duration::from_millis(200),
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        mock.set_status(500);
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            max_retries: 1,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+
+        let client = reqwest::Client::new();
+        let requests = (0..5).map(|id| {
+            let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_getBalance", "params": ["0x0000000000000000000000000000000000000000", "0x1"]});
+            client.post(&url).json(&tx).send()
+        });
+        for (id, response) in futures::future::join_all(requests).await.into_iter().enumerate() {
+            let response = response.unwrap();
+            assert_eq!(response.status(), 502);
+            let response: Value = response.json().await.unwrap();
+            assert_eq!(response["id"], id);
+            assert_eq!(response["error"]["code"], -32007);
+            assert!(response["error"]["message"]
+                .as_str()
+                .unwrap()
+                .contains("HTTP 500"));
+        }
+        assert_eq!(mock.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tx_replay_protection() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0xabc"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            tx_replay_window: 60_000,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_sendRawTransaction", "params": ["0xf86c"]});
+        let response = post(&url, tx).await;
+        assert_eq!(response["result"], "0xabc");
+        assert_eq!(mock.requests(), 1);
+
+        // Resubmitting returns the original response without hitting the RPC
+        let tx = json!({"jsonrpc": "2.0", "id": 2, "method": "eth_sendRawTransaction", "params": ["0xf86c"]});
+        let response = post(&url, tx).await;
+        assert_eq!(response["result"], "0xabc");
+        assert_eq!(response["id"], 2);
+        assert_eq!(mock.requests(), 1);
+
+        // Different transactions still get submitted
+        let tx = json!({"jsonrpc": "2.0", "id": 3, "method": "eth_sendRawTransaction", "params": ["0xf86d"]});
+        post(&url, tx).await;
+        assert_eq!(mock.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tx_replay_protection_concurrent() {
+        let mock = MockRpc::spawn(
+            Duration::from_millis(50),
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0xabc"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            tx_replay_window: 60_000,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+
+        // Submissions that arrive while the first is in-flight wait for its response
+        let responses = futures::future::join_all((0..4).map(|id| {
+            let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_sendRawTransaction", "params": ["0xf86c"]});
+            post(&url, tx)
+        }))
+        .await;
+        for (id, response) in responses.iter().enumerate() {
+            assert_eq!(response["result"], "0xabc");
+            assert_eq!(response["id"], id);
+        }
+        assert_eq!(mock.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_transactions() {
+        let known = MockRpc::spawn(Duration::ZERO, |request| {
+            json!({"jsonrpc": "2.0", "id": request["id"], "error": {"code": -32000, "message": "already known"}})
+        })
+        .await;
+        let accepted = MockRpc::spawn(
+            Duration::from_millis(20),
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0xabc"}),
+        )
+        .await;
+        let rpc_list = vec![
+            Rpc::new(known.url.clone(), None, 0, 0, 1.0),
+            Rpc::new(accepted.url.clone(), None, 0, 0, 1.0),
+        ];
+        let config = Settings {
+            broadcast_transactions: true,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(rpc_list, config).await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 7, "method": "eth_sendRawTransaction", "params": ["0xf86c"]});
+        let response = post(&url, tx).await;
+        assert_eq!(response["result"], "0xabc");
+        assert_eq!(response["id"], 7);
+        assert_eq!(known.requests(), 1);
+        assert_eq!(accepted.requests(), 1);
+
+        // Other requests still go to a single RPC
+        let tx = json!({"jsonrpc": "2.0", "id": 8, "method": "eth_chainId", "params": []});
+        post(&url, tx).await;
+        assert_eq!(known.requests() + accepted.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_tx_replay_protection_disabled() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0xabc"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let url = spawn_blutgang(vec![rpc], Settings::default()).await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_sendRawTransaction", "params": ["0xf86c"]});
+        post(&url, tx.clone()).await;
+        post(&url, tx).await;
+        assert_eq!(mock.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+        let client = reqwest::Client::new();
+
+        let preflight = client
+            .request(reqwest::Method::OPTIONS, &url)
+            .header("Origin", "https://app.example.com")
+            .header("Access-Control-Request-Method", "POST")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(preflight.status(), 204);
+        assert_eq!(
+            preflight.headers()["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+        assert_eq!(
+            preflight.headers()["access-control-allow-methods"],
+            "POST, OPTIONS"
+        );
+        assert_eq!(mock.requests(), 0);
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []});
+        let allowed = client
+            .post(&url)
+            .header("Origin", "https://app.example.com")
+            .json(&tx)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            allowed.headers()["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+
+        let denied = client
+            .post(&url)
+            .header("Origin", "https://evil.example.com")
+            .json(&tx)
+            .send()
+            .await
+            .unwrap();
+        assert!(!denied.headers().contains_key("access-control-allow-origin"));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            rate_limit_rps: 1,
+            rate_limit_burst: 2,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+        let client = reqwest::Client::new();
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []});
+
+        for _ in 0..2 {
+            let response = client.post(&url).json(&tx).send().await.unwrap();
+            assert_eq!(response.status(), 200);
+        }
+
+        let limited = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(limited.status(), 429);
+        assert_eq!(limited.headers()["retry-after"], "1");
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let mock = MockRpc::spawn(Duration::ZERO, |request| {
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": format!("0x{}", "ab".repeat(2048))})
+        })
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            compression: true,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+        let client = reqwest::Client::new();
+        let tx =
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getCode", "params": ["0x0", "0x1"]});
+
+        let compressed = client
+            .post(&url)
+            .header("Accept-Encoding", "gzip, zstd")
+            .json(&tx)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(compressed.headers()["content-encoding"], "gzip");
+        let body = compressed.bytes().await.unwrap();
+        let body: Value = serde_json::from_reader(flate2::read::GzDecoder::new(&body[..])).unwrap();
+        assert_eq!(body["result"], format!("0x{}", "ab".repeat(2048)));
+
+        // Clients can ask for zstd over gzip
+        let compressed = client
+            .post(&url)
+            .header("Accept-Encoding", "gzip;q=0.5, zstd")
+            .json(&tx)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(compressed.headers()["content-encoding"], "zstd");
+        let body = compressed.bytes().await.unwrap();
+        let body: Value = serde_json::from_slice(&zstd::decode_all(&body[..]).unwrap()).unwrap();
+        assert_eq!(body["result"], format!("0x{}", "ab".repeat(2048)));
+
+        // Uncompressed for clients that don't accept it
+        let plain = client.post(&url).json(&tx).send().await.unwrap();
+        assert!(!plain.headers().contains_key("content-encoding"));
+        let body: Value = plain.json().await.unwrap();
+        assert_eq!(body["result"], format!("0x{}", "ab".repeat(2048)));
+    }
+
+    #[tokio::test]
+    async fn test_cache_status_header() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        named_numbers.write().unwrap().latest = 16;
+        let config = Settings {
+            expose_node_header: true,
+            ..Default::default()
+        };
+        let url = spawn_blutgang_with(vec![rpc], config, readiness, named_numbers).await;
+        let client = reqwest::Client::new();
+        let address = "0x407d73d8a49eeb85d32cf465507dd71d507100c1";
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [address, "0x10"]});
+
+        let miss = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(miss.headers()[CACHE_STATUS_HEADER], "miss");
+        assert_eq!(miss.headers()[NODE_HEADER], mock.url.as_str());
+
+        let hit = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(hit.headers()[CACHE_STATUS_HEADER], "hit");
+        assert!(!hit.headers().contains_key(NODE_HEADER));
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+        let local = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(local.headers()[CACHE_STATUS_HEADER], "local");
+    }
+
+    #[tokio::test]
+    async fn test_node_header_after_pool_change() {
+        let mock = MockRpc::spawn(
+            Duration::from_millis(100),
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(mock.url.clone(), None, 0, 0, 1.0)]));
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let config = Settings {
+            expose_node_header: true,
+            ..Default::default()
+        };
+        let url = spawn_blutgang_with_chains(
+            rpc_list.clone(),
+            Vec::new(),
+            config,
+            readiness,
+            Arc::new(RwLock::new(NamedBlocknumbers::default())),
+        )
+        .await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []});
+        let response = tokio::spawn(async move {
+            reqwest::Client::new()
+                .post(&url)
+                .json(&tx)
+                .send()
+                .await
+                .unwrap()
+        });
+
+        // An RPC takes the position of the one serving the request while it's in-flight
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        rpc_list
+            .write()
+            .unwrap()
+            .insert(0, Rpc::new("http://127.0.0.1:1".to_string(), None, 0, 0, 1.0));
+
+        let response = response.await.unwrap();
+        assert_eq!(response.headers()[NODE_HEADER], mock.url.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_cache_key_ignores_id_and_hex_casing() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        named_numbers.write().unwrap().latest = 16;
+        let url =
+            spawn_blutgang_with(vec![rpc], Settings::default(), readiness, named_numbers).await;
+        let client = reqwest::Client::new();
+        let address = "0x407d73d8a49eeb85d32cf465507dd71d507100c1";
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [address, "0x10"]});
+        let miss = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(miss.headers()[CACHE_STATUS_HEADER], "miss");
+
+        // Different id, checksummed address and padded block number
+        let tx = json!({
+            "jsonrpc": "2.0",
+            "id": "abc",
+            "method": "eth_getBalance",
+            "params": ["0x407D73d8a49eeb85D32Cf465507dd71d507100c1", "0x010"],
+        });
+        let hit = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(hit.headers()[CACHE_STATUS_HEADER], "hit");
+        let body: Value = hit.json().await.unwrap();
+        assert_eq!(body["id"], "abc");
+        assert_eq!(body["result"], "0x1");
+
+        let tx = json!({"jsonrpc": "2.0", "id": 42, "method": "eth_getBalance", "params": [address, "0x10"]});
+        let hit = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(hit.headers()[CACHE_STATUS_HEADER], "hit");
+        let body: Value = hit.json().await.unwrap();
+        assert_eq!(body["id"], 42);
+
+        assert_eq!(mock.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_jsonrpc_version() {
+        // Answer with the version the RPC got
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": request["jsonrpc"]}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let lenient = spawn_blutgang(vec![rpc.clone()], Settings::default()).await;
+        let strict = spawn_blutgang(
+            vec![rpc],
+            Settings {
+                strict_jsonrpc: true,
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // Different params so the second isn't served from the cache
+        for tx in [
+            json!({"id": 1, "method": "eth_gasPrice", "params": [1]}),
+            json!({"jsonrpc": "1.0", "id": 1, "method": "eth_gasPrice", "params": [2]}),
+        ] {
+            let body = post(&lenient, tx.clone()).await;
+            assert_eq!(body["result"], "2.0", "{}", tx);
+
+            let body = post(&strict, tx.clone()).await;
+            assert_eq!(body["error"]["code"], -32600, "{}", tx);
+            assert_eq!(body["id"], 1);
+        }
+
+        let body = post(
+            &strict,
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eth_gasPrice"}),
+        )
+        .await;
+        assert_eq!(body["result"], "2.0");
+    }
+
+    #[tokio::test]
+    async fn test_malformed_requests() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let url = spawn_blutgang(vec![rpc], Settings::default()).await;
+        let client = reqwest::Client::new();
+
+        let send = |body: Vec<u8>| {
+            client
+                .post(&url)
+                .header("content-type", "application/json")
+                .body(body)
+                .send()
+        };
+
+        // Not JSON, or not even UTF-8
+        for body in [
+            b"{\"method\": ".to_vec(),
+            b"not json".to_vec(),
+            vec![0xff, 0xfe, 0xfd],
+        ] {
+            let response = send(body).await.unwrap();
+            assert_eq!(response.status(), 200);
+            let body: Value = response.json().await.unwrap();
+            assert_eq!(body["error"]["code"], -32700);
+            assert_eq!(body["id"], Value::Null);
+        }
+
+        let invalid = [
+            json!({"jsonrpc": "2.0", "id": 1, "params": []}),
+            json!({"jsonrpc": "2.0", "id": 2, "method": 5}),
+            json!(5),
+        ];
+        for tx in invalid {
+            let response = send(tx.to_string().into_bytes()).await.unwrap();
+            let body: Value = response.json().await.unwrap();
+            assert_eq!(body["error"]["code"], -32600, "{}", tx);
+            assert_eq!(body["id"], tx.get("id").cloned().unwrap_or_default());
+        }
+
+        // Invalid calls in a batch get their own error
+        let batch = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"},
+            {"jsonrpc": "2.0", "id": 2},
+        ]);
+        let body: Value = send(batch.to_string().into_bytes())
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body[0]["result"], "0x1");
+        assert_eq!(body[1]["error"]["code"], -32600);
+        assert_eq!(body[1]["id"], 2);
+
+        // Nothing malformed made it to the RPC
+        assert_eq!(mock.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_method_timeouts() {
+        let slow = MockRpc::spawn(
+            Duration::from_millis(100),
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": []}),
+        )
+        .await;
+        let rpc = Rpc::new(slow.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            ttl: 50,
+            max_retries: 2,
+            method_timeouts: Arc::new([("eth_getLogs".to_string(), 1000)].into()),
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+        let client = reqwest::Client::new();
+
+        // Gets more time than `ttl`
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getLogs", "params": [{"fromBlock": "0x1", "toBlock": "0x2"}]});
+        let response = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["result"], json!([]));
+
+        // Everything else still times out after `ttl`, once per retry
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []});
+        let response = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(response.status(), 408);
+        assert_eq!(slow.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_pending_requests_are_never_cached() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": {"number": "0x11"}}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        // Would be cached for a minute if it weren't for `pending`
+        let config = Settings {
+            method_cache: Arc::new(MethodCache::new(
+                [("eth_getBlockByNumber".to_string(), 60)].into(),
+                Default::default(),
+            )),
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+        let client = reqwest::Client::new();
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "params": ["pending", false]});
+        for _ in 0..2 {
+            let response = client.post(&url).json(&tx).send().await.unwrap();
+            assert_eq!(response.headers()[CACHE_STATUS_HEADER], "miss");
+        }
+
+        assert_eq!(mock.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_chain_routing() {
+        let chain = |result: &'static str| move |request: Value| json!({"jsonrpc": "2.0", "id": request["id"], "result": result});
+        let eth = MockRpc::spawn(Duration::ZERO, chain("0x1")).await;
+        let arbitrum = MockRpc::spawn(Duration::ZERO, chain("0xa4b1")).await;
+
+        let rpc = |mock: &MockRpc| Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let url = spawn_blutgang_with_chains(
+            Arc::new(RwLock::new(vec![rpc(&eth)])),
+            vec![("arbitrum", vec![rpc(&arbitrum)])],
+            Settings::default(),
+            readiness,
+            Arc::new(RwLock::new(NamedBlocknumbers::default())),
+        )
+        .await;
+
+        // Cached, so a shared cache would answer the second chain with the first one's result
+        let address = "0x407d73d8a49eeb85d32cf465507dd71d507100c1";
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [address, "0x1"]});
+        let balance = |path: &'static str| {
+            let url = format!("{}{}", url, path);
+            let tx = tx.clone();
+            async move {
+                let response = reqwest::Client::new()
+                    .post(&url)
+                    .json(&tx)
+                    .send()
+                    .await
+                    .unwrap();
+                match response.status().as_u16() {
+                    200 => Some(response.json::<Value>().await.unwrap()["result"].clone()),
+                    404 => None,
+                    status => panic!("Unexpected status {}", status),
+                }
+            }
+        };
+
+        assert_eq!(balance("/").await, Some(json!("0x1")));
+        assert_eq!(balance("/arbitrum").await, Some(json!("0xa4b1")));
+        assert_eq!(balance("/arbitrum/").await, Some(json!("0xa4b1")));
+        assert_eq!(balance("/optimism").await, None);
+
+        // Every chain has its own cache
+        assert_eq!(balance("/").await, Some(json!("0x1")));
+        assert_eq!(eth.requests(), 1);
+        assert_eq!(arbitrum.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_node_header_disabled() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let url = spawn_blutgang(vec![rpc], Settings::default()).await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []});
+        let response = reqwest::Client::new()
+            .post(&url)
+            .json(&tx)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[CACHE_STATUS_HEADER], "miss");
+        assert!(!response.headers().contains_key(NODE_HEADER));
+    }
+
+    #[tokio::test]
+    async fn test_auth_token() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            auth_token: Some("hunter2".to_string()),
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+        let client = reqwest::Client::new();
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []});
+
+        let missing = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(missing.status(), 401);
+
+        let wrong = client
+            .post(&url)
+            .bearer_auth("hunter3")
+            .json(&tx)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(wrong.status(), 401);
+        assert_eq!(mock.requests(), 0);
+
+        let response: Value = client
+            .post(&url)
+            .bearer_auth("hunter2")
+            .json(&tx)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(response["result"], "0x1");
+        assert_eq!(mock.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_batch_requests() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": request["params"][0]}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        named_numbers.write().unwrap().latest = 16;
+        let url =
+            spawn_blutgang_with(vec![rpc], Settings::default(), readiness, named_numbers).await;
+        let balance = |id: Value, address: &str| json!({"jsonrpc": "2.0", "id": id, "method": "eth_getBalance", "params": [address, "0x10"]});
+
+        // Warm the cache for the first call
+        post(&url, balance(json!(9), "0xa")).await;
+        assert_eq!(mock.requests(), 1);
+
+        let batch = json!([
+            balance(json!(1), "0xa"),
+            balance(json!(1), "0xb"),
+            balance(json!("c"), "0xc"),
+            42,
+        ]);
+        let response = reqwest::Client::new()
+            .post(&url)
+            .json(&batch)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[CACHE_STATUS_HEADER], "miss");
+
+        let responses: Value = response.json().await.unwrap();
+        let responses = responses.as_array().unwrap();
+        assert_eq!(responses.len(), 4);
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[0]["result"], "0xa");
+        assert_eq!(responses[1]["id"], 1);
+        assert_eq!(responses[1]["result"], "0xb");
+        assert_eq!(responses[2]["id"], "c");
+        assert_eq!(responses[2]["result"], "0xc");
+        assert_eq!(responses[3]["id"], Value::Null);
+        assert_eq!(responses[3]["error"]["code"], -32600);
+
+        // Only the uncached calls were forwarded
+        assert_eq!(mock.requests(), 3);
+
+        assert_eq!(post(&url, json!([])).await, json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_batch_limits() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": request["params"][0]}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            max_batch_size: 3,
+            batch_concurrency: 1,
+            rate_limit_rps: 1,
+            rate_limit_burst: 5,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+        let balance = |address: &str| json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [address, "0x10"]});
+
+        // Batches over the limit don't reach the RPCs
+        let response = post(
+            &url,
+            json!([balance("0xa"), balance("0xb"), balance("0xc"), balance("0xd")]),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], -32600);
+        assert_eq!(mock.requests(), 0);
+
+        // Every call takes a token
+        let response = post(&url, json!([balance("0xa"), balance("0xb"), balance("0xc")])).await;
+        assert_eq!(response.as_array().unwrap().len(), 3);
+        assert_eq!(mock.requests(), 3);
+
+        let limited = reqwest::Client::new()
+            .post(&url)
+            .json(&json!([balance("0xd"), balance("0xe")]))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(limited.status(), 429);
+        assert_eq!(mock.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_logs_range_limit() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": []}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            max_logs_range: 10,
+            max_logs_chunks: 3,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+        let logs = |to: &str| json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getLogs", "params": [{"fromBlock": "0x0", "toBlock": to}]});
+
+        // 3 chunks of 10 blocks
+        let response = post(&url, logs("0x1d")).await;
+        assert_eq!(response["result"], json!([]));
+        assert_eq!(mock.requests(), 3);
+
+        // 4 chunks is one too many
+        let response = post(&url, logs("0x1e")).await;
+        assert_eq!(response["error"]["code"], -32005);
+        assert_eq!(mock.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_unfired_hedges_are_not_counted() {
+        let mock = |result: &'static str| {
+            MockRpc::spawn(
+                Duration::ZERO,
+                move |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": result}),
+            )
+        };
+        let (a, b) = (mock("0x1").await, mock("0x1").await);
+        let rpcs = vec![
+            Rpc::new(a.url.clone(), None, 0, 0, 1.0),
+            Rpc::new(b.url.clone(), None, 0, 0, 1.0),
+        ];
+        let config = Settings {
+            hedge_delay: 500,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(rpcs.clone(), config).await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0xa", "0x1"]});
+        assert_eq!(post(&url, tx).await["result"], "0x1");
+
+        // Both RPCs respond right away, so the hedge never fires
+        assert_eq!(a.requests() + b.requests(), 1);
+        assert_eq!(rpcs[0].requests() + rpcs[1].requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_body_size_limits() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": request["params"][0]}),
+        )
+        .await;
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        rpc.max_response_bytes = 256;
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let settings = Settings {
+            max_request_bytes: 512,
+            ..Settings::default()
+        };
+        let url = spawn_blutgang_with(
+            vec![rpc],
+            settings,
+            readiness,
+            Arc::new(RwLock::new(NamedBlocknumbers::default())),
+        )
+        .await;
+        let echo = |id: u64, data: String| json!({"jsonrpc": "2.0", "id": id, "method": "eth_call", "params": [data]});
+
+        let response = post(&url, echo(1, "0x1".to_string())).await;
+        assert_eq!(response["result"], "0x1");
+
+        // Too large for us
+        let response = reqwest::Client::new()
+            .post(&url)
+            .json(&echo(2, format!("0x{}", "ab".repeat(512))))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 413);
+        assert_eq!(mock.requests(), 1);
+
+        // Fits our limit, but the echoed response doesn't fit the RPC's
+        let response = post(&url, echo(3, format!("0x{}", "ab".repeat(150)))).await;
+        assert_eq!(response["id"], 3);
+        assert_eq!(response["error"]["code"], -32008);
+        // Not retried
+        assert_eq!(mock.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_methods() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let readiness = Arc::new(Readiness::new(false));
+        readiness.mark_health_checked();
+        let settings = Settings {
+            method_filter: Arc::new(MethodFilter::new(
+                ["eth_chainId".to_string(), "debug_traceCall".to_string()].into(),
+                ["debug_traceCall".to_string()].into(),
+            )),
+            ..Settings::default()
+        };
+        let url = spawn_blutgang_with(
+            vec![rpc],
+            settings,
+            readiness,
+            Arc::new(RwLock::new(NamedBlocknumbers::default())),
+        )
+        .await;
+        let call = |id: u64, method: &str| json!({"jsonrpc": "2.0", "id": id, "method": method, "params": []});
+
+        let response = post(&url, call(1, "eth_gasPrice")).await;
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["error"]["code"], -32601);
+
+        let responses = post(
+            &url,
+            json!([
+                call(1, "eth_chainId"),
+                call(2, "debug_traceCall"),
+                call(3, "eth_gasPrice"),
+            ]),
+        )
+        .await;
+        assert_eq!(responses[0]["result"], "0x1");
+        assert_eq!(responses[1]["id"], 2);
+        assert_eq!(responses[1]["error"]["code"], -32601);
+        assert_eq!(responses[2]["error"]["code"], -32601);
+
+        // Blocked calls never reach the RPC
+        assert_eq!(mock.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_requests_are_retried() {
+        let failing = MockRpc::spawn_with_status(502, |_| json!("Bad Gateway")).await;
+        let working = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc_list = vec![
+            Rpc::new(failing.url.clone(), None, 0, 0, 1.0),
+            // Nothing listens here
+            Rpc::new("http://127.0.0.1:1".to_string(), None, 0, 0, 1.0),
+            Rpc::new(working.url.clone(), None, 0, 0, 1.0),
+        ];
+        let url = spawn_blutgang(rpc_list, Settings::default()).await;
+
+        for id in 0..3 {
+            let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_chainId", "params": []});
+            assert_eq!(post(&url, tx).await["result"], "0x1");
+        }
+        assert_eq!(working.requests(), 3);
+        // Failed RPCs aren't tried twice for the same request
+        assert!(failing.requests() <= 3);
+    }
+
+    #[tokio::test]
+    async fn test_failed_requests_give_up() {
+        let failing = MockRpc::spawn_with_status(502, |_| json!("Bad Gateway")).await;
+        let rpc = Rpc::new(failing.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            max_retries: 3,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []});
+        let response = reqwest::Client::new()
+            .post(&url)
+            .json(&tx)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 502);
+        assert!(response.text().await.unwrap().contains("HTTP 502"));
+        assert_eq!(failing.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_serve_stale_on_error() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let config = Settings {
+            max_retries: 2,
+            method_cache: Arc::new(MethodCache::new(
+                [("eth_gasPrice".to_string(), 1)].into(),
+                Default::default(),
+            )),
+            serve_stale_on_error: true,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![rpc], config).await;
+        let client = reqwest::Client::new();
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_gasPrice", "params": []});
+        let miss = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(miss.headers()[CACHE_STATUS_HEADER], "miss");
+
+        // The entry expired and the RPC is down
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        mock.set_status(502);
+
+        let tx = json!({"jsonrpc": "2.0", "id": 2, "method": "eth_gasPrice", "params": []});
+        let stale = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(stale.status(), 200);
+        assert_eq!(stale.headers()[CACHE_STATUS_HEADER], "stale");
+        let stale: Value = stale.json().await.unwrap();
+        assert_eq!(stale["id"], 2);
+        assert_eq!(stale["result"], "0x1");
+
+        // Requests we never cached still fail
+        let tx = json!({"jsonrpc": "2.0", "id": 3, "method": "eth_chainId", "params": []});
+        let failed = client.post(&url).json(&tx).send().await.unwrap();
+        assert_eq!(failed.status(), 502);
+    }
+
+    #[tokio::test]
+    async fn test_jsonrpc_errors_are_not_retried() {
+        let mock = MockRpc::spawn(Duration::ZERO, |request| {
+            json!({"jsonrpc": "2.0", "id": request["id"], "error": {"code": -32000, "message": "execution reverted"}})
+        })
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let url = spawn_blutgang(vec![rpc], Settings::default()).await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call", "params": [{}, "latest"]});
+        assert_eq!(
+            post(&url, tx).await["error"]["message"],
+            "execution reverted"
+        );
+        assert_eq!(mock.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrency() {
+        let respond =
+            |request: Value| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"});
+        let first = MockRpc::spawn(Duration::from_millis(200), respond).await;
+        let second = MockRpc::spawn(Duration::from_millis(200), respond).await;
+        let mut first_rpc = Rpc::new(first.url.clone(), None, 0, 0, 1.0);
+        first_rpc.set_max_concurrency(1);
+        let mut second_rpc = Rpc::new(second.url.clone(), None, 0, 0, 1.0);
+        second_rpc.set_max_concurrency(1);
+        let config = Settings {
+            ttl: 1000,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(vec![first_rpc, second_rpc], config).await;
+
+        // Two requests fit, the third one waits for a free slot.
+        // Different params so the requests don't get coalesced.
+        let requests = (0..3).map(|id| {
+            let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_call", "params": [{"data": format!("0x{}", id)}, "latest"]});
+            post(&url, tx)
+        });
+        let time = Instant::now();
+        for response in futures::future::join_all(requests).await {
+            assert_eq!(response["result"], "0x1");
+        }
+
+        assert!(first.requests() >= 1 && second.requests() >= 1);
+        assert_eq!(first.requests() + second.requests(), 3);
+        assert!(time.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_request_deadline() {
+        let header = |value: &str| HeaderValue::from_str(value).unwrap();
+
+        assert_eq!(request_deadline(None, 0), None);
+        assert_eq!(
+            request_deadline(None, 500),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            request_deadline(Some(&header("100")), 0),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            request_deadline(Some(&header("100")), 500),
+            Some(Duration::from_millis(100))
+        );
+        // Clients can't go past the default
+        assert_eq!(
+            request_deadline(Some(&header("2000")), 500),
+            Some(Duration::from_millis(500))
+        );
+        // Bad headers fall back to the default
+        assert_eq!(
+            request_deadline(Some(&header("soon")), 500),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(request_deadline(Some(&header("0")), 0), None);
+    }
+
+    #[tokio::test]
+    async fn test_request_deadline_exceeded() {
+        // The RPC and `ttl` are far slower than any deadline, so only a deadline
+        // can end the request in time, even on a busy machine
+        let respond =
+            |request: Value| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"});
+        let slow = MockRpc::spawn(Duration::from_secs(30), respond).await;
+        let rpc_list = vec![Rpc::new(slow.url.clone(), None, 0, 0, 1.0)];
+        let config = Settings {
+            ttl: 60_000,
+            request_deadline_ms: 2000,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(rpc_list, config).await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+        let client = reqwest::Client::new();
+        let post = |timeout: Option<&'static str>| {
+            let mut request = client.post(&url).json(&tx);
+            if let Some(timeout) = timeout {
+                request = request.header(TIMEOUT_HEADER, timeout);
+            }
+            async move {
+                let start = Instant::now();
+                let response = request.send().await.unwrap();
+                (start.elapsed(), response)
+            }
+        };
+
+        // Default deadline
+        let (elapsed, response) = post(None).await;
+        assert!(elapsed >= Duration::from_millis(2000), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(15), "took {:?}", elapsed);
+        assert_eq!(response.status(), 408);
+        let response: Value = response.json().await.unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["error"]["code"], -32001);
+
+        // Shortened by the client
+        let (elapsed, response) = post(Some("50")).await;
+        assert!(elapsed < Duration::from_millis(1500), "took {:?}", elapsed);
+        let response: Value = response.json().await.unwrap();
+        assert_eq!(response["error"]["code"], -32001);
+
+        // Clients can't go past the default
+        let (elapsed, response) = post(Some("60000")).await;
+        assert!(elapsed < Duration::from_secs(15), "took {:?}", elapsed);
+        let response: Value = response.json().await.unwrap();
+        assert_eq!(response["error"]["code"], -32001);
+    }
+
+    #[tokio::test]
+    async fn test_sticky_sessions() {
+        let respond =
+            |request: Value| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"});
+        let first = MockRpc::spawn(Duration::ZERO, respond).await;
+        let second = MockRpc::spawn(Duration::ZERO, respond).await;
+        let rpc_list = vec![
+            Rpc::new(first.url.clone(), None, 0, 0, 1.0),
+            Rpc::new(second.url.clone(), None, 0, 0, 1.0),
+        ];
+        let config = Settings {
+            selection_strategy: SelectionStrategy::RoundRobin,
+            sticky_sessions: true,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(rpc_list, config).await;
+
+        let client = reqwest::Client::new();
+        for id in 0..10 {
+            let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_blockNumber", "params": []});
+            client
+                .post(&url)
+                .header(SESSION_HEADER, "alice")
+                .json(&tx)
+                .send()
+                .await
+                .unwrap();
+        }
+
+        // Every request of the session went to the same RPC
+        assert_eq!(first.requests() + second.requests(), 10);
+        assert!(
+            first.requests() == 10 || second.requests() == 10,
+            "first: {}, second: {}",
+            first.requests(),
+            second.requests()
+        );
+    }
+    #[tokio::test]
+    async fn test_shadow_requests() {
+        let primary = MockRpc::spawn(
+            Duration::ZERO,
+            |request: Value| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let shadow = MockRpc::spawn(
+            Duration::ZERO,
+            |request: Value| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x2"}),
+        )
+        .await;
+        let rpc_list = vec![
+            Rpc::new(primary.url.clone(), None, 0, 0, 1.0),
+            Rpc::new(shadow.url.clone(), None, 0, 0, 1.0),
+        ];
+        let config = Settings {
+            shadow_sample_rate: 1.0,
+            ..Default::default()
+        };
+        let url = spawn_blutgang(rpc_list, config).await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x0000000000000000000000000000000000000000", "0x1"]});
+        post(&url, tx).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(primary.requests() + shadow.requests(), 2);
+        assert_eq!(primary.requests(), 1);
+
+        // Cache hits don't get shadowed
+        let tx = json!({"jsonrpc": "2.0", "id": 2, "method": "eth_getBalance", "params": ["0x0000000000000000000000000000000000000000", "0x1"]});
+        post(&url, tx).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(primary.requests() + shadow.requests(), 2);
+    }
+
+    #[test]
+    fn test_responses_match() {
+        assert!(responses_match(
+            r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#,
+            r#"{"id":7,"jsonrpc":"2.0","result":"0x1"}"#,
+        ));
+        assert!(!responses_match(
+            r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#,
+            r#"{"jsonrpc":"2.0","id":1,"result":"0x2"}"#,
+        ));
+        assert!(!responses_match("not json", r#"{"result":"0x1"}"#));
+    }
 }
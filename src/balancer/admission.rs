@@ -0,0 +1,272 @@
+// Admission control for requests going to the RPCs.
+//
+// Caps the amount of requests we're forwarding at once. Requests over the
+// limit are queued and admitted by priority, then in the order they arrived.
+use hyper::header::HeaderValue;
+
+use std::{
+    cmp::{
+        Ordering,
+        Reverse,
+    },
+    collections::BinaryHeap,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+
+use tokio::sync::oneshot;
+
+// Header clients can use to hint at the priority of their request
+pub const PRIORITY_HEADER: &str = "x-blutgang-priority";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn from_str(priority: &str) -> Option<Self> {
+        match priority {
+            "low" => Some(Priority::Low),
+            "normal" => Some(Priority::Normal),
+            "high" => Some(Priority::High),
+            _ => None,
+        }
+    }
+}
+
+// Get the priority of a request from its priority header, clamped to `max`.
+//
+// Missing or invalid hints are treated as `normal`.
+pub fn request_priority(header: Option<&HeaderValue>, max: Priority) -> Priority {
+    let priority = header
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| Priority::from_str(&header.to_lowercase()))
+        .unwrap_or_default();
+
+    priority.min(max)
+}
+
+struct Waiter {
+    priority: Priority,
+    // Used to keep waiters with the same priority in FIFO order
+    seq: Reverse<u64>,
+    tx: oneshot::Sender<AdmissionPermit>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.priority, self.seq).cmp(&(other.priority, other.seq))
+    }
+}
+
+#[derive(Default)]
+struct AdmissionState {
+    available: usize,
+    seq: u64,
+    queue: BinaryHeap<Waiter>,
+}
+
+#[derive(Default)]
+pub struct AdmissionControl {
+    // Max requests admitted at once, 0 means unbounded
+    capacity: usize,
+    state: Mutex<AdmissionState>,
+}
+
+impl std::fmt::Debug for AdmissionControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "AdmissionControl {{ capacity: {} }}", self.capacity)
+    }
+}
+
+impl AdmissionControl {
+    pub fn new(capacity: usize) -> Self {
+        AdmissionControl {
+            capacity,
+            state: Mutex::new(AdmissionState {
+                available: capacity,
+                ..Default::default()
+            }),
+        }
+    }
+
+    // Wait until the request can be admitted. The slot is released when
+    // the returned permit is dropped.
+    pub async fn acquire(self: &Arc<Self>, priority: Priority) -> AdmissionPermit {
+        if self.capacity == 0 {
+            return AdmissionPermit { admission: None };
+        }
+
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 && state.queue.is_empty() {
+                state.available -= 1;
+                return AdmissionPermit {
+                    admission: Some(self.clone()),
+                };
+            }
+
+            let (tx, rx) = oneshot::channel();
+            state.seq += 1;
+            let seq = Reverse(state.seq);
+            state.queue.push(Waiter { priority, seq, tx });
+            rx
+        };
+
+        // The sender only gets dropped if the permit is handed over, so this can't fail
+        rx.await.unwrap()
+    }
+
+    // Hand the slot over to the next request in line, or make it available
+    fn release(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.queue.pop() {
+            let permit = AdmissionPermit {
+                admission: Some(self.clone()),
+            };
+            match waiter.tx.send(permit) {
+                Ok(_) => return,
+                // The request was dropped while waiting, disarm the permit and try the next one
+                Err(mut permit) => permit.admission = None,
+            }
+        }
+        state.available += 1;
+    }
+
+    // Number of requests waiting to be admitted
+    pub fn queued(&self) -> usize {
+        self.state.lock().unwrap().queue.len()
+    }
+}
+
+pub struct AdmissionPermit {
+    admission: Option<Arc<AdmissionControl>>,
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        if let Some(admission) = self.admission.take() {
+            admission.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_request_priority() {
+        let high = HeaderValue::from_static("high");
+        let low = HeaderValue::from_static("LOW");
+        let invalid = HeaderValue::from_static("urgent");
+
+        assert_eq!(
+            request_priority(Some(&high), Priority::High),
+            Priority::High
+        );
+        assert_eq!(request_priority(Some(&low), Priority::High), Priority::Low);
+        assert_eq!(request_priority(None, Priority::High), Priority::Normal);
+        assert_eq!(
+            request_priority(Some(&invalid), Priority::High),
+            Priority::Normal
+        );
+
+        // Untrusted clients get clamped
+        assert_eq!(
+            request_priority(Some(&high), Priority::Normal),
+            Priority::Normal
+        );
+        assert_eq!(
+            request_priority(Some(&low), Priority::Normal),
+            Priority::Low
+        );
+    }
+
+    #[tokio::test]
+    async fn test_high_priority_jumps_queue() {
+        let admission = Arc::new(AdmissionControl::new(1));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        // Fill up the only slot
+        let permit = admission.acquire(Priority::Normal).await;
+
+        for (name, priority) in [
+            ("normal1", Priority::Normal),
+            ("low", Priority::Low),
+            ("normal2", Priority::Normal),
+            ("high", Priority::High),
+        ] {
+            let admission = admission.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let _permit = admission.acquire(priority).await;
+                tx.send(name).unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            });
+            // Make sure they're queued in order
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(admission.queued(), 4);
+
+        drop(permit);
+
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            order.push(rx.recv().await.unwrap());
+        }
+        assert_eq!(order, vec!["high", "normal1", "normal2", "low"]);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_waiter_releases_slot() {
+        let admission = Arc::new(AdmissionControl::new(1));
+        let permit = admission.acquire(Priority::Normal).await;
+
+        // Queue a request and drop it before it's admitted
+        let waiting =
+            tokio::time::timeout(Duration::from_millis(10), admission.acquire(Priority::High))
+                .await;
+        assert!(waiting.is_err());
+
+        drop(permit);
+
+        // The slot should still be available
+        let permit = tokio::time::timeout(
+            Duration::from_millis(100),
+            admission.acquire(Priority::Normal),
+        )
+        .await;
+        assert!(permit.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unbounded() {
+        let admission = Arc::new(AdmissionControl::new(0));
+        let _a = admission.acquire(Priority::Low).await;
+        let _b = admission.acquire(Priority::Low).await;
+        assert_eq!(admission.queued(), 0);
+    }
+}
@@ -0,0 +1,58 @@
+// Bearer token authentication.
+//
+// When `auth_token` is set, requests have to carry a matching
+// `Authorization: Bearer <token>` header. We check it before reading the
+// body so unauthorized requests stay cheap to reject.
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{
+        HeaderValue,
+        WWW_AUTHENTICATE,
+    },
+    Response,
+};
+
+// Returns true if `header` carries `token` as a bearer token.
+//
+// Compares hashes of the tokens, which is constant-time and doesn't leak the
+// length of `token` either.
+pub fn is_authorized(header: Option<&HeaderValue>, token: &str) -> bool {
+    let provided = match header
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+    {
+        Some(provided) => provided.trim(),
+        None => return false,
+    };
+
+    // `blake3::Hash` equality is constant-time
+    blake3::hash(provided.as_bytes()) == blake3::hash(token.as_bytes())
+}
+
+pub fn unauthorized_response() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(401)
+        .header(WWW_AUTHENTICATE, "Bearer")
+        .body(Full::new(Bytes::from("Unauthorized")))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_authorized() {
+        let valid = HeaderValue::from_static("Bearer hunter2");
+        let wrong = HeaderValue::from_static("Bearer hunter3");
+        let prefix = HeaderValue::from_static("Bearer hunter");
+        let basic = HeaderValue::from_static("Basic hunter2");
+
+        assert!(is_authorized(Some(&valid), "hunter2"));
+        assert!(!is_authorized(Some(&wrong), "hunter2"));
+        assert!(!is_authorized(Some(&prefix), "hunter2"));
+        assert!(!is_authorized(Some(&basic), "hunter2"));
+        assert!(!is_authorized(None, "hunter2"));
+    }
+}
@@ -0,0 +1,105 @@
+// Request and response size limits.
+//
+// Request bodies over `max_request_bytes` get a 413, checked against
+// `Content-Length` first and enforced while buffering for chunked bodies.
+// Responses from RPCs over `max_response_bytes` are dropped without being
+// cached, and the client gets a JSON-RPC error instead.
+//
+// Bodies that aren't valid JSON get a JSON-RPC parse error.
+use http_body_util::{
+    BodyExt,
+    Full,
+    LengthLimitError,
+    Limited,
+};
+use hyper::{
+    body::{
+        Bytes,
+        Incoming,
+    },
+    header::CONTENT_LENGTH,
+    HeaderMap,
+    Response,
+};
+
+pub const DEFAULT_MAX_REQUEST_BYTES: usize = 10 * 1024 * 1024;
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 128 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum BodyError {
+    TooLarge,
+    Read(String),
+    InvalidJson,
+}
+
+impl std::fmt::Display for BodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BodyError::TooLarge => write!(f, "Body is too large"),
+            BodyError::Read(reason) => write!(f, "Could not read body: {}", reason),
+            BodyError::InvalidJson => write!(f, "Body is not valid JSON"),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+// Returns true if `Content-Length` says the body is over `max_bytes`
+pub fn exceeds_content_length(headers: &HeaderMap, max_bytes: usize) -> bool {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok())
+        .is_some_and(|length| length > max_bytes as u64)
+}
+
+// Buffer `body`, giving up as soon as it goes over `max_bytes`
+pub async fn read_limited(body: Incoming, max_bytes: usize) -> Result<Bytes, BodyError> {
+    match Limited::new(body, max_bytes).collect().await {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(err) if err.downcast_ref::<LengthLimitError>().is_some() => Err(BodyError::TooLarge),
+        Err(err) => Err(BodyError::Read(err.to_string())),
+    }
+}
+
+pub fn payload_too_large_response() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(413)
+        .body(Full::new(Bytes::from("Request body too large")))
+        .unwrap()
+}
+
+pub fn bad_body_response(err: BodyError) -> Response<Full<Bytes>> {
+    match err {
+        BodyError::TooLarge => payload_too_large_response(),
+        BodyError::InvalidJson => {
+            Response::builder()
+                .status(200)
+                .header("Content-Type", "application/json")
+                .body(Full::new(Bytes::from(crate::parse_error!())))
+                .unwrap()
+        }
+        BodyError::Read(_) => {
+            Response::builder()
+                .status(400)
+                .body(Full::new(Bytes::from(err.to_string())))
+                .unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_exceeds_content_length() {
+        let mut headers = HeaderMap::new();
+        assert!(!exceeds_content_length(&headers, 10));
+
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("10"));
+        assert!(!exceeds_content_length(&headers, 10));
+        assert!(exceeds_content_length(&headers, 9));
+    }
+}
@@ -0,0 +1,351 @@
+// Keeps the sled cache under `cache_max_bytes`.
+//
+// sled has no notion of a size limit, so a background task checks its size on
+// disk and evicts entries that weren't read lately once it's over the cap.
+// Every chain has its own tree in the DB, and they get swept in turns.
+//
+// Recency is approximated CLOCK style. Reads and writes set a reference bit for
+// the entry in a fixed size bitmap, and eviction sweeps the cache from where it
+// last stopped, sparing entries with their bit set (and clearing it) and evicting
+// the rest. The bitmap takes 128 KiB and setting a bit is a single atomic OR, so
+// tracking adds close to nothing to requests. The price is accuracy:
+// - Entries sharing a bit share their recency, so some cold entries survive a sweep.
+// - sled only gives space back to the OS as it rewrites its segments, so the size
+//   on disk lags behind evictions. Bytes we evicted count as gone until the size
+//   on disk drops by as much, or for `RECLAIM_WINDOW` checks at most, so we don't
+//   evict them over again. We evict down to 90% of the cap to leave room.
+//
+// Evicted entries take their expiry and method index entries with them. We don't
+// know which method an entry was cached for, so its index entry gets removed under
+// every method in the index.
+use crate::{
+    balancer::processing::{
+        expiry_key,
+        indexed_methods,
+        remove_method_index,
+        EXPIRY_PREFIX,
+        METHOD_PREFIX,
+    },
+    config::cache_setup::is_metadata,
+};
+
+use sled::{
+    Batch,
+    Db,
+    IVec,
+    Tree,
+};
+use tracing::{
+    info,
+    warn,
+};
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+    time::Duration,
+};
+
+// How many reference bits we keep
+const RECENCY_SLOTS: usize = 1 << 20;
+// Time between checks of the cache size
+const EVICTION_INTERVAL: Duration = Duration::from_secs(30);
+// Checks evicted bytes are assumed to still be on disk for, if sled doesn't give them back
+const RECLAIM_WINDOW: usize = 10;
+
+// Reference bits of the entries in the cache, empty unless the cache is capped
+#[derive(Debug, Default)]
+pub struct CacheRecency {
+    bits: Vec<AtomicU64>,
+}
+
+impl CacheRecency {
+    pub fn new() -> Self {
+        CacheRecency {
+            bits: (0..RECENCY_SLOTS / 64).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    // Word and bit of the slot of `key`. Keys end with the hash of their request,
+    // so the last 8 bytes are spread evenly enough. Responses and cached errors
+    // for the same request share a slot.
+    fn slot(&self, key: &[u8]) -> Option<(usize, u64)> {
+        if self.bits.is_empty() {
+            return None;
+        }
+
+        let hash = key[key.len().saturating_sub(8)..]
+            .iter()
+            .fold(0u64, |hash, byte| hash << 8 | *byte as u64);
+        let slot = (hash % RECENCY_SLOTS as u64) as usize;
+        Some((slot / 64, 1 << (slot % 64)))
+    }
+
+    // Mark the entry at `key` as recently used
+    pub fn touch(&self, key: &[u8]) {
+        if let Some((word, bit)) = self.slot(key) {
+            self.bits[word].fetch_or(bit, Ordering::Relaxed);
+        }
+    }
+
+    // Clear the reference bit of `key`, returning if it was set
+    fn take(&self, key: &[u8]) -> bool {
+        match self.slot(key) {
+            Some((word, bit)) => self.bits[word].fetch_and(!bit, Ordering::Relaxed) & bit != 0,
+            None => false,
+        }
+    }
+}
+
+// Bytes evicted in the last `RECLAIM_WINDOW` checks that sled didn't give back yet
+#[derive(Debug, Default)]
+struct Unreclaimed {
+    // Oldest first
+    freed: VecDeque<u64>,
+    last_size: u64,
+}
+
+impl Unreclaimed {
+    // Size of the cache on disk, minus what we already evicted from it
+    fn live_size(&mut self, size: u64) -> u64 {
+        // Whatever the DB shrunk by came from the oldest evictions
+        let mut reclaimed = self.last_size.saturating_sub(size);
+        self.last_size = size;
+        while reclaimed > 0 {
+            let Some(oldest) = self.freed.front_mut() else {
+                break;
+            };
+            let taken = reclaimed.min(*oldest);
+            *oldest -= taken;
+            reclaimed -= taken;
+            if *oldest == 0 {
+                self.freed.pop_front();
+            }
+        }
+
+        size.saturating_sub(self.freed.iter().sum())
+    }
+
+    // Record what a check evicted, forgetting evictions from before the window
+    fn push(&mut self, freed: u64) {
+        self.freed.push_back(freed);
+        while self.freed.len() > RECLAIM_WINDOW {
+            self.freed.pop_front();
+        }
+    }
+}
+
+// Check the size of `db` every `EVICTION_INTERVAL`, and evict entries from the
+// `caches` in it if it's over `max_bytes`
+pub async fn evict_cache(db: Arc<Db>, caches: Vec<(Arc<Tree>, Arc<CacheRecency>)>, max_bytes: u64) {
+    // Where the last sweep of each cache stopped
+    let mut hands = vec![IVec::default(); caches.len()];
+    // Cache to start the next sweep with
+    let mut first = 0;
+    let mut unreclaimed = Unreclaimed::default();
+
+    loop {
+        tokio::time::sleep(EVICTION_INTERVAL).await;
+
+        let size = match db.size_on_disk() {
+            Ok(size) => unreclaimed.live_size(size),
+            Err(err) => {
+                warn!("Could not get the size of the cache: {}", err);
+                continue;
+            }
+        };
+        if size <= max_bytes || caches.is_empty() {
+            unreclaimed.push(0);
+            continue;
+        }
+
+        let target = size - max_bytes / 10 * 9;
+        let swept = {
+            let caches = caches.clone();
+            tokio::task::spawn_blocking(move || {
+                let (mut evicted, mut freed) = (0, 0);
+                for i in (0..caches.len()).map(|i| (first + i) % caches.len()) {
+                    if freed >= target {
+                        break;
+                    }
+
+                    let (cache, recency) = &caches[i];
+                    match sweep(cache, recency, &mut hands[i], target - freed) {
+                        Ok((tree_evicted, tree_freed)) => {
+                            evicted += tree_evicted;
+                            freed += tree_freed;
+                        }
+                        Err(err) => warn!("Could not evict entries from the cache: {}", err),
+                    }
+                }
+                (evicted, freed, hands)
+            })
+            .await
+        };
+
+        match swept {
+            Ok((evicted, freed, next_hands)) => {
+                hands = next_hands;
+                unreclaimed.push(freed);
+                info!(
+                    "Cache is {} bytes, over the cap of {}. Evicted {} entries ({} bytes).",
+                    size, max_bytes, evicted, freed
+                );
+            }
+            Err(err) => {
+                warn!("Cache eviction failed: {}", err);
+                hands = vec![IVec::default(); caches.len()];
+                unreclaimed.push(0);
+            }
+        }
+        first = (first + 1) % caches.len();
+    }
+}
+
+// Returns true if `key` is a cached response or error
+fn is_evictable(key: &[u8]) -> bool {
+    !is_metadata(key) && !key.starts_with(EXPIRY_PREFIX) && !key.starts_with(METHOD_PREFIX)
+}
+
+// Evict entries that weren't used since the last sweep until about `target` bytes are freed.
+//
+// Starts after `hand`, where the last sweep stopped, and goes around the cache up
+// to twice, since the first lap may only clear reference bits.
+// Returns how many entries were evicted and how many bytes they took.
+fn sweep(
+    cache: &Tree,
+    recency: &CacheRecency,
+    hand: &mut IVec,
+    target: u64,
+) -> Result<(usize, u64), sled::Error> {
+    let methods = indexed_methods(cache)?;
+    let mut batch = Batch::default();
+    let mut evicted = 0;
+    let mut freed = 0;
+
+    let entries = cache
+        .range::<&[u8], _>((
+            std::ops::Bound::Excluded(hand.as_ref()),
+            std::ops::Bound::Unbounded,
+        ))
+        .chain(cache.iter())
+        .chain(cache.iter());
+    for entry in entries {
+        if freed >= target {
+            break;
+        }
+
+        let (key, value) = entry?;
+        if !is_evictable(&key) || recency.take(&key) {
+            continue;
+        }
+
+        remove_method_index(&mut batch, &methods, &key);
+        batch.remove(expiry_key(&key));
+        batch.remove(key.clone());
+        freed += (key.len() + value.len()) as u64;
+        evicted += 1;
+        *hand = key;
+    }
+
+    cache.apply_batch(batch)?;
+    Ok((evicted, freed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::processing::method_index_key;
+
+    #[test]
+    fn test_cache_recency() {
+        let recency = CacheRecency::new();
+        recency.touch(b"key1");
+        assert!(recency.take(b"key1"));
+        assert!(!recency.take(b"key1"));
+        assert!(!recency.take(b"key2"));
+
+        // Disabled
+        let recency = CacheRecency::default();
+        recency.touch(b"key1");
+        assert!(!recency.take(b"key1"));
+    }
+
+    #[test]
+    fn test_unreclaimed() {
+        let mut unreclaimed = Unreclaimed::default();
+        assert_eq!(unreclaimed.live_size(1000), 1000);
+        unreclaimed.push(300);
+
+        // Not given back yet, so it's not counted
+        assert_eq!(unreclaimed.live_size(1000), 700);
+        // Partly given back
+        assert_eq!(unreclaimed.live_size(900), 700);
+
+        // Evictions sled never gives back are forgotten after `RECLAIM_WINDOW` checks
+        for _ in 1..RECLAIM_WINDOW {
+            unreclaimed.push(0);
+        }
+        assert_eq!(unreclaimed.live_size(900), 700);
+        unreclaimed.push(0);
+        assert_eq!(unreclaimed.live_size(900), 900);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let cache = sled::Config::new().temporary(true).open().unwrap();
+        let recency = CacheRecency::new();
+        for i in 0..10u64 {
+            let key = i.to_be_bytes();
+            cache.insert(key, vec![0; 100]).unwrap();
+            let method = if i % 2 == 0 { "eth_call" } else { "eth_getLogs" };
+            cache.insert(method_index_key(method, &key), &[]).unwrap();
+        }
+        cache
+            .insert(expiry_key(&9u64.to_be_bytes()), &[0; 8])
+            .unwrap();
+        cache.insert(b"cache_version", &[0, 0]).unwrap();
+
+        // Recently used entries are spared
+        recency.touch(&0u64.to_be_bytes());
+        recency.touch(&1u64.to_be_bytes());
+
+        let mut hand = IVec::default();
+        let (evicted, freed) = sweep(&cache, &recency, &mut hand, 300).unwrap();
+        assert_eq!(evicted, 3);
+        assert_eq!(freed, 324);
+        assert!(cache.contains_key(0u64.to_be_bytes()).unwrap());
+        assert!(cache.contains_key(1u64.to_be_bytes()).unwrap());
+        assert!(!cache.contains_key(2u64.to_be_bytes()).unwrap());
+        assert!(!cache.contains_key(4u64.to_be_bytes()).unwrap());
+        assert!(cache.contains_key(5u64.to_be_bytes()).unwrap());
+        assert_eq!(hand.as_ref(), 4u64.to_be_bytes());
+        // Index entries go with their entry, whichever method they're under
+        assert!(cache
+            .contains_key(method_index_key("eth_call", &0u64.to_be_bytes()))
+            .unwrap());
+        assert!(!cache
+            .contains_key(method_index_key("eth_call", &2u64.to_be_bytes()))
+            .unwrap());
+        assert!(!cache
+            .contains_key(method_index_key("eth_getLogs", &3u64.to_be_bytes()))
+            .unwrap());
+
+        // The next sweep picks up where we stopped, expiry keys go with their entry
+        sweep(&cache, &recency, &mut hand, 600).unwrap();
+        assert!(!cache.contains_key(9u64.to_be_bytes()).unwrap());
+        assert!(!cache.contains_key(expiry_key(&9u64.to_be_bytes())).unwrap());
+
+        // Their bits got cleared on the first lap, so everything goes eventually
+        sweep(&cache, &recency, &mut hand, u64::MAX).unwrap();
+        assert!((0..10u64).all(|i| !cache.contains_key(i.to_be_bytes()).unwrap()));
+        assert!(cache.contains_key(b"cache_version").unwrap());
+        assert_eq!(cache.scan_prefix(METHOD_PREFIX).count(), 0);
+    }
+}
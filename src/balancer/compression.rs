@@ -0,0 +1,244 @@
+// Response compression.
+//
+// When `compression` is enabled, JSON responses of at least `compression_min_size`
+// bytes get compressed for clients that accept it. gzip, deflate and zstd are
+// supported, and the encoding is picked from the `Accept-Encoding` q-values.
+// On ties we prefer gzip since every client can decode it. Cached bodies are
+// already stored compressed by sled when `[sled] compression` is on.
+use std::io::Write;
+
+use flate2::{
+    write::{
+        DeflateEncoder,
+        GzEncoder,
+    },
+    Compression,
+};
+use http_body_util::{
+    BodyExt,
+    Full,
+};
+use hyper::{
+    body::Bytes,
+    header::{
+        HeaderValue,
+        CONTENT_ENCODING,
+        CONTENT_TYPE,
+        VARY,
+    },
+    Response,
+};
+use tracing::warn;
+
+// Good ratio on JSON without costing much latency
+const ZSTD_LEVEL: i32 = 3;
+const FLATE_LEVEL: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    Zstd,
+}
+
+impl Encoding {
+    // In order of preference when the client weighs them equally
+    const SUPPORTED: [Encoding; 3] = [Encoding::Gzip, Encoding::Zstd, Encoding::Deflate];
+
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    fn encode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(FLATE_LEVEL));
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(FLATE_LEVEL));
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Encoding::Zstd => zstd::encode_all(body, ZSTD_LEVEL),
+        }
+    }
+}
+
+// Pick the encoding to use from the `Accept-Encoding` header.
+//
+// Returns `None` if the client didn't send one or accepts none we support.
+// A wildcard only stands in for gzip, since that's the one every client handles.
+pub fn negotiate_encoding(header: Option<&HeaderValue>) -> Option<Encoding> {
+    let header = header.and_then(|header| header.to_str().ok())?;
+
+    let mut weights: [Option<f32>; 3] = [None; 3];
+    let mut wildcard = None;
+
+    for encoding in header.split(',') {
+        let mut parts = encoding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+
+        // Encodings without a q-value have a weight of 1
+        let q = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .map(|q| q.parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+
+        if name == "*" {
+            wildcard = Some(q);
+            continue;
+        }
+
+        if let Some(i) = Encoding::SUPPORTED
+            .iter()
+            .position(|supported| name.eq_ignore_ascii_case(supported.name()))
+        {
+            weights[i] = Some(q);
+        }
+    }
+
+    // gzip is first in `SUPPORTED`
+    if weights[0].is_none() {
+        weights[0] = wildcard;
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for (encoding, q) in Encoding::SUPPORTED.iter().zip(weights) {
+        let Some(q) = q else { continue };
+        if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+            best = Some((*encoding, q));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+// Compress the body of `response` if it's JSON and at least `min_size` bytes long
+pub async fn compress_response(
+    response: Response<Full<Bytes>>,
+    encoding: Encoding,
+    min_size: usize,
+) -> Response<Full<Bytes>> {
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/json");
+    if !is_json || response.headers().contains_key(CONTENT_ENCODING) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    // `Full` can't fail
+    let body = body.collect().await.unwrap().to_bytes();
+
+    // The response depends on `Accept-Encoding` either way, so let caches know
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+
+    if body.len() < min_size {
+        return Response::from_parts(parts, Full::new(body));
+    }
+
+    match encoding.encode(&body) {
+        Ok(compressed) => {
+            parts.headers.insert(
+                CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.name()),
+            );
+            Response::from_parts(parts, Full::new(Bytes::from(compressed)))
+        }
+        Err(err) => {
+            warn!("Could not compress response: {}", err);
+            Response::from_parts(parts, Full::new(body))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn json_response(body: String) -> Response<Full<Bytes>> {
+        Response::builder()
+            .status(200)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    }
+
+    async fn body_bytes(response: Response<Full<Bytes>>) -> Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        let negotiate =
+            |header: &'static str| negotiate_encoding(Some(&HeaderValue::from_static(header)));
+
+        assert_eq!(negotiate("gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("zstd"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("deflate"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("gzip, deflate, br, zstd"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0.5, zstd;q=1.0"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("gzip;q=1.0, zstd;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, gzip;q=0"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("*"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("zstd;q=0.1, *;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0, *"), None);
+        assert_eq!(negotiate("br"), None);
+        assert_eq!(negotiate("zstd;q=0"), None);
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate_encoding(None), None);
+    }
+
+    #[tokio::test]
+    async fn test_compress_response() {
+        let body = format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":\"0x{}\"}}",
+            "ab".repeat(2048)
+        );
+        let response = compress_response(json_response(body.clone()), Encoding::Gzip, 1024).await;
+
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "Accept-Encoding");
+        let compressed = body_bytes(response).await;
+        assert!(compressed.len() < body.len());
+
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[tokio::test]
+    async fn test_compress_response_zstd() {
+        let body = format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":\"0x{}\"}}",
+            "ab".repeat(2048)
+        );
+        let response = compress_response(json_response(body.clone()), Encoding::Zstd, 1024).await;
+
+        assert_eq!(response.headers()[CONTENT_ENCODING], "zstd");
+        let compressed = body_bytes(response).await;
+        assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), body.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_skip_small_responses() {
+        let body = "{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":\"0x1\"}".to_string();
+        let response = compress_response(json_response(body.clone()), Encoding::Gzip, 1024).await;
+
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(body_bytes(response).await, body.as_bytes());
+    }
+}
@@ -0,0 +1,120 @@
+// CORS support for browser clients.
+//
+// Off unless `allowed_origins` is set. When it's off, responses keep allowing
+// every origin like they always have. When it's on, we answer `OPTIONS` preflight
+// requests ourselves, and only responses to requests coming from an allowed
+// origin carry `Access-Control-Allow-Origin`.
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{
+        HeaderValue,
+        ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS,
+        ACCESS_CONTROL_ALLOW_ORIGIN,
+        VARY,
+    },
+    Response,
+};
+
+// Returns the `Access-Control-Allow-Origin` value for a request from `origin`,
+// or `None` if the origin isn't allowed. `*` in `allowed` allows every origin.
+pub fn allowed_origin(allowed: &[String], origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+    if allowed.iter().any(|allowed| allowed == "*") {
+        return Some(HeaderValue::from_static("*"));
+    }
+
+    let origin = origin?;
+    let origin_str = origin.to_str().ok()?;
+    allowed
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(origin_str))
+        .then(|| origin.clone())
+}
+
+// Response to an `OPTIONS` preflight request.
+//
+// `Authorization` has to be allowed for browsers to send the `auth_token`.
+pub fn preflight_response(allow_origin: Option<HeaderValue>) -> Response<Full<Bytes>> {
+    let mut response = Response::builder()
+        .status(204)
+        .header(ACCESS_CONTROL_ALLOW_METHODS, "POST, OPTIONS")
+        .header(ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, Authorization")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+
+    add_cors_headers(&mut response, allow_origin);
+    response
+}
+
+// Let the browser read `response` only if the origin is allowed
+pub fn add_cors_headers(response: &mut Response<Full<Bytes>>, allow_origin: Option<HeaderValue>) {
+    // Responses allow every origin by default, which is what we have to undo here
+    response.headers_mut().remove(ACCESS_CONTROL_ALLOW_ORIGIN);
+
+    let allow_origin = match allow_origin {
+        Some(allow_origin) => allow_origin,
+        None => return,
+    };
+
+    // Caches need to know the response depends on the origin unless it's for everyone
+    if allow_origin != "*" {
+        response
+            .headers_mut()
+            .insert(VARY, HeaderValue::from_static("Origin"));
+    }
+    response
+        .headers_mut()
+        .insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origins(origins: &[&str]) -> Vec<String> {
+        origins.iter().map(|origin| origin.to_string()).collect()
+    }
+
+    #[test]
+    fn test_allowed_origin() {
+        let allowed = origins(&["https://app.example.com"]);
+        let app = HeaderValue::from_static("https://app.example.com");
+        let evil = HeaderValue::from_static("https://evil.example.com");
+
+        assert_eq!(allowed_origin(&allowed, Some(&app)), Some(app.clone()));
+        assert_eq!(allowed_origin(&allowed, Some(&evil)), None);
+        assert_eq!(allowed_origin(&allowed, None), None);
+
+        // CORS is off by default
+        assert_eq!(allowed_origin(&[], Some(&app)), None);
+
+        assert_eq!(
+            allowed_origin(&origins(&["*"]), Some(&evil)),
+            Some(HeaderValue::from_static("*"))
+        );
+    }
+
+    #[test]
+    fn test_preflight_response() {
+        let response =
+            preflight_response(Some(HeaderValue::from_static("https://app.example.com")));
+
+        assert_eq!(response.status(), 204);
+        let headers = response.headers();
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "POST, OPTIONS");
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_HEADERS],
+            "Content-Type, Authorization"
+        );
+        assert_eq!(headers[VARY], "Origin");
+
+        // Disallowed origins don't get told they're allowed
+        let response = preflight_response(None);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
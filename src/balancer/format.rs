@@ -1,5 +1,13 @@
-use crate::NamedBlocknumbers;
-use http_body_util::BodyExt;
+use crate::{
+    balancer::{
+        body_limit::{
+            read_limited,
+            BodyError,
+        },
+        hedge::is_idempotent,
+    },
+    NamedBlocknumbers,
+};
 use hyper::{
     body::Incoming,
     Request,
@@ -8,11 +16,10 @@ use memchr::memmem;
 use serde_json::{
     json,
     Value,
-    Value::Null,
 };
-use simd_json::serde::from_str;
+use simd_json::serde::from_slice;
 use std::{
-    str::from_utf8,
+    collections::HashMap,
     sync::{
         Arc,
         RwLock,
@@ -50,10 +57,65 @@ fn has_named_number(param: &str) -> NamedNumber {
     NamedNumber::Null
 }
 
+// The JSON-RPC standard is all over the place so depending on the method, we need to look at
+// different param indexes. Why? Has i ever???
+fn default_block_param(method: &str) -> Option<usize> {
+    match method {
+        "eth_getBalance" | "eth_getTransactionCount" | "eth_getCode" | "eth_call" => Some(1),
+        "eth_getStorageAt" => Some(2),
+        "eth_getBlockTransactionCountByNumber"
+        | "eth_getUncleCountByBlockNumber"
+        | "eth_getBlockByNumber"
+        | "eth_getTransactionByBlockNumberAndIndex"
+        | "eth_getUncleByBlockNumberAndIndex" => Some(0),
+        _ => None,
+    }
+}
+
+// Returns true if `method` reads the state at a block, which full nodes
+// prune after a while. Blocks, receipts and logs are kept by full nodes too.
+pub fn reads_state(method: &str) -> bool {
+    matches!(
+        method,
+        "eth_getBalance"
+            | "eth_getTransactionCount"
+            | "eth_getCode"
+            | "eth_getStorageAt"
+            | "eth_call"
+            | "eth_estimateGas"
+            | "eth_getProof"
+            | "eth_createAccessList"
+    )
+}
+
+// Where the block parameter lives for methods we don't know about by default.
+//
+// Positions are JSON pointers into `params`, so a block number at param index 2
+// is `/2`, and one nested in an object at param index 0 is `/0/blockNumber`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockParams {
+    custom: HashMap<String, String>,
+}
+
+impl BlockParams {
+    pub fn new(custom: HashMap<String, String>) -> Self {
+        BlockParams { custom }
+    }
+
+    // Get the JSON pointer to the block param of `method`, if we know where it is
+    pub fn pointer(&self, method: &str) -> Option<String> {
+        match self.custom.get(method) {
+            Some(pointer) => Some(pointer.to_owned()),
+            None => default_block_param(method).map(|position| format!("/{}", position)),
+        }
+    }
+}
+
 // Return the blocknumber from a json-rpc request as a Option<String>, returning None if it cant find anything
 pub fn get_block_number_from_request(
     tx: Value,
     named_blocknumbers: &Arc<RwLock<NamedBlocknumbers>>,
+    block_params: &BlockParams,
 ) -> Option<u64> {
     // Return none if `params` is not a thing
     let params = tx["params"].as_array();
@@ -65,24 +127,13 @@ pub fn get_block_number_from_request(
         return None;
     }
 
-    // The JSON-RPC standard is all over the place so depending on the method, we need to look at
-    // different param indexes. Why? Has i ever???
-    let position = match tx["method"].as_str() {
-        Some("eth_getBalance") => 1,
-        Some("eth_getStorageAt") => 2,
-        Some("eth_getTransactionCount") => 1,
-        Some("eth_getBlockTransactionCountByNumber") => 0,
-        Some("eth_getUncleCountByBlockNumber") => 0,
-        Some("eth_getCode") => 1,
-        Some("eth_call") => 1,
-        Some("eth_getBlockByNumber") => 0,
-        Some("eth_getTransactionByBlockNumberAndIndex") => 0,
-        Some("eth_getUncleByBlockNumberAndIndex") => 0,
-        _ => return None,
-    };
+    let pointer = block_params.pointer(tx["method"].as_str()?)?;
 
     // Get the corresponding blockbumber from the params
-    let block_number = tx["params"][position].to_string().replace('\"', "");
+    let block_number = tx["params"]
+        .pointer(&pointer)?
+        .to_string()
+        .replace('\"', "");
 
     // Return the corresponding named parameter from the RwLock is present
     let nn = has_named_number(&block_number);
@@ -108,10 +159,33 @@ pub fn get_block_number_from_request(
     Some(block_number)
 }
 
-// Replaces block tags with a hex number and return the request
+// Returns true if the request references a block above the current head.
+//
+// Always false if we don't know the head yet.
+pub fn is_future_block(
+    tx: &Value,
+    named_blocknumbers: &Arc<RwLock<NamedBlocknumbers>>,
+    block_params: &BlockParams,
+) -> bool {
+    let latest = named_blocknumbers.read().unwrap().latest;
+    if latest == 0 {
+        return false;
+    }
+
+    match get_block_number_from_request(tx.clone(), named_blocknumbers, block_params) {
+        Some(block_number) => block_number > latest,
+        None => false,
+    }
+}
+
+// Replaces block tags with a hex number and return the request.
+//
+// Only `latest`, `safe` and `finalized` get replaced, and only for methods
+// that don't change state. `pending` and `earliest` are left as-is.
 pub fn replace_block_tags(
     tx: &mut Value,
     named_blocknumbers: &Arc<RwLock<NamedBlocknumbers>>,
+    block_params: &BlockParams,
 ) -> Value {
     // Return if `params` is not a thing
     let params = tx["params"].as_array();
@@ -119,23 +193,21 @@ pub fn replace_block_tags(
         return tx.to_owned();
     }
 
-    // Determine the correct parameter index based on the method
-    let position = match tx["method"].as_str() {
-        Some("eth_getBalance")
-        | Some("eth_getTransactionCount")
-        | Some("eth_getCode")
-        | Some("eth_call") => 1,
-        Some("eth_getStorageAt") => 2,
-        Some("eth_getBlockTransactionCountByNumber")
-        | Some("eth_getUncleCountByBlockNumber")
-        | Some("eth_getBlockByNumber")
-        | Some("eth_getTransactionByBlockNumberAndIndex")
-        | Some("eth_getUncleByBlockNumberAndIndex") => 0,
-        _ => return tx.to_owned(),
+    // Determine the correct parameter position based on the method
+    let pointer = match tx["method"]
+        .as_str()
+        .filter(|method| is_idempotent(method))
+        .and_then(|method| block_params.pointer(method))
+    {
+        Some(pointer) => pointer,
+        None => return tx.to_owned(),
     };
 
     // Extract the block number parameter
-    let block_number = tx["params"][position].to_string().replace('\"', "");
+    let block_number = match tx["params"].pointer(&pointer) {
+        Some(block_number) => block_number.to_string().replace('\"', ""),
+        None => return tx.to_owned(),
+    };
 
     // Check if the block number is a named tag
     let nn = has_named_number(&block_number);
@@ -144,15 +216,17 @@ pub fn replace_block_tags(
 
         // Replace the named block tag with its corresponding hex value
         match nn {
-            NamedNumber::Latest => {
-                if rwlock_guard.latest != 0 {
-                    tx["params"][position] = json!(format!("0x{:x}", rwlock_guard.latest));
-                }
+            NamedNumber::Latest if rwlock_guard.latest != 0 => {
+                *tx["params"].pointer_mut(&pointer).unwrap() =
+                    json!(format!("0x{:x}", rwlock_guard.latest));
+            }
+            NamedNumber::Safe if rwlock_guard.safe != 0 => {
+                *tx["params"].pointer_mut(&pointer).unwrap() =
+                    json!(format!("0x{:x}", rwlock_guard.safe));
             }
-            NamedNumber::Finalized => {
-                if rwlock_guard.finalized != 0 {
-                    tx["params"][position] = json!(format!("0x{:x}", rwlock_guard.finalized));
-                }
+            NamedNumber::Finalized if rwlock_guard.finalized != 0 => {
+                *tx["params"].pointer_mut(&pointer).unwrap() =
+                    json!(format!("0x{:x}", rwlock_guard.finalized));
             }
             _ => (),
         }
@@ -161,28 +235,94 @@ pub fn replace_block_tags(
     tx.to_owned()
 }
 
-pub async fn incoming_to_value(tx: Request<Incoming>) -> Result<Value, hyper::Error> {
-    #[cfg(feature = "debug-verbose")]
-    println!("Incoming request: {:?}", tx);
-
-    let tx = tx.collect().await?.to_bytes().clone();
-    let mut tx = from_utf8(&tx).unwrap().to_owned();
-
-    let ret = match unsafe { from_str(&mut tx) } {
-        Ok(ret) => ret,
-        Err(_) => {
-            // Insane error handling
-            let ret = json!({
-                "id": Null,
-                "jsonrpc": "2.0",
-                "result": "Invalid JSON",
-            });
+// Fields of an `eth_call` call object that are quantities
+const CALL_QUANTITIES: [&str; 6] = [
+    "gas",
+    "gasPrice",
+    "maxFeePerGas",
+    "maxPriorityFeePerGas",
+    "value",
+    "nonce",
+];
+
+// Get the key a request is cached under.
+//
+// Only `method` and `params` change the result, so the id and `jsonrpc` are left out.
+// Every param is kept, like the full transactions flag of `eth_getBlockByNumber`.
+// Hex is lowercased and block numbers lose their leading zeros, so requests that
+// mean the same thing end up in the same cache entry. Object keys are always
+// serialized sorted, so their order doesn't matter either.
+pub fn cache_key(tx: &Value, block_params: &BlockParams) -> String {
+    let mut params = tx["params"].clone();
+    lowercase_hex(&mut params);
+
+    let method = tx["method"].as_str().unwrap_or_default();
+    let mut quantities: Vec<String> = block_params.pointer(method).into_iter().collect();
+    if method == "eth_getLogs" {
+        quantities.extend(["/0/fromBlock".to_string(), "/0/toBlock".to_string()]);
+    }
+    if method == "eth_call" {
+        // Unset fields can also be sent as null
+        if let Some(Value::Object(call)) = params.get_mut(0) {
+            call.retain(|_, value| !value.is_null());
+        }
+        quantities.extend(CALL_QUANTITIES.iter().map(|field| format!("/0/{}", field)));
+    }
 
-            return Ok(ret);
+    for pointer in quantities {
+        if let Some(quantity) = params.pointer_mut(&pointer) {
+            trim_quantity(quantity);
         }
-    };
+    }
+
+    json!({"method": tx["method"], "params": params}).to_string()
+}
 
-    Ok(ret)
+fn is_hex(param: &str) -> bool {
+    param.len() > 2
+        && (param.starts_with("0x") || param.starts_with("0X"))
+        && param[2..].bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+// Lowercase every hex string in `value`
+fn lowercase_hex(value: &mut Value) {
+    match value {
+        Value::String(param) if is_hex(param) => *param = param.to_lowercase(),
+        Value::Array(values) => values.iter_mut().for_each(lowercase_hex),
+        Value::Object(values) => values.values_mut().for_each(lowercase_hex),
+        _ => (),
+    }
+}
+
+// Strip leading zeros from a hex quantity, so `0x0a` becomes `0xa`
+fn trim_quantity(quantity: &mut Value) {
+    let trimmed = quantity
+        .as_str()
+        .filter(|quantity| is_hex(quantity))
+        .map(|quantity| {
+            let digits = quantity[2..].trim_start_matches('0');
+            match digits.is_empty() {
+                true => "0x0".to_string(),
+                false => format!("0x{}", digits),
+            }
+        });
+
+    if let Some(trimmed) = trimmed {
+        *quantity = json!(trimmed);
+    }
+}
+
+pub async fn incoming_to_value(
+    tx: Request<Incoming>,
+    max_bytes: usize,
+) -> Result<Value, BodyError> {
+    #[cfg(feature = "debug-verbose")]
+    tracing::debug!("Incoming request: {:?}", tx);
+
+    let tx = read_limited(tx.into_body(), max_bytes).await?;
+
+    // Also rejects bodies that aren't valid UTF-8
+    from_slice(&mut tx.to_vec()).map_err(|_| BodyError::InvalidJson)
 }
 
 #[cfg(test)]
@@ -229,7 +369,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             Some(10)
         );
 
@@ -241,7 +381,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             None
         );
 
@@ -253,7 +393,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             Some(1)
         );
 
@@ -265,7 +405,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             None
         );
 
@@ -277,7 +417,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             Some(10)
         );
 
@@ -289,7 +429,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             Some(1)
         );
 
@@ -301,7 +441,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             None
         );
 
@@ -313,7 +453,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             Some(10)
         );
 
@@ -325,7 +465,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             Some(3)
         );
 
@@ -337,7 +477,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             Some(4)
         );
 
@@ -349,7 +489,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             Some(5)
         );
 
@@ -361,7 +501,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             Some(1)
         );
 
@@ -373,7 +513,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             None
         );
 
@@ -385,7 +525,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             Some(10)
         );
 
@@ -397,7 +537,7 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             Some(1)
         );
 
@@ -409,11 +549,58 @@ mod tests {
         });
 
         assert_eq!(
-            get_block_number_from_request(request, &named_blocknumbers),
+            get_block_number_from_request(request, &named_blocknumbers, &BlockParams::default()),
             None
         );
     }
 
+    #[test]
+    fn is_future_block_test() {
+        let named_blocknumbers = dummy_named_blocknumbers();
+
+        let request = json!({
+            "method": "eth_getBlockByNumber",
+            "params": ["0xb", false]
+        });
+        assert!(is_future_block(
+            &request,
+            &named_blocknumbers,
+            &BlockParams::default()
+        ));
+
+        let request = json!({
+            "method": "eth_getBlockByNumber",
+            "params": ["0xa", false]
+        });
+        assert!(!is_future_block(
+            &request,
+            &named_blocknumbers,
+            &BlockParams::default()
+        ));
+
+        let request = json!({
+            "method": "eth_getBlockByNumber",
+            "params": ["latest", false]
+        });
+        assert!(!is_future_block(
+            &request,
+            &named_blocknumbers,
+            &BlockParams::default()
+        ));
+
+        // We can't tell if we don't know the head
+        named_blocknumbers.write().unwrap().latest = 0;
+        let request = json!({
+            "method": "eth_getBlockByNumber",
+            "params": ["0xb", false]
+        });
+        assert!(!is_future_block(
+            &request,
+            &named_blocknumbers,
+            &BlockParams::default()
+        ));
+    }
+
     #[test]
     fn replace_named_block_number_test() {
         let named_blocknumbers = dummy_named_blocknumbers();
@@ -427,7 +614,51 @@ mod tests {
             "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0xa"]
         });
 
-        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), expected);
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers, &BlockParams::default()),
+            expected
+        );
+    }
+
+    #[test]
+    fn replace_safe_and_finalized_test() {
+        let named_blocknumbers = dummy_named_blocknumbers();
+
+        let mut tx = json!({"method": "eth_getBlockByNumber", "params": ["safe", false]});
+        let expected = json!({"method": "eth_getBlockByNumber", "params": ["0x3", false]});
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers, &BlockParams::default()),
+            expected
+        );
+
+        let mut tx = json!({"method": "eth_getBlockByNumber", "params": ["finalized", false]});
+        let expected = json!({"method": "eth_getBlockByNumber", "params": ["0x4", false]});
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers, &BlockParams::default()),
+            expected
+        );
+
+        // `pending` changes as transactions come in, so it's left alone
+        let mut tx = json!({"method": "eth_getBlockByNumber", "params": ["pending", false]});
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers, &BlockParams::default()),
+            tx
+        );
+    }
+
+    #[test]
+    fn keep_tags_of_state_changing_methods_test() {
+        let named_blocknumbers = dummy_named_blocknumbers();
+        let block_params = BlockParams::new(HashMap::from([(
+            "eth_sendRawTransaction".to_string(),
+            "/1".to_string(),
+        )]));
+
+        let mut tx = json!({"method": "eth_sendRawTransaction", "params": ["0xf86c", "latest"]});
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers, &block_params),
+            tx
+        );
     }
 
     #[test]
@@ -438,7 +669,10 @@ mod tests {
             "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]
         });
 
-        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), tx);
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers, &BlockParams::default()),
+            tx
+        );
     }
 
     #[test]
@@ -449,7 +683,10 @@ mod tests {
             "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "invalid"]
         });
 
-        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), tx);
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers, &BlockParams::default()),
+            tx
+        );
     }
 
     #[test]
@@ -468,7 +705,7 @@ mod tests {
                 "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0xa"]
             });
 
-            let a = replace_block_tags(&mut tx, &named_blocknumbers);
+            let a = replace_block_tags(&mut tx, &named_blocknumbers, &BlockParams::default());
 
             assert_eq!(a, expected);
         }
@@ -487,11 +724,19 @@ mod tests {
         });
 
         assert_eq!(
-            replace_block_tags(&mut tx_no_params, &named_blocknumbers),
+            replace_block_tags(
+                &mut tx_no_params,
+                &named_blocknumbers,
+                &BlockParams::default()
+            ),
             tx_no_params
         );
         assert_eq!(
-            replace_block_tags(&mut tx_empty_params, &named_blocknumbers),
+            replace_block_tags(
+                &mut tx_empty_params,
+                &named_blocknumbers,
+                &BlockParams::default()
+            ),
             tx_empty_params
         );
     }
@@ -510,7 +755,10 @@ mod tests {
             "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "latest"]
         });
 
-        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), expected);
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers, &BlockParams::default()),
+            expected
+        );
     }
 
     #[test]
@@ -521,6 +769,147 @@ mod tests {
             "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", 100]
         });
 
-        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), tx);
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers, &BlockParams::default()),
+            tx
+        );
+    }
+
+    #[test]
+    fn custom_block_params_test() {
+        let named_blocknumbers = dummy_named_blocknumbers();
+        let block_params = BlockParams::new(HashMap::from([
+            ("vendor_getAt".to_string(), "/2".to_string()),
+            ("vendor_getNested".to_string(), "/0/blockNumber".to_string()),
+            // Overrides the default position
+            ("eth_getBalance".to_string(), "/0".to_string()),
+        ]));
+
+        assert_eq!(block_params.pointer("vendor_getAt"), Some("/2".to_string()));
+        assert_eq!(block_params.pointer("eth_call"), Some("/1".to_string()));
+        assert_eq!(block_params.pointer("vendor_unknown"), None);
+
+        let request = json!({"method": "vendor_getAt", "params": ["0x0", "0x0", "0x7"]});
+        assert_eq!(
+            get_block_number_from_request(request, &named_blocknumbers, &block_params),
+            Some(7)
+        );
+
+        let request = json!({"method": "vendor_getNested", "params": [{"blockNumber": "safe"}]});
+        assert_eq!(
+            get_block_number_from_request(request, &named_blocknumbers, &block_params),
+            Some(3)
+        );
+
+        let request = json!({"method": "eth_getBalance", "params": ["0x8", "0x7"]});
+        assert_eq!(
+            get_block_number_from_request(request, &named_blocknumbers, &block_params),
+            Some(8)
+        );
+
+        // Missing param
+        let request = json!({"method": "vendor_getAt", "params": ["0x0"]});
+        assert_eq!(
+            get_block_number_from_request(request, &named_blocknumbers, &block_params),
+            None
+        );
+
+        // Tags get replaced at the configured position
+        let mut tx = json!({"method": "vendor_getNested", "params": [{"blockNumber": "latest"}]});
+        let expected = json!({"method": "vendor_getNested", "params": [{"blockNumber": "0xa"}]});
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers, &block_params),
+            expected
+        );
+    }
+
+    #[test]
+    fn cache_key_test() {
+        let block_params = BlockParams::default();
+        let key = |tx: Value| cache_key(&tx, &block_params);
+
+        // Ids and the jsonrpc version don't matter
+        assert_eq!(
+            key(
+                json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0xab", "0x1"]})
+            ),
+            key(json!({"id": "some-id", "method": "eth_getBalance", "params": ["0xab", "0x1"]})),
+        );
+
+        // Neither does hex casing, or leading zeros in block numbers
+        assert_eq!(
+            key(json!({"method": "eth_getBalance", "params": ["0xAB", "0x01"]})),
+            key(json!({"method": "eth_getBalance", "params": ["0xab", "0x1"]})),
+        );
+        assert_eq!(
+            key(
+                json!({"method": "eth_getLogs", "params": [{"fromBlock": "0x0A", "toBlock": "0x0b", "address": "0xCD"}]})
+            ),
+            key(
+                json!({"method": "eth_getLogs", "params": [{"fromBlock": "0xa", "toBlock": "0xb", "address": "0xcd"}]})
+            ),
+        );
+
+        // Leading zeros in data are significant
+        assert_ne!(
+            key(json!({"method": "eth_getBalance", "params": ["0x00ab", "0x1"]})),
+            key(json!({"method": "eth_getBalance", "params": ["0xab", "0x1"]})),
+        );
+        assert_ne!(
+            key(json!({"method": "eth_getBalance", "params": ["0xab", "0x1"]})),
+            key(json!({"method": "eth_getCode", "params": ["0xab", "0x1"]})),
+        );
+
+        // Non-hex strings are left alone
+        assert_ne!(
+            key(json!({"method": "web3_sha3", "params": ["Hello"]})),
+            key(json!({"method": "web3_sha3", "params": ["hello"]})),
+        );
+
+        // Flags change the shape of the result
+        assert_ne!(
+            key(json!({"method": "eth_getBlockByNumber", "params": ["0x1", true]})),
+            key(json!({"method": "eth_getBlockByNumber", "params": ["0x1", false]})),
+        );
+        assert_eq!(
+            key(json!({"method": "eth_getBlockByNumber", "params": ["0x01", true]})),
+            key(json!({"method": "eth_getBlockByNumber", "params": ["0x1", true]})),
+        );
+    }
+
+    #[test]
+    fn cache_key_eth_call_test() {
+        let block_params = BlockParams::default();
+        let key = |tx: &str| cache_key(&serde_json::from_str(tx).unwrap(), &block_params);
+
+        // Field order, address casing, leading zeros in quantities and unset fields
+        assert_eq!(
+            key(
+                r#"{"method": "eth_call", "params": [{"to": "0xABCD", "data": "0x70a08231", "value": "0x00", "gas": "0x0186a0"}, "0x0a"]}"#
+            ),
+            key(
+                r#"{"method": "eth_call", "params": [{"gas": "0x186a0", "from": null, "value": "0x0", "data": "0x70A08231", "to": "0xabcd"}, "0xa"]}"#
+            ),
+        );
+
+        // Quantities larger than a u64
+        assert_eq!(
+            key(
+                r#"{"method": "eth_call", "params": [{"to": "0xab", "value": "0x00de0b6b3a7640000000"}, "0x1"]}"#
+            ),
+            key(
+                r#"{"method": "eth_call", "params": [{"to": "0xab", "value": "0xde0b6b3a7640000000"}, "0x1"]}"#
+            ),
+        );
+
+        // Leading zeros in calldata are significant
+        assert_ne!(
+            key(r#"{"method": "eth_call", "params": [{"to": "0xab", "data": "0x00ab"}, "0x1"]}"#),
+            key(r#"{"method": "eth_call", "params": [{"to": "0xab", "data": "0xab"}, "0x1"]}"#),
+        );
+        assert_ne!(
+            key(r#"{"method": "eth_call", "params": [{"to": "0xab"}, "0x1"]}"#),
+            key(r#"{"method": "eth_call", "params": [{"to": "0xab"}, "0x2"]}"#),
+        );
     }
 }
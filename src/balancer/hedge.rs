@@ -0,0 +1,204 @@
+// Hedged requests.
+//
+// Requests are sent to a single RPC. If it hasn't responded after the hedge
+// delay, the same request is sent to a second RPC and we take whichever
+// response comes first, dropping the other one. Unlike always racing
+// requests, this only adds load for requests in the slow tail.
+use crate::rpc::{
+    error::RpcError,
+    types::Rpc,
+};
+
+use tracing::info;
+
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::time::sleep;
+
+// Returns true if `method` can safely be sent more than once.
+pub fn is_idempotent(method: &str) -> bool {
+    !matches!(
+        method,
+        "eth_sendRawTransaction"
+            | "eth_sendTransaction"
+            | "eth_sign"
+            | "eth_signTransaction"
+            | "eth_signTypedData"
+            | "eth_submitWork"
+            | "eth_submitHashrate"
+            | "eth_newFilter"
+            | "eth_newBlockFilter"
+            | "eth_newPendingTransactionFilter"
+            | "eth_uninstallFilter"
+            | "eth_getFilterChanges"
+            | "eth_subscribe"
+            | "eth_unsubscribe"
+    ) && !method.starts_with("personal_")
+}
+
+// Who responded to a hedged request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HedgeWinner {
+    Primary,
+    Hedge,
+}
+
+#[derive(Debug)]
+pub struct HedgedResponse {
+    pub rx: Result<String, RpcError>,
+    // Who `rx` came from, be it a response or an error
+    pub winner: HedgeWinner,
+    // The hedge delay expired and the request was also sent to the hedge
+    pub hedged: bool,
+}
+
+// Send `tx` to `rpc`, hedging it to `hedge` if `rpc` takes longer than `delay`.
+//
+// The hedge only counts the request if it's actually sent to it, and it's
+// skipped if it's already at its `max_concurrency`.
+pub async fn send_hedged(
+    rpc: &Rpc,
+    hedge: Option<&Rpc>,
+    tx: Value,
+    delay: Duration,
+) -> HedgedResponse {
+    let primary = |rx| {
+        HedgedResponse {
+            rx,
+            winner: HedgeWinner::Primary,
+            hedged: false,
+        }
+    };
+
+    let hedge = match hedge {
+        Some(hedge) => hedge,
+        None => return primary(rpc.send_request(tx).await),
+    };
+
+    let primary_rx = rpc.send_request(tx.clone());
+    tokio::pin!(primary_rx);
+
+    tokio::select! {
+        rx = &mut primary_rx => return primary(rx),
+        _ = sleep(delay) => {}
+    }
+
+    // Only hedge if the hedge has room for another request
+    let _capacity = match hedge.try_acquire_capacity() {
+        Ok(capacity) => capacity,
+        Err(_) => {
+            info!("{} is slow to respond, but {} is saturated", rpc.url, hedge.url);
+            return primary(primary_rx.await);
+        }
+    };
+
+    info!(
+        "{} is slow to respond, hedging request to: {}",
+        rpc.url, hedge.url
+    );
+    let _in_flight = hedge.track_in_flight();
+    hedge.count_request();
+
+    // Whichever loses gets dropped, cancelling the request
+    let (rx, winner) = tokio::select! {
+        rx = &mut primary_rx => (rx, HedgeWinner::Primary),
+        rx = hedge.send_request(tx) => (rx, HedgeWinner::Hedge),
+    };
+
+    HedgedResponse {
+        rx,
+        winner,
+        hedged: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::MockRpc;
+    use serde_json::json;
+    use std::time::Instant;
+
+    async fn mock_rpc(delay: Duration, result: &'static str) -> (MockRpc, Rpc) {
+        let mock = MockRpc::spawn(
+            delay,
+            move |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": result}),
+        )
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        (mock, rpc)
+    }
+
+    #[test]
+    fn test_is_idempotent() {
+        assert!(is_idempotent("eth_call"));
+        assert!(is_idempotent("eth_getLogs"));
+        assert!(!is_idempotent("eth_sendRawTransaction"));
+        assert!(!is_idempotent("personal_sign"));
+    }
+
+    #[tokio::test]
+    async fn test_hedge_wins() {
+        let (_slow_mock, slow) = mock_rpc(Duration::from_millis(500), "slow").await;
+        let (fast_mock, fast) = mock_rpc(Duration::ZERO, "fast").await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call", "params": []});
+        let start = Instant::now();
+        let response = send_hedged(&slow, Some(&fast), tx, Duration::from_millis(50)).await;
+
+        assert_eq!(response.winner, HedgeWinner::Hedge);
+        assert!(response.hedged);
+        assert!(response.rx.unwrap().contains("fast"));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(fast_mock.requests(), 1);
+        assert_eq!(fast.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_hedge_error() {
+        let (_slow_mock, slow) = mock_rpc(Duration::from_millis(500), "slow").await;
+        let broken = Rpc::new("http://127.0.0.1:1".to_string(), None, 0, 0, 1.0);
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call", "params": []});
+        let response = send_hedged(&slow, Some(&broken), tx, Duration::from_millis(50)).await;
+
+        // The error came from the hedge, not the primary
+        assert_eq!(response.winner, HedgeWinner::Hedge);
+        assert!(response.hedged);
+        assert!(response.rx.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_no_hedge_for_fast_primary() {
+        let (_primary_mock, primary) = mock_rpc(Duration::ZERO, "primary").await;
+        let (hedge_mock, hedge) = mock_rpc(Duration::ZERO, "hedge").await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call", "params": []});
+        let response = send_hedged(&primary, Some(&hedge), tx, Duration::from_millis(200)).await;
+
+        assert_eq!(response.winner, HedgeWinner::Primary);
+        assert!(!response.hedged);
+        assert!(response.rx.unwrap().contains("primary"));
+        assert_eq!(hedge_mock.requests(), 0);
+        // Hedges that never fire don't count as requests to the hedge
+        assert_eq!(hedge.requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_no_hedge_to_saturated_rpc() {
+        let (_slow_mock, slow) = mock_rpc(Duration::from_millis(100), "slow").await;
+        let (hedge_mock, mut hedge) = mock_rpc(Duration::ZERO, "hedge").await;
+        hedge.set_max_concurrency(1);
+        let _capacity = hedge.acquire_capacity().await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call", "params": []});
+        let response = send_hedged(&slow, Some(&hedge), tx, Duration::from_millis(10)).await;
+
+        assert_eq!(response.winner, HedgeWinner::Primary);
+        assert!(!response.hedged);
+        assert!(response.rx.unwrap().contains("slow"));
+        assert_eq!(hedge_mock.requests(), 0);
+        assert_eq!(hedge.requests(), 0);
+    }
+}
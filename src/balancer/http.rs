@@ -0,0 +1,147 @@
+// Connection-level tuning for the HTTP/1 listener.
+//
+// hyper needs a timer for the header read timeout, and `hyper_util` only
+// ships one in newer versions than we use, so we bring our own.
+use crate::config::types::HttpSettings;
+
+use hyper::{
+    rt::{
+        Sleep,
+        Timer,
+    },
+    server::conn::http1,
+};
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+// Build the builder used to serve every incoming connection
+pub fn http1_builder(http: &HttpSettings) -> http1::Builder {
+    let mut builder = http1::Builder::new();
+    builder
+        .keep_alive(http.keepalive)
+        .max_buf_size(http.max_buf_size);
+
+    if http.header_timeout_ms != 0 {
+        builder
+            .timer(TokioTimer)
+            .header_read_timeout(Duration::from_millis(http.header_timeout_ms));
+    }
+
+    builder
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokioTimer;
+
+impl Timer for TokioTimer {
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Sleep>> {
+        Box::pin(TokioSleep(Box::pin(tokio::time::sleep(duration))))
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Sleep>> {
+        Box::pin(TokioSleep(Box::pin(tokio::time::sleep_until(
+            deadline.into(),
+        ))))
+    }
+}
+
+struct TokioSleep(Pin<Box<tokio::time::Sleep>>);
+
+impl Future for TokioSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+impl Sleep for TokioSleep {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::service_fn;
+    use hyper_util_blutgang::rt::TokioIo;
+    use std::convert::Infallible;
+    use tokio::{
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt,
+        },
+        net::{
+            TcpListener,
+            TcpStream,
+        },
+    };
+
+    // Serve one connection with `http`, answering every request with an empty body
+    async fn serve(http: HttpSettings) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = http1_builder(&http)
+                .serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(|_| {
+                        async {
+                            Ok::<_, Infallible>(hyper::Response::new(http_body_util::Full::new(
+                                hyper::body::Bytes::new(),
+                            )))
+                        }
+                    }),
+                )
+                .await;
+        });
+        addr
+    }
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: blutgang\r\n\r\n";
+
+    #[tokio::test]
+    async fn test_keepalive() {
+        let addr = serve(HttpSettings {
+            keepalive: false,
+            ..Default::default()
+        })
+        .await;
+
+        // The connection gets closed after the first response
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(REQUEST).await.unwrap();
+        let mut response = String::new();
+        let read =
+            tokio::time::timeout(Duration::from_secs(2), stream.read_to_string(&mut response))
+                .await;
+        assert!(read.is_ok(), "connection wasn't closed");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[tokio::test]
+    async fn test_header_timeout() {
+        let addr = serve(HttpSettings {
+            header_timeout_ms: 50,
+            ..Default::default()
+        })
+        .await;
+
+        // Send half the headers and stall
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&REQUEST[..10]).await.unwrap();
+        let mut response = Vec::new();
+        let read =
+            tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut response)).await;
+        assert!(read.is_ok(), "connection wasn't closed");
+    }
+}
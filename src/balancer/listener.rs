@@ -0,0 +1,248 @@
+// Listening for incoming connections.
+//
+// We always listen on TCP at `address`. If `unix_socket` is set, we also listen
+// on a Unix domain socket at that path, which is handy for colocated services.
+// Connections from both get served the same way. The TCP listen backlog is
+// configurable, the unix socket uses the OS default.
+use tracing::warn;
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncWrite,
+        ReadBuf,
+    },
+    net::{
+        TcpListener,
+        TcpSocket,
+        TcpStream,
+    },
+};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use tokio::net::{
+    UnixListener,
+    UnixStream,
+};
+
+// A connection accepted by `Listener`
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Listener {
+    tcp: TcpListener,
+    #[cfg(unix)]
+    unix: Option<(UnixListener, PathBuf)>,
+}
+
+impl Listener {
+    // Bind to `address` with a listen `backlog`, and to `unix_socket` if set
+    pub async fn bind(
+        address: SocketAddr,
+        unix_socket: Option<&str>,
+        backlog: u32,
+    ) -> io::Result<Self> {
+        let tcp = bind_tcp(address, backlog)?;
+
+        #[cfg(unix)]
+        let unix = match unix_socket {
+            Some(path) => {
+                let path = PathBuf::from(path);
+                remove_stale_socket(&path)?;
+                Some((UnixListener::bind(&path)?, path))
+            }
+            None => None,
+        };
+
+        #[cfg(not(unix))]
+        if unix_socket.is_some() {
+            warn!("Unix sockets are not supported on this platform, ignoring `unix_socket`.");
+        }
+
+        Ok(Listener {
+            tcp,
+            #[cfg(unix)]
+            unix,
+        })
+    }
+
+    // Accept the next connection on any of our sockets.
+    //
+    // Returns the address of the peer for TCP connections.
+    pub async fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        #[cfg(unix)]
+        if let Some((unix, _)) = &self.unix {
+            return tokio::select! {
+                accepted = self.tcp.accept() => {
+                    accepted.map(|(stream, addr)| (Stream::Tcp(stream), Some(addr)))
+                }
+                accepted = unix.accept() => {
+                    accepted.map(|(stream, _)| (Stream::Unix(stream), None))
+                }
+            };
+        }
+
+        let (stream, addr) = self.tcp.accept().await?;
+        Ok((Stream::Tcp(stream), Some(addr)))
+    }
+}
+
+// Bind a TCP listener, with SO_REUSEADDR like `TcpListener::bind` so restarts
+// don't fail on sockets in TIME_WAIT
+fn bind_tcp(address: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(address)?;
+    socket.listen(backlog)
+}
+
+// Remove the socket file so it doesn't stick around after we exit
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Some((_, path)) = &self.unix {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// Remove a socket file left behind by a previous run that didn't exit cleanly
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            warn!("Removing stale unix socket at {}", path.display());
+            std::fs::remove_file(path)
+        }
+        // Let bind fail instead of deleting something that isn't a socket
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::io::{
+        AsyncReadExt,
+        AsyncWriteExt,
+    };
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("blutgang-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_accept_tcp_and_unix() {
+        let path = socket_path("accept");
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), path.to_str(), 128)
+            .await
+            .unwrap();
+        let tcp_addr = listener.tcp.local_addr().unwrap();
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut stream, peer) = listener.accept().await.unwrap();
+        assert!(matches!(stream, Stream::Unix(_)));
+        assert!(peer.is_none());
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let _client = TcpStream::connect(tcp_addr).await.unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        assert!(matches!(stream, Stream::Tcp(_)));
+        assert!(peer.is_some());
+
+        // The socket file goes away with the listener
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_stale_socket_removed() {
+        let path = socket_path("stale");
+
+        // Unlike ours, std listeners leave the socket file behind when dropped
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), path.to_str(), 128)
+            .await
+            .unwrap();
+        let _client = UnixStream::connect(&path).await.unwrap();
+        assert!(listener.accept().await.is_ok());
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,560 @@
+// Sharded `eth_getLogs` cache.
+//
+// Caching whole `eth_getLogs` responses has poor hit rates, since filters
+// from different clients rarely match exactly. Instead, we cache the logs of
+// every address in every finalized block on their own, and compose responses
+// from those shards, filtering by topic ourselves. Overlapping filters over
+// the same addresses then share what's cached.
+//
+// Only filters over finalized block ranges with at least one address are
+// sharded. Everything else goes through the regular cache.
+//
+// Shards are keyed like any other entry, so they follow `cache_version` and
+// the schema version, and `blutgang_purgeMethod` for `eth_getLogs` drops them.
+use crate::{
+    balancer::{
+        admission::AdmissionPermit,
+        processing::{
+            fetch_logs_chunk,
+            method_index_key,
+            CacheArgs,
+            LogsChunkError,
+        },
+    },
+    config::types::SelectionStrategy,
+    rpc::types::hex_to_decimal,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        Arc,
+        RwLock,
+    },
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+use sled::Batch;
+
+// Max amount of blocks a filter can span to be sharded
+const MAX_SHARDED_RANGE: u64 = 2000;
+
+// Prefix of shard keys. Keeps them apart from regular cache entries.
+pub const SHARD_PREFIX: &[u8] = b"logs/";
+
+#[derive(Debug, Clone, PartialEq)]
+struct LogsFilter {
+    from_block: u64,
+    to_block: u64,
+    addresses: Vec<String>,
+    // Topics to match at each position, `None` matches anything
+    topics: Vec<Option<Vec<String>>>,
+}
+
+// Shards that aren't cached yet
+#[derive(Debug, Default)]
+struct MissingShards {
+    addresses: Vec<String>,
+    from_block: u64,
+    to_block: u64,
+}
+
+// Parse an `eth_getLogs` request into a filter we can shard.
+fn parse_filter(tx: &Value, finalized: u64) -> Option<LogsFilter> {
+    if tx["method"] != "eth_getLogs" {
+        return None;
+    }
+
+    let filter = tx["params"].get(0)?.as_object()?;
+    if filter.contains_key("blockHash") {
+        return None;
+    }
+
+    let from_block = hex_to_decimal(filter.get("fromBlock")?.as_str()?).ok()?;
+    let to_block = hex_to_decimal(filter.get("toBlock")?.as_str()?).ok()?;
+    if from_block > to_block || to_block > finalized || to_block - from_block >= MAX_SHARDED_RANGE {
+        return None;
+    }
+
+    let addresses: Vec<String> = match filter.get("address")? {
+        Value::String(address) => vec![address.to_lowercase()],
+        Value::Array(addresses) => {
+            addresses
+                .iter()
+                .map(|address| address.as_str().map(str::to_lowercase))
+                .collect::<Option<_>>()?
+        }
+        _ => return None,
+    };
+    if addresses.is_empty() {
+        return None;
+    }
+
+    let topics = match filter.get("topics") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(topics)) => {
+            topics
+                .iter()
+                .map(|topic| {
+                    match topic {
+                        Value::Null => Some(None),
+                        Value::String(topic) => Some(Some(vec![topic.to_lowercase()])),
+                        Value::Array(options) => {
+                            options
+                                .iter()
+                                .map(|option| option.as_str().map(str::to_lowercase))
+                                .collect::<Option<Vec<_>>>()
+                                .map(Some)
+                        }
+                        _ => None,
+                    }
+                })
+                .collect::<Option<_>>()?
+        }
+        _ => return None,
+    };
+
+    Some(LogsFilter {
+        from_block,
+        to_block,
+        addresses,
+        topics,
+    })
+}
+
+fn shard_key(address: &str, block: u64, cache_args: &CacheArgs) -> Vec<u8> {
+    let mut key = Vec::with_capacity(SHARD_PREFIX.len() + address.len() + 8);
+    key.extend_from_slice(SHARD_PREFIX);
+    key.extend_from_slice(address.as_bytes());
+    key.extend_from_slice(&block.to_be_bytes());
+    cache_args.key(&key)
+}
+
+// Returns true if `log` matches the topics of `filter`
+fn matches_topics(log: &Value, topics: &[Option<Vec<String>>]) -> bool {
+    topics.iter().enumerate().all(|(position, options)| {
+        match options {
+            None => true,
+            Some(options) => {
+                log["topics"][position]
+                    .as_str()
+                    .map(|topic| options.contains(&topic.to_lowercase()))
+                    .unwrap_or(false)
+            }
+        }
+    })
+}
+
+// Collect the cached logs for `filter`, along with the shards we don't have yet.
+fn lookup(
+    filter: &LogsFilter,
+    cache_args: &CacheArgs,
+) -> Result<(Vec<Value>, MissingShards), sled::Error> {
+    let mut logs = Vec::new();
+    let mut missing = MissingShards {
+        from_block: u64::MAX,
+        ..Default::default()
+    };
+
+    for address in &filter.addresses {
+        let mut address_missing = false;
+
+        for block in filter.from_block..=filter.to_block {
+            let key = shard_key(address, block, cache_args);
+            match cache_args.cache.get(&key)? {
+                Some(shard) => {
+                    cache_args.cache_state.recency().touch(&key);
+                    let shard: Vec<Value> = serde_json::from_slice(&shard).unwrap_or_default();
+                    logs.extend(shard);
+                }
+                None => {
+                    address_missing = true;
+                    missing.from_block = missing.from_block.min(block);
+                    missing.to_block = missing.to_block.max(block);
+                }
+            }
+        }
+
+        if address_missing {
+            missing.addresses.push(address.clone());
+        }
+    }
+
+    Ok((logs, missing))
+}
+
+// Store the logs of `addresses` over a block range as shards.
+//
+// Every address gets a shard for every block, even if it's empty,
+// so we know we don't have to ask for it again.
+fn store(
+    logs: &[Value],
+    addresses: &[String],
+    from_block: u64,
+    to_block: u64,
+    cache_args: &CacheArgs,
+) {
+    if !cache_args.cache_state.can_write() {
+        return;
+    }
+
+    let mut shards: HashMap<(String, u64), Vec<&Value>> = HashMap::new();
+    for address in addresses {
+        for block in from_block..=to_block {
+            shards.insert((address.clone(), block), Vec::new());
+        }
+    }
+
+    for log in logs {
+        let address = log["address"].as_str().map(str::to_lowercase);
+        let block = log["blockNumber"]
+            .as_str()
+            .and_then(|block| hex_to_decimal(block).ok());
+
+        if let (Some(address), Some(block)) = (address, block) {
+            if let Some(shard) = shards.get_mut(&(address, block)) {
+                shard.push(log);
+            }
+        }
+    }
+
+    let mut batch = Batch::default();
+    let mut keys = Vec::with_capacity(shards.len());
+    for ((address, block), shard) in shards {
+        let key = shard_key(&address, block, cache_args);
+        batch.insert(method_index_key("eth_getLogs", &key), &[]);
+        batch.insert(key.as_slice(), serde_json::to_vec(&shard).unwrap());
+        keys.push(key);
+    }
+
+    cache_args
+        .cache_state
+        .handle_write(cache_args.cache.apply_batch(batch));
+    for key in keys {
+        cache_args.cache_state.recency().touch(&key);
+    }
+}
+
+// Fetch the logs of the missing shards from an RPC.
+//
+// Like any other request, this waits on the RPC's `max_concurrency` and is
+// retried up to `max_retries` times if the RPC doesn't respond.
+async fn fetch(
+    missing: &MissingShards,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    strategy: SelectionStrategy,
+    ttl: u128,
+    max_retries: u32,
+) -> Option<Vec<Value>> {
+    let tx = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "eth_getLogs",
+        "params": [{
+            "address": missing.addresses,
+        }],
+    });
+    let range = (missing.from_block, missing.to_block);
+
+    for _ in 0..max_retries.max(1) {
+        match fetch_logs_chunk(&tx, range, rpc_list, strategy, ttl).await {
+            Ok(logs) => return Some(logs),
+            // The RPC answered with an error, the regular path passes it on
+            Err(LogsChunkError::TooLarge(_)) | Err(LogsChunkError::Failed(_)) => return None,
+            Err(LogsChunkError::TimedOut) => {}
+        }
+    }
+
+    None
+}
+
+// Serve an `eth_getLogs` request from the sharded cache, fetching any missing shards.
+//
+// Returns `None` if the request can't be sharded, or if fetching the missing shards
+// failed, in which case the request should go through the regular path.
+// `admission` is only waited on if there are shards to fetch.
+pub async fn sharded_logs(
+    tx: &Value,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    cache_args: &CacheArgs,
+    admission: impl Future<Output = AdmissionPermit>,
+    strategy: SelectionStrategy,
+    ttl: u128,
+    max_retries: u32,
+) -> Option<Vec<Value>> {
+    let filter = parse_filter(tx, *cache_args.finalized_rx.borrow())?;
+
+    let (mut logs, missing) = lookup(&filter, cache_args).ok()?;
+    if !missing.addresses.is_empty() {
+        let _admission = admission.await;
+        let fetched = fetch(&missing, rpc_list, strategy, ttl, max_retries).await?;
+        store(
+            &fetched,
+            &missing.addresses,
+            missing.from_block,
+            missing.to_block,
+            cache_args,
+        );
+
+        // Fetched logs can overlap with cached ones, duplicates get removed below
+        logs.extend(fetched);
+    }
+
+    let block_and_index = |log: &Value| {
+        let number = |field: &str| {
+            log[field]
+                .as_str()
+                .and_then(|number| hex_to_decimal(number).ok())
+                .unwrap_or(0)
+        };
+        (number("blockNumber"), number("logIndex"))
+    };
+
+    let mut logs: Vec<Value> = logs
+        .into_iter()
+        .filter(|log| {
+            let block = block_and_index(log).0;
+            block >= filter.from_block
+                && block <= filter.to_block
+                && matches_topics(log, &filter.topics)
+        })
+        .collect();
+    logs.sort_by_key(block_and_index);
+    logs.dedup();
+
+    Some(logs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        balancer::{
+            admission::{
+                AdmissionControl,
+                Priority,
+            },
+            processing::purge_method,
+        },
+        rpc::mock::MockRpc,
+    };
+    use std::{
+        sync::Mutex,
+        time::Duration,
+    };
+    use tokio::sync::watch;
+
+    const ADDRESS: &str = "0x00000000000000000000000000000000000000aa";
+    const TRANSFER: &str = "0x1111111111111111111111111111111111111111111111111111111111111111";
+    const APPROVAL: &str = "0x2222222222222222222222222222222222222222222222222222222222222222";
+
+    // One transfer and one approval in every block
+    fn logs_in_range(from_block: u64, to_block: u64) -> Vec<Value> {
+        (from_block..=to_block)
+            .flat_map(|block| {
+                [TRANSFER, APPROVAL]
+                    .iter()
+                    .enumerate()
+                    .map(|(index, topic)| {
+                        json!({
+                            "address": ADDRESS,
+                            "blockNumber": format!("{:#x}", block),
+                            "logIndex": format!("{:#x}", index),
+                            "topics": [topic],
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    fn get_logs(from_block: u64, to_block: u64, topics: Value) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_getLogs",
+            "params": [{
+                "fromBlock": format!("{:#x}", from_block),
+                "toBlock": format!("{:#x}", to_block),
+                "address": ADDRESS,
+                "topics": topics,
+            }],
+        })
+    }
+
+    async fn sharded(
+        tx: Value,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        cache_args: &CacheArgs,
+    ) -> Option<Vec<Value>> {
+        let admission = Arc::new(AdmissionControl::default());
+        sharded_logs(
+            &tx,
+            rpc_list,
+            cache_args,
+            admission.acquire(Priority::Normal),
+            SelectionStrategy::default(),
+            1000,
+            2,
+        )
+        .await
+    }
+
+    // Responds to every `eth_getLogs` with `logs_in_range`
+    async fn logs_mock() -> MockRpc {
+        MockRpc::spawn(Duration::ZERO, move |request| {
+            let filter = &request["params"][0];
+            let from_block = hex_to_decimal(filter["fromBlock"].as_str().unwrap()).unwrap();
+            let to_block = hex_to_decimal(filter["toBlock"].as_str().unwrap()).unwrap();
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": logs_in_range(from_block, to_block)})
+        })
+        .await
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        let filter = parse_filter(&get_logs(1, 5, json!([TRANSFER, null])), 10).unwrap();
+        assert_eq!(filter.from_block, 1);
+        assert_eq!(filter.to_block, 5);
+        assert_eq!(filter.addresses, vec![ADDRESS.to_string()]);
+        assert_eq!(filter.topics, vec![Some(vec![TRANSFER.to_string()]), None]);
+
+        // Unfinalized
+        assert!(parse_filter(&get_logs(1, 11, json!([])), 10).is_none());
+
+        // No address
+        let mut tx = get_logs(1, 5, json!([]));
+        tx["params"][0].as_object_mut().unwrap().remove("address");
+        assert!(parse_filter(&tx, 10).is_none());
+
+        // Block tags
+        let mut tx = get_logs(1, 5, json!([]));
+        tx["params"][0]["toBlock"] = "latest".into();
+        assert!(parse_filter(&tx, 10).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_filters_share_shards() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_mock = requests.clone();
+        let mock = MockRpc::spawn(Duration::ZERO, move |request| {
+            let filter = &request["params"][0];
+            let from_block = hex_to_decimal(filter["fromBlock"].as_str().unwrap()).unwrap();
+            let to_block = hex_to_decimal(filter["toBlock"].as_str().unwrap()).unwrap();
+            requests_mock.lock().unwrap().push((from_block, to_block));
+
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": logs_in_range(from_block, to_block)})
+        })
+        .await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            mock.url.clone(),
+            None,
+            0,
+            0,
+            1.0,
+        )]));
+
+        let (_finalized_tx, finalized_rx) = watch::channel(100);
+        let cache_args = CacheArgs {
+            finalized_rx,
+            cache: CacheArgs::temporary_cache(),
+            ..CacheArgs::default()
+        };
+
+        // Transfers in blocks 1-5
+        let logs = sharded(get_logs(1, 5, json!([TRANSFER])), &rpc_list, &cache_args)
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 5);
+        assert!(logs.iter().all(|log| log["topics"][0] == TRANSFER));
+
+        // Approvals in blocks 2-4 are served from the same shards
+        let logs = sharded(get_logs(2, 4, json!([APPROVAL])), &rpc_list, &cache_args)
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 3);
+        assert_eq!(logs[0]["blockNumber"], "0x2");
+        assert!(logs.iter().all(|log| log["topics"][0] == APPROVAL));
+        assert_eq!(*requests.lock().unwrap(), vec![(1, 5)]);
+
+        // Only the blocks we don't have get fetched
+        let logs = sharded(get_logs(4, 7, Value::Null), &rpc_list, &cache_args)
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 8);
+        assert_eq!(logs, logs_in_range(4, 7));
+        assert_eq!(*requests.lock().unwrap(), vec![(1, 5), (6, 7)]);
+    }
+
+    #[tokio::test]
+    async fn test_shards_are_versioned_and_purgeable() {
+        let mock = logs_mock().await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            mock.url.clone(),
+            None,
+            0,
+            0,
+            1.0,
+        )]));
+        let (_finalized_tx, finalized_rx) = watch::channel(100);
+        let cache_args = CacheArgs {
+            finalized_rx,
+            cache: CacheArgs::temporary_cache(),
+            cache_version: 3,
+            ..CacheArgs::default()
+        };
+
+        sharded(get_logs(1, 2, json!([])), &rpc_list, &cache_args)
+            .await
+            .unwrap();
+        let key = shard_key(ADDRESS, 1, &cache_args);
+        assert!(key.starts_with(&cache_args.key(SHARD_PREFIX)));
+        assert!(cache_args.cache.contains_key(&key).unwrap());
+
+        // Another cache version doesn't see them
+        let other_version = CacheArgs {
+            cache_version: 4,
+            ..cache_args.clone()
+        };
+        let (_, missing) = lookup(
+            &parse_filter(&get_logs(1, 2, json!([])), 100).unwrap(),
+            &other_version,
+        )
+        .unwrap();
+        assert_eq!(missing.addresses, vec![ADDRESS.to_string()]);
+
+        assert_eq!(purge_method(&cache_args.cache, "eth_getLogs").unwrap(), 2);
+        assert!(!cache_args.cache.contains_key(&key).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_retries() {
+        let mock = logs_mock().await;
+        mock.set_status(500);
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            mock.url.clone(),
+            None,
+            0,
+            0,
+            1.0,
+        )]));
+        let (_finalized_tx, finalized_rx) = watch::channel(100);
+        let cache_args = CacheArgs {
+            finalized_rx,
+            cache: CacheArgs::temporary_cache(),
+            ..CacheArgs::default()
+        };
+
+        // Every attempt failed, so the request goes through the regular path
+        assert!(sharded(get_logs(1, 2, json!([])), &rpc_list, &cache_args)
+            .await
+            .is_none());
+        assert_eq!(mock.requests(), 2);
+        assert!(cache_args.cache.is_empty());
+    }
+}
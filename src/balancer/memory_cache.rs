@@ -0,0 +1,167 @@
+// In-memory LRU in front of the sled cache.
+//
+// Keeps the `capacity` most recently used cache entries in memory, so hot
+// keys don't have to go through sled on every request. Entries are keyed
+// the same as in sled. Disabled if `capacity` is 0.
+use sled::IVec;
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    sync::Mutex,
+};
+
+#[derive(Debug)]
+struct Entry {
+    value: IVec,
+    // Unix time in ms the entry expires at, for methods with a TTL
+    expires_at: Option<u64>,
+    // When the entry was last used, the lowest gets evicted first
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<Vec<u8>, Entry>,
+    // Last use -> key, in the order entries were used
+    order: BTreeMap<u64, Vec<u8>>,
+    clock: u64,
+}
+
+impl Lru {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+}
+
+impl MemoryCache {
+    pub fn new(capacity: usize) -> Self {
+        MemoryCache {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity != 0
+    }
+
+    // Get the entry for `key`, unless it expired before `now` (unix time in ms)
+    pub fn get(&self, key: &[u8], now: u64) -> Option<IVec> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        let used = lru.tick();
+        let lru = &mut *lru;
+
+        let entry = lru.entries.get_mut(key)?;
+        if entry.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            lru.order.remove(&entry.used);
+            lru.entries.remove(key);
+            return None;
+        }
+
+        lru.order.remove(&entry.used);
+        lru.order.insert(used, key.to_vec());
+        entry.used = used;
+
+        Some(entry.value.clone())
+    }
+
+    // Insert or replace the entry for `key`, evicting the least recently used
+    // entry if we're full.
+    pub fn insert(&self, key: &[u8], value: IVec, expires_at: Option<u64>) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        let used = lru.tick();
+
+        if let Some(old) = lru.entries.remove(key) {
+            lru.order.remove(&old.used);
+        } else if lru.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = lru.order.pop_first() {
+                lru.entries.remove(&oldest);
+            }
+        }
+
+        lru.order.insert(used, key.to_vec());
+        lru.entries.insert(
+            key.to_vec(),
+            Entry {
+                value,
+                expires_at,
+                used,
+            },
+        );
+    }
+
+    // Drop every entry, e.g. when a reorg might have made them stale
+    pub fn clear(&self) {
+        let mut lru = self.lru.lock().unwrap();
+        lru.entries.clear();
+        lru.order.clear();
+    }
+
+    #[cfg(test)]
+    pub fn entries(&self) -> usize {
+        self.lru.lock().unwrap().entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = MemoryCache::new(2);
+        cache.insert(b"a", IVec::from("1"), None);
+        cache.insert(b"b", IVec::from("2"), None);
+
+        // Using `a` makes `b` the oldest
+        assert_eq!(cache.get(b"a", 0), Some(IVec::from("1")));
+        cache.insert(b"c", IVec::from("3"), None);
+
+        assert_eq!(cache.entries(), 2);
+        assert_eq!(cache.get(b"b", 0), None);
+        assert_eq!(cache.get(b"a", 0), Some(IVec::from("1")));
+        assert_eq!(cache.get(b"c", 0), Some(IVec::from("3")));
+
+        // Replacing doesn't evict
+        cache.insert(b"c", IVec::from("4"), None);
+        assert_eq!(cache.entries(), 2);
+        assert_eq!(cache.get(b"c", 0), Some(IVec::from("4")));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let cache = MemoryCache::new(2);
+        cache.insert(b"a", IVec::from("1"), Some(100));
+
+        assert_eq!(cache.get(b"a", 99), Some(IVec::from("1")));
+        assert_eq!(cache.get(b"a", 100), None);
+        assert_eq!(cache.entries(), 0);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let cache = MemoryCache::new(0);
+        cache.insert(b"a", IVec::from("1"), None);
+
+        assert_eq!(cache.get(b"a", 0), None);
+        assert_eq!(cache.entries(), 0);
+    }
+}
@@ -0,0 +1,105 @@
+// Method allow and deny lists.
+//
+// Calls to methods in `denied_methods`, or missing from `allowed_methods` when
+// it isn't empty, get rejected before touching the cache or any RPC. The deny
+// list wins over the allow list.
+use crate::balancer::processing::jsonrpc_error;
+
+use serde_json::Value;
+
+use std::collections::HashSet;
+
+// Error code for methods we don't serve, same as for methods that don't exist
+pub const METHOD_BLOCKED: i64 = -32601;
+
+pub const DEFAULT_BLOCKED_MESSAGE: &str = "error: Method blocked!";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodFilter {
+    // Every method is allowed if empty
+    allowed: HashSet<String>,
+    denied: HashSet<String>,
+    // Error message blocked calls get
+    message: String,
+}
+
+impl Default for MethodFilter {
+    fn default() -> Self {
+        MethodFilter::new(HashSet::new(), HashSet::new())
+    }
+}
+
+impl MethodFilter {
+    pub fn new(allowed: HashSet<String>, denied: HashSet<String>) -> Self {
+        MethodFilter {
+            allowed,
+            denied,
+            message: DEFAULT_BLOCKED_MESSAGE.to_string(),
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> Self {
+        self.message = message;
+        self
+    }
+
+    pub fn is_allowed(&self, method: &str) -> bool {
+        !self.denied.contains(method) && (self.allowed.is_empty() || self.allowed.contains(method))
+    }
+
+    // Error response for a blocked call to `method`, saying which list blocked it
+    pub fn blocked(&self, id: &Value, method: &str) -> Value {
+        let reason = match self.denied.contains(method) {
+            true => format!("{} is in denied_methods", method),
+            false => format!("{} is not in allowed_methods", method),
+        };
+
+        jsonrpc_error(id, METHOD_BLOCKED, &self.message, Some(&reason))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(methods: &[&str]) -> HashSet<String> {
+        methods.iter().map(|method| method.to_string()).collect()
+    }
+
+    #[test]
+    fn test_method_filter() {
+        assert!(MethodFilter::default().is_allowed("eth_call"));
+
+        let filter = MethodFilter::new(set(&[]), set(&["debug_traceCall"]));
+        assert!(filter.is_allowed("eth_call"));
+        assert!(!filter.is_allowed("debug_traceCall"));
+
+        let filter = MethodFilter::new(
+            set(&["eth_call", "eth_blockNumber"]),
+            set(&["eth_blockNumber"]),
+        );
+        assert!(filter.is_allowed("eth_call"));
+        assert!(!filter.is_allowed("eth_getLogs"));
+        // Deny wins
+        assert!(!filter.is_allowed("eth_blockNumber"));
+    }
+
+    #[test]
+    fn test_blocked() {
+        let filter = MethodFilter::new(set(&["eth_call"]), set(&["debug_traceCall"]));
+
+        let rax = filter.blocked(&Value::from(7), "debug_traceCall");
+        assert_eq!(rax["id"], 7);
+        assert_eq!(rax["error"]["code"], METHOD_BLOCKED);
+        assert_eq!(rax["error"]["message"], DEFAULT_BLOCKED_MESSAGE);
+        assert_eq!(rax["error"]["data"], "debug_traceCall is in denied_methods");
+
+        let filter = filter.with_message("not here".to_string());
+        let rax = filter.blocked(&Value::Null, "eth_getLogs");
+        assert_eq!(rax["error"]["message"], "not here");
+        assert_eq!(
+            rax["error"]["data"],
+            "eth_getLogs is not in allowed_methods"
+        );
+    }
+}
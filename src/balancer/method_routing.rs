@@ -0,0 +1,79 @@
+// Per-method routing.
+//
+// Heavy methods like `trace_*` and `debug_*` are often only enabled on some nodes.
+// `method_routing` pins methods to a group of RPCs, set with `groups` on each RPC,
+// and calls to them only go to RPCs in that group. Routes ending with `_` match
+// every method with that prefix, and exact routes win over prefixes.
+use crate::balancer::processing::jsonrpc_error;
+
+use serde_json::Value;
+
+use std::collections::HashMap;
+
+// Same error code as when no archive node is available
+pub const NO_ROUTED_RPC: i64 = -32002;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MethodRouting {
+    // Method or method prefix, and the group serving it
+    routes: HashMap<String, String>,
+}
+
+impl MethodRouting {
+    pub fn new(routes: HashMap<String, String>) -> Self {
+        MethodRouting { routes }
+    }
+
+    // Groups methods can be routed to
+    pub fn groups(&self) -> impl Iterator<Item = &str> {
+        self.routes.values().map(String::as_str)
+    }
+
+    // Group of RPCs `method` is pinned to, if any
+    pub fn group(&self, method: &str) -> Option<&str> {
+        if let Some(group) = self.routes.get(method) {
+            return Some(group);
+        }
+
+        // Longest matching prefix
+        self.routes
+            .iter()
+            .filter(|(route, _)| route.ends_with('_') && method.starts_with(route.as_str()))
+            .max_by_key(|(route, _)| route.len())
+            .map(|(_, group)| group.as_str())
+    }
+
+    // Error response for a call to `method` when no RPC in its `group` is available
+    pub fn unavailable(id: &Value, method: &str, group: &str) -> Value {
+        jsonrpc_error(
+            id,
+            NO_ROUTED_RPC,
+            "error: No RPC available for this method!",
+            Some(&format!(
+                "{} is routed to the `{}` group, and none of its RPCs are in the active pool",
+                method, group
+            )),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_routing() {
+        let routing = MethodRouting::new(HashMap::from([
+            ("trace_".to_string(), "tracing".to_string()),
+            ("debug_".to_string(), "tracing".to_string()),
+            ("debug_traceCall".to_string(), "archive".to_string()),
+            ("debug_trace".to_string(), "not_a_prefix".to_string()),
+        ]));
+
+        assert_eq!(routing.group("trace_block"), Some("tracing"));
+        assert_eq!(routing.group("debug_traceTransaction"), Some("tracing"));
+        assert_eq!(routing.group("debug_traceCall"), Some("archive"));
+        assert_eq!(routing.group("eth_call"), None);
+        assert_eq!(MethodRouting::default().group("trace_block"), None);
+    }
+}
@@ -1,5 +1,27 @@
 pub mod accept_http;
+pub mod admission;
+pub mod auth;
+pub mod body_limit;
+pub mod cache_eviction;
+pub mod compression;
+pub mod cors;
 pub mod format;
+pub mod hedge;
+pub mod http;
+pub mod listener;
+pub mod logs_shards;
+pub mod memory_cache;
+pub mod method_filter;
+pub mod method_routing;
 pub mod processing;
+pub mod rate_limit;
 mod response_errors;
 pub mod selection;
+pub mod shutdown;
+pub mod single_flight;
+pub mod sticky;
+pub mod tls;
+pub mod tx_broadcast;
+pub mod tx_replay;
+pub mod validation;
+pub mod warmup;
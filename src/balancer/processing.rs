@@ -1,38 +1,239 @@
 use crate::{
+    balancer::selection::select::pick_with,
     balancer::{
-        format::get_block_number_from_request,
+        cache_eviction::CacheRecency,
+        format::{
+            get_block_number_from_request,
+            BlockParams,
+        },
+        hedge::is_idempotent,
+        memory_cache::MemoryCache,
+        method_filter::MethodFilter,
         selection::cache_rules::{
             cache_method,
             cache_result,
+            finalized_only,
+            MethodCache,
+        },
+    },
+    config::types::SelectionStrategy,
+    health::{
+        head_cache::HeadCache,
+        safe_block::NamedBlocknumbers,
+        webhook::{
+            HealthEvent,
+            HealthWebhook,
         },
     },
-    health::safe_block::NamedBlocknumbers,
+    rpc::{
+        error::RpcError,
+        types::hex_to_decimal,
+    },
+    warn_limited,
     Rpc,
 };
 
+use tracing::{
+    error,
+    info,
+    trace,
+    warn,
+};
+
 use std::{
     collections::BTreeMap,
-    println,
     sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
         Arc,
         RwLock,
     },
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
 };
 
-use tokio::sync::watch;
+use tokio::{
+    sync::watch,
+    time::timeout,
+};
 
 use blake3::Hash;
-use serde_json::Value;
+use futures::stream::{
+    self,
+    StreamExt,
+};
+use serde_json::{
+    json,
+    Value,
+};
 use simd_json::to_vec;
-use sled::Db;
+use sled::Tree;
+
+// Build a JSON-RPC error response to the request with `id`.
+//
+// `data` explains why the request failed, and is left out if `None`.
+pub fn jsonrpc_error(id: &Value, code: i64, message: &str, data: Option<&str>) -> Value {
+    let mut error = json!({
+        "code": code,
+        "message": message,
+    });
+    if let Some(data) = data {
+        error["data"] = data.into();
+    }
+
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": error,
+    })
+}
+
+// Error object for requests that failed upstream, once we're out of retries
+pub fn upstream_error_object(err: &RpcError) -> Value {
+    json!({
+        "code": -32007,
+        "message": format!("error: {}", err),
+    })
+}
+
+// Version of the format of cached entries.
+//
+// Entries from other versions live under their own key namespace,
+// so they're never mistaken for entries in the current format.
+//
+// 1: requests are keyed by method and canonicalized params, without their id
+// 2: `eth_call` objects are normalized before they're keyed
+pub const CACHE_SCHEMA_VERSION: u8 = 2;
+
+// Oldest schema version whose entries are keyed the way requests are hashed now.
+//
+// Entries of older versions can never be found from a request, so they can't be
+// migrated either. They get cleared on startup instead.
+pub const REKEYED_SCHEMA_VERSION: u8 = 2;
+
+// Length of request hashes, the last part of the key of cached entries
+#[cfg(not(feature = "xxhash"))]
+pub const TX_HASH_LEN: usize = 32;
+#[cfg(feature = "xxhash")]
+pub const TX_HASH_LEN: usize = 8;
+
+// Prefix of the keys of entries cached under a configured `cache_version`,
+// followed by the cache version and the schema version.
+pub const CACHE_VERSION_PREFIX: &[u8] = b"cache/";
+
+// Key of the entry for `tx_hash` under the configured `cache_version` and a schema `version`.
+//
+// Version 0 keys are just the hash, so caches from before versioning stay valid.
+// Bumping `cache_version` moves every entry to a new namespace, which invalidates
+// the whole cache without clearing it.
+pub fn versioned_key(cache_version: u8, version: u8, tx_hash: &[u8]) -> Vec<u8> {
+    match (cache_version, version) {
+        (0, 0) => tx_hash.to_vec(),
+        (0, _) => [&[version], tx_hash].concat(),
+        _ => [CACHE_VERSION_PREFIX, &[cache_version, version], tx_hash].concat(),
+    }
+}
+
+// Prefix of the keys holding when entries of methods with a TTL expire.
+//
+// The expiry is stored as big endian unix time in ms, under this prefix
+// followed by the key of the entry.
+pub const EXPIRY_PREFIX: &[u8] = b"expiry/";
+
+pub fn expiry_key(key: &[u8]) -> Vec<u8> {
+    [EXPIRY_PREFIX, key].concat()
+}
+
+// Prefix of the keys holding cached JSON-RPC errors, followed by the key of the entry.
+//
+// Errors are kept apart from responses so they never outlive their expiry,
+// and can be purged without touching anything else.
+pub const ERROR_PREFIX: &[u8] = b"error/";
+
+pub fn error_key(key: &[u8]) -> Vec<u8> {
+    [ERROR_PREFIX, key].concat()
+}
+
+// Prefix of the index of cached entries by method, so they can be purged by method.
+//
+// Every cached entry gets an empty index entry under this prefix, followed by the
+// method, a null byte and the key of the entry. That's an extra write and key per
+// cached entry. Whatever removes an entry removes its index entry with it.
+pub const METHOD_PREFIX: &[u8] = b"method/";
+
+pub fn method_index_key(method: &str, key: &[u8]) -> Vec<u8> {
+    [METHOD_PREFIX, method.as_bytes(), b"\0", key].concat()
+}
+
+// Methods with entries in the method index of `cache`.
+//
+// Skips over the index entries of each method, so this only reads a key per method.
+pub fn indexed_methods(cache: &Tree) -> Result<Vec<String>, sled::Error> {
+    let mut methods = Vec::new();
+    let mut from = METHOD_PREFIX.to_vec();
+
+    while let Some(entry) = cache.range(from.as_slice()..).next() {
+        let (index_key, _) = entry?;
+        let Some(method) = index_key
+            .strip_prefix(METHOD_PREFIX)
+            .and_then(|rest| rest.split(|byte| *byte == 0).next())
+        else {
+            break;
+        };
+
+        methods.push(String::from_utf8_lossy(method).into_owned());
+        // Index keys of a method all sort before its name followed by 0x01
+        from = [METHOD_PREFIX, method, b"\x01"].concat();
+    }
+
+    Ok(methods)
+}
+
+// Remove the index entry of `key`, whichever of `methods` it's cached under
+pub fn remove_method_index(batch: &mut sled::Batch, methods: &[String], key: &[u8]) {
+    for method in methods {
+        batch.remove(method_index_key(method, key));
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
 
 #[derive(Clone)]
 pub struct CacheArgs {
     pub finalized_rx: watch::Receiver<u64>,
     pub named_numbers: Arc<RwLock<NamedBlocknumbers>>,
-    pub cache: Arc<Db>,
-    pub head_cache: Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    pub cache: Arc<Tree>,
+    pub head_cache: Arc<HeadCache>,
+    // Cache responses for unfinalized blocks in `head_cache` until they finalize.
+    // When disabled, only responses for finalized blocks get cached.
+    pub head_cache_enabled: bool,
+    pub block_params: Arc<BlockParams>,
+    pub cache_state: Arc<CacheWriteState>,
+    pub reorg_window: Arc<ReorgWindow>,
+    pub schema_version: u8,
+    // Namespace set by `cache_version`, entries of other namespaces are never served
+    pub cache_version: u8,
+    // Move finalized entries from older schema versions to the current one on read
+    pub migrate_cache: bool,
+    // Keep responses of methods with a TTL after they expire so they can be served
+    // stale, instead of removing them when they're looked up. Expired errors always go.
+    pub keep_expired: bool,
+    pub method_cache: Arc<MethodCache>,
+    pub method_filter: Arc<MethodFilter>,
+    // LRU of hot entries in front of `cache`
+    pub memory_cache: Arc<MemoryCache>,
+    // Fields to strip from responses before they're returned or cached
+    pub response_transform: Arc<ResponseTransform>,
 }
 
 impl CacheArgs {
@@ -41,10 +242,339 @@ impl CacheArgs {
         CacheArgs {
             finalized_rx: watch::channel(0).1,
             named_numbers: Arc::new(RwLock::new(NamedBlocknumbers::default())),
-            cache: Arc::new(sled::Config::default().open().unwrap()),
+            cache: Arc::new(Tree::clone(
+                &sled::Config::new().temporary(true).open().unwrap(),
+            )),
             head_cache: Arc::new(RwLock::new(BTreeMap::new())),
+            head_cache_enabled: true,
+            block_params: Arc::new(BlockParams::default()),
+            cache_state: Arc::new(CacheWriteState::default()),
+            reorg_window: Arc::new(ReorgWindow::default()),
+            schema_version: CACHE_SCHEMA_VERSION,
+            cache_version: 0,
+            migrate_cache: false,
+            keep_expired: false,
+            method_cache: Arc::new(MethodCache::default()),
+            method_filter: Arc::new(MethodFilter::default()),
+            memory_cache: Arc::new(MemoryCache::default()),
+            response_transform: Arc::new(ResponseTransform::default()),
+        }
+    }
+
+    // Temporary cache for tests
+    #[cfg(test)]
+    pub fn temporary_cache() -> Arc<Tree> {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Arc::new(Tree::clone(&db))
+    }
+
+    // Key of the entry for `tx_hash` in the current schema version
+    pub fn key(&self, tx_hash: &[u8]) -> Vec<u8> {
+        versioned_key(self.cache_version, self.schema_version, tx_hash)
+    }
+
+    // Returns true if `tx` is for a finalized block
+    fn is_finalized(&self, tx: &Value) -> bool {
+        match get_block_number_from_request(tx.clone(), &self.named_numbers, &self.block_params) {
+            Some(num) => num <= *self.finalized_rx.borrow(),
+            None => false,
+        }
+    }
+
+    // Returns true if `tx` is for an unfinalized block and we're shortly after a reorg,
+    // meaning that whatever we have cached for it might be about to get invalidated.
+    fn in_reorg_window(&self, tx: &Value) -> bool {
+        if !self.reorg_window.is_active() {
+            return false;
+        }
+
+        match get_block_number_from_request(tx.clone(), &self.named_numbers, &self.block_params) {
+            Some(num) => num > *self.finalized_rx.borrow(),
+            None => false,
+        }
+    }
+}
+
+// Strips fields set with `strip_response_fields` from responses.
+//
+// Providers like to add their own fields to responses. Removing them before a
+// response gets cached means it's the same no matter which RPC served it.
+//
+// Paths are dot separated keys starting at the root of the response, like
+// `result.l1Fee`. A `*` matches every element of an array or object.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResponseTransform {
+    paths: Vec<Vec<String>>,
+}
+
+impl ResponseTransform {
+    // Returns the first invalid path as the error
+    pub fn new(paths: &[String]) -> Result<Self, String> {
+        let paths = paths
+            .iter()
+            .map(|path| {
+                let segments: Vec<String> = path
+                    .strip_prefix("$.")
+                    .unwrap_or(path)
+                    .split('.')
+                    .map(str::to_string)
+                    .collect();
+
+                match segments.iter().any(String::is_empty) {
+                    true => Err(path.clone()),
+                    false => Ok(segments),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(ResponseTransform { paths })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub fn strip(&self, rx: &mut Value) {
+        for path in &self.paths {
+            strip_path(rx, path);
         }
     }
+
+    // Same as `strip`, for responses we haven't parsed.
+    //
+    // Responses that aren't JSON are returned as is.
+    pub fn apply(&self, rx: String) -> String {
+        if self.is_empty() {
+            return rx;
+        }
+
+        match serde_json::from_str::<Value>(&rx) {
+            Ok(mut rx) => {
+                self.strip(&mut rx);
+                rx.to_string()
+            }
+            Err(_) => rx,
+        }
+    }
+}
+
+fn strip_path(value: &mut Value, path: &[String]) {
+    let (segment, rest) = match path {
+        [] => return,
+        [segment, rest @ ..] => (segment, rest),
+    };
+
+    if rest.is_empty() {
+        match value {
+            Value::Object(object) if segment == "*" => object.clear(),
+            Value::Object(object) => {
+                object.remove(segment);
+            }
+            Value::Array(array) if segment == "*" => array.clear(),
+            _ => {}
+        }
+        return;
+    }
+
+    match value {
+        Value::Object(object) if segment == "*" => {
+            object
+                .values_mut()
+                .for_each(|value| strip_path(value, rest))
+        }
+        Value::Object(object) => {
+            if let Some(value) = object.get_mut(segment) {
+                strip_path(value, rest);
+            }
+        }
+        Value::Array(array) if segment == "*" => {
+            array.iter_mut().for_each(|value| strip_path(value, rest))
+        }
+        _ => {}
+    }
+}
+
+// Tracks the time of the last reorg.
+//
+// For `window` after a reorg, reads and writes for unfinalized blocks
+// skip the cache and go straight to the RPCs.
+#[derive(Debug, Default)]
+pub struct ReorgWindow {
+    window: Duration,
+    reorg_at: RwLock<Option<Instant>>,
+}
+
+impl ReorgWindow {
+    pub fn new(window: Duration) -> Self {
+        ReorgWindow {
+            window,
+            reorg_at: RwLock::new(None),
+        }
+    }
+
+    // Start the window, should be called when a reorg is detected
+    pub fn start(&self) {
+        if !self.window.is_zero() {
+            *self.reorg_at.write().unwrap() = Some(Instant::now());
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        match *self.reorg_at.read().unwrap() {
+            Some(reorg_at) => reorg_at.elapsed() < self.window,
+            None => false,
+        }
+    }
+}
+
+// How long to wait before trying to write to a full cache again
+const READ_ONLY_RETRY: Duration = Duration::from_secs(60);
+
+// Tracks if we can write to the cache.
+//
+// If the disk fills up, we stop writing to the cache and keep serving
+// from what's already cached and from the RPCs. Every `READ_ONLY_RETRY`
+// we let a write through to check if space was freed. Going read-only and
+// back is reported to the health webhook.
+#[derive(Debug, Default)]
+pub struct CacheWriteState {
+    read_only_since: RwLock<Option<Instant>>,
+    webhook: HealthWebhook,
+    // Head cache writes skipped because the entry was unchanged
+    deduped_head_writes: AtomicU64,
+    // Which entries got used lately, if the cache is capped by `cache_max_bytes`
+    recency: Arc<CacheRecency>,
+}
+
+impl CacheWriteState {
+    // Also track which entries get used if `track_recency`, so the least
+    // recently used ones can be evicted when the cache grows too big
+    pub fn new(track_recency: bool, webhook: HealthWebhook) -> Self {
+        CacheWriteState {
+            webhook,
+            recency: Arc::new(match track_recency {
+                true => CacheRecency::new(),
+                false => CacheRecency::default(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    pub fn recency(&self) -> &Arc<CacheRecency> {
+        &self.recency
+    }
+
+    pub fn deduped_head_writes(&self) -> u64 {
+        self.deduped_head_writes.load(Ordering::Relaxed)
+    }
+
+    pub fn count_deduped_head_write(&self) {
+        self.deduped_head_writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only_since.read().unwrap().is_some()
+    }
+
+    pub fn can_write(&self) -> bool {
+        match *self.read_only_since.read().unwrap() {
+            Some(since) => since.elapsed() >= READ_ONLY_RETRY,
+            None => true,
+        }
+    }
+
+    // Handle the result of a cache write, switching to read-only mode
+    // if we ran out of space and back if writes succeed again.
+    pub fn handle_write<T>(&self, result: Result<T, sled::Error>) {
+        match result {
+            Ok(_) => {
+                let mut read_only_since = self.read_only_since.write().unwrap();
+                if read_only_since.is_some() {
+                    info!("Cache writes succeeding again, leaving read-only mode.");
+                    *read_only_since = None;
+                    self.webhook.notify_cache(HealthEvent::LeftReadOnly);
+                }
+            }
+            Err(err) if is_out_of_space(&err) => {
+                error!("!!! Out of space while writing to the cache: {} !!!", err);
+                error!("!!! Cache is now READ-ONLY until space is freed. Still serving from the cache and RPCs. !!!");
+                // Retries that fail again don't count as entering it
+                let entered = self
+                    .read_only_since
+                    .write()
+                    .unwrap()
+                    .replace(Instant::now())
+                    .is_none();
+                if entered {
+                    self.webhook.notify_cache(HealthEvent::EnteredReadOnly);
+                }
+            }
+            Err(err) => {
+                warn!("Failed to write to the cache: {}", err);
+            }
+        }
+    }
+}
+
+fn is_out_of_space(err: &sled::Error) -> bool {
+    // ENOSPC
+    const OUT_OF_SPACE: i32 = 28;
+
+    match err {
+        sled::Error::Io(err) => err.raw_os_error() == Some(OUT_OF_SPACE),
+        _ => false,
+    }
+}
+
+// Check that `tx` is a JSON-RPC request we can forward, returning why if it isn't.
+//
+// Unless `strict`, requests with a missing or wrong `jsonrpc` version are let through,
+// since plenty of clients get it wrong. Their version gets set to 2.0, since some
+// RPCs reject requests without it.
+pub fn validate_request(tx: &mut Value, strict: bool) -> Result<(), &'static str> {
+    if !tx.is_object() {
+        return Err("Request must be an object");
+    }
+
+    if tx["jsonrpc"] != "2.0" {
+        if strict {
+            return Err("Unsupported jsonrpc version");
+        }
+        tx["jsonrpc"] = "2.0".into();
+    }
+
+    if !tx["method"].is_string() {
+        return Err("Missing method");
+    }
+
+    Ok(())
+}
+
+// Returns true if `tx` reads the mempool, or pending state through a `pending` block tag.
+//
+// The responses change with every transaction that comes in, so these are never
+// cached or served from the cache, whatever `method_cache` says.
+pub fn is_pending(tx: &Value) -> bool {
+    let method = tx["method"].as_str().unwrap_or_default();
+    if method.starts_with("txpool_") || method == "eth_pendingTransactions" {
+        return true;
+    }
+
+    let is_pending_tag = |param: &Value| {
+        param
+            .as_str()
+            .is_some_and(|tag| tag.eq_ignore_ascii_case("pending"))
+    };
+
+    // Block tags can be positional, or in objects like `eth_getLogs` filters and EIP-1898 params
+    tx["params"].as_array().is_some_and(|params| {
+        params.iter().any(|param| {
+            is_pending_tag(param)
+                || ["blockNumber", "blockTag", "fromBlock", "toBlock"]
+                    .iter()
+                    .any(|field| is_pending_tag(&param[field]))
+        })
+    })
 }
 
 // TODO: we should find a way to check values directly and not convert Value to str
@@ -57,31 +587,388 @@ pub fn can_cache(method: &str, result: &str) -> bool {
 
 // Check if we should cache the querry, and if so cache it in the DB
 pub fn cache_querry(rx: &mut str, method: Value, tx_hash: Hash, cache_args: &CacheArgs) {
+    let method_name = &method["method"].as_str().unwrap_or_default().to_string();
+    if !cache_args.method_cache.is_cacheable(method_name) || is_pending(&method) {
+        return;
+    }
+
+    // Cache well-formed errors for a short while, if enabled
+    if let Some(ttl) = cache_args.method_cache.error_ttl(method_name) {
+        if is_jsonrpc_error(rx) && cache_method(&method.to_string()) {
+            let key = error_key(&cache_args.key(tx_hash.as_bytes()));
+            cache_with_expiry(rx, method_name, key, ttl, cache_args);
+            return;
+        }
+    }
+
+    // Methods with a TTL are cached until they expire, whatever block they're for
+    if let Some(ttl) = cache_args.method_cache.ttl(method_name) {
+        if cache_result(rx) {
+            let key = cache_args.key(tx_hash.as_bytes());
+            cache_with_expiry(rx, method_name, key, ttl, cache_args);
+        }
+        return;
+    }
+
     let tx_string = method.to_string();
+    let finalized_only = method["method"]
+        .as_str()
+        .map(finalized_only)
+        .unwrap_or(false);
 
     if can_cache(&tx_string, rx) {
         // Insert the response hash into the head_cache
-        let num = get_block_number_from_request(method, &cache_args.named_numbers);
+        let num = get_block_number_from_request(
+            method,
+            &cache_args.named_numbers,
+            &cache_args.block_params,
+        );
 
-        // Insert the key of the request we made into our `head_cache`
-        // so we can invalidate it and remove it from the DB if it reorgs.
+        // Responses for unfinalized blocks go into our `head_cache` instead
+        // of the DB, so we can drop them if they reorg.
         if let Some(num) = num {
-            if num > *cache_args.finalized_rx.borrow() {
+            // Never cache requests for blocks above the head, the response is
+            // whatever the node we routed to felt like returning.
+            let latest = cache_args.named_numbers.read().unwrap().latest;
+            if latest != 0 && num > latest {
+                return;
+            }
+
+            // Don't cache tip adjacent responses right after a reorg
+            if num > *cache_args.finalized_rx.borrow() && cache_args.reorg_window.is_active() {
+                return;
+            }
+
+            let is_head = num > *cache_args.finalized_rx.borrow();
+            if is_head && finalized_only {
+                return;
+            }
+
+            // Nothing to cache if the RPC didn't respond with JSON
+            let rx_bytes = match without_id(rx) {
+                Some(rx_bytes) => rx_bytes,
+                None => return,
+            };
+
+            let key = cache_args.key(tx_hash.as_bytes());
+
+            // Keep it out of sled until the block finalizes, `manage_cache` moves it there then
+            if is_head {
+                if !cache_args.head_cache_enabled {
+                    return;
+                }
+
                 let mut head_cache = cache_args.head_cache.write().unwrap();
-                head_cache.entry(num).or_default().push(tx_hash.to_string());
+                let entries = head_cache.entry(num).or_default();
+
+                // Don't rewrite the entry if we already have the same response for this block
+                if matches!(entries.get(&key), Some((_, cached)) if cached == rx_bytes.as_slice()) {
+                    cache_args.cache_state.count_deduped_head_write();
+                    return;
+                }
+
+                let rx_bytes = sled::IVec::from(rx_bytes);
+                entries.insert(key.clone(), (method_name.to_string(), rx_bytes.clone()));
+                cache_args.memory_cache.insert(&key, rx_bytes, None);
+                return;
             }
 
-            // Replace the id with Value::Null and insert the request
-            // TODO: kinda cringe how we do this gymnasctics of changing things back and forth
-            let mut rx_value: Value = unsafe { simd_json::serde::from_str(rx).unwrap() };
-            rx_value["id"] = Value::Null;
+            if !cache_args.cache_state.can_write() {
+                return;
+            }
 
+            let mut batch = sled::Batch::default();
+            batch.insert(method_index_key(method_name, &key), &[]);
+            batch.insert(key.as_slice(), rx_bytes.as_slice());
             cache_args
-                .cache
-                .insert(tx_hash.as_bytes(), to_vec(&rx_value).unwrap().as_slice())
-                .unwrap();
+                .cache_state
+                .handle_write(cache_args.cache.apply_batch(batch));
+            cache_args.cache_state.recency.touch(&key);
+            cache_args.memory_cache.insert(&key, rx_bytes.into(), None);
+        }
+    }
+}
+
+// Replace the id with Value::Null so the response can be served for any request
+// TODO: kinda cringe how we do this gymnasctics of changing things back and forth
+//
+// Returns `None` if `rx` isn't a JSON object.
+fn without_id(rx: &mut str) -> Option<Vec<u8>> {
+    let mut rx_value: Value = unsafe { simd_json::serde::from_str(rx).ok()? };
+    if !rx_value.is_object() {
+        return None;
+    }
+    rx_value["id"] = Value::Null;
+    to_vec(&rx_value).ok()
+}
+
+// Returns true if `rx` is a JSON-RPC response with an error object
+fn is_jsonrpc_error(rx: &str) -> bool {
+    match serde_json::from_str::<Value>(rx) {
+        Ok(rx) => rx.get("result").is_none() && rx["error"]["code"].is_i64(),
+        Err(_) => false,
+    }
+}
+
+// Cache a response at `key` along with when it expires
+fn cache_with_expiry(
+    rx: &mut str,
+    method: &str,
+    key: Vec<u8>,
+    ttl: Duration,
+    cache_args: &CacheArgs,
+) {
+    if !cache_args.cache_state.can_write() {
+        return;
+    }
+
+    let expires_at = unix_millis().saturating_add(ttl.as_millis() as u64);
+
+    let rx_bytes = match without_id(rx) {
+        Some(rx_bytes) => sled::IVec::from(rx_bytes),
+        None => return,
+    };
+
+    let mut batch = sled::Batch::default();
+    batch.insert(expiry_key(&key), &expires_at.to_be_bytes());
+    batch.insert(method_index_key(method, &key), &[]);
+    batch.insert(key.as_slice(), rx_bytes.clone());
+    cache_args
+        .cache_state
+        .handle_write(cache_args.cache.apply_batch(batch));
+    cache_args.cache_state.recency.touch(&key);
+    cache_args
+        .memory_cache
+        .insert(&key, rx_bytes, Some(expires_at));
+}
+
+// Get the cached response for `tx`, if any.
+//
+// Looks in the in-memory LRU first, then in sled, and keeps entries found in
+// sled in the LRU. Returns `None` for unfinalized blocks while we're in a
+// reorg window, and for responses of methods with a TTL that expired.
+// Falls back to cached errors if there's no response and they're enabled.
+pub fn cache_lookup(
+    tx: &Value,
+    tx_hash: &[u8],
+    cache_args: &CacheArgs,
+) -> Result<Option<sled::IVec>, sled::Error> {
+    let method = tx["method"].as_str().unwrap_or_default();
+    if !cache_args.method_cache.is_cacheable(method) || is_pending(tx) {
+        return Ok(None);
+    }
+
+    if cache_args.in_reorg_window(tx) {
+        return Ok(None);
+    }
+
+    let key = cache_args.key(tx_hash);
+
+    if cache_args.memory_cache.is_enabled() {
+        if let Some(rax) = cache_args.memory_cache.get(&key, unix_millis()) {
+            return Ok(Some(rax));
+        }
+    }
+
+    // Responses for unfinalized blocks wait in the head cache until they finalize
+    if cache_args.head_cache_enabled {
+        let unfinalized = cache_args
+            .head_cache
+            .read()
+            .unwrap()
+            .values()
+            .find_map(|entries| entries.get(&key).map(|(_, rax)| rax.clone()));
+        if unfinalized.is_some() {
+            return Ok(unfinalized);
+        }
+    }
+
+    let rax = match cache_args.method_cache.ttl(method) {
+        Some(_) => get_unexpired(&key, method, !cache_args.keep_expired, cache_args)?,
+        None => {
+            let rax = match cache_args.cache.get(&key)? {
+                Some(rax) => Some(rax),
+                None if cache_args.migrate_cache => migrate_entry(tx, tx_hash, &key, cache_args)?,
+                None => None,
+            };
+            if let Some(rax) = &rax {
+                cache_args.memory_cache.insert(&key, rax.clone(), None);
+            }
+            rax
+        }
+    };
+
+    if rax.is_none() && cache_args.method_cache.error_ttl(method).is_some() {
+        let key = error_key(&key);
+        if cache_args.memory_cache.is_enabled() {
+            if let Some(rax) = cache_args.memory_cache.get(&key, unix_millis()) {
+                return Ok(Some(rax));
+            }
+        }
+        return get_unexpired(&key, method, true, cache_args);
+    }
+
+    Ok(rax)
+}
+
+// `cache_lookup`, but a cache we can't read from is just a miss.
+//
+// The request still gets served by an RPC, so a broken DB doesn't take us down.
+pub fn cache_lookup_or_miss(
+    tx: &Value,
+    tx_hash: &[u8],
+    cache_args: &CacheArgs,
+) -> Option<sled::IVec> {
+    match cache_lookup(tx, tx_hash, cache_args) {
+        Ok(Some(rax)) => {
+            cache_args
+                .cache_state
+                .recency
+                .touch(&cache_args.key(tx_hash));
+            Some(rax)
+        }
+        Ok(None) => None,
+        Err(err) => {
+            warn_limited!(
+                "Could not read from the cache, forwarding to an RPC: {}",
+                err
+            );
+            None
+        }
+    }
+}
+
+// Get the last cached response for `tx`, even if it expired.
+//
+// Used when every RPC failed the request and `serve_stale_on_error` is enabled.
+// Requests that change state or are for pending blocks never get stale responses.
+pub fn cache_lookup_stale(
+    tx: &Value,
+    tx_hash: &[u8],
+    cache_args: &CacheArgs,
+) -> Result<Option<sled::IVec>, sled::Error> {
+    let method = tx["method"].as_str().unwrap_or_default();
+    if !cache_args.method_cache.is_cacheable(method) || !is_idempotent(method) || is_pending(tx) {
+        return Ok(None);
+    }
+
+    cache_args.cache.get(cache_args.key(tx_hash))
+}
+
+// Get the entry at `key` if it hasn't expired yet, keeping it in the LRU.
+//
+// Expired entries of `method` get removed along with their expiry and index
+// entries if `remove_expired` is set, so they don't stick around until evicted.
+fn get_unexpired(
+    key: &[u8],
+    method: &str,
+    remove_expired: bool,
+    cache_args: &CacheArgs,
+) -> Result<Option<sled::IVec>, sled::Error> {
+    let expires_at = cache_args
+        .cache
+        .get(expiry_key(key))?
+        .and_then(|expires_at| expires_at.as_ref().try_into().ok())
+        .map(u64::from_be_bytes);
+
+    match expires_at {
+        Some(expires_at) if expires_at > unix_millis() => {
+            let rax = cache_args.cache.get(key)?;
+            if let Some(rax) = &rax {
+                cache_args
+                    .memory_cache
+                    .insert(key, rax.clone(), Some(expires_at));
+            }
+            Ok(rax)
+        }
+        Some(_) if remove_expired && cache_args.cache_state.can_write() => {
+            let mut batch = sled::Batch::default();
+            batch.remove(expiry_key(key));
+            batch.remove(method_index_key(method, key));
+            batch.remove(key);
+            cache_args
+                .cache_state
+                .handle_write(cache_args.cache.apply_batch(batch));
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+// Remove every cached response and error of `method`, returning how many were removed.
+//
+// Only reaches entries written since the method index was added. Entries in the
+// in-memory LRU aren't removed, so callers should clear it as well.
+pub fn purge_method(cache: &Tree, method: &str) -> Result<usize, sled::Error> {
+    let prefix = method_index_key(method, b"");
+    let mut batch = sled::Batch::default();
+    let mut purged = 0;
+
+    for entry in cache.scan_prefix(&prefix) {
+        let (index_key, _) = entry?;
+        let key = &index_key[prefix.len()..];
+        if cache.contains_key(key)? {
+            purged += 1;
         }
+        batch.remove(expiry_key(key));
+        batch.remove(key);
+        batch.remove(index_key);
     }
+
+    cache.apply_batch(batch)?;
+    Ok(purged)
+}
+
+// Remove every cached error, returning how many were removed.
+//
+// Entries in the in-memory LRU aren't removed, so callers should clear it as well.
+pub fn purge_errors(cache: &Tree) -> Result<usize, sled::Error> {
+    let methods = indexed_methods(cache)?;
+    let mut batch = sled::Batch::default();
+    let mut purged = 0;
+
+    for entry in cache.scan_prefix(ERROR_PREFIX) {
+        let (key, _) = entry?;
+        remove_method_index(&mut batch, &methods, &key);
+        batch.remove(expiry_key(&key));
+        batch.remove(key);
+        purged += 1;
+    }
+
+    cache.apply_batch(batch)?;
+    Ok(purged)
+}
+
+// Look for `tx` in older schema versions and move it to `key` if found.
+//
+// Only finalized entries get migrated, everything else is a miss. Entries
+// from other cache versions are never migrated.
+fn migrate_entry(
+    tx: &Value,
+    tx_hash: &[u8],
+    key: &[u8],
+    cache_args: &CacheArgs,
+) -> Result<Option<sled::IVec>, sled::Error> {
+    if !cache_args.is_finalized(tx) {
+        return Ok(None);
+    }
+
+    for version in (0..cache_args.schema_version).rev() {
+        let old_key = versioned_key(cache_args.cache_version, version, tx_hash);
+        if let Some(rax) = cache_args.cache.get(&old_key)? {
+            if cache_args.cache_state.can_write() {
+                let mut batch = sled::Batch::default();
+                batch.insert(key, rax.clone());
+                batch.remove(old_key);
+                cache_args
+                    .cache_state
+                    .handle_write(cache_args.cache.apply_batch(batch));
+            }
+            return Ok(Some(rax));
+        }
+    }
+
+    Ok(None)
 }
 
 pub fn update_rpc_latency(rpc_list: &Arc<RwLock<Vec<Rpc>>>, rpc_position: usize, time: Duration) {
@@ -90,22 +977,207 @@ pub fn update_rpc_latency(rpc_list: &Arc<RwLock<Vec<Rpc>>>, rpc_position: usize,
         e.into_inner()
     });
 
-    // Handle weird edge cases ¯\_(ツ)_/¯
-    if !rpc_list_guard.is_empty() {
-        let index = if rpc_position >= rpc_list_guard.len() {
-            rpc_list_guard.len() - 1
-        } else {
-            rpc_position
-        };
-        rpc_list_guard[index].update_latency(time.as_nanos() as f64);
-        rpc_list_guard[index].last_used = time.as_micros();
-        println!("LA {}", rpc_list_guard[index].status.latency);
+    // Handle weird edge cases ¯\_(ツ)_/¯
+    if !rpc_list_guard.is_empty() {
+        let index = if rpc_position >= rpc_list_guard.len() {
+            rpc_list_guard.len() - 1
+        } else {
+            rpc_position
+        };
+        rpc_list_guard[index].update_latency(time.as_nanos() as f64);
+        rpc_list_guard[index].last_used = time.as_micros();
+        trace!("Latency: {}", rpc_list_guard[index].status.latency);
+    }
+}
+
+// Record that an RPC just responded with an error
+pub fn mark_rpc_error(rpc_list: &Arc<RwLock<Vec<Rpc>>>, rpc_position: usize) {
+    let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| e.into_inner());
+
+    if let Some(rpc) = rpc_list_guard.get_mut(rpc_position) {
+        rpc.count_error();
+        rpc.status.last_error = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+    }
+}
+
+// Update the moving average of the response time of an RPC, used by `least_latency`
+pub fn update_rpc_latency_ema(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    rpc_position: usize,
+    time: Duration,
+    ema_alpha: f64,
+) {
+    let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| e.into_inner());
+
+    if let Some(rpc) = rpc_list_guard.get_mut(rpc_position) {
+        rpc.update_latency_ema(time.as_secs_f64() * 1000.0, ema_alpha);
+    }
+}
+
+// How many chunks of a split `eth_getLogs` request are fetched at once
+const LOGS_CHUNK_CONCURRENCY: usize = 8;
+
+// Parts of error messages providers use to reject `eth_getLogs` ranges that are too large
+const LOGS_RANGE_TOO_LARGE: [&str; 6] = [
+    "block range",
+    "range too large",
+    "range is too large",
+    "more than",
+    "exceed",
+    "limited to",
+];
+
+#[derive(Debug)]
+pub enum LogsChunkError {
+    // The RPC wants a smaller range
+    TooLarge(Value),
+    // The RPC responded with an error we can't do anything about
+    Failed(Value),
+    TimedOut,
+}
+
+// Returns the block range of an `eth_getLogs` request if it spans more than
+// `max_range` blocks, so it should be split up. 0 never splits.
+pub fn logs_range_to_split(tx: &Value, max_range: u64) -> Option<(u64, u64)> {
+    if max_range == 0 || tx["method"] != "eth_getLogs" {
+        return None;
+    }
+
+    let filter = tx["params"].get(0)?.as_object()?;
+    let from_block = hex_to_decimal(filter.get("fromBlock")?.as_str()?).ok()?;
+    let to_block = hex_to_decimal(filter.get("toBlock")?.as_str()?).ok()?;
+
+    (to_block >= from_block && to_block - from_block >= max_range).then_some((from_block, to_block))
+}
+
+// Fetch the logs of `tx` over a single chunk of blocks
+pub async fn fetch_logs_chunk(
+    tx: &Value,
+    (from_block, to_block): (u64, u64),
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    strategy: SelectionStrategy,
+    ttl: u128,
+) -> Result<Vec<Value>, LogsChunkError> {
+    let mut tx = tx.clone();
+    tx["params"][0]["fromBlock"] = format!("{:#x}", from_block).into();
+    tx["params"][0]["toBlock"] = format!("{:#x}", to_block).into();
+
+    let (rpc, rpc_position) = {
+        let mut rpc_list = rpc_list.write().unwrap();
+        pick_with(&mut rpc_list, strategy)
+    };
+    let rpc_position = rpc_position.ok_or(LogsChunkError::TimedOut)?;
+    let ttl = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+
+    // Wait for the RPC to drop below `max_concurrency`, like any other request
+    let _capacity = timeout(ttl, rpc.acquire_capacity())
+        .await
+        .map_err(|_| LogsChunkError::TimedOut)?;
+
+    let _in_flight = rpc.track_in_flight();
+    rpc.count_request();
+
+    let rx = match timeout(ttl, rpc.send_request(tx)).await {
+        Ok(Ok(rx)) => rx,
+        Ok(Err(_)) => {
+            mark_rpc_error(rpc_list, rpc_position);
+            return Err(LogsChunkError::TimedOut);
+        }
+        Err(_) => {
+            rpc.count_error();
+            return Err(LogsChunkError::TimedOut);
+        }
+    };
+
+    let mut rx: Value = serde_json::from_str(&rx).map_err(|_| LogsChunkError::TimedOut)?;
+    if let Value::Array(logs) = rx["result"].take() {
+        return Ok(logs);
+    }
+
+    let message = rx["error"]["message"]
+        .as_str()
+        .unwrap_or_default()
+        .to_lowercase();
+    match LOGS_RANGE_TOO_LARGE
+        .iter()
+        .any(|pattern| message.contains(pattern))
+    {
+        true => Err(LogsChunkError::TooLarge(rx)),
+        false => Err(LogsChunkError::Failed(rx)),
+    }
+}
+
+// Number of chunks of `max_range` blocks `from_block..=to_block` gets split into
+pub fn logs_chunk_count(from_block: u64, to_block: u64, max_range: u64) -> u64 {
+    (to_block - from_block) / max_range + 1
+}
+
+// Get the logs of an `eth_getLogs` request over `from_block..=to_block` by splitting
+// the range into chunks of `max_range` blocks, fetched across RPCs with up to
+// `LOGS_CHUNK_CONCURRENCY` in flight at once.
+//
+// Chunks an RPC rejects as too large get halved and retried, as long as we stay
+// within `max_chunks` fetched chunks (0 is unbounded). Chunks never overlap,
+// so the logs just get concatenated in block order.
+//
+// Returns the error response of the RPC if a chunk fails, or `None` if it timed out.
+#[allow(clippy::too_many_arguments)]
+pub async fn split_logs(
+    tx: &Value,
+    from_block: u64,
+    to_block: u64,
+    max_range: u64,
+    max_chunks: u64,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    strategy: SelectionStrategy,
+    ttl: u128,
+) -> Result<Vec<Value>, Option<Value>> {
+    let mut pending: Vec<(u64, u64)> = (from_block..=to_block)
+        .step_by(max_range as usize)
+        .map(|start| (start, start.saturating_add(max_range - 1).min(to_block)))
+        .collect();
+    let mut chunks = pending.len() as u64;
+    let mut fetched = Vec::new();
+
+    while !pending.is_empty() {
+        let mut results = stream::iter(pending.into_iter().map(|range| {
+            async move { (range, fetch_logs_chunk(tx, range, rpc_list, strategy, ttl).await) }
+        }))
+        .buffer_unordered(LOGS_CHUNK_CONCURRENCY);
+
+        let mut retry = Vec::new();
+        while let Some((range, result)) = results.next().await {
+            match result {
+                Ok(logs) => fetched.push((range.0, logs)),
+                Err(LogsChunkError::TooLarge(rx)) => {
+                    // Halving adds a chunk, so give up with the RPCs error once we're out of them
+                    if range.0 == range.1 || (max_chunks != 0 && chunks >= max_chunks) {
+                        return Err(Some(rx));
+                    }
+                    chunks += 1;
+
+                    let middle = range.0 + (range.1 - range.0) / 2;
+                    retry.push((range.0, middle));
+                    retry.push((middle + 1, range.1));
+                }
+                Err(LogsChunkError::TimedOut) => return Err(None),
+                Err(LogsChunkError::Failed(rx)) => return Err(Some(rx)),
+            }
+        }
+        pending = retry;
     }
+
+    fetched.sort_by_key(|(from_block, _)| *from_block);
+    Ok(fetched.into_iter().flat_map(|(_, logs)| logs).collect())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn test_can_cache() {
@@ -113,6 +1185,96 @@ mod tests {
         assert!(!can_cache("eth_subscribe", r#"{"result": "0x1"}"#));
     }
 
+    #[test]
+    fn test_validate_request_version() {
+        // Lenient, versions get set to 2.0
+        for mut tx in [
+            json!({"id": 1, "method": "eth_chainId"}),
+            json!({"jsonrpc": "1.0", "id": 1, "method": "eth_chainId"}),
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"}),
+        ] {
+            assert_eq!(validate_request(&mut tx, false), Ok(()));
+            assert_eq!(
+                tx,
+                json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"})
+            );
+        }
+
+        // Strict, only 2.0 is accepted
+        let mut tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"});
+        assert_eq!(validate_request(&mut tx, true), Ok(()));
+        for mut tx in [
+            json!({"id": 1, "method": "eth_chainId"}),
+            json!({"jsonrpc": "1.0", "id": 1, "method": "eth_chainId"}),
+            json!({"jsonrpc": 2, "id": 1, "method": "eth_chainId"}),
+        ] {
+            assert_eq!(
+                validate_request(&mut tx, true),
+                Err("Unsupported jsonrpc version")
+            );
+        }
+
+        let mut tx = json!({"jsonrpc": "2.0", "id": 1});
+        assert_eq!(validate_request(&mut tx, false), Err("Missing method"));
+    }
+
+    #[test]
+    fn test_jsonrpc_error() {
+        assert_eq!(
+            jsonrpc_error(&json!(1), -32600, "Invalid Request", None),
+            json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32600, "message": "Invalid Request"}})
+        );
+        assert_eq!(
+            jsonrpc_error(&Value::Null, -32002, "nope", Some("why")),
+            json!({"jsonrpc": "2.0", "id": null, "error": {"code": -32002, "message": "nope", "data": "why"}})
+        );
+    }
+
+    #[test]
+    fn test_response_transform() {
+        let transform = ResponseTransform::new(&[
+            "result.l1Fee".to_string(),
+            "$.result.transactions.*.yParity".to_string(),
+            "result.missing.field".to_string(),
+        ])
+        .unwrap();
+
+        let rx = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "number": "0x1",
+                "l1Fee": "0x10",
+                "transactions": [
+                    {"hash": "0xa", "yParity": "0x0"},
+                    {"hash": "0xb"},
+                ],
+            },
+        });
+        let stripped: Value = serde_json::from_str(&transform.apply(rx.to_string())).unwrap();
+        assert_eq!(
+            stripped,
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": {
+                    "number": "0x1",
+                    "transactions": [{"hash": "0xa"}, {"hash": "0xb"}],
+                },
+            })
+        );
+
+        // Left alone if there's nothing to strip, or it's not JSON
+        let rx = r#"{"id":1,"result":"0x1"}"#.to_string();
+        assert_eq!(ResponseTransform::default().apply(rx.clone()), rx);
+        assert_eq!(transform.apply("not json".to_string()), "not json");
+
+        assert_eq!(
+            ResponseTransform::new(&["result..l1Fee".to_string()]),
+            Err("result..l1Fee".to_string())
+        );
+    }
+
     // TODO: this :(
     // #[tokio::test]
     // async fn test_cache_querry() {
@@ -128,6 +1290,842 @@ mod tests {
     //     assert_eq!(cached_str, r#"{"id":null,"jsonrpc":"2.0","result":"0x1"}"#);
     // }
 
+    #[test]
+    fn test_method_cache_ttl() {
+        let cache_args = CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            method_cache: Arc::new(MethodCache::new(
+                [("eth_gasPrice".to_string(), 60)].into(),
+                Default::default(),
+            )),
+            ..CacheArgs::default()
+        };
+
+        // Cached even though it doesn't reference a block
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let tx = serde_json::json!({"method": "eth_gasPrice", "params": []});
+        let tx_hash = blake3::hash(tx.to_string().as_bytes());
+        cache_querry(&mut rx, tx.clone(), tx_hash, &cache_args);
+        assert!(cache_lookup(&tx, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_some());
+
+        // Expired entries are a miss, and get removed along with their index entry
+        let expired = (unix_millis() - 1).to_be_bytes();
+        cache_args
+            .cache
+            .insert(expiry_key(&cache_args.key(tx_hash.as_bytes())), &expired)
+            .unwrap();
+        assert!(cache_lookup(&tx, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+        assert!(cache_args.cache.is_empty());
+
+        // And get refreshed by the next response
+        cache_querry(&mut rx, tx.clone(), tx_hash, &cache_args);
+        assert!(cache_lookup(&tx, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_cache_lookup_stale() {
+        let cache_args = CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            keep_expired: true,
+            method_cache: Arc::new(MethodCache::new(
+                [("eth_gasPrice".to_string(), 60)].into(),
+                Default::default(),
+            )),
+            ..CacheArgs::default()
+        };
+
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let tx = serde_json::json!({"method": "eth_gasPrice", "params": []});
+        let tx_hash = blake3::hash(tx.to_string().as_bytes());
+        cache_querry(&mut rx, tx.clone(), tx_hash, &cache_args);
+        let expired = (unix_millis() - 1).to_be_bytes();
+        cache_args
+            .cache
+            .insert(expiry_key(&cache_args.key(tx_hash.as_bytes())), &expired)
+            .unwrap();
+
+        // Expired entries are still there when everything else failed
+        assert!(cache_lookup(&tx, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+        assert!(cache_lookup_stale(&tx, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_some());
+
+        // Never for pending blocks or requests that change state
+        for tx in [
+            serde_json::json!({"method": "eth_getBalance", "params": ["0x0000000000000000000000000000000000000000", "pending"]}),
+            serde_json::json!({"method": "eth_sendRawTransaction", "params": ["0x00"]}),
+        ] {
+            let tx_hash = blake3::hash(tx.to_string().as_bytes());
+            cache_args
+                .cache
+                .insert(cache_args.key(tx_hash.as_bytes()), rx.as_bytes())
+                .unwrap();
+            assert!(cache_lookup_stale(&tx, tx_hash.as_bytes(), &cache_args)
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn test_is_pending() {
+        use serde_json::json;
+
+        let pending = [
+            json!({"method": "eth_getBlockByNumber", "params": ["pending", false]}),
+            json!({"method": "eth_call", "params": [{"to": "0x0"}, "PENDING"]}),
+            json!({"method": "eth_getLogs", "params": [{"fromBlock": "0x1", "toBlock": "pending"}]}),
+            json!({"method": "eth_getBalance", "params": ["0x0", {"blockNumber": "pending"}]}),
+            json!({"method": "txpool_content", "params": []}),
+            json!({"method": "eth_pendingTransactions", "params": []}),
+        ];
+        for tx in pending {
+            assert!(is_pending(&tx), "{}", tx);
+        }
+
+        let not_pending = [
+            json!({"method": "eth_getBlockByNumber", "params": ["0x1", false]}),
+            json!({"method": "eth_call", "params": [{"to": "0x0", "data": "pending"}, "0x1"]}),
+            json!({"method": "eth_chainId"}),
+        ];
+        for tx in not_pending {
+            assert!(!is_pending(&tx), "{}", tx);
+        }
+    }
+
+    #[test]
+    fn test_pending_never_cached() {
+        // Even methods that are always cached for a while
+        let cache_args = CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            method_cache: Arc::new(MethodCache::new(
+                [("eth_getBlockByNumber".to_string(), 60)].into(),
+                Default::default(),
+            )),
+            ..CacheArgs::default()
+        };
+
+        let mut rx = r#"{"jsonrpc":"2.0","result":{"number":"0x10"},"id":1}"#.to_string();
+        let tx =
+            serde_json::json!({"method": "eth_getBlockByNumber", "params": ["pending", false]});
+        let tx_hash = blake3::hash(tx.to_string().as_bytes());
+        cache_querry(&mut rx, tx.clone(), tx_hash, &cache_args);
+        assert!(cache_args.cache.is_empty());
+
+        // Never served from the cache either, even if it somehow got there
+        let key = cache_args.key(tx_hash.as_bytes());
+        cache_args.cache.insert(&key, rx.as_bytes()).unwrap();
+        cache_args
+            .cache
+            .insert(expiry_key(&key), &u64::MAX.to_be_bytes())
+            .unwrap();
+        assert!(cache_lookup(&tx, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_error_caching() {
+        let method_cache = MethodCache::new(Default::default(), Default::default());
+        let cache_args = CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            finalized_rx: watch::channel(10).1,
+            method_cache: Arc::new(method_cache.clone()),
+            ..CacheArgs::default()
+        };
+
+        let mut rx =
+            r#"{"jsonrpc":"2.0","error":{"code":3,"message":"execution reverted"},"id":1}"#
+                .to_string();
+        let tx = serde_json::json!({"method": "eth_call", "params": [{"to": "0x01"}, "0x1"]});
+        let tx_hash = blake3::hash(tx.to_string().as_bytes());
+
+        // Not cached unless enabled
+        cache_querry(&mut rx, tx.clone(), tx_hash, &cache_args);
+        assert!(cache_lookup(&tx, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+
+        let cache_args = CacheArgs {
+            method_cache: Arc::new(method_cache.with_error_ttl(Some(Duration::from_secs(5)))),
+            ..cache_args
+        };
+        cache_querry(&mut rx, tx.clone(), tx_hash, &cache_args);
+        assert_eq!(
+            cache_lookup(&tx, tx_hash.as_bytes(), &cache_args)
+                .unwrap()
+                .unwrap(),
+            without_id(&mut rx).unwrap().as_slice()
+        );
+
+        // Kept apart from responses, and expire on their own. Expired errors get
+        // removed when they're looked up, even when responses are kept for stale serving.
+        let cache_args = CacheArgs {
+            keep_expired: true,
+            ..cache_args
+        };
+        let key = cache_args.key(tx_hash.as_bytes());
+        assert!(cache_args.cache.get(&key).unwrap().is_none());
+        let expired = (unix_millis() - 1).to_be_bytes();
+        cache_args
+            .cache
+            .insert(expiry_key(&error_key(&key)), &expired)
+            .unwrap();
+        assert!(cache_lookup(&tx, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+        assert!(cache_args.cache.is_empty());
+
+        // Responses that aren't JSON-RPC errors never end up there
+        let mut rx = r#"{"jsonrpc":"2.0","id":1,"result":null,"error":"oops"}"#.to_string();
+        let other = serde_json::json!({"method": "eth_call", "params": [{"to": "0x02"}, "0x1"]});
+        let other_hash = blake3::hash(other.to_string().as_bytes());
+        cache_querry(&mut rx, other.clone(), other_hash, &cache_args);
+        assert!(cache_lookup(&other, other_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+
+        // Purging errors leaves responses alone
+        let mut rx =
+            r#"{"jsonrpc":"2.0","error":{"code":3,"message":"execution reverted"},"id":1}"#
+                .to_string();
+        cache_querry(&mut rx, tx.clone(), tx_hash, &cache_args);
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        cache_querry(&mut rx, other.clone(), other_hash, &cache_args);
+        assert_eq!(purge_errors(&cache_args.cache).unwrap(), 1);
+        assert_eq!(cache_args.cache.scan_prefix(ERROR_PREFIX).count(), 0);
+        assert_eq!(cache_args.cache.scan_prefix(METHOD_PREFIX).count(), 1);
+        assert!(cache_lookup(&other, other_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_memory_cache_before_sled() {
+        let cache_args = CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            finalized_rx: watch::channel(10).1,
+            memory_cache: Arc::new(MemoryCache::new(16)),
+            ..CacheArgs::default()
+        };
+
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let tx = serde_json::json!({"method": "eth_getCode", "params": ["0x01", "0x1"]});
+        let tx_hash = blake3::hash(tx.to_string().as_bytes());
+        cache_querry(&mut rx, tx.clone(), tx_hash, &cache_args);
+        assert_eq!(cache_args.memory_cache.entries(), 1);
+
+        // Still served with the entry gone from sled, so we never got to sled
+        cache_args
+            .cache
+            .remove(cache_args.key(tx_hash.as_bytes()))
+            .unwrap();
+        let cached = cache_lookup(&tx, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cached, without_id(&mut rx).unwrap().as_slice());
+
+        // Entries only in sled get pulled into memory on lookup
+        let tx = serde_json::json!({"method": "eth_getCode", "params": ["0x02", "0x1"]});
+        let tx_hash = blake3::hash(tx.to_string().as_bytes());
+        cache_args
+            .cache
+            .insert(cache_args.key(tx_hash.as_bytes()), "0x2")
+            .unwrap();
+        assert!(cache_lookup(&tx, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_some());
+        assert_eq!(cache_args.memory_cache.entries(), 2);
+    }
+
+    #[test]
+    fn test_memory_cache_ttl() {
+        let cache_args = CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            method_cache: Arc::new(MethodCache::new(
+                [("eth_gasPrice".to_string(), 60)].into(),
+                Default::default(),
+            )),
+            memory_cache: Arc::new(MemoryCache::new(16)),
+            ..CacheArgs::default()
+        };
+
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let tx = serde_json::json!({"method": "eth_gasPrice", "params": []});
+        let tx_hash = blake3::hash(tx.to_string().as_bytes());
+        cache_querry(&mut rx, tx.clone(), tx_hash, &cache_args);
+
+        // Entries expire in memory when they expire in sled
+        let key = cache_args.key(tx_hash.as_bytes());
+        assert!(cache_args.memory_cache.get(&key, unix_millis()).is_some());
+        assert!(cache_args
+            .memory_cache
+            .get(&key, unix_millis() + 61_000)
+            .is_none());
+    }
+
+    #[test]
+    fn test_no_cache_methods() {
+        let cache_args = CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            method_cache: Arc::new(MethodCache::new(
+                [("eth_getBlockByNumber".to_string(), 60)].into(),
+                ["eth_getBlockByNumber".to_string()].into(),
+            )),
+            ..CacheArgs::default()
+        };
+
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let tx = serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0x1", false]});
+        let tx_hash = blake3::hash(tx.to_string().as_bytes());
+        cache_querry(&mut rx, tx.clone(), tx_hash, &cache_args);
+        assert!(cache_args.cache.get(cache_args.key(tx_hash.as_bytes())).unwrap().is_none());
+
+        // Entries cached before the method was excluded aren't served either
+        cache_args
+            .cache
+            .insert(tx_hash.as_bytes(), rx.as_bytes())
+            .unwrap();
+        assert!(cache_lookup(&tx, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_cache_querry_skips_future_blocks() {
+        let cache_args = CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            ..CacheArgs::default()
+        };
+        cache_args.named_numbers.write().unwrap().latest = 10;
+
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let method =
+            serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0xb", false]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+
+        cache_querry(&mut rx, method.clone(), tx_hash, &cache_args);
+        assert!(cache_lookup(&method, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+
+        let method =
+            serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0xa", false]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+
+        cache_querry(&mut rx, method.clone(), tx_hash, &cache_args);
+        assert!(cache_lookup(&method, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_cache_querry_dedups_head_writes() {
+        let cache_args = CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            ..CacheArgs::default()
+        };
+        cache_args.named_numbers.write().unwrap().latest = 10;
+
+        let method =
+            serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0xa", false]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+
+        for _ in 0..2 {
+            let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+            cache_querry(&mut rx, method.clone(), tx_hash, &cache_args);
+        }
+        assert_eq!(cache_args.head_cache.read().unwrap()[&10].len(), 1);
+        // Nothing gets written to sled until the block finalizes
+        assert!(cache_args.cache.is_empty());
+        assert_eq!(cache_args.cache_state.deduped_head_writes(), 1);
+
+        // A different response still gets written
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x2","id":1}"#.to_string();
+        cache_querry(&mut rx, method, tx_hash, &cache_args);
+        assert_eq!(cache_args.head_cache.read().unwrap()[&10].len(), 1);
+        assert_eq!(cache_args.cache_state.deduped_head_writes(), 1);
+    }
+
+    #[test]
+    fn test_cache_lookup_migrates_old_schema() {
+        let (_finalized_tx, finalized_rx) = watch::channel(10);
+        let cache_args = CacheArgs {
+            finalized_rx,
+            cache: CacheArgs::temporary_cache(),
+            schema_version: 2,
+            migrate_cache: true,
+            ..CacheArgs::default()
+        };
+        cache_args.named_numbers.write().unwrap().latest = 20;
+
+        let finalized =
+            serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0xa", false]});
+        let finalized_hash = blake3::hash(finalized.to_string().as_bytes());
+        let tip = serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0x14", false]});
+        let tip_hash = blake3::hash(tip.to_string().as_bytes());
+
+        // Cached by older versions of blutgang
+        let rx: &[u8] = br#"{"jsonrpc":"2.0","result":"0x1","id":null}"#;
+        let old_key = versioned_key(0, 0, finalized_hash.as_bytes());
+        cache_args.cache.insert(&old_key, rx).unwrap();
+        cache_args
+            .cache
+            .insert(versioned_key(0, 1, tip_hash.as_bytes()), rx)
+            .unwrap();
+
+        // Finalized entries are served and moved to the current version
+        let cached = cache_lookup(&finalized, finalized_hash.as_bytes(), &cache_args).unwrap();
+        assert_eq!(cached.unwrap(), rx);
+        assert!(cache_args.cache.get(&old_key).unwrap().is_none());
+        assert!(cache_args
+            .cache
+            .get(cache_args.key(finalized_hash.as_bytes()))
+            .unwrap()
+            .is_some());
+
+        // Unfinalized entries are a miss
+        assert!(cache_lookup(&tip, tip_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+
+        // Nothing gets migrated if disabled
+        let cache_args = CacheArgs {
+            migrate_cache: false,
+            ..cache_args
+        };
+        cache_args.cache.insert(&old_key, rx).unwrap();
+        cache_args
+            .cache
+            .remove(cache_args.key(finalized_hash.as_bytes()))
+            .unwrap();
+        assert!(
+            cache_lookup(&finalized, finalized_hash.as_bytes(), &cache_args)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_cache_version() {
+        let (_finalized_tx, finalized_rx) = watch::channel(10);
+        let cache_args = CacheArgs {
+            finalized_rx,
+            cache: CacheArgs::temporary_cache(),
+            schema_version: 1,
+            migrate_cache: true,
+            ..CacheArgs::default()
+        };
+        cache_args.named_numbers.write().unwrap().latest = 20;
+
+        let tx = serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0xa", false]});
+        let tx_hash = blake3::hash(tx.to_string().as_bytes());
+        let rx: &[u8] = br#"{"jsonrpc":"2.0","result":"0x1","id":null}"#;
+        cache_args
+            .cache
+            .insert(cache_args.key(tx_hash.as_bytes()), rx)
+            .unwrap();
+
+        // Bumping the cache version leaves every entry behind, even with migrations on
+        let cache_args = CacheArgs {
+            cache_version: 1,
+            ..cache_args
+        };
+        assert_eq!(
+            cache_args.key(tx_hash.as_bytes()),
+            [b"cache/\x01\x01", tx_hash.as_bytes().as_slice()].concat()
+        );
+        assert!(cache_lookup(&tx, tx_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+
+        // Older schema versions of the same cache version still get migrated
+        cache_args
+            .cache
+            .insert(versioned_key(1, 0, tx_hash.as_bytes()), rx)
+            .unwrap();
+        let cached = cache_lookup(&tx, tx_hash.as_bytes(), &cache_args).unwrap();
+        assert_eq!(cached.unwrap(), rx);
+    }
+
+    #[test]
+    fn test_cache_querry_get_code() {
+        let (_finalized_tx, finalized_rx) = watch::channel(10);
+        let cache_args = CacheArgs {
+            finalized_rx,
+            cache: CacheArgs::temporary_cache(),
+            ..CacheArgs::default()
+        };
+        cache_args.named_numbers.write().unwrap().latest = 20;
+
+        // Finalized, including empty code
+        for rx in [
+            r#"{"jsonrpc":"2.0","result":"0x6080","id":1}"#,
+            r#"{"jsonrpc":"2.0","result":"0x","id":1}"#,
+        ] {
+            let mut rx = rx.to_string();
+            let method = serde_json::json!({"method": "eth_getCode", "params": ["0x01", "0xa"]});
+            let tx_hash = blake3::hash(method.to_string().as_bytes());
+
+            cache_querry(&mut rx, method, tx_hash, &cache_args);
+            assert!(cache_args.cache.get(cache_args.key(tx_hash.as_bytes())).unwrap().is_some());
+            cache_args.cache.remove(cache_args.key(tx_hash.as_bytes())).unwrap();
+        }
+
+        // `latest`, both as a tag and after it got replaced with the head
+        for block in ["latest", "0x14"] {
+            let mut rx = r#"{"jsonrpc":"2.0","result":"0x6080","id":1}"#.to_string();
+            let method = serde_json::json!({"method": "eth_getCode", "params": ["0x01", block]});
+            let tx_hash = blake3::hash(method.to_string().as_bytes());
+
+            cache_querry(&mut rx, method, tx_hash, &cache_args);
+            assert!(cache_args.cache.get(cache_args.key(tx_hash.as_bytes())).unwrap().is_none());
+        }
+        assert!(cache_args.head_cache.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cache_querry_get_storage_at() {
+        let (_finalized_tx, finalized_rx) = watch::channel(10);
+        let cache_args = CacheArgs {
+            finalized_rx,
+            cache: CacheArgs::temporary_cache(),
+            ..CacheArgs::default()
+        };
+        cache_args.named_numbers.write().unwrap().latest = 20;
+
+        let zero = r#"{"jsonrpc":"2.0","result":"0x0000000000000000000000000000000000000000000000000000000000000000","id":1}"#;
+
+        // Finalized, including zero value slots
+        for slot in ["0x0", "0x1"] {
+            let mut rx = zero.to_string();
+            let method =
+                serde_json::json!({"method": "eth_getStorageAt", "params": ["0x01", slot, "0xa"]});
+            let tx_hash = blake3::hash(method.to_string().as_bytes());
+
+            cache_querry(&mut rx, method, tx_hash, &cache_args);
+            assert!(cache_args.cache.get(cache_args.key(tx_hash.as_bytes())).unwrap().is_some());
+        }
+
+        // `latest`, both as a tag and after it got replaced with the head
+        for block in ["latest", "0x14"] {
+            let mut rx = zero.to_string();
+            let method =
+                serde_json::json!({"method": "eth_getStorageAt", "params": ["0x01", "0x0", block]});
+            let tx_hash = blake3::hash(method.to_string().as_bytes());
+
+            cache_querry(&mut rx, method, tx_hash, &cache_args);
+            assert!(cache_args.cache.get(cache_args.key(tx_hash.as_bytes())).unwrap().is_none());
+        }
+        assert!(cache_args.head_cache.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_head_cache_disabled() {
+        let (_finalized_tx, finalized_rx) = watch::channel(10);
+        let cache_args = CacheArgs {
+            finalized_rx,
+            cache: CacheArgs::temporary_cache(),
+            head_cache_enabled: false,
+            ..CacheArgs::default()
+        };
+        cache_args.named_numbers.write().unwrap().latest = 20;
+        let rx = r#"{"jsonrpc":"2.0","result":{"number":"0x1"},"id":1}"#;
+
+        // Unfinalized blocks aren't cached anywhere
+        let mut rx_head = rx.to_string();
+        let head = serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0x14", false]});
+        let head_hash = blake3::hash(head.to_string().as_bytes());
+        cache_querry(&mut rx_head, head.clone(), head_hash, &cache_args);
+        assert!(cache_args.head_cache.read().unwrap().is_empty());
+        assert!(cache_lookup(&head, head_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+
+        // Finalized ones still go to sled
+        let mut rx_finalized = rx.to_string();
+        let finalized =
+            serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0xa", false]});
+        let finalized_hash = blake3::hash(finalized.to_string().as_bytes());
+        cache_querry(
+            &mut rx_finalized,
+            finalized.clone(),
+            finalized_hash,
+            &cache_args,
+        );
+        assert!(
+            cache_lookup(&finalized, finalized_hash.as_bytes(), &cache_args)
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_cache_querry_eth_call() {
+        use crate::balancer::format::cache_key;
+
+        let (_finalized_tx, finalized_rx) = watch::channel(10);
+        let cache_args = CacheArgs {
+            finalized_rx,
+            cache: CacheArgs::temporary_cache(),
+            ..CacheArgs::default()
+        };
+        cache_args.named_numbers.write().unwrap().latest = 20;
+        let hash = |tx: &Value| blake3::hash(cache_key(tx, &cache_args.block_params).as_bytes());
+        let rx = r#"{"jsonrpc":"2.0","result":"0x01","id":1}"#;
+
+        // Finalized calls are cached for good
+        let mut rx_finalized = rx.to_string();
+        let call: Value = serde_json::from_str(
+            r#"{"method": "eth_call", "params": [{"to": "0xAB", "data": "0x70a08231", "gas": "0x0100"}, "0xa"]}"#,
+        )
+        .unwrap();
+        cache_querry(&mut rx_finalized, call.clone(), hash(&call), &cache_args);
+        assert!(cache_args
+            .cache
+            .get(cache_args.key(hash(&call).as_bytes()))
+            .unwrap()
+            .is_some());
+
+        // The same call with its fields in another order hits the same entry
+        let reordered: Value = serde_json::from_str(
+            r#"{"method": "eth_call", "params": [{"gas": "0x100", "data": "0x70a08231", "to": "0xab"}, "0xa"]}"#,
+        )
+        .unwrap();
+        assert_eq!(hash(&call), hash(&reordered));
+        assert!(
+            cache_lookup(&reordered, hash(&reordered).as_bytes(), &cache_args)
+                .unwrap()
+                .is_some()
+        );
+
+        // Never against `latest` or `pending`, not even in the head cache
+        for block in ["latest", "0x14", "pending"] {
+            let mut rx = rx.to_string();
+            let call = serde_json::json!({"method": "eth_call", "params": [{"to": "0xab"}, block]});
+            cache_querry(&mut rx, call.clone(), hash(&call), &cache_args);
+            assert!(cache_args
+                .cache
+                .get(cache_args.key(hash(&call).as_bytes()))
+                .unwrap()
+                .is_none());
+        }
+        assert!(cache_args.head_cache.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cache_querry_custom_block_param() {
+        let (_finalized_tx, finalized_rx) = watch::channel(10);
+        let block_params = BlockParams::new(HashMap::from([(
+            "vendor_getThing".to_string(),
+            "/1/block".to_string(),
+        )]));
+        let cache_args = CacheArgs {
+            finalized_rx,
+            cache: CacheArgs::temporary_cache(),
+            block_params: Arc::new(block_params),
+            ..CacheArgs::default()
+        };
+        cache_args.named_numbers.write().unwrap().latest = 20;
+
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+
+        // Finalized, should be cached permanently
+        let method =
+            serde_json::json!({"method": "vendor_getThing", "params": ["0x0", {"block": "0x5"}]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(&mut rx, method, tx_hash, &cache_args);
+        assert!(cache_args.cache.get(cache_args.key(tx_hash.as_bytes())).unwrap().is_some());
+        assert!(cache_args.head_cache.read().unwrap().is_empty());
+
+        // Not finalized, should be kept in the head cache until it finalizes
+        let method =
+            serde_json::json!({"method": "vendor_getThing", "params": ["0x0", {"block": "0xf"}]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(&mut rx, method, tx_hash, &cache_args);
+        assert!(cache_args.cache.get(cache_args.key(tx_hash.as_bytes())).unwrap().is_none());
+        assert!(cache_args.head_cache.read().unwrap().contains_key(&15));
+
+        // Unknown methods don't get cached
+        let method =
+            serde_json::json!({"method": "vendor_getOther", "params": ["0x0", {"block": "0x5"}]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(&mut rx, method, tx_hash, &cache_args);
+        assert!(cache_args.cache.get(cache_args.key(tx_hash.as_bytes())).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cache_unavailable() {
+        // Every read and write on a dropped tree fails
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("cache").unwrap();
+        db.drop_tree("cache").unwrap();
+        let cache_args = CacheArgs {
+            cache: Arc::new(tree),
+            finalized_rx: watch::channel(10).1,
+            ..CacheArgs::default()
+        };
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+
+        // Failed writes get skipped
+        let method =
+            serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0x1", false]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(&mut rx, method.clone(), tx_hash, &cache_args);
+        assert!(!cache_args.cache_state.is_read_only());
+        cache_args.memory_cache.clear();
+
+        // Failed reads are misses
+        assert!(cache_lookup(&method, tx_hash.as_bytes(), &cache_args).is_err());
+        assert!(cache_lookup_or_miss(&method, tx_hash.as_bytes(), &cache_args).is_none());
+    }
+
+    #[test]
+    fn test_cache_read_only_on_out_of_space() {
+        let cache_args = CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            finalized_rx: watch::channel(10).1,
+            ..CacheArgs::default()
+        };
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+
+        // Other errors don't change anything
+        let err = sled::Error::Io(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        cache_args.cache_state.handle_write::<()>(Err(err));
+        assert!(!cache_args.cache_state.is_read_only());
+
+        // Disk is full
+        let err = sled::Error::Io(std::io::Error::from_raw_os_error(28));
+        cache_args.cache_state.handle_write::<()>(Err(err));
+        assert!(cache_args.cache_state.is_read_only());
+
+        // We shouldn't be writing anything new, but still reading
+        let method =
+            serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0x1", false]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(&mut rx, method, tx_hash, &cache_args);
+        assert!(cache_args.cache.get(cache_args.key(tx_hash.as_bytes())).unwrap().is_none());
+
+        // Space got freed up, a successful write leaves read-only mode
+        *cache_args.cache_state.read_only_since.write().unwrap() =
+            Some(Instant::now() - READ_ONLY_RETRY);
+        let method =
+            serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0x2", false]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(&mut rx, method, tx_hash, &cache_args);
+        assert!(cache_args.cache.get(cache_args.key(tx_hash.as_bytes())).unwrap().is_some());
+        assert!(!cache_args.cache_state.is_read_only());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_webhook() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = events.clone();
+        let receiver = crate::rpc::mock::MockRpc::spawn(Duration::ZERO, move |event| {
+            received.lock().unwrap().push(event["event"].clone());
+            serde_json::json!({})
+        })
+        .await;
+        let cache_state =
+            CacheWriteState::new(false, HealthWebhook::new(Some(receiver.url.clone())));
+
+        // Failing again after a retry doesn't count as entering read-only mode again
+        for _ in 0..2 {
+            let err = sled::Error::Io(std::io::Error::from_raw_os_error(28));
+            cache_state.handle_write::<()>(Err(err));
+        }
+        cache_state.handle_write(Ok(()));
+        cache_state.handle_write(Ok(()));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let mut events = events.lock().unwrap().clone();
+        events.sort_by_key(|event| event.to_string());
+        assert_eq!(events, ["entered_read_only", "left_read_only"]);
+    }
+
+    #[test]
+    fn test_reorg_window_bypasses_tip() {
+        let (_finalized_tx, finalized_rx) = watch::channel(10);
+        let cache_args = CacheArgs {
+            finalized_rx,
+            cache: CacheArgs::temporary_cache(),
+            reorg_window: Arc::new(ReorgWindow::new(Duration::from_millis(100))),
+            ..CacheArgs::default()
+        };
+        cache_args.named_numbers.write().unwrap().latest = 20;
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+
+        let tip = serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0xf", false]});
+        let tip_hash = blake3::hash(tip.to_string().as_bytes());
+        let finalized =
+            serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0x5", false]});
+        let finalized_hash = blake3::hash(finalized.to_string().as_bytes());
+
+        cache_querry(&mut rx, tip.clone(), tip_hash, &cache_args);
+        cache_querry(&mut rx, finalized.clone(), finalized_hash, &cache_args);
+        assert!(cache_lookup(&tip, tip_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_some());
+
+        // Tip reads bypass the cache during the window, finalized ones don't
+        cache_args.reorg_window.start();
+        assert!(cache_lookup(&tip, tip_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+        assert!(
+            cache_lookup(&finalized, finalized_hash.as_bytes(), &cache_args)
+                .unwrap()
+                .is_some()
+        );
+
+        // Tip writes get skipped too
+        let new_tip =
+            serde_json::json!({"method": "eth_getBlockByNumber", "params": ["0x10", false]});
+        let new_tip_hash = blake3::hash(new_tip.to_string().as_bytes());
+        cache_querry(&mut rx, new_tip, new_tip_hash, &cache_args);
+        assert!(cache_args
+            .cache
+            .get(new_tip_hash.as_bytes())
+            .unwrap()
+            .is_none());
+
+        // Back to normal after the window
+        std::thread::sleep(Duration::from_millis(110));
+        assert!(cache_lookup(&tip, tip_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_reorg_window_disabled() {
+        let reorg_window = ReorgWindow::default();
+        reorg_window.start();
+        assert!(!reorg_window.is_active());
+    }
+
+    #[tokio::test]
+    async fn test_update_rpc_latency_ema() {
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default()]));
+
+        // The first sample is taken as is
+        update_rpc_latency_ema(&rpc_list, 0, Duration::from_millis(100), 0.5);
+        assert_eq!(rpc_list.read().unwrap()[0].status.latency_ms, 100.0);
+
+        update_rpc_latency_ema(&rpc_list, 0, Duration::from_millis(200), 0.5);
+        assert_eq!(rpc_list.read().unwrap()[0].status.latency_ms, 150.0);
+
+        // Out of bounds positions are ignored
+        update_rpc_latency_ema(&rpc_list, 1, Duration::from_millis(200), 0.5);
+        assert_eq!(rpc_list.read().unwrap()[0].status.latency_ms, 150.0);
+    }
+
     #[tokio::test]
     async fn test_update_rpc_latency() {
         let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
@@ -220,4 +2218,104 @@ mod tests {
             "Should update the last RPC in the list"
         );
     }
+
+    #[test]
+    fn test_logs_range_to_split() {
+        let logs = |from: &str, to: &str| serde_json::json!({"method": "eth_getLogs", "params": [{"fromBlock": from, "toBlock": to}]});
+
+        assert_eq!(logs_range_to_split(&logs("0x0", "0x9"), 5), Some((0, 9)));
+        assert_eq!(logs_range_to_split(&logs("0x0", "0x4"), 5), None);
+        assert_eq!(logs_range_to_split(&logs("0x0", "0x9"), 0), None);
+        assert_eq!(logs_range_to_split(&logs("0x0", "latest"), 5), None);
+    }
+
+    #[tokio::test]
+    async fn test_split_logs() {
+        use crate::rpc::mock::MockRpc;
+
+        // Rejects ranges over 3 blocks and has one log per block
+        let mock = MockRpc::spawn(Duration::ZERO, |request| {
+            let filter = &request["params"][0];
+            let from = hex_to_decimal(filter["fromBlock"].as_str().unwrap()).unwrap();
+            let to = hex_to_decimal(filter["toBlock"].as_str().unwrap()).unwrap();
+            if to - from >= 3 {
+                return serde_json::json!({"jsonrpc": "2.0", "id": request["id"], "error": {"code": -32005, "message": "block range too large"}});
+            }
+
+            let logs: Vec<Value> = (from..=to)
+                .map(|block| serde_json::json!({"blockNumber": format!("{:#x}", block)}))
+                .collect();
+            serde_json::json!({"jsonrpc": "2.0", "id": request["id"], "result": logs})
+        })
+        .await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            mock.url.clone(),
+            None,
+            0,
+            0,
+            1.0,
+        )]));
+        let tx = serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getLogs", "params": [{"fromBlock": "0x0", "toBlock": "0x13"}]});
+
+        let logs = split_logs(&tx, 0, 19, 5, 0, &rpc_list, SelectionStrategy::default(), 1000)
+            .await
+            .unwrap();
+
+        let blocks: Vec<u64> = logs
+            .iter()
+            .map(|log| hex_to_decimal(log["blockNumber"].as_str().unwrap()).unwrap())
+            .collect();
+        assert_eq!(blocks, (0..=19).collect::<Vec<_>>());
+        // 4 chunks of 5 blocks, each rejected once and halved
+        assert_eq!(mock.requests(), 12);
+
+        // Halving stops once there are `max_chunks` chunks, with the RPCs error
+        let rx = split_logs(&tx, 0, 19, 5, 6, &rpc_list, SelectionStrategy::default(), 1000)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(rx["error"]["message"], "block range too large");
+        assert_eq!(logs_chunk_count(0, 19, 5), 4);
+        assert_eq!(logs_chunk_count(0, 20, 5), 5);
+        assert_eq!(logs_chunk_count(7, 7, 5), 1);
+    }
+    #[test]
+    fn test_purge_method() {
+        let cache_args = CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            finalized_rx: watch::channel(10).1,
+            ..CacheArgs::default()
+        };
+
+        let call = serde_json::json!({"method": "eth_call", "params": [{"to": "0x01"}, "0x1"]});
+        let call_hash = blake3::hash(call.to_string().as_bytes());
+        let balance = serde_json::json!({"method": "eth_getBalance", "params": ["0x01", "0x1"]});
+        let balance_hash = blake3::hash(balance.to_string().as_bytes());
+
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        cache_querry(&mut rx, call.clone(), call_hash, &cache_args);
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x2","id":1}"#.to_string();
+        cache_querry(&mut rx, balance.clone(), balance_hash, &cache_args);
+
+        assert_eq!(
+            indexed_methods(&cache_args.cache).unwrap(),
+            ["eth_call", "eth_getBalance"]
+        );
+
+        assert_eq!(purge_method(&cache_args.cache, "eth_call").unwrap(), 1);
+        assert!(cache_lookup(&call, call_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_none());
+        assert!(cache_lookup(&balance, balance_hash.as_bytes(), &cache_args)
+            .unwrap()
+            .is_some());
+
+        // The index goes with the entries
+        assert_eq!(purge_method(&cache_args.cache, "eth_call").unwrap(), 0);
+        assert_eq!(cache_args.cache.scan_prefix(METHOD_PREFIX).count(), 1);
+        assert_eq!(
+            indexed_methods(&cache_args.cache).unwrap(),
+            ["eth_getBalance"]
+        );
+    }
 }
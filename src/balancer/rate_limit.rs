@@ -0,0 +1,190 @@
+// Per-client rate limiting.
+//
+// Every client IP gets a token bucket that holds up to `burst` tokens and refills
+// at `rps` tokens per second. Each request takes a token, batches take one per
+// call, and clients with an empty bucket get a 429 until it refills. Off if `rps` is 0.
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::RETRY_AFTER,
+    Response,
+};
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    // Tokens refilled per second. 0 disables rate limiting
+    rps: f64,
+    // Max tokens a bucket can hold
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    // `burst` of 0 lets clients burst up to `rps` requests
+    pub fn new(rps: u64, burst: u64) -> Self {
+        let burst = if burst == 0 { rps } else { burst };
+        RateLimiter {
+            rps: rps as f64,
+            burst: burst as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rps > 0.0
+    }
+
+    // Take a token from the bucket of `ip`.
+    //
+    // Returns how long the client has to wait for a token if the bucket is empty.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.check_n(ip, 1)
+    }
+
+    // Take `tokens` tokens from the bucket of `ip` at once, e.g. for every call in a batch.
+    // Nothing is taken if there aren't enough of them.
+    //
+    // Returns how long the client has to wait until there are enough tokens.
+    pub fn check_n(&self, ip: IpAddr, tokens: u64) -> Result<(), Duration> {
+        if !self.is_enabled() || tokens == 0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+
+        let tokens = tokens as f64;
+        if bucket.tokens >= tokens {
+            bucket.tokens -= tokens;
+            return Ok(());
+        }
+
+        Err(Duration::from_secs_f64((tokens - bucket.tokens) / self.rps))
+    }
+
+    // Drop the buckets of clients that have been idle long enough for them to refill
+    pub fn collect_idle(&self) {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| self.refill(bucket, now) < self.burst);
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rps).min(self.burst)
+    }
+
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+}
+
+pub fn rate_limited_response(retry_after: Duration) -> Response<Full<Bytes>> {
+    // `Retry-After` is in whole seconds, round up so clients don't come back too early
+    let retry_after = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+    Response::builder()
+        .status(429)
+        .header(RETRY_AFTER, retry_after.max(1))
+        .body(Full::new(Bytes::from("Too many requests")))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = RateLimiter::new(10, 3);
+
+        for _ in 0..3 {
+            assert!(limiter.check(ip("10.0.0.1")).is_ok());
+        }
+        let retry_after = limiter.check(ip("10.0.0.1")).unwrap_err();
+        assert!(retry_after <= Duration::from_millis(100));
+
+        // Other clients have their own budget
+        assert!(limiter.check(ip("10.0.0.2")).is_ok());
+
+        // Refills over time
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(limiter.check(ip("10.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limit_many() {
+        let limiter = RateLimiter::new(10, 5);
+
+        assert!(limiter.check_n(ip("10.0.0.1"), 4).is_ok());
+        // Not enough left for the whole batch, and nothing gets taken
+        assert!(limiter.check_n(ip("10.0.0.1"), 2).is_err());
+        assert!(limiter.check(ip("10.0.0.1")).is_ok());
+        assert!(limiter.check(ip("10.0.0.1")).is_err());
+    }
+
+    #[test]
+    fn test_rate_limit_disabled() {
+        let limiter = RateLimiter::new(0, 0);
+
+        for _ in 0..100 {
+            assert!(limiter.check(ip("10.0.0.1")).is_ok());
+        }
+        assert_eq!(limiter.tracked(), 0);
+    }
+
+    #[test]
+    fn test_collect_idle() {
+        let limiter = RateLimiter::new(100, 1);
+        limiter.check(ip("10.0.0.1")).unwrap();
+        limiter.check(ip("10.0.0.2")).unwrap();
+
+        // Both buckets are empty right after
+        limiter.collect_idle();
+        assert_eq!(limiter.tracked(), 2);
+
+        std::thread::sleep(Duration::from_millis(20));
+        limiter.collect_idle();
+        assert_eq!(limiter.tracked(), 0);
+    }
+
+    #[test]
+    fn test_rate_limited_response() {
+        let response = rate_limited_response(Duration::from_millis(1500));
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+
+        let response = rate_limited_response(Duration::from_millis(10));
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+    }
+}
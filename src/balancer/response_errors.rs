@@ -3,12 +3,20 @@
 
 #[macro_export]
 macro_rules! no_rpc_available {
-    () => {
+    (
+        $id:expr
+    ) => {
         Ok(hyper::Response::builder()
-            .status(500)
+            .status(503)
+            .header("Content-Type", "application/json")
             .body(Full::new(Bytes::from(
-                "{code:-32002, message:\"error: No working RPC available! Try again later...\"}"
-                    .to_string(),
+                $crate::balancer::processing::jsonrpc_error(
+                    &$id,
+                    -32002,
+                    "error: No healthy upstreams available! Try again later...",
+                    None,
+                )
+                .to_string(),
             )))
             .unwrap())
     };
@@ -16,33 +24,143 @@ macro_rules! no_rpc_available {
 
 #[macro_export]
 macro_rules! timed_out {
-    () => {
+    (
+        $id:expr
+    ) => {
         Ok(hyper::Response::builder()
             .status(408)
+            .header("Content-Type", "application/json")
             .body(Full::new(Bytes::from(
-                "{code:-32001, message:\"error: Request timed out! Try again later...\"}"
-                    .to_string(),
+                $crate::balancer::processing::jsonrpc_error(
+                    &$id,
+                    -32001,
+                    "error: Request timed out! Try again later...",
+                    None,
+                )
+                .to_string(),
             )))
             .unwrap())
     };
 }
 
 #[macro_export]
-macro_rules! print_cache_error {
-    () => {
-        println!("\x1b[31m!!! Cache error! Check the DB !!!\x1b[0m");
-        println!("To recover, please stop blutgang, delete your cache folder, and start blutgang again.");
-        println!("If the error perists, please open up an issue: https://github.com/rainshowerLabs/blutgang/issues");
+macro_rules! upstream_error {
+    (
+        $id:expr,
+        $error:expr
+    ) => {
+        Ok(hyper::Response::builder()
+            .status(502)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": $id,
+                    "error": $error,
+                })
+                .to_string(),
+            )))
+            .unwrap())
+    };
+}
+
+#[macro_export]
+macro_rules! future_block {
+    (
+        $id:expr
+    ) => {
+        Ok(hyper::Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(
+                $crate::balancer::processing::jsonrpc_error(
+                    &$id,
+                    -32000,
+                    "error: Requested block is ahead of the current head!",
+                    None,
+                )
+                .to_string(),
+            )))
+            .unwrap())
+    };
+}
+
+#[macro_export]
+macro_rules! response_too_large {
+    (
+        $id:expr
+    ) => {
+        $crate::balancer::processing::jsonrpc_error(
+            &$id,
+            -32008,
+            "error: Response is larger than max_response_bytes!",
+            None,
+        )
+        .to_string()
     };
 }
 
 #[macro_export]
-macro_rules! cache_error {
+macro_rules! parse_error {
     () => {
+        $crate::balancer::processing::jsonrpc_error(
+            &serde_json::Value::Null,
+            -32700,
+            "Parse error",
+            None,
+        )
+        .to_string()
+    };
+}
+
+#[macro_export]
+macro_rules! invalid_request {
+    (
+        $id:expr,
+        $reason:expr
+    ) => {
+        $crate::balancer::processing::jsonrpc_error(&$id, -32600, "Invalid Request", Some($reason))
+            .to_string()
+    };
+}
+
+// `$message` is `no_archive_message`, and can be set by the user
+#[macro_export]
+macro_rules! no_archive_rpc {
+    (
+        $id:expr,
+        $message:expr
+    ) => {
         Ok(hyper::Response::builder()
-            .status(500)
+            .status(200)
+            .header("Content-Type", "application/json")
             .body(Full::new(Bytes::from(
-                "{code:-32003, message:\"error: Cache error! Try again later...\"}".to_string(),
+                $crate::balancer::processing::jsonrpc_error(
+                    &$id,
+                    -32002,
+                    $message,
+                    Some("No RPC in the active pool is an archive node"),
+                )
+                .to_string(),
+            )))
+            .unwrap())
+    };
+}
+
+// `$method` is pinned to `$group` with `method_routing`
+#[macro_export]
+macro_rules! no_routed_rpc {
+    (
+        $id:expr,
+        $method:expr,
+        $group:expr
+    ) => {
+        Ok(hyper::Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(
+                $crate::balancer::method_routing::MethodRouting::unavailable(&$id, $method, $group)
+                    .to_string(),
             )))
             .unwrap())
     };
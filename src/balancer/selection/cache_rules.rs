@@ -1,5 +1,62 @@
 use memchr::memmem;
 
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    time::Duration,
+};
+
+// Per-method overrides of the caching rules, set with the `method_cache`
+// config section and `no_cache_methods`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MethodCache {
+    // Responses are cached for this many seconds, even if they don't reference a block
+    ttls: HashMap<String, u64>,
+    // Responses are never cached
+    no_cache: HashSet<String>,
+    // JSON-RPC errors are cached for this long, if set
+    error_ttl: Option<Duration>,
+}
+
+impl MethodCache {
+    pub fn new(ttls: HashMap<String, u64>, no_cache: HashSet<String>) -> Self {
+        MethodCache {
+            ttls,
+            no_cache,
+            error_ttl: None,
+        }
+    }
+
+    pub fn with_error_ttl(mut self, error_ttl: Option<Duration>) -> Self {
+        self.error_ttl = error_ttl;
+        self
+    }
+
+    // How long responses to `method` should be cached for, if it has a TTL
+    pub fn ttl(&self, method: &str) -> Option<Duration> {
+        if !self.is_cacheable(method) {
+            return None;
+        }
+
+        self.ttls.get(method).map(|ttl| Duration::from_secs(*ttl))
+    }
+
+    // How long JSON-RPC errors in response to `method` should be cached for, if at all
+    pub fn error_ttl(&self, method: &str) -> Option<Duration> {
+        if !self.is_cacheable(method) {
+            return None;
+        }
+
+        self.error_ttl
+    }
+
+    pub fn is_cacheable(&self, method: &str) -> bool {
+        !self.no_cache.contains(method)
+    }
+}
+
 // Return true if we are supposed to be caching the input.
 //
 // This loop cannot be unrolled because it wouldn't work against mangled queries that would
@@ -34,6 +91,15 @@ pub fn cache_method(rx: &str) -> bool {
     true
 }
 
+// Return true if the method should only be cached for finalized blocks.
+//
+// Responses for these can change across reorgs in ways that aren't always
+// caught by invalidating the head cache, e.g. contracts redeployed with CREATE2.
+// `eth_call` executes against that same state.
+pub fn finalized_only(method: &str) -> bool {
+    matches!(method, "eth_getCode" | "eth_getStorageAt" | "eth_call")
+}
+
 // Same as cache_method but for results
 pub fn cache_result(rx: &str) -> bool {
     // If no-cache feature is on, return false
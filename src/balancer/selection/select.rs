@@ -1,8 +1,22 @@
-use crate::Rpc;
+use crate::{
+    config::types::SelectionStrategy,
+    Rpc,
+};
+use rand::Rng;
 use std::time::SystemTime;
 
 // Generic entry point fn to select the next rpc and return its position
-pub fn pick(list: &mut Vec<Rpc>) -> (Rpc, Option<usize>) {
+pub fn pick(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+    pick_with(list, SelectionStrategy::RoundRobin)
+}
+
+// Select the next rpc with a specific strategy and return its position
+pub fn pick_with(list: &mut [Rpc], strategy: SelectionStrategy) -> (Rpc, Option<usize>) {
+    // Leave out RPCs cooling down after rate limiting us
+    if list.iter().any(Rpc::is_cooling_down) {
+        return pick_filtered(list, strategy, |_| true);
+    }
+
     // If len is 1, return the only element
     if list.len() == 1 {
         return (list[0].clone(), Some(0));
@@ -10,11 +24,145 @@ pub fn pick(list: &mut Vec<Rpc>) -> (Rpc, Option<usize>) {
         return (Rpc::default(), None);
     }
 
-    algo(list)
+    match strategy {
+        SelectionStrategy::RoundRobin => algo(list),
+        SelectionStrategy::LeastLatency => least_latency(list),
+        SelectionStrategy::Weighted => weighted(list),
+        SelectionStrategy::Random => {
+            let index = rand::thread_rng().gen_range(0..list.len());
+            (list[index].clone(), Some(index))
+        }
+        // Nothing to hash, fall back to round robin
+        SelectionStrategy::HashAffinity => algo(list),
+    }
+}
+
+// Select the next rpc out of the ones matching `filter` and return its position in `list`
+pub fn pick_filtered(
+    list: &mut [Rpc],
+    strategy: SelectionStrategy,
+    filter: impl Fn(&Rpc) -> bool,
+) -> (Rpc, Option<usize>) {
+    // RPCs cooling down after rate limiting us are skipped
+    let indices: Vec<usize> = (0..list.len())
+        .filter(|&i| filter(&list[i]) && !list[i].is_cooling_down())
+        .collect();
+    if indices.len() == list.len() {
+        return pick_with(list, strategy);
+    }
+
+    // Pick out of the matching RPCs and write back what the selection changed
+    let mut matching: Vec<Rpc> = indices.iter().map(|&i| list[i].clone()).collect();
+    let (rpc, position) = pick_with(&mut matching, strategy);
+    for (rpc, &i) in matching.into_iter().zip(indices.iter()) {
+        list[i].consecutive = rpc.consecutive;
+        list[i].last_used = rpc.last_used;
+        list[i].current_weight = rpc.current_weight;
+    }
+
+    (rpc, position.map(|position| indices[position]))
+}
+
+// Select the next rpc for the request hashed to `key`, out of the ones matching `filter`
+pub fn pick_keyed(
+    list: &mut [Rpc],
+    strategy: SelectionStrategy,
+    key: &[u8],
+    filter: impl Fn(&Rpc) -> bool,
+) -> (Rpc, Option<usize>) {
+    match strategy {
+        SelectionStrategy::HashAffinity => hash_affinity(list, key, filter),
+        _ => pick_filtered(list, strategy, filter),
+    }
+}
+
+// Picks the RPC matching `filter` that scores highest for `key`.
+//
+// Rendezvous hashing: every RPC gets a score by hashing `key` with its URL. When an RPC
+// joins or leaves the pool, only the keys it wins move, the rest stay where they were.
+fn hash_affinity(
+    list: &mut [Rpc],
+    key: &[u8],
+    filter: impl Fn(&Rpc) -> bool,
+) -> (Rpc, Option<usize>) {
+    let score = |rpc: &Rpc| {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(key);
+        hasher.update(rpc.url.as_bytes());
+        let mut score = [0; 8];
+        score.copy_from_slice(&hasher.finalize().as_bytes()[..8]);
+        u64::from_be_bytes(score)
+    };
+
+    match (0..list.len())
+        .filter(|&i| filter(&list[i]) && !list[i].is_cooling_down())
+        .max_by_key(|&i| score(&list[i]))
+    {
+        Some(index) => (list[index].clone(), Some(index)),
+        None => (Rpc::default(), None),
+    }
+}
+
+// Picks RPCs in proportion to their `weight`, with smooth weighted round robin.
+//
+// Every pick, each RPC's `current_weight` goes up by its weight, the RPC with the
+// highest one gets picked and has it lowered by the total. Picks get spread out
+// evenly instead of the heaviest RPC getting a burst of them in a row.
+//
+// RPCs with a weight of 0 don't get picked. If every RPC has a weight of 0,
+// they all get picked equally.
+fn weighted(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+    let unweighted = list.iter().all(|rpc| rpc.weight == 0);
+    let weight = |rpc: &Rpc| if unweighted { 1 } else { rpc.weight as i64 };
+
+    let mut total = 0;
+    let mut choice: Option<usize> = None;
+    for index in 0..list.len() {
+        let weight = weight(&list[index]);
+        if weight == 0 {
+            continue;
+        }
+
+        list[index].current_weight += weight;
+        total += weight;
+        if choice.map_or(true, |choice| {
+            list[index].current_weight > list[choice].current_weight
+        }) {
+            choice = Some(index);
+        }
+    }
+
+    let choice = choice.expect("at least one RPC has a weight");
+    list[choice].current_weight -= total;
+
+    (list[choice].clone(), Some(choice))
+}
+
+// Picks an RPC at random, weighted by the inverse of its latency.
+//
+// RPCs we don't have a latency for yet get the highest weight so they get measured.
+fn least_latency(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+    // Latencies under 1ms are treated as 1ms so one RPC can't take all the weight
+    let weights: Vec<f64> = list
+        .iter()
+        .map(|rpc| 1.0 / rpc.status.latency_ms.max(1.0))
+        .collect();
+
+    let mut target = rand::thread_rng().gen::<f64>() * weights.iter().sum::<f64>();
+    let mut choice = list.len() - 1;
+    for (index, weight) in weights.iter().enumerate() {
+        if target < *weight {
+            choice = index;
+            break;
+        }
+        target -= weight;
+    }
+
+    (list[choice].clone(), Some(choice))
 }
 
 // Sorting algo
-pub fn argsort(data: &Vec<Rpc>) -> Vec<usize> {
+pub fn argsort(data: &[Rpc]) -> Vec<usize> {
     let mut indices = (0..data.len()).collect::<Vec<usize>>();
 
     // Use sort_by_cached_key with a closure that compares latency
@@ -35,7 +183,7 @@ pub fn argsort(data: &Vec<Rpc>) -> Vec<usize> {
     not(feature = "selection-random"),
     not(feature = "old-weighted-round-robin"),
 ))]
-fn algo(list: &mut Vec<Rpc>) -> (Rpc, Option<usize>) {
+fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
     // Sort by latency
     let indices = argsort(list);
 
@@ -73,8 +221,6 @@ fn algo(list: &mut Vec<Rpc>) -> (Rpc, Option<usize>) {
     feature = "selection-random"
 ))]
 fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
-    use rand::Rng;
-
     let mut rng = rand::thread_rng();
     let index = rng.gen_range(0..list.len());
     (list[index].clone(), Some(index))
@@ -84,7 +230,7 @@ fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
     feature = "selection-weighed-round-robin",
     feature = "old-weighted-round-robin",
 ))]
-fn algo(list: &mut Vec<Rpc>) -> (Rpc, Option<usize>) {
+fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
     // Sort by latency
     let indices = argsort(list);
 
@@ -163,6 +309,172 @@ mod tests {
         assert_eq!(index, Some(1));
     }
 
+    #[test]
+    fn test_pick_least_latency() {
+        let mut fast = Rpc::default();
+        fast.status.latency_ms = 10.0;
+        let mut slow = Rpc::default();
+        slow.status.latency_ms = 90.0;
+
+        let mut rpc_list = vec![slow, fast];
+
+        let mut fast_picks = 0;
+        for _ in 0..1000 {
+            if pick_with(&mut rpc_list, SelectionStrategy::LeastLatency).1 == Some(1) {
+                fast_picks += 1;
+            }
+        }
+
+        // Should be around 900
+        assert!(fast_picks > 800, "fast RPC picked {} times", fast_picks);
+    }
+
+    #[test]
+    fn test_pick_weighted() {
+        let mut paid = Rpc::default();
+        paid.weight = 70;
+        let mut free = Rpc::default();
+        free.weight = 30;
+        let mut off = Rpc::default();
+        off.weight = 0;
+
+        let mut rpc_list = vec![paid, free, off];
+
+        let mut picks = [0; 3];
+        for _ in 0..1000 {
+            picks[pick_with(&mut rpc_list, SelectionStrategy::Weighted)
+                .1
+                .unwrap()] += 1;
+        }
+
+        assert_eq!(picks, [700, 300, 0]);
+
+        // Picks are spread out instead of coming in bursts
+        let mut rpc_list = vec![Rpc::default(), Rpc::default()];
+        rpc_list[0].weight = 2;
+        let order: Vec<usize> = (0..6)
+            .map(|_| {
+                pick_with(&mut rpc_list, SelectionStrategy::Weighted)
+                    .1
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(order, [0, 1, 0, 0, 1, 0]);
+
+        // Every RPC gets picked if none have any weight
+        for rpc in rpc_list.iter_mut() {
+            rpc.weight = 0;
+        }
+        let mut picks = [0; 2];
+        for _ in 0..300 {
+            picks[pick_with(&mut rpc_list, SelectionStrategy::Weighted)
+                .1
+                .unwrap()] += 1;
+        }
+        assert_eq!(picks, [150, 150]);
+    }
+
+    #[test]
+    fn test_pick_filtered() {
+        let mut archive = Rpc::default();
+        archive.archive = true;
+        archive.status.latency = 50.0;
+        let mut full = Rpc::default();
+        full.status.latency = 1.0;
+
+        let mut rpc_list = vec![full.clone(), archive, full];
+
+        for _ in 0..10 {
+            let (rpc, index) = pick_filtered(&mut rpc_list, SelectionStrategy::RoundRobin, |rpc| {
+                rpc.archive
+            });
+            assert!(rpc.archive);
+            assert_eq!(index, Some(1));
+        }
+
+        rpc_list[1].archive = false;
+        let (_, index) = pick_filtered(&mut rpc_list, SelectionStrategy::RoundRobin, |rpc| {
+            rpc.archive
+        });
+        assert_eq!(index, None);
+    }
+
+    #[test]
+    fn test_pick_hash_affinity() {
+        let mut rpc_list: Vec<Rpc> = (0..5)
+            .map(|i| Rpc::new(format!("http://rpc{}.example", i), None, 0, 0, 1.0))
+            .collect();
+        let strategy = SelectionStrategy::HashAffinity;
+        let keys: Vec<[u8; 8]> = (0..100u64).map(u64::to_be_bytes).collect();
+        let picks: Vec<String> = keys
+            .iter()
+            .map(|key| pick_keyed(&mut rpc_list, strategy, key, |_| true).0.url)
+            .collect();
+
+        // Same request, same RPC
+        assert_eq!(
+            pick_keyed(&mut rpc_list, strategy, &keys[0], |_| true)
+                .0
+                .url,
+            picks[0]
+        );
+        // Requests get spread over the pool
+        assert!(rpc_list.iter().all(|rpc| picks.contains(&rpc.url)));
+
+        // Only the requests of an RPC leaving the pool move
+        let removed = rpc_list.remove(2).url;
+        for (key, pick) in keys.iter().zip(picks.iter()) {
+            let (rpc, _) = pick_keyed(&mut rpc_list, strategy, key, |_| true);
+            if *pick != removed {
+                assert_eq!(rpc.url, *pick);
+            }
+        }
+
+        // Filtered out RPCs are skipped
+        let (rpc, _) = pick_keyed(&mut rpc_list, strategy, &keys[0], |rpc| rpc.url != picks[0]);
+        assert_ne!(rpc.url, picks[0]);
+        assert_eq!(
+            pick_keyed(&mut rpc_list, strategy, &keys[0], |_| false).1,
+            None
+        );
+    }
+
+    #[test]
+    fn test_skip_cooling_down() {
+        let mut rpc_list = vec![Rpc::default(), Rpc::default()];
+        rpc_list[0].start_cooldown();
+
+        for strategy in [
+            SelectionStrategy::RoundRobin,
+            SelectionStrategy::LeastLatency,
+            SelectionStrategy::Weighted,
+            SelectionStrategy::Random,
+        ] {
+            for _ in 0..10 {
+                assert_eq!(pick_with(&mut rpc_list, strategy).1, Some(1));
+                assert_eq!(pick_filtered(&mut rpc_list, strategy, |_| true).1, Some(1));
+            }
+        }
+
+        // Nothing to pick while every RPC is cooling down
+        rpc_list[1].start_cooldown();
+        assert_eq!(pick(&mut rpc_list).1, None);
+    }
+
+    #[test]
+    fn test_pick_random() {
+        let mut rpc_list = vec![Rpc::default(), Rpc::default(), Rpc::default()];
+
+        let mut picked = [false; 3];
+        for _ in 0..100 {
+            let (_, index) = pick_with(&mut rpc_list, SelectionStrategy::Random);
+            picked[index.unwrap()] = true;
+        }
+
+        assert_eq!(picked, [true; 3]);
+        assert_eq!(pick_with(&mut [], SelectionStrategy::Random).1, None);
+    }
+
     // Test max_delay when picking rpcs
     #[test]
     fn test_pick_max_delay() {
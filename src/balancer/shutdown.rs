@@ -0,0 +1,196 @@
+// Graceful shutdown.
+//
+// On SIGINT/SIGTERM we stop accepting new connections, tell the ones we're
+// serving to close once their in-flight request is done, and wait for them
+// to do so for up to `shutdown_grace_ms` before flushing the cache and exiting.
+//
+// The same tracker caps how many connections we serve at once, so a connection
+// flood can't run us out of memory or file descriptors.
+use std::{
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    signal::ctrl_c,
+    sync::{
+        Notify,
+        OwnedSemaphorePermit,
+        Semaphore,
+    },
+    time::timeout,
+};
+
+// How long a new connection waits for a slot when we're at `max_concurrent_connections`
+pub const CONNECTION_SLOT_WAIT: Duration = Duration::from_secs(1);
+
+// Resolves once we receive SIGINT, or SIGTERM on unix
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{
+            signal,
+            SignalKind,
+        };
+
+        let mut terminate = signal(SignalKind::terminate())
+            .expect("\x1b[31mErr:\x1b[0m Could not listen for SIGTERM!");
+
+        tokio::select! {
+            _ = ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = ctrl_c().await;
+    }
+}
+
+// Counts the connections being served, so we can wait for them to finish
+#[derive(Debug, Default)]
+pub struct ConnectionTracker {
+    active: AtomicUsize,
+    idle: Notify,
+    // Slots for connections, unlimited if `None`
+    slots: Option<Arc<Semaphore>>,
+}
+
+impl ConnectionTracker {
+    // Serve up to `max` connections at once, 0 for no limit
+    pub fn with_limit(max: usize) -> Self {
+        ConnectionTracker {
+            slots: (max != 0).then(|| Arc::new(Semaphore::new(max))),
+            ..Default::default()
+        }
+    }
+
+    // Count a connection until the returned guard is dropped.
+    //
+    // If we're at the limit, waits up to `wait` for a free slot and returns `None`
+    // if none freed up in time.
+    pub async fn admit(self: &Arc<Self>, wait: Duration) -> Option<ConnectionGuard> {
+        let slot = match &self.slots {
+            Some(slots) => {
+                Some(
+                    timeout(wait, slots.clone().acquire_owned())
+                        .await
+                        .ok()?
+                        .ok()?,
+                )
+            }
+            None => None,
+        };
+
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Some(ConnectionGuard {
+            tracker: self.clone(),
+            _slot: slot,
+        })
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    // Wait until there are no connections left
+    pub async fn wait_idle(&self) {
+        loop {
+            // Register before checking so we can't miss the last connection closing
+            let idle = self.idle.notified();
+            if self.active() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    tracker: Arc<ConnectionTracker>,
+    // Released when the connection closes
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.tracker.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.tracker.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_idle() {
+        let tracker = Arc::new(ConnectionTracker::default());
+
+        // Nothing to wait for
+        timeout(Duration::from_millis(50), tracker.wait_idle())
+            .await
+            .unwrap();
+
+        let first = tracker.admit(Duration::ZERO).await.unwrap();
+        let second = tracker.admit(Duration::ZERO).await.unwrap();
+        assert_eq!(tracker.active(), 2);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(first);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(second);
+        });
+
+        timeout(Duration::from_secs(1), tracker.wait_idle())
+            .await
+            .unwrap();
+        assert_eq!(tracker.active(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let tracker = Arc::new(ConnectionTracker::with_limit(2));
+        let wait = Duration::from_millis(20);
+
+        let first = tracker.admit(wait).await.unwrap();
+        let _second = tracker.admit(wait).await.unwrap();
+        assert!(tracker.admit(wait).await.is_none());
+        assert_eq!(tracker.active(), 2);
+
+        // Waiting connections get the slot of the next one to close
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(first);
+        });
+        assert!(tracker.admit(Duration::from_secs(1)).await.is_some());
+
+        // Unlimited
+        let tracker = Arc::new(ConnectionTracker::with_limit(0));
+        let mut connections = Vec::new();
+        for _ in 0..10 {
+            connections.push(tracker.admit(wait).await.unwrap());
+        }
+        assert!(tracker.admit(wait).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_wait_idle_times_out() {
+        let tracker = Arc::new(ConnectionTracker::default());
+        let _connection = tracker.admit(Duration::ZERO).await.unwrap();
+
+        assert!(timeout(Duration::from_millis(50), tracker.wait_idle())
+            .await
+            .is_err());
+    }
+}
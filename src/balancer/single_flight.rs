@@ -0,0 +1,151 @@
+// Request coalescing.
+//
+// When many clients send the same request at once, like asking for the
+// latest block right after it's produced, only the first one is forwarded
+// to an RPC. Identical requests that come in while it's in-flight wait for
+// it and get the same response.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+};
+
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+// What the first request ended with, shared with everyone waiting on it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlightOutcome {
+    Response(String),
+    NoRpcAvailable,
+    TimedOut,
+    // Every RPC we tried failed, the error object of the last failure
+    Error(Value),
+}
+
+// Flights in progress, keyed by cache key
+type InFlight = HashMap<Vec<u8>, (u64, broadcast::Sender<FlightOutcome>)>;
+
+#[derive(Debug)]
+pub enum Flight {
+    // We're the first, send the request and `finish` the flight
+    Leader(FlightGuard),
+    // An identical request is in-flight, wait for its outcome
+    Follower(broadcast::Receiver<FlightOutcome>),
+    // The request can't be coalesced
+    Disabled,
+}
+
+#[derive(Debug, Default)]
+pub struct SingleFlight {
+    in_flight: Mutex<InFlight>,
+    next_id: AtomicU64,
+}
+
+impl SingleFlight {
+    // Join the flight for `key`, starting one if there's none in-flight.
+    // Only requests that are safe to send once for many clients should be coalesced.
+    pub fn join(self: &Arc<Self>, key: &[u8], coalesce: bool) -> Flight {
+        if !coalesce {
+            return Flight::Disabled;
+        }
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some((_, tx)) = in_flight.get(key) {
+            return Flight::Follower(tx.subscribe());
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, _) = broadcast::channel(1);
+        in_flight.insert(key.to_vec(), (id, tx.clone()));
+
+        Flight::Leader(FlightGuard {
+            flights: Arc::clone(self),
+            key: key.to_vec(),
+            id,
+            tx,
+        })
+    }
+
+    // Remove the flight for `key` if it's still the one with `id`
+    fn land(&self, key: &[u8], id: u64) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.get(key).map(|(current, _)| *current) == Some(id) {
+            in_flight.remove(key);
+        }
+    }
+}
+
+// Held by the request that was sent to an RPC.
+//
+// If it's dropped without finishing, like when the client disconnects,
+// the flight is removed and whoever was waiting sends their own request.
+#[derive(Debug)]
+pub struct FlightGuard {
+    flights: Arc<SingleFlight>,
+    key: Vec<u8>,
+    id: u64,
+    tx: broadcast::Sender<FlightOutcome>,
+}
+
+impl FlightGuard {
+    // Share the outcome with everyone waiting. New requests after this start a new flight.
+    pub fn finish(self, outcome: FlightOutcome) {
+        self.flights.land(&self.key, self.id);
+        let _ = self.tx.send(outcome);
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        self.flights.land(&self.key, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_single_flight() {
+        let flights = Arc::new(SingleFlight::default());
+
+        let leader = match flights.join(b"key", true) {
+            Flight::Leader(leader) => leader,
+            _ => panic!("expected to lead"),
+        };
+        let mut follower = match flights.join(b"key", true) {
+            Flight::Follower(follower) => follower,
+            _ => panic!("expected to follow"),
+        };
+        assert!(matches!(flights.join(b"key", false), Flight::Disabled));
+        assert!(matches!(flights.join(b"other", true), Flight::Leader(_)));
+
+        leader.finish(FlightOutcome::TimedOut);
+        assert_eq!(follower.recv().await.unwrap(), FlightOutcome::TimedOut);
+
+        // Finished flights are removed so the next request is sent again
+        assert!(matches!(flights.join(b"key", true), Flight::Leader(_)));
+    }
+
+    #[tokio::test]
+    async fn test_single_flight_dropped() {
+        let flights = Arc::new(SingleFlight::default());
+
+        let leader = flights.join(b"key", true);
+        let mut follower = match flights.join(b"key", true) {
+            Flight::Follower(follower) => follower,
+            _ => panic!("expected to follow"),
+        };
+
+        drop(leader);
+        assert!(follower.recv().await.is_err());
+        assert!(matches!(flights.join(b"key", true), Flight::Leader(_)));
+    }
+}
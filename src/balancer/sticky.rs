@@ -0,0 +1,166 @@
+// Sticky sessions.
+//
+// Clients sending dependent calls, like submitting a transaction and then
+// querying it by hash, can get confused when nodes with slightly different
+// mempools answer them. With sticky sessions enabled, we remember which RPC
+// served each session, and keep sending the session's requests to it for `ttl`
+// after its last request. Sessions only move to another RPC if theirs gets
+// taken out of the active pool or fails the request.
+use hyper::{
+    header::HeaderValue,
+    HeaderMap,
+};
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::RwLock,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+// Header clients can use to name their session instead of using their IP
+pub const SESSION_HEADER: &str = "x-session";
+
+#[derive(Debug, Default)]
+pub struct StickySessions {
+    // How long to keep routing a session to the same RPC. 0 disables sticky sessions
+    ttl: Duration,
+    sessions: RwLock<Sessions>,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    // Session -> (time of the last request, URL of the RPC serving it)
+    routes: HashMap<String, (Instant, String)>,
+    // When expired sessions were last dropped, they're dropped at most once per `ttl`
+    pruned: Option<Instant>,
+}
+
+impl StickySessions {
+    pub fn new(ttl: Duration) -> Self {
+        StickySessions {
+            ttl,
+            sessions: RwLock::new(Sessions::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    // Get the session of a request, from its `X-Session` header or the IP of the client.
+    //
+    // Returns `None` if sticky sessions are disabled or we can't tell.
+    pub fn session(
+        &self,
+        headers: &HeaderMap<HeaderValue>,
+        peer: Option<SocketAddr>,
+    ) -> Option<String> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        match headers
+            .get(SESSION_HEADER)
+            .and_then(|session| session.to_str().ok())
+        {
+            Some(session) if !session.is_empty() => Some(format!("session:{}", session)),
+            _ => peer.map(|peer| format!("ip:{}", peer.ip())),
+        }
+    }
+
+    // Get the URL of the RPC serving `session`, if it hasn't expired.
+    //
+    // Drops the session if it expired.
+    pub fn get(&self, session: &str) -> Option<String> {
+        match self.sessions.read().unwrap().routes.get(session) {
+            Some((time, url)) if time.elapsed() < self.ttl => return Some(url.clone()),
+            Some(_) => {}
+            None => return None,
+        }
+
+        let mut sessions = self.sessions.write().unwrap();
+        // It could have been routed again since we looked
+        if matches!(sessions.routes.get(session), Some((time, _)) if time.elapsed() >= self.ttl) {
+            sessions.routes.remove(session);
+        }
+
+        None
+    }
+
+    // Route `session` to `url`, and restart its TTL.
+    //
+    // Also drops every expired session, if we haven't in the last `ttl`.
+    pub fn insert(&self, session: &str, url: &str) {
+        let mut sessions = self.sessions.write().unwrap();
+        let now = Instant::now();
+        if sessions
+            .pruned
+            .map_or(true, |pruned| now.duration_since(pruned) >= self.ttl)
+        {
+            sessions
+                .routes
+                .retain(|_, (time, _)| now.duration_since(*time) < self.ttl);
+            sessions.pruned = Some(now);
+        }
+        sessions
+            .routes
+            .insert(session.to_string(), (now, url.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let sticky = StickySessions::new(Duration::from_secs(1));
+        let peer: SocketAddr = "10.0.0.1:4242".parse().unwrap();
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            sticky.session(&headers, Some(peer)),
+            Some("ip:10.0.0.1".to_string())
+        );
+        assert_eq!(sticky.session(&headers, None), None);
+
+        headers.insert(SESSION_HEADER, HeaderValue::from_static("alice"));
+        assert_eq!(
+            sticky.session(&headers, Some(peer)),
+            Some("session:alice".to_string())
+        );
+
+        // Disabled
+        let sticky = StickySessions::new(Duration::ZERO);
+        assert_eq!(sticky.session(&headers, Some(peer)), None);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let sticky = StickySessions::new(Duration::from_millis(50));
+
+        sticky.insert("ip:10.0.0.1", "http://a");
+        assert_eq!(sticky.get("ip:10.0.0.1"), Some("http://a".to_string()));
+        assert_eq!(sticky.get("ip:10.0.0.2"), None);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(sticky.get("ip:10.0.0.1"), None);
+        // Expired sessions get dropped when they're looked up
+        assert!(sticky.sessions.read().unwrap().routes.is_empty());
+
+        // And on insert, at most once per TTL
+        sticky.insert("ip:10.0.0.1", "http://a");
+        std::thread::sleep(Duration::from_millis(60));
+        sticky.sessions.write().unwrap().pruned = Some(Instant::now());
+        sticky.insert("ip:10.0.0.2", "http://b");
+        assert_eq!(sticky.sessions.read().unwrap().routes.len(), 2);
+
+        sticky.sessions.write().unwrap().pruned = None;
+        sticky.insert("ip:10.0.0.3", "http://c");
+        assert_eq!(sticky.sessions.read().unwrap().routes.len(), 2);
+    }
+}
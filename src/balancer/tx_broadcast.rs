@@ -0,0 +1,289 @@
+// Broadcasting of `eth_sendRawTransaction`.
+//
+// Instead of submitting transactions to a single RPC, we send them to every
+// RPC in the active pool at once and return the first successful response.
+// That way one slow or broken node can't keep a transaction from propagating.
+// Every send runs in its own task, so the RPCs that are slower than the first
+// one to accept the transaction still get it.
+use crate::rpc::types::Rpc;
+
+use futures::{
+    stream::FuturesUnordered,
+    StreamExt,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use tiny_keccak::{
+    Hasher,
+    Keccak,
+};
+
+use tracing::warn;
+
+use std::time::Duration;
+
+use tokio::time::{
+    timeout_at,
+    Instant,
+};
+
+// Errors nodes return for transactions they already have in their mempool.
+// The transaction made it to the network, so these count as a success.
+const KNOWN_TX_ERRORS: [&str; 3] = ["already known", "nonce too low", "already imported"];
+
+// Returns true if `response` is an error saying the node already has the transaction.
+pub fn is_known_tx_error(response: &str) -> bool {
+    let response: Value = match serde_json::from_str(response) {
+        Ok(response) => response,
+        Err(_) => return false,
+    };
+
+    let message = match response["error"]["message"].as_str() {
+        Some(message) => message.to_lowercase(),
+        None => return false,
+    };
+
+    KNOWN_TX_ERRORS.iter().any(|known| message.contains(known))
+}
+
+// Hash of the raw transaction in `tx`, which is what nodes return when they accept it.
+//
+// Returns `None` if the transaction isn't valid hex.
+pub fn raw_tx_hash(tx: &Value) -> Option<String> {
+    let raw_tx = tx["params"][0].as_str()?.strip_prefix("0x")?;
+    if raw_tx.len() % 2 != 0 {
+        return None;
+    }
+
+    let bytes = (0..raw_tx.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(raw_tx.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    let mut hash = [0u8; 32];
+    let mut keccak = Keccak::v256();
+    keccak.update(&bytes);
+    keccak.finalize(&mut hash);
+
+    Some(format!(
+        "0x{}",
+        hash.iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>()
+    ))
+}
+
+// Turn an error saying the node already has the transaction into the
+// successful response it would've sent if it didn't
+fn known_tx_response(known: String, tx_hash: Option<&str>) -> String {
+    let (Some(tx_hash), Ok(known)) = (tx_hash, serde_json::from_str::<Value>(&known)) else {
+        return known;
+    };
+
+    json!({"jsonrpc": "2.0", "id": known["id"], "result": tx_hash}).to_string()
+}
+
+// Send `tx` to every RPC in `rpcs` at once.
+//
+// Returns the first successful response. If none of the RPCs accepted the
+// transaction, but one says it already has it, returns a successful response
+// with the transaction hash. Otherwise returns the first error response.
+// Returns `None` if no RPC responded within `ttl`.
+pub async fn broadcast_tx(rpcs: &[Rpc], tx: Value, ttl: Duration) -> Option<String> {
+    let deadline = Instant::now() + ttl;
+    let tx_hash = raw_tx_hash(&tx);
+
+    // Tasks keep running after we return, so every RPC gets the transaction
+    let mut pending: FuturesUnordered<_> = rpcs
+        .iter()
+        .cloned()
+        .map(|rpc| {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                // Wait for the RPC to drop below `max_concurrency`, but not past the deadline
+                let _capacity = match timeout_at(deadline, rpc.acquire_capacity()).await {
+                    Ok(capacity) => capacity,
+                    Err(_) => {
+                        warn!("{} is saturated, could not broadcast transaction", rpc.url);
+                        return None;
+                    }
+                };
+                let _in_flight = rpc.track_in_flight();
+                rpc.count_request();
+
+                match timeout_at(deadline, rpc.send_request(tx)).await {
+                    Ok(Ok(rx)) => Some(rx),
+                    Ok(Err(err)) => {
+                        warn!("Could not broadcast transaction to {}: {}", rpc.url, err);
+                        rpc.count_error();
+                        None
+                    }
+                    Err(_) => {
+                        warn!("Timed out broadcasting transaction to {}", rpc.url);
+                        rpc.count_error();
+                        None
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let mut known = None;
+    let mut failed = None;
+    while let Ok(Some(rx)) = timeout_at(deadline, pending.next()).await {
+        let rx = match rx {
+            Ok(Some(rx)) => rx,
+            _ => continue,
+        };
+
+        if !rx.contains("\"error\"") {
+            return Some(rx);
+        }
+
+        if is_known_tx_error(&rx) {
+            known.get_or_insert(rx);
+        } else {
+            failed.get_or_insert(rx);
+        }
+    }
+
+    match known {
+        Some(known) => Some(known_tx_response(known, tx_hash.as_deref())),
+        None => failed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::MockRpc;
+    use serde_json::json;
+
+    async fn mock_rpc(delay: Duration, response: Value) -> (MockRpc, Rpc) {
+        let mock = MockRpc::spawn(delay, move |request| {
+            let mut response = response.clone();
+            response["jsonrpc"] = "2.0".into();
+            response["id"] = request["id"].clone();
+            response
+        })
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        (mock, rpc)
+    }
+
+    fn raw_tx() -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "method": "eth_sendRawTransaction", "params": ["0xf86c"]})
+    }
+
+    #[test]
+    fn test_is_known_tx_error() {
+        assert!(is_known_tx_error(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"already known"}}"#
+        ));
+        assert!(is_known_tx_error(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"Nonce too low"}}"#
+        ));
+        assert!(!is_known_tx_error(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"insufficient funds"}}"#
+        ));
+        assert!(!is_known_tx_error(
+            r#"{"jsonrpc":"2.0","id":1,"result":"0xabc"}"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_reaches_every_rpc() {
+        let (failing_mock, failing) = mock_rpc(
+            Duration::ZERO,
+            json!({"error": {"code": -32000, "message": "insufficient funds"}}),
+        )
+        .await;
+        let (slow_mock, slow) =
+            mock_rpc(Duration::from_millis(50), json!({"result": "0xabc"})).await;
+
+        let rx = broadcast_tx(&[failing, slow], raw_tx(), Duration::from_secs(1)).await;
+
+        assert!(rx.unwrap().contains("0xabc"));
+        assert_eq!(failing_mock.requests(), 1);
+        assert_eq!(slow_mock.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_known_tx() {
+        let (_failing_mock, failing) = mock_rpc(
+            Duration::ZERO,
+            json!({"error": {"code": -32000, "message": "insufficient funds"}}),
+        )
+        .await;
+        let (_known_mock, known) = mock_rpc(
+            Duration::from_millis(20),
+            json!({"error": {"code": -32000, "message": "already known"}}),
+        )
+        .await;
+
+        let rx = broadcast_tx(&[failing, known], raw_tx(), Duration::from_secs(1)).await;
+
+        // Nodes that already have the transaction mean it got through
+        let rx: Value = serde_json::from_str(&rx.unwrap()).unwrap();
+        assert_eq!(rx["id"], 1);
+        assert_eq!(rx["result"], raw_tx_hash(&raw_tx()).unwrap());
+        assert!(rx.get("error").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_continues_after_success() {
+        let (_fast_mock, fast) = mock_rpc(Duration::ZERO, json!({"result": "0xabc"})).await;
+        let (slow_mock, slow) =
+            mock_rpc(Duration::from_millis(100), json!({"result": "0xabc"})).await;
+
+        let rx = broadcast_tx(&[fast, slow], raw_tx(), Duration::from_secs(1)).await;
+        assert!(rx.unwrap().contains("0xabc"));
+
+        // The slow RPC still gets the transaction after we returned
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(slow_mock.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_waits_for_capacity() {
+        let (saturated_mock, mut saturated) =
+            mock_rpc(Duration::ZERO, json!({"result": "0xabc"})).await;
+        saturated.set_max_concurrency(1);
+        let capacity = saturated.acquire_capacity().await;
+
+        // Nothing gets sent to the RPC while it's at its `max_concurrency`
+        let rx = broadcast_tx(&[saturated.clone()], raw_tx(), Duration::from_millis(50)).await;
+        assert!(rx.is_none());
+        assert_eq!(saturated_mock.requests(), 0);
+
+        drop(capacity);
+        let rx = broadcast_tx(&[saturated], raw_tx(), Duration::from_secs(1)).await;
+        assert!(rx.unwrap().contains("0xabc"));
+        assert_eq!(saturated_mock.requests(), 1);
+    }
+
+    #[test]
+    fn test_raw_tx_hash() {
+        let tx = |raw_tx: &str| json!({"params": [raw_tx]});
+
+        assert_eq!(
+            raw_tx_hash(&tx("0x")).unwrap(),
+            "0xc5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+        assert!(raw_tx_hash(&tx("0xf86")).is_none());
+        assert!(raw_tx_hash(&tx("0xzz")).is_none());
+        assert!(raw_tx_hash(&json!({"params": []})).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_timeout() {
+        let (_slow_mock, slow) =
+            mock_rpc(Duration::from_millis(500), json!({"result": "0xabc"})).await;
+
+        let rx = broadcast_tx(&[slow], raw_tx(), Duration::from_millis(50)).await;
+
+        assert!(rx.is_none());
+    }
+}
@@ -0,0 +1,226 @@
+// Replay protection for `eth_sendRawTransaction`.
+//
+// Retries can end up submitting the same transaction to multiple nodes.
+// For setups that want exactly-once submissions, we remember the response
+// for every raw transaction we forwarded for `window`, and return it instead
+// of submitting the transaction again.
+//
+// The first submission reserves the raw tx before it's forwarded, so identical
+// submissions that come in while it's in-flight wait for its response instead
+// of being forwarded too.
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+#[derive(Debug)]
+enum Submission {
+    // Being forwarded, whoever waits on it gets the response once it's remembered
+    Pending(broadcast::Sender<Value>),
+    // Time of submission and response
+    Submitted(Instant, Value),
+}
+
+#[derive(Debug)]
+pub enum Reservation {
+    // The raw tx was submitted within the window, this is its response
+    Submitted(Value),
+    // We're the first to submit the raw tx, forward it and `finish` the guard
+    Reserved(ReplayGuard),
+}
+
+#[derive(Debug, Default)]
+pub struct TxReplayCache {
+    // How long to remember submissions for. 0 disables replay protection
+    window: Duration,
+    // Raw tx -> submission
+    submitted: RwLock<HashMap<String, Submission>>,
+}
+
+impl TxReplayCache {
+    pub fn new(window: Duration) -> Self {
+        TxReplayCache {
+            window,
+            submitted: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    // Returns the raw tx if `tx` is a `eth_sendRawTransaction` request
+    // and replay protection is enabled.
+    pub fn raw_tx<'a>(&self, tx: &'a Value) -> Option<&'a str> {
+        if !self.is_enabled() || tx["method"] != "eth_sendRawTransaction" {
+            return None;
+        }
+
+        tx["params"][0].as_str()
+    }
+
+    // Get the response of a submission of `raw_tx` within the window, or reserve it.
+    //
+    // If the raw tx is being submitted, this waits for that submission. If it
+    // doesn't get remembered, we try to reserve it again.
+    //
+    // Also drops every submission that's outside of the window.
+    pub async fn reserve(self: &Arc<Self>, raw_tx: &str) -> Reservation {
+        loop {
+            let mut rx = {
+                let mut submitted = self.submitted.write().unwrap();
+                submitted.retain(|_, submission| {
+                    match submission {
+                        Submission::Pending(_) => true,
+                        Submission::Submitted(time, _) => time.elapsed() < self.window,
+                    }
+                });
+
+                match submitted.get(raw_tx) {
+                    Some(Submission::Submitted(_, response)) => {
+                        return Reservation::Submitted(response.clone())
+                    }
+                    Some(Submission::Pending(tx)) => tx.subscribe(),
+                    None => {
+                        let (tx, _) = broadcast::channel(1);
+                        submitted.insert(raw_tx.to_string(), Submission::Pending(tx));
+                        return Reservation::Reserved(ReplayGuard {
+                            replay: Arc::clone(self),
+                            raw_tx: raw_tx.to_string(),
+                        });
+                    }
+                }
+            };
+
+            // The sender is only dropped without sending if the submission failed
+            if let Ok(response) = rx.recv().await {
+                return Reservation::Submitted(response);
+            }
+        }
+    }
+}
+
+// Held by the request that forwards the raw tx.
+//
+// If it's dropped without finishing, like when the submission failed or the
+// client disconnected, the reservation is removed and whoever was waiting
+// tries to submit the raw tx themselves.
+#[derive(Debug)]
+pub struct ReplayGuard {
+    replay: Arc<TxReplayCache>,
+    raw_tx: String,
+}
+
+impl ReplayGuard {
+    // Remember the response of the submission and share it with everyone waiting
+    pub fn finish(self, response: Value) {
+        let mut submitted = self.replay.submitted.write().unwrap();
+        let previous = submitted.insert(
+            self.raw_tx.clone(),
+            Submission::Submitted(Instant::now(), response.clone()),
+        );
+        if let Some(Submission::Pending(tx)) = previous {
+            let _ = tx.send(response);
+        }
+    }
+}
+
+impl Drop for ReplayGuard {
+    fn drop(&mut self) {
+        let mut submitted = self.replay.submitted.write().unwrap();
+        if matches!(
+            submitted.get(&self.raw_tx),
+            Some(Submission::Pending(_))
+        ) {
+            submitted.remove(&self.raw_tx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    async fn reserved(replay: &Arc<TxReplayCache>, raw_tx: &str) -> ReplayGuard {
+        match replay.reserve(raw_tx).await {
+            Reservation::Reserved(guard) => guard,
+            Reservation::Submitted(_) => panic!("expected to reserve"),
+        }
+    }
+
+    #[test]
+    fn test_raw_tx() {
+        let replay = TxReplayCache::new(Duration::from_secs(1));
+
+        let tx = json!({"method": "eth_sendRawTransaction", "params": ["0xf86c"]});
+        assert_eq!(replay.raw_tx(&tx), Some("0xf86c"));
+
+        let tx = json!({"method": "eth_call", "params": ["0xf86c"]});
+        assert_eq!(replay.raw_tx(&tx), None);
+
+        // Disabled
+        let replay = TxReplayCache::new(Duration::ZERO);
+        let tx = json!({"method": "eth_sendRawTransaction", "params": ["0xf86c"]});
+        assert_eq!(replay.raw_tx(&tx), None);
+    }
+
+    #[tokio::test]
+    async fn test_window() {
+        let replay = Arc::new(TxReplayCache::new(Duration::from_millis(50)));
+
+        reserved(&replay, "0xf86c").await.finish(json!("response"));
+        assert!(matches!(
+            replay.reserve("0xf86c").await,
+            Reservation::Submitted(response) if response == "response"
+        ));
+        reserved(&replay, "0xf86d").await;
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        reserved(&replay, "0xf86c").await;
+
+        // Expired submissions get dropped when reserving
+        reserved(&replay, "0xf86e").await.finish(json!("response"));
+        assert_eq!(replay.submitted.read().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pending_submission() {
+        let replay = Arc::new(TxReplayCache::new(Duration::from_secs(1)));
+
+        // Identical submissions wait for the first one
+        let guard = reserved(&replay, "0xf86c").await;
+        let waiting = tokio::spawn({
+            let replay = Arc::clone(&replay);
+            async move { replay.reserve("0xf86c").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiting.is_finished());
+
+        guard.finish(json!("response"));
+        assert!(matches!(
+            waiting.await.unwrap(),
+            Reservation::Submitted(response) if response == "response"
+        ));
+
+        // If the first one fails, the next one gets to submit
+        let guard = reserved(&replay, "0xf86d").await;
+        let waiting = tokio::spawn({
+            let replay = Arc::clone(&replay);
+            async move { replay.reserve("0xf86d").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(guard);
+        assert!(matches!(waiting.await.unwrap(), Reservation::Reserved(_)));
+    }
+}
@@ -0,0 +1,201 @@
+// Response validation.
+//
+// Buggy RPCs sometimes respond with garbage, like a block without a hash
+// or a number that isn't a number. When enabled, responses to well-known
+// methods are checked against the shape the spec says they should have,
+// and responses that don't match are treated as node errors.
+use serde_json::Value;
+
+// Check that `response` is a valid JSON-RPC response to `call`.
+//
+// Error responses are valid, they're a legitimate answer from the node.
+// Methods we don't know the shape of are always valid.
+pub fn validate_response(call: &Value, response: &str) -> Result<(), String> {
+    let method = call["method"].as_str().unwrap_or_default();
+    let response: Value =
+        serde_json::from_str(response).map_err(|_| "response is not valid JSON".to_string())?;
+
+    if response.get("error").is_some() {
+        return Ok(());
+    }
+
+    let result = match response.get("result") {
+        Some(result) => result,
+        None => return Err("response has no result or error".to_string()),
+    };
+
+    match method {
+        "eth_blockNumber"
+        | "eth_chainId"
+        | "eth_gasPrice"
+        | "eth_getBalance"
+        | "eth_getTransactionCount"
+        | "eth_estimateGas"
+        | "eth_maxPriorityFeePerGas"
+        | "net_peerCount" => expect_quantity(result, "result"),
+        "eth_getCode" | "eth_call" | "eth_getStorageAt" => expect_data(result, "result"),
+        // The pending block isn't sealed, so it has no hash yet
+        "eth_getBlockByNumber" if call["params"][0] == "pending" => {
+            validate_block(result, &["number", "parentHash"])
+        }
+        "eth_getBlockByNumber" | "eth_getBlockByHash" => {
+            validate_block(result, &["number", "hash", "parentHash"])
+        }
+        "eth_getTransactionByHash" => validate_nullable_object(result, &["hash", "from"]),
+        "eth_getTransactionReceipt" => {
+            validate_nullable_object(result, &["transactionHash", "blockHash", "blockNumber"])?;
+            match result.get("logs") {
+                Some(logs) if !logs.is_array() => Err("receipt logs are not an array".to_string()),
+                _ => Ok(()),
+            }
+        }
+        "eth_getLogs" => {
+            match result.is_array() {
+                true => Ok(()),
+                false => Err("logs are not an array".to_string()),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+// Blocks are either `null` if we don't have them, or an object with `fields`
+fn validate_block(block: &Value, fields: &[&str]) -> Result<(), String> {
+    validate_nullable_object(block, fields)?;
+
+    if block.is_null() {
+        return Ok(());
+    }
+
+    expect_quantity(&block["number"], "block number")?;
+    match block.get("transactions") {
+        Some(transactions) if transactions.is_array() => Ok(()),
+        _ => Err("block transactions are not an array".to_string()),
+    }
+}
+
+// Check that `value` is either null, or an object with all of `fields`
+fn validate_nullable_object(value: &Value, fields: &[&str]) -> Result<(), String> {
+    if value.is_null() {
+        return Ok(());
+    }
+
+    let object = value
+        .as_object()
+        .ok_or_else(|| "result is not an object".to_string())?;
+
+    for field in fields {
+        if object.get(*field).map_or(true, Value::is_null) {
+            return Err(format!("result is missing `{}`", field));
+        }
+    }
+
+    Ok(())
+}
+
+// Quantities are hex encoded with no leading zeroes, like `0x1a`
+fn expect_quantity(value: &Value, name: &str) -> Result<(), String> {
+    match value.as_str().and_then(|value| value.strip_prefix("0x")) {
+        Some(digits)
+            if !digits.is_empty()
+                && digits.chars().all(|c| c.is_ascii_hexdigit())
+                && (digits == "0" || !digits.starts_with('0')) =>
+        {
+            Ok(())
+        }
+        _ => Err(format!("{} is not a hex quantity", name)),
+    }
+}
+
+// Data is hex encoded bytes, like `0x` or `0x00ff`
+fn expect_data(value: &Value, name: &str) -> Result<(), String> {
+    match value.as_str().and_then(|value| value.strip_prefix("0x")) {
+        Some(digits) if digits.len() % 2 == 0 && digits.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(())
+        }
+        _ => Err(format!("{} is not hex data", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn validate(method: &str, response: &str) -> Result<(), String> {
+        validate_response(&json!({"method": method, "params": []}), response)
+    }
+
+    #[test]
+    fn test_validate_block() {
+        let valid = r#"{"jsonrpc":"2.0","id":1,"result":{"number":"0x10","hash":"0xabcd","parentHash":"0x1234","transactions":[]}}"#;
+        assert!(validate("eth_getBlockByNumber", valid).is_ok());
+
+        // Missing blocks are fine
+        let missing = r#"{"jsonrpc":"2.0","id":1,"result":null}"#;
+        assert!(validate("eth_getBlockByNumber", missing).is_ok());
+
+        let no_hash = r#"{"jsonrpc":"2.0","id":1,"result":{"number":"0x10","parentHash":"0x1234","transactions":[]}}"#;
+        assert!(validate("eth_getBlockByNumber", no_hash).is_err());
+
+        let bad_number = r#"{"jsonrpc":"2.0","id":1,"result":{"number":16,"hash":"0xabcd","parentHash":"0x1234","transactions":[]}}"#;
+        assert!(validate("eth_getBlockByHash", bad_number).is_err());
+
+        let not_object = r#"{"jsonrpc":"2.0","id":1,"result":"0x10"}"#;
+        assert!(validate("eth_getBlockByNumber", not_object).is_err());
+    }
+
+    #[test]
+    fn test_validate_pending_block() {
+        // Pending blocks aren't sealed yet, so they have no hash, nonce or miner
+        let pending = r#"{"jsonrpc":"2.0","id":1,"result":{"number":"0x10","hash":null,"nonce":null,"miner":null,"parentHash":"0x1234","transactions":[]}}"#;
+        let call = |tag| json!({"method": "eth_getBlockByNumber", "params": [tag, false]});
+
+        assert!(validate_response(&call("pending"), pending).is_ok());
+        assert!(validate_response(&call("latest"), pending).is_err());
+
+        // Still has to look like a block
+        let bad_number = r#"{"jsonrpc":"2.0","id":1,"result":{"number":16,"hash":null,"parentHash":"0x1234","transactions":[]}}"#;
+        assert!(validate_response(&call("pending"), bad_number).is_err());
+    }
+
+    #[test]
+    fn test_validate_quantity() {
+        let valid = r#"{"jsonrpc":"2.0","id":1,"result":"0x1a"}"#;
+        assert!(validate("eth_blockNumber", valid).is_ok());
+        let zero = r#"{"jsonrpc":"2.0","id":1,"result":"0x0"}"#;
+        assert!(validate("eth_blockNumber", zero).is_ok());
+
+        for invalid in [r#""0x""#, r#""0x01""#, r#""1a""#, r#""0xzz""#, "26", "null"] {
+            let response = format!(r#"{{"jsonrpc":"2.0","id":1,"result":{}}}"#, invalid);
+            assert!(
+                validate("eth_blockNumber", &response).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_other() {
+        // Errors are a valid response
+        let error = r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"oops"}}"#;
+        assert!(validate("eth_getBlockByNumber", error).is_ok());
+
+        // Unknown methods aren't checked
+        let unknown = r#"{"jsonrpc":"2.0","id":1,"result":42}"#;
+        assert!(validate("vendor_method", unknown).is_ok());
+
+        let empty = r#"{"jsonrpc":"2.0","id":1}"#;
+        assert!(validate("vendor_method", empty).is_err());
+        assert!(validate("eth_blockNumber", "not json").is_err());
+
+        let code = r#"{"jsonrpc":"2.0","id":1,"result":"0x"}"#;
+        assert!(validate("eth_getCode", code).is_ok());
+        let odd = r#"{"jsonrpc":"2.0","id":1,"result":"0x123"}"#;
+        assert!(validate("eth_call", odd).is_err());
+
+        let logs = r#"{"jsonrpc":"2.0","id":1,"result":{}}"#;
+        assert!(validate("eth_getLogs", logs).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+// Cache warmup.
+//
+// Sends a list of requests to the RPCs and caches the responses, following
+// the same caching rules as requests coming from users. Used on startup with
+// `cache_warmup`, and by `blutgang_warm`.
+//
+// Up to `WARMUP_PARALLELISM` requests are in-flight at once, so large lists
+// don't take forever without flooding the RPCs.
+use crate::{
+    balancer::{
+        format::{
+            cache_key,
+            replace_block_tags,
+        },
+        processing::{
+            cache_querry,
+            CacheArgs,
+        },
+        selection::select::pick,
+    },
+    Rpc,
+};
+
+use tracing::info;
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use blake3::hash;
+use futures::{
+    stream,
+    StreamExt,
+};
+use serde_json::Value;
+use tokio::time::timeout;
+
+// Max warmup requests in-flight at once
+const WARMUP_PARALLELISM: usize = 8;
+
+// Warm the cache with `requests`, returning the result of each one in order.
+pub async fn warm_cache(
+    requests: &[Value],
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    cache_args: &CacheArgs,
+    ttl: u128,
+) -> Vec<Result<(), String>> {
+    let results: Vec<Result<(), String>> = stream::iter(requests.to_vec())
+        .map(|request| warm_request(request, rpc_list, cache_args, ttl))
+        .buffered(WARMUP_PARALLELISM)
+        .collect()
+        .await;
+
+    let warmed = results.iter().filter(|result| result.is_ok()).count();
+    info!(
+        "Cache warmup done, {}/{} requests succeeded.",
+        warmed,
+        requests.len()
+    );
+
+    results
+}
+
+async fn warm_request(
+    mut tx: Value,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    cache_args: &CacheArgs,
+    ttl: u128,
+) -> Result<(), String> {
+    if !tx.is_object() {
+        return Err("Request is not an object".to_string());
+    }
+
+    if tx.get("jsonrpc").is_none() {
+        tx["jsonrpc"] = "2.0".into();
+    }
+    tx["id"] = Value::Null;
+
+    // Hash the request the same way we do for incoming requests,
+    // so the cache key matches what users will send.
+    let tx = replace_block_tags(&mut tx, &cache_args.named_numbers, &cache_args.block_params);
+    let tx_hash = hash(cache_key(&tx, &cache_args.block_params).as_bytes());
+
+    let (rpc, rpc_position) = {
+        let mut rpc_list = rpc_list.write().unwrap();
+        pick(&mut rpc_list)
+    };
+    if rpc_position.is_none() {
+        return Err("No RPC available".to_string());
+    }
+
+    let rx = match timeout(
+        Duration::from_millis(ttl.try_into().unwrap()),
+        rpc.send_request(tx.clone()),
+    )
+    .await
+    {
+        Ok(Ok(rx)) => rx,
+        Ok(Err(err)) => return Err(err.to_string()),
+        Err(_) => return Err("Request timed out".to_string()),
+    };
+
+    if rx.contains("\"error\"") {
+        return Err(rx);
+    }
+
+    let mut rx = cache_args.response_transform.apply(rx);
+    cache_querry(&mut rx, tx, tx_hash, cache_args);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::MockRpc;
+    use serde_json::json;
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn test_warm_cache() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x6080"}),
+        )
+        .await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            mock.url.clone(),
+            None,
+            0,
+            0,
+            1.0,
+        )]));
+
+        let (_finalized_tx, finalized_rx) = watch::channel(10);
+        let cache_args = CacheArgs {
+            finalized_rx,
+            cache: CacheArgs::temporary_cache(),
+            ..CacheArgs::default()
+        };
+
+        let requests = vec![
+            json!({"method": "eth_getCode", "params": ["0x01", "0xa"]}),
+            json!("not a request"),
+        ];
+        let results = warm_cache(&requests, &rpc_list, &cache_args, 1000).await;
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+
+        // Cached under the same key as a user request
+        let user_request =
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getCode", "params": ["0x01", "0xa"]});
+        let tx_hash = hash(cache_key(&user_request, &cache_args.block_params).as_bytes());
+        assert!(cache_args.cache.get(cache_args.key(tx_hash.as_bytes())).unwrap().is_some());
+    }
+}
@@ -1,36 +1,76 @@
-use crate::config::setup::{
-    TAGLINE,
-    VERSION_STR,
+use crate::{
+    balancer::{
+        logs_shards::SHARD_PREFIX,
+        processing::{
+            expiry_key,
+            CACHE_SCHEMA_VERSION,
+            CACHE_VERSION_PREFIX,
+            ERROR_PREFIX,
+            EXPIRY_PREFIX,
+            METHOD_PREFIX,
+            REKEYED_SCHEMA_VERSION,
+            TX_HASH_LEN,
+        },
+    },
+    config::setup::{
+        TAGLINE,
+        VERSION_STR,
+    },
+};
+use sled::{
+    Db,
+    Tree,
+};
+use tracing::{
+    error,
+    info,
+    warn,
+};
+
+use std::{
+    cell::Cell,
+    fs::{
+        File,
+        OpenOptions,
+    },
+    io::{
+        self,
+        BufReader,
+        BufWriter,
+        Read,
+        Write,
+    },
+    path::Path,
+    sync::Arc,
 };
-use sled::Db;
-use std::sync::Arc;
 
-pub fn setup_data(cache: Arc<Db>) {
+// `blutgang_is_lb` hashed with blake3
+const BLUTGANG_IS_LB_KEY: [u8; 32] = [
+    176, 76, 1, 109, 13, 127, 134, 25, 55, 111, 28, 182, 82, 155, 135, 143, 204, 161, 53, 4, 158,
+    140, 22, 219, 138, 5, 57, 150, 8, 154, 17, 252,
+];
+// `web3_clientVersion` hashed with blake3
+const WEB3_CLIENT_VERSION_KEY: [u8; 32] = [
+    36, 20, 170, 125, 105, 107, 149, 148, 52, 126, 215, 218, 112, 55, 222, 60, 186, 44, 67, 121,
+    225, 160, 31, 209, 9, 99, 81, 233, 137, 37, 62, 79,
+];
+// Schema version and `cache_version` the cache was last used with
+const CACHE_VERSION_KEY: &[u8] = b"cache_version";
+
+pub fn setup_data(cache: Arc<Db>, cache_version: u8) {
     let version_json = format!(
         "{{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":\"{}; {}\"}}",
         VERSION_STR, TAGLINE
     );
 
-    println!("\x1b[35mInfo:\x1b[0m Starting {}", VERSION_STR);
+    info!("Starting {}", VERSION_STR);
 
     // Insert kv pair `blutgang_is_lb` `true` to know what we're interacting with
     // `blutgang_is_lb` is cached as a blake3 cache
-    let _ = cache.insert(
-        [
-            176, 76, 1, 109, 13, 127, 134, 25, 55, 111, 28, 182, 82, 155, 135, 143, 204, 161, 53,
-            4, 158, 140, 22, 219, 138, 5, 57, 150, 8, 154, 17, 252,
-        ],
-        version_json.as_bytes(),
-    );
+    let _ = cache.insert(BLUTGANG_IS_LB_KEY, version_json.as_bytes());
     // Insert kv pair `web3_clientVersion` `true` to know what we're interacting with
     // `web3_clientVersion` is cached as a blake3 cache
-    let _ = cache.insert(
-        [
-            36, 20, 170, 125, 105, 107, 149, 148, 52, 126, 215, 218, 112, 55, 222, 60, 186, 44, 67,
-            121, 225, 160, 31, 209, 9, 99, 81, 233, 137, 37, 62, 79,
-        ],
-        version_json.as_bytes(),
-    );
+    let _ = cache.insert(WEB3_CLIENT_VERSION_KEY, version_json.as_bytes());
 
     // Insert which hashing algo we're using based on the selected features.
     // If `xxhash` is enabled we're using xxhash3, otherwise blake3.
@@ -39,16 +79,502 @@ pub fn setup_data(cache: Arc<Db>) {
     if cfg!(feature = "xxhash") {
         let _ = cache.insert(b"xxhash", b"true");
         if cache.get(b"blake3").unwrap().is_some() {
-            println!("\x1b[31mErr:\x1b[0m Blutgang has detected that your DB is using blake3 while we're currently using xxhash! \
+            error!("Blutgang has detected that your DB is using blake3 while we're currently using xxhash! \
                 Please remove all cache entries and try again.");
-            println!("If you believe this is an error, please open a pull request!");
+            error!("If you believe this is an error, please open a pull request!");
         }
     } else {
         let _ = cache.insert(b"blake3", b"true");
         if cache.get(b"xxhash").unwrap().is_some() {
-            println!("\x1b[31mErr:\x1b[0m Blutgang has detected that your DB is using xxhash while we're currently using blake3! \
+            error!("Blutgang has detected that your DB is using xxhash while we're currently using blake3! \
                 Please remove all cache entries and try again.");
-            println!("If you believe this is an error, please open a pull request!");
+            error!("If you believe this is an error, please open a pull request!");
+        }
+    }
+
+    // Record the versions of the entries we're caching, and tell if they changed.
+    //
+    // Entries cached under another version are left on disk, but never served.
+    // Caches from before versioning are on schema version 0.
+    let mut schema_version = 0;
+    if let Ok(Some(previous)) = cache.get(CACHE_VERSION_KEY) {
+        if let [previous_schema_version, previous_cache_version] = *previous {
+            schema_version = previous_schema_version;
+            if previous_cache_version != cache_version {
+                warn!(
+                    "cache_version changed from {} to {}, entries cached under the old version won't be served.",
+                    previous_cache_version, cache_version
+                );
+            }
+            if (REKEYED_SCHEMA_VERSION..CACHE_SCHEMA_VERSION).contains(&schema_version) {
+                warn!(
+                    "Cache format changed from version {} to {}, old entries won't be served \
+                    unless `lazy_cache_migration` is enabled.",
+                    schema_version, CACHE_SCHEMA_VERSION
+                );
+            }
+        }
+    }
+
+    // Entries keyed in a format we no longer use can't be served or migrated
+    if schema_version < REKEYED_SCHEMA_VERSION {
+        match clear_rekeyed(&cache) {
+            Ok(0) => {}
+            Ok(cleared) => {
+                warn!(
+                    "Cache format changed from version {} to {}, cleared {} keys cached in the old format.",
+                    schema_version, CACHE_SCHEMA_VERSION, cleared
+                );
+            }
+            Err(err) => error!("Could not clear entries cached in an old format: {}", err),
+        }
+    }
+
+    let _ = cache.insert(CACHE_VERSION_KEY, &[CACHE_SCHEMA_VERSION, cache_version]);
+}
+
+// Schema version of the cached entry `key` belongs to, be it the key of the
+// entry itself or its expiry, error or method index key.
+//
+// Returns `None` for keys that don't belong to a cached entry.
+fn entry_schema_version(key: &[u8]) -> Option<u8> {
+    let key = match key.strip_prefix(METHOD_PREFIX) {
+        Some(index) => &index[index.iter().position(|byte| *byte == 0)? + 1..],
+        None => key,
+    };
+    let key = key.strip_prefix(EXPIRY_PREFIX).unwrap_or(key);
+    let key = key.strip_prefix(ERROR_PREFIX).unwrap_or(key);
+
+    // What gets versioned, either a request hash or a logs shard
+    let is_entry = |body: &[u8]| body.len() == TX_HASH_LEN || body.starts_with(SHARD_PREFIX);
+
+    match key.strip_prefix(CACHE_VERSION_PREFIX) {
+        Some([_, version, body @ ..]) if is_entry(body) => Some(*version),
+        Some(_) => None,
+        None if is_entry(key) => Some(0),
+        None => {
+            match key {
+                [version, body @ ..] if *version != 0 && is_entry(body) => Some(*version),
+                _ => None,
+            }
+        }
+    }
+}
+
+// Remove every key `remove` picks from the default cache and the caches of
+// other chains, a batch at a time so big caches aren't held in memory.
+//
+// Returns how many keys were removed.
+fn remove_keys(cache: &Db, remove: impl Fn(&[u8]) -> bool) -> Result<usize, sled::Error> {
+    let mut removed = 0;
+
+    for (_, tree) in cache_trees(cache)? {
+        let mut batch = sled::Batch::default();
+        let mut batched = 0;
+        for entry in tree.iter() {
+            let (key, _) = entry?;
+            if !remove(&key) {
+                continue;
+            }
+
+            batch.remove(key);
+            batched += 1;
+            if batched == REMOVE_BATCH_ENTRIES {
+                tree.apply_batch(std::mem::take(&mut batch))?;
+                removed += batched;
+                batched = 0;
+            }
+        }
+        tree.apply_batch(batch)?;
+        removed += batched;
+    }
+
+    Ok(removed)
+}
+
+// Remove every entry cached under a schema version older than `REKEYED_SCHEMA_VERSION`,
+// along with their expiry, error and method index keys.
+//
+// Returns how many keys were removed.
+fn clear_rekeyed(cache: &Db) -> Result<usize, sled::Error> {
+    remove_keys(cache, |key| {
+        !is_metadata(key)
+            && entry_schema_version(key).is_some_and(|version| version < REKEYED_SCHEMA_VERSION)
+    })
+}
+
+// Cache dumps.
+//
+// A dump is a zstd compressed stream of a header followed by length prefixed
+// tree name/key/value triples, covering the default cache and the cache of every
+// other chain we serve. Only entries that never change get dumped: entries of
+// methods with a TTL and our own metadata are skipped, so a dump can be imported
+// into a cache that's already running.
+//
+// Version 1 dumps only had key/value pairs of the default cache, and can still be imported.
+const DUMP_MAGIC: &[u8] = b"BLUTGANG_CACHE";
+const DUMP_VERSION: u8 = 2;
+
+// Level 19 is slow, but dumps are made rarely and shipped around
+const DUMP_ZSTD_LEVEL: i32 = 19;
+
+// Entries written to sled at once when importing, so big dumps aren't held in memory
+const IMPORT_BATCH_ENTRIES: usize = 10_000;
+
+// Keys removed from sled at once when clearing entries
+const REMOVE_BATCH_ENTRIES: usize = 10_000;
+
+fn hash_algo() -> u8 {
+    u8::from(cfg!(feature = "xxhash"))
+}
+
+// Entries blutgang writes about itself, rather than cached responses
+pub(crate) fn is_metadata(key: &[u8]) -> bool {
+    key == b"xxhash"
+        || key == b"blake3"
+        || key == BLUTGANG_IS_LB_KEY
+        || key == WEB3_CLIENT_VERSION_KEY
+        || key == CACHE_VERSION_KEY
+}
+
+fn is_exportable(cache: &Tree, key: &[u8]) -> bool {
+    if is_metadata(key) || key.starts_with(EXPIRY_PREFIX) {
+        return false;
+    }
+
+    // Entries with an expiry are volatile
+    !matches!(cache.contains_key(expiry_key(key)), Ok(true))
+}
+
+// Trees holding the cache of the other chains we serve, as opposed to their health
+fn is_chain_cache(name: &[u8]) -> bool {
+    name.starts_with(b"chain/") && !name.ends_with(b"/health")
+}
+
+// The default cache, named by an empty name, and the cache of every other chain
+pub(crate) fn cache_trees(cache: &Db) -> Result<Vec<(Vec<u8>, Tree)>, sled::Error> {
+    let mut trees = vec![(Vec::new(), (**cache).clone())];
+    for name in cache.tree_names() {
+        if is_chain_cache(&name) {
+            trees.push((name.to_vec(), cache.open_tree(&name)?));
         }
     }
+
+    Ok(trees)
+}
+
+fn write_chunk(writer: &mut impl Write, chunk: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(chunk.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Cache entry too large"))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(chunk)
+}
+
+// Returns `None` if we're at the end of the dump
+fn read_chunk(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let mut chunk = vec![0; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut chunk)?;
+    Ok(Some(chunk))
+}
+
+// Remove every cached response and error from `cache` and the caches of other
+// chains, keeping metadata.
+//
+// Returns how many entries were removed, not counting expiry and index keys.
+pub fn clear_cache(cache: &Db) -> Result<usize, sled::Error> {
+    let cleared = Cell::new(0);
+    remove_keys(cache, |key| {
+        if is_metadata(key) {
+            return false;
+        }
+        if !key.starts_with(EXPIRY_PREFIX) && !key.starts_with(METHOD_PREFIX) {
+            cleared.set(cleared.get() + 1);
+        }
+        true
+    })?;
+
+    Ok(cleared.get())
+}
+
+// Write all immutable entries of `cache` and the caches of other chains to a new dump at `path`.
+//
+// Returns how many entries were exported. Existing files are never overwritten.
+pub fn export_cache(cache: &Db, path: &Path) -> io::Result<usize> {
+    let sled_err = |err| io::Error::new(io::ErrorKind::Other, err);
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut writer = zstd::stream::Encoder::new(BufWriter::new(file), DUMP_ZSTD_LEVEL)?;
+
+    writer.write_all(DUMP_MAGIC)?;
+    writer.write_all(&[DUMP_VERSION, hash_algo()])?;
+
+    let mut exported = 0;
+    for (name, tree) in cache_trees(cache).map_err(sled_err)? {
+        for entry in tree.iter() {
+            let (key, value) = entry.map_err(sled_err)?;
+            if !is_exportable(&tree, &key) {
+                continue;
+            }
+
+            write_chunk(&mut writer, &name)?;
+            write_chunk(&mut writer, &key)?;
+            write_chunk(&mut writer, &value)?;
+            exported += 1;
+        }
+    }
+
+    writer.finish()?.flush()?;
+    Ok(exported)
+}
+
+// Insert all entries of the dump at `path` into `cache` and the caches of other chains.
+//
+// Returns how many entries were imported. Dumps made with a different hashing
+// algorithm get refused, as their keys would never match.
+pub fn import_cache(cache: &Db, path: &Path) -> io::Result<usize> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+    let sled_err = |err| io::Error::new(io::ErrorKind::Other, err);
+    let mut reader = zstd::stream::Decoder::new(BufReader::new(File::open(path)?))?;
+
+    let mut header = [0; DUMP_MAGIC.len() + 2];
+    reader.read_exact(&mut header)?;
+    if &header[..DUMP_MAGIC.len()] != DUMP_MAGIC {
+        return Err(invalid("Not a blutgang cache dump"));
+    }
+    let version = header[DUMP_MAGIC.len()];
+    if version != 1 && version != DUMP_VERSION {
+        return Err(invalid("Unsupported cache dump version"));
+    }
+    if header[DUMP_MAGIC.len() + 1] != hash_algo() {
+        return Err(invalid("Cache dump uses a different hashing algorithm"));
+    }
+
+    // Entries are grouped by tree, so we only need to keep track of the current one
+    let mut name = Vec::new();
+    let mut tree: Tree = (**cache).clone();
+    let mut batch = sled::Batch::default();
+    let mut batched = 0;
+    let mut imported = 0;
+    loop {
+        // Version 1 dumps only have entries of the default cache
+        let entry_name = match version {
+            1 => Vec::new(),
+            _ => {
+                match read_chunk(&mut reader)? {
+                    Some(entry_name) => entry_name,
+                    None => break,
+                }
+            }
+        };
+        let key = match read_chunk(&mut reader)? {
+            Some(key) => key,
+            None if version == 1 => break,
+            None => return Err(invalid("Truncated cache dump")),
+        };
+        let value = read_chunk(&mut reader)?.ok_or_else(|| invalid("Truncated cache dump"))?;
+
+        if entry_name != name || batched == IMPORT_BATCH_ENTRIES {
+            tree.apply_batch(std::mem::take(&mut batch))
+                .map_err(sled_err)?;
+            batched = 0;
+        }
+        if entry_name != name {
+            if !entry_name.is_empty() && !is_chain_cache(&entry_name) {
+                return Err(invalid("Cache dump has entries outside of the cache"));
+            }
+            tree = match entry_name.is_empty() {
+                true => (**cache).clone(),
+                false => cache.open_tree(&entry_name).map_err(sled_err)?,
+            };
+            name = entry_name;
+        }
+
+        batch.insert(key, value);
+        batched += 1;
+        imported += 1;
+    }
+
+    tree.apply_batch(batch).map_err(sled_err)?;
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn test_export_import_cache() {
+        let path = std::env::temp_dir().join(format!("blutgang_dump_{}.zst", std::process::id()));
+
+        let cache = create_test_cache();
+        setup_data(Arc::new(cache.clone()), 0);
+        cache.insert(b"immutable", b"response").unwrap();
+        cache.insert(b"volatile", b"response").unwrap();
+        cache
+            .insert(expiry_key(b"volatile"), &u64::MAX.to_be_bytes())
+            .unwrap();
+
+        assert_eq!(export_cache(&cache, &path).unwrap(), 1);
+
+        let fresh = create_test_cache();
+        assert_eq!(import_cache(&fresh, &path).unwrap(), 1);
+        assert_eq!(fresh.get(b"immutable").unwrap().unwrap(), b"response");
+        assert!(fresh.get(b"volatile").unwrap().is_none());
+        assert!(fresh.get(BLUTGANG_IS_LB_KEY).unwrap().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_export_import_chain_caches() {
+        let path =
+            std::env::temp_dir().join(format!("blutgang_chain_dump_{}.zst", std::process::id()));
+
+        let cache = create_test_cache();
+        cache.insert(b"default", b"response").unwrap();
+        let chain = cache.open_tree("chain/polygon").unwrap();
+        for i in 0..IMPORT_BATCH_ENTRIES as u32 + 1 {
+            chain.insert(i.to_be_bytes(), b"response").unwrap();
+        }
+        // Health isn't part of the cache
+        let health = cache.open_tree("chain/polygon/health").unwrap();
+        health.insert(b"rpc", b"health").unwrap();
+
+        assert_eq!(
+            export_cache(&cache, &path).unwrap(),
+            IMPORT_BATCH_ENTRIES + 2
+        );
+        // Dumps never overwrite existing files
+        assert_eq!(
+            export_cache(&cache, &path).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+
+        let fresh = create_test_cache();
+        assert_eq!(
+            import_cache(&fresh, &path).unwrap(),
+            IMPORT_BATCH_ENTRIES + 2
+        );
+        assert_eq!(fresh.get(b"default").unwrap().unwrap(), b"response");
+        let chain = fresh.open_tree("chain/polygon").unwrap();
+        assert_eq!(chain.len(), IMPORT_BATCH_ENTRIES + 1);
+        assert!(chain.get(b"default").unwrap().is_none());
+        assert!(!fresh
+            .tree_names()
+            .iter()
+            .any(|name| &name[..] == b"chain/polygon/health"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_import_invalid_dump() {
+        let path =
+            std::env::temp_dir().join(format!("blutgang_bad_dump_{}.zst", std::process::id()));
+        std::fs::write(
+            &path,
+            zstd::encode_all(&b"not a dump at all"[..], 3).unwrap(),
+        )
+        .unwrap();
+
+        let err = import_cache(&create_test_cache(), &path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_clear_cache() {
+        let cache = create_test_cache();
+        setup_data(Arc::new(cache.clone()), 0);
+        cache.insert(b"immutable", b"response").unwrap();
+        cache.insert(b"volatile", b"response").unwrap();
+        cache
+            .insert(expiry_key(b"volatile"), &u64::MAX.to_be_bytes())
+            .unwrap();
+        cache
+            .insert([METHOD_PREFIX, b"eth_call\0volatile"].concat(), b"")
+            .unwrap();
+
+        let chain = cache.open_tree("chain/other").unwrap();
+        chain.insert(b"immutable", b"response").unwrap();
+
+        assert_eq!(clear_cache(&cache).unwrap(), 3);
+        assert_eq!(cache.len(), 4);
+        assert!(cache.get(BLUTGANG_IS_LB_KEY).unwrap().is_some());
+        assert!(cache.get(b"immutable").unwrap().is_none());
+        assert!(chain.is_empty());
+    }
+
+    #[test]
+    fn test_setup_data_clears_rekeyed_entries() {
+        let cache = create_test_cache();
+        cache.insert(CACHE_VERSION_KEY, &[0, 0]).unwrap();
+        let chain = cache.open_tree("chain/other").unwrap();
+
+        // Keyed by older versions of blutgang
+        let old = [7; TX_HASH_LEN];
+        let old_versioned = [CACHE_VERSION_PREFIX, &[1, 0], &old].concat();
+        let old_shard = [SHARD_PREFIX, b"0xabc"].concat();
+        // Keyed the way we key requests now
+        let current = [&[CACHE_SCHEMA_VERSION][..], &old].concat();
+        for key in [
+            old.to_vec(),
+            old_versioned.clone(),
+            old_shard.clone(),
+            expiry_key(&old),
+            expiry_key(&[ERROR_PREFIX, &old].concat()),
+            [METHOD_PREFIX, b"eth_call\0", &old].concat(),
+            current.clone(),
+            expiry_key(&current),
+            [METHOD_PREFIX, b"eth_call\0", &current].concat(),
+        ] {
+            cache.insert(&key, b"response").unwrap();
+        }
+        chain.insert(old, b"response").unwrap();
+        chain.insert(&current, b"response").unwrap();
+
+        setup_data(Arc::new(cache.clone()), 0);
+
+        assert!(cache.get(old).unwrap().is_none());
+        assert!(cache.get(old_versioned).unwrap().is_none());
+        assert!(cache.get(old_shard).unwrap().is_none());
+        assert_eq!(cache.scan_prefix(EXPIRY_PREFIX).count(), 1);
+        assert_eq!(cache.scan_prefix(METHOD_PREFIX).count(), 1);
+        assert!(cache.get(&current).unwrap().is_some());
+        assert!(cache.get(BLUTGANG_IS_LB_KEY).unwrap().is_some());
+        assert!(chain.get(old).unwrap().is_none());
+        assert!(chain.get(&current).unwrap().is_some());
+
+        // Only caches on an older schema get cleared
+        cache.insert(old, b"response").unwrap();
+        setup_data(Arc::new(cache.clone()), 0);
+        assert!(cache.get(old).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_setup_data_records_cache_version() {
+        let cache = create_test_cache();
+        setup_data(Arc::new(cache.clone()), 0);
+        assert_eq!(
+            cache.get(CACHE_VERSION_KEY).unwrap().unwrap(),
+            [CACHE_SCHEMA_VERSION, 0]
+        );
+
+        setup_data(Arc::new(cache.clone()), 3);
+        assert_eq!(
+            cache.get(CACHE_VERSION_KEY).unwrap().unwrap(),
+            [CACHE_SCHEMA_VERSION, 3]
+        );
+        assert!(is_metadata(CACHE_VERSION_KEY));
+    }
 }
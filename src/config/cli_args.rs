@@ -10,6 +10,9 @@ pub fn create_match() -> clap::Command {
         .version(VERSION_STR)
         .author("makemake <vukasin@gostovic.me> and contributors")
         .about("Blutgang load balancer and cache. For more info read the wiki: https://github.com/rainshowerLabs/blutgang/wiki")
+        .after_help("Config file settings can be overridden with environment variables. BLUTGANG_<KEY> sets <key> in the [blutgang] table, \
+            BLUTGANG_<TABLE>__<KEY> sets <key> in [<table>], and BLUTGANG_RPC_LIST replaces the RPCs with a comma separated list of URLs. \
+            Without a config file, blutgang starts from defaults if any of them are set.")
         .arg(Arg::new("rpc_list")
             .long("rpc_list")
             .short('r')
@@ -24,6 +27,11 @@ pub fn create_match() -> clap::Command {
             .default_value("config.toml")
             .conflicts_with("rpc_list")
             .help("TOML config file for blutgang"))
+        .arg(Arg::new("check_config")
+            .long("check-config")
+            .num_args(0..)
+            .conflicts_with("rpc_list")
+            .help("Validate the config file and exit without starting blutgang"))
         .arg(Arg::new("port")
             .long("port")
             .short('p')
@@ -64,7 +72,12 @@ pub fn create_match() -> clap::Command {
             .long("flush_every_ms")
             .num_args(1..)
             .default_value("1000")
-            .help("Time in ms to flush the DB"))
+            .help("Time in ms to flush the DB. 0 only flushes on shutdown"))
+        .arg(Arg::new("sled_mode")
+            .long("sled_mode")
+            .num_args(1..)
+            .default_value("HighThroughput")
+            .help("sled mode. Can be HighThroughput/LowSpace"))
         .arg(Arg::new("clear")
             .long("clear")
             .num_args(0..)
@@ -0,0 +1,540 @@
+// Overriding config file settings with `BLUTGANG_` environment variables.
+//
+// `BLUTGANG_<KEY>` sets `<key>` in the `[blutgang]` table, and
+// `BLUTGANG_<TABLE>__<KEY>` sets `<key>` in `[<table>]`, e.g. `BLUTGANG_TTL` or
+// `BLUTGANG_ADMIN__ENABLED`. `BLUTGANG_RPC_LIST` replaces every RPC table with
+// a comma separated list of RPC URLs.
+//
+// Values are typed from the settings blutgang knows about, so `BLUTGANG_AUTH_TOKEN=123456`
+// stays a string, and a typo in the name is an error instead of being ignored.
+use crate::config::types::NON_RPC_TABLES;
+
+use toml::{
+    Table,
+    Value,
+};
+
+pub const ENV_PREFIX: &str = "BLUTGANG_";
+
+// Settings blutgang can't start without, used when there's no config file
+// and everything comes from environment variables
+pub const BASE_CONFIG: &str = r#"
+[blutgang]
+do_clear = false
+address = "127.0.0.1:3000"
+sort_on_startup = false
+ma_length = 100
+health_check = false
+ttl = 1000
+max_retries = 32
+health_check_ttl = 2000
+
+[admin]
+enabled = false
+
+[sled]
+db_path = "./blutgang-cache"
+mode = "HighThroughput"
+cache_capacity = 1000000000
+compression = false
+print_profile = false
+flush_every_ms = 1000
+"#;
+
+// Limits for RPCs from `BLUTGANG_RPC_LIST`, same as for `--rpc_list`
+const ENV_RPC_MAX_CONSECUTIVE: i64 = 6;
+const ENV_RPC_MAX_PER_SECOND: i64 = 0;
+
+// Environment variables starting with `ENV_PREFIX`, skipping ones that aren't unicode
+pub fn blutgang_vars() -> Vec<(String, String)> {
+    std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect()
+}
+
+// Apply `vars` on top of the parsed config file, env vars taking precedence
+pub fn apply_env_overrides(config: &mut Value, vars: &[(String, String)]) -> Result<(), String> {
+    let config = config
+        .as_table_mut()
+        .ok_or("Config is not a table".to_string())?;
+
+    for (name, raw) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let key = key.to_lowercase();
+
+        if key == "rpc_list" {
+            set_rpc_list(config, name, raw)?;
+            continue;
+        }
+
+        let (table, key) = key.split_once("__").unwrap_or(("blutgang", &key));
+        if table.is_empty() || key.is_empty() || key.contains("__") {
+            return Err(format!(
+                "{}: Expected {}<KEY> or {}<TABLE>__<KEY>",
+                name, ENV_PREFIX, ENV_PREFIX
+            ));
+        }
+
+        let setting_type = setting_type(table, key)
+            .ok_or(format!("{}: Unknown setting `{}` in [{}]", name, key, table))?;
+        let value = parse_env_value(name, raw, setting_type)?;
+
+        config
+            .entry(table)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or(format!("{}: {} is not a table", name, table))?
+            .insert(key.to_string(), value);
+    }
+
+    Ok(())
+}
+
+// Type a setting gets parsed as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SettingType {
+    Str,
+    Int,
+    Float,
+    // Either an int or a float
+    Number,
+    // Either an int or a str
+    IntOrStr,
+    Bool,
+    Array,
+    Table,
+}
+
+impl SettingType {
+    fn name(&self) -> &'static str {
+        match self {
+            SettingType::Str => "string",
+            SettingType::Int => "integer",
+            SettingType::Float => "float",
+            SettingType::Number => "number",
+            SettingType::IntOrStr => "integer or string",
+            SettingType::Bool => "boolean",
+            SettingType::Array => "array",
+            SettingType::Table => "table",
+        }
+    }
+}
+
+use SettingType::{
+    Array,
+    Bool,
+    Float,
+    Int,
+    IntOrStr,
+    Number,
+    Str,
+};
+
+const BLUTGANG_SETTINGS: &[(&str, SettingType)] = &[
+    ("access_log", Str),
+    ("access_log_sample_rate", Float),
+    ("address", Str),
+    ("allowed_methods", Array),
+    ("allowed_origins", Array),
+    ("archive_threshold", Int),
+    ("auth_token", Str),
+    ("batch_concurrency", Int),
+    ("blocked_method_message", Str),
+    ("broadcast_transactions", Bool),
+    ("cache_errors", Bool),
+    ("cache_max_bytes", Int),
+    ("cache_version", Int),
+    ("cache_warmup", Array),
+    ("chain_id_check_ttl", Int),
+    ("check_net_version", Bool),
+    ("compression", Bool),
+    ("compression_min_size", Int),
+    ("denied_methods", Array),
+    ("do_clear", Bool),
+    ("drop_stale_heads", Bool),
+    ("error_cache_ttl", Int),
+    ("expected_chain_id", Int),
+    ("expose_node_header", Bool),
+    ("fleet_metadata_ttl", Int),
+    ("future_blocks", Str),
+    ("head_agreement_quorum", Number),
+    ("head_cache_enabled", Bool),
+    ("head_cache_max_blocks", Int),
+    ("head_check_stagger", Int),
+    ("head_method", Str),
+    ("head_number_format", Str),
+    ("head_result_pointer", Str),
+    ("health_check", Bool),
+    ("health_check_jitter", Int),
+    ("health_check_ttl", Int),
+    ("health_webhook_url", Str),
+    ("hedge_delay", Int),
+    ("latency_ema_alpha", Float),
+    ("lazy_cache_migration", Bool),
+    ("listen_backlog", Int),
+    ("local_newheads", Bool),
+    ("log_format", Str),
+    ("log_level", Str),
+    ("log_rate_limit", Int),
+    ("ma_length", Int),
+    ("max_batch_size", Int),
+    ("max_concurrent_connections", Int),
+    ("max_concurrent_requests", Int),
+    ("max_consecutive_failures", Int),
+    ("max_logs_chunks", Int),
+    ("max_logs_range", Int),
+    ("max_request_bytes", Int),
+    ("max_response_bytes", Int),
+    ("max_retries", Int),
+    ("max_subscription_lifetime", Int),
+    ("memory_cache_entries", Int),
+    ("min_peers", Int),
+    ("no_archive_message", Str),
+    ("no_cache_methods", Array),
+    ("no_proxy", Str),
+    ("poverty_backoff_max", Int),
+    ("poverty_check_ttl", Int),
+    ("priority_clients", SettingType::Table),
+    ("probe_block_hash", Str),
+    ("probe_block_number", Int),
+    ("probe_block_ttl", Int),
+    ("proxy_url", Str),
+    ("rate_limit_burst", Int),
+    ("rate_limit_cooldown_ms", Int),
+    ("rate_limit_rps", Int),
+    ("ready_after_warmup", Bool),
+    ("reorg_bypass_window", Int),
+    ("request_deadline_ms", Int),
+    ("restore_health", Bool),
+    ("selection_strategy", Str),
+    ("serve_block_number_locally", Bool),
+    ("serve_named_blocks_locally", Bool),
+    ("serve_stale_on_error", Bool),
+    ("shadow_sample_rate", Float),
+    ("shard_logs_cache", Bool),
+    ("shutdown_grace_ms", Int),
+    ("sort_on_startup", Bool),
+    ("stale_latest_lag", Int),
+    ("stale_latest_strikes", Int),
+    ("startup_check", Bool),
+    ("startup_check_strict", Bool),
+    ("sticky_session_ttl", Int),
+    ("sticky_sessions", Bool),
+    ("strict_jsonrpc", Bool),
+    ("strip_response_fields", Array),
+    ("subscription_queue_timeout", Int),
+    ("ttl", Int),
+    ("ttl_tolerance_blocks", Int),
+    ("tx_replay_window", Int),
+    ("unix_socket", Str),
+    ("unknown_subscriptions", Str),
+    ("validate_responses", Bool),
+    ("ws_channel_capacity", Int),
+    ("ws_coalesce_new_heads", Bool),
+    ("ws_max_notifications_per_second", Int),
+    ("ws_notification_buffer", Int),
+    ("ws_ping_interval_ms", Int),
+    ("ws_pong_timeout_ms", Int),
+];
+
+const SLED_SETTINGS: &[(&str, SettingType)] = &[
+    ("cache_capacity", Int),
+    ("compression", Bool),
+    ("db_path", Str),
+    ("flush_every_ms", Int),
+    ("mode", Str),
+    ("print_profile", Bool),
+];
+
+const ADMIN_SETTINGS: &[(&str, SettingType)] = &[
+    ("address", Str),
+    ("auth_token", Str),
+    ("dump_dir", Str),
+    ("enabled", Bool),
+    ("jwt", Bool),
+    ("key", Str),
+    ("max_connections", Int),
+    ("metrics_enabled", Bool),
+    ("readonly", Bool),
+    ("request_timeout_ms", Int),
+];
+
+const TLS_SETTINGS: &[(&str, SettingType)] = &[
+    ("cert_path", Str),
+    ("enabled", Bool),
+    ("key_path", Str),
+];
+
+const HTTP_SETTINGS: &[(&str, SettingType)] = &[
+    ("header_timeout_ms", Int),
+    ("keepalive", Bool),
+    ("max_buf_size", Int),
+];
+
+const RPC_SETTINGS: &[(&str, SettingType)] = &[
+    ("accept_invalid_certs", Bool),
+    ("archive", Bool),
+    ("ca_bundle", Str),
+    ("groups", Array),
+    ("headers", SettingType::Table),
+    ("http_version", Str),
+    ("max_concurrency", Int),
+    ("max_consecutive", Int),
+    ("max_per_second", Int),
+    ("pool_idle_timeout_ms", Int),
+    ("pool_max_connections", Int),
+    ("pool_max_idle", Int),
+    ("url", Str),
+    ("weight", Int),
+    ("ws_url", Str),
+];
+
+// Type of `key` in `table`, or `None` if blutgang doesn't know the setting
+fn setting_type(table: &str, key: &str) -> Option<SettingType> {
+    let settings = match table {
+        "blutgang" => BLUTGANG_SETTINGS,
+        "sled" => SLED_SETTINGS,
+        "admin" => ADMIN_SETTINGS,
+        "tls" => TLS_SETTINGS,
+        "http" => HTTP_SETTINGS,
+        // Tables keyed by method or chain name
+        "method_cache" | "method_timeouts" => return Some(Int),
+        "method_routing" => return Some(Str),
+        "block_params" => return Some(IntOrStr),
+        "health_probes" | "chains" => return Some(SettingType::Table),
+        // Every other table is an RPC
+        _ => RPC_SETTINGS,
+    };
+
+    settings
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, setting_type)| *setting_type)
+}
+
+// Parse `raw` as the type blutgang expects for the setting.
+//
+// Strings are taken as-is, everything else has to be a TOML value of the right type.
+fn parse_env_value(name: &str, raw: &str, setting_type: SettingType) -> Result<Value, String> {
+    let parsed = format!("value = {}", raw)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"));
+
+    match (setting_type, parsed) {
+        (Str, _) => Ok(Value::String(raw.to_string())),
+        // Integers are fine where floats are expected
+        (Float, Some(Value::Integer(int))) => Ok(Value::Float(int as f64)),
+        (IntOrStr, Some(value @ Value::Integer(_))) => Ok(value),
+        (IntOrStr, _) => Ok(Value::String(raw.to_string())),
+        (Int, Some(value @ Value::Integer(_)))
+        | (Float | Number, Some(value @ Value::Float(_)))
+        | (Number, Some(value @ Value::Integer(_)))
+        | (Bool, Some(value @ Value::Boolean(_)))
+        | (Array, Some(value @ Value::Array(_)))
+        | (SettingType::Table, Some(value @ Value::Table(_))) => Ok(value),
+        _ => {
+            Err(format!(
+                "{}: Could not parse `{}` as {}",
+                name,
+                raw,
+                setting_type.name()
+            ))
+        }
+    }
+}
+
+// Replace every RPC table with ones made from the comma separated URLs in `raw`
+fn set_rpc_list(config: &mut Table, name: &str, raw: &str) -> Result<(), String> {
+    let urls: Vec<&str> = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .collect();
+    if urls.is_empty() {
+        return Err(format!("{}: No RPC URLs", name));
+    }
+
+    config.retain(|table_name, _| NON_RPC_TABLES.contains(&table_name));
+    for (index, url) in urls.into_iter().enumerate() {
+        let mut rpc = Table::new();
+        rpc.insert("url".to_string(), Value::String(url.to_string()));
+        rpc.insert(
+            "max_consecutive".to_string(),
+            Value::Integer(ENV_RPC_MAX_CONSECUTIVE),
+        );
+        rpc.insert(
+            "max_per_second".to_string(),
+            Value::Integer(ENV_RPC_MAX_PER_SECOND),
+        );
+        config.insert(format!("rpc_{}", index), Value::Table(rpc));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_env_overrides() {
+        let mut config = r#"
+            [blutgang]
+            ttl = 1000
+            ma_length = 15
+            address = "127.0.0.1:3000"
+
+            [admin]
+            enabled = false
+
+            [node]
+            url = "http://node.example"
+        "#
+        .parse::<Value>()
+        .unwrap();
+
+        apply_env_overrides(
+            &mut config,
+            &vars(&[
+                ("BLUTGANG_TTL", "500"),
+                ("BLUTGANG_MA_LENGTH", "20"),
+                ("BLUTGANG_LATENCY_EMA_ALPHA", "1"),
+                ("BLUTGANG_AUTH_TOKEN", "123456"),
+                ("BLUTGANG_SLED__DB_PATH", "12345"),
+                ("BLUTGANG_HEAD_AGREEMENT_QUORUM", "0.5"),
+                ("BLUTGANG_BLOCK_PARAMS__ETH_CALL", "1"),
+                ("BLUTGANG_NODE__WEIGHT", "3"),
+                ("BLUTGANG_ADDRESS", "0.0.0.0:3000"),
+                ("BLUTGANG_HEALTH_CHECK", "true"),
+                ("BLUTGANG_ADMIN__ENABLED", "true"),
+                ("BLUTGANG_HTTP__KEEPALIVE", "false"),
+                ("OTHER_TTL", "1"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config["blutgang"]["ttl"].as_integer(), Some(500));
+        assert_eq!(config["blutgang"]["ma_length"].as_integer(), Some(20));
+        assert_eq!(config["blutgang"]["latency_ema_alpha"].as_float(), Some(1.0));
+        // Numbers stay strings where blutgang expects a string
+        assert_eq!(config["blutgang"]["auth_token"].as_str(), Some("123456"));
+        assert_eq!(config["sled"]["db_path"].as_str(), Some("12345"));
+        assert_eq!(
+            config["blutgang"]["head_agreement_quorum"].as_float(),
+            Some(0.5)
+        );
+        assert_eq!(config["block_params"]["eth_call"].as_integer(), Some(1));
+        assert_eq!(config["node"]["weight"].as_integer(), Some(3));
+        assert_eq!(config["blutgang"]["address"].as_str(), Some("0.0.0.0:3000"));
+        assert_eq!(config["blutgang"]["health_check"].as_bool(), Some(true));
+        assert_eq!(config["admin"]["enabled"].as_bool(), Some(true));
+        assert_eq!(config["http"]["keepalive"].as_bool(), Some(false));
+        assert_eq!(config["node"]["url"].as_str(), Some("http://node.example"));
+        assert!(config["blutgang"].get("other_ttl").is_none());
+
+        // Values have to match the type blutgang expects
+        let err = apply_env_overrides(&mut config, &vars(&[("BLUTGANG_TTL", "soon")]));
+        assert_eq!(
+            err,
+            Err("BLUTGANG_TTL: Could not parse `soon` as integer".to_string())
+        );
+        let err = apply_env_overrides(&mut config, &vars(&[("BLUTGANG_HEALTH_CHECK", "1")]));
+        assert_eq!(
+            err,
+            Err("BLUTGANG_HEALTH_CHECK: Could not parse `1` as boolean".to_string())
+        );
+        assert!(apply_env_overrides(&mut config, &vars(&[("BLUTGANG___TTL", "1")])).is_err());
+
+        // Typos are errors instead of being ignored
+        let err = apply_env_overrides(&mut config, &vars(&[("BLUTGANG_HEALTH_CHEK", "true")]));
+        assert_eq!(
+            err,
+            Err("BLUTGANG_HEALTH_CHEK: Unknown setting `health_chek` in [blutgang]".to_string())
+        );
+        assert!(
+            apply_env_overrides(&mut config, &vars(&[("BLUTGANG_ADMIN__ENABLE", "true")])).is_err()
+        );
+        assert!(apply_env_overrides(&mut config, &vars(&[("BLUTGANG_NODE__URLS", "a")])).is_err());
+    }
+
+    #[test]
+    fn test_env_settings_cover_example_config() {
+        let config = std::fs::read_to_string("example_config.toml")
+            .unwrap()
+            .parse::<Table>()
+            .unwrap();
+
+        for (table_name, table) in &config {
+            for (key, value) in table.as_table().unwrap() {
+                let setting_type = setting_type(table_name, key)
+                    .unwrap_or_else(|| panic!("unknown setting {} in [{}]", key, table_name));
+                let raw = match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                assert!(
+                    parse_env_value(key, &raw, setting_type).is_ok(),
+                    "{} in [{}]",
+                    key,
+                    table_name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_env_rpc_list() {
+        let mut config = BASE_CONFIG.parse::<Value>().unwrap();
+        config
+            .as_table_mut()
+            .unwrap()
+            .insert("node".to_string(), Value::Table(Table::new()));
+
+        apply_env_overrides(
+            &mut config,
+            &vars(&[("BLUTGANG_RPC_LIST", "http://a.example, http://b.example,")]),
+        )
+        .unwrap();
+
+        let config = config.as_table().unwrap();
+        assert!(config.get("node").is_none());
+        assert_eq!(config["rpc_0"]["url"].as_str(), Some("http://a.example"));
+        assert_eq!(config["rpc_1"]["url"].as_str(), Some("http://b.example"));
+        assert_eq!(config["rpc_1"]["max_consecutive"].as_integer(), Some(6));
+        assert!(config.contains_key("sled"));
+
+        let mut config = BASE_CONFIG.parse::<Value>().unwrap();
+        assert!(apply_env_overrides(&mut config, &vars(&[("BLUTGANG_RPC_LIST", " ,")])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_env_without_config_file() {
+        let mut config = BASE_CONFIG.parse::<Value>().unwrap();
+        apply_env_overrides(
+            &mut config,
+            &vars(&[
+                ("BLUTGANG_RPC_LIST", "http://a.example"),
+                ("BLUTGANG_HEALTH_CHECK", "true"),
+                ("BLUTGANG_HEALTH_CHECK_TTL", "500"),
+                ("BLUTGANG_SLED__DB_PATH", "/tmp/blutgang-env-test"),
+            ]),
+        )
+        .unwrap();
+
+        let settings = crate::Settings::create_from_file(toml::to_string(&config).unwrap()).await;
+        assert_eq!(settings.rpc_list.len(), 1);
+        assert_eq!(settings.rpc_list[0].url, "http://a.example");
+        assert_eq!(settings.health_check_ttl, 500);
+        assert!(settings.validate().is_empty());
+    }
+}
@@ -7,6 +7,7 @@ use std::error::Error;
 pub enum ConfigError {
     RpcError(String),
     BadConfig,
+    TlsError(String),
 }
 
 impl std::fmt::Display for ConfigError {
@@ -14,6 +15,7 @@ impl std::fmt::Display for ConfigError {
         match self {
             ConfigError::RpcError(e) => write!(f, "Error while calling RPC: {}", e),
             ConfigError::BadConfig => write!(f, "Invalid Config File!"),
+            ConfigError::TlsError(e) => write!(f, "Could not set up TLS: {}", e),
         }
     }
 }
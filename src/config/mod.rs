@@ -1,5 +1,7 @@
 pub mod cache_setup;
 pub mod cli_args;
+pub mod env;
 pub mod error;
+pub mod reload;
 pub mod setup;
 pub mod types;
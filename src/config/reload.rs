@@ -0,0 +1,564 @@
+// Config hot-reload on SIGHUP.
+//
+// Only some settings can be changed while running:
+// - The RPC list. New RPCs get added to the active pool and removed ones are dropped
+//   from both the active and poverty lists. RPCs that are still in the config keep
+//   their connections, stats and place in the pool, but pick up their new settings.
+// - `ttl`, `max_retries` and `health_check_ttl`.
+// - `[method_cache]`, `[method_timeouts]` and `no_cache_methods`.
+// - `auth_token` and `admin.auth_token`, so tokens can be rotated.
+//
+// These get reloaded for every chain we serve. Adding or removing chains,
+// and everything else, needs a restart. Changes to settings that can't be applied
+// live, like the bind address, get logged as ignored.
+use crate::{
+    config::{
+        error::ConfigError,
+        types::Settings,
+    },
+    websocket::types::WsconnMessage,
+    Rpc,
+};
+
+use tracing::{
+    error,
+    info,
+    warn,
+};
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    sync::{
+        Arc,
+        RwLock,
+    },
+};
+
+use tokio::sync::mpsc;
+
+// Names of the `fields` that differ between `old` and `new`, prefixed with `prefix`.
+//
+// Not every setting implements `PartialEq`, so they get compared by their `Debug` output.
+macro_rules! changed_settings {
+    ($old:expr, $new:expr, $prefix:literal, $($field:ident),+ $(,)?) => {
+        {
+            let mut changed = Vec::new();
+            $(
+                if format!("{:?}", $old.$field) != format!("{:?}", $new.$field) {
+                    changed.push(concat!($prefix, stringify!($field)));
+                }
+            )+
+            changed
+        }
+    };
+}
+
+// Everything we need to reload the config of a chain we serve
+#[derive(Debug, Clone)]
+pub struct ReloadChain {
+    pub config: Arc<RwLock<Settings>>,
+    pub rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    pub poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    pub incoming_tx: mpsc::Sender<WsconnMessage>,
+}
+
+// Reload the config file every time we receive a SIGHUP
+#[cfg(unix)]
+pub async fn reload_on_sighup(default: ReloadChain, chains: BTreeMap<String, ReloadChain>) {
+    use tokio::signal::unix::{
+        signal,
+        SignalKind,
+    };
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            error!("Could not listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading config...");
+        match reload_config(&default, &chains).await {
+            Ok(()) => info!("Config reloaded."),
+            Err(err) => {
+                error!("Could not reload config, keeping the current one: {}", err)
+            }
+        }
+    }
+}
+
+// Parse the config file again and apply what can be changed live to every chain
+pub async fn reload_config(
+    default: &ReloadChain,
+    chains: &BTreeMap<String, ReloadChain>,
+) -> Result<(), ConfigError> {
+    let path = match default.config.read().unwrap().config_path.clone() {
+        Some(path) => path,
+        None => return Err(ConfigError::BadConfig),
+    };
+    let file = fs::read_to_string(&path).map_err(|_| ConfigError::BadConfig)?;
+
+    // Parsing panics on invalid configs, so do it on its own task to survive that.
+    // RPCs don't get sorted, reloading shouldn't wait on them.
+    let mut new = tokio::task::spawn(Settings::parse_file(file, false))
+        .await
+        .map_err(|_| ConfigError::BadConfig)?;
+
+    // Other chains share these settings, so only report them once
+    for name in ignored_settings(&default.config.read().unwrap(), &new) {
+        warn!(
+            "`{}` can't be changed without a restart, ignoring it.",
+            name
+        );
+    }
+
+    let mut new_chains = std::mem::take(&mut new.chains);
+    reload_chain(default, new).await?;
+
+    for (name, chain) in chains {
+        match new_chains.remove(name) {
+            Some(new) => reload_chain(chain, new).await?,
+            None => {
+                warn!(
+                    "Chain {} was removed from the config, it keeps being served until a restart.",
+                    name
+                )
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Apply the reloadable settings in `new` to `chain`
+async fn reload_chain(chain: &ReloadChain, new: Settings) -> Result<(), ConfigError> {
+    let is_ws = chain.config.read().unwrap().is_ws;
+    if apply_reload(&chain.config, &chain.rpc_list, &chain.poverty_list, new) && is_ws {
+        // Only connect to new WS endpoints and drop removed ones
+        chain.incoming_tx.send(WsconnMessage::Sync()).await?;
+    }
+
+    Ok(())
+}
+
+// Swap the reloadable settings of `config` for the ones in `new`.
+//
+// Returns true if RPCs were added, removed or got a new WS endpoint.
+fn apply_reload(
+    config: &Arc<RwLock<Settings>>,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    new: Settings,
+) -> bool {
+    let mut config_guard = config.write().unwrap();
+
+    config_guard.ttl = new.ttl;
+    config_guard.max_retries = new.max_retries;
+    config_guard.health_check_ttl = new.health_check_ttl;
+    config_guard.method_cache = new.method_cache;
+    config_guard.method_timeouts = new.method_timeouts;
+    config_guard.auth_token = new.auth_token;
+    config_guard.admin.auth_token = new.admin.auth_token;
+
+    let changed = update_rpcs(rpc_list, poverty_list, &new.rpc_list);
+    config_guard.rpc_list = new.rpc_list;
+
+    changed
+}
+
+// Names of the settings that differ between `old` and `new` but only get read on startup
+fn ignored_settings(old: &Settings, new: &Settings) -> Vec<&'static str> {
+    let mut ignored = changed_settings!(
+        old,
+        new,
+        "",
+        is_ws,
+        ma_length,
+        do_clear,
+        address,
+        unix_socket,
+        listen_backlog,
+        max_concurrent_connections,
+        allowed_origins,
+        proxy_url,
+        no_proxy,
+        compression,
+        compression_min_size,
+        expose_node_header,
+        health_check,
+        health_check_jitter,
+        head_check_stagger,
+        poverty_check_ttl,
+        poverty_backoff_max,
+        health_webhook_url,
+        head_agreement_quorum,
+        ttl_tolerance_blocks,
+        stale_latest_lag,
+        stale_latest_strikes,
+        max_consecutive_failures,
+        fleet_metadata_ttl,
+        shutdown_grace_ms,
+        chain_id_check_ttl,
+        expected_chain_id,
+        check_net_version,
+        probe_block_ttl,
+        probe_block,
+        health_probes,
+        selection_strategy,
+        latency_ema_alpha,
+        validate_responses,
+        serve_stale_on_error,
+        shadow_sample_rate,
+        shard_logs_cache,
+        max_logs_range,
+        max_logs_chunks,
+        broadcast_transactions,
+        serve_block_number_locally,
+        serve_named_blocks_locally,
+        archive_threshold,
+        no_archive_message,
+        future_blocks,
+        subscription_queue_timeout,
+        ws_ping_interval_ms,
+        ws_pong_timeout_ms,
+        tx_replay_window,
+        sticky_sessions,
+        sticky_session_ttl,
+        block_params,
+        method_filter,
+        method_routing,
+        response_transform,
+        drop_stale_heads,
+        ws_max_notifications_per_second,
+        ws_coalesce_new_heads,
+        ws_notification_buffer,
+        reorg_bypass_window,
+        max_concurrent_requests,
+        memory_cache_entries,
+        cache_max_bytes,
+        head_cache_enabled,
+        head_cache_max_blocks,
+        rate_limit_rps,
+        rate_limit_burst,
+        priority_clients,
+        log_rate_limit,
+        log_level,
+        log_format,
+        access_log,
+        access_log_sample_rate,
+        ready_after_warmup,
+        max_subscription_lifetime,
+        ws_channel_capacity,
+        unknown_subscriptions,
+        local_newheads,
+        startup_check,
+        startup_check_strict,
+        restore_health,
+        max_request_bytes,
+        max_batch_size,
+        batch_concurrency,
+        strict_jsonrpc,
+        max_response_bytes,
+        rate_limit_cooldown_ms,
+        head_method,
+        lazy_cache_migration,
+        cache_version,
+        hedge_delay,
+        request_deadline_ms,
+        cache_warmup,
+        tls,
+        http,
+    );
+    // Chains get reloaded along with the default chain, but adding or removing
+    // them needs a restart
+    if !old.chains.keys().eq(new.chains.keys()) {
+        ignored.push("chains");
+    }
+    // sled's config holds a random temporary path, so only compare what we set
+    let (old_sled, new_sled) = (&old.sled_config, &new.sled_config);
+    let sled = [
+        ("sled.db_path", old_sled.path != new_sled.path),
+        (
+            "sled.mode",
+            format!("{:?}", old_sled.mode) != format!("{:?}", new_sled.mode),
+        ),
+        (
+            "sled.cache_capacity",
+            old_sled.cache_capacity != new_sled.cache_capacity,
+        ),
+        (
+            "sled.compression",
+            old_sled.use_compression != new_sled.use_compression,
+        ),
+        (
+            "sled.print_profile",
+            old_sled.print_profile_on_drop != new_sled.print_profile_on_drop,
+        ),
+        (
+            "sled.flush_every_ms",
+            old_sled.flush_every_ms != new_sled.flush_every_ms,
+        ),
+    ];
+    ignored.extend(
+        sled.into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| name),
+    );
+    ignored.extend(changed_settings!(
+        old.admin,
+        new.admin,
+        "admin.",
+        enabled,
+        address,
+        readonly,
+        jwt,
+        max_connections,
+        request_timeout_ms,
+        metrics_enabled,
+        dump_dir,
+    ));
+
+    ignored
+}
+
+// Make the RPC lists match `configured`, keeping the RPCs that are still in it.
+//
+// Returns true if RPCs were added, removed or got a new WS endpoint.
+fn update_rpcs(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    configured: &[Rpc],
+) -> bool {
+    let mut rpc_list_guard = rpc_list.write().unwrap();
+    let mut poverty_list_guard = poverty_list.write().unwrap();
+    let mut changed = false;
+
+    let find = |url: &str| configured.iter().find(|rpc| rpc.url == url);
+
+    for list in [&mut *rpc_list_guard, &mut *poverty_list_guard] {
+        list.retain(|rpc| {
+            let keep = find(&rpc.url).is_some();
+            if !keep {
+                info!("{} was removed from the config, dropping it.", rpc.url);
+                changed = true;
+            }
+            keep
+        });
+
+        for rpc in list.iter_mut() {
+            let new = find(&rpc.url).unwrap();
+            changed |= rpc.ws_url != new.ws_url;
+            rpc.ws_url = new.ws_url.clone();
+            rpc.max_consecutive = new.max_consecutive;
+            rpc.min_time_delta = new.min_time_delta;
+            if rpc.max_concurrency != new.max_concurrency {
+                rpc.set_max_concurrency(new.max_concurrency);
+            }
+            rpc.archive = new.archive;
+            if rpc.client_settings != new.client_settings {
+                rpc.set_client_settings(new.client_settings.clone());
+            }
+        }
+    }
+
+    for new in configured {
+        let exists = rpc_list_guard
+            .iter()
+            .chain(poverty_list_guard.iter())
+            .any(|rpc| rpc.url == new.url);
+
+        if !exists {
+            info!(
+                "{} was added to the config, adding it to the active RPC pool.",
+                new.url
+            );
+            rpc_list_guard.push(new.clone());
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::types::AdminSettings;
+
+    fn rpc(url: &str) -> Rpc {
+        Rpc::new(url.to_string(), None, 10, 0, 1.0)
+    }
+
+    fn urls(list: &Arc<RwLock<Vec<Rpc>>>) -> Vec<String> {
+        list.read()
+            .unwrap()
+            .iter()
+            .map(|rpc| rpc.url.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_reload() {
+        let config = Arc::new(RwLock::new(Settings::default()));
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("a"), rpc("b")]));
+        let poverty_list = Arc::new(RwLock::new(vec![rpc("c")]));
+        rpc_list.write().unwrap()[0].count_request();
+
+        let mut kept = rpc("a");
+        kept.max_consecutive = 50;
+        let new = Settings {
+            rpc_list: vec![kept, rpc("c"), rpc("d")],
+            ttl: 5000,
+            health_check_ttl: 300,
+            method_timeouts: Arc::new([("eth_getLogs".to_string(), 10_000)].into()),
+            address: "127.0.0.1:4000".parse().unwrap(),
+            ..Default::default()
+        };
+
+        assert!(apply_reload(&config, &rpc_list, &poverty_list, new));
+
+        assert_eq!(urls(&rpc_list), vec!["a", "d"]);
+        assert_eq!(urls(&poverty_list), vec!["c"]);
+
+        // Kept RPCs keep their stats but get their new settings
+        let kept = rpc_list.read().unwrap()[0].clone();
+        assert_eq!(kept.requests(), 1);
+        assert_eq!(kept.max_consecutive, 50);
+
+        let config = config.read().unwrap();
+        assert_eq!(config.ttl, 5000);
+        assert_eq!(config.health_check_ttl, 300);
+        assert_eq!(config.method_timeouts["eth_getLogs"], 10_000);
+        assert_eq!(config.rpc_list.len(), 3);
+        // Can't be changed live
+        assert_eq!(config.address, Settings::default().address);
+    }
+
+    fn test_chain(config: Settings, rpc_list: Vec<Rpc>) -> ReloadChain {
+        ReloadChain {
+            // Nothing's listening for syncs
+            config: Arc::new(RwLock::new(Settings {
+                is_ws: false,
+                ..config
+            })),
+            rpc_list: Arc::new(RwLock::new(rpc_list)),
+            poverty_list: Arc::new(RwLock::new(Vec::new())),
+            incoming_tx: mpsc::channel(16).0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload_config_chains() {
+        let path = std::env::temp_dir().join(format!(
+            "blutgang_test_reload_{}.toml",
+            std::process::id()
+        ));
+        // The example config serves the default chain from `merkle`
+        let file = fs::read_to_string("example_config.toml").unwrap();
+        fs::write(
+            &path,
+            format!(
+                "{}\n[chains.base]\nrpcs = [\"base_rpc\"]\n\n[base_rpc]\nurl = \"http://base\"\nmax_consecutive = 150\nmax_per_second = 200\n",
+                file
+            ),
+        )
+        .unwrap();
+
+        let default = test_chain(
+            Settings {
+                config_path: Some(path.to_str().unwrap().to_string()),
+                ..Default::default()
+            },
+            vec![rpc("https://eth.merkle.io")],
+        );
+        let chains = BTreeMap::from([
+            (
+                "base".to_string(),
+                test_chain(Settings::default(), vec![rpc("http://old")]),
+            ),
+            // Not in the config anymore, gets left alone
+            (
+                "gone".to_string(),
+                test_chain(Settings::default(), vec![rpc("http://gone")]),
+            ),
+        ]);
+
+        let reloaded = reload_config(&default, &chains).await;
+        fs::remove_file(&path).unwrap();
+        reloaded.unwrap();
+
+        assert_eq!(urls(&default.rpc_list), vec!["https://eth.merkle.io"]);
+        assert_eq!(default.config.read().unwrap().ttl, 30);
+        assert_eq!(urls(&chains["base"].rpc_list), vec!["http://base"]);
+        assert_eq!(chains["base"].config.read().unwrap().ttl, 30);
+        assert_eq!(urls(&chains["gone"].rpc_list), vec!["http://gone"]);
+        assert_eq!(
+            chains["gone"].config.read().unwrap().ttl,
+            Settings::default().ttl
+        );
+    }
+
+    #[test]
+    fn test_ignored_settings() {
+        let old = Settings::default();
+        let new = Settings {
+            max_batch_size: 10,
+            hedge_delay: 100,
+            ttl: 5000,
+            admin: AdminSettings {
+                readonly: true,
+                auth_token: Some("token".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Reloadable settings don't get reported
+        assert_eq!(
+            ignored_settings(&old, &new),
+            vec!["max_batch_size", "hedge_delay", "admin.readonly"]
+        );
+        assert!(ignored_settings(&old, &Settings::default()).is_empty());
+
+        // Only adding or removing chains is ignored, their settings get reloaded
+        let chain = |ttl| {
+            Settings {
+                ttl,
+                ..Default::default()
+            }
+        };
+        let old = Settings {
+            chains: BTreeMap::from([("base".to_string(), chain(1000))]),
+            ..Default::default()
+        };
+        let new = Settings {
+            chains: BTreeMap::from([("base".to_string(), chain(5000))]),
+            ..Default::default()
+        };
+        assert!(ignored_settings(&old, &new).is_empty());
+        let new = Settings {
+            chains: BTreeMap::from([("arbitrum".to_string(), chain(1000))]),
+            ..Default::default()
+        };
+        assert_eq!(ignored_settings(&old, &new), vec!["chains"]);
+    }
+
+    #[test]
+    fn test_apply_reload_same_rpcs() {
+        let config = Arc::new(RwLock::new(Settings::default()));
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("a")]));
+        let poverty_list = Arc::new(RwLock::new(vec![rpc("b")]));
+
+        let new = Settings {
+            rpc_list: vec![rpc("b"), rpc("a")],
+            ..Default::default()
+        };
+
+        assert!(!apply_reload(&config, &rpc_list, &poverty_list, new));
+        assert_eq!(urls(&rpc_list), vec!["a"]);
+        assert_eq!(urls(&poverty_list), vec!["b"]);
+    }
+}
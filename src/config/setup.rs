@@ -14,7 +14,7 @@ pub const TAGLINE: &str = "`Now there's a way forward.`";
 
 #[derive(Debug)]
 enum StartingLatencyResp {
-    Ok(Rpc),
+    Ok(Box<Rpc>),
     Error(ConfigError),
 }
 
@@ -47,7 +47,7 @@ async fn set_starting_latency(
 
     println!("{}: {}ns", rpc.url, rpc.status.latency);
 
-    tx.send(StartingLatencyResp::Ok(rpc)).await?;
+    tx.send(StartingLatencyResp::Ok(Box::new(rpc))).await?;
 
     Ok(())
 }
@@ -80,7 +80,7 @@ pub async fn sort_by_latency(
     // Collect results from tasks
     while let Some(rpc) = rx.recv().await {
         let rpc = match rpc {
-            StartingLatencyResp::Ok(rax) => rax,
+            StartingLatencyResp::Ok(rax) => *rax,
             StartingLatencyResp::Error(e) => {
                 println!("\x1b[31mErr:\x1b[0m {}", e);
                 continue;
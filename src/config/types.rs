@@ -1,5 +1,41 @@
 use crate::{
-    config::setup::sort_by_latency,
+    balancer::{
+        admission::Priority,
+        body_limit::{
+            DEFAULT_MAX_REQUEST_BYTES,
+            DEFAULT_MAX_RESPONSE_BYTES,
+        },
+        format::BlockParams,
+        method_filter::{
+            MethodFilter,
+            DEFAULT_BLOCKED_MESSAGE,
+        },
+        method_routing::MethodRouting,
+        processing::ResponseTransform,
+        selection::cache_rules::MethodCache,
+    },
+    config::{
+        env::{
+            apply_env_overrides,
+            blutgang_vars,
+            BASE_CONFIG,
+        },
+        setup::sort_by_latency,
+    },
+    health::probes::HealthProbe,
+    log::setup::{
+        parse_log_level,
+        LogFormat,
+    },
+    rpc::types::{
+        load_ca_bundle,
+        ClientSettings,
+        HeadMethod,
+        HttpVersion,
+        NumberFormat,
+        RpcHeaders,
+        DEFAULT_RATE_LIMIT_COOLDOWN,
+    },
     Rpc,
 };
 use clap::{
@@ -9,19 +45,76 @@ use clap::{
 use jsonwebtoken::DecodingKey;
 
 use sled::Config;
+use tracing::level_filters::LevelFilter;
 
 use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
     fmt,
     fmt::Debug,
     fs::{
         self,
     },
-    net::SocketAddr,
+    net::{
+        IpAddr,
+        SocketAddr,
+    },
+    path::Path,
     println,
+    sync::Arc,
+    thread,
+    time::Duration,
 };
 
 use toml::Value;
 
+// Top level tables that aren't RPCs
+pub const NON_RPC_TABLES: [&str; 9] = [
+    "blutgang",
+    "sled",
+    "admin",
+    "block_params",
+    "method_cache",
+    "method_timeouts",
+    "tls",
+    "http",
+    "chains",
+];
+
+// Name of the thread `check_config` parses the config on
+const CHECK_CONFIG_THREAD: &str = "check-config";
+
+// Certificate and key used to serve HTTPS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsSettings {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+// Tuning for the HTTP/1 connections clients make to us
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpSettings {
+    // Keep connections open between requests
+    pub keepalive: bool,
+    // Time in ms clients have to send the headers of a request, 0 means no timeout
+    pub header_timeout_ms: u64,
+    // Max bytes buffered per connection
+    pub max_buf_size: usize,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            keepalive: true,
+            header_timeout_ms: 0,
+            // Same as hyper's default
+            max_buf_size: 8192 + 4096 * 100,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct AdminSettings {
     pub enabled: bool,
@@ -29,6 +122,16 @@ pub struct AdminSettings {
     pub readonly: bool,
     pub jwt: bool,
     pub key: DecodingKey,
+    // Max admin connections served at once, 0 means unbounded
+    pub max_connections: usize,
+    // Time in ms to answer an admin request, 0 means no timeout
+    pub request_timeout_ms: u64,
+    // Serve Prometheus metrics at `/metrics`
+    pub metrics_enabled: bool,
+    // Bearer token admin requests need to carry, if set. Reloadable
+    pub auth_token: Option<String>,
+    // Directory cache dumps get written to and read from. Dumps are disabled without it
+    pub dump_dir: Option<String>,
 }
 
 impl Default for AdminSettings {
@@ -39,6 +142,11 @@ impl Default for AdminSettings {
             readonly: false,
             jwt: false,
             key: DecodingKey::from_secret(b""),
+            max_connections: 0,
+            request_timeout_ms: 0,
+            metrics_enabled: false,
+            auth_token: None,
+            dump_dir: None,
         }
     }
 }
@@ -50,22 +158,379 @@ impl Debug for AdminSettings {
         write!(f, ", address: {:?}", self.address)?;
         write!(f, ", readonly: {:?}", self.readonly)?;
         write!(f, ", jwt: HIDDEN",)?;
+        write!(f, ", max_connections: {:?}", self.max_connections)?;
+        write!(f, ", request_timeout_ms: {:?}", self.request_timeout_ms)?;
+        write!(f, ", metrics_enabled: {:?}", self.metrics_enabled)?;
+        write!(f, ", auth_token: HIDDEN",)?;
+        write!(f, ", dump_dir: {:?}", self.dump_dir)?;
         write!(f, " }}")
     }
 }
 
+// What to do with requests for blocks above the current head
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FutureBlockBehavior {
+    // Forward the request to a node and return whatever it responds with
+    #[default]
+    Forward,
+    // Return a consistent error without contacting any node
+    Error,
+}
+
+impl FutureBlockBehavior {
+    fn from_str(behavior: &str) -> Self {
+        match behavior {
+            "forward" => FutureBlockBehavior::Forward,
+            "error" => FutureBlockBehavior::Error,
+            _ => {
+                panic!(
+                    "\x1b[31mErr:\x1b[0m Invalid future_blocks value! Can be `forward` or `error`."
+                )
+            }
+        }
+    }
+}
+
+// What to do with subscriptions of types we don't know how to validate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownSubscriptions {
+    // Forward them to a node as-is
+    #[default]
+    Forward,
+    // Reject them without contacting any node
+    Reject,
+}
+
+impl UnknownSubscriptions {
+    fn from_str(policy: &str) -> Self {
+        match policy {
+            "forward" => UnknownSubscriptions::Forward,
+            "reject" => UnknownSubscriptions::Reject,
+            _ => {
+                panic!(
+                    "\x1b[31mErr:\x1b[0m Invalid unknown_subscriptions value! Can be `forward` or `reject`."
+                )
+            }
+        }
+    }
+}
+
+// How to pick which RPC to forward requests to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SelectionStrategy {
+    // Weighted round robin, honoring `max_consecutive` and `max_per_second`
+    #[default]
+    RoundRobin,
+    // Pick RPCs at random, weighted by the inverse of their latency
+    LeastLatency,
+    // Pick RPCs at random
+    Random,
+    // Take turns between RPCs in proportion to their `weight`
+    Weighted,
+    // Send identical requests to the same RPC, picked by hashing the request
+    HashAffinity,
+}
+
+impl SelectionStrategy {
+    fn from_str(strategy: &str) -> Self {
+        match strategy {
+            "round_robin" => SelectionStrategy::RoundRobin,
+            "least_latency" => SelectionStrategy::LeastLatency,
+            "random" => SelectionStrategy::Random,
+            "weighted" => SelectionStrategy::Weighted,
+            "hash_affinity" => SelectionStrategy::HashAffinity,
+            _ => {
+                panic!(
+                    "\x1b[31mErr:\x1b[0m Invalid selection_strategy value! Can be `round_robin`, `least_latency`, `random`, `weighted` or `hash_affinity`."
+                )
+            }
+        }
+    }
+}
+
+// Parse the sled `mode`
+fn sled_mode(mode: &str) -> sled::Mode {
+    match mode {
+        "HighThroughput" => sled::Mode::HighThroughput,
+        "LowSpace" => sled::Mode::LowSpace,
+        _ => {
+            panic!("\x1b[31mErr:\x1b[0m Invalid sled mode! Can be `HighThroughput` or `LowSpace`.")
+        }
+    }
+}
+
+// How often sled flushes to disk. 0 means we only flush manually,
+// on shutdown or with `blutgang_flush_cache`.
+fn sled_flush_every_ms(flush_every_ms: u64) -> Option<u64> {
+    match flush_every_ms {
+        0 => None,
+        flush_every_ms => Some(flush_every_ms),
+    }
+}
+
+// URL used to tell if two RPCs are the same node.
+//
+// Ignores the case of the scheme and host, and trailing slashes.
+fn normalize_url(url: &str) -> String {
+    let url = url.trim();
+    match reqwest::Url::parse(url) {
+        Ok(parsed) => parsed.as_str().trim_end_matches('/').to_string(),
+        Err(_) => url.trim_end_matches('/').to_string(),
+    }
+}
+
+// RPCs whose `url` or `ws_url` we can't connect to
+fn rpc_url_errors(rpc_list: &[Rpc]) -> Vec<String> {
+    let mut errors = Vec::new();
+    for rpc in rpc_list {
+        let urls = [
+            ("url", Some(&rpc.url), ["http", "https"]),
+            ("ws_url", rpc.ws_url.as_ref(), ["ws", "wss"]),
+        ];
+        for (field, url, schemes) in urls {
+            let Some(url) = url else {
+                continue;
+            };
+            match reqwest::Url::parse(url) {
+                Ok(parsed) if schemes.contains(&parsed.scheme()) => {}
+                Ok(parsed) => {
+                    errors.push(format!(
+                        "{}: {} has scheme {}, expected {}",
+                        field,
+                        url,
+                        parsed.scheme(),
+                        schemes.join(" or ")
+                    ))
+                }
+                Err(err) => errors.push(format!("{}: {} is invalid: {}", field, url, err)),
+            }
+        }
+    }
+    errors
+}
+
+// Merge RPCs that point to the same URL into one.
+//
+// Duplicates would open their own connections and get counted twice by
+// selection, health checks and metrics. The merged RPC takes the highest
+// `weight` and `max_concurrency`, other settings come from the first entry.
+fn dedupe_rpcs(rpc_list: Vec<Rpc>) -> Vec<Rpc> {
+    let mut deduped: Vec<Rpc> = Vec::with_capacity(rpc_list.len());
+    for rpc in rpc_list {
+        let url = normalize_url(&rpc.url);
+        let first = match deduped
+            .iter_mut()
+            .find(|first| normalize_url(&first.url) == url)
+        {
+            Some(first) => first,
+            None => {
+                deduped.push(rpc);
+                continue;
+            }
+        };
+
+        println!(
+            "\x1b[93mWrn:\x1b[0m {} is listed more than once, merging duplicates.",
+            first.url
+        );
+        if first.ws_url != rpc.ws_url
+            || first.archive != rpc.archive
+            || first.max_consecutive != rpc.max_consecutive
+            || first.min_time_delta != rpc.min_time_delta
+            || first.client_settings != rpc.client_settings
+        {
+            println!(
+                "\x1b[93mWrn:\x1b[0m Duplicates of {} have conflicting settings, using the first one.",
+                first.url
+            );
+        }
+
+        first.weight = first.weight.max(rpc.weight);
+        // 0 means unbounded
+        if first.max_concurrency != 0 {
+            let max_concurrency = match rpc.max_concurrency {
+                0 => 0,
+                max_concurrency => first.max_concurrency.max(max_concurrency),
+            };
+            first.set_max_concurrency(max_concurrency);
+        }
+    }
+
+    deduped
+}
+
+// How many RPCs need to agree on a head before we consider it the head of the chain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeadAgreementQuorum {
+    // At least this many RPCs
+    Count(usize),
+    // At least this fraction of the responding RPCs
+    Fraction(f64),
+}
+
+impl Default for HeadAgreementQuorum {
+    fn default() -> Self {
+        HeadAgreementQuorum::Count(1)
+    }
+}
+
+impl HeadAgreementQuorum {
+    fn from_toml(quorum: &Value) -> Self {
+        if let Some(count) = quorum.as_integer() {
+            if count < 1 {
+                panic!("\x1b[31mErr:\x1b[0m head_agreement_quorum must be at least 1!");
+            }
+            return HeadAgreementQuorum::Count(count as usize);
+        }
+
+        match quorum.as_float() {
+            Some(fraction) if fraction > 0.0 && fraction <= 1.0 => {
+                HeadAgreementQuorum::Fraction(fraction)
+            }
+            _ => {
+                panic!(
+                    "\x1b[31mErr:\x1b[0m Invalid head_agreement_quorum! Can be a count of RPCs, or a fraction between 0 and 1."
+                )
+            }
+        }
+    }
+
+    // Number of RPCs out of `responding` that need to agree on a head.
+    // Never more than `responding`, and never less than 1.
+    pub fn required(&self, responding: usize) -> usize {
+        let required = match self {
+            HeadAgreementQuorum::Count(count) => *count,
+            HeadAgreementQuorum::Fraction(fraction) => {
+                (fraction * responding as f64).ceil() as usize
+            }
+        };
+
+        required.clamp(1, responding.max(1))
+    }
+}
+
+// Settings that can be changed by reloading the config with a SIGHUP
+// are listed in `config::reload`.
 #[derive(Debug, Clone)]
 pub struct Settings {
+    // Reloadable
     pub rpc_list: Vec<Rpc>,
     pub is_ws: bool,
+    // Length of the latency moving average of RPCs
+    pub ma_length: f64,
     pub do_clear: bool,
     pub address: SocketAddr,
+    pub unix_socket: Option<String>,
+    pub listen_backlog: u32,
+    pub max_concurrent_connections: usize,
+    pub allowed_origins: Vec<String>,
+    // Proxy for requests to the RPCs, and the hosts that bypass it
+    pub proxy_url: Option<String>,
+    pub no_proxy: Option<String>,
+    // Reloadable
+    pub auth_token: Option<String>,
+    pub compression: bool,
+    pub compression_min_size: usize,
+    pub expose_node_header: bool,
     pub health_check: bool,
+    // Reloadable
     pub ttl: u128,
+    // Reloadable
     pub max_retries: u32,
+    // Reloadable
     pub health_check_ttl: u64,
+    pub health_check_jitter: u64,
+    pub head_check_stagger: u64,
+    pub poverty_check_ttl: u64,
+    pub poverty_backoff_max: u64,
+    pub health_webhook_url: Option<String>,
+    pub head_agreement_quorum: HeadAgreementQuorum,
+    pub ttl_tolerance_blocks: u64,
+    pub stale_latest_lag: u64,
+    pub stale_latest_strikes: u32,
+    pub max_consecutive_failures: u32,
+    pub fleet_metadata_ttl: u64,
+    pub shutdown_grace_ms: u64,
+    pub chain_id_check_ttl: u64,
+    pub expected_chain_id: Option<u64>,
+    pub check_net_version: bool,
+    pub probe_block_ttl: u64,
+    pub probe_block: Option<(u64, String)>,
+    pub health_probes: Vec<HealthProbe>,
+    pub selection_strategy: SelectionStrategy,
+    pub latency_ema_alpha: f64,
+    pub validate_responses: bool,
+    pub serve_stale_on_error: bool,
+    pub shadow_sample_rate: f64,
+    pub shard_logs_cache: bool,
+    pub max_logs_range: u64,
+    pub max_logs_chunks: u64,
+    pub broadcast_transactions: bool,
+    pub serve_block_number_locally: bool,
+    pub serve_named_blocks_locally: bool,
+    pub archive_threshold: u64,
+    pub no_archive_message: Arc<str>,
+    pub future_blocks: FutureBlockBehavior,
+    pub subscription_queue_timeout: u64,
+    pub ws_ping_interval_ms: u64,
+    pub ws_pong_timeout_ms: u64,
+    pub tx_replay_window: u64,
+    pub sticky_sessions: bool,
+    pub sticky_session_ttl: u64,
+    pub block_params: Arc<BlockParams>,
+    // Reloadable
+    pub method_cache: Arc<MethodCache>,
+    pub method_timeouts: Arc<HashMap<String, u128>>,
+    pub method_filter: Arc<MethodFilter>,
+    pub method_routing: Arc<MethodRouting>,
+    pub response_transform: Arc<ResponseTransform>,
+    pub drop_stale_heads: bool,
+    pub ws_max_notifications_per_second: u32,
+    pub ws_coalesce_new_heads: bool,
+    pub ws_notification_buffer: usize,
+    pub reorg_bypass_window: u64,
+    pub max_concurrent_requests: usize,
+    pub memory_cache_entries: usize,
+    pub cache_max_bytes: u64,
+    pub head_cache_enabled: bool,
+    pub head_cache_max_blocks: u64,
+    pub rate_limit_rps: u64,
+    pub rate_limit_burst: u64,
+    pub priority_clients: HashMap<IpAddr, Priority>,
+    pub log_rate_limit: u64,
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    pub access_log: Option<String>,
+    pub access_log_sample_rate: f64,
+    pub ready_after_warmup: bool,
+    pub max_subscription_lifetime: u64,
+    pub ws_channel_capacity: usize,
+    pub unknown_subscriptions: UnknownSubscriptions,
+    pub local_newheads: bool,
+    pub startup_check: bool,
+    pub startup_check_strict: bool,
+    pub restore_health: bool,
+    pub max_request_bytes: usize,
+    pub max_batch_size: usize,
+    pub batch_concurrency: usize,
+    pub strict_jsonrpc: bool,
+    pub max_response_bytes: usize,
+    pub rate_limit_cooldown_ms: u64,
+    pub head_method: Arc<HeadMethod>,
+    pub lazy_cache_migration: bool,
+    pub cache_version: u8,
+    pub hedge_delay: u64,
+    pub request_deadline_ms: u64,
+    pub cache_warmup: Vec<serde_json::Value>,
     pub sled_config: Config,
     pub admin: AdminSettings,
+    pub tls: Option<TlsSettings>,
+    pub http: HttpSettings,
+    // Where the config was read from, if it was read from a file
+    pub config_path: Option<String>,
+    // Other chains we serve, by the path prefix they're served at.
+    // Same settings as the default chain, apart from their RPCs.
+    pub chains: BTreeMap<String, Settings>,
 }
 
 impl Default for Settings {
@@ -73,14 +538,113 @@ impl Default for Settings {
         Self {
             rpc_list: Vec::new(),
             is_ws: true,
+            ma_length: 100.0,
             do_clear: false,
             address: "127.0.0.1:3000".parse::<SocketAddr>().unwrap(),
+            unix_socket: None,
+            listen_backlog: 1024,
+            max_concurrent_connections: 10_000,
+            allowed_origins: Vec::new(),
+            proxy_url: None,
+            no_proxy: None,
+            auth_token: None,
+            compression: false,
+            compression_min_size: 1024,
+            expose_node_header: false,
             health_check: false,
             ttl: 1000,
             max_retries: 32,
             health_check_ttl: 1000,
+            health_check_jitter: 0,
+            head_check_stagger: 0,
+            poverty_check_ttl: 0,
+            poverty_backoff_max: 0,
+            health_webhook_url: None,
+            head_agreement_quorum: HeadAgreementQuorum::Count(1),
+            ttl_tolerance_blocks: 0,
+            stale_latest_lag: 0,
+            stale_latest_strikes: 3,
+            max_consecutive_failures: 0,
+            fleet_metadata_ttl: 0,
+            shutdown_grace_ms: 10_000,
+            chain_id_check_ttl: 60_000,
+            check_net_version: false,
+            expected_chain_id: None,
+            probe_block_ttl: 300_000,
+            probe_block: None,
+            health_probes: Vec::new(),
+            selection_strategy: SelectionStrategy::RoundRobin,
+            latency_ema_alpha: 0.2,
+            validate_responses: false,
+            serve_stale_on_error: false,
+            shadow_sample_rate: 0.0,
+            shard_logs_cache: false,
+            max_logs_range: 0,
+            max_logs_chunks: 100,
+            broadcast_transactions: false,
+            serve_block_number_locally: true,
+            serve_named_blocks_locally: true,
+            archive_threshold: 0,
+            no_archive_message: Arc::from(
+                "error: No archive RPC available to serve historical state! Try again later...",
+            ),
+            future_blocks: FutureBlockBehavior::Forward,
+            subscription_queue_timeout: 30000,
+            ws_ping_interval_ms: 0,
+            ws_pong_timeout_ms: 10000,
+            tx_replay_window: 0,
+            sticky_sessions: false,
+            sticky_session_ttl: 60_000,
+            block_params: Arc::new(BlockParams::default()),
+            method_cache: Arc::new(MethodCache::default()),
+            method_timeouts: Arc::new(HashMap::new()),
+            method_filter: Arc::new(MethodFilter::default()),
+            method_routing: Arc::new(MethodRouting::default()),
+            response_transform: Arc::new(ResponseTransform::default()),
+            drop_stale_heads: false,
+            ws_max_notifications_per_second: 0,
+            ws_coalesce_new_heads: false,
+            ws_notification_buffer: 1024,
+            reorg_bypass_window: 0,
+            max_concurrent_requests: 0,
+            memory_cache_entries: 0,
+            cache_max_bytes: 0,
+            head_cache_enabled: true,
+            head_cache_max_blocks: 1024,
+            rate_limit_rps: 0,
+            rate_limit_burst: 0,
+            priority_clients: HashMap::new(),
+            log_rate_limit: 0,
+            log_level: LevelFilter::INFO,
+            log_format: LogFormat::Text,
+            access_log: None,
+            access_log_sample_rate: 1.0,
+            ready_after_warmup: false,
+            max_subscription_lifetime: 0,
+            ws_channel_capacity: 4096,
+            unknown_subscriptions: UnknownSubscriptions::Forward,
+            local_newheads: false,
+            startup_check: false,
+            startup_check_strict: false,
+            restore_health: true,
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            max_batch_size: 1000,
+            batch_concurrency: 16,
+            strict_jsonrpc: false,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            rate_limit_cooldown_ms: DEFAULT_RATE_LIMIT_COOLDOWN.as_millis() as u64,
+            head_method: Arc::new(HeadMethod::default()),
+            lazy_cache_migration: false,
+            cache_version: 0,
+            hedge_delay: 0,
+            request_deadline_ms: 0,
+            cache_warmup: Vec::new(),
             sled_config: sled::Config::default(),
             admin: AdminSettings::default(),
+            tls: None,
+            http: HttpSettings::default(),
+            config_path: None,
+            chains: BTreeMap::new(),
         }
     }
 }
@@ -91,22 +655,201 @@ impl Settings {
 
         // Try to open the file at the path specified in the args
         let path = matches.get_one::<String>("config").unwrap();
+        if matches.get_occurrences::<String>("check_config").is_some() {
+            std::process::exit(Settings::check_config(path).await);
+        }
         let file: Option<String> = match fs::read_to_string(path) {
             Ok(file) => Some(file),
+            Err(_) if !blutgang_vars().is_empty() => None,
             Err(_) => panic!("\x1b[31mErr:\x1b[0m Error opening config file at {}", path),
         };
 
         if let Some(file) = file {
             println!("\x1b[35mInfo:\x1b[0m Using config file at {}", path);
-            return Settings::create_from_file(file).await;
+            return Settings {
+                config_path: Some(path.to_string()),
+                ..Settings::create_from_file(file).await
+            };
+        }
+
+        // Containers can configure everything through the environment
+        if !blutgang_vars().is_empty() {
+            println!(
+                "\x1b[35mInfo:\x1b[0m No config file at {}, using environment variables for settings...",
+                path
+            );
+            return Settings::create_from_file(BASE_CONFIG.to_string()).await;
         }
 
         println!("\x1b[35mInfo:\x1b[0m Using command line arguments for settings...");
         Settings::create_from_matches(matches)
     }
 
-    async fn create_from_file(conf_file: String) -> Settings {
-        let parsed_toml = conf_file.parse::<Value>().expect("Error parsing TOML");
+    pub async fn create_from_file(conf_file: String) -> Settings {
+        Settings::parse_file(conf_file, true).await
+    }
+
+    // Parse and validate the config file at `path`, without starting anything.
+    //
+    // Returns the exit code for `--check-config`, 0 if the config is valid.
+    pub async fn check_config(path: &str) -> i32 {
+        let file = match fs::read_to_string(path) {
+            Ok(file) => file,
+            Err(_) if !blutgang_vars().is_empty() => BASE_CONFIG.to_string(),
+            Err(err) => {
+                eprintln!(
+                    "\x1b[31mErr:\x1b[0m Error opening config file at {}: {}",
+                    path, err
+                );
+                return 1;
+            }
+        };
+
+        // Parsing panics on invalid configs, print why instead of the whole panic.
+        //
+        // Parse on a thread of our own and only keep quiet about its panics,
+        // everything else still goes to the hook that was set before.
+        let previous = Arc::new(std::panic::take_hook());
+        std::panic::set_hook(Box::new({
+            let previous = Arc::clone(&previous);
+            move |info| {
+                if thread::current().name() != Some(CHECK_CONFIG_THREAD) {
+                    previous(info);
+                }
+            }
+        }));
+
+        // RPCs don't get sorted, so we don't need to reach them.
+        let parsed = tokio::task::spawn_blocking(move || {
+            thread::Builder::new()
+                .name(CHECK_CONFIG_THREAD.to_string())
+                .spawn(move || {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .expect("\x1b[31mErr:\x1b[0m Could not build runtime to check config!")
+                        .block_on(Settings::parse_file(file, false))
+                })
+                .expect("\x1b[31mErr:\x1b[0m Could not spawn thread to check config!")
+                .join()
+        })
+        .await
+        .expect("\x1b[31mErr:\x1b[0m Could not check config!");
+
+        // Put the previous hook back
+        drop(std::panic::take_hook());
+        match Arc::try_unwrap(previous) {
+            Ok(previous) => std::panic::set_hook(previous),
+            Err(previous) => std::panic::set_hook(Box::new(move |info| previous(info))),
+        }
+
+        let errors = match parsed {
+            Ok(settings) => settings.validate(),
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<String>()
+                    .map(String::as_str)
+                    .or_else(|| panic.downcast_ref::<&str>().copied())
+                    .unwrap_or("Could not parse config");
+                vec![message
+                    .trim_start_matches("\x1b[31mErr:\x1b[0m ")
+                    .to_string()]
+            }
+        };
+
+        if errors.is_empty() {
+            println!("\x1b[35mInfo:\x1b[0m Config at {} is valid", path);
+            return 0;
+        }
+
+        for error in &errors {
+            eprintln!("\x1b[31mErr:\x1b[0m {}", error);
+        }
+        1
+    }
+
+    // Problems parsing lets through, each naming the setting at fault
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.rpc_list.is_empty() {
+            errors.push("No RPCs configured".to_string());
+        }
+        errors.extend(rpc_url_errors(&self.rpc_list));
+        for (prefix, chain) in &self.chains {
+            if chain.rpc_list.is_empty() {
+                errors.push(format!("chains.{}: No RPCs configured", prefix));
+            }
+            errors.extend(
+                rpc_url_errors(&chain.rpc_list)
+                    .into_iter()
+                    .map(|error| format!("chains.{}: {}", prefix, error)),
+            );
+        }
+
+        if self.ttl == 0 {
+            errors.push("ttl: Must be above 0".to_string());
+        }
+        if self.ma_length <= 0.0 {
+            errors.push("ma_length: Must be above 0".to_string());
+        }
+        if self.health_check && self.health_check_ttl == 0 {
+            errors.push("health_check_ttl: Must be above 0 with health_check enabled".to_string());
+        }
+        if !self.health_check && !self.health_probes.is_empty() {
+            errors.push(
+                "health_probes: Probes and min_peers only run with health_check enabled"
+                    .to_string(),
+            );
+        }
+        if self.admin.enabled && self.admin.address == self.address {
+            errors.push(format!(
+                "admin.address: {} is also used by address",
+                self.admin.address
+            ));
+        }
+
+        // Files we read, and directories we create files in
+        if let Some(tls) = &self.tls {
+            for (field, path) in [
+                ("tls.cert_path", &tls.cert_path),
+                ("tls.key_path", &tls.key_path),
+            ] {
+                if !Path::new(path).is_file() {
+                    errors.push(format!("{}: {} does not exist", field, path));
+                }
+            }
+        }
+        for (field, path) in [
+            ("access_log", &self.access_log),
+            ("unix_socket", &self.unix_socket),
+        ] {
+            let parent = path
+                .as_deref()
+                .and_then(|path| Path::new(path).parent())
+                .filter(|parent| !parent.as_os_str().is_empty());
+            if let Some(parent) = parent {
+                if !parent.is_dir() {
+                    errors.push(format!(
+                        "{}: Directory {} does not exist",
+                        field,
+                        parent.display()
+                    ));
+                }
+            }
+        }
+
+        errors
+    }
+
+    // Parse a config file.
+    //
+    // RPCs are only sorted on startup if `sort` is set, since it sends requests to them.
+    pub(crate) async fn parse_file(conf_file: String, sort: bool) -> Settings {
+        let mut parsed_toml = conf_file.parse::<Value>().expect("Error parsing TOML");
+        if let Err(err) = apply_env_overrides(&mut parsed_toml, &blutgang_vars()) {
+            panic!("\x1b[31mErr:\x1b[0m Invalid environment variable {}", err);
+        }
 
         let table_names: Vec<&String> = parsed_toml.as_table().unwrap().keys().collect::<Vec<_>>();
 
@@ -146,6 +889,35 @@ impl Settings {
             .parse::<SocketAddr>()
             .expect("\x1b[31mErr:\x1b[0m Could not address to SocketAddr!");
 
+        // Optional, also listen on a unix socket at this path
+        let unix_socket = blutgang_table.get("unix_socket").map(|unix_socket| {
+            unix_socket
+                .as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse unix_socket as str!")
+                .to_string()
+        });
+
+        // Optional, how many connections can wait to be accepted on the TCP socket
+        let listen_backlog = blutgang_table
+            .get("listen_backlog")
+            .map(|listen_backlog| {
+                listen_backlog
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse listen_backlog as int!")
+                    as u32
+            })
+            .unwrap_or(Settings::default().listen_backlog);
+
+        // Optional, how many connections we serve at once. 0 is unlimited
+        let max_concurrent_connections = blutgang_table
+            .get("max_concurrent_connections")
+            .map(|max_concurrent_connections| {
+                max_concurrent_connections.as_integer().expect(
+                    "\x1b[31mErr:\x1b[0m Could not parse max_concurrent_connections as int!",
+                ) as usize
+            })
+            .unwrap_or(Settings::default().max_concurrent_connections);
+
         let ma_length = blutgang_table
             .get("ma_length")
             .expect("\x1b[31mErr:\x1b[0m Missing ma_length!")
@@ -181,6 +953,1053 @@ impl Settings {
             u64::MAX
         };
 
+        // Optional, max ms to randomly add to or subtract from `health_check_ttl`
+        let health_check_jitter = blutgang_table
+            .get("health_check_jitter")
+            .map(|health_check_jitter| {
+                health_check_jitter
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse health_check_jitter as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().health_check_jitter);
+
+        // Optional, max ms to randomly delay the head check of each RPC by
+        let head_check_stagger = blutgang_table
+            .get("head_check_stagger")
+            .map(|head_check_stagger| {
+                head_check_stagger
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse head_check_stagger as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().head_check_stagger);
+
+        // Optional, how often to check the poverty list. 0 checks it on every health check
+        let poverty_check_ttl = blutgang_table
+            .get("poverty_check_ttl")
+            .map(|poverty_check_ttl| {
+                poverty_check_ttl
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse poverty_check_ttl as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().poverty_check_ttl);
+
+        // Optional, max time in ms between checks of RPCs that keep failing to leave the poverty list
+        let poverty_backoff_max = blutgang_table
+            .get("poverty_backoff_max")
+            .map(|poverty_backoff_max| {
+                poverty_backoff_max
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse poverty_backoff_max as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().poverty_backoff_max);
+
+        // Optional, URL to POST RPC health changes to
+        let health_webhook_url =
+            blutgang_table
+                .get("health_webhook_url")
+                .map(|health_webhook_url| {
+                    health_webhook_url
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse health_webhook_url as str!")
+                        .to_string()
+                });
+
+        // Optional, how many RPCs need to agree on the head. Defaults to 1, the highest head
+        let head_agreement_quorum = blutgang_table
+            .get("head_agreement_quorum")
+            .map(HeadAgreementQuorum::from_toml)
+            .unwrap_or(Settings::default().head_agreement_quorum);
+
+        // Optional, how many blocks an RPC can be behind the agreed head without being removed
+        let ttl_tolerance_blocks = blutgang_table
+            .get("ttl_tolerance_blocks")
+            .map(|ttl_tolerance_blocks| {
+                ttl_tolerance_blocks
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse ttl_tolerance_blocks as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().ttl_tolerance_blocks);
+
+        // Optional, how far behind the agreed head the `latest` block an RPC serves can be.
+        // 0 disables checking the `latest` block
+        let stale_latest_lag = blutgang_table
+            .get("stale_latest_lag")
+            .map(|stale_latest_lag| {
+                stale_latest_lag
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse stale_latest_lag as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().stale_latest_lag);
+
+        // Optional, how many health checks in a row an RPC can serve a stale `latest` block
+        let stale_latest_strikes = blutgang_table
+            .get("stale_latest_strikes")
+            .map(|stale_latest_strikes| {
+                stale_latest_strikes
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse stale_latest_strikes as int!")
+                    as u32
+            })
+            .unwrap_or(Settings::default().stale_latest_strikes);
+
+        // Optional, how many failed health checks in a row before an unresponsive RPC is removed
+        let max_consecutive_failures = blutgang_table
+            .get("max_consecutive_failures")
+            .map(|max_consecutive_failures| {
+                max_consecutive_failures
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_consecutive_failures as int!")
+                    as u32
+            })
+            .unwrap_or(Settings::default().max_consecutive_failures);
+
+        // Optional, how often to collect node metadata for `blutgang_fleet`. 0 disables it
+        let fleet_metadata_ttl = blutgang_table
+            .get("fleet_metadata_ttl")
+            .map(|fleet_metadata_ttl| {
+                fleet_metadata_ttl
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse fleet_metadata_ttl as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().fleet_metadata_ttl);
+
+        // Optional, how long to wait for connections to finish when shutting down
+        let shutdown_grace_ms = blutgang_table
+            .get("shutdown_grace_ms")
+            .map(|shutdown_grace_ms| {
+                shutdown_grace_ms
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse shutdown_grace_ms as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().shutdown_grace_ms);
+
+        // Optional, how often to check the RPCs are on the right chain
+        let chain_id_check_ttl = blutgang_table
+            .get("chain_id_check_ttl")
+            .map(|chain_id_check_ttl| {
+                chain_id_check_ttl
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse chain_id_check_ttl as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().chain_id_check_ttl);
+
+        // Optional, defaults to the chain id reported by most RPCs
+        let expected_chain_id = blutgang_table
+            .get("expected_chain_id")
+            .map(|expected_chain_id| {
+                expected_chain_id
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse expected_chain_id as int!")
+                    as u64
+            });
+
+        // Optional, also ban RPCs whose net_version disagrees with their chain id
+        let check_net_version = blutgang_table
+            .get("check_net_version")
+            .map(|check_net_version| {
+                check_net_version
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse check_net_version as bool!")
+            })
+            .unwrap_or(Settings::default().check_net_version);
+
+        // Optional, how often to check the RPCs agree on the hash of `probe_block_number`
+        let probe_block_ttl = blutgang_table
+            .get("probe_block_ttl")
+            .map(|probe_block_ttl| {
+                probe_block_ttl
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse probe_block_ttl as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().probe_block_ttl);
+
+        // Optional, the probe is disabled unless both are set
+        let probe_block_number = blutgang_table.get("probe_block_number").map(|number| {
+            number
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse probe_block_number as int!")
+                as u64
+        });
+        let probe_block_hash = blutgang_table.get("probe_block_hash").map(|hash| {
+            hash.as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse probe_block_hash as str!")
+                .to_lowercase()
+        });
+        let probe_block = match (probe_block_number, probe_block_hash) {
+            (Some(number), Some(hash)) => Some((number, hash)),
+            (None, None) => None,
+            _ => {
+                panic!(
+                    "\x1b[31mErr:\x1b[0m probe_block_number and probe_block_hash must be set together!"
+                )
+            }
+        };
+
+        // Optional, remove RPCs with fewer peers than this. 0 disables it
+        let min_peers = blutgang_table
+            .get("min_peers")
+            .map(|min_peers| {
+                min_peers
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse min_peers as int!")
+                    as u64
+            })
+            .unwrap_or(0);
+
+        // Optional, defaults to weighted round robin
+        let selection_strategy = blutgang_table
+            .get("selection_strategy")
+            .map(|selection_strategy| {
+                SelectionStrategy::from_str(
+                    selection_strategy
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse selection_strategy as str!"),
+                )
+            })
+            .unwrap_or(Settings::default().selection_strategy);
+
+        // Optional, how much weight new latency samples have in the moving average
+        let latency_ema_alpha = blutgang_table
+            .get("latency_ema_alpha")
+            .map(|latency_ema_alpha| {
+                latency_ema_alpha
+                    .as_float()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse latency_ema_alpha as float!")
+            })
+            .unwrap_or(Settings::default().latency_ema_alpha);
+        if latency_ema_alpha <= 0.0 || latency_ema_alpha > 1.0 {
+            panic!("\x1b[31mErr:\x1b[0m latency_ema_alpha must be between 0 and 1!");
+        }
+
+        // Optional, defaults to forwarding responses without checking them
+        let validate_responses = blutgang_table
+            .get("validate_responses")
+            .map(|validate_responses| {
+                validate_responses
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse validate_responses as bool!")
+            })
+            .unwrap_or(Settings::default().validate_responses);
+
+        // Optional, serve expired cache entries when every RPC fails a request
+        let serve_stale_on_error = blutgang_table
+            .get("serve_stale_on_error")
+            .map(|serve_stale_on_error| {
+                serve_stale_on_error
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse serve_stale_on_error as bool!")
+            })
+            .unwrap_or(Settings::default().serve_stale_on_error);
+
+        // Optional, fraction of read requests to also send to a second RPC to compare responses
+        let shadow_sample_rate = blutgang_table
+            .get("shadow_sample_rate")
+            .map(|shadow_sample_rate| {
+                shadow_sample_rate
+                    .as_float()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse shadow_sample_rate as float!")
+            })
+            .unwrap_or(Settings::default().shadow_sample_rate);
+        if !(0.0..=1.0).contains(&shadow_sample_rate) {
+            panic!("\x1b[31mErr:\x1b[0m shadow_sample_rate must be between 0 and 1!");
+        }
+
+        // Optional, cache finalized logs per block and address instead of per request
+        let shard_logs_cache = blutgang_table
+            .get("shard_logs_cache")
+            .map(|shard_logs_cache| {
+                shard_logs_cache
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse shard_logs_cache as bool!")
+            })
+            .unwrap_or(Settings::default().shard_logs_cache);
+
+        // Optional, split `eth_getLogs` over larger ranges into chunks of this many blocks
+        let max_logs_range = blutgang_table
+            .get("max_logs_range")
+            .map(|max_logs_range| {
+                max_logs_range
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_logs_range as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().max_logs_range);
+
+        // Optional, most chunks a single `eth_getLogs` request gets split into
+        let max_logs_chunks = blutgang_table
+            .get("max_logs_chunks")
+            .map(|max_logs_chunks| {
+                max_logs_chunks
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_logs_chunks as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().max_logs_chunks);
+
+        // Optional, send transactions to every RPC instead of just one
+        let broadcast_transactions = blutgang_table
+            .get("broadcast_transactions")
+            .map(|broadcast_transactions| {
+                broadcast_transactions
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse broadcast_transactions as bool!")
+            })
+            .unwrap_or(Settings::default().broadcast_transactions);
+
+        // Optional, answer `eth_blockNumber` from the head we're tracking
+        let serve_block_number_locally = blutgang_table
+            .get("serve_block_number_locally")
+            .map(|serve_block_number_locally| {
+                serve_block_number_locally.as_bool().expect(
+                    "\x1b[31mErr:\x1b[0m Could not parse serve_block_number_locally as bool!",
+                )
+            })
+            .unwrap_or(Settings::default().serve_block_number_locally);
+
+        // Optional, answer requests for the finalized and safe block numbers locally
+        let serve_named_blocks_locally = blutgang_table
+            .get("serve_named_blocks_locally")
+            .map(|serve_named_blocks_locally| {
+                serve_named_blocks_locally.as_bool().expect(
+                    "\x1b[31mErr:\x1b[0m Could not parse serve_named_blocks_locally as bool!",
+                )
+            })
+            .unwrap_or(Settings::default().serve_named_blocks_locally);
+
+        // Optional, route requests for old state to archive nodes only
+        let archive_threshold = blutgang_table
+            .get("archive_threshold")
+            .map(|archive_threshold| {
+                archive_threshold
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse archive_threshold as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().archive_threshold);
+        // Optional, error message for requests only an archive node could serve
+        let no_archive_message = blutgang_table
+            .get("no_archive_message")
+            .map(|no_archive_message| {
+                Arc::from(
+                    no_archive_message
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse no_archive_message as str!"),
+                )
+            })
+            .unwrap_or(Settings::default().no_archive_message);
+
+        // Optional, defaults to forwarding requests for future blocks
+        let future_blocks = blutgang_table
+            .get("future_blocks")
+            .map(|future_blocks| {
+                FutureBlockBehavior::from_str(
+                    future_blocks
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse future_blocks as str!"),
+                )
+            })
+            .unwrap_or_default();
+
+        // Optional, how long to hold `logs` subscriptions while no WS node is available
+        let subscription_queue_timeout = blutgang_table
+            .get("subscription_queue_timeout")
+            .map(|subscription_queue_timeout| {
+                subscription_queue_timeout.as_integer().expect(
+                    "\x1b[31mErr:\x1b[0m Could not parse subscription_queue_timeout as int!",
+                ) as u64
+            })
+            .unwrap_or(Settings::default().subscription_queue_timeout);
+
+        // Optional, how often to ping WS nodes and how long to wait for a pong
+        let ws_ping_interval_ms = blutgang_table
+            .get("ws_ping_interval_ms")
+            .map(|ws_ping_interval_ms| {
+                ws_ping_interval_ms
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse ws_ping_interval_ms as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().ws_ping_interval_ms);
+
+        let ws_pong_timeout_ms = blutgang_table
+            .get("ws_pong_timeout_ms")
+            .map(|ws_pong_timeout_ms| {
+                ws_pong_timeout_ms
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse ws_pong_timeout_ms as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().ws_pong_timeout_ms);
+
+        // Optional, how long to suppress duplicate raw transaction submissions for
+        let tx_replay_window = blutgang_table
+            .get("tx_replay_window")
+            .map(|tx_replay_window| {
+                tx_replay_window
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse tx_replay_window as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().tx_replay_window);
+
+        // Optional, keep routing each client's requests to the same RPC
+        let sticky_sessions = blutgang_table
+            .get("sticky_sessions")
+            .map(|sticky_sessions| {
+                sticky_sessions
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse sticky_sessions as bool!")
+            })
+            .unwrap_or(Settings::default().sticky_sessions);
+
+        // Optional, how long a session sticks to its RPC after its last request
+        let sticky_session_ttl = blutgang_table
+            .get("sticky_session_ttl")
+            .map(|sticky_session_ttl| {
+                sticky_session_ttl
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse sticky_session_ttl as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().sticky_session_ttl);
+
+        // Optional, drop out of order and duplicate heads from `newHeads` subscriptions
+        let drop_stale_heads = blutgang_table
+            .get("drop_stale_heads")
+            .map(|drop_stale_heads| {
+                drop_stale_heads
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse drop_stale_heads as bool!")
+            })
+            .unwrap_or(Settings::default().drop_stale_heads);
+
+        // Optional, notifications a single subscription can get per second. 0 is unlimited
+        let ws_max_notifications_per_second = blutgang_table
+            .get("ws_max_notifications_per_second")
+            .map(|ws_max_notifications_per_second| {
+                ws_max_notifications_per_second.as_integer().expect(
+                    "\x1b[31mErr:\x1b[0m Could not parse ws_max_notifications_per_second as int!",
+                ) as u32
+            })
+            .unwrap_or(Settings::default().ws_max_notifications_per_second);
+
+        // Optional, only send the latest head to clients behind on `newHeads`
+        let ws_coalesce_new_heads = blutgang_table
+            .get("ws_coalesce_new_heads")
+            .map(|ws_coalesce_new_heads| {
+                ws_coalesce_new_heads
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse ws_coalesce_new_heads as bool!")
+            })
+            .unwrap_or(Settings::default().ws_coalesce_new_heads);
+
+        // Optional, notifications buffered per client before the oldest get dropped
+        let ws_notification_buffer = blutgang_table
+            .get("ws_notification_buffer")
+            .map(|ws_notification_buffer| {
+                ws_notification_buffer
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse ws_notification_buffer as int!")
+                    as usize
+            })
+            .unwrap_or(Settings::default().ws_notification_buffer);
+
+        // Optional, how long to bypass the cache for unfinalized blocks after a reorg
+        let reorg_bypass_window = blutgang_table
+            .get("reorg_bypass_window")
+            .map(|reorg_bypass_window| {
+                reorg_bypass_window
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse reorg_bypass_window as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().reorg_bypass_window);
+
+        // Optional, max requests forwarded to the RPCs at once
+        let max_concurrent_requests = blutgang_table
+            .get("max_concurrent_requests")
+            .map(|max_concurrent_requests| {
+                max_concurrent_requests
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_concurrent_requests as int!")
+                    as usize
+            })
+            .unwrap_or(Settings::default().max_concurrent_requests);
+
+        // Optional, how many cache entries to keep in memory in front of sled
+        let memory_cache_entries = blutgang_table
+            .get("memory_cache_entries")
+            .map(|memory_cache_entries| {
+                memory_cache_entries
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse memory_cache_entries as int!")
+                    as usize
+            })
+            .unwrap_or(Settings::default().memory_cache_entries);
+
+        // Optional, how big the cache can get on disk before we evict entries
+        let cache_max_bytes = blutgang_table
+            .get("cache_max_bytes")
+            .map(|cache_max_bytes| {
+                cache_max_bytes
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse cache_max_bytes as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().cache_max_bytes);
+
+        // Optional, whether to cache responses for unfinalized blocks
+        let head_cache_enabled = blutgang_table
+            .get("head_cache_enabled")
+            .map(|head_cache_enabled| {
+                head_cache_enabled
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse head_cache_enabled as bool!")
+            })
+            .unwrap_or(Settings::default().head_cache_enabled);
+
+        // Optional, how far below the head unfinalized responses are kept around
+        let head_cache_max_blocks = blutgang_table
+            .get("head_cache_max_blocks")
+            .map(|head_cache_max_blocks| {
+                head_cache_max_blocks
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse head_cache_max_blocks as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().head_cache_max_blocks);
+
+        // Optional, requests per second each client IP can make. 0 disables rate limiting
+        let rate_limit_rps = blutgang_table
+            .get("rate_limit_rps")
+            .map(|rate_limit_rps| {
+                rate_limit_rps
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse rate_limit_rps as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().rate_limit_rps);
+
+        // Optional, how many requests each client IP can make at once
+        let rate_limit_burst = blutgang_table
+            .get("rate_limit_burst")
+            .map(|rate_limit_burst| {
+                rate_limit_burst
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse rate_limit_burst as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().rate_limit_burst);
+
+        // Optional, collapse repeated log lines within this window
+        let log_rate_limit = blutgang_table
+            .get("log_rate_limit")
+            .map(|log_rate_limit| {
+                log_rate_limit
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse log_rate_limit as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().log_rate_limit);
+
+        // Optional, most verbose level we log at
+        let log_level = blutgang_table
+            .get("log_level")
+            .map(|log_level| {
+                let log_level = log_level
+                    .as_str()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse log_level as str!");
+                parse_log_level(log_level).expect(
+                    "\x1b[31mErr:\x1b[0m Invalid log_level! Can be `error`, `warn`, `info`, `debug` or `trace`.",
+                )
+            })
+            .unwrap_or(Settings::default().log_level);
+
+        // Optional, format of log lines
+        let log_format = blutgang_table
+            .get("log_format")
+            .map(|log_format| {
+                let log_format = log_format
+                    .as_str()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse log_format as str!");
+                LogFormat::from_str(log_format)
+                    .expect("\x1b[31mErr:\x1b[0m Invalid log_format! Can be `text` or `json`.")
+            })
+            .unwrap_or(Settings::default().log_format);
+
+        // Optional, file to write a line to for every request
+        let access_log = blutgang_table.get("access_log").map(|access_log| {
+            access_log
+                .as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse access_log as str!")
+                .to_string()
+        });
+
+        // Optional, share of requests written to the access log
+        let access_log_sample_rate = blutgang_table
+            .get("access_log_sample_rate")
+            .map(|access_log_sample_rate| {
+                access_log_sample_rate
+                    .as_float()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse access_log_sample_rate as float!")
+            })
+            .unwrap_or(Settings::default().access_log_sample_rate);
+        if !(0.0..=1.0).contains(&access_log_sample_rate) {
+            panic!("\x1b[31mErr:\x1b[0m access_log_sample_rate must be between 0 and 1!");
+        }
+
+        // Optional, only report as ready once the cache warmup is done
+        let ready_after_warmup = blutgang_table
+            .get("ready_after_warmup")
+            .map(|ready_after_warmup| {
+                ready_after_warmup
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse ready_after_warmup as bool!")
+            })
+            .unwrap_or(Settings::default().ready_after_warmup);
+
+        // Optional, cancel subscriptions after they've been active for this long
+        let max_subscription_lifetime = blutgang_table
+            .get("max_subscription_lifetime")
+            .map(|max_subscription_lifetime| {
+                max_subscription_lifetime
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_subscription_lifetime as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().max_subscription_lifetime);
+
+        // Optional, messages queued for the WS connection manager before senders wait
+        let ws_channel_capacity = blutgang_table
+            .get("ws_channel_capacity")
+            .map(|ws_channel_capacity| {
+                ws_channel_capacity
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse ws_channel_capacity as int!")
+                    as usize
+            })
+            .unwrap_or(Settings::default().ws_channel_capacity);
+        if ws_channel_capacity == 0 {
+            panic!("\x1b[31mErr:\x1b[0m ws_channel_capacity can't be 0!");
+        }
+
+        // Optional, time after which slow requests get hedged to another RPC
+        let hedge_delay = blutgang_table
+            .get("hedge_delay")
+            .map(|hedge_delay| {
+                hedge_delay
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse hedge_delay as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().hedge_delay);
+
+        // Optional, how long a request can take in total before we give up on it
+        let request_deadline_ms = blutgang_table
+            .get("request_deadline_ms")
+            .map(|request_deadline_ms| {
+                request_deadline_ms
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse request_deadline_ms as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().request_deadline_ms);
+
+        // Optional, what to do with subscription types we can't validate
+        let unknown_subscriptions =
+            blutgang_table
+                .get("unknown_subscriptions")
+                .map(|unknown_subscriptions| {
+                    UnknownSubscriptions::from_str(unknown_subscriptions.as_str().expect(
+                        "\x1b[31mErr:\x1b[0m Could not parse unknown_subscriptions as str!",
+                    ))
+                })
+                .unwrap_or(Settings::default().unknown_subscriptions);
+
+        // Optional, serve newHeads subscriptions from the heads the health check sees
+        let local_newheads = blutgang_table
+            .get("local_newheads")
+            .map(|local_newheads| {
+                local_newheads
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse local_newheads as bool!")
+            })
+            .unwrap_or(Settings::default().local_newheads);
+
+        // Optional, check that every RPC is reachable and on the right chain on startup
+        let startup_check = blutgang_table
+            .get("startup_check")
+            .map(|startup_check| {
+                startup_check
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse startup_check as bool!")
+            })
+            .unwrap_or(Settings::default().startup_check);
+
+        // Optional, exit instead of demoting RPCs that fail the startup check
+        let startup_check_strict = blutgang_table
+            .get("startup_check_strict")
+            .map(|startup_check_strict| {
+                startup_check_strict
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse startup_check_strict as bool!")
+            })
+            .unwrap_or(Settings::default().startup_check_strict);
+
+        // Optional, start RPCs that were in poverty before restarting in poverty
+        let restore_health = blutgang_table
+            .get("restore_health")
+            .map(|restore_health| {
+                restore_health
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse restore_health as bool!")
+            })
+            .unwrap_or(Settings::default().restore_health);
+
+        // Optional, largest request body we accept from clients
+        let max_request_bytes = blutgang_table
+            .get("max_request_bytes")
+            .map(|max_request_bytes| {
+                max_request_bytes
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_request_bytes as int!")
+                    as usize
+            })
+            .unwrap_or(Settings::default().max_request_bytes);
+
+        // Optional, most calls we accept in a single batch
+        let max_batch_size = blutgang_table
+            .get("max_batch_size")
+            .map(|max_batch_size| {
+                max_batch_size
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_batch_size as int!")
+                    as usize
+            })
+            .unwrap_or(Settings::default().max_batch_size);
+
+        // Optional, how many calls of a batch we serve at once
+        let batch_concurrency = blutgang_table
+            .get("batch_concurrency")
+            .map(|batch_concurrency| {
+                batch_concurrency
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse batch_concurrency as int!")
+                    as usize
+            })
+            .unwrap_or(Settings::default().batch_concurrency);
+
+        // Optional, reject requests without a `"jsonrpc": "2.0"` version
+        let strict_jsonrpc = blutgang_table
+            .get("strict_jsonrpc")
+            .map(|strict_jsonrpc| {
+                strict_jsonrpc
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse strict_jsonrpc as bool!")
+            })
+            .unwrap_or(Settings::default().strict_jsonrpc);
+
+        // Optional, largest response we accept from RPCs
+        let max_response_bytes = blutgang_table
+            .get("max_response_bytes")
+            .map(|max_response_bytes| {
+                max_response_bytes
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_response_bytes as int!")
+                    as usize
+            })
+            .unwrap_or(Settings::default().max_response_bytes);
+
+        // Optional, how long to skip RPCs for after they respond with HTTP 429
+        let rate_limit_cooldown_ms = blutgang_table
+            .get("rate_limit_cooldown_ms")
+            .map(|rate_limit_cooldown_ms| {
+                rate_limit_cooldown_ms
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse rate_limit_cooldown_ms as int!")
+                    as u64
+            })
+            .unwrap_or(Settings::default().rate_limit_cooldown_ms);
+
+        // Optional, how to get the head block from RPCs on chains without `eth_blockNumber`
+        let default_head_method = HeadMethod::default();
+        let head_method = Arc::new(HeadMethod {
+            method: blutgang_table
+                .get("head_method")
+                .map(|head_method| {
+                    head_method
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse head_method as str!")
+                        .to_string()
+                })
+                .unwrap_or(default_head_method.method),
+            result_pointer: blutgang_table
+                .get("head_result_pointer")
+                .map(|head_result_pointer| {
+                    head_result_pointer
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse head_result_pointer as str!")
+                        .to_string()
+                })
+                .unwrap_or(default_head_method.result_pointer),
+            format: blutgang_table
+                .get("head_number_format")
+                .map(|head_number_format| {
+                    let head_number_format = head_number_format
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse head_number_format as str!");
+                    NumberFormat::from_str(head_number_format).expect(
+                        "\x1b[31mErr:\x1b[0m Invalid head_number_format! Can be `hex` or `decimal`.",
+                    )
+                })
+                .unwrap_or(default_head_method.format),
+        });
+
+        // Optional, move finalized entries from older cache versions on read
+        let lazy_cache_migration = blutgang_table
+            .get("lazy_cache_migration")
+            .map(|lazy_cache_migration| {
+                lazy_cache_migration
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse lazy_cache_migration as bool!")
+            })
+            .unwrap_or(Settings::default().lazy_cache_migration);
+
+        // Optional, namespace of cache entries. Bumping it invalidates the whole cache
+        let cache_version = blutgang_table
+            .get("cache_version")
+            .map(|cache_version| {
+                let cache_version = cache_version
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse cache_version as int!");
+                u8::try_from(cache_version)
+                    .expect("\x1b[31mErr:\x1b[0m cache_version must be between 0 and 255!")
+            })
+            .unwrap_or(Settings::default().cache_version);
+
+        // Optional, requests to cache on startup
+        let cache_warmup = blutgang_table
+            .get("cache_warmup")
+            .map(|cache_warmup| {
+                cache_warmup
+                    .as_array()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse cache_warmup as array!")
+                    .iter()
+                    .map(|request| {
+                        serde_json::to_value(request)
+                            .expect("\x1b[31mErr:\x1b[0m Invalid request in cache_warmup!")
+                    })
+                    .collect()
+            })
+            .unwrap_or(Settings::default().cache_warmup);
+
+        // Optional, methods whose responses are never cached
+        let no_cache_methods = blutgang_table
+            .get("no_cache_methods")
+            .map(|no_cache_methods| {
+                no_cache_methods
+                    .as_array()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse no_cache_methods as array!")
+                    .iter()
+                    .map(|method| {
+                        method
+                            .as_str()
+                            .expect("\x1b[31mErr:\x1b[0m Invalid method in no_cache_methods!")
+                            .to_string()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Optional, cache JSON-RPC errors for `error_cache_ttl` ms
+        let cache_errors = blutgang_table
+            .get("cache_errors")
+            .map(|cache_errors| {
+                cache_errors
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse cache_errors as bool!")
+            })
+            .unwrap_or(false);
+        let error_cache_ttl = blutgang_table
+            .get("error_cache_ttl")
+            .map(|error_cache_ttl| {
+                error_cache_ttl
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse error_cache_ttl as int!")
+                    as u64
+            })
+            .unwrap_or(5000);
+        let error_ttl = cache_errors.then(|| Duration::from_millis(error_cache_ttl));
+
+        // Optional, methods clients can call. Every method is allowed if empty
+        let allowed_methods = blutgang_table
+            .get("allowed_methods")
+            .map(|allowed_methods| {
+                allowed_methods
+                    .as_array()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse allowed_methods as array!")
+                    .iter()
+                    .map(|method| {
+                        method
+                            .as_str()
+                            .expect("\x1b[31mErr:\x1b[0m Invalid method in allowed_methods!")
+                            .to_string()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Optional, error message for calls to methods we don't serve
+        let blocked_method_message = blutgang_table
+            .get("blocked_method_message")
+            .map(|blocked_method_message| {
+                blocked_method_message
+                    .as_str()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse blocked_method_message as str!")
+                    .to_string()
+            })
+            .unwrap_or(DEFAULT_BLOCKED_MESSAGE.to_string());
+
+        // Optional, methods clients can't call, even if they're in `allowed_methods`
+        let denied_methods = blutgang_table
+            .get("denied_methods")
+            .map(|denied_methods| {
+                denied_methods
+                    .as_array()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse denied_methods as array!")
+                    .iter()
+                    .map(|method| {
+                        method
+                            .as_str()
+                            .expect("\x1b[31mErr:\x1b[0m Invalid method in denied_methods!")
+                            .to_string()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Optional, JSON paths to strip from responses before returning and caching them
+        let strip_response_fields: Vec<String> = blutgang_table
+            .get("strip_response_fields")
+            .map(|strip_response_fields| {
+                strip_response_fields
+                    .as_array()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse strip_response_fields as array!")
+                    .iter()
+                    .map(|path| {
+                        path.as_str()
+                            .expect("\x1b[31mErr:\x1b[0m Invalid path in strip_response_fields!")
+                            .to_string()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let response_transform = Arc::new(
+            ResponseTransform::new(&strip_response_fields)
+                .expect("\x1b[31mErr:\x1b[0m Invalid path in strip_response_fields!"),
+        );
+
+        // Optional, origins browsers can call us from. CORS is off if empty
+        let allowed_origins = blutgang_table
+            .get("allowed_origins")
+            .map(|allowed_origins| {
+                allowed_origins
+                    .as_array()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse allowed_origins as array!")
+                    .iter()
+                    .map(|origin| {
+                        origin
+                            .as_str()
+                            .expect("\x1b[31mErr:\x1b[0m Invalid origin in allowed_origins!")
+                            .to_string()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Optional, proxy to send requests to the RPCs through
+        let proxy_url = blutgang_table.get("proxy_url").map(|proxy_url| {
+            let proxy_url = proxy_url
+                .as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse proxy_url as str!")
+                .to_string();
+            reqwest::Proxy::all(&proxy_url).expect("\x1b[31mErr:\x1b[0m Invalid proxy_url!");
+            proxy_url
+        });
+        let no_proxy = blutgang_table.get("no_proxy").map(|no_proxy| {
+            no_proxy
+                .as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse no_proxy as str!")
+                .to_string()
+        });
+
+        // Optional, bearer token requests need to carry
+        let auth_token = blutgang_table.get("auth_token").map(|auth_token| {
+            auth_token
+                .as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse auth_token as str!")
+                .to_string()
+        });
+
+        // Optional, compress responses for clients that accept it
+        let response_compression = blutgang_table
+            .get("compression")
+            .map(|compression| {
+                compression
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse compression as bool!")
+            })
+            .unwrap_or(Settings::default().compression);
+
+        // Optional, only compress responses at least this many bytes long
+        let compression_min_size = blutgang_table
+            .get("compression_min_size")
+            .map(|compression_min_size| {
+                compression_min_size
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse compression_min_size as int!")
+                    as usize
+            })
+            .unwrap_or(Settings::default().compression_min_size);
+
+        // Optional, name the RPC that served a request in `X-Blutgang-Node`
+        let expose_node_header = blutgang_table
+            .get("expose_node_header")
+            .map(|expose_node_header| {
+                expose_node_header
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse expose_node_header as bool!")
+            })
+            .unwrap_or(Settings::default().expose_node_header);
+
+        // Optional, max priority trusted clients can request, by IP
+        let mut priority_clients = HashMap::new();
+        if let Some(clients) = blutgang_table.get("priority_clients") {
+            let clients = clients
+                .as_table()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse priority_clients as table!");
+            for (ip, priority) in clients {
+                let ip = ip
+                    .parse::<IpAddr>()
+                    .expect("\x1b[31mErr:\x1b[0m Invalid IP in priority_clients!");
+                let priority = priority
+                    .as_str()
+                    .and_then(Priority::from_str)
+                    .expect("\x1b[31mErr:\x1b[0m Invalid priority in priority_clients! Can be `low`, `normal` or `high`.");
+                priority_clients.insert(ip, priority);
+            }
+        }
+
         // Parse `sled` table
         let sled_table = parsed_toml
             .get("sled")
@@ -220,30 +2039,238 @@ impl Settings {
             .expect("\x1b[31mErr:\x1b[0m Missing sled_mode!")
             .as_str()
             .expect("\x1b[31mErr:\x1b[0m Could not parse sled_mode as str!");
-        let mut sled_mode = sled::Mode::HighThroughput;
-
-        if sled_mode_str == "LowSpace" {
-            sled_mode = sled::Mode::LowSpace;
-        }
 
         // Create sled config
         let sled_config = Config::new()
             .path(db_path)
             .cache_capacity(cache_capacity.try_into().unwrap())
-            .mode(sled_mode)
-            .flush_every_ms(Some(flush_every_ms as u64))
+            .mode(sled_mode(sled_mode_str))
+            .flush_every_ms(sled_flush_every_ms(flush_every_ms as u64))
             .print_profile_on_drop(print_profile)
             .use_compression(compression);
 
+        // Parse the optional `block_params` table
+        //
+        // Maps methods to the position of their block param, either as a param
+        // index or as a JSON pointer into `params`.
+        let mut custom_block_params = HashMap::new();
+        if let Some(block_params_table) = parsed_toml.get("block_params") {
+            let block_params_table = block_params_table
+                .as_table()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse block_params table!");
+
+            for (method, position) in block_params_table {
+                let pointer = match position {
+                    Value::Integer(index) if *index >= 0 => format!("/{}", index),
+                    Value::String(pointer) if pointer.starts_with('/') => pointer.to_owned(),
+                    _ => {
+                        panic!(
+                            "\x1b[31mErr:\x1b[0m Invalid block param position for {}! Must be a param index or a JSON pointer.",
+                            method
+                        )
+                    }
+                };
+                custom_block_params.insert(method.to_owned(), pointer);
+            }
+        }
+        let block_params = Arc::new(BlockParams::new(custom_block_params));
+
+        // Parse the optional `method_cache` table
+        //
+        // Maps methods to how many seconds their responses are cached for.
+        let mut method_ttls = HashMap::new();
+        if let Some(method_cache_table) = parsed_toml.get("method_cache") {
+            let method_cache_table = method_cache_table
+                .as_table()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse method_cache table!");
+
+            for (method, ttl) in method_cache_table {
+                let ttl = ttl
+                    .as_integer()
+                    .filter(|ttl| *ttl >= 0)
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "\x1b[31mErr:\x1b[0m Invalid cache TTL for {}! Must be a number of seconds.",
+                            method
+                        )
+                    });
+                method_ttls.insert(method.to_owned(), ttl as u64);
+            }
+        }
+        let method_cache =
+            Arc::new(MethodCache::new(method_ttls, no_cache_methods).with_error_ttl(error_ttl));
+
+        // Parse the optional `method_timeouts` table
+        //
+        // Maps methods to how many ms we wait for an RPC to respond, instead of `ttl`.
+        let mut method_timeouts = HashMap::new();
+        if let Some(method_timeouts_table) = parsed_toml.get("method_timeouts") {
+            let method_timeouts_table = method_timeouts_table
+                .as_table()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse method_timeouts table!");
+
+            for (method, timeout) in method_timeouts_table {
+                let timeout = timeout
+                    .as_integer()
+                    .filter(|timeout| *timeout > 0)
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "\x1b[31mErr:\x1b[0m Invalid timeout for {}! Must be a number of ms.",
+                            method
+                        )
+                    });
+                method_timeouts.insert(method.to_owned(), timeout as u128);
+            }
+        }
+        let method_timeouts = Arc::new(method_timeouts);
+
+        // Parse the optional `method_routing` table
+        //
+        // Maps methods, or method prefixes ending with `_`, to the group of RPCs serving them.
+        let mut routes = HashMap::new();
+        if let Some(method_routing_table) = parsed_toml.get("method_routing") {
+            let method_routing_table = method_routing_table
+                .as_table()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse method_routing table!");
+
+            for (method, group) in method_routing_table {
+                let group = group.as_str().unwrap_or_else(|| {
+                    panic!(
+                        "\x1b[31mErr:\x1b[0m Invalid group for {} in method_routing! Must be a str.",
+                        method
+                    )
+                });
+                routes.insert(method.to_owned(), group.to_string());
+            }
+        }
+        let method_routing = Arc::new(MethodRouting::new(routes));
+
+        // Parse the optional `health_probes` table
+        //
+        // Maps methods to call during health checks to the bounds their result needs to be within.
+        let mut health_probes = Vec::new();
+        if min_peers != 0 {
+            health_probes.push(HealthProbe::min_peers(min_peers));
+        }
+        if let Some(health_probes_table) = parsed_toml.get("health_probes") {
+            let health_probes_table = health_probes_table
+                .as_table()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse health_probes table!");
+
+            for (method, probe) in health_probes_table {
+                let probe = probe.as_table().unwrap_or_else(|| {
+                    panic!(
+                        "\x1b[31mErr:\x1b[0m Invalid health probe for {}! Must be a table.",
+                        method
+                    )
+                });
+                let bound = |name: &str| {
+                    probe.get(name).map(|bound| {
+                        bound
+                            .as_integer()
+                            .filter(|bound| *bound >= 0)
+                            .unwrap_or_else(|| {
+                                panic!(
+                                    "\x1b[31mErr:\x1b[0m Invalid {} for health probe {}! Must be a positive int.",
+                                    name, method
+                                )
+                            }) as u64
+                    })
+                };
+                let (min, max) = (bound("min"), bound("max"));
+                if min.is_none() && max.is_none() {
+                    panic!(
+                        "\x1b[31mErr:\x1b[0m Health probe {} needs a min, a max or both!",
+                        method
+                    );
+                }
+                let params = match probe.get("params") {
+                    Some(params @ Value::Array(_)) => serde_json::to_value(params).unwrap(),
+                    Some(_) => {
+                        panic!(
+                            "\x1b[31mErr:\x1b[0m Invalid params for health probe {}! Must be an array.",
+                            method
+                        )
+                    }
+                    None => serde_json::Value::Array(Vec::new()),
+                };
+
+                health_probes.push(HealthProbe {
+                    method: method.to_owned(),
+                    params,
+                    min,
+                    max,
+                });
+            }
+        }
+
         // Parse all the other tables as RPCs and put them in a Vec<Rpc>
         //
         // Sort RPCs by latency if enabled
 
+        // Parse the optional `chains` table
+        //
+        // Maps the path prefix of each chain to the RPC tables serving it.
+        // RPCs that aren't in any chain serve the default chain.
+        let mut chain_tables = BTreeMap::new();
+        let mut rpc_chains = HashMap::new();
+        if let Some(chains_table) = parsed_toml.get("chains") {
+            let chains_table = chains_table
+                .as_table()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse chains table!");
+
+            for (name, chain) in chains_table {
+                if name.is_empty() || name.contains('/') {
+                    panic!(
+                        "\x1b[31mErr:\x1b[0m Invalid chain name {}! Can't be empty or contain `/`.",
+                        name
+                    );
+                }
+
+                let chain = chain
+                    .as_table()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse chain as table!");
+                let rpcs = chain
+                    .get("rpcs")
+                    .and_then(|rpcs| rpcs.as_array())
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "\x1b[31mErr:\x1b[0m Missing rpcs array from chain {}!",
+                            name
+                        )
+                    });
+                for rpc in rpcs {
+                    let rpc = rpc
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Invalid RPC in chain rpcs!");
+                    if parsed_toml.get(rpc).is_none() {
+                        panic!(
+                            "\x1b[31mErr:\x1b[0m Chain {} uses RPC {}, which doesn't exist!",
+                            name, rpc
+                        );
+                    }
+                    if rpc_chains.insert(rpc.to_string(), name.clone()).is_some() {
+                        panic!("\x1b[31mErr:\x1b[0m RPC {} is in more than one chain!", rpc);
+                    }
+                }
+
+                // Optional, defaults to the chain id reported by most of its RPCs
+                let expected_chain_id = chain.get("expected_chain_id").map(|expected_chain_id| {
+                    expected_chain_id
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse expected_chain_id as int!")
+                        as u64
+                });
+                chain_tables.insert(name.clone(), expected_chain_id);
+            }
+        }
+
         // `is_ws` flag is used to turn off WS specific things when a WS endpoint isnt present.
         let mut is_ws = true;
         let mut rpc_list: Vec<Rpc> = Vec::new();
+        let mut chain_rpcs: BTreeMap<String, Vec<Rpc>> = BTreeMap::new();
         for table_name in table_names {
-            if table_name != "blutgang" && table_name != "sled" && table_name != "admin" {
+            if !NON_RPC_TABLES.contains(&table_name.as_str()) {
                 let rpc_table = parsed_toml.get(table_name).unwrap().as_table().unwrap();
 
                 let max_consecutive = rpc_table
@@ -262,9 +2289,7 @@ impl Settings {
 
                 // If the delta time isnt 0, we need to get how many microsecond need to pass
                 // before we can send a new request
-                if delta != 0 {
-                    delta = 1_000_000 / delta;
-                }
+                delta = 1_000_000u64.checked_div(delta).unwrap_or(0);
 
                 let url = rpc_table
                     .get("url")
@@ -286,13 +2311,202 @@ impl Settings {
                         )
                     }
                     None => {
-                        is_ws = false;
+                        // RPCs of other chains don't turn WS off for the default chain
+                        if !rpc_chains.contains_key(table_name.as_str()) {
+                            is_ws = false;
+                        }
                         None
                     }
                 };
 
-                let rpc = Rpc::new(url, ws_url, max_consecutive, delta.into(), ma_length);
-                rpc_list.push(rpc);
+                // Optional, used as the capacity when computing pool saturation
+                let max_concurrency = rpc_table
+                    .get("max_concurrency")
+                    .map(|max_concurrency| {
+                        max_concurrency
+                            .as_integer()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse max_concurrency as int!")
+                            as u32
+                    })
+                    .unwrap_or(0);
+
+                // Optional, whether this RPC can serve historical queries
+                let archive = rpc_table
+                    .get("archive")
+                    .map(|archive| {
+                        archive
+                            .as_bool()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse archive as bool!")
+                    })
+                    .unwrap_or(false);
+
+                // Optional, share of traffic relative to other RPCs with `weighted` selection
+                let weight = rpc_table
+                    .get("weight")
+                    .map(|weight| {
+                        let weight = weight
+                            .as_integer()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse weight as int!");
+                        u32::try_from(weight).unwrap_or_else(|_| {
+                            panic!("\x1b[31mErr:\x1b[0m weight must be between 0 and {}!", u32::MAX)
+                        })
+                    })
+                    .unwrap_or(1);
+
+                // Optional, groups methods can be routed to this RPC with
+                let groups: Arc<[String]> = rpc_table
+                    .get("groups")
+                    .map(|groups| {
+                        groups
+                            .as_array()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse groups as array!")
+                            .iter()
+                            .map(|group| {
+                                group
+                                    .as_str()
+                                    .expect("\x1b[31mErr:\x1b[0m Could not parse group as str!")
+                                    .to_string()
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_else(|| Arc::from([]));
+
+                // Optional, connection pool sizing for this RPC
+                let pool_max_idle = rpc_table.get("pool_max_idle").map(|pool_max_idle| {
+                    pool_max_idle
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse pool_max_idle as int!")
+                        as usize
+                });
+                let pool_max_connections =
+                    rpc_table
+                        .get("pool_max_connections")
+                        .map(|pool_max_connections| {
+                            pool_max_connections.as_integer().expect(
+                                "\x1b[31mErr:\x1b[0m Could not parse pool_max_connections as int!",
+                            ) as usize
+                        });
+                let pool_idle_timeout_ms =
+                    rpc_table
+                        .get("pool_idle_timeout_ms")
+                        .map(|pool_idle_timeout_ms| {
+                            pool_idle_timeout_ms.as_integer().expect(
+                                "\x1b[31mErr:\x1b[0m Could not parse pool_idle_timeout_ms as int!",
+                            ) as u64
+                        });
+
+                // Optional, HTTP version to use with this RPC
+                let http_version = rpc_table
+                    .get("http_version")
+                    .map(|http_version| {
+                        let http_version = http_version
+                            .as_str()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse http_version as str!");
+                        HttpVersion::from_str(http_version).expect(
+                            "\x1b[31mErr:\x1b[0m Invalid http_version! Can be `http1`, `http2` or `auto`.",
+                        )
+                    })
+                    .unwrap_or_default();
+
+                // Optional, extra headers sent with every request to this RPC
+                let headers = rpc_table
+                    .get("headers")
+                    .map(|headers| {
+                        let headers = headers
+                            .as_table()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse headers as table!");
+                        let headers = RpcHeaders(
+                            headers
+                                .iter()
+                                .map(|(name, value)| {
+                                    let value = value.as_str().expect(
+                                        "\x1b[31mErr:\x1b[0m Could not parse header value as str!",
+                                    );
+                                    (name.clone(), value.to_string())
+                                })
+                                .collect(),
+                        );
+                        if let Err(err) = headers.to_header_map() {
+                            panic!("\x1b[31mErr:\x1b[0m Invalid RPC headers: {}", err);
+                        }
+                        headers
+                    })
+                    .unwrap_or_default();
+
+                // Optional, TLS certificate verification for this RPC
+                let accept_invalid_certs = rpc_table
+                    .get("accept_invalid_certs")
+                    .map(|accept_invalid_certs| {
+                        accept_invalid_certs.as_bool().expect(
+                            "\x1b[31mErr:\x1b[0m Could not parse accept_invalid_certs as bool!",
+                        )
+                    })
+                    .unwrap_or(false);
+                if accept_invalid_certs {
+                    println!(
+                        "\x1b[93mWrn:\x1b[0m TLS certificate verification is disabled for {}!",
+                        url
+                    );
+                }
+                let ca_bundle = rpc_table.get("ca_bundle").map(|ca_bundle| {
+                    let ca_bundle = ca_bundle
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse ca_bundle as str!")
+                        .to_string();
+                    if let Err(err) = load_ca_bundle(&ca_bundle) {
+                        panic!("\x1b[31mErr:\x1b[0m Invalid ca_bundle: {}", err);
+                    }
+                    ca_bundle
+                });
+
+                let client_settings = ClientSettings {
+                    pool_max_idle,
+                    pool_max_connections,
+                    pool_idle_timeout: pool_idle_timeout_ms.map(Duration::from_millis),
+                    http_version,
+                    proxy_url: proxy_url.clone(),
+                    no_proxy: no_proxy.clone(),
+                    headers,
+                    accept_invalid_certs,
+                    ca_bundle,
+                };
+
+                let mut rpc = Rpc::new(url, ws_url, max_consecutive, delta.into(), ma_length);
+                rpc.set_max_concurrency(max_concurrency);
+                rpc.archive = archive;
+                rpc.weight = weight;
+                rpc.groups = groups;
+                rpc.max_response_bytes = max_response_bytes;
+                rpc.rate_limit_cooldown = Duration::from_millis(rate_limit_cooldown_ms);
+                rpc.head_method = head_method.clone();
+                if client_settings != ClientSettings::default() {
+                    rpc.set_client_settings(client_settings);
+                }
+
+                match rpc_chains.get(table_name.as_str()) {
+                    Some(chain) => chain_rpcs.entry(chain.clone()).or_default().push(rpc),
+                    None => rpc_list.push(rpc),
+                }
+            }
+        }
+
+        let mut rpc_list = dedupe_rpcs(rpc_list);
+        let mut chain_rpcs: BTreeMap<String, Vec<Rpc>> = chain_rpcs
+            .into_iter()
+            .map(|(chain, rpc_list)| (chain, dedupe_rpcs(rpc_list)))
+            .collect();
+
+        // Catch typos in groups, routed methods would never get served
+        for group in method_routing.groups() {
+            if !rpc_list
+                .iter()
+                .chain(chain_rpcs.values().flatten())
+                .any(|rpc| rpc.in_group(group))
+            {
+                panic!(
+                    "\x1b[31mErr:\x1b[0m No RPC is in the `{}` group used in method_routing!",
+                    group
+                );
             }
         }
 
@@ -343,12 +2557,52 @@ impl Settings {
                 String::new()
             };
 
+            let max_connections = admin_table
+                .get("max_connections")
+                .map(|x| {
+                    x.as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse admin max_connections as int!")
+                        as usize
+                })
+                .unwrap_or(AdminSettings::default().max_connections);
+            let request_timeout_ms = admin_table
+                .get("request_timeout_ms")
+                .map(|x| {
+                    x.as_integer().expect(
+                        "\x1b[31mErr:\x1b[0m Could not parse admin request_timeout_ms as int!",
+                    ) as u64
+                })
+                .unwrap_or(AdminSettings::default().request_timeout_ms);
+            let metrics_enabled = admin_table
+                .get("metrics_enabled")
+                .map(|x| {
+                    x.as_bool().expect(
+                        "\x1b[31mErr:\x1b[0m Could not parse admin metrics_enabled as bool!",
+                    )
+                })
+                .unwrap_or(AdminSettings::default().metrics_enabled);
+            let auth_token = admin_table.get("auth_token").map(|x| {
+                x.as_str()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse admin auth_token as str!")
+                    .to_string()
+            });
+            let dump_dir = admin_table.get("dump_dir").map(|x| {
+                x.as_str()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse admin dump_dir as str!")
+                    .to_string()
+            });
+
             AdminSettings {
                 enabled,
                 address: address.parse::<SocketAddr>().unwrap(),
                 readonly,
                 jwt,
                 key: DecodingKey::from_secret(key.as_bytes()),
+                max_connections,
+                request_timeout_ms,
+                metrics_enabled,
+                auth_token,
+                dump_dir,
             }
         } else {
             AdminSettings {
@@ -357,10 +2611,88 @@ impl Settings {
                 readonly: false,
                 jwt: false,
                 key: DecodingKey::from_secret(b""),
+                ..Default::default()
             }
         };
 
-        if sort_on_startup {
+        // Optional, serve HTTPS instead of HTTP
+        let tls = parsed_toml.get("tls").and_then(|tls_table| {
+            let tls_table = tls_table
+                .as_table()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse tls table!");
+            let enabled = tls_table
+                .get("enabled")
+                .map(|enabled| {
+                    enabled
+                        .as_bool()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse tls enabled as bool!")
+                })
+                .unwrap_or(true);
+            if !enabled {
+                return None;
+            }
+
+            let path = |key: &str| {
+                tls_table
+                    .get(key)
+                    .unwrap_or_else(|| panic!("\x1b[31mErr:\x1b[0m Missing tls {}!", key))
+                    .as_str()
+                    .unwrap_or_else(|| {
+                        panic!("\x1b[31mErr:\x1b[0m Could not parse tls {} as str!", key)
+                    })
+                    .to_string()
+            };
+
+            Some(TlsSettings {
+                cert_path: path("cert_path"),
+                key_path: path("key_path"),
+            })
+        });
+
+        // Parse the optional `http` table
+        let http = parsed_toml
+            .get("http")
+            .map(|http_table| {
+                let http_table = http_table
+                    .as_table()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse http table!");
+                let keepalive = http_table
+                    .get("keepalive")
+                    .map(|x| {
+                        x.as_bool()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse http keepalive as bool!")
+                    })
+                    .unwrap_or(HttpSettings::default().keepalive);
+                let header_timeout_ms = http_table
+                    .get("header_timeout_ms")
+                    .map(|x| {
+                        x.as_integer().expect(
+                            "\x1b[31mErr:\x1b[0m Could not parse http header_timeout_ms as int!",
+                        ) as u64
+                    })
+                    .unwrap_or(HttpSettings::default().header_timeout_ms);
+                let max_buf_size = http_table
+                    .get("max_buf_size")
+                    .map(|x| {
+                        x.as_integer()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse http max_buf_size as int!")
+                            as usize
+                    })
+                    .unwrap_or(HttpSettings::default().max_buf_size);
+                // hyper panics on anything smaller
+                if max_buf_size < 8192 {
+                    panic!("\x1b[31mErr:\x1b[0m http max_buf_size can't be less than 8192!");
+                }
+
+                HttpSettings {
+                    keepalive,
+                    header_timeout_ms,
+                    max_buf_size,
+                }
+            })
+            .unwrap_or_default();
+
+        if sort_on_startup && sort {
             println!("Sorting RPCs by latency...");
             rpc_list = match sort_by_latency(rpc_list, ma_length).await {
                 Ok(rax) => rax,
@@ -370,18 +2702,137 @@ impl Settings {
             };
         }
 
-        Settings {
+        let mut settings = Settings {
             rpc_list,
             is_ws,
+            ma_length,
             do_clear,
             address,
+            unix_socket,
+            listen_backlog,
+            max_concurrent_connections,
+            allowed_origins,
+            proxy_url,
+            no_proxy,
+            auth_token,
+            compression: response_compression,
+            compression_min_size,
+            expose_node_header,
             health_check,
             ttl,
             max_retries,
             health_check_ttl,
+            health_check_jitter,
+            head_check_stagger,
+            poverty_check_ttl,
+            poverty_backoff_max,
+            health_webhook_url,
+            head_agreement_quorum,
+            ttl_tolerance_blocks,
+            stale_latest_lag,
+            stale_latest_strikes,
+            max_consecutive_failures,
+            fleet_metadata_ttl,
+            shutdown_grace_ms,
+            chain_id_check_ttl,
+            check_net_version,
+            expected_chain_id,
+            probe_block_ttl,
+            probe_block,
+            health_probes,
+            selection_strategy,
+            latency_ema_alpha,
+            validate_responses,
+            serve_stale_on_error,
+            shadow_sample_rate,
+            shard_logs_cache,
+            max_logs_range,
+            max_logs_chunks,
+            broadcast_transactions,
+            serve_block_number_locally,
+            serve_named_blocks_locally,
+            archive_threshold,
+            no_archive_message,
+            future_blocks,
+            subscription_queue_timeout,
+            ws_ping_interval_ms,
+            ws_pong_timeout_ms,
+            tx_replay_window,
+            sticky_sessions,
+            sticky_session_ttl,
+            block_params,
+            method_cache,
+            method_timeouts,
+            method_filter: Arc::new(
+                MethodFilter::new(allowed_methods, denied_methods)
+                    .with_message(blocked_method_message),
+            ),
+            method_routing,
+            response_transform,
+            drop_stale_heads,
+            ws_max_notifications_per_second,
+            ws_coalesce_new_heads,
+            ws_notification_buffer,
+            reorg_bypass_window,
+            max_concurrent_requests,
+            memory_cache_entries,
+            cache_max_bytes,
+            head_cache_enabled,
+            head_cache_max_blocks,
+            rate_limit_rps,
+            rate_limit_burst,
+            priority_clients,
+            log_rate_limit,
+            log_level,
+            log_format,
+            access_log,
+            access_log_sample_rate,
+            ready_after_warmup,
+            max_subscription_lifetime,
+            ws_channel_capacity,
+            unknown_subscriptions,
+            local_newheads,
+            startup_check,
+            startup_check_strict,
+            restore_health,
+            max_request_bytes,
+            max_batch_size,
+            batch_concurrency,
+            strict_jsonrpc,
+            max_response_bytes,
+            rate_limit_cooldown_ms,
+            head_method,
+            lazy_cache_migration,
+            cache_version,
+            hedge_delay,
+            request_deadline_ms,
+            cache_warmup,
             sled_config,
             admin,
-        }
+            tls,
+            http,
+            config_path: None,
+            chains: BTreeMap::new(),
+        };
+
+        // Chains share our settings, apart from their RPCs and checks tied to a chain
+        settings.chains = chain_tables
+            .into_iter()
+            .map(|(name, expected_chain_id)| {
+                let rpc_list = chain_rpcs.remove(&name).unwrap_or_default();
+                let chain = Settings {
+                    is_ws: !rpc_list.is_empty() && rpc_list.iter().all(|rpc| rpc.ws_url.is_some()),
+                    rpc_list,
+                    expected_chain_id,
+                    probe_block: None,
+                    cache_warmup: Vec::new(),
+                    ..settings.clone()
+                };
+                (name, chain)
+            })
+            .collect();
+
+        settings
     }
 
     fn create_from_matches(matches: ArgMatches) -> Settings {
@@ -401,9 +2852,7 @@ impl Settings {
             .expect("Invalid max_per_second")
             .to_owned();
 
-        if delta != 0 {
-            delta = 1_000_000 / delta;
-        }
+        delta = 1_000_000u64.checked_div(delta).unwrap_or(0);
 
         // Turn the rpc_list into a csv vec
         let rpc_list: Vec<&str> = rpc_list.split(',').collect();
@@ -413,6 +2862,7 @@ impl Settings {
             .iter()
             .map(|rpc| Rpc::new(rpc.to_string(), None, 6, delta.into(), ma_length))
             .collect();
+        let rpc_list = dedupe_rpcs(rpc_list);
 
         // Build the SocketAddr
         let address = matches
@@ -449,17 +2899,21 @@ impl Settings {
             .parse::<u64>()
             .expect("Invalid flush_every_ms");
 
+        let sled_mode_str = matches
+            .get_one::<String>("sled_mode")
+            .expect("Invalid sled_mode");
+
         let clear = matches.get_occurrences::<String>("clear").is_some();
         let compression = matches.get_occurrences::<String>("compression").is_some();
 
         // Set config for sled
         let sled_config = Config::default()
             .path(db_path)
-            .mode(sled::Mode::HighThroughput)
+            .mode(sled_mode(sled_mode_str))
             .cache_capacity(cache_capacity)
             .use_compression(compression)
             .print_profile_on_drop(print_profile)
-            .flush_every_ms(Some(flush_every_ms));
+            .flush_every_ms(sled_flush_every_ms(flush_every_ms));
 
         let health_check = matches.get_occurrences::<String>("health_check").is_some();
 
@@ -497,6 +2951,7 @@ impl Settings {
                 readonly,
                 jwt,
                 key: DecodingKey::from_secret(key.as_bytes()),
+                ..Default::default()
             }
         } else {
             AdminSettings {
@@ -505,12 +2960,14 @@ impl Settings {
                 readonly: false,
                 jwt: false,
                 key: DecodingKey::from_secret(b""),
+                ..Default::default()
             }
         };
 
         Settings {
             rpc_list,
             is_ws: false,
+            ma_length,
             do_clear: clear,
             address,
             health_check,
@@ -519,6 +2976,152 @@ impl Settings {
             health_check_ttl,
             sled_config,
             admin,
+            ..Default::default()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sled_flush_every_ms() {
+        assert_eq!(sled_flush_every_ms(1000), Some(1000));
+        // Manual flushes only
+        assert_eq!(sled_flush_every_ms(0), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let settings = Settings {
+            rpc_list: vec![Rpc::new(
+                "http://node.example".to_string(),
+                Some("wss://node.example".to_string()),
+                0,
+                0,
+                1.0,
+            )],
+            ..Default::default()
+        };
+        assert!(settings.validate().is_empty());
+
+        let settings = Settings {
+            rpc_list: vec![
+                Rpc::new("node.example".to_string(), None, 0, 0, 1.0),
+                Rpc::new(
+                    "ws://node.example".to_string(),
+                    Some("http://node.example".to_string()),
+                    0,
+                    0,
+                    1.0,
+                ),
+            ],
+            ttl: 0,
+            health_check: false,
+            health_probes: vec![HealthProbe::min_peers(5)],
+            tls: Some(TlsSettings {
+                cert_path: "/does/not/exist.pem".to_string(),
+                key_path: "/does/not/exist.key".to_string(),
+            }),
+            access_log: Some("/does/not/exist/access.log".to_string()),
+            ..Default::default()
+        };
+        let errors = settings.validate();
+        let fields: Vec<&str> = errors
+            .iter()
+            .map(|error| error.split(':').next().unwrap())
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "url",
+                "url",
+                "ws_url",
+                "ttl",
+                "health_probes",
+                "tls.cert_path",
+                "tls.key_path",
+                "access_log"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_config() {
+        assert_eq!(Settings::check_config("example_config.toml").await, 0);
+        assert_eq!(Settings::check_config("does_not_exist.toml").await, 1);
+
+        let path = std::env::temp_dir().join("blutgang_test_check_config.toml");
+        fs::write(&path, "[blutgang]\ndo_clear = \"yes\"\n").unwrap();
+        assert_eq!(Settings::check_config(path.to_str().unwrap()).await, 1);
+
+        // Negative weights get rejected
+        let file = fs::read_to_string("example_config.toml").unwrap();
+        fs::write(&path, file.replace("#weight = 1", "weight = -1")).unwrap();
+        assert_eq!(Settings::check_config(path.to_str().unwrap()).await, 1);
+
+        // Panics elsewhere still reach the hook that was set before
+        let panics = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new({
+            let panics = Arc::clone(&panics);
+            move |info| {
+                if thread::current().name() == Some("test_check_config_panic") {
+                    panics.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+                previous(info);
+            }
+        }));
+        assert_eq!(Settings::check_config(path.to_str().unwrap()).await, 1);
+        let _ = thread::Builder::new()
+            .name("test_check_config_panic".to_string())
+            .spawn(|| panic!("expected"))
+            .unwrap()
+            .join();
+        drop(std::panic::take_hook());
+        assert_eq!(panics.load(std::sync::atomic::Ordering::Relaxed), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_dedupe_rpcs() {
+        let rpc = |url: &str, weight: u32, max_concurrency: u32| {
+            let mut rpc = Rpc::new(url.to_string(), None, 0, 0, 1.0);
+            rpc.weight = weight;
+            rpc.set_max_concurrency(max_concurrency);
+            rpc
+        };
+
+        let rpc_list = dedupe_rpcs(vec![
+            rpc("http://node.example/", 1, 10),
+            rpc("http://other.example", 1, 0),
+            rpc("HTTP://Node.Example", 3, 20),
+        ]);
+        assert_eq!(rpc_list.len(), 2);
+        assert_eq!(rpc_list[0].url, "http://node.example/");
+        assert_eq!(rpc_list[0].weight, 3);
+        assert_eq!(rpc_list[0].max_concurrency, 20);
+        assert_eq!(rpc_list[1].url, "http://other.example");
+
+        // Unbounded wins
+        let rpc_list = dedupe_rpcs(vec![rpc("http://a", 1, 10), rpc("http://a", 1, 0)]);
+        assert_eq!(rpc_list.len(), 1);
+        assert_eq!(rpc_list[0].max_concurrency, 0);
+    }
+
+    #[test]
+    fn test_sled_mode() {
+        assert!(matches!(sled_mode("LowSpace"), sled::Mode::LowSpace));
+        assert!(matches!(
+            sled_mode("HighThroughput"),
+            sled::Mode::HighThroughput
+        ));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_sled_mode() {
+        sled_mode("FastAndSmall");
+    }
+}
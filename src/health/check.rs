@@ -1,13 +1,24 @@
 use crate::IncomingResponse;
 use crate::SubscriptionData;
 use crate::{
+    config::types::HeadAgreementQuorum,
     health::{
         error::HealthError,
+        probes::HealthProbe,
+        readiness::Readiness,
         safe_block::{
             get_safe_block,
             NamedBlocknumbers,
         },
+        trigger::HealthTrigger,
+        webhook::{
+            HealthEvent,
+            HealthWebhook,
+        },
     },
+    info_limited,
+    rpc::types::LAG_WINDOW,
+    warn_limited,
     websocket::{
         subscription_manager::move_subscriptions,
         types::{
@@ -18,14 +29,24 @@ use crate::{
     Rpc,
     Settings,
 };
+use futures::future::join_all;
+use rand::Rng;
 use tokio::sync::broadcast;
 
-use std::println;
+use tracing::{
+    debug,
+    error,
+    info,
+};
+
 use std::sync::{
     Arc,
     RwLock,
 };
-use std::time::Duration;
+use std::time::{
+    Duration,
+    Instant,
+};
 
 use tokio::{
     sync::mpsc,
@@ -39,22 +60,185 @@ use tokio::{
 struct HeadResult {
     rpc_list_index: usize,
     reported_head: u64,
+    // Heads of nodes that are still syncing can't be trusted
+    syncing: bool,
+    // How long the RPC took to report its head, the `ttl` if it didn't
+    latency: Duration,
+}
+
+// Settings for catching RPCs that serve stale `latest` blocks
+#[derive(Debug, Default, Clone, Copy)]
+struct StaleLatestProbe {
+    // How many blocks behind the agreed head the `latest` block can be, 0 disables the probe
+    max_lag: u64,
+    // How many stale `latest` blocks in a row before we stop trusting the RPC
+    strikes: u32,
+}
+
+// When RPCs get moved to the poverty list
+#[derive(Debug, Default, Clone, Copy)]
+struct PovertyRules {
+    // How many RPCs need to agree on a head for it to be used
+    quorum: HeadAgreementQuorum,
+    // How many blocks behind the agreed head an RPC can be
+    tolerance: u64,
+    // Failed head checks in a row before an unresponsive RPC is removed, 0 removes it right away
+    max_consecutive_failures: u32,
+    // First and max delay between checks of RPCs that keep failing to get out of poverty.
+    // A max of 0 checks them every time.
+    backoff_base: Duration,
+    backoff_max: Duration,
+}
+
+impl StaleLatestProbe {
+    fn is_enabled(&self) -> bool {
+        self.max_lag != 0
+    }
+}
+
+// Tracks when a check that runs less often than the health check is due.
+//
+// Used for the poverty list, since it's only checked to eventually recover nodes,
+// and for collecting node metadata.
+#[derive(Debug, Default)]
+struct CheckSchedule {
+    last_check: Option<Instant>,
+}
+
+impl CheckSchedule {
+    // Returns true if the check should run now. A `ttl` of 0
+    // means it runs on every health check.
+    fn is_due(&mut self, ttl: Duration) -> bool {
+        match self.last_check {
+            Some(last_check) if last_check.elapsed() < ttl => false,
+            _ => {
+                self.last_check = Some(Instant::now());
+                true
+            }
+        }
+    }
+}
+
+// Add or subtract up to `jitter` ms from `ttl` at random, so instances sharing
+// RPCs don't end up checking them in sync
+fn jittered(ttl: u64, jitter: u64) -> Duration {
+    if jitter == 0 {
+        return Duration::from_millis(ttl);
+    }
+
+    let offset = rand::thread_rng().gen_range(0..=jitter);
+    match rand::thread_rng().gen_bool(0.5) {
+        true => Duration::from_millis(ttl.saturating_add(offset)),
+        false => Duration::from_millis(ttl.saturating_sub(offset)),
+    }
 }
 
 // Call check and safe_block in a loop
+#[allow(clippy::too_many_arguments)]
 pub async fn health_check(
     rpc_list: Arc<RwLock<Vec<Rpc>>>,
     poverty_list: Arc<RwLock<Vec<Rpc>>>,
     finalized_tx: tokio::sync::watch::Sender<u64>,
     named_numbers_rwlock: &Arc<RwLock<NamedBlocknumbers>>,
     config: &Arc<RwLock<Settings>>,
+    readiness: &Arc<Readiness>,
+    trigger: &Arc<HealthTrigger>,
+    incoming_tx: Option<mpsc::Sender<WsconnMessage>>,
 ) -> Result<(), HealthError> {
+    let _attached = trigger.attach();
+    let mut poverty_schedule = CheckSchedule::default();
+    let mut metadata_schedule = CheckSchedule::default();
+    let mut chain_id_schedule = CheckSchedule::default();
+    let mut probe_block_schedule = CheckSchedule::default();
+    let mut ws_reprobe_schedule = CheckSchedule::default();
+    let webhook = HealthWebhook::new(config.read().unwrap().health_webhook_url.clone());
+
     loop {
         let health_check_ttl = config.read().unwrap().health_check_ttl;
+        let health_check_jitter = config.read().unwrap().health_check_jitter;
+        let head_check_stagger = Duration::from_millis(config.read().unwrap().head_check_stagger);
+        let poverty_check_ttl = config.read().unwrap().poverty_check_ttl;
+        let fleet_metadata_ttl = config.read().unwrap().fleet_metadata_ttl;
+        let chain_id_check_ttl = config.read().unwrap().chain_id_check_ttl;
+        let expected_chain_id = config.read().unwrap().expected_chain_id;
+        let check_net_version = config.read().unwrap().check_net_version;
+        let probe_block_ttl = config.read().unwrap().probe_block_ttl;
+        let probe_block = config.read().unwrap().probe_block.clone();
+        let health_probes = config.read().unwrap().health_probes.clone();
         let ttl = config.read().unwrap().ttl;
+        let rules = PovertyRules {
+            quorum: config.read().unwrap().head_agreement_quorum,
+            tolerance: config.read().unwrap().ttl_tolerance_blocks,
+            max_consecutive_failures: config.read().unwrap().max_consecutive_failures,
+            backoff_base: Duration::from_millis(health_check_ttl),
+            backoff_max: Duration::from_millis(config.read().unwrap().poverty_backoff_max),
+        };
+        let stale_probe = StaleLatestProbe {
+            max_lag: config.read().unwrap().stale_latest_lag,
+            strikes: config.read().unwrap().stale_latest_strikes,
+        };
+
+        // Wait for the next check, unless someone asks for one sooner
+        trigger
+            .wait(jittered(health_check_ttl, health_check_jitter))
+            .await;
+        trigger.start();
+
+        // Ban RPCs that are on the wrong chain
+        if chain_id_check_ttl != 0
+            && chain_id_schedule.is_due(Duration::from_millis(chain_id_check_ttl))
+        {
+            let chain_ids = chain_id_check(&rpc_list, &poverty_list, ttl).await;
+            if check_net_version {
+                let mismatches = net_version_check(&rpc_list, &poverty_list, &chain_ids, ttl).await;
+                ban_inconsistent_chain_id(&rpc_list, &poverty_list, mismatches);
+            }
+            ban_wrong_chain(&rpc_list, &poverty_list, chain_ids, expected_chain_id);
+        }
+
+        // Ban RPCs that disagree on the hash of a known block
+        if let Some((number, expected_hash)) = probe_block {
+            if probe_block_ttl != 0
+                && probe_block_schedule.is_due(Duration::from_millis(probe_block_ttl))
+            {
+                let hashes = block_hash_check(&rpc_list, &poverty_list, number, ttl).await;
+                ban_wrong_block_hash(&rpc_list, &poverty_list, hashes, number, &expected_hash);
+            }
+        }
+
+        let check_poverty = poverty_schedule.is_due(Duration::from_millis(poverty_check_ttl));
+        check(
+            &rpc_list,
+            &poverty_list,
+            &ttl,
+            check_poverty,
+            rules,
+            stale_probe,
+            &health_probes,
+            head_check_stagger,
+            &webhook,
+            trigger,
+        )
+        .await?;
+
+        // Try to reconnect WS endpoints that dropped while their HTTP stayed healthy,
+        // as often as we recheck the poverty list
+        if let Some(incoming_tx) = &incoming_tx {
+            if has_ws_erroring(&rpc_list)
+                && ws_reprobe_schedule.is_due(Duration::from_millis(poverty_check_ttl))
+            {
+                incoming_tx.send(WsconnMessage::Sync()).await.unwrap_or(());
+            }
+        }
+
+        // Refresh what we know about the nodes for `blutgang_fleet`
+        if fleet_metadata_ttl != 0
+            && metadata_schedule.is_due(Duration::from_millis(fleet_metadata_ttl))
+        {
+            collect_metadata(&rpc_list, ttl).await;
+            collect_metadata(&poverty_list, ttl).await;
+        }
 
-        sleep(Duration::from_millis(health_check_ttl)).await;
-        check(&rpc_list, &poverty_list, &ttl).await?;
         get_safe_block(
             &rpc_list,
             &finalized_tx,
@@ -62,41 +246,96 @@ pub async fn health_check(
             health_check_ttl,
         )
         .await?;
+
+        if !rpc_list.read().unwrap().is_empty() {
+            readiness.mark_health_checked();
+        }
+
+        trigger.finish();
     }
 }
 
 // Track the head of each RPC and process them accordingly
+#[allow(clippy::too_many_arguments)]
 async fn check(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     ttl: &u128,
+    check_poverty: bool,
+    rules: PovertyRules,
+    stale_probe: StaleLatestProbe,
+    probes: &[HealthProbe],
+    stagger: Duration,
+    webhook: &HealthWebhook,
+    trigger: &HealthTrigger,
 ) -> Result<(), HealthError> {
-    print!("\x1b[35mInfo:\x1b[0m Checking RPC health... ");
+    debug!("Checking RPC health...");
     // Head blocks reported by each RPC, we also use it to mark delinquents
     //
     // If a head is marked at `0` that means that the rpc is delinquent
-    let heads = head_check(rpc_list, *ttl).await?;
+    let heads = head_check(rpc_list, *ttl, stagger).await?;
 
     // Remove RPCs that are falling behind
-    let agreed_head = make_poverty(rpc_list, poverty_list, heads)?;
+    let in_poverty = poverty_list.read().unwrap().len();
+    let agreed_head = make_poverty(rpc_list, poverty_list, heads, rules, trigger)?;
+
+    // Remove RPCs that keep serving stale `latest` blocks
+    if stale_probe.is_enabled() {
+        let latest = latest_check(rpc_list, *ttl).await;
+        track_stale_latest(rpc_list, latest, agreed_head, stale_probe);
+        make_stale_poverty(rpc_list, poverty_list);
+    }
+
+    // Remove RPCs failing a health probe
+    if !probes.is_empty() {
+        probe_check(rpc_list, probes, *ttl).await;
+        make_probe_poverty(rpc_list, poverty_list);
+    }
+
+    // Removed RPCs get added to the end of the poverty list
+    webhook.notify(
+        HealthEvent::EnteredPoverty,
+        &poverty_list.read().unwrap()[in_poverty..],
+    );
 
     // Check if any rpc nodes made it out
     // Its ok if we call them twice because some might have been accidentally put here
+    if check_poverty {
+        // Do a head check over the current poverty list to see if any nodes are back to normal
+        let poverty_heads = head_check(poverty_list, *ttl, stagger).await?;
+
+        // RPCs removed for serving stale blocks need to serve a fresh one to get out
+        if stale_probe.is_enabled() {
+            let latest = latest_check(poverty_list, *ttl).await;
+            track_stale_latest(poverty_list, latest, agreed_head, stale_probe);
+        }
 
-    // Do a head check over the current poverty list to see if any nodes are back to normal
-    let poverty_heads = head_check(poverty_list, *ttl).await?;
+        // RPCs removed for failing a probe need to pass it to get out
+        if !probes.is_empty() {
+            probe_check(poverty_list, probes, *ttl).await;
+        }
 
-    escape_poverty(rpc_list, poverty_list, poverty_heads, agreed_head)?;
+        let active = rpc_list.read().unwrap().len();
+        escape_poverty(rpc_list, poverty_list, poverty_heads, agreed_head, rules)?;
+        webhook.notify(
+            HealthEvent::LeftPoverty,
+            &rpc_list.read().unwrap()[active..],
+        );
+    }
 
-    println!("OK!");
+    debug!("Health check OK!");
 
     Ok(())
 }
 
-// Check what heads are reported by each RPC
+// Check what heads are reported by each RPC.
+//
+// Each RPC is probed after a random delay of up to `stagger`, so they
+// don't all get probed at the same moment.
 async fn head_check(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     ttl: u128,
+    stagger: Duration,
 ) -> Result<Vec<HeadResult>, HealthError> {
     let len = rpc_list.read().unwrap().len();
     let mut heads = Vec::<HeadResult>::new();
@@ -115,12 +354,31 @@ async fn head_check(
     // Iterate over all RPCs
     for i in 0..len {
         let rpc_clone = rpc_list.read().unwrap()[i].clone();
+        if skip_check(&rpc_clone) {
+            continue;
+        }
         let tx = tx.clone(); // Clone the sender for this RPC
 
+        let delay = match stagger.is_zero() {
+            true => Duration::ZERO,
+            false => rand::thread_rng().gen_range(Duration::ZERO..stagger),
+        };
+
         // Spawn a future for each RPC
         let rpc_future = async move {
-            let a = rpc_clone.block_number();
-            let result = timeout(Duration::from_millis(ttl.try_into().unwrap()), a).await;
+            if !delay.is_zero() {
+                sleep(delay).await;
+            }
+
+            let ttl = Duration::from_millis(ttl.try_into().unwrap());
+            let started = Instant::now();
+            let ((result, elapsed), syncing) = tokio::join!(
+                async {
+                    let result = timeout(ttl, rpc_clone.block_number()).await;
+                    (result, started.elapsed())
+                },
+                timeout(ttl, rpc_clone.is_syncing()),
+            );
 
             let head = match result {
                 Ok(response) => response.unwrap_or(0), // Handle timeout as 0
@@ -130,24 +388,30 @@ async fn head_check(
             let head_result = HeadResult {
                 rpc_list_index: i,
                 reported_head: head,
+                // Nodes that don't answer `eth_syncing` get judged by their head alone
+                syncing: matches!(syncing, Ok(Ok(true))),
+                latency: match head {
+                    0 => ttl,
+                    _ => elapsed,
+                },
             };
 
-            // Send the result to the main thread through the channel
-            tx.send(head_result)
-                .await
-                .expect("head check: Channel send error");
+            // Send the result to the main thread through the channel.
+            // Fails if the health check was dropped, in which case nobody needs it.
+            let _ = tx.send(head_result).await;
         };
 
         rpc_futures.push(rpc_future);
     }
 
     // Wait for all RPC futures concurrently
+    let probed = rpc_futures.len();
     for rpc_future in rpc_futures {
         tokio::spawn(rpc_future);
     }
 
     // Collect the results in order from the channel
-    for _ in 0..len {
+    for _ in 0..probed {
         if let Some(result) = rx.recv().await {
             heads.push(result);
         }
@@ -156,33 +420,561 @@ async fn head_check(
     Ok(heads)
 }
 
-// Add unresponsive/erroring RPCs to the poverty list
+// Get the highest head that at least `quorum` of the responding RPCs are at.
+//
+// With a quorum of 1 this is just the highest reported head.
+fn agreed_head(heads: &[HeadResult], quorum: HeadAgreementQuorum) -> u64 {
+    // Heads of 0 are from RPCs that didn't respond
+    let mut reported: Vec<u64> = heads
+        .iter()
+        .filter(|head| !head.syncing)
+        .map(|head| head.reported_head)
+        .filter(|head| *head != 0)
+        .collect();
+
+    if reported.is_empty() {
+        return 0;
+    }
+
+    // Sorted highest first, the head at `required - 1` is one
+    // that `required` RPCs are at or above
+    reported.sort_unstable_by(|a, b| b.cmp(a));
+    reported[quorum.required(reported.len()) - 1]
+}
+
+// Check what `latest` block is served by each RPC
+async fn latest_check(rpc_list: &Arc<RwLock<Vec<Rpc>>>, ttl: u128) -> Vec<HeadResult> {
+    let rpcs = rpc_list.read().unwrap().clone();
+
+    let probes =
+        rpcs.iter()
+            .enumerate()
+            .filter(|(_, rpc)| !skip_check(rpc))
+            .map(|(rpc_list_index, rpc)| {
+                async move {
+                    // Errors and timeouts are reported as 0
+                    let reported_head =
+                        match timeout(Duration::from_millis(ttl as u64), rpc.get_latest_block())
+                            .await
+                        {
+                            Ok(Ok(latest)) => latest,
+                            _ => 0,
+                        };
+
+                    HeadResult {
+                        rpc_list_index,
+                        reported_head,
+                        syncing: false,
+                        ..Default::default()
+                    }
+                }
+            });
+
+    join_all(probes).await
+}
+
+// Get the chain id reported by every RPC in both lists, by url.
+//
+// RPCs that don't respond, or were banned for something other than the
+// chain id, are left out.
+async fn chain_id_check(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    ttl: u128,
+) -> Vec<(String, u64)> {
+    let mut rpcs = rpc_list.read().unwrap().clone();
+    rpcs.extend(poverty_list.read().unwrap().iter().cloned());
+    // RPCs banned for being on the wrong chain get a chance to redeem themselves
+    rpcs.retain(|rpc| !rpc.status.banned || rpc.status.wrong_chain);
+
+    let chain_ids = join_all(rpcs.iter().map(|rpc| {
+        async move {
+            match timeout(Duration::from_millis(ttl as u64), rpc.get_chain_id()).await {
+                Ok(Ok(chain_id)) => Some((rpc.url.clone(), chain_id)),
+                _ => None,
+            }
+        }
+    }))
+    .await;
+
+    chain_ids.into_iter().flatten().collect()
+}
+
+// Get the RPCs whose `net_version` disagrees with the chain id they reported,
+// along with both numbers. RPCs that don't respond are left out.
+async fn net_version_check(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    chain_ids: &[(String, u64)],
+    ttl: u128,
+) -> Vec<(String, u64, u64)> {
+    let mut rpcs = rpc_list.read().unwrap().clone();
+    rpcs.extend(poverty_list.read().unwrap().iter().cloned());
+
+    let mismatches = join_all(chain_ids.iter().map(|(url, chain_id)| {
+        let rpc = rpcs.iter().find(|rpc| &rpc.url == url);
+        async move {
+            let net_version =
+                timeout(Duration::from_millis(ttl as u64), rpc?.get_net_version()).await;
+            match net_version {
+                Ok(Ok(net_version)) if net_version != *chain_id => {
+                    Some((url.clone(), *chain_id, net_version))
+                }
+                _ => None,
+            }
+        }
+    }))
+    .await;
+
+    mismatches.into_iter().flatten().collect()
+}
+
+// Ban every RPC whose `net_version` disagrees with its own chain id
+fn ban_inconsistent_chain_id(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    mismatches: Vec<(String, u64, u64)>,
+) {
+    if mismatches.is_empty() {
+        return;
+    }
+
+    let mut rpc_list_guard = rpc_list.write().unwrap();
+    let mut poverty_list_guard = poverty_list.write().unwrap();
+
+    for (url, chain_id, net_version) in mismatches {
+        if ban(&mut rpc_list_guard, &mut poverty_list_guard, &url) {
+            error!(
+                "{} reports chain id {} but net_version {}! Banning it from the active RPC pool.",
+                url, chain_id, net_version
+            );
+        }
+    }
+
+    rpc_list_guard.retain(|rpc| !rpc.status.banned);
+}
+
+// Fewest RPCs that have to report a chain id before we trust the majority
+const MIN_CHAIN_ID_RESPONDERS: usize = 3;
+
+// Chain id reported by a strict majority of RPCs.
+//
+// Returns `None` on ties or with fewer than `MIN_CHAIN_ID_RESPONDERS` responses,
+// since we can't tell which side is wrong then.
+pub fn majority_chain_id(chain_ids: &[(String, u64)]) -> Option<u64> {
+    if chain_ids.len() < MIN_CHAIN_ID_RESPONDERS {
+        return None;
+    }
+
+    let mut counts = std::collections::HashMap::<u64, usize>::new();
+    for (_, chain_id) in chain_ids {
+        *counts.entry(*chain_id).or_default() += 1;
+    }
+
+    counts
+        .into_iter()
+        .find(|(_, count)| count * 2 > chain_ids.len())
+        .map(|(chain_id, _)| chain_id)
+}
+
+// Ban every RPC that reported a chain id other than `expected_chain_id`,
+// or the one reported by a strict majority of RPCs if it's not set.
+//
+// RPCs banned here that report the expected chain id again get unbanned,
+// and go through the poverty list like any other RPC that fell behind.
+fn ban_wrong_chain(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    chain_ids: Vec<(String, u64)>,
+    expected_chain_id: Option<u64>,
+) {
+    let mut rpc_list_guard = rpc_list.write().unwrap();
+    let mut poverty_list_guard = poverty_list.write().unwrap();
+
+    // Remember what every RPC reported, for `blutgang_health`
+    for rpc in rpc_list_guard
+        .iter_mut()
+        .chain(poverty_list_guard.iter_mut())
+    {
+        if let Some((_, chain_id)) = chain_ids.iter().find(|(url, _)| url == &rpc.url) {
+            rpc.status.chain_id = Some(*chain_id);
+        }
+    }
+
+    let expected = match expected_chain_id.or_else(|| majority_chain_id(&chain_ids)) {
+        Some(expected) => expected,
+        None => return,
+    };
+
+    for (url, chain_id) in chain_ids {
+        if chain_id == expected {
+            if let Some(rpc) = poverty_list_guard
+                .iter_mut()
+                .find(|rpc| rpc.url == url && rpc.status.wrong_chain)
+            {
+                rpc.status.banned = false;
+                rpc.status.wrong_chain = false;
+                info!("{} is back on chain {}, unbanning it.", url, expected);
+            }
+            continue;
+        }
+
+        if ban(&mut rpc_list_guard, &mut poverty_list_guard, &url) {
+            error!(
+                "{} is on chain {}, expected chain {}! Banning it from the active RPC pool.",
+                url, chain_id, expected
+            );
+        }
+        if let Some(rpc) = poverty_list_guard.iter_mut().find(|rpc| rpc.url == url) {
+            rpc.status.wrong_chain = true;
+        }
+    }
+
+    rpc_list_guard.retain(|rpc| !rpc.status.banned);
+}
+
+// Ban the RPC with `url`, copying it to the poverty list if it's active.
+// The caller removes banned RPCs from the active list.
+//
+// Returns false if the RPC isn't in either list anymore.
+fn ban(rpc_list: &mut [Rpc], poverty_list: &mut Vec<Rpc>, url: &str) -> bool {
+    let ban = |rpc: &mut Rpc| {
+        rpc.status.banned = true;
+        rpc.status.is_erroring = true;
+    };
+
+    if let Some(rpc) = rpc_list.iter_mut().find(|rpc| rpc.url == url) {
+        ban(rpc);
+        poverty_list.push(rpc.clone());
+    } else if let Some(rpc) = poverty_list.iter_mut().find(|rpc| rpc.url == url) {
+        ban(rpc);
+    } else {
+        return false;
+    }
+
+    true
+}
+
+// Get the hash of block `number` from every RPC that isn't banned.
+// RPCs that fail to respond, or don't have the block, are left out.
+async fn block_hash_check(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    number: u64,
+    ttl: u128,
+) -> Vec<(String, String)> {
+    let mut rpcs = rpc_list.read().unwrap().clone();
+    rpcs.extend(poverty_list.read().unwrap().iter().cloned());
+    rpcs.retain(|rpc| !rpc.status.banned);
+
+    let hashes = join_all(rpcs.iter().map(|rpc| {
+        async move {
+            match timeout(
+                Duration::from_millis(ttl as u64),
+                rpc.get_block_hash(number),
+            )
+            .await
+            {
+                Ok(Ok(Some(hash))) => Some((rpc.url.clone(), hash)),
+                _ => None,
+            }
+        }
+    }))
+    .await;
+
+    hashes.into_iter().flatten().collect()
+}
+
+// Ban every RPC that returned a hash other than `expected_hash` for the probe block.
+// They're on a fork, or serving corrupted data.
+fn ban_wrong_block_hash(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    hashes: Vec<(String, String)>,
+    number: u64,
+    expected_hash: &str,
+) {
+    let mut rpc_list_guard = rpc_list.write().unwrap();
+    let mut poverty_list_guard = poverty_list.write().unwrap();
+
+    for (url, hash) in hashes {
+        if hash.eq_ignore_ascii_case(expected_hash) {
+            continue;
+        }
+
+        if ban(&mut rpc_list_guard, &mut poverty_list_guard, &url) {
+            error!(
+                "{} returned hash {} for block {}, expected {}! Banning it from the active RPC pool.",
+                url, hash, number, expected_hash
+            );
+        }
+    }
+
+    rpc_list_guard.retain(|rpc| !rpc.status.banned);
+}
+
+// Collect metadata about each node and store it on its RPC
+async fn collect_metadata(rpc_list: &Arc<RwLock<Vec<Rpc>>>, ttl: u128) {
+    let rpcs = rpc_list.read().unwrap().clone();
+
+    let metadata = join_all(rpcs.iter().map(|rpc| {
+        async move {
+            timeout(Duration::from_millis(ttl as u64), rpc.get_metadata())
+                .await
+                .unwrap_or_default()
+        }
+    }))
+    .await;
+
+    // The list can change while we're waiting, so find the RPCs by their url
+    let mut rpc_list_guard = rpc_list.write().unwrap();
+    for (rpc, metadata) in rpcs.iter().zip(metadata) {
+        if let Some(current) = rpc_list_guard
+            .iter_mut()
+            .find(|current| current.url == rpc.url)
+        {
+            current.metadata = metadata;
+        }
+    }
+}
+
+// Count strikes for RPCs serving `latest` blocks too far behind the agreed head,
+// and flag the ones with too many strikes in a row
+fn track_stale_latest(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    latest: Vec<HeadResult>,
+    agreed_head: u64,
+    stale_probe: StaleLatestProbe,
+) {
+    let mut rpc_list_guard = rpc_list.write().unwrap();
+
+    for latest in latest {
+        // Unresponsive RPCs are taken care of by the head check
+        if latest.reported_head == 0 {
+            continue;
+        }
+
+        if let Some(rpc) = rpc_list_guard.get_mut(latest.rpc_list_index) {
+            if latest.reported_head.saturating_add(stale_probe.max_lag) < agreed_head {
+                rpc.status.stale_latest_strikes += 1;
+            } else {
+                rpc.status.stale_latest_strikes = 0;
+            }
+
+            rpc.status.serves_stale_latest =
+                rpc.status.stale_latest_strikes >= stale_probe.strikes.max(1);
+        }
+    }
+}
+
+// Move RPCs flagged for serving stale `latest` blocks to the poverty list
+fn make_stale_poverty(rpc_list: &Arc<RwLock<Vec<Rpc>>>, poverty_list: &Arc<RwLock<Vec<Rpc>>>) {
+    let mut rpc_list_guard = rpc_list.write().unwrap();
+    let mut poverty_list_guard = poverty_list.write().unwrap();
+
+    for rpc in rpc_list_guard.iter_mut() {
+        if rpc.status.serves_stale_latest {
+            rpc.status.is_erroring = true;
+            warn_limited!(
+                "{} is serving stale `latest` blocks! Removing from active RPC pool.",
+                rpc.url
+            );
+
+            poverty_list_guard.push(rpc.clone());
+        }
+    }
+
+    rpc_list_guard.retain(|rpc| !rpc.status.is_erroring);
+}
+
+// Run the health `probes` on each RPC and record their results.
+//
+// RPCs get flagged with the first probe they fail. Probes they didn't answer can't
+// clear the flag, but unresponsive RPCs are taken care of by the head check anyway.
+async fn probe_check(rpc_list: &Arc<RwLock<Vec<Rpc>>>, probes: &[HealthProbe], ttl: u128) {
+    let rpcs: Vec<Rpc> = rpc_list
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|rpc| !skip_check(rpc))
+        .cloned()
+        .collect();
+
+    let results = join_all(rpcs.iter().map(|rpc| {
+        join_all(probes.iter().map(|probe| {
+            async move {
+                timeout(Duration::from_millis(ttl as u64), probe.run(rpc))
+                    .await
+                    .ok()
+                    .flatten()
+            }
+        }))
+    }))
+    .await;
+
+    // The list can change while we're waiting, so find the RPCs by their url
+    let mut rpc_list_guard = rpc_list.write().unwrap();
+    for (rpc, results) in rpcs.iter().zip(results) {
+        let current = match rpc_list_guard
+            .iter_mut()
+            .find(|current| current.url == rpc.url)
+        {
+            Some(current) => current,
+            None => continue,
+        };
+
+        let mut failed_probe = None;
+        for (probe, value) in probes.iter().zip(&results) {
+            if let Some(value) = value {
+                current
+                    .status
+                    .probe_results
+                    .insert(probe.method.clone(), *value);
+                failed_probe = failed_probe.or_else(|| probe.failure(*value));
+            }
+        }
+
+        if failed_probe.is_some() || results.iter().all(Option::is_some) {
+            current.status.failed_probe = failed_probe;
+        }
+    }
+}
+
+// Move RPCs flagged for failing a health probe to the poverty list
+fn make_probe_poverty(rpc_list: &Arc<RwLock<Vec<Rpc>>>, poverty_list: &Arc<RwLock<Vec<Rpc>>>) {
+    let mut rpc_list_guard = rpc_list.write().unwrap();
+    let mut poverty_list_guard = poverty_list.write().unwrap();
+
+    for rpc in rpc_list_guard.iter_mut() {
+        if let Some(failed_probe) = &rpc.status.failed_probe {
+            rpc.status.is_erroring = true;
+            warn_limited!(
+                "{} failed a health probe, {}! Removing from active RPC pool.",
+                rpc.url,
+                failed_probe
+            );
+
+            poverty_list_guard.push(rpc.clone());
+        }
+    }
+
+    rpc_list_guard.retain(|rpc| !rpc.status.is_erroring);
+}
+
+// Returns true if the RPC shouldn't be checked, because it's banned, paused or backing off
+fn skip_check(rpc: &Rpc) -> bool {
+    rpc.status.banned
+        || rpc.status.paused
+        || rpc
+            .status
+            .next_retry_at
+            .is_some_and(|next_retry_at| next_retry_at > Instant::now())
+}
+
+// Record the outcome of a head check on the RPC.
+//
+// A head of `0` means it didn't respond. Response times also count towards
+// the latency used to pick RPCs.
+fn record_head(rpc: &mut Rpc, reported_head: u64, agreed_head: u64, latency: Duration) {
+    rpc.status.head_lag = agreed_head.saturating_sub(reported_head);
+    rpc.status.last_latency_ms = latency.as_secs_f64() * 1000.0;
+
+    if reported_head == 0 {
+        rpc.status.consecutive_failures = rpc.status.consecutive_failures.saturating_add(1);
+    } else {
+        rpc.update_latency(latency.as_nanos() as f64);
+        // Without an agreed head the lag doesn't tell us anything
+        if agreed_head != 0 {
+            rpc.record_lag(rpc.status.head_lag);
+        }
+        rpc.status.last_head = reported_head;
+        rpc.status.consecutive_failures = 0;
+        rpc.status.last_healthy_check = Some(Instant::now());
+    }
+}
+
+// Add unresponsive/erroring RPCs to the poverty list.
+//
+// If none of the RPCs report a valid head there's nothing to agree on, so
+// instead of treating 0 as the agreed head we flag the cluster as unhealthy on
+// `trigger` and leave every RPC where it is until some of them recover.
 fn make_poverty(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     heads: Vec<HeadResult>,
+    rules: PovertyRules,
+    trigger: &HealthTrigger,
 ) -> Result<u64, HealthError> {
-    // Get the highest head reported by the RPCs
-    let mut highest_head = 0;
-    for head in &heads {
-        if head.reported_head > highest_head {
-            highest_head = head.reported_head;
-        }
-    }
+    let agreed_head = agreed_head(&heads, rules.quorum);
 
-    // Mark all RPCs that dont report the highest head as erroring
+    // Mark all RPCs that are too far behind the agreed head as erroring
     let mut rpc_list_guard = rpc_list.write().unwrap();
     let mut poverty_list_guard = poverty_list.write().unwrap();
 
+    let no_consensus = agreed_head == 0 && !heads.is_empty();
+    let had_no_consensus = trigger.set_no_consensus(no_consensus);
+    if no_consensus {
+        warn_limited!(
+            "None of the {} active RPCs reported a valid head! Keeping them all in the active pool.",
+            heads.len()
+        );
+    } else if had_no_consensus {
+        info_limited!("RPCs are reporting valid heads again.");
+    }
+
     for head in heads {
-        if head.reported_head < highest_head {
-            // Mark the RPC as erroring
+        record_head(
+            &mut rpc_list_guard[head.rpc_list_index],
+            head.reported_head,
+            agreed_head,
+            head.latency,
+        );
+        rpc_list_guard[head.rpc_list_index].status.is_syncing = head.syncing;
+
+        if no_consensus {
+            continue;
+        }
+
+        // Unresponsive RPCs get a few chances in case it was a one-off timeout
+        let unresponsive = head.reported_head == 0
+            && rpc_list_guard[head.rpc_list_index]
+                .status
+                .consecutive_failures
+                >= rules.max_consecutive_failures;
+        let behind = head.reported_head != 0
+            && head.reported_head < agreed_head.saturating_sub(rules.tolerance);
+
+        if head.syncing {
+            // Syncing nodes can report a plausible head while serving garbage state
+            rpc_list_guard[head.rpc_list_index].status.is_erroring = true;
+            warn_limited!(
+                "{} is syncing! Removing from active RPC pool.",
+                rpc_list_guard[head.rpc_list_index].url
+            );
+
+            poverty_list_guard.push(rpc_list_guard[head.rpc_list_index].clone());
+        } else if unresponsive {
             rpc_list_guard[head.rpc_list_index].status.is_erroring = true;
-            println!(
-                "\x1b[93mWrn:\x1b[0m {} is falling behind! Removing froma active RPC pool.",
+            warn_limited!(
+                "{} is unresponsive! Removing from active RPC pool.",
                 rpc_list_guard[head.rpc_list_index].url
             );
 
+            poverty_list_guard.push(rpc_list_guard[head.rpc_list_index].clone());
+        } else if behind {
+            // Mark the RPC as erroring
+            rpc_list_guard[head.rpc_list_index].status.is_erroring = true;
+            warn_limited!(
+                "{} is falling behind! Reported head {} is {} blocks behind the agreed head {} (tolerance {}, max lag over the last {} checks {}). Removing from active RPC pool.",
+                rpc_list_guard[head.rpc_list_index].url,
+                head.reported_head,
+                agreed_head - head.reported_head,
+                agreed_head,
+                rules.tolerance,
+                LAG_WINDOW,
+                rpc_list_guard[head.rpc_list_index].max_observed_lag()
+            );
+
             // Add the RPC to the poverty list
             poverty_list_guard.push(rpc_list_guard[head.rpc_list_index].clone());
         }
@@ -191,7 +983,7 @@ fn make_poverty(
     // Go over rpc_list_guard and remove all erroring rpcs
     rpc_list_guard.retain(|rpc| !rpc.status.is_erroring);
 
-    Ok(highest_head)
+    Ok(agreed_head)
 }
 
 // Go over the `poverty_list` to see if any nodes are back to normal
@@ -200,17 +992,48 @@ fn escape_poverty(
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_heads: Vec<HeadResult>,
     agreed_head: u64,
+    rules: PovertyRules,
 ) -> Result<(), HealthError> {
     // Check if any nodes made it 🗣️🔥🔥🔥
     let mut poverty_list_guard = poverty_list.write().unwrap();
     let mut rpc_list_guard = rpc_list.write().unwrap();
 
     for head_result in poverty_heads {
-        if head_result.reported_head >= agreed_head {
+        record_head(
+            &mut poverty_list_guard[head_result.rpc_list_index],
+            head_result.reported_head,
+            agreed_head,
+            head_result.latency,
+        );
+        poverty_list_guard[head_result.rpc_list_index]
+            .status
+            .is_syncing = head_result.syncing;
+
+        // Unresponsive RPCs report 0, which would pass when there's no agreed head
+        // because every RPC is in poverty
+        if head_result.reported_head != 0
+            && head_result.reported_head >= agreed_head.saturating_sub(rules.tolerance)
+            && !head_result.syncing
+            && !poverty_list_guard[head_result.rpc_list_index]
+                .status
+                .serves_stale_latest
+            && !poverty_list_guard[head_result.rpc_list_index].status.banned
+            && !poverty_list_guard[head_result.rpc_list_index].status.paused
+            && poverty_list_guard[head_result.rpc_list_index]
+                .status
+                .failed_probe
+                .is_none()
+        {
             let mut rpc = poverty_list_guard[head_result.rpc_list_index].clone();
             rpc.status.is_erroring = false;
-            println!(
-                "\x1b[35mInfo:\x1b[0m {} is following the head again! Added to active RPC pool.",
+            // Give its WS endpoint another chance on the next reconnect
+            rpc.status.ws_erroring = false;
+            rpc.status.next_retry_at = None;
+            rpc.status.retry_backoff = Duration::ZERO;
+            // Connections pooled while it was failing are likely dead
+            rpc.reset_client();
+            info_limited!(
+                "{} is following the head again! Added to active RPC pool.",
                 rpc.url
             );
 
@@ -221,6 +1044,15 @@ fn escape_poverty(
             poverty_list_guard[head_result.rpc_list_index]
                 .status
                 .is_erroring = false;
+        } else if !rules.backoff_max.is_zero() {
+            // Wait longer before checking it again
+            let status = &mut poverty_list_guard[head_result.rpc_list_index].status;
+            status.retry_backoff = match status.retry_backoff.is_zero() {
+                true => rules.backoff_base,
+                false => status.retry_backoff * 2,
+            }
+            .min(rules.backoff_max);
+            status.next_retry_at = Some(Instant::now() + status.retry_backoff);
         }
     }
 
@@ -230,27 +1062,19 @@ fn escape_poverty(
     Ok(())
 }
 
-// Remove the RPC that dropped out ws_conn and add it to the poverty list
-pub async fn send_dropped_to_poverty(
+// Stop sending subscriptions to the RPC that dropped out of ws_conn.
+//
+// Its HTTP endpoint is checked separately, so it stays in the active pool.
+pub async fn handle_ws_drop(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
-    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
-    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    incoming_tx: &mpsc::Sender<WsconnMessage>,
     rx: broadcast::Receiver<IncomingResponse>,
     sub_data: &Arc<SubscriptionData>,
     ws_conn_index: usize,
 ) -> Result<(), HealthError> {
-    {
-        let mut rpc_list_guard = rpc_list.write().unwrap();
-        let mut poverty_list_guard = poverty_list.write().unwrap();
-
-        // Check if the RPC is in the rpc_list
-        if let Some(rpc) = rpc_list_guard.get(ws_conn_index) {
-            // Add the RPC to the poverty list
-            poverty_list_guard.push(rpc.clone());
-
-            // Remove the RPC from the rpc_list
-            rpc_list_guard.remove(ws_conn_index);
-        }
+    if let Some(rpc) = rpc_list.write().unwrap().get_mut(ws_conn_index) {
+        warn_limited!("WS of {} dropped! Moving its subscriptions away.", rpc.url);
+        rpc.status.ws_erroring = true;
     }
 
     // Move subscriptions away from that node
@@ -259,12 +1083,22 @@ pub async fn send_dropped_to_poverty(
     Ok(())
 }
 
+// Returns true if an active RPC has a WS endpoint we couldn't connect to.
+//
+// Syncing the WS connections reconnects to them, and clears `ws_erroring` if it works.
+fn has_ws_erroring(rpc_list: &Arc<RwLock<Vec<Rpc>>>) -> bool {
+    rpc_list
+        .read()
+        .unwrap()
+        .iter()
+        .any(|rpc| rpc.ws_url.is_some() && rpc.status.ws_erroring)
+}
+
 // Listen for dropped ws connections and handle them
 pub async fn dropped_listener(
     rpc_list: Arc<RwLock<Vec<Rpc>>>,
-    poverty_list: Arc<RwLock<Vec<Rpc>>>,
-    mut ws_err_rx: mpsc::UnboundedReceiver<WsChannelErr>,
-    incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
+    mut ws_err_rx: mpsc::Receiver<WsChannelErr>,
+    incoming_tx: mpsc::Sender<WsconnMessage>,
     rx: broadcast::Receiver<IncomingResponse>,
     sub_data: Arc<SubscriptionData>,
 ) -> Result<(), HealthError> {
@@ -273,17 +1107,13 @@ pub async fn dropped_listener(
 
         match ws_err {
             Some(WsChannelErr::Closed(index)) => {
-                send_dropped_to_poverty(
-                    &rpc_list,
-                    &poverty_list,
-                    &incoming_tx,
-                    rx.resubscribe(),
-                    &sub_data,
-                    index,
-                )
-                .await
-                .unwrap_or(());
-                incoming_tx.send(WsconnMessage::Reconnect()).unwrap_or(());
+                handle_ws_drop(&rpc_list, &incoming_tx, rx.resubscribe(), &sub_data, index)
+                    .await
+                    .unwrap_or(());
+                incoming_tx
+                    .send(WsconnMessage::Reconnect())
+                    .await
+                    .unwrap_or(());
             }
             None => {
                 return Err(HealthError::InvalidResponse(
@@ -300,6 +1130,344 @@ pub async fn dropped_listener(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rpc::mock::MockRpc;
+    use serde_json::{
+        json,
+        Value,
+    };
+    use std::sync::atomic::{
+        AtomicU64,
+        Ordering,
+    };
+
+    async fn mock_rpc(head: u64) -> (MockRpc, Rpc) {
+        let mock = MockRpc::spawn(Duration::ZERO, move |request| {
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": format!("{:#x}", head)})
+        })
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        (mock, rpc)
+    }
+
+    #[test]
+    fn test_poverty_schedule() {
+        let mut schedule = CheckSchedule::default();
+
+        // Checked every time when 0
+        assert!(schedule.is_due(Duration::ZERO));
+        assert!(schedule.is_due(Duration::ZERO));
+
+        let mut schedule = CheckSchedule::default();
+        let ttl = Duration::from_millis(50);
+        assert!(schedule.is_due(ttl));
+        assert!(!schedule.is_due(ttl));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(schedule.is_due(ttl));
+    }
+
+    #[tokio::test]
+    async fn test_poverty_checked_on_its_own_cadence() {
+        let (active_mock, active) = mock_rpc(10).await;
+        let (poverty_mock, mut poverty) = mock_rpc(5).await;
+        poverty.status.is_erroring = true;
+        let rpc_list = Arc::new(RwLock::new(vec![active]));
+        let poverty_list = Arc::new(RwLock::new(vec![poverty]));
+
+        let mut schedule = CheckSchedule::default();
+        let poverty_ttl = Duration::from_millis(150);
+        for _ in 0..5 {
+            let check_poverty = schedule.is_due(poverty_ttl);
+            check(
+                &rpc_list,
+                &poverty_list,
+                &1000,
+                check_poverty,
+                PovertyRules::default(),
+                StaleLatestProbe::default(),
+                &[],
+                Duration::ZERO,
+                &HealthWebhook::default(),
+                &HealthTrigger::default(),
+            )
+            .await
+            .unwrap();
+            sleep(Duration::from_millis(60)).await;
+        }
+
+        // The active list is checked every time, the poverty list once per `poverty_ttl`.
+        // Every check asks for both `eth_blockNumber` and `eth_syncing`.
+        assert_eq!(active_mock.requests(), 10);
+        assert_eq!(poverty_mock.requests(), 4);
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_check_records_latency() {
+        let slow_mock = MockRpc::spawn(
+            Duration::from_millis(50),
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0xa"}),
+        )
+        .await;
+        let slow = Rpc::new(slow_mock.url.clone(), None, 0, 0, 1.0);
+        // Nothing listens on port 1
+        let down = Rpc::new("http://127.0.0.1:1".to_string(), None, 0, 0, 1.0);
+        let rpc_list = Arc::new(RwLock::new(vec![slow, down]));
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+
+        check(
+            &rpc_list,
+            &poverty_list,
+            &1000,
+            false,
+            PovertyRules {
+                max_consecutive_failures: 2,
+                ..Default::default()
+            },
+            StaleLatestProbe::default(),
+            &[],
+            Duration::ZERO,
+            &HealthWebhook::default(),
+            &HealthTrigger::default(),
+        )
+        .await
+        .unwrap();
+
+        let rpc_list = rpc_list.read().unwrap();
+        assert!(rpc_list[0].status.last_latency_ms >= 50.0);
+        assert!(rpc_list[0].status.last_latency_ms < 1000.0);
+        assert!(rpc_list[0].status.latency > 0.0);
+        // Unresponsive RPCs get the `ttl`
+        assert_eq!(rpc_list[1].status.last_latency_ms, 1000.0);
+    }
+
+    #[tokio::test]
+    async fn test_probe_failures_demote() {
+        // Reports head 10 and `peers` peers
+        let peers = Arc::new(AtomicU64::new(1));
+        let reported = peers.clone();
+        let isolated_mock = MockRpc::spawn(Duration::ZERO, move |request| {
+            let result = match request["method"].as_str() {
+                Some("net_peerCount") => format!("{:#x}", reported.load(Ordering::Relaxed)),
+                _ => "0xa".to_string(),
+            };
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+        })
+        .await;
+        let isolated = Rpc::new(isolated_mock.url.clone(), None, 0, 0, 1.0);
+        let (_healthy_mock, healthy) = mock_rpc(10).await;
+        let rpc_list = Arc::new(RwLock::new(vec![isolated, healthy]));
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+        let probes = [HealthProbe::min_peers(5)];
+        let webhook = HealthWebhook::default();
+        let trigger = HealthTrigger::default();
+
+        let check = || {
+            check(
+                &rpc_list,
+                &poverty_list,
+                &1000,
+                true,
+                PovertyRules::default(),
+                StaleLatestProbe::default(),
+                &probes,
+                Duration::ZERO,
+                &webhook,
+                &trigger,
+            )
+        };
+
+        check().await.unwrap();
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        let demoted = poverty_list.read().unwrap()[0].clone();
+        assert_eq!(demoted.url, isolated_mock.url);
+        assert_eq!(demoted.status.probe_results["net_peerCount"], 1);
+        assert_eq!(
+            demoted.status.failed_probe.as_deref(),
+            Some("net_peerCount is 1, below 5")
+        );
+
+        // Back once it's connected again
+        peers.store(25, Ordering::Relaxed);
+        check().await.unwrap();
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+        assert!(poverty_list.read().unwrap().is_empty());
+        assert!(rpc_list.read().unwrap()[1].status.failed_probe.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_paused_rpc_stays_in_poverty() {
+        let (_active_mock, active) = mock_rpc(10).await;
+        let (paused_mock, mut paused) = mock_rpc(10).await;
+        paused.status.is_erroring = true;
+        paused.status.paused = true;
+        let rpc_list = Arc::new(RwLock::new(vec![active]));
+        let poverty_list = Arc::new(RwLock::new(vec![paused]));
+
+        check(
+            &rpc_list,
+            &poverty_list,
+            &1000,
+            true,
+            PovertyRules::default(),
+            StaleLatestProbe::default(),
+            &[],
+            Duration::ZERO,
+            &HealthWebhook::default(),
+            &HealthTrigger::default(),
+        )
+        .await
+        .unwrap();
+
+        // Healthy, but not checked or brought back
+        assert_eq!(paused_mock.requests(), 0);
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_latest_flagged() {
+        let (honest_mock, honest) = mock_rpc(100).await;
+
+        // Claims the same head as everyone else, but serves an old `latest` block
+        let stale_mock = MockRpc::spawn(Duration::ZERO, |request| {
+            let result = match request["method"].as_str() {
+                Some("eth_getBlockByNumber") => json!({"number": "0x50"}),
+                _ => json!("0x64"),
+            };
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+        })
+        .await;
+        let stale = Rpc::new(stale_mock.url.clone(), None, 0, 0, 1.0);
+
+        let rpc_list = Arc::new(RwLock::new(vec![honest, stale]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+        let stale_probe = StaleLatestProbe {
+            max_lag: 5,
+            strikes: 2,
+        };
+
+        // Not flagged until it has enough strikes
+        check(
+            &rpc_list,
+            &poverty_list,
+            &1000,
+            true,
+            PovertyRules::default(),
+            stale_probe,
+            &[],
+            Duration::ZERO,
+            &HealthWebhook::default(),
+            &HealthTrigger::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+        assert_eq!(rpc_list.read().unwrap()[1].status.stale_latest_strikes, 1);
+
+        check(
+            &rpc_list,
+            &poverty_list,
+            &1000,
+            true,
+            PovertyRules::default(),
+            stale_probe,
+            &[],
+            Duration::ZERO,
+            &HealthWebhook::default(),
+            &HealthTrigger::default(),
+        )
+        .await
+        .unwrap();
+
+        // Reporting the head isn't enough to get it out of poverty
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert_eq!(rpc_list.read().unwrap()[0].url, honest_mock.url);
+        let poverty_list_guard = poverty_list.read().unwrap();
+        assert_eq!(poverty_list_guard.len(), 1);
+        assert!(poverty_list_guard[0].status.serves_stale_latest);
+    }
+
+    #[tokio::test]
+    async fn test_syncing_nodes_are_demoted() {
+        let mock = |syncing: Value| {
+            MockRpc::spawn(Duration::ZERO, move |request| {
+                let result = match request["method"].as_str() {
+                    Some("eth_syncing") => syncing.clone(),
+                    _ => json!("0x64"),
+                };
+                json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+            })
+        };
+        let synced_mock = mock(json!(false)).await;
+        let syncing_mock = mock(json!({
+            "startingBlock": "0x0",
+            "currentBlock": "0x64",
+            "highestBlock": "0x1000",
+        }))
+        .await;
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(synced_mock.url.clone(), None, 0, 0, 1.0),
+            Rpc::new(syncing_mock.url.clone(), None, 0, 0, 1.0),
+        ]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+
+        for _ in 0..2 {
+            check(
+                &rpc_list,
+                &poverty_list,
+                &1000,
+                true,
+                PovertyRules::default(),
+                StaleLatestProbe::default(),
+                &[],
+                Duration::ZERO,
+                &HealthWebhook::default(),
+                &HealthTrigger::default(),
+            )
+            .await
+            .unwrap();
+        }
+
+        // Demoted even though its head matches, and kept out while it's syncing
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert_eq!(rpc_list.read().unwrap()[0].url, synced_mock.url);
+        let poverty_list_guard = poverty_list.read().unwrap();
+        assert_eq!(poverty_list_guard.len(), 1);
+        assert_eq!(poverty_list_guard[0].status.last_head, 100);
+        assert!(poverty_list_guard[0].status.is_syncing);
+    }
+
+    #[tokio::test]
+    async fn test_collect_metadata() {
+        let mock = MockRpc::spawn(Duration::ZERO, |request| {
+            let result = match request["method"].as_str() {
+                Some("web3_clientVersion") => json!("Geth/v1.13.0"),
+                Some("net_peerCount") => json!("0x19"),
+                Some("eth_chainId") => json!("0x1"),
+                Some("eth_syncing") => json!(false),
+                Some("eth_getBalance") => json!("0x0"),
+                _ => Value::Null,
+            };
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+        })
+        .await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            mock.url.clone(),
+            None,
+            0,
+            0,
+            1.0,
+        )]));
+
+        collect_metadata(&rpc_list, 1000).await;
+
+        let metadata = rpc_list.read().unwrap()[0].metadata.clone();
+        assert_eq!(metadata.client_version.as_deref(), Some("Geth/v1.13.0"));
+        assert_eq!(metadata.peer_count, Some(25));
+        assert_eq!(metadata.chain_id, Some(1));
+        assert_eq!(metadata.syncing, Some(false));
+        assert_eq!(metadata.archive, Some(true));
+    }
 
     // Construct a hypothetical RPC and heads list for testing
     fn dummy_head_check() -> Vec<HeadResult> {
@@ -307,14 +1475,20 @@ mod tests {
             HeadResult {
                 rpc_list_index: 0,
                 reported_head: 18177557,
+                syncing: false,
+                ..Default::default()
             },
             HeadResult {
                 rpc_list_index: 1,
                 reported_head: 18193012,
+                syncing: false,
+                ..Default::default()
             },
             HeadResult {
                 rpc_list_index: 2,
                 reported_head: 0,
+                syncing: false,
+                ..Default::default()
             },
         ]
     }
@@ -333,7 +1507,13 @@ mod tests {
         let heads = dummy_head_check();
 
         // Call the make_poverty function
-        let result = make_poverty(&rpc_list, &poverty_list, heads);
+        let result = make_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads,
+            PovertyRules::default(),
+            &HealthTrigger::default(),
+        );
         assert!(result.is_ok());
 
         // Check the state of RPCs after the test
@@ -347,6 +1527,346 @@ mod tests {
         assert_eq!(poverty_list_guard.len(), 2);
     }
 
+    #[test]
+    fn test_ban_wrong_chain() {
+        let rpc = |url: &str| Rpc::new(url.to_string(), None, 0, 0, 1.0);
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("a"), rpc("b"), rpc("c"), rpc("e")]));
+        let poverty_list = Arc::new(RwLock::new(vec![rpc("d")]));
+        poverty_list.write().unwrap()[0].status.is_erroring = true;
+
+        // Majority is chain 1
+        let chain_ids = vec![
+            ("a".to_string(), 1),
+            ("b".to_string(), 5),
+            ("c".to_string(), 1),
+            ("d".to_string(), 5),
+            ("e".to_string(), 1),
+        ];
+        ban_wrong_chain(&rpc_list, &poverty_list, chain_ids, None);
+
+        let urls = |list: &Arc<RwLock<Vec<Rpc>>>| {
+            list.read()
+                .unwrap()
+                .iter()
+                .map(|rpc| (rpc.url.clone(), rpc.status.banned))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            urls(&rpc_list),
+            vec![
+                ("a".to_string(), false),
+                ("c".to_string(), false),
+                ("e".to_string(), false)
+            ]
+        );
+        assert_eq!(
+            urls(&poverty_list),
+            vec![("d".to_string(), true), ("b".to_string(), true)]
+        );
+        assert_eq!(rpc_list.read().unwrap()[0].status.chain_id, Some(1));
+        assert_eq!(poverty_list.read().unwrap()[1].status.chain_id, Some(5));
+
+        // Banned RPCs don't get out, even if they follow the head
+        assert!(skip_check(&poverty_list.read().unwrap()[1]));
+        escape_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads(&[10, 10]),
+            10,
+            PovertyRules::default(),
+        )
+        .unwrap();
+        assert_eq!(rpc_list.read().unwrap().len(), 3);
+        assert_eq!(poverty_list.read().unwrap().len(), 2);
+
+        // The configured chain id wins over the majority
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("a"), rpc("b"), rpc("c")]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+        let chain_ids = vec![
+            ("a".to_string(), 1),
+            ("b".to_string(), 5),
+            ("c".to_string(), 1),
+        ];
+        ban_wrong_chain(&rpc_list, &poverty_list, chain_ids, Some(5));
+        assert_eq!(urls(&rpc_list), vec![("b".to_string(), false)]);
+        assert_eq!(poverty_list.read().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_net_version_check() {
+        let mock = |chain_id: &'static str, net_version: &'static str| {
+            MockRpc::spawn(Duration::ZERO, move |request| {
+                let result = match request["method"].as_str() {
+                    Some("eth_chainId") => chain_id,
+                    _ => net_version,
+                };
+                json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+            })
+        };
+        let consistent = mock("0x1", "1").await;
+        let hex = mock("0x89", "0x89").await;
+        let spoofed = mock("0x1", "137").await;
+
+        let rpc = |mock: &MockRpc| Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let rpc_list = Arc::new(RwLock::new(vec![
+            rpc(&consistent),
+            rpc(&hex),
+            rpc(&spoofed),
+        ]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+
+        let chain_ids = chain_id_check(&rpc_list, &poverty_list, 1000).await;
+        let mismatches = net_version_check(&rpc_list, &poverty_list, &chain_ids, 1000).await;
+        assert_eq!(mismatches, vec![(spoofed.url.clone(), 1, 137)]);
+
+        ban_inconsistent_chain_id(&rpc_list, &poverty_list, mismatches);
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+        let poverty_list_guard = poverty_list.read().unwrap();
+        assert_eq!(poverty_list_guard.len(), 1);
+        assert_eq!(poverty_list_guard[0].url, spoofed.url);
+        assert!(poverty_list_guard[0].status.banned);
+    }
+
+    #[test]
+    fn test_majority_chain_id() {
+        let chain_ids = |ids: &[u64]| {
+            ids.iter()
+                .map(|id| (String::new(), *id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(majority_chain_id(&chain_ids(&[])), None);
+        assert_eq!(majority_chain_id(&chain_ids(&[10, 1, 10])), Some(10));
+        assert_eq!(majority_chain_id(&chain_ids(&[1, 1, 1, 10])), Some(1));
+        // Ties and too few responders don't have a majority
+        assert_eq!(majority_chain_id(&chain_ids(&[10, 1])), None);
+        assert_eq!(majority_chain_id(&chain_ids(&[1, 1])), None);
+        assert_eq!(majority_chain_id(&chain_ids(&[10, 10, 1, 1])), None);
+        assert_eq!(majority_chain_id(&chain_ids(&[10, 1, 5])), None);
+    }
+
+    #[test]
+    fn test_ban_wrong_chain_without_majority() {
+        let rpc = |url: &str| Rpc::new(url.to_string(), None, 0, 0, 1.0);
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("a"), rpc("b")]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+        let chain_ids = vec![("a".to_string(), 1), ("b".to_string(), 5)];
+
+        // Two RPCs that disagree could be either way round, so leave them be
+        ban_wrong_chain(&rpc_list, &poverty_list, chain_ids.clone(), None);
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+        assert!(poverty_list.read().unwrap().is_empty());
+
+        // Unless we know which chain we're supposed to be on
+        ban_wrong_chain(&rpc_list, &poverty_list, chain_ids, Some(1));
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert!(poverty_list.read().unwrap()[0].status.banned);
+    }
+
+    #[tokio::test]
+    async fn test_unban_right_chain() {
+        let mock = MockRpc::spawn(Duration::ZERO, |request| {
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"})
+        })
+        .await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            mock.url.clone(),
+            None,
+            0,
+            0,
+            1.0,
+        )]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+
+        // Say it reported the wrong chain during a hiccup
+        ban_wrong_chain(
+            &rpc_list,
+            &poverty_list,
+            vec![(mock.url.clone(), 5)],
+            Some(1),
+        );
+        assert!(rpc_list.read().unwrap().is_empty());
+        assert!(poverty_list.read().unwrap()[0].status.banned);
+
+        // It still gets checked, and is unbanned once it's back on the right chain
+        let chain_ids = chain_id_check(&rpc_list, &poverty_list, 1000).await;
+        assert_eq!(chain_ids, vec![(mock.url.clone(), 1)]);
+        ban_wrong_chain(&rpc_list, &poverty_list, chain_ids, Some(1));
+
+        let poverty_list_guard = poverty_list.read().unwrap();
+        assert!(!poverty_list_guard[0].status.banned);
+        assert!(!poverty_list_guard[0].status.wrong_chain);
+        // Getting back in the active pool is up to the poverty check
+        assert!(poverty_list_guard[0].status.is_erroring);
+    }
+
+    fn heads(reported: &[u64]) -> Vec<HeadResult> {
+        reported
+            .iter()
+            .enumerate()
+            .map(|(rpc_list_index, reported_head)| {
+                HeadResult {
+                    rpc_list_index,
+                    reported_head: *reported_head,
+                    syncing: false,
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_agreed_head() {
+        let reported = heads(&[101, 100, 100, 0]);
+
+        // Highest head by default
+        assert_eq!(agreed_head(&reported, HeadAgreementQuorum::Count(1)), 101);
+        assert_eq!(agreed_head(&reported, HeadAgreementQuorum::Count(2)), 100);
+        // Clamped to the amount of responding RPCs
+        assert_eq!(agreed_head(&reported, HeadAgreementQuorum::Count(10)), 100);
+        // 0.5 of 3 responding RPCs is 2
+        assert_eq!(
+            agreed_head(&reported, HeadAgreementQuorum::Fraction(0.5)),
+            100
+        );
+        assert_eq!(
+            agreed_head(&reported, HeadAgreementQuorum::Fraction(0.3)),
+            101
+        );
+        assert_eq!(
+            agreed_head(&heads(&[0, 0]), HeadAgreementQuorum::Count(1)),
+            0
+        );
+    }
+
+    #[test]
+    fn test_poverty_quorum() {
+        // A single RPC one block ahead shouldn't push the rest out of the pool
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default(); 4]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+
+        let agreed = make_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads(&[101, 100, 100, 0]),
+            PovertyRules {
+                quorum: HeadAgreementQuorum::Count(2),
+                ..Default::default()
+            },
+            &HealthTrigger::default(),
+        )
+        .unwrap();
+
+        // Only the unresponsive RPC gets removed
+        assert_eq!(agreed, 100);
+        assert_eq!(rpc_list.read().unwrap().len(), 3);
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_poverty_tolerance() {
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default(); 3]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+
+        make_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads(&[100, 99, 97]),
+            PovertyRules {
+                tolerance: 1,
+                ..Default::default()
+            },
+            &HealthTrigger::default(),
+        )
+        .unwrap();
+
+        // The RPC 1 block behind stays, the one 3 blocks behind doesn't
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_poverty_tolerance_inclusive() {
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default(); 3]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+
+        make_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads(&[100, 98, 97]),
+            PovertyRules {
+                tolerance: 2,
+                ..Default::default()
+            },
+            &HealthTrigger::default(),
+        )
+        .unwrap();
+
+        // Exactly `tolerance` blocks behind is still in the pool
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_poverty_consecutive_failures() {
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default(); 2]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+        let rules = PovertyRules {
+            max_consecutive_failures: 3,
+            ..Default::default()
+        };
+        let trigger = HealthTrigger::default();
+
+        // The second RPC times out twice, then recovers
+        for reported in [[100, 0], [101, 0], [102, 102]] {
+            make_poverty(&rpc_list, &poverty_list, heads(&reported), rules, &trigger).unwrap();
+        }
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+        assert_eq!(rpc_list.read().unwrap()[1].status.consecutive_failures, 0);
+
+        // Only removed after failing 3 times in a row
+        for _ in 0..2 {
+            make_poverty(&rpc_list, &poverty_list, heads(&[103, 0]), rules, &trigger).unwrap();
+        }
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+        make_poverty(&rpc_list, &poverty_list, heads(&[104, 0]), rules, &trigger).unwrap();
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_poverty_no_consensus() {
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default(); 3]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+        let trigger = HealthTrigger::default();
+
+        // Nobody responds, so there's no head to agree on
+        let agreed = make_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads(&[0, 0, 0]),
+            PovertyRules::default(),
+            &trigger,
+        )
+        .unwrap();
+        assert_eq!(agreed, 0);
+        assert!(trigger.no_consensus());
+
+        // Everyone stays active so they can recover
+        assert_eq!(rpc_list.read().unwrap().len(), 3);
+        assert!(poverty_list.read().unwrap().is_empty());
+
+        make_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads(&[100, 100, 0]),
+            PovertyRules::default(),
+            &trigger,
+        )
+        .unwrap();
+        assert!(!trigger.no_consensus());
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+    }
+
     #[test]
     fn test_escape() {
         // Create a mock RPC list and poverty list
@@ -365,15 +1885,25 @@ mod tests {
             HeadResult {
                 rpc_list_index: 0,
                 reported_head: 18177557,
+                syncing: false,
+                ..Default::default()
             },
             HeadResult {
                 rpc_list_index: 1,
                 reported_head: 18193012,
+                syncing: false,
+                ..Default::default()
             },
         ];
 
         // Call the escape_poverty function
-        let result = escape_poverty(&rpc_list, &poverty_list, heads, 18193012);
+        let result = escape_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads,
+            18193012,
+            PovertyRules::default(),
+        );
         assert!(result.is_ok());
 
         // Check the state of RPCs after the test
@@ -385,4 +1915,221 @@ mod tests {
         // The poverty list should have 1 RPC
         assert_eq!(poverty_list_guard.len(), 1);
     }
+
+    #[test]
+    fn test_escape_tolerance() {
+        let mut behind = Rpc::default();
+        behind.status.is_erroring = true;
+        let mut far_behind = Rpc::default();
+        far_behind.status.is_erroring = true;
+
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default()]));
+        let poverty_list = Arc::new(RwLock::new(vec![behind, far_behind]));
+
+        // Within 2 blocks of the agreed head is close enough to get back in
+        let rules = PovertyRules {
+            tolerance: 2,
+            ..Default::default()
+        };
+        escape_poverty(&rpc_list, &poverty_list, heads(&[99, 97]), 100, rules).unwrap();
+
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_escape_with_empty_rpc_list() {
+        let mut dead = Rpc::default();
+        dead.status.is_erroring = true;
+        let mut recovered = Rpc::default();
+        recovered.status.is_erroring = true;
+
+        let rpc_list = Arc::new(RwLock::new(Vec::new()));
+        let poverty_list = Arc::new(RwLock::new(vec![dead, recovered]));
+
+        // Nothing is active to agree on a head, so only RPCs that respond get back in
+        escape_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads(&[0, 100]),
+            0,
+            PovertyRules::default(),
+        )
+        .unwrap();
+
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_escape_backoff() {
+        let mut failing = Rpc::default();
+        failing.status.is_erroring = true;
+
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default()]));
+        let poverty_list = Arc::new(RwLock::new(vec![failing]));
+        let rules = PovertyRules {
+            backoff_base: Duration::from_millis(100),
+            backoff_max: Duration::from_millis(250),
+            ..Default::default()
+        };
+
+        // Doubles every time it fails to get out, up to the max
+        for expected in [100, 200, 250, 250] {
+            escape_poverty(&rpc_list, &poverty_list, heads(&[90]), 100, rules).unwrap();
+            let status = poverty_list.read().unwrap()[0].status.clone();
+            assert_eq!(status.retry_backoff, Duration::from_millis(expected));
+            assert!(skip_check(&poverty_list.read().unwrap()[0]));
+        }
+
+        // Backoff is cleared once it's back in the active pool
+        escape_poverty(&rpc_list, &poverty_list, heads(&[100]), 100, rules).unwrap();
+        let recovered = rpc_list.read().unwrap()[1].clone();
+        assert_eq!(recovered.status.retry_backoff, Duration::ZERO);
+        assert!(!skip_check(&recovered));
+    }
+
+    #[tokio::test]
+    async fn test_head_check_skips_backoff() {
+        let (waiting_mock, mut waiting) = mock_rpc(10).await;
+        waiting.status.next_retry_at = Some(Instant::now() + Duration::from_secs(60));
+        let (due_mock, mut due) = mock_rpc(10).await;
+        due.status.next_retry_at = Some(Instant::now());
+        let poverty_list = Arc::new(RwLock::new(vec![waiting, due]));
+
+        let heads = head_check(&poverty_list, 1000, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(heads.len(), 1);
+        assert_eq!(heads[0].rpc_list_index, 1);
+        assert_eq!(waiting_mock.requests(), 0);
+        // `eth_blockNumber` and `eth_syncing`
+        assert_eq!(due_mock.requests(), 2);
+    }
+
+    #[test]
+    fn test_record_head() {
+        let mut rpc = Rpc::default();
+        let latency = Duration::from_millis(5);
+
+        record_head(&mut rpc, 98, 100, latency);
+        assert_eq!(rpc.status.last_head, 98);
+        assert_eq!(rpc.status.head_lag, 2);
+        assert_eq!(rpc.status.last_latency_ms, 5.0);
+        assert!(rpc.status.last_healthy_check.is_some());
+
+        // Failures keep the last head we got and count up until a success
+        record_head(&mut rpc, 0, 101, latency);
+        record_head(&mut rpc, 0, 102, latency);
+        assert_eq!(rpc.status.last_head, 98);
+        assert_eq!(rpc.status.consecutive_failures, 2);
+
+        record_head(&mut rpc, 103, 103, latency);
+        assert_eq!(rpc.status.consecutive_failures, 0);
+        assert_eq!(rpc.status.head_lag, 0);
+
+        // Failed checks and checks without an agreed head don't count towards the max lag
+        record_head(&mut rpc, 90, 0, latency);
+        assert_eq!(rpc.status.recent_lags, [2, 0]);
+        assert_eq!(rpc.max_observed_lag(), 2);
+
+        // Lags older than the window get forgotten
+        for _ in 0..LAG_WINDOW {
+            record_head(&mut rpc, 110, 111, latency);
+        }
+        assert_eq!(rpc.status.recent_lags.len(), LAG_WINDOW);
+        assert_eq!(rpc.max_observed_lag(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_probe_block_hash() {
+        let block = |hash: Value| move |request: Value| json!({"jsonrpc": "2.0", "id": request["id"], "result": hash.clone()});
+        let good = MockRpc::spawn(Duration::ZERO, |request| {
+            assert_eq!(request["params"], json!(["0x64", false]));
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": {"hash": "0xABCD"}})
+        })
+        .await;
+        let forked = MockRpc::spawn(Duration::ZERO, block(json!({"hash": "0xbeef"}))).await;
+        let pruned = MockRpc::spawn(Duration::ZERO, block(Value::Null)).await;
+
+        let rpc = |mock: &MockRpc| Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let rpc_list = Arc::new(RwLock::new(vec![rpc(&good), rpc(&forked)]));
+        let poverty_list = Arc::new(RwLock::new(vec![rpc(&pruned)]));
+
+        // Nodes without the block aren't reported
+        let hashes = block_hash_check(&rpc_list, &poverty_list, 100, 1000).await;
+        assert_eq!(
+            hashes,
+            vec![
+                (good.url.clone(), "0xabcd".to_string()),
+                (forked.url.clone(), "0xbeef".to_string()),
+            ]
+        );
+
+        // Hashes are compared case-insensitively
+        ban_wrong_block_hash(&rpc_list, &poverty_list, hashes, 100, "0xAbCd");
+
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(rpc_list.len(), 1);
+        assert_eq!(rpc_list[0].url, good.url);
+
+        let poverty_list = poverty_list.read().unwrap();
+        assert_eq!(poverty_list.len(), 2);
+        assert!(!poverty_list[0].status.banned);
+        assert_eq!(poverty_list[1].url, forked.url);
+        assert!(poverty_list[1].status.banned);
+    }
+
+    #[test]
+    fn test_jittered() {
+        assert_eq!(jittered(1000, 0), Duration::from_millis(1000));
+
+        for _ in 0..100 {
+            let ttl = jittered(1000, 200);
+            assert!(
+                ttl >= Duration::from_millis(800) && ttl <= Duration::from_millis(1200),
+                "{:?}",
+                ttl
+            );
+        }
+
+        // Never goes below 0
+        for _ in 0..100 {
+            assert!(jittered(10, 200) <= Duration::from_millis(210));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_head_check_stagger() {
+        let (_mock, rpc) = mock_rpc(100).await;
+        let rpc_list = Arc::new(RwLock::new(vec![rpc.clone(), rpc.clone(), rpc]));
+
+        let heads = head_check(&rpc_list, 1000, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(heads.len(), 3);
+        assert!(heads.iter().all(|head| head.reported_head == 100));
+    }
+    #[tokio::test]
+    async fn test_ws_drop_keeps_serving_http() {
+        let (_mock, rpc) = mock_rpc(10).await;
+        let rpc_list = Arc::new(RwLock::new(vec![rpc]));
+        let (incoming_tx, _incoming_rx) = mpsc::channel(16);
+        let (_outgoing_tx, outgoing_rx) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+
+        handle_ws_drop(&rpc_list, &incoming_tx, outgoing_rx, &sub_data, 0)
+            .await
+            .unwrap();
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert!(rpc_list.read().unwrap()[0].status.ws_erroring);
+        assert!(!rpc_list.read().unwrap()[0].status.is_erroring);
+
+        // Only RPCs with a WS endpoint get reprobed
+        assert!(!has_ws_erroring(&rpc_list));
+        rpc_list.write().unwrap()[0].ws_url = Some("ws://127.0.0.1:1".to_string());
+        assert!(has_ws_erroring(&rpc_list));
+        rpc_list.write().unwrap()[0].status.ws_erroring = false;
+        assert!(!has_ws_erroring(&rpc_list));
+    }
 }
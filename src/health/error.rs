@@ -12,6 +12,7 @@ pub enum HealthError {
     //InvalidHexFormat,
     OutOfBounds,
     InvalidResponse(String),
+    StartupCheckFailed(String),
 }
 
 impl std::fmt::Display for HealthError {
@@ -29,6 +30,9 @@ impl std::fmt::Display for HealthError {
                 )
             }
             HealthError::InvalidResponse(reason) => write!(f, "Invalid RPC response: {}", reason),
+            HealthError::StartupCheckFailed(rpcs) => {
+                write!(f, "Startup check failed for: {}", rpcs)
+            }
         }
     }
 }
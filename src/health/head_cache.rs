@@ -1,23 +1,54 @@
+use crate::balancer::{
+    memory_cache::MemoryCache,
+    processing::{
+        method_index_key,
+        CacheWriteState,
+    },
+};
+
+use tracing::{
+    error,
+    info,
+    warn,
+};
+
 use std::{
-    collections::BTreeMap,
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
     sync::{
         Arc,
         RwLock,
     },
 };
 
-use sled::Batch;
+use sled::{
+    Batch,
+    IVec,
+};
 use tokio_stream::{
     wrappers::WatchStream,
     StreamExt,
 };
 
+// Responses we cached for each unfinalized block, by key, along with the
+// method of their request.
+//
+// These stay in memory until their block finalizes and only then get written
+// to sled, so reorged responses never make it to the durable cache. Responses
+// for blocks that haven't finalized by the time we shut down are lost.
+pub type HeadCache = RwLock<BTreeMap<u64, HashMap<Vec<u8>, (String, IVec)>>>;
+
 // Check if we need to do a reorg or if a new block has finalized.
 pub async fn manage_cache(
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    head_cache: &Arc<HeadCache>,
     blocknum_rx: tokio::sync::watch::Receiver<u64>,
     finalized_rx: Arc<tokio::sync::watch::Receiver<u64>>,
-    cache: &Arc<sled::Db>,
+    cache: &Arc<sled::Tree>,
+    cache_state: &Arc<CacheWriteState>,
+    memory_cache: &Arc<MemoryCache>,
+    max_blocks: u64,
 ) -> Result<(), sled::Error> {
     let mut block_number = 0;
     let mut last_finalized = 0;
@@ -32,18 +63,28 @@ pub async fn manage_cache(
         // that means that the chain has experienced a reorg and that we should
         // remove everything from the last block to the `new_block`
         if new_block <= block_number {
-            println!("\x1b[93mWrn:\x1b[0m Reorg detected!\nRemoving stale entries from the cache.");
-            handle_reorg(head_cache, block_number, new_block, cache)?;
+            warn!("Reorg detected!\nRemoving stale entries from the cache.");
+            if let Err(err) = handle_reorg(head_cache, new_block, block_number, cache) {
+                error!("Could not remove reorged entries from the cache: {}", err);
+            }
+            // We don't know which entries in memory are for reorged blocks, so drop them all
+            memory_cache.clear();
         }
 
-        // Check if finalized_stream has changed
+        // Check if finalized_stream has changed. Reorged entries are gone by now,
+        // so only canonical ones get promoted.
         if last_finalized != *finalized_rx.borrow() {
             last_finalized = *finalized_rx.borrow();
-            println!(
-                "\x1b[35mInfo:\x1b[0m New finalized block!\nRemoving stale entries from the cache."
+            info!("New finalized block!\nMoving finalized entries to the cache.");
+            promote_finalized(head_cache, last_finalized, cache, cache_state);
+        }
+
+        let evicted = evict_old_blocks(head_cache, new_block, max_blocks);
+        if evicted != 0 {
+            warn!(
+                "Dropped unfinalized entries for {} blocks more than {} blocks below the head.",
+                evicted, max_blocks
             );
-            // Remove stale entries from the head_cache
-            remove_stale(head_cache, last_finalized)?;
         }
 
         block_number = new_block;
@@ -54,24 +95,26 @@ pub async fn manage_cache(
 // We use the head_cache to store keys of querries we made near the tip
 // If a reorg happens, we need to remove all querries in the reorg range
 // from the sled database.
-fn handle_reorg(
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+pub fn handle_reorg(
+    head_cache: &Arc<HeadCache>,
     block_number: u64,
     new_block: u64,
-    cache: &Arc<sled::Db>,
+    cache: &Arc<sled::Tree>,
 ) -> Result<(), sled::Error> {
     // sled batch
     let mut batch = Batch::default();
 
     // Go over the head cache and get all the keys from block_number to new_block
     let mut head_cache_guard = head_cache.write().unwrap();
-    for i in block_number..new_block + 1 {
-        if let Some(keys) = head_cache_guard.get(&i) {
-            for key in keys {
-                batch.remove(key.as_bytes());
+    let reorged: Vec<u64> = head_cache_guard
+        .range(block_number..=new_block)
+        .map(|(num, _)| *num)
+        .collect();
+    for i in reorged {
+        if let Some(entries) = head_cache_guard.remove(&i) {
+            for key in entries.into_keys() {
+                batch.remove(key);
             }
-            // Remove the entry from the head_cache
-            head_cache_guard.remove(&i);
         }
     }
 
@@ -81,65 +124,218 @@ fn handle_reorg(
     Ok(())
 }
 
-// Removes stale entries from `head_cache`
+// How many of the latest heads we remember the hashes of
+const HEAD_HASHES_DEPTH: u64 = 256;
+
+// Hashes of the latest heads, by block number.
+//
+// Reorgs don't always make the head go back, the new chain can be just as long
+// or longer. We catch those by checking that every new head builds on the one
+// we saw before it.
+#[derive(Debug, Default)]
+pub struct HeadHashes {
+    hashes: BTreeMap<u64, String>,
+}
+
+impl HeadHashes {
+    // Record a new head, returning the first block number that reorged if it
+    // doesn't build on the heads we've seen.
+    //
+    // We can't tell how deep a reorg goes without asking for the new ancestors,
+    // so a head whose parent doesn't match is assumed to replace its parent.
+    pub fn insert(&mut self, number: u64, hash: &str, parent_hash: &str) -> Option<u64> {
+        let parent = number.checked_sub(1).and_then(|parent| {
+            self.hashes
+                .get(&parent)
+                .map(|stored| (parent, stored.as_str()))
+        });
+
+        let reorged_from = match parent {
+            Some((parent, stored)) if stored != parent_hash => Some(parent),
+            _ => {
+                match self.hashes.get(&number) {
+                    Some(stored) if stored != hash => Some(number),
+                    // Heads above this one got dropped from the chain
+                    _ if self.hashes.range(number + 1..).next().is_some() => Some(number + 1),
+                    _ => None,
+                }
+            }
+        };
+
+        if let Some(from) = reorged_from {
+            self.hashes.split_off(&from);
+        }
+        self.hashes.insert(number, hash.to_string());
+        self.hashes = self
+            .hashes
+            .split_off(&number.saturating_sub(HEAD_HASHES_DEPTH));
+
+        reorged_from
+    }
+
+    // Heads between the last one we saw and `number` that we never got, like
+    // while the newHeads subscription was moving to another node.
+    pub fn missed(&self, number: u64) -> Option<(u64, u64)> {
+        let (last, _) = self.hashes.last_key_value()?;
+        (number > last + 1).then(|| (last + 1, number - 1))
+    }
+}
+
+// Moves the responses of finalized blocks from `head_cache` to sled
 //
 // Once a new block finalizes, we can be sure that certain TXs wont
 // reorg, so theyre safe to be permanantly in the cache.
-fn remove_stale(
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
-    block_number: u64,
-) -> Result<(), sled::Error> {
-    // Get the lowest block_number from the BTreeMap
+//
+// Writes go through `cache_state` like every other cache write. While the
+// cache is read-only, finalized entries are dropped instead.
+fn promote_finalized(
+    head_cache: &Arc<HeadCache>,
+    finalized: u64,
+    cache: &Arc<sled::Tree>,
+    cache_state: &CacheWriteState,
+) {
+    let mut batch = Batch::default();
+    let mut keys = Vec::new();
+
+    // Hold the lock until the batch is applied so lookups don't miss in between
     let mut head_cache_guard = head_cache.write().unwrap();
+    let unfinalized = head_cache_guard.split_off(&finalized.saturating_add(1));
+    let finalized = std::mem::replace(&mut *head_cache_guard, unfinalized);
 
-    let oldest = match head_cache_guard.iter().next() {
-        Some((oldest, _)) => *oldest,
-        None => return Ok(()), // Return early if the map is empty
-    };
+    if !cache_state.can_write() {
+        return;
+    }
 
-    // Remove all entries from the head_cache up to block_number
-    for i in oldest..=block_number + 1 {
-        head_cache_guard.remove(&i);
+    for (_, entries) in finalized {
+        for (key, (method, response)) in entries {
+            batch.insert(method_index_key(&method, &key), &[]);
+            batch.insert(key.as_slice(), response);
+            keys.push(key);
+        }
     }
 
-    Ok(())
+    cache_state.handle_write(cache.apply_batch(batch));
+    for key in keys {
+        cache_state.recency().touch(&key);
+    }
+}
+
+// Drops the responses of blocks more than `max_blocks` below `head`.
+//
+// Blocks only stay in `head_cache` until they finalize, so this only matters
+// if finality stalls. Returns how many blocks got dropped.
+fn evict_old_blocks(head_cache: &Arc<HeadCache>, head: u64, max_blocks: u64) -> usize {
+    if max_blocks == 0 {
+        return 0;
+    }
+
+    let mut head_cache_guard = head_cache.write().unwrap();
+    let kept = head_cache_guard.split_off(&head.saturating_sub(max_blocks));
+    std::mem::replace(&mut *head_cache_guard, kept).len()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::balancer::processing::{
+        cache_lookup,
+        cache_querry,
+        CacheArgs,
+    };
+    use serde_json::{
+        json,
+        Value,
+    };
     use sled::Config;
 
-    // #[tokio::test]
-    // async fn test_manage_cache() {
-    //     // Create test data and resources
-    //     let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
-    //     let (blocknum_tx, blocknum_rx) = tokio::sync::watch::channel(0);
-    //     let (finalized_tx, finalized_rx) = tokio::sync::watch::channel(0);
-    //     let cache = Arc::new(Config::new().temporary(true).open().unwrap());
+    fn hash(tx: &Value) -> blake3::Hash {
+        blake3::hash(tx.to_string().as_bytes())
+    }
+
+    fn entries(key: &str) -> HashMap<Vec<u8>, (String, IVec)> {
+        HashMap::from([(
+            key.as_bytes().to_vec(),
+            ("eth_call".to_string(), IVec::from("response")),
+        )])
+    }
+
+    // Wait for `done` to hold, so we know `manage_cache` got to a new head
+    async fn wait_for(done: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("manage_cache didn't handle the new head in time");
+    }
+
+    #[tokio::test]
+    async fn test_manage_cache() {
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+        let db = Config::new().temporary(true).open().unwrap();
+        let cache = Arc::new(sled::Tree::clone(&db));
+        let (blocknum_tx, blocknum_rx) = tokio::sync::watch::channel(12);
+        let (finalized_tx, finalized_rx) = tokio::sync::watch::channel(9);
 
-    //     // Spawn the manage_cache function in a separate thread
-    //     let manage_cache_handle = tokio::spawn(async move {
-    //         let head_cache_clone = Arc::clone(&head_cache);
-    //         let cache_clone = Arc::clone(&cache);
+        {
+            let mut head_cache_guard = head_cache.write().unwrap();
+            for num in 9..=12 {
+                head_cache_guard.insert(num, entries(&format!("key{}", num)));
+            }
+        }
+
+        let manager = {
+            let head_cache = Arc::clone(&head_cache);
+            let cache = Arc::clone(&cache);
+            tokio::spawn(async move {
+                let _ = manage_cache(
+                    &head_cache,
+                    blocknum_rx,
+                    Arc::new(finalized_rx),
+                    &cache,
+                    &Arc::new(CacheWriteState::default()),
+                    &Arc::new(MemoryCache::default()),
+                    0,
+                )
+                .await;
+            })
+        };
+
+        // Block 9 is already final
+        wait_for(|| cache.get("key9").unwrap().is_some()).await;
+        assert!(cache
+            .get(method_index_key("eth_call", b"key9"))
+            .unwrap()
+            .is_some());
 
-    //         let _ = manage_cache(&head_cache_clone, blocknum_rx, finalized_rx, &cache_clone).await;
-    //     });
+        // Blocks 11 and 12 reorg right as 12 finalizes
+        finalized_tx.send(12).unwrap();
+        blocknum_tx.send(11).unwrap();
+        wait_for(|| head_cache.read().unwrap().is_empty()).await;
 
-    //     // Simulate changes in blocknum_rx and finalized_rx
-    //     blocknum_tx.send(5).unwrap();
-    //     finalized_tx.send(2).unwrap();
+        assert_eq!(cache.get("key10").unwrap().unwrap(), "response");
+        assert!(cache
+            .get(method_index_key("eth_call", b"key10"))
+            .unwrap()
+            .is_some());
+        for key in ["key11", "key12"] {
+            assert!(cache.get(key).unwrap().is_none());
+            assert!(cache
+                .get(method_index_key("eth_call", key.as_bytes()))
+                .unwrap()
+                .is_none());
+        }
 
-    //     // Wait for the manage_cache function to complete
-    //     let result = manage_cache_handle.await;
-    //     assert!(result.is_ok());
-    // }
+        manager.abort();
+    }
 
     #[test]
     fn test_handle_reorg() {
         // Create test data and resources
         let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
-        let cache = Arc::new(Config::new().temporary(true).open().unwrap());
+        let db = Config::new().temporary(true).open().unwrap();
+        let cache = Arc::new(sled::Tree::clone(&db));
 
         let _ = cache.insert("key1", "value1");
         let _ = cache.insert("key2", "value2");
@@ -148,9 +344,9 @@ mod tests {
         // Add some data to the head_cache
         {
             let mut head_cache_guard = head_cache.write().unwrap();
-            head_cache_guard.insert(1, vec!["key1".to_string()]);
-            head_cache_guard.insert(2, vec!["key2".to_string()]);
-            head_cache_guard.insert(3, vec!["key3".to_string()]);
+            head_cache_guard.insert(1, entries("key1"));
+            head_cache_guard.insert(2, entries("key2"));
+            head_cache_guard.insert(3, entries("key3"));
         }
 
         // Call handle_reorg
@@ -173,24 +369,162 @@ mod tests {
     }
 
     #[test]
-    fn test_remove_stale() {
+    fn test_promote_finalized() {
         // Create test data and resources
         let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+        let db = Config::new().temporary(true).open().unwrap();
+        let cache = Arc::new(sled::Tree::clone(&db));
 
         // Add some data to the head_cache
         {
             let mut head_cache_guard = head_cache.write().unwrap();
-            head_cache_guard.insert(1, vec!["key1".to_string()]);
-            head_cache_guard.insert(2, vec!["key2".to_string()]);
+            head_cache_guard.insert(1, entries("key1"));
+            head_cache_guard.insert(2, entries("key2"));
         }
 
-        // Call remove_stale
-        let result = remove_stale(&head_cache, 1);
+        // Call promote_finalized
+        promote_finalized(&head_cache, 1, &cache, &CacheWriteState::default());
 
-        // Verify the result and check if the data is removed from the cache
-        assert!(result.is_ok());
+        // Only the finalized block moves to the cache
         let head_cache_guard = head_cache.read().unwrap();
         assert!(!head_cache_guard.contains_key(&1));
-        assert!(!head_cache_guard.contains_key(&2));
+        assert!(head_cache_guard.contains_key(&2));
+        assert_eq!(cache.get("key1").unwrap().unwrap(), "response");
+        assert!(cache.get("key2").unwrap().is_none());
+        assert!(cache
+            .get(method_index_key("eth_call", b"key1"))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_promote_finalized_read_only() {
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+        let db = Config::new().temporary(true).open().unwrap();
+        let cache = Arc::new(sled::Tree::clone(&db));
+        head_cache.write().unwrap().insert(1, entries("key1"));
+
+        // Out of space
+        let cache_state = CacheWriteState::default();
+        cache_state.handle_write::<()>(Err(sled::Error::Io(
+            std::io::Error::from_raw_os_error(28),
+        )));
+        assert!(!cache_state.can_write());
+
+        // Finalized entries leave the head cache without being written
+        promote_finalized(&head_cache, 1, &cache, &cache_state);
+        assert!(head_cache.read().unwrap().is_empty());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_evict_old_blocks() {
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        // Finality stalled, new heads keep coming
+        for head in 1..=1000 {
+            head_cache
+                .write()
+                .unwrap()
+                .insert(head, entries(&head.to_string()));
+            evict_old_blocks(&head_cache, head, 64);
+            assert!(head_cache.read().unwrap().len() <= 65);
+        }
+
+        let head_cache_guard = head_cache.read().unwrap();
+        assert_eq!(head_cache_guard.keys().next(), Some(&936));
+        assert_eq!(head_cache_guard.keys().last(), Some(&1000));
+        drop(head_cache_guard);
+
+        // Disabled
+        assert_eq!(evict_old_blocks(&head_cache, 10_000, 0), 0);
+        assert_eq!(head_cache.read().unwrap().len(), 65);
+    }
+
+    #[test]
+    fn test_head_hashes() {
+        let mut head_hashes = HeadHashes::default();
+        assert_eq!(head_hashes.insert(10, "0xa", "0x9"), None);
+        assert_eq!(head_hashes.insert(11, "0xb", "0xa"), None);
+        // Same head again
+        assert_eq!(head_hashes.insert(11, "0xb", "0xa"), None);
+
+        // New head on a different parent
+        assert_eq!(head_hashes.insert(12, "0xc", "0xb2"), Some(11));
+        assert_eq!(head_hashes.insert(13, "0xd", "0xc"), None);
+
+        // Sibling of the current head
+        assert_eq!(head_hashes.insert(13, "0xd2", "0xc"), Some(13));
+
+        // Going back to an earlier head drops the ones after it
+        assert_eq!(head_hashes.insert(12, "0xc", "0xb2"), Some(13));
+        assert_eq!(head_hashes.insert(13, "0xd3", "0xc"), None);
+
+        // Heads we never got
+        assert_eq!(head_hashes.missed(14), None);
+        assert_eq!(head_hashes.missed(13), None);
+        assert_eq!(head_hashes.missed(17), Some((14, 16)));
+        assert_eq!(HeadHashes::default().missed(17), None);
+
+        // Only the latest heads are kept around
+        head_hashes.insert(1000, "0xe", "0xf");
+        assert_eq!(head_hashes.hashes.len(), 1);
+    }
+
+    #[test]
+    fn test_reorg_drops_stale_entries() {
+        let cache_args = CacheArgs {
+            cache: CacheArgs::temporary_cache(),
+            ..CacheArgs::default()
+        };
+        cache_args.named_numbers.write().unwrap().latest = 12;
+
+        let mut head_hashes = HeadHashes::default();
+        head_hashes.insert(11, "0xb", "0xa");
+        head_hashes.insert(12, "0xc", "0xb");
+
+        // Cache responses for the unfinalized blocks
+        let requests: Vec<Value> = (10..=12)
+            .map(|num| {
+                json!({"method": "eth_getBlockByNumber", "params": [format!("{:#x}", num), false]})
+            })
+            .collect();
+        for tx in &requests {
+            let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+            cache_querry(&mut rx, tx.clone(), hash(tx), &cache_args);
+            assert!(cache_lookup(tx, hash(tx).as_bytes(), &cache_args)
+                .unwrap()
+                .is_some());
+        }
+
+        // Block 12 gets replaced by a block 13 building on another 12
+        let from = head_hashes.insert(13, "0xd", "0xc2").unwrap();
+        handle_reorg(&cache_args.head_cache, from, u64::MAX, &cache_args.cache).unwrap();
+        cache_args.memory_cache.clear();
+
+        for tx in &requests[..2] {
+            assert!(cache_lookup(tx, hash(tx).as_bytes(), &cache_args)
+                .unwrap()
+                .is_some());
+        }
+        assert!(
+            cache_lookup(&requests[2], hash(&requests[2]).as_bytes(), &cache_args)
+                .unwrap()
+                .is_none()
+        );
+        assert!(!cache_args.head_cache.read().unwrap().contains_key(&12));
+
+        // Only canonical responses make it to sled once their blocks finalize
+        promote_finalized(
+            &cache_args.head_cache,
+            12,
+            &cache_args.cache,
+            &cache_args.cache_state,
+        );
+        assert!(cache_args.head_cache.read().unwrap().is_empty());
+        for (tx, cached) in requests.iter().zip([true, true, false]) {
+            let key = cache_args.key(hash(tx).as_bytes());
+            assert_eq!(cache_args.cache.get(key).unwrap().is_some(), cached);
+        }
     }
 }
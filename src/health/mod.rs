@@ -1,4 +1,10 @@
 pub mod check;
 pub mod error;
 pub mod head_cache;
+pub mod persist;
+pub mod probes;
+pub mod readiness;
 pub mod safe_block;
+pub mod startup_check;
+pub mod trigger;
+pub mod webhook;
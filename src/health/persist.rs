@@ -0,0 +1,190 @@
+// Health verdicts that survive restarts.
+//
+// After health checks, we save which RPCs are in the poverty list and their
+// backoff to a sled tree. On startup, RPCs that were in poverty go straight back
+// there and wait for a health check to let them out, instead of getting traffic
+// until the first check catches them again.
+use crate::Rpc;
+
+use serde_json::{
+    json,
+    Value,
+};
+use sled::Tree;
+
+use tracing::{
+    error,
+    info,
+};
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use tokio::time::sleep;
+
+// What we remember about an RPC in the poverty list, by its URL
+type Verdicts = BTreeMap<String, Value>;
+
+fn verdicts(poverty_list: &Arc<RwLock<Vec<Rpc>>>) -> Verdicts {
+    poverty_list
+        .read()
+        .unwrap()
+        .iter()
+        .map(|rpc| {
+            (
+                rpc.url.clone(),
+                json!({
+                    "retry_backoff_ms": rpc.status.retry_backoff.as_millis() as u64,
+                }),
+            )
+        })
+        .collect()
+}
+
+fn save(tree: &Tree, verdicts: &Verdicts) -> Result<(), sled::Error> {
+    tree.clear()?;
+    for (url, verdict) in verdicts {
+        tree.insert(url.as_bytes(), verdict.to_string().as_bytes())?;
+    }
+    tree.flush()?;
+
+    Ok(())
+}
+
+// Move RPCs that were in poverty when we last ran back to the poverty list.
+//
+// Returns how many were moved. They get checked at the next poverty check.
+pub fn restore_health(
+    tree: &Tree,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> usize {
+    let mut rpc_list_guard = rpc_list.write().unwrap();
+    let mut poverty_list_guard = poverty_list.write().unwrap();
+
+    let mut restored = 0;
+    let mut i = 0;
+    while i < rpc_list_guard.len() {
+        let verdict = match tree.get(rpc_list_guard[i].url.as_bytes()) {
+            Ok(Some(verdict)) => verdict,
+            Ok(None) => {
+                i += 1;
+                continue;
+            }
+            Err(err) => {
+                error!("Could not read saved health: {}", err);
+                break;
+            }
+        };
+        let verdict: Value = serde_json::from_slice(&verdict).unwrap_or_default();
+
+        let mut rpc = rpc_list_guard.remove(i);
+        rpc.status.is_erroring = true;
+        rpc.status.retry_backoff =
+            Duration::from_millis(verdict["retry_backoff_ms"].as_u64().unwrap_or(0));
+        info!(
+            "{} was in poverty before restarting, keeping it there",
+            rpc.url
+        );
+        poverty_list_guard.push(rpc);
+        restored += 1;
+    }
+
+    restored
+}
+
+// Save the poverty list to `tree` every `interval`, if it changed
+pub async fn persist_health(tree: Tree, poverty_list: Arc<RwLock<Vec<Rpc>>>, interval: Duration) {
+    let mut saved = None;
+    loop {
+        sleep(interval).await;
+
+        let verdicts = verdicts(&poverty_list);
+        if saved.as_ref() == Some(&verdicts) {
+            continue;
+        }
+
+        let tree = tree.clone();
+        let result = tokio::task::spawn_blocking({
+            let verdicts = verdicts.clone();
+            move || save(&tree, &verdicts)
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => saved = Some(verdicts),
+            Ok(Err(err)) => error!("Could not save health: {}", err),
+            Err(err) => error!("Could not save health: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc(url: &str) -> Rpc {
+        Rpc::new(url.to_string(), None, 0, 0, 1.0)
+    }
+
+    fn urls(list: &Arc<RwLock<Vec<Rpc>>>) -> Vec<String> {
+        list.read()
+            .unwrap()
+            .iter()
+            .map(|rpc| rpc.url.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("health").unwrap();
+
+        // Last run, `b` was in poverty
+        let mut dead = rpc("http://b");
+        dead.status.retry_backoff = Duration::from_millis(400);
+        let poverty_list = Arc::new(RwLock::new(vec![dead]));
+        save(&tree, &verdicts(&poverty_list)).unwrap();
+
+        let rpc_list = Arc::new(RwLock::new(vec![
+            rpc("http://a"),
+            rpc("http://b"),
+            rpc("http://c"),
+        ]));
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+        assert_eq!(restore_health(&tree, &rpc_list, &poverty_list), 1);
+
+        assert_eq!(urls(&rpc_list), vec!["http://a", "http://c"]);
+        assert_eq!(urls(&poverty_list), vec!["http://b"]);
+        let restored = poverty_list.read().unwrap()[0].status.clone();
+        assert!(restored.is_erroring);
+        assert_eq!(restored.retry_backoff, Duration::from_millis(400));
+        // Checked at the next poverty check
+        assert_eq!(restored.next_retry_at, None);
+
+        // RPCs that recovered are forgotten on the next save
+        save(&tree, &verdicts(&Arc::new(RwLock::new(Vec::new())))).unwrap();
+        assert!(tree.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_persist_health() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("health").unwrap();
+        let poverty_list = Arc::new(RwLock::new(vec![rpc("http://a")]));
+
+        tokio::spawn(persist_health(
+            tree.clone(),
+            Arc::clone(&poverty_list),
+            Duration::from_millis(10),
+        ));
+        sleep(Duration::from_millis(100)).await;
+
+        assert!(tree.contains_key(b"http://a").unwrap());
+    }
+}
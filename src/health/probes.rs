@@ -0,0 +1,101 @@
+// Supplementary health probes.
+//
+// The head check can't tell apart a node that's isolated from the network from one
+// that's fine, as long as its head looks plausible. Probes call extra methods on
+// every RPC during the health check, like `net_peerCount`, and remove RPCs whose
+// results are out of bounds from the active pool until they're back in bounds.
+use crate::{
+    rpc::types::hex_to_decimal,
+    Rpc,
+};
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthProbe {
+    pub method: String,
+    pub params: Value,
+    // Bounds the result needs to be within, both inclusive
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+}
+
+impl HealthProbe {
+    // Probe for nodes with fewer than `min_peers` peers
+    pub fn min_peers(min_peers: u64) -> Self {
+        HealthProbe {
+            method: "net_peerCount".to_string(),
+            params: Value::Array(Vec::new()),
+            min: Some(min_peers),
+            max: None,
+        }
+    }
+
+    // Call the probe method on `rpc` and return its result as a number.
+    //
+    // Returns `None` if it didn't respond with one.
+    pub async fn run(&self, rpc: &Rpc) -> Option<u64> {
+        let response = rpc.call(&self.method, self.params.clone()).await.ok()?;
+        probe_value(&response["result"])
+    }
+
+    // Returns why `value` fails the probe, if it does
+    pub fn failure(&self, value: u64) -> Option<String> {
+        match (self.min, self.max) {
+            (Some(min), _) if value < min => {
+                Some(format!("{} is {}, below {}", self.method, value, min))
+            }
+            (_, Some(max)) if value > max => {
+                Some(format!("{} is {}, above {}", self.method, value, max))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Probe results can be hex quantities, plain numbers or bools
+fn probe_value(result: &Value) -> Option<u64> {
+    match result {
+        Value::String(hex) => hex_to_decimal(hex).ok(),
+        Value::Number(number) => number.as_u64(),
+        Value::Bool(bool) => Some(*bool as u64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_probe_value() {
+        assert_eq!(probe_value(&json!("0x19")), Some(25));
+        assert_eq!(probe_value(&json!(25)), Some(25));
+        assert_eq!(probe_value(&json!(true)), Some(1));
+        assert_eq!(probe_value(&json!("peers")), None);
+        assert_eq!(probe_value(&Value::Null), None);
+    }
+
+    #[test]
+    fn test_probe_failure() {
+        let probe = HealthProbe::min_peers(5);
+        assert_eq!(probe.failure(5), None);
+        assert_eq!(
+            probe.failure(2),
+            Some("net_peerCount is 2, below 5".to_string())
+        );
+
+        let probe = HealthProbe {
+            method: "txpool_status".to_string(),
+            params: json!([]),
+            min: None,
+            max: Some(100),
+        };
+        assert_eq!(probe.failure(0), None);
+        assert_eq!(
+            probe.failure(101),
+            Some("txpool_status is 101, above 100".to_string())
+        );
+    }
+}
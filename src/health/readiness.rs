@@ -0,0 +1,107 @@
+// Readiness tracking for the `/ready` endpoint.
+//
+// We're ready once a health check has passed. With `ready_after_warmup`,
+// we also wait for the cache warmup to finish so the first requests we
+// get routed don't hit a cold cache. Once ready, we stop being ready
+// whenever there's no healthy RPC left to route requests to.
+use crate::Rpc;
+
+use std::{
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        RwLock,
+    },
+    time::Duration,
+};
+
+use tokio::time::sleep;
+
+// How often to check if the health check passed while waiting for it
+const READINESS_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Default)]
+pub struct Readiness {
+    ready_after_warmup: bool,
+    health_checked: AtomicBool,
+    warmed_up: AtomicBool,
+}
+
+impl Readiness {
+    pub fn new(ready_after_warmup: bool) -> Self {
+        Readiness {
+            ready_after_warmup,
+            ..Default::default()
+        }
+    }
+
+    pub fn mark_health_checked(&self) {
+        self.health_checked.store(true, Ordering::Relaxed);
+    }
+
+    pub fn mark_warmed_up(&self) {
+        self.warmed_up.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_health_checked(&self) -> bool {
+        self.health_checked.load(Ordering::Relaxed)
+    }
+
+    pub fn is_ready(&self, rpc_list: &RwLock<Vec<Rpc>>) -> bool {
+        self.is_health_checked()
+            && (!self.ready_after_warmup || self.warmed_up.load(Ordering::Relaxed))
+            && !rpc_list.read().unwrap().is_empty()
+    }
+
+    // Wait until the first health check passed
+    pub async fn wait_for_health_check(&self) {
+        while !self.is_health_checked() {
+            sleep(READINESS_POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc_list() -> RwLock<Vec<Rpc>> {
+        RwLock::new(vec![Rpc::new("http://a".to_string(), None, 0, 0, 1.0)])
+    }
+
+    #[test]
+    fn test_ready_without_warmup_gating() {
+        let readiness = Readiness::new(false);
+        let rpc_list = rpc_list();
+        assert!(!readiness.is_ready(&rpc_list));
+
+        readiness.mark_health_checked();
+        assert!(readiness.is_ready(&rpc_list));
+    }
+
+    #[test]
+    fn test_ready_after_warmup() {
+        let readiness = Readiness::new(true);
+        let rpc_list = rpc_list();
+
+        readiness.mark_warmed_up();
+        assert!(!readiness.is_ready(&rpc_list));
+
+        readiness.mark_health_checked();
+        assert!(readiness.is_ready(&rpc_list));
+    }
+
+    #[test]
+    fn test_not_ready_without_rpcs() {
+        let readiness = Readiness::new(false);
+        let rpc_list = rpc_list();
+        readiness.mark_health_checked();
+        assert!(readiness.is_ready(&rpc_list));
+
+        // Every RPC got dropped from the active pool
+        rpc_list.write().unwrap().clear();
+        assert!(!readiness.is_ready(&rpc_list));
+    }
+}
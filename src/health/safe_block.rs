@@ -1,6 +1,10 @@
 use crate::{
     balancer::processing::CacheArgs,
     config::setup::WS_HEALTH_CHECK_USER_ID,
+    health::head_cache::{
+        handle_reorg,
+        HeadHashes,
+    },
     rpc::{
         error::RpcError,
         types::{
@@ -10,7 +14,10 @@ use crate::{
     },
     websocket::{
         client::execute_ws_call,
-        subscription_manager::move_subscriptions,
+        subscription_manager::{
+            move_subscriptions,
+            SubscriptionQueue,
+        },
         types::{
             IncomingResponse,
             RequestResult,
@@ -20,6 +27,12 @@ use crate::{
     },
 };
 
+use tracing::{
+    error,
+    info,
+    warn,
+};
+
 use std::sync::{
     Arc,
     RwLock,
@@ -143,9 +156,10 @@ pub async fn get_safe_block(
 // Send a message subscribing to newHeads
 async fn send_newheads_sub_message(
     user_id: u32,
-    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    incoming_tx: &mpsc::Sender<WsconnMessage>,
     outgoing_rx: &broadcast::Receiver<IncomingResponse>,
     sub_data: &Arc<SubscriptionData>,
+    sub_queue: &SubscriptionQueue,
     cache_args: &CacheArgs,
 ) {
     let mut call = format!(
@@ -160,6 +174,7 @@ async fn send_newheads_sub_message(
         incoming_tx,
         outgoing_rx.resubscribe(),
         sub_data,
+        sub_queue,
         cache_args,
     )
     .await
@@ -178,10 +193,11 @@ async fn send_newheads_sub_message(
 
 // Subscribe to eth_subscribe("newHeads") and write to NamedBlocknumbers
 pub async fn subscribe_to_new_heads(
-    incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
+    incoming_tx: mpsc::Sender<WsconnMessage>,
     outgoing_rx: broadcast::Receiver<IncomingResponse>,
     blocknum_tx: watch::Sender<u64>,
     sub_data: Arc<SubscriptionData>,
+    sub_queue: SubscriptionQueue,
     cache_args: CacheArgs,
     ttl: u64,
 ) {
@@ -196,26 +212,80 @@ pub async fn subscribe_to_new_heads(
     let user_id = WS_HEALTH_CHECK_USER_ID;
 
     // Add the user to the sink map
-    println!("\x1b[35mInfo:\x1b[0m Adding user {} to sink map", user_id);
+    info!("Adding user {} to sink map", user_id);
     let user_data = tx.clone();
     sub_data.add_user(user_id, user_data);
 
-    send_newheads_sub_message(user_id, &incoming_tx, &outgoing_rx, &sub_data, &cache_args).await;
+    send_newheads_sub_message(
+        user_id,
+        &incoming_tx,
+        &outgoing_rx,
+        &sub_data,
+        &sub_queue,
+        &cache_args,
+    )
+    .await;
 
     // New message == new head received. We can then update and process
     // everything associated with a new head block.
     let mut subscription_id: String = "".to_string();
+    let mut head_hashes = HeadHashes::default();
     loop {
         match timeout(Duration::from_millis((ttl as f64 * 1.5) as u64), rx.recv()).await {
             Ok(Some(msg)) => {
                 if let RequestResult::Subscription(sub) = msg {
-                    let mut nn_rwlock = cache_args.named_numbers.write().unwrap();
-                    let a = hex_to_decimal(sub["params"]["result"]["number"].as_str().unwrap())
-                        .unwrap();
+                    let head = &sub["params"]["result"];
+                    let a = hex_to_decimal(head["number"].as_str().unwrap()).unwrap();
                     subscription_id = sub["params"]["subscription"].as_str().unwrap().to_owned();
-                    println!("\x1b[35mInfo:\x1b[0m New chain head: {}", a);
+                    info!("New chain head: {}", a);
+
+                    // We can't tell if heads we never got reorged, so drop what we cached for them
+                    if let Some((from, to)) = head_hashes.missed(a) {
+                        warn!(
+                            "Missed heads {} to {}! Removing cached entries for them.",
+                            from, to
+                        );
+                        if let Err(err) =
+                            handle_reorg(&cache_args.head_cache, from, to, &cache_args.cache)
+                        {
+                            error!("Could not remove missed entries from the cache: {}", err);
+                        }
+                        cache_args.memory_cache.clear();
+                    }
+
+                    // Drop everything we cached for reorged blocks before anyone can be served it
+                    let reorged_from = head_hashes.insert(
+                        a,
+                        head["hash"].as_str().unwrap_or_default(),
+                        head["parentHash"].as_str().unwrap_or_default(),
+                    );
+                    if let Some(from) = reorged_from {
+                        warn!(
+                            "Head {} doesn't build on the last one! Removing cached entries from block {} on.",
+                            a, from
+                        );
+                        if let Err(err) =
+                            handle_reorg(&cache_args.head_cache, from, u64::MAX, &cache_args.cache)
+                        {
+                            error!("Could not remove reorged entries from the cache: {}", err);
+                        }
+                        cache_args.memory_cache.clear();
+                    }
+
+                    let mut nn_rwlock = cache_args.named_numbers.write().unwrap();
+
+                    // A head that isn't above the last one means the chain reorged
+                    if reorged_from.is_some() || (nn_rwlock.latest != 0 && a <= nn_rwlock.latest) {
+                        warn!(
+                            "Reorg detected at block {}! Bypassing the cache for unfinalized blocks.",
+                            a
+                        );
+                        cache_args.reorg_window.start();
+                    }
+
                     let _ = blocknum_tx.send(a);
                     nn_rwlock.latest = a;
+                    sub_data.publish_head(sub["params"]["result"].clone());
                 }
             }
             Ok(None) => {
@@ -224,12 +294,10 @@ pub async fn subscribe_to_new_heads(
             }
             Err(_) => {
                 // Handle the timeout case
-                {
-                    let mut nn_rwlock = cache_args.named_numbers.write().unwrap();
-                    nn_rwlock.latest = 0;
-                    incoming_tx.send(WsconnMessage::Reconnect()).unwrap();
-                }
-                println!("Timeout in newHeads subscription");
+                cache_args.named_numbers.write().unwrap().latest = 0;
+                sub_data.clear_local_heads();
+                incoming_tx.send(WsconnMessage::Reconnect()).await.unwrap();
+                warn!("Timeout in newHeads subscription");
                 let node_id = sub_data.get_node_from_id(&subscription_id).unwrap();
                 match move_subscriptions(
                     &incoming_tx,
@@ -240,7 +308,7 @@ pub async fn subscribe_to_new_heads(
                 .await
                 {
                     Ok(_) => {}
-                    Err(err) => println!("{}", err),
+                    Err(err) => error!("{}", err),
                 };
             }
         }
@@ -0,0 +1,245 @@
+// Startup self-test.
+//
+// With `startup_check` enabled, every RPC gets asked for its chain ID and head
+// before we start taking requests, and RPCs with a WS endpoint get connected to.
+// RPCs have to be on `expected_chain_id`, or the chain most RPCs report if it's
+// not set, like in the health check. RPCs that fail get
+// moved to the poverty list, where the health check can bring them back, or
+// abort startup if `startup_check_strict` is set.
+use crate::{
+    health::{
+        check::majority_chain_id,
+        error::HealthError,
+    },
+    Rpc,
+};
+
+use futures::future::join_all;
+use tokio::time::timeout;
+use tokio_tungstenite::connect_async;
+use tracing::{
+    error,
+    info,
+    warn,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Probe {
+    chain_id: u64,
+    block_number: u64,
+}
+
+// Check that `rpc` answers over HTTP, and WS if it has an endpoint
+async fn probe(rpc: &Rpc, ttl: Duration) -> Result<Probe, String> {
+    let chain_id = timeout(ttl, rpc.get_chain_id())
+        .await
+        .map_err(|_| "eth_chainId timed out".to_string())?
+        .map_err(|err| format!("eth_chainId failed: {}", err))?;
+    let block_number = timeout(ttl, rpc.block_number())
+        .await
+        .map_err(|_| "eth_blockNumber timed out".to_string())?
+        .map_err(|err| format!("eth_blockNumber failed: {}", err))?;
+
+    if let Some(ws_url) = &rpc.ws_url {
+        let (mut ws_stream, _) = timeout(ttl, connect_async(ws_url))
+            .await
+            .map_err(|_| "WS connection timed out".to_string())?
+            .map_err(|err| format!("WS connection failed: {}", err))?;
+        let _ = ws_stream.close(None).await;
+    }
+
+    Ok(Probe {
+        chain_id,
+        block_number,
+    })
+}
+
+// Probe every RPC in `rpc_list`, each getting `ttl` per request.
+//
+// Returns an error if any RPC failed and `strict` is set. Otherwise, failing
+// RPCs are moved to `poverty_list`.
+pub async fn startup_check(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    expected_chain_id: Option<u64>,
+    strict: bool,
+    ttl: Duration,
+) -> Result<(), HealthError> {
+    let rpcs = rpc_list.read().unwrap().clone();
+    info!("Running startup check on {} RPCs...", rpcs.len());
+
+    let probes = join_all(rpcs.iter().map(|rpc| probe(rpc, ttl))).await;
+    let chain_id = expected_chain_id.or_else(|| {
+        let chain_ids: Vec<(String, u64)> = rpcs
+            .iter()
+            .zip(&probes)
+            .filter_map(|(rpc, probe)| Some((rpc.url.clone(), probe.as_ref().ok()?.chain_id)))
+            .collect();
+        majority_chain_id(&chain_ids)
+    });
+
+    let mut failed = Vec::new();
+    for (rpc, probe) in rpcs.iter().zip(probes) {
+        let result = probe.and_then(|probe| {
+            // Without a clear majority there's no telling who's on the wrong chain
+            if let Some(chain_id) = chain_id.filter(|chain_id| *chain_id != probe.chain_id) {
+                return Err(format!(
+                    "on chain {}, expected chain {}",
+                    probe.chain_id, chain_id
+                ));
+            }
+            Ok(probe)
+        });
+
+        match result {
+            Ok(probe) => {
+                info!(
+                    rpc = rpc.url,
+                    chain_id = probe.chain_id,
+                    block_number = probe.block_number,
+                    "Startup check passed"
+                );
+            }
+            Err(reason) => {
+                if strict {
+                    error!(rpc = rpc.url, reason, "Startup check failed");
+                } else {
+                    warn!(
+                        rpc = rpc.url,
+                        reason, "Startup check failed, moving RPC to the poverty list"
+                    );
+                }
+                failed.push(rpc.url.clone());
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    if strict {
+        return Err(HealthError::StartupCheckFailed(failed.join(", ")));
+    }
+
+    let mut rpc_list = rpc_list.write().unwrap();
+    let mut poverty_list = poverty_list.write().unwrap();
+    let (failing, passing) = rpc_list
+        .drain(..)
+        .partition(|rpc| failed.contains(&rpc.url));
+    *rpc_list = passing;
+    poverty_list.extend::<Vec<Rpc>>(failing);
+
+    if rpc_list.is_empty() {
+        warn!("No RPC passed the startup check!");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::MockRpc;
+    use serde_json::json;
+
+    async fn mock_chain(chain_id: &'static str) -> MockRpc {
+        MockRpc::spawn(Duration::ZERO, move |request| {
+            let result = match request["method"].as_str() {
+                Some("eth_chainId") => chain_id,
+                _ => "0x10",
+            };
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+        })
+        .await
+    }
+
+    fn list(urls: &[&str]) -> Arc<RwLock<Vec<Rpc>>> {
+        Arc::new(RwLock::new(
+            urls.iter()
+                .map(|url| Rpc::new(url.to_string(), None, 0, 0, 1.0))
+                .collect(),
+        ))
+    }
+
+    fn urls(list: &Arc<RwLock<Vec<Rpc>>>) -> Vec<String> {
+        list.read()
+            .unwrap()
+            .iter()
+            .map(|rpc| rpc.url.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_startup_check_demotes() {
+        let mainnet = mock_chain("0x1").await;
+        let mainnet2 = mock_chain("0x1").await;
+        let testnet = mock_chain("0x5").await;
+        let unreachable = "http://127.0.0.1:1".to_string();
+
+        let rpc_list = list(&[&unreachable, &mainnet.url, &testnet.url, &mainnet2.url]);
+        let poverty_list = list(&[]);
+
+        startup_check(
+            &rpc_list,
+            &poverty_list,
+            None,
+            false,
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(urls(&rpc_list), vec![mainnet.url.clone(), mainnet2.url]);
+        assert_eq!(urls(&poverty_list), vec![unreachable, testnet.url.clone()]);
+
+        // `expected_chain_id` wins over the majority
+        let rpc_list = list(&[&mainnet.url, &testnet.url]);
+        let poverty_list = list(&[]);
+        startup_check(
+            &rpc_list,
+            &poverty_list,
+            Some(5),
+            false,
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(urls(&rpc_list), vec![testnet.url]);
+        assert_eq!(urls(&poverty_list), vec![mainnet.url]);
+    }
+
+    #[tokio::test]
+    async fn test_startup_check_strict() {
+        let mainnet = mock_chain("0x1").await;
+        let rpc_list = list(&[&mainnet.url, "http://127.0.0.1:1"]);
+        let poverty_list = list(&[]);
+
+        let result =
+            startup_check(&rpc_list, &poverty_list, None, true, Duration::from_secs(1)).await;
+
+        assert!(matches!(
+            result,
+            Err(HealthError::StartupCheckFailed(failed)) if failed == "http://127.0.0.1:1"
+        ));
+        // Nothing gets moved around when we're about to exit
+        assert_eq!(urls(&rpc_list).len(), 2);
+        assert!(urls(&poverty_list).is_empty());
+
+        let rpc_list = list(&[&mainnet.url]);
+        assert!(
+            startup_check(&rpc_list, &poverty_list, None, true, Duration::from_secs(1))
+                .await
+                .is_ok()
+        );
+    }
+}
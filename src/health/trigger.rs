@@ -0,0 +1,150 @@
+// Health checks on demand.
+//
+// The health check loop waits on a `HealthTrigger` between checks instead of
+// just sleeping, so `blutgang_runHealthCheck` can wake it up and wait for the
+// check it started to finish. The loop also uses it to tell the admin namespace
+// about problems with the whole cluster, like no RPC reporting a valid head.
+use std::{
+    sync::atomic::{
+        AtomicBool,
+        AtomicU64,
+        Ordering,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{
+        watch,
+        Notify,
+    },
+    time::sleep,
+};
+
+#[derive(Debug)]
+pub struct HealthTrigger {
+    // Wakes the health check loop up before its next scheduled check
+    notify: Notify,
+    // Health checks the loop started and finished so far
+    started: AtomicU64,
+    finished: watch::Sender<u64>,
+    // Set while a health check loop is attached
+    running: AtomicBool,
+    // Set while none of the active RPCs report a valid head
+    no_consensus: AtomicBool,
+}
+
+impl Default for HealthTrigger {
+    fn default() -> Self {
+        HealthTrigger {
+            notify: Notify::new(),
+            started: AtomicU64::new(0),
+            finished: watch::channel(0).0,
+            running: AtomicBool::new(false),
+            no_consensus: AtomicBool::new(false),
+        }
+    }
+}
+
+// Marks the loop as stopped when dropped, so nobody waits on it forever
+pub struct Attached<'a>(&'a HealthTrigger);
+
+impl Drop for Attached<'_> {
+    fn drop(&mut self) {
+        self.0.running.store(false, Ordering::SeqCst);
+        self.0.finished.send_replace(u64::MAX);
+    }
+}
+
+impl HealthTrigger {
+    // Called by the health check loop for as long as it runs
+    pub fn attach(&self) -> Attached<'_> {
+        self.running.store(true, Ordering::SeqCst);
+        Attached(self)
+    }
+
+    // Wait for `period`, or until someone asks for a health check
+    pub async fn wait(&self, period: Duration) {
+        tokio::select! {
+            _ = sleep(period) => {},
+            _ = self.notify.notified() => {},
+        }
+    }
+
+    pub fn start(&self) {
+        self.started.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn finish(&self) {
+        self.finished
+            .send_replace(self.started.load(Ordering::SeqCst));
+    }
+
+    // Returns whether we were without consensus before
+    pub fn set_no_consensus(&self, no_consensus: bool) -> bool {
+        self.no_consensus.swap(no_consensus, Ordering::SeqCst)
+    }
+
+    pub fn no_consensus(&self) -> bool {
+        self.no_consensus.load(Ordering::SeqCst)
+    }
+
+    // Run a health check now and wait for it to finish.
+    //
+    // If a check is already running, waits for the one after it. Returns false
+    // if there is no health check loop to run it.
+    pub async fn run(&self) -> bool {
+        if !self.running.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        let mut finished = self.finished.subscribe();
+        let target = self.started.load(Ordering::SeqCst) + 1;
+        self.notify.notify_one();
+        let _ = finished.wait_for(|&finished| finished >= target).await;
+
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_run_health_check() {
+        let trigger = Arc::new(HealthTrigger::default());
+        assert!(!trigger.run().await);
+
+        // A loop that would only check once an hour
+        let checks = Arc::new(AtomicU64::new(0));
+        let handle = tokio::spawn({
+            let trigger = Arc::clone(&trigger);
+            let checks = Arc::clone(&checks);
+            async move {
+                let _attached = trigger.attach();
+                loop {
+                    trigger.wait(Duration::from_secs(3600)).await;
+                    trigger.start();
+                    checks.fetch_add(1, Ordering::SeqCst);
+                    trigger.finish();
+                }
+            }
+        });
+        sleep(Duration::from_millis(10)).await;
+
+        for expected in 1..=3 {
+            assert!(timeout(Duration::from_secs(1), trigger.run())
+                .await
+                .unwrap());
+            assert_eq!(checks.load(Ordering::SeqCst), expected);
+        }
+
+        // Nobody waits on a loop that stopped
+        handle.abort();
+        let _ = handle.await;
+        assert!(!trigger.run().await);
+    }
+}
@@ -0,0 +1,190 @@
+// Health webhook.
+//
+// POSTs an event to `health_webhook_url` whenever an RPC gets removed from or
+// added back to the active pool, or the cache goes read-only and back, so
+// operators can alert on it without polling the admin API. Events are sent in the background and never hold up the
+// health check. Failed sends are logged and not retried.
+use crate::{
+    warn_limited,
+    Rpc,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+use std::time::Duration;
+
+// How long to wait for the webhook to accept an event
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthEvent {
+    // Moved to the poverty list
+    EnteredPoverty,
+    // Back in the active pool
+    LeftPoverty,
+    // Cache writes stopped because the disk is full
+    EnteredReadOnly,
+    // Cache writes are succeeding again
+    LeftReadOnly,
+}
+
+impl HealthEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HealthEvent::EnteredPoverty => "entered_poverty",
+            HealthEvent::LeftPoverty => "left_poverty",
+            HealthEvent::EnteredReadOnly => "entered_read_only",
+            HealthEvent::LeftReadOnly => "left_read_only",
+        }
+    }
+}
+
+// Disabled unless created with a URL
+#[derive(Debug, Default)]
+pub struct HealthWebhook {
+    // Where to send events, and the client we send them with
+    target: Option<(String, reqwest::Client)>,
+}
+
+impl HealthWebhook {
+    pub fn new(url: Option<String>) -> Self {
+        HealthWebhook {
+            target: url.map(|url| (url, reqwest::Client::new())),
+        }
+    }
+
+    // Send an `event` for every RPC in `rpcs`
+    pub fn notify(&self, event: HealthEvent, rpcs: &[Rpc]) {
+        for rpc in rpcs {
+            self.send(payload(event, rpc));
+        }
+    }
+
+    // Send an `event` about the cache
+    pub fn notify_cache(&self, event: HealthEvent) {
+        self.send(json!({
+            "event": event.as_str(),
+            "timestamp": timestamp(),
+        }));
+    }
+
+    fn send(&self, payload: Value) {
+        let (url, client) = match &self.target {
+            Some(target) => target,
+            None => return,
+        };
+
+        let request = client.post(url).timeout(WEBHOOK_TIMEOUT).json(&payload);
+        let url = url.clone();
+        tokio::spawn(async move {
+            let sent = request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(err) = sent {
+                warn_limited!("Could not send health event to {}: {}", url, err);
+            }
+        });
+    }
+}
+
+fn timestamp() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+fn payload(event: HealthEvent, rpc: &Rpc) -> Value {
+    // Unresponsive RPCs didn't report a head
+    let reported_head = match rpc.status.consecutive_failures {
+        0 => rpc.status.last_head,
+        _ => 0,
+    };
+    // `head_lag` is how far `reported_head` was from the agreed head
+    let lag = (reported_head != 0).then_some(rpc.status.head_lag);
+
+    json!({
+        "event": event.as_str(),
+        "url": rpc.url,
+        "timestamp": timestamp(),
+        "reported_head": reported_head,
+        "agreed_head": reported_head + rpc.status.head_lag,
+        "lag": lag,
+        "max_observed_lag": rpc.max_observed_lag(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::MockRpc;
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+
+    #[tokio::test]
+    async fn test_health_webhook() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let received = events.clone();
+        let receiver = MockRpc::spawn(Duration::ZERO, move |event| {
+            received.lock().unwrap().push(event);
+            json!({})
+        })
+        .await;
+
+        let mut behind = Rpc::new("http://behind.example".to_string(), None, 0, 0, 1.0);
+        behind.status.last_head = 100;
+        behind.status.head_lag = 5;
+        behind.record_lag(3);
+        behind.record_lag(5);
+        let mut down = Rpc::new("http://down.example".to_string(), None, 0, 0, 1.0);
+        down.status.last_head = 100;
+        down.status.head_lag = 105;
+        down.status.consecutive_failures = 1;
+
+        // Disabled
+        HealthWebhook::default().notify(HealthEvent::EnteredPoverty, &[behind.clone()]);
+
+        let webhook = HealthWebhook::new(Some(receiver.url.clone()));
+        webhook.notify(HealthEvent::EnteredPoverty, &[behind, down]);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let mut events = events.lock().unwrap().clone();
+        events.sort_by_key(|event| event["url"].to_string());
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "entered_poverty");
+        assert_eq!(events[0]["url"], "http://behind.example");
+        assert_eq!(events[0]["reported_head"], 100);
+        assert_eq!(events[0]["agreed_head"], 105);
+        assert_eq!(events[0]["lag"], 5);
+        assert_eq!(events[0]["max_observed_lag"], 5);
+        assert!(events[0]["timestamp"].is_string());
+        assert_eq!(events[1]["url"], "http://down.example");
+        assert_eq!(events[1]["reported_head"], 0);
+        assert_eq!(events[1]["agreed_head"], 105);
+        assert!(events[1]["lag"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_cache_webhook() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let received = events.clone();
+        let receiver = MockRpc::spawn(Duration::ZERO, move |event| {
+            received.lock().unwrap().push(event);
+            json!({})
+        })
+        .await;
+
+        let webhook = HealthWebhook::new(Some(receiver.url.clone()));
+        webhook.notify_cache(HealthEvent::EnteredReadOnly);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let events = events.lock().unwrap().clone();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "entered_read_only");
+        assert!(events[0]["timestamp"].is_string());
+        assert!(events[0].get("url").is_none());
+    }
+}
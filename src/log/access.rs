@@ -0,0 +1,200 @@
+// Access log.
+//
+// Unlike the tracing logs, this records one line per request for auditing and
+// traffic analysis: when it came in, who sent it, what it asked for, where we
+// got the response from and how long it took. Lines are newline-delimited JSON.
+//
+// Requests only queue their line on a bounded channel. A dedicated thread does
+// the writing, so a slow disk never adds latency to requests. If the queue is
+// full, lines get dropped.
+use crate::warn_limited;
+
+use rand::Rng;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::sync::mpsc;
+
+use std::{
+    fs::OpenOptions,
+    io::{
+        self,
+        BufWriter,
+        Write,
+    },
+    net::IpAddr,
+    time::Duration,
+};
+
+// Select either blake3 or xxhash based on the features
+#[cfg(not(feature = "xxhash"))]
+use blake3::hash;
+#[cfg(feature = "xxhash")]
+use xxhash_rust::xxh3::xxh3_64;
+
+// Lines waiting to be written before we start dropping them
+const ACCESS_LOG_QUEUE: usize = 8192;
+
+#[derive(Debug, Default, Clone)]
+pub struct AccessEntry {
+    pub client_ip: Option<IpAddr>,
+    pub method: String,
+    pub params_hash: String,
+    pub cache: String,
+    pub rpc: Option<String>,
+    pub status: u16,
+    pub latency: Duration,
+}
+
+impl AccessEntry {
+    fn to_line(&self) -> String {
+        json!({
+            "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "client_ip": self.client_ip,
+            "method": self.method,
+            "params_hash": self.params_hash,
+            "cache": self.cache,
+            "rpc": self.rpc,
+            "status": self.status,
+            "latency_ms": self.latency.as_secs_f64() * 1000.0,
+        })
+        .to_string()
+    }
+}
+
+// Hash of the params of a request, so identical requests can be told apart
+// from different ones without logging what they contain.
+pub fn params_hash(params: &Value) -> String {
+    let params = params.to_string();
+    #[cfg(not(feature = "xxhash"))]
+    {
+        hash(params.as_bytes()).to_hex().to_string()
+    }
+    #[cfg(feature = "xxhash")]
+    {
+        format!("{:016x}", xxh3_64(params.as_bytes()))
+    }
+}
+
+// Disabled unless opened with `AccessLog::open`
+#[derive(Debug, Default)]
+pub struct AccessLog {
+    tx: Option<mpsc::Sender<String>>,
+    // Share of requests we log, between 0 and 1
+    sample_rate: f64,
+}
+
+impl AccessLog {
+    // Append lines to the file at `path`, for `sample_rate` of requests
+    pub fn open(path: &str, sample_rate: f64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (tx, rx) = mpsc::channel(ACCESS_LOG_QUEUE);
+
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_lines(BufWriter::new(file), rx))?;
+
+        Ok(AccessLog {
+            tx: Some(tx),
+            sample_rate,
+        })
+    }
+
+    // Decide if we log the current request
+    pub fn sample(&self) -> bool {
+        self.tx.is_some() && rand::thread_rng().gen_bool(self.sample_rate.clamp(0.0, 1.0))
+    }
+
+    pub fn record(&self, entry: &AccessEntry) {
+        let tx = match &self.tx {
+            Some(tx) => tx,
+            None => return,
+        };
+
+        if tx.try_send(entry.to_line()).is_err() {
+            warn_limited!("Access log can't keep up, dropping lines");
+        }
+    }
+}
+
+// Write lines until every sender is gone, flushing whenever we run out of them
+fn write_lines(mut file: impl Write, mut rx: mpsc::Receiver<String>) {
+    while let Some(line) = rx.blocking_recv() {
+        let mut line = Some(line);
+        while let Some(next) = line {
+            if let Err(err) = writeln!(file, "{}", next) {
+                warn_limited!("Could not write to the access log: {}", err);
+            }
+            line = rx.try_recv().ok();
+        }
+
+        if let Err(err) = file.flush() {
+            warn_limited!("Could not write to the access log: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params_hash() {
+        assert_eq!(
+            params_hash(&json!(["0x1", false])),
+            params_hash(&json!(["0x1", false]))
+        );
+        assert_ne!(
+            params_hash(&json!(["0x1", false])),
+            params_hash(&json!(["0x2", false]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        let path =
+            std::env::temp_dir().join(format!("blutgang-access-{}.log", rand::random::<u64>()));
+        let path = path.to_str().unwrap();
+
+        // Disabled, or nothing sampled
+        assert!(!AccessLog::default().sample());
+        assert!(!AccessLog::open(path, 0.0).unwrap().sample());
+
+        let access_log = AccessLog::open(path, 1.0).unwrap();
+        assert!(access_log.sample());
+        access_log.record(&AccessEntry {
+            client_ip: Some("10.0.0.1".parse().unwrap()),
+            method: "eth_getBalance".to_string(),
+            params_hash: params_hash(&json!(["0x1", "latest"])),
+            cache: "miss".to_string(),
+            rpc: Some("http://node.example".to_string()),
+            status: 200,
+            latency: Duration::from_millis(12),
+        });
+        access_log.record(&AccessEntry {
+            method: "eth_chainId".to_string(),
+            cache: "hit".to_string(),
+            status: 200,
+            ..Default::default()
+        });
+        drop(access_log);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let lines = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let lines: Vec<Value> = lines
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["client_ip"], "10.0.0.1");
+        assert_eq!(lines[0]["method"], "eth_getBalance");
+        assert_eq!(lines[0]["rpc"], "http://node.example");
+        assert_eq!(lines[0]["status"], 200);
+        assert_eq!(lines[0]["latency_ms"], 12.0);
+        assert!(lines[0]["timestamp"].is_string());
+        assert_eq!(lines[1]["cache"], "hit");
+        assert_eq!(lines[1]["rpc"], Value::Null);
+    }
+}
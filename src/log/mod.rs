@@ -0,0 +1,3 @@
+pub mod access;
+pub mod rate_limit;
+pub mod setup;
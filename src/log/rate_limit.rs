@@ -0,0 +1,190 @@
+// Rate limited logging.
+//
+// Some conditions, like an RPC falling behind during an outage, can print the
+// same line thousands of times per second. Lines logged through here are printed
+// once per `window`, and repeats within the window are collapsed into a count
+// that gets printed alongside the next line.
+use tracing::Level;
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        OnceLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+static LOG_LIMITER: OnceLock<LogLimiter> = OnceLock::new();
+
+// Set up the global limiter. Lines are printed as-is until this is called.
+pub fn init_log_limiter(window: Duration) {
+    let _ = LOG_LIMITER.set(LogLimiter::new(window));
+}
+
+// Log `line` at `level`, collapsing repeats of the same `key` within the window.
+pub fn log_limited(level: Level, key: &str, line: String) {
+    let line = match LOG_LIMITER.get() {
+        Some(limiter) => {
+            match limiter.check(key, line) {
+                Some(line) => line,
+                None => return,
+            }
+        }
+        None => line,
+    };
+
+    // `tracing` needs the level to be known at compile time
+    match level {
+        Level::ERROR => tracing::error!("{}", line),
+        Level::WARN => tracing::warn!("{}", line),
+        Level::INFO => tracing::info!("{}", line),
+        Level::DEBUG => tracing::debug!("{}", line),
+        Level::TRACE => tracing::trace!("{}", line),
+    }
+}
+
+// Rate limited logging at `level`. Identical lines are collapsed.
+//
+// A key can be passed with `key = ...` to collapse lines that differ,
+// e.g. connections from the same IP on different ports.
+#[macro_export]
+macro_rules! log_limited {
+    ($level:expr, key = $key:expr, $($arg:tt)*) => {
+        $crate::log::rate_limit::log_limited($level, &$key, format!($($arg)*))
+    };
+    ($level:expr, $($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        $crate::log::rate_limit::log_limited($level, &line.clone(), line)
+    }};
+}
+
+// Rate limited `info!`
+#[macro_export]
+macro_rules! info_limited {
+    ($($arg:tt)*) => {
+        $crate::log_limited!(tracing::Level::INFO, $($arg)*)
+    };
+}
+
+// Rate limited `warn!`
+#[macro_export]
+macro_rules! warn_limited {
+    ($($arg:tt)*) => {
+        $crate::log_limited!(tracing::Level::WARN, $($arg)*)
+    };
+}
+
+#[derive(Debug)]
+struct LogEvent {
+    printed_at: Instant,
+    suppressed: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct LogLimiter {
+    // 0 disables rate limiting
+    window: Duration,
+    events: Mutex<HashMap<String, LogEvent>>,
+}
+
+impl LogLimiter {
+    pub fn new(window: Duration) -> Self {
+        LogLimiter {
+            window,
+            events: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns the line to print, if any.
+    //
+    // The first line for `key` is always printed. Repeats are suppressed until
+    // the window is over, then the next one is printed with the suppressed count.
+    pub fn check(&self, key: &str, line: String) -> Option<String> {
+        if self.window.is_zero() {
+            return Some(line);
+        }
+
+        let mut events = self.events.lock().unwrap();
+
+        // Forget about events that haven't happened in a while
+        let window = self.window;
+        events.retain(|_, event| event.printed_at.elapsed() < window * 2);
+
+        match events.get_mut(key) {
+            Some(event) if event.printed_at.elapsed() < window => {
+                event.suppressed += 1;
+                None
+            }
+            Some(event) => {
+                let suppressed = event.suppressed;
+                event.printed_at = Instant::now();
+                event.suppressed = 0;
+
+                if suppressed == 0 {
+                    return Some(line);
+                }
+                Some(format!(
+                    "{} (repeated {} more times in the last {}s)",
+                    line,
+                    suppressed,
+                    window.as_secs_f64()
+                ))
+            }
+            None => {
+                events.insert(
+                    key.to_string(),
+                    LogEvent {
+                        printed_at: Instant::now(),
+                        suppressed: 0,
+                    },
+                );
+                Some(line)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_are_collapsed() {
+        let limiter = LogLimiter::new(Duration::from_millis(50));
+        let line = "http://rpc is falling behind!".to_string();
+
+        assert_eq!(limiter.check(&line, line.clone()), Some(line.clone()));
+        for _ in 0..100 {
+            assert_eq!(limiter.check(&line, line.clone()), None);
+        }
+
+        // Different lines aren't affected
+        let other = "http://other is falling behind!".to_string();
+        assert_eq!(limiter.check(&other, other.clone()), Some(other));
+
+        // After the window, the repeats are summarized
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(
+            limiter.check(&line, line.clone()),
+            Some(format!(
+                "{} (repeated 100 more times in the last 0.05s)",
+                line
+            ))
+        );
+        assert_eq!(limiter.check(&line, line.clone()), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let limiter = LogLimiter::new(Duration::ZERO);
+        let line = "Connection from: 127.0.0.1".to_string();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(&line, line.clone()), Some(line.clone()));
+        }
+    }
+}
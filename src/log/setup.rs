@@ -0,0 +1,120 @@
+// Logging setup.
+//
+// Everything goes through `tracing`. `log_level` sets the most verbose level
+// we print, and `log_format` picks between human readable lines and one JSON
+// object per line, for feeding into log aggregators.
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::fmt;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    // Human readable lines
+    #[default]
+    Text,
+    // One JSON object per line, with fields kept apart
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_str(format: &str) -> Option<Self> {
+        match format {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse_log_level(level: &str) -> Option<LevelFilter> {
+    match level {
+        "error" => Some(LevelFilter::ERROR),
+        "warn" => Some(LevelFilter::WARN),
+        "info" => Some(LevelFilter::INFO),
+        "debug" => Some(LevelFilter::DEBUG),
+        "trace" => Some(LevelFilter::TRACE),
+        _ => None,
+    }
+}
+
+// Set up the global subscriber. Only the first call does anything.
+pub fn init_logging(level: LevelFilter, format: LogFormat) {
+    let subscriber = fmt().with_max_level(level);
+    let result = match format {
+        LogFormat::Text => subscriber.try_init(),
+        LogFormat::Json => subscriber.json().try_init(),
+    };
+
+    if let Err(err) = result {
+        println!("\x1b[93mWrn:\x1b[0m Could not set up logging: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io,
+        sync::{
+            Arc,
+            Mutex,
+        },
+    };
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse_settings() {
+        assert_eq!(parse_log_level("warn"), Some(LevelFilter::WARN));
+        assert_eq!(parse_log_level("trace"), Some(LevelFilter::TRACE));
+        assert_eq!(parse_log_level("loud"), None);
+        assert_eq!(LogFormat::from_str("json"), Some(LogFormat::Json));
+        assert_eq!(LogFormat::from_str("xml"), None);
+    }
+
+    #[test]
+    fn test_json_fields() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = fmt()
+            .with_max_level(LevelFilter::INFO)
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", method = "eth_call");
+            span.in_scope(|| {
+                tracing::info!(
+                    rpc = "http://rpc",
+                    latency_ms = 12,
+                    cache = "MISS",
+                    "Request served"
+                );
+            });
+            tracing::debug!("Filtered out");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 1);
+
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "Request served");
+        assert_eq!(line["fields"]["rpc"], "http://rpc");
+        assert_eq!(line["fields"]["latency_ms"], 12);
+        assert_eq!(line["fields"]["cache"], "MISS");
+        assert_eq!(line["span"]["method"], "eth_call");
+    }
+}
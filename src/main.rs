@@ -2,22 +2,55 @@ mod admin;
 mod balancer;
 mod config;
 mod health;
+mod log;
 mod rpc;
 mod websocket;
 
 use crate::{
-    admin::listener::listen_for_admin_requests,
+    admin::{
+        chains::{
+            AdminChain,
+            AdminChains,
+        },
+        listener::listen_for_admin_requests,
+        metrics::Metrics,
+    },
     balancer::{
         accept_http::{
             accept_request,
             ConnectionParams,
             RequestChannels,
         },
-        processing::CacheArgs,
+        admission::AdmissionControl,
+        cache_eviction::evict_cache,
+        http::http1_builder,
+        listener::Listener,
+        memory_cache::MemoryCache,
+        processing::{
+            CacheArgs,
+            CacheWriteState,
+            ReorgWindow,
+            CACHE_SCHEMA_VERSION,
+        },
+        rate_limit::RateLimiter,
+        shutdown::{
+            shutdown_signal,
+            ConnectionTracker,
+            CONNECTION_SLOT_WAIT,
+        },
+        single_flight::SingleFlight,
+        sticky::StickySessions,
+        tls::tls_acceptor,
+        tx_replay::TxReplayCache,
+        warmup::warm_cache,
     },
     config::{
         cache_setup::setup_data,
         cli_args::create_match,
+        reload::{
+            reload_on_sighup,
+            ReloadChain,
+        },
         types::Settings,
     },
     health::{
@@ -25,16 +58,39 @@ use crate::{
             dropped_listener,
             health_check,
         },
-        head_cache::manage_cache,
+        head_cache::{
+            manage_cache,
+            HeadCache,
+        },
+        persist::{
+            persist_health,
+            restore_health,
+        },
+        readiness::Readiness,
         safe_block::{
             subscribe_to_new_heads,
             NamedBlocknumbers,
         },
+        startup_check::startup_check,
+        trigger::HealthTrigger,
+        webhook::HealthWebhook,
+    },
+    log::{
+        access::AccessLog,
+        rate_limit::init_log_limiter,
+        setup::init_logging,
     },
     rpc::types::Rpc,
     websocket::{
-        client::ws_conn_manager,
-        subscription_manager::subscription_dispatcher,
+        client::{
+            ws_conn_manager,
+            WsKeepalive,
+        },
+        subscription_manager::{
+            subscription_dispatcher,
+            NotificationLimits,
+            SubscriptionQueue,
+        },
         types::{
             IncomingResponse,
             SubscriptionData,
@@ -44,28 +100,31 @@ use crate::{
     },
 };
 
+use tracing::{
+    error,
+    info,
+    warn,
+};
+
 use std::{
     collections::BTreeMap,
-    println,
     sync::{
         Arc,
         RwLock,
     },
+    time::Duration,
 };
 
 use tokio::{
-    net::TcpListener,
     sync::{
         broadcast,
         mpsc,
         watch,
     },
+    time::timeout,
 };
 
-use hyper::{
-    server::conn::http1,
-    service::service_fn,
-};
+use hyper::service::service_fn;
 use hyper_util_blutgang::rt::TokioIo;
 
 // jeemalloc offers faster mallocs when dealing with lots of threads which is what we're doing
@@ -77,91 +136,508 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Get all the cli args and set them
     let config = Arc::new(RwLock::new(Settings::new(create_match()).await));
 
+    {
+        let config_guard = config.read().unwrap();
+        init_logging(config_guard.log_level, config_guard.log_format);
+    }
+
+    // Collapse repeated log lines if enabled
+    init_log_limiter(Duration::from_millis(config.read().unwrap().log_rate_limit));
+
+    // Log every request to a file if enabled
+    let access_log = {
+        let config_guard = config.read().unwrap();
+        match &config_guard.access_log {
+            Some(path) => {
+                AccessLog::open(path, config_guard.access_log_sample_rate).unwrap_or_else(|err| {
+                    panic!("\x1b[31mErr:\x1b[0m Could not open access log: {}", err)
+                })
+            }
+            None => AccessLog::default(),
+        }
+    };
+    let access_log = Arc::new(access_log);
+
     // Copy the configuration values we need
-    let (addr, do_clear, do_health_check, admin_enabled, is_ws, health_check_ttl) = {
+    let (addr, do_clear, admin_enabled) = {
         let config_guard = config.read().unwrap();
         (
             config_guard.address,
             config_guard.do_clear,
-            config_guard.health_check,
             config_guard.admin.enabled,
-            config_guard.is_ws,
-            config_guard.health_check_ttl,
         )
     };
 
-    // Make the list a rwlock
-    let rpc_list_rwlock = Arc::new(RwLock::new(config.read().unwrap().rpc_list.clone()));
-
     // Create/Open sled DB
-    let cache = Arc::new(config.read().unwrap().sled_config.open().unwrap());
+    let cache = match config.read().unwrap().sled_config.open() {
+        Ok(cache) => Arc::new(cache),
+        Err(err) => {
+            error!("Could not open the cache DB: {}", err);
+            error!("Check that the cache path is writable and not used by another blutgang instance, or delete it to start over.");
+            std::process::exit(1);
+        }
+    };
 
-    // Cache for storing querries near the tip
-    let head_cache = Arc::new(RwLock::new(BTreeMap::<u64, Vec<String>>::new()));
+    // Limits the amount of requests we forward at once
+    let admission = Arc::new(AdmissionControl::new(
+        config.read().unwrap().max_concurrent_requests,
+    ));
+
+    // Limits how many requests each client IP can make
+    let rate_limiter = {
+        let config_guard = config.read().unwrap();
+        Arc::new(RateLimiter::new(
+            config_guard.rate_limit_rps,
+            config_guard.rate_limit_burst,
+        ))
+    };
+    if rate_limiter.is_enabled() {
+        // Forget about clients once they've been idle long enough for their bucket to refill
+        let rate_limiter_gc = Arc::clone(&rate_limiter);
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                rate_limiter_gc.collect_idle();
+            }
+        });
+    }
+
+    // Tracks if we're ready to take traffic for `/ready`
+    let readiness = Arc::new(Readiness::new(config.read().unwrap().ready_after_warmup));
 
     // Clear database if specified
     if do_clear {
         cache.clear().unwrap();
-        println!("\x1b[93mWrn:\x1b[0m All data cleared from the database.");
+        warn!("All data cleared from the database.");
     }
     // Insert data about blutgang and our settings into the DB
     //
     // Print any relevant warnings about a misconfigured DB. Check docs for more
-    setup_data(Arc::clone(&cache));
+    setup_data(Arc::clone(&cache), config.read().unwrap().cache_version);
+
+    // We create a TcpListener and bind it to 127.0.0.1:3000, and to a unix socket if set
+    let unix_socket = config.read().unwrap().unix_socket.clone();
+    let listen_backlog = config.read().unwrap().listen_backlog;
+    let listener = Listener::bind(addr, unix_socket.as_deref(), listen_backlog).await?;
+    info!("Bound to: {}", addr);
+    if let Some(unix_socket) = &unix_socket {
+        info!("Bound to unix socket: {}", unix_socket);
+    }
 
-    // We create a TcpListener and bind it to 127.0.0.1:3000
-    let listener = TcpListener::bind(addr).await?;
-    println!("\x1b[35mInfo:\x1b[0m Bound to: {}", addr);
+    // Wrap connections in TLS if enabled
+    let tls_acceptor = match config.read().unwrap().tls.as_ref() {
+        Some(tls) => {
+            info!("TLS enabled, serving HTTPS");
+            Some(Arc::new(tls_acceptor(tls)?))
+        }
+        None => None,
+    };
 
-    let (blocknum_tx, blocknum_rx) = watch::channel(0);
-    let (finalized_tx, finalized_rx) = watch::channel(0);
+    // Every connection is served with the same keep-alive and buffer settings
+    let http1_builder = http1_builder(&config.read().unwrap().http);
+
+    // Request counters served at `/metrics` on the admin port
+    let metrics = Arc::new(Metrics::default());
+
+    // The default chain is served at `/` and lives in the default tree of the DB
+    let default_chain = start_chain(
+        Arc::clone(&config),
+        Arc::new(sled::Tree::clone(&cache)),
+        cache.open_tree("health")?,
+        Arc::clone(&readiness),
+        &admission,
+        &rate_limiter,
+        &metrics,
+    )
+    .await;
+
+    // Every other chain gets its own tree
+    let chain_settings = config.read().unwrap().chains.clone();
+    let mut chains = BTreeMap::new();
+    let mut chain_handles = BTreeMap::new();
+    for (name, settings) in chain_settings {
+        let tree = cache.open_tree(format!("chain/{}", name))?;
+        if do_clear {
+            tree.clear()?;
+        }
 
-    let finalized_rx_arc = Arc::new(finalized_rx.clone());
-    let rpc_poverty_list = Arc::new(RwLock::new(Vec::<Rpc>::new()));
+        info!("Serving chain {} at /{}", name, name);
+        let chain = start_chain(
+            Arc::new(RwLock::new(settings)),
+            Arc::new(tree),
+            cache.open_tree(format!("chain/{}/health", name))?,
+            Arc::new(Readiness::new(false)),
+            &admission,
+            &rate_limiter,
+            &metrics,
+        )
+        .await;
+        chains.insert(name.clone(), chain.params.clone());
+        chain_handles.insert(name, chain);
+    }
+    let chains = Arc::new(chains);
+
+    // Spawn a thread to keep the cache under `cache_max_bytes`
+    let cache_max_bytes = config.read().unwrap().cache_max_bytes;
+    if cache_max_bytes != 0 {
+        let caches = std::iter::once(&default_chain)
+            .chain(chain_handles.values())
+            .map(|chain| {
+                (
+                    Arc::clone(&chain.params.cache),
+                    Arc::clone(chain.params.cache_state.recency()),
+                )
+            })
+            .collect();
+        let cache_eviction = Arc::clone(&cache);
+        tokio::task::spawn(async move {
+            evict_cache(cache_eviction, caches, cache_max_bytes).await;
+        });
+    }
 
     // Spawn a thread for the admin namespace if enabled
     if admin_enabled {
-        let rpc_list_admin = Arc::clone(&rpc_list_rwlock);
-        let poverty_list_admin = Arc::clone(&rpc_poverty_list);
+        let mut chains_admin = AdminChains::new(default_chain.admin_chain());
+        chains_admin.chains = chain_handles
+            .iter()
+            .map(|(name, chain)| (name.clone(), chain.admin_chain()))
+            .collect();
         let cache_admin = Arc::clone(&cache);
-        let config_admin = Arc::clone(&config);
+        let metrics_admin = Arc::clone(&metrics);
+        let admission_admin = Arc::clone(&admission);
         tokio::task::spawn(async move {
-            println!("\x1b[35mInfo:\x1b[0m Admin namespace enabled, accepting admin methods at admin port");
+            info!("Admin namespace enabled, accepting admin methods at admin port");
             let _ = listen_for_admin_requests(
-                rpc_list_admin,
-                poverty_list_admin,
+                Arc::new(chains_admin),
                 cache_admin,
-                config_admin,
+                metrics_admin,
+                admission_admin,
             )
             .await;
         });
     }
 
-    // Spawn a thread for the head cache
-    let head_cache_clone = Arc::clone(&head_cache);
-    let cache_clone = Arc::clone(&cache);
-    let finalized_rxclone = Arc::clone(&finalized_rx_arc);
-    tokio::task::spawn(async move {
-        let _ = manage_cache(
-            &head_cache_clone,
-            blocknum_rx,
-            finalized_rxclone,
-            &cache_clone,
+    // Reload the config file on SIGHUP
+    #[cfg(unix)]
+    {
+        let default_reload = default_chain.reload_chain();
+        let chains_reload = chain_handles
+            .iter()
+            .map(|(name, chain)| (name.clone(), chain.reload_chain()))
+            .collect();
+        tokio::task::spawn(async move {
+            reload_on_sighup(default_reload, chains_reload).await;
+        });
+    }
+
+    // Tells connections to close once they're done with their request when shutting down
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let connections = Arc::new(ConnectionTracker::with_limit(
+        config.read().unwrap().max_concurrent_connections,
+    ));
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    // We start a loop to continuously accept incoming connections
+    loop {
+        let (stream, socketaddr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = &mut shutdown => break,
+        };
+        match socketaddr {
+            Some(socketaddr) => {
+                info_limited!(
+                    key = format!("connection {}", socketaddr.ip()),
+                    "Connection from: {}",
+                    socketaddr
+                )
+            }
+            None => {
+                info_limited!(key = "connection unix", "Connection from unix socket")
+            }
+        }
+
+        let mut connection_params = default_chain
+            .params
+            .clone()
+            .with_chains(&chains)
+            .with_access_log(&access_log);
+        if let Some(socketaddr) = socketaddr {
+            connection_params = connection_params.with_peer(socketaddr);
+        }
+
+        // Spawn a tokio task to serve multiple connections concurrently
+        let shutdown_rx = shutdown_rx.clone();
+        // At `max_concurrent_connections`, wait a bit for a connection to close
+        // and drop this one if none does
+        let connection = match connections.admit(CONNECTION_SLOT_WAIT).await {
+            Some(connection) => connection,
+            None => {
+                warn_limited!(
+                    key = "connection limit",
+                    "Too many connections, dropping a new one!"
+                );
+                continue;
+            }
+        };
+        let tls_acceptor = tls_acceptor.clone();
+        let http1_builder = http1_builder.clone();
+        tokio::task::spawn(async move {
+            let _connection = connection;
+
+            // Use an adapter to access something implementing `tokio::io` traits as if they implement
+            // `hyper::rt` IO traits.
+            match tls_acceptor {
+                Some(tls_acceptor) => {
+                    match tls_acceptor.accept(stream).await {
+                        Ok(stream) => {
+                            accept!(
+                                TokioIo::new(stream),
+                                http1_builder,
+                                connection_params.clone(),
+                                shutdown_rx
+                            );
+                        }
+                        Err(err) => {
+                            error!("TLS handshake failed: {}", err);
+                        }
+                    }
+                }
+                None => {
+                    accept!(
+                        TokioIo::new(stream),
+                        http1_builder,
+                        connection_params.clone(),
+                        shutdown_rx
+                    );
+                }
+            }
+        });
+    }
+
+    // Stop accepting connections and let the ones we have finish.
+    // This also removes the unix socket file.
+    drop(listener);
+    let shutdown_grace_ms = config.read().unwrap().shutdown_grace_ms;
+    info!(
+        "Shutting down, waiting up to {}ms for {} connection(s) to finish...",
+        shutdown_grace_ms,
+        connections.active()
+    );
+    let _ = shutdown_tx.send(true);
+    if timeout(
+        Duration::from_millis(shutdown_grace_ms),
+        connections.wait_idle(),
+    )
+    .await
+    .is_err()
+    {
+        warn!(
+            "Grace period over, dropping {} connection(s).",
+            connections.active()
+        );
+    }
+    drop(chain_handles);
+
+    // Make sure everything we cached makes it to disk
+    match cache.flush_async().await {
+        Ok(_) => info!("Cache flushed to disk."),
+        Err(err) => error!("Could not flush cache: {}", err),
+    }
+
+    Ok(())
+}
+
+// A chain we serve, with the tasks keeping track of its RPCs and head running
+struct Chain {
+    // Cloned for every connection to the chain
+    params: ConnectionParams,
+    poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    // Wakes the health check loop up for `blutgang_runHealthCheck`
+    health_trigger: Arc<HealthTrigger>,
+    // For caching responses outside of the request path, like `blutgang_warm`
+    cache_args: CacheArgs,
+    // Kept open so messages for `ws_conn_manager` don't fail to send when WS is disabled
+    _incoming_rx: Option<mpsc::Receiver<WsconnMessage>>,
+}
+
+impl Chain {
+    // What the admin namespace needs to manage the chain
+    fn admin_chain(&self) -> AdminChain {
+        AdminChain {
+            rpc_list: Arc::clone(&self.params.rpc_list_rwlock),
+            poverty_list: Arc::clone(&self.poverty_list),
+            health_trigger: Arc::clone(&self.health_trigger),
+            incoming_tx: self.params.channels.incoming_tx.clone(),
+            cache_args: self.cache_args.clone(),
+            readiness: Arc::clone(&self.params.readiness),
+            config: Arc::clone(&self.params.config),
+        }
+    }
+
+    // What reloading the config needs to update the chain
+    fn reload_chain(&self) -> ReloadChain {
+        ReloadChain {
+            config: Arc::clone(&self.params.config),
+            rpc_list: Arc::clone(&self.params.rpc_list_rwlock),
+            poverty_list: Arc::clone(&self.poverty_list),
+            incoming_tx: self.params.channels.incoming_tx.clone(),
+        }
+    }
+}
+
+// Start serving the chain `config` is for, caching its responses in `cache`
+// and saving the health of its RPCs in `health_tree`
+async fn start_chain(
+    config: Arc<RwLock<Settings>>,
+    cache: Arc<sled::Tree>,
+    health_tree: sled::Tree,
+    readiness: Arc<Readiness>,
+    admission: &Arc<AdmissionControl>,
+    rate_limiter: &Arc<RateLimiter>,
+    metrics: &Arc<Metrics>,
+) -> Chain {
+    let (do_health_check, is_ws, health_check_ttl) = {
+        let config_guard = config.read().unwrap();
+        (
+            config_guard.health_check,
+            config_guard.is_ws,
+            config_guard.health_check_ttl,
         )
-        .await;
-    });
+    };
+
+    // Make the list a rwlock
+    let rpc_list_rwlock = Arc::new(RwLock::new(config.read().unwrap().rpc_list.clone()));
+
+    // Tracks if we can write to the cache
+    let cache_state = Arc::new(CacheWriteState::new(
+        config.read().unwrap().cache_max_bytes != 0,
+        HealthWebhook::new(config.read().unwrap().health_webhook_url.clone()),
+    ));
+
+    // Bypasses the cache for tip adjacent requests right after a reorg
+    let reorg_window = Arc::new(ReorgWindow::new(Duration::from_millis(
+        config.read().unwrap().reorg_bypass_window,
+    )));
+
+    // Responses to recently submitted raw transactions
+    let tx_replay = Arc::new(TxReplayCache::new(Duration::from_millis(
+        config.read().unwrap().tx_replay_window,
+    )));
+
+    // RPCs serving each client session, if sticky sessions are enabled
+    let sticky_ttl = match config.read().unwrap().sticky_sessions {
+        true => Duration::from_millis(config.read().unwrap().sticky_session_ttl),
+        false => Duration::ZERO,
+    };
+    let sticky = Arc::new(StickySessions::new(sticky_ttl));
+
+    // Coalesces identical requests that are in-flight at the same time
+    let single_flight = Arc::new(SingleFlight::default());
+
+    if !do_health_check {
+        readiness.mark_health_checked();
+    }
+
+    // Responses for unfinalized blocks, waiting to be written to the cache
+    let head_cache: Arc<HeadCache> = Arc::new(RwLock::new(BTreeMap::new()));
+
+    // Keeps hot cache entries in memory in front of sled
+    let memory_cache = Arc::new(MemoryCache::new(
+        config.read().unwrap().memory_cache_entries,
+    ));
+
+    let (blocknum_tx, blocknum_rx) = watch::channel(0);
+    let (finalized_tx, finalized_rx) = watch::channel(0);
+
+    let finalized_rx_arc = Arc::new(finalized_rx.clone());
+    let rpc_poverty_list = Arc::new(RwLock::new(Vec::<Rpc>::new()));
+
+    // Keep RPCs that were in poverty before we restarted there until they're checked again.
+    // Nothing would let them out without health checks.
+    if do_health_check {
+        if config.read().unwrap().restore_health {
+            let restored = restore_health(&health_tree, &rpc_list_rwlock, &rpc_poverty_list);
+            if restored != 0 {
+                info!("Restored {} RPCs to the poverty list", restored);
+            }
+        }
+
+        tokio::task::spawn(persist_health(
+            health_tree,
+            Arc::clone(&rpc_poverty_list),
+            Duration::from_millis(health_check_ttl),
+        ));
+    }
+
+    // Make sure every RPC is reachable and on the right chain before taking requests
+    let (do_startup_check, startup_check_strict, expected_chain_id) = {
+        let config_guard = config.read().unwrap();
+        (
+            config_guard.startup_check,
+            config_guard.startup_check_strict,
+            config_guard.expected_chain_id,
+        )
+    };
+    if do_startup_check {
+        if let Err(err) = startup_check(
+            &rpc_list_rwlock,
+            &rpc_poverty_list,
+            expected_chain_id,
+            startup_check_strict,
+            Duration::from_millis(health_check_ttl),
+        )
+        .await
+        {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    // Used to talk to `ws_conn_manager`, created here so the admin namespace can reach it
+    let ws_channel_capacity = config.read().unwrap().ws_channel_capacity;
+    let (incoming_tx, incoming_rx) = mpsc::channel::<WsconnMessage>(ws_channel_capacity);
+    let mut incoming_rx = Some(incoming_rx);
+
+    // Spawn a thread for the head cache. Without it, responses for unfinalized
+    // blocks aren't cached, so there's nothing to promote or reorg.
+    if config.read().unwrap().head_cache_enabled {
+        let head_cache_clone = Arc::clone(&head_cache);
+        let memory_cache_clone = Arc::clone(&memory_cache);
+        let cache_clone = Arc::clone(&cache);
+        let cache_state_clone = Arc::clone(&cache_state);
+        let finalized_rxclone = Arc::clone(&finalized_rx_arc);
+        let head_cache_max_blocks = config.read().unwrap().head_cache_max_blocks;
+        tokio::task::spawn(async move {
+            let _ = manage_cache(
+                &head_cache_clone,
+                blocknum_rx,
+                finalized_rxclone,
+                &cache_clone,
+                &cache_state_clone,
+                &memory_cache_clone,
+                head_cache_max_blocks,
+            )
+            .await;
+        });
+    }
 
     // Spawn a thread for the health check
     //
     // Also handle the finalized block tracking in this thread
     let named_blocknumbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
 
+    let health_trigger = Arc::new(HealthTrigger::default());
     if do_health_check {
+        let health_trigger = Arc::clone(&health_trigger);
         let poverty_list_health = Arc::clone(&rpc_poverty_list);
         let config_health = Arc::clone(&config);
+        let readiness_health = Arc::clone(&readiness);
 
         let rpc_list_health = Arc::clone(&rpc_list_rwlock);
         let named_blocknumbers_health = Arc::clone(&named_blocknumbers);
+        let incoming_tx_health = is_ws.then(|| incoming_tx.clone());
 
         tokio::task::spawn(async move {
             let _ = health_check(
@@ -170,17 +646,65 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 finalized_tx,
                 &named_blocknumbers_health,
                 &config_health,
+                &readiness_health,
+                &health_trigger,
+                incoming_tx_health,
             )
             .await;
         });
     }
 
+    // Warm up the cache once we know the state of the chain
+    let chain_cache_args;
+    {
+        let cache_warmup = config.read().unwrap().cache_warmup.clone();
+        let config_warmup = Arc::clone(&config);
+        let rpc_list_warmup = Arc::clone(&rpc_list_rwlock);
+        let readiness_warmup = Arc::clone(&readiness);
+        let cache_args = CacheArgs {
+            finalized_rx: finalized_rx.clone(),
+            named_numbers: named_blocknumbers.clone(),
+            cache: cache.clone(),
+            head_cache: head_cache.clone(),
+            head_cache_enabled: config.read().unwrap().head_cache_enabled,
+            block_params: config.read().unwrap().block_params.clone(),
+            cache_state: cache_state.clone(),
+            reorg_window: reorg_window.clone(),
+            schema_version: CACHE_SCHEMA_VERSION,
+            cache_version: config.read().unwrap().cache_version,
+            migrate_cache: config.read().unwrap().lazy_cache_migration,
+            keep_expired: config.read().unwrap().serve_stale_on_error,
+            method_cache: config.read().unwrap().method_cache.clone(),
+            method_filter: config.read().unwrap().method_filter.clone(),
+            memory_cache: memory_cache.clone(),
+            response_transform: config.read().unwrap().response_transform.clone(),
+        };
+        chain_cache_args = cache_args.clone();
+
+        tokio::task::spawn(async move {
+            if !cache_warmup.is_empty() {
+                readiness_warmup.wait_for_health_check().await;
+
+                // The config can get reloaded while we wait
+                let (ttl, method_cache) = {
+                    let config_guard = config_warmup.read().unwrap();
+                    (config_guard.ttl, config_guard.method_cache.clone())
+                };
+                let cache_args = CacheArgs {
+                    method_cache,
+                    ..cache_args
+                };
+                warm_cache(&cache_warmup, &rpc_list_warmup, &cache_args, ttl).await;
+            }
+            readiness_warmup.mark_warmed_up();
+        });
+    }
+
     // WebSocket connection + health check setup. Only runs when every node has a WS endpoint.
-    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel::<WsconnMessage>();
     let (outgoing_tx, outgoing_rx) = broadcast::channel::<IncomingResponse>(2048);
     let sub_data = Arc::new(SubscriptionData::new());
     if is_ws {
-        let (ws_error_tx, ws_error_rx) = mpsc::unbounded_channel::<WsChannelErr>();
+        let (ws_error_tx, ws_error_rx) = mpsc::channel::<WsChannelErr>(ws_channel_capacity);
 
         let rpc_list_ws = Arc::clone(&rpc_list_rwlock);
         // TODO: make this more ergonomic
@@ -189,29 +713,49 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         >::new()));
         let outgoing_rx_ws = outgoing_rx.resubscribe();
         let incoming_tx_ws = incoming_tx.clone();
+        let incoming_tx_manager = incoming_tx.clone();
+        let incoming_rx = incoming_rx.take().unwrap();
         let ws_error_tx_ws = ws_error_tx.clone();
 
         let sub_dispatcher = Arc::clone(&sub_data);
+        let sub_data_manager = Arc::clone(&sub_data);
+        let drop_stale_heads = config.read().unwrap().drop_stale_heads;
+        let notification_limits = NotificationLimits {
+            max_per_second: config.read().unwrap().ws_max_notifications_per_second,
+            coalesce_new_heads: config.read().unwrap().ws_coalesce_new_heads,
+        };
+        let keepalive = WsKeepalive {
+            ping_interval: Duration::from_millis(config.read().unwrap().ws_ping_interval_ms),
+            pong_timeout: Duration::from_millis(config.read().unwrap().ws_pong_timeout_ms),
+        };
 
         tokio::task::spawn(async move {
             tokio::task::spawn(async move {
-                let _ =
-                    subscription_dispatcher(outgoing_rx_ws, incoming_tx_ws, sub_dispatcher).await;
+                let _ = subscription_dispatcher(
+                    outgoing_rx_ws,
+                    incoming_tx_ws,
+                    sub_dispatcher,
+                    drop_stale_heads,
+                    notification_limits,
+                )
+                .await;
             });
 
             let _ = ws_conn_manager(
                 rpc_list_ws,
                 ws_handle,
+                incoming_tx_manager,
                 incoming_rx,
                 outgoing_tx,
                 ws_error_tx_ws,
+                sub_data_manager,
+                keepalive,
             )
             .await;
         });
 
         if do_health_check {
             let dropped_rpc = Arc::clone(&rpc_list_rwlock);
-            let dropped_povrty = Arc::clone(&rpc_poverty_list);
             let dropped_inc = incoming_tx.clone();
             let dropped_rx = outgoing_rx.resubscribe();
             let dropped_sub_data = Arc::clone(&sub_data);
@@ -219,7 +763,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             tokio::task::spawn(async move {
                 dropped_listener(
                     dropped_rpc,
-                    dropped_povrty,
                     ws_error_rx,
                     dropped_inc,
                     dropped_rx,
@@ -237,14 +780,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 named_numbers: named_blocknumbers.clone(),
                 cache: cache.clone(),
                 head_cache: head_cache.clone(),
+                head_cache_enabled: config.read().unwrap().head_cache_enabled,
+                block_params: config.read().unwrap().block_params.clone(),
+                cache_state: cache_state.clone(),
+                reorg_window: reorg_window.clone(),
+                schema_version: CACHE_SCHEMA_VERSION,
+                cache_version: config.read().unwrap().cache_version,
+                migrate_cache: config.read().unwrap().lazy_cache_migration,
+                keep_expired: config.read().unwrap().serve_stale_on_error,
+                method_cache: config.read().unwrap().method_cache.clone(),
+                method_filter: config.read().unwrap().method_filter.clone(),
+                memory_cache: memory_cache.clone(),
+                response_transform: config.read().unwrap().response_transform.clone(),
             };
 
+            let sub_queue = SubscriptionQueue::new(
+                &rpc_list_rwlock,
+                Duration::from_millis(config.read().unwrap().subscription_queue_timeout),
+            );
+
             tokio::task::spawn(async move {
                 subscribe_to_new_heads(
                     heads_inc,
                     heads_rx,
                     blocknum_tx,
                     heads_sub_data,
+                    sub_queue,
                     cache_args,
                     health_check_ttl,
                 )
@@ -253,34 +814,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    // We start a loop to continuously accept incoming connections
-    loop {
-        let (stream, socketaddr) = listener.accept().await?;
-        println!("\x1b[35mInfo:\x1b[0m Connection from: {}", socketaddr);
-
-        // Use an adapter to access something implementing `tokio::io` traits as if they implement
-        // `hyper::rt` IO traits.
-        let io = TokioIo::new(stream);
-
-        let channels = RequestChannels::new(
-            finalized_rx_arc.clone(),
-            incoming_tx.clone(),
-            outgoing_rx.resubscribe(),
-        );
-
-        let connection_params = ConnectionParams::new(
-            &rpc_list_rwlock,
-            channels,
-            &named_blocknumbers,
-            &head_cache,
-            &sub_data,
-            &cache,
-            &config,
-        );
-
-        // Spawn a tokio task to serve multiple connections concurrently
-        tokio::task::spawn(async move {
-            accept!(io, connection_params.clone());
-        });
+    let channels = RequestChannels::new(finalized_rx_arc, incoming_tx, outgoing_rx);
+    let params = ConnectionParams::new(
+        &rpc_list_rwlock,
+        channels,
+        &named_blocknumbers,
+        &head_cache,
+        &memory_cache,
+        &sub_data,
+        &cache,
+        &cache_state,
+        &reorg_window,
+        &tx_replay,
+        &sticky,
+        admission,
+        rate_limiter,
+        &readiness,
+        &single_flight,
+        metrics,
+        &config,
+    );
+
+    Chain {
+        params,
+        poverty_list: rpc_poverty_list,
+        health_trigger,
+        cache_args: chain_cache_args,
+        _incoming_rx: incoming_rx,
     }
 }
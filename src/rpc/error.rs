@@ -8,6 +8,10 @@ pub enum RpcError {
     //InvalidHexFormat,
     OutOfBounds,
     InvalidResponse(String),
+    // Holds the limit that was exceeded
+    ResponseTooLarge(usize),
+    // The RPC responded with HTTP 429
+    RateLimited,
 }
 
 impl std::fmt::Display for RpcError {
@@ -21,6 +25,10 @@ impl std::fmt::Display for RpcError {
                 )
             }
             RpcError::InvalidResponse(reason) => write!(f, "Invalid RPC response: {}", reason),
+            RpcError::ResponseTooLarge(max) => {
+                write!(f, "Response is larger than {} bytes", max)
+            }
+            RpcError::RateLimited => write!(f, "RPC is rate limiting us"),
         }
     }
 }
@@ -0,0 +1,174 @@
+// Mock JSON-RPC node used for testing code that talks to RPCs over HTTP.
+//
+// Every request is parsed as JSON and passed to a user supplied closure,
+// which decides what the node responds with.
+use http_body_util::{
+    BodyExt,
+    Full,
+};
+use hyper::{
+    body::Bytes,
+    server::conn::{
+        http1,
+        http2,
+    },
+    service::service_fn,
+    HeaderMap,
+    Request,
+    Response,
+    StatusCode,
+};
+use hyper_util_blutgang::rt::{
+    TokioExecutor,
+    TokioIo,
+};
+use serde_json::Value;
+
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{
+            AtomicU16,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    net::TcpListener,
+    time::sleep,
+};
+
+type Responder = Arc<dyn Fn(Value) -> Value + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct MockRpc {
+    pub url: String,
+    // Total connections accepted
+    connections: Arc<AtomicUsize>,
+    // Total requests served
+    requests: Arc<AtomicUsize>,
+    // Headers of the last request
+    last_headers: Arc<Mutex<HeaderMap>>,
+    // HTTP status of every response
+    status: Arc<AtomicU16>,
+}
+
+impl MockRpc {
+    // Spawn a node responding to every request with the output of `respond`
+    // after waiting for `delay`.
+    pub async fn spawn<F>(delay: Duration, respond: F) -> Self
+    where
+        F: Fn(Value) -> Value + Send + Sync + 'static,
+    {
+        MockRpc::spawn_with(false, StatusCode::OK, delay, respond).await
+    }
+
+    // Same as `spawn`, but the node only speaks HTTP/2
+    pub async fn spawn_http2<F>(delay: Duration, respond: F) -> Self
+    where
+        F: Fn(Value) -> Value + Send + Sync + 'static,
+    {
+        MockRpc::spawn_with(true, StatusCode::OK, delay, respond).await
+    }
+
+    // Same as `spawn`, but every response has the HTTP status `status`
+    pub async fn spawn_with_status<F>(status: u16, respond: F) -> Self
+    where
+        F: Fn(Value) -> Value + Send + Sync + 'static,
+    {
+        let status = StatusCode::from_u16(status).unwrap();
+        MockRpc::spawn_with(false, status, Duration::ZERO, respond).await
+    }
+
+    async fn spawn_with<F>(http2: bool, status: StatusCode, delay: Duration, respond: F) -> Self
+    where
+        F: Fn(Value) -> Value + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let mock = MockRpc {
+            url,
+            connections: Arc::new(AtomicUsize::new(0)),
+            requests: Arc::new(AtomicUsize::new(0)),
+            last_headers: Arc::new(Mutex::new(HeaderMap::new())),
+            status: Arc::new(AtomicU16::new(status.as_u16())),
+        };
+
+        let respond: Responder = Arc::new(respond);
+        let connections = Arc::clone(&mock.connections);
+        let requests = Arc::clone(&mock.requests);
+        let last_headers = Arc::clone(&mock.last_headers);
+        let status = Arc::clone(&mock.status);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(rax) => rax,
+                    Err(_) => return,
+                };
+                connections.fetch_add(1, Ordering::SeqCst);
+
+                let respond = Arc::clone(&respond);
+                let requests = Arc::clone(&requests);
+                let last_headers = Arc::clone(&last_headers);
+                let status = Arc::clone(&status);
+                tokio::spawn(async move {
+                    let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                        let respond = Arc::clone(&respond);
+                        let requests = Arc::clone(&requests);
+                        let status = Arc::clone(&status);
+                        *last_headers.lock().unwrap() = req.headers().clone();
+                        async move {
+                            requests.fetch_add(1, Ordering::SeqCst);
+                            let body = req.collect().await.unwrap().to_bytes();
+                            let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+
+                            sleep(delay).await;
+
+                            let response = respond(request).to_string();
+                            let mut response = Response::new(Full::new(Bytes::from(response)));
+                            *response.status_mut() =
+                                StatusCode::from_u16(status.load(Ordering::SeqCst)).unwrap();
+                            Ok::<_, Infallible>(response)
+                        }
+                    });
+
+                    if http2 {
+                        let _ = http2::Builder::new(TokioExecutor::new())
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    } else {
+                        let _ = http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    }
+                });
+            }
+        });
+
+        mock
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    pub fn last_headers(&self) -> HeaderMap {
+        self.last_headers.lock().unwrap().clone()
+    }
+
+    // Respond to every following request with the HTTP status `status`
+    pub fn set_status(&self, status: u16) {
+        self.status.store(status, Ordering::SeqCst);
+    }
+}
@@ -1,2 +1,4 @@
 pub mod error;
+#[cfg(test)]
+pub mod mock;
 pub mod types;
@@ -1,5 +1,46 @@
-use crate::rpc::error::RpcError;
-use reqwest::Client;
+use crate::{
+    balancer::body_limit::DEFAULT_MAX_RESPONSE_BYTES,
+    rpc::error::RpcError,
+};
+use reqwest::{
+    header::{
+        HeaderMap,
+        HeaderName,
+        HeaderValue,
+    },
+    Certificate,
+    Client,
+    NoProxy,
+    Proxy,
+};
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+        VecDeque,
+    },
+    fmt,
+    sync::{
+        atomic::{
+            AtomicU32,
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use tokio::sync::{
+    OwnedSemaphorePermit,
+    Semaphore,
+    TryAcquireError,
+};
 
 use serde_json::{
     json,
@@ -7,6 +48,12 @@ use serde_json::{
 };
 use simd_json;
 
+// How long RPCs get skipped for after rate limiting us, by default
+pub const DEFAULT_RATE_LIMIT_COOLDOWN: Duration = Duration::from_secs(5);
+
+// How many health checks `max_observed_lag` looks back over
+pub const LAG_WINDOW: usize = 100;
+
 // All as floats so we have an easier time getting averages, stats and terminology copied from flood.
 #[derive(Debug, Clone, Default)]
 pub struct Status {
@@ -14,17 +61,287 @@ pub struct Status {
     // Also set the last time it was called, so we can check again later
     pub is_erroring: bool,
     pub last_error: u64,
+    // The WS endpoint dropped or we couldn't connect to it. This only keeps the
+    // RPC from getting subscriptions, it still serves HTTP requests.
+    pub ws_erroring: bool,
 
     // The latency is a moving average of the last n calls
     pub latency: f64,
     pub latency_data: Vec<f64>,
     ma_length: f64,
+    // Exponential moving average of the response time in ms, 0 if unknown
+    pub latency_ms: f64,
+
+    // Consecutive health checks where the `latest` block served was stale.
+    // If there's too many of them we stop trusting the RPC.
+    pub stale_latest_strikes: u32,
+    pub serves_stale_latest: bool,
+
+    // How many blocks behind the agreed head we were at the last health check
+    pub head_lag: u64,
+    // Lag at the last `LAG_WINDOW` health checks the RPC answered, oldest first
+    pub recent_lags: VecDeque<u64>,
+    // Last head reported in a health check, 0 if we never got one
+    pub last_head: u64,
+    // Round trip time of `eth_blockNumber` at the last health check in ms.
+    // Set to the health check `ttl` if the RPC didn't answer, 0 if it was never checked.
+    pub last_latency_ms: f64,
+    // Health checks failed in a row, reset by a successful one
+    pub consecutive_failures: u32,
+    // When we last got a head from the RPC during a health check
+    pub last_healthy_check: Option<Instant>,
+
+    // RPCs in the poverty list aren't checked again until `next_retry_at`.
+    // `retry_backoff` doubles every time they fail to get out.
+    pub next_retry_at: Option<Instant>,
+    pub retry_backoff: Duration,
+
+    // Set when the RPC is on the wrong chain, or serving bad data.
+    // Banned RPCs never leave the poverty list.
+    pub banned: bool,
+    // The ban came from the chain id check. These RPCs keep getting their chain id
+    // checked, and are unbanned once they report the expected one again.
+    pub wrong_chain: bool,
+    // Set by `blutgang_pauseRpc`. Paused RPCs stay in the poverty list without being
+    // health checked until they're resumed.
+    pub paused: bool,
+    // The node reported it's still syncing at the last health check
+    pub is_syncing: bool,
+    // Last result of each health probe, by method
+    pub probe_results: HashMap<String, u64>,
+    // Why the RPC failed a health probe, if it did at the last one it answered
+    pub failed_probe: Option<String>,
+    // Chain id reported at the last chain id check
+    pub chain_id: Option<u64>,
     // ???
     // pub throughput: f64,
 }
 
 unsafe impl Sync for Status {}
 
+// Metadata about the node behind an RPC, collected during health checks.
+// Fields are `None` if the node didn't answer, or if they were never collected.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeMetadata {
+    pub client_version: Option<String>,
+    pub peer_count: Option<u64>,
+    pub chain_id: Option<u64>,
+    pub syncing: Option<bool>,
+    // Whether the node can serve state for old blocks
+    pub archive: Option<bool>,
+}
+
+// HTTP version used to talk to an RPC
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    // Only use HTTP/1.1
+    Http1,
+    // Only use HTTP/2, multiplexing requests over a single connection
+    Http2,
+    // Negotiate HTTP/2 via ALPN on HTTPS RPCs, falling back to HTTP/1.1
+    #[default]
+    Auto,
+}
+
+impl HttpVersion {
+    pub fn from_str(version: &str) -> Option<Self> {
+        match version {
+            "http1" => Some(HttpVersion::Http1),
+            "http2" => Some(HttpVersion::Http2),
+            "auto" => Some(HttpVersion::Auto),
+            _ => None,
+        }
+    }
+}
+
+// How an RPC encodes the head block number
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NumberFormat {
+    // `0x` prefixed hex string, like `eth_blockNumber`
+    #[default]
+    Hex,
+    // Decimal string or JSON number
+    Decimal,
+}
+
+impl NumberFormat {
+    pub fn from_str(format: &str) -> Option<Self> {
+        match format {
+            "hex" => Some(NumberFormat::Hex),
+            "decimal" => Some(NumberFormat::Decimal),
+            _ => None,
+        }
+    }
+
+    fn parse(&self, number: &Value) -> Option<u64> {
+        match self {
+            NumberFormat::Hex => hex_to_decimal(number.as_str()?).ok(),
+            NumberFormat::Decimal => number.as_u64().or_else(|| number.as_str()?.parse().ok()),
+        }
+    }
+}
+
+// Request we send to get the head block of an RPC.
+//
+// Lets us health check chains that don't have `eth_blockNumber`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeadMethod {
+    pub method: String,
+    // JSON pointer to the head number in `result`. Empty if `result` is the number.
+    pub result_pointer: String,
+    pub format: NumberFormat,
+}
+
+impl Default for HeadMethod {
+    fn default() -> Self {
+        HeadMethod {
+            method: "eth_blockNumber".to_string(),
+            result_pointer: String::new(),
+            format: NumberFormat::Hex,
+        }
+    }
+}
+
+impl HeadMethod {
+    // Get the head block number out of a response to `method`
+    fn extract(&self, rx: &str) -> Result<u64, RpcError> {
+        let mut rx = rx.to_string();
+        let json: Value = unsafe { simd_json::serde::from_str(&mut rx)? };
+
+        json["result"]
+            .pointer(&self.result_pointer)
+            .and_then(|number| self.format.parse(number))
+            .ok_or_else(|| RpcError::InvalidResponse("error: Invalid response".to_string()))
+    }
+}
+
+// Hosts we never go through the proxy for, unless `no_proxy` or `NO_PROXY` say otherwise
+const DEFAULT_NO_PROXY: &str = "localhost,127.0.0.1,::1";
+
+// Extra headers sent with every request to an RPC, like API keys.
+//
+// The values are usually secrets, so they never get debug printed.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct RpcHeaders(pub BTreeMap<String, String>);
+
+impl RpcHeaders {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn to_header_map(&self) -> Result<HeaderMap, String> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.0 {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name `{}`", name))?;
+            let mut value = HeaderValue::from_str(value)
+                .map_err(|_| format!("invalid value for header `{}`", name))?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+
+        Ok(headers)
+    }
+}
+
+impl fmt::Debug for RpcHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.keys().map(|name| (name, "<redacted>")))
+            .finish()
+    }
+}
+
+// Settings used when building the HTTP client of an RPC
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientSettings {
+    // Max idle connections kept alive in the pool, `None` means unbounded
+    pub pool_max_idle: Option<usize>,
+    // Max connections open to the RPC at once, `None` means unbounded
+    pub pool_max_connections: Option<usize>,
+    // How long idle connections are kept alive for, `None` uses reqwest's default of 90s
+    pub pool_idle_timeout: Option<Duration>,
+    pub http_version: HttpVersion,
+    // Proxy to send requests through, and the hosts to bypass it for in `NO_PROXY` format.
+    // `None` leaves proxying up to the `HTTP(S)_PROXY` environment variables.
+    pub proxy_url: Option<String>,
+    pub no_proxy: Option<String>,
+    pub headers: RpcHeaders,
+    // Skip verifying the RPC's TLS certificate. Only meant for self-signed test nodes.
+    pub accept_invalid_certs: bool,
+    // Path to a PEM bundle of extra CA certificates to trust for this RPC
+    pub ca_bundle: Option<String>,
+}
+
+// Reads the CA certificates in the PEM bundle at `path`
+pub fn load_ca_bundle(path: &str) -> Result<Vec<Certificate>, String> {
+    let pem = std::fs::read(path).map_err(|err| format!("Could not read {}: {}", path, err))?;
+    let certs = Certificate::from_pem_bundle(&pem)
+        .map_err(|err| format!("Could not parse {}: {}", path, err))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path));
+    }
+    Ok(certs)
+}
+
+impl ClientSettings {
+    fn build_client(&self) -> Client {
+        let mut builder = Client::builder();
+
+        if self.accept_invalid_certs {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        if let Some(ca_bundle) = &self.ca_bundle {
+            let certs = load_ca_bundle(ca_bundle)
+                .unwrap_or_else(|err| panic!("\x1b[31mErr:\x1b[0m Invalid ca_bundle: {}", err));
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        if let Some(proxy_url) = &self.proxy_url {
+            let no_proxy = self
+                .no_proxy
+                .as_deref()
+                .and_then(NoProxy::from_string)
+                .or_else(NoProxy::from_env)
+                .or_else(|| NoProxy::from_string(DEFAULT_NO_PROXY));
+            let proxy = Proxy::all(proxy_url)
+                .expect("\x1b[31mErr:\x1b[0m Invalid proxy_url!")
+                .no_proxy(no_proxy);
+
+            builder = builder.proxy(proxy);
+        }
+
+        if !self.headers.is_empty() {
+            let headers = self
+                .headers
+                .to_header_map()
+                .expect("\x1b[31mErr:\x1b[0m Invalid RPC headers!");
+            builder = builder.default_headers(headers);
+        }
+
+        if let Some(pool_max_idle) = self.pool_max_idle {
+            builder = builder.pool_max_idle_per_host(pool_max_idle);
+        }
+
+        if let Some(pool_idle_timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(pool_idle_timeout);
+        }
+
+        builder = match self.http_version {
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge(),
+            HttpVersion::Auto => builder,
+        };
+
+        builder
+            .build()
+            .expect("\x1b[31mErr:\x1b[0m Could not build HTTP client for RPC!")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Rpc {
     pub url: String,            // url of the rpc we're forwarding requests to.
@@ -37,10 +354,61 @@ pub struct Rpc {
     // For max_per_second
     pub last_used: u128,
     pub min_time_delta: u128, // microseconds
+    // Capacity of concurrent in-flight requests, 0 means unbounded.
+    // Enforced by `concurrency` when set with `set_max_concurrency`.
+    pub max_concurrency: u32,
+    // Requests currently being processed by this RPC.
+    // Shared between clones so the count survives `pick` handing out copies.
+    in_flight: Arc<AtomicU32>,
+    // Requests forwarded to this RPC and how many of them failed, for metrics.
+    // Shared between clones like `in_flight`.
+    requests: Arc<AtomicU64>,
+    errors: Arc<AtomicU64>,
+    // Share of traffic relative to other RPCs
+    pub weight: u32,
+    // For smooth weighted round robin with `weighted` selection
+    pub current_weight: i64,
+    // Keeps the state of every block, so it can serve historical queries
+    pub archive: bool,
+    // Groups methods can be routed to with `method_routing`
+    pub groups: Arc<[String]>,
+    // Responses larger than this get dropped
+    pub max_response_bytes: usize,
+    // Settings the client was built with
+    pub client_settings: ClientSettings,
+    // Last metadata collected about the node
+    pub metadata: NodeMetadata,
+    // How to ask for the head block
+    pub head_method: Arc<HeadMethod>,
+    // Caps the amount of open connections if `pool_max_connections` is set.
+    //
+    // reqwest opens a new connection for every concurrent request when the
+    // idle ones are exhausted, so limiting concurrent sends limits connections.
+    connections: Option<Arc<Semaphore>>,
+    // Holds `max_concurrency` permits, one per request in-flight.
+    // Shared between clones like `in_flight`.
+    concurrency: Option<Arc<Semaphore>>,
+    // How long to skip the RPC for after it responds with HTTP 429, 0 disables it
+    pub rate_limit_cooldown: Duration,
+    // Until when the RPC is cooling down after rate limiting us.
+    // Shared between clones like `in_flight`.
+    cooldown_until: Arc<Mutex<Option<Instant>>>,
 }
 
 unsafe impl Sync for Rpc {}
 
+// Decrements the in-flight counter of an RPC when dropped
+#[derive(Debug)]
+pub struct InFlightGuard {
+    in_flight: Arc<AtomicU32>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 impl Default for Rpc {
     fn default() -> Self {
         Self {
@@ -52,6 +420,22 @@ impl Default for Rpc {
             consecutive: 0,
             last_used: 0,
             min_time_delta: 0,
+            max_concurrency: 0,
+            in_flight: Arc::new(AtomicU32::new(0)),
+            requests: Arc::new(AtomicU64::new(0)),
+            errors: Arc::new(AtomicU64::new(0)),
+            weight: 1,
+            current_weight: 0,
+            archive: false,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            client_settings: ClientSettings::default(),
+            metadata: NodeMetadata::default(),
+            head_method: Arc::new(HeadMethod::default()),
+            groups: Arc::from([]),
+            connections: None,
+            concurrency: None,
+            rate_limit_cooldown: DEFAULT_RATE_LIMIT_COOLDOWN,
+            cooldown_until: Arc::new(Mutex::new(None)),
         }
     }
 }
@@ -77,13 +461,153 @@ impl Rpc {
             consecutive: 0,
             last_used: 0,
             min_time_delta,
+            max_concurrency: 0,
+            in_flight: Arc::new(AtomicU32::new(0)),
+            requests: Arc::new(AtomicU64::new(0)),
+            errors: Arc::new(AtomicU64::new(0)),
+            weight: 1,
+            current_weight: 0,
+            archive: false,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            client_settings: ClientSettings::default(),
+            metadata: NodeMetadata::default(),
+            head_method: Arc::new(HeadMethod::default()),
+            groups: Arc::from([]),
+            connections: None,
+            concurrency: None,
+            rate_limit_cooldown: DEFAULT_RATE_LIMIT_COOLDOWN,
+            cooldown_until: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Rebuild the HTTP client of the RPC with new settings
+    pub fn set_client_settings(&mut self, client_settings: ClientSettings) {
+        self.client = client_settings.build_client();
+        self.connections = client_settings
+            .pool_max_connections
+            .map(|max| Arc::new(Semaphore::new(max)));
+        self.client_settings = client_settings;
+    }
+
+    // Rebuild the HTTP client with the same settings, dropping every pooled connection.
+    //
+    // Clones share their pool, so this also stops sharing it with them.
+    pub fn reset_client(&mut self) {
+        self.set_client_settings(self.client_settings.clone());
+    }
+
+    // Cap the requests in-flight to this RPC at once, 0 means unbounded
+    pub fn set_max_concurrency(&mut self, max_concurrency: u32) {
+        self.max_concurrency = max_concurrency;
+        self.concurrency =
+            (max_concurrency != 0).then(|| Arc::new(Semaphore::new(max_concurrency as usize)));
+    }
+
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups.iter().any(|rpc_group| rpc_group == group)
+    }
+
+    // Returns false if the RPC already has `max_concurrency` requests in-flight
+    pub fn has_capacity(&self) -> bool {
+        self.concurrency
+            .as_ref()
+            .map_or(true, |concurrency| concurrency.available_permits() != 0)
+    }
+
+    // Wait for the RPC to have capacity for another request.
+    //
+    // The slot is taken until the returned permit is dropped.
+    // Returns `None` right away if the concurrency isn't capped.
+    pub async fn acquire_capacity(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.concurrency {
+            Some(concurrency) => Arc::clone(concurrency).acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+
+    // Take a slot for another request without waiting.
+    //
+    // Errors if the RPC already has `max_concurrency` requests in-flight.
+    // Returns `Ok(None)` if the concurrency isn't capped.
+    pub fn try_acquire_capacity(&self) -> Result<Option<OwnedSemaphorePermit>, TryAcquireError> {
+        match &self.concurrency {
+            Some(concurrency) => Arc::clone(concurrency).try_acquire_owned().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Number of requests currently in-flight to this RPC
+    pub fn in_flight(&self) -> u32 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    // Mark a request as in-flight until the returned guard is dropped
+    pub fn track_in_flight(&self) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard {
+            in_flight: Arc::clone(&self.in_flight),
+        }
+    }
+
+    // Count a request forwarded to this RPC
+    pub fn count_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Count a forwarded request that failed or timed out
+    pub fn count_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    // Skip the RPC for `rate_limit_cooldown`
+    pub fn start_cooldown(&self) {
+        if self.rate_limit_cooldown.is_zero() {
+            return;
         }
+        *self.cooldown_until.lock().unwrap() = Some(Instant::now() + self.rate_limit_cooldown);
+    }
+
+    // Returns true while the RPC is cooling down after rate limiting us
+    pub fn is_cooling_down(&self) -> bool {
+        self.cooldown_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    // Ratio of in-flight requests to capacity, clamped to 0-1.
+    // Returns `None` if the RPC has no configured capacity.
+    pub fn utilization(&self) -> Option<f64> {
+        if self.max_concurrency == 0 {
+            return None;
+        }
+
+        Some((self.in_flight() as f64 / self.max_concurrency as f64).min(1.0))
     }
 
     // Generic fn to send rpc
     pub async fn send_request(&self, tx: Value) -> Result<String, crate::rpc::types::RpcError> {
         #[cfg(feature = "debug-verbose")]
-        println!("Sending request: {}", tx.clone());
+        tracing::debug!("Sending request: {}", tx.clone());
+
+        // Wait for a free connection slot if the pool is capped
+        let _connection =
+            match &self.connections {
+                Some(connections) => {
+                    Some(connections.acquire().await.map_err(|err| {
+                        crate::rpc::types::RpcError::InvalidResponse(err.to_string())
+                    })?)
+                }
+                None => None,
+            };
 
         let response = match self.client.post(&self.url).json(&tx).send().await {
             Ok(response) => response,
@@ -94,43 +618,99 @@ impl Rpc {
             }
         };
 
+        // Back off from RPCs that rate limit us
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            self.start_cooldown();
+            return Err(RpcError::RateLimited);
+        }
+
+        // Some RPCs answer JSON-RPC errors with a 5xx. Those are real responses,
+        // anything else means the RPC itself is having trouble.
+        if response.status().is_server_error() {
+            let status = response.status();
+            let body = self.read_response(response).await.unwrap_or_default();
+            return match serde_json::from_str::<Value>(&body) {
+                Ok(rx) if rx.get("error").is_some() => Ok(body),
+                _ => {
+                    Err(crate::rpc::types::RpcError::InvalidResponse(format!(
+                        "HTTP {}",
+                        status
+                    )))
+                }
+            };
+        }
+
         #[cfg(feature = "debug-verbose")]
         {
-            let a = response.text().await.unwrap();
-            println!("response: {}", a);
+            let a = self.read_response(response).await?;
+            tracing::debug!("Response: {}", a);
             return Ok(a);
         }
 
         #[cfg(not(feature = "debug-verbose"))]
-        Ok(response.text().await.unwrap())
+        self.read_response(response).await
+    }
+
+    // Read the body of `response`, giving up once it's over `max_response_bytes`
+    async fn read_response(&self, mut response: reqwest::Response) -> Result<String, RpcError> {
+        if response
+            .content_length()
+            .is_some_and(|length| length > self.max_response_bytes as u64)
+        {
+            return Err(RpcError::ResponseTooLarge(self.max_response_bytes));
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|err| RpcError::InvalidResponse(err.to_string()))?
+        {
+            if body.len() + chunk.len() > self.max_response_bytes {
+                return Err(RpcError::ResponseTooLarge(self.max_response_bytes));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        String::from_utf8(body).map_err(|err| RpcError::InvalidResponse(err.to_string()))
     }
 
     // Request blocknumber and return its value
     pub async fn block_number(&self) -> Result<u64, crate::rpc::types::RpcError> {
         let request = json!({
-            "method": "eth_blockNumber".to_string(),
+            "method": self.head_method.method,
             "params": serde_json::Value::Null,
             "id": 1,
             "jsonrpc": "2.0".to_string(),
         });
 
         let number = self.send_request(request).await?;
-        let return_number = extract_number(&number)?;
-
-        Ok(return_number)
+        self.head_method.extract(&number)
     }
 
     // Get the latest finalized block
     pub async fn get_finalized_block(&self) -> Result<u64, crate::rpc::types::RpcError> {
+        self.get_block_by_tag("finalized").await
+    }
+
+    // Get the number of the block served as `latest`.
+    //
+    // Can differ from `block_number` for RPCs serving cached blocks.
+    pub async fn get_latest_block(&self) -> Result<u64, crate::rpc::types::RpcError> {
+        self.get_block_by_tag("latest").await
+    }
+
+    // Get the number of the block returned for a block tag
+    pub async fn get_block_by_tag(&self, tag: &str) -> Result<u64, crate::rpc::types::RpcError> {
         let request = json!({
             "method": "eth_getBlockByNumber".to_string(),
-            "params": ["finalized", false],
+            "params": [tag, false],
             "id": 1,
             "jsonrpc": "2.0".to_string(),
         });
 
         let number: Value =
-            unsafe { simd_json::serde::from_str(&mut self.send_request(request).await?).unwrap() };
+            unsafe { simd_json::serde::from_str(&mut self.send_request(request).await?)? };
         let number = &number["result"]["number"];
 
         let number = match number.as_str() {
@@ -150,11 +730,161 @@ impl Rpc {
         Ok(return_number)
     }
 
+    // Send a request and parse the response as JSON
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let request = json!({
+            "method": method,
+            "params": params,
+            "id": 1,
+            "jsonrpc": "2.0",
+        });
+
+        let mut response = self.send_request(request).await?;
+        let response: Value = unsafe { simd_json::serde::from_str(&mut response)? };
+
+        Ok(response)
+    }
+
+    pub async fn get_chain_id(&self) -> Result<u64, RpcError> {
+        let response = self.call("eth_chainId", json!([])).await?;
+
+        match response["result"].as_str() {
+            Some(chain_id) => {
+                hex_to_decimal(chain_id).map_err(|err| RpcError::InvalidResponse(err.to_string()))
+            }
+            None => {
+                Err(RpcError::InvalidResponse(
+                    "error: Invalid response".to_string(),
+                ))
+            }
+        }
+    }
+
+    // `net_version` is usually a decimal string, but some nodes return hex
+    pub async fn get_net_version(&self) -> Result<u64, RpcError> {
+        let response = self.call("net_version", json!([])).await?;
+
+        let net_version = response["result"]
+            .as_str()
+            .ok_or_else(|| RpcError::InvalidResponse("error: Invalid response".to_string()))?;
+        let net_version = match net_version.starts_with("0x") {
+            true => hex_to_decimal(net_version),
+            false => net_version.parse(),
+        };
+
+        net_version.map_err(|err| RpcError::InvalidResponse(err.to_string()))
+    }
+
+    // Returns the hash of block `number`, or None if the node doesn't have it
+    pub async fn get_block_hash(&self, number: u64) -> Result<Option<String>, RpcError> {
+        let response = self
+            .call(
+                "eth_getBlockByNumber",
+                json!([format!("0x{:x}", number), false]),
+            )
+            .await?;
+
+        match &response["result"] {
+            Value::Null => Ok(None),
+            block => {
+                match block["hash"].as_str() {
+                    Some(hash) => Ok(Some(hash.to_lowercase())),
+                    None => {
+                        Err(RpcError::InvalidResponse(
+                            "error: Invalid response".to_string(),
+                        ))
+                    }
+                }
+            }
+        }
+    }
+
+    // Returns true if the node is still syncing
+    pub async fn is_syncing(&self) -> Result<bool, RpcError> {
+        let response = self.call("eth_syncing", json!([])).await?;
+
+        // `false`, or an object with the sync status
+        match &response["result"] {
+            Value::Bool(false) => Ok(false),
+            Value::Object(_) => Ok(true),
+            _ => {
+                Err(RpcError::InvalidResponse(
+                    "error: Invalid response".to_string(),
+                ))
+            }
+        }
+    }
+
+    // Collect metadata about the node. Requests that fail leave their fields as `None`.
+    pub async fn get_metadata(&self) -> NodeMetadata {
+        let (client_version, peer_count, chain_id, syncing, archive) = tokio::join!(
+            self.call("web3_clientVersion", json!([])),
+            self.call("net_peerCount", json!([])),
+            self.call("eth_chainId", json!([])),
+            self.call("eth_syncing", json!([])),
+            // Pruned nodes error out when asked for state this old
+            self.call(
+                "eth_getBalance",
+                json!(["0x0000000000000000000000000000000000000000", "0x1"])
+            ),
+        );
+
+        let hex_result = |response: Result<Value, RpcError>| {
+            response
+                .ok()
+                .and_then(|response| response["result"].as_str().map(str::to_string))
+                .and_then(|result| hex_to_decimal(&result).ok())
+        };
+
+        NodeMetadata {
+            client_version: client_version
+                .ok()
+                .and_then(|response| response["result"].as_str().map(str::to_string)),
+            peer_count: hex_result(peer_count),
+            chain_id: hex_result(chain_id),
+            // `eth_syncing` returns `false`, or an object with the sync status
+            syncing: syncing.ok().and_then(|response| {
+                match &response["result"] {
+                    Value::Null => None,
+                    result => Some(result != &Value::Bool(false)),
+                }
+            }),
+            archive: archive.ok().map(|response| {
+                response.get("result").is_some() && response.get("error").is_none()
+            }),
+        }
+    }
+
+    // Update the exponential moving average of the response time.
+    // `alpha` is the weight given to the latest sample.
+    pub fn update_latency_ema(&mut self, latency_ms: f64, alpha: f64) {
+        self.status.latency_ms = if self.status.latency_ms == 0.0 {
+            latency_ms
+        } else {
+            alpha * latency_ms + (1.0 - alpha) * self.status.latency_ms
+        };
+    }
+
+    // Record the lag at a health check, dropping the oldest one past `LAG_WINDOW`
+    pub fn record_lag(&mut self, lag: u64) {
+        if self.status.recent_lags.len() >= LAG_WINDOW {
+            self.status.recent_lags.pop_front();
+        }
+        self.status.recent_lags.push_back(lag);
+    }
+
+    // Highest lag seen over the last `LAG_WINDOW` health checks, 0 if there are none
+    pub fn max_observed_lag(&self) -> u64 {
+        self.status.recent_lags.iter().copied().max().unwrap_or(0)
+    }
+
     // Update the latency of the last n calls.
     // We don't do it within send_request because we might kill it if it times out.
     pub fn update_latency(&mut self, latest: f64) {
         // If we have data >= to ma_length, remove the first one in line
-        if self.status.latency_data.len() >= self.status.ma_length as usize {
+        if !self.status.latency_data.is_empty()
+            && self.status.latency_data.len() >= self.status.ma_length as usize
+        {
             self.status.latency_data.remove(0);
         }
 
@@ -165,25 +895,6 @@ impl Rpc {
     }
 }
 
-// Take in the result of eth_getBlockByNumber, and extract the block number
-fn extract_number(rx: &str) -> Result<u64, RpcError> {
-    let mut rx = rx.to_string();
-
-    let json: Value = unsafe { simd_json::serde::from_str(&mut rx)? };
-
-    let number = match json["result"].as_str() {
-        Some(number) => number,
-        None => {
-            return Err(RpcError::InvalidResponse(
-                "error: Invalid response".to_string(),
-            ))
-        }
-    };
-
-    let number = hex_to_decimal(number).unwrap();
-    Ok(number)
-}
-
 pub fn hex_to_decimal(hex_string: &str) -> Result<u64, std::num::ParseIntError> {
     // TODO: theres a bizzare edge case where the last " isnt removed in the
     // previou step so check for that here and remove it if necessary
@@ -194,3 +905,364 @@ pub fn hex_to_decimal(hex_string: &str) -> Result<u64, std::num::ParseIntError>
 
     u64::from_str_radix(hex_string, 16)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::MockRpc;
+    use std::time::Duration;
+
+    async fn send_concurrent(rpc: &Rpc, count: usize) {
+        let mut handles = Vec::new();
+        for _ in 0..count {
+            let rpc = rpc.clone();
+            handles.push(tokio::spawn(async move {
+                rpc.send_request(json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber"}))
+                    .await
+                    .unwrap()
+            }));
+        }
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_errors() {
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber"});
+
+        // Proxies in front of RPCs respond with an error page
+        let mock = MockRpc::spawn_with_status(502, |_| json!("Bad Gateway")).await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        assert!(rpc.send_request(tx.clone()).await.is_err());
+
+        // JSON-RPC errors are responses, whatever the status
+        let mock = MockRpc::spawn_with_status(500, |request| {
+            json!({"jsonrpc": "2.0", "id": request["id"], "error": {"code": -32000, "message": "execution reverted"}})
+        })
+        .await;
+        let rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        let rx = rpc.send_request(tx).await.unwrap();
+        assert!(rx.contains("execution reverted"));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_cooldown() {
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber"});
+        let mock = MockRpc::spawn_with_status(429, |_| json!("Too Many Requests")).await;
+
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        rpc.rate_limit_cooldown = Duration::from_millis(50);
+        // Clones handed out by the balancer share the cooldown
+        let picked = rpc.clone();
+        assert!(!rpc.is_cooling_down());
+
+        assert!(matches!(
+            picked.send_request(tx.clone()).await,
+            Err(RpcError::RateLimited)
+        ));
+        assert!(rpc.is_cooling_down());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(!rpc.is_cooling_down());
+
+        // Disabled
+        rpc.rate_limit_cooldown = Duration::ZERO;
+        assert!(rpc.send_request(tx).await.is_err());
+        assert!(!rpc.is_cooling_down());
+    }
+
+    async fn slow_mock() -> MockRpc {
+        MockRpc::spawn(
+            Duration::from_millis(100),
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_pool_max_idle() {
+        let mock = slow_mock().await;
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        rpc.set_client_settings(ClientSettings {
+            pool_max_idle: Some(1),
+            ..Default::default()
+        });
+
+        send_concurrent(&rpc, 4).await;
+        assert_eq!(mock.connections(), 4);
+
+        // Only 1 connection should've been kept idle, so we need 3 new ones
+        send_concurrent(&rpc, 4).await;
+        assert_eq!(mock.connections(), 7);
+    }
+
+    #[test]
+    fn test_load_ca_bundle() {
+        let dir = std::env::temp_dir().join(format!("blutgang_ca_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let missing = dir.join("missing.pem");
+        assert!(load_ca_bundle(missing.to_str().unwrap()).is_err());
+
+        let not_pem = dir.join("not_pem.pem");
+        std::fs::write(&not_pem, "definitely not a certificate").unwrap();
+        assert!(load_ca_bundle(not_pem.to_str().unwrap()).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_accept_invalid_certs() {
+        let mock = slow_mock().await;
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        rpc.set_client_settings(ClientSettings {
+            accept_invalid_certs: true,
+            ..Default::default()
+        });
+        assert!(rpc.client_settings.accept_invalid_certs);
+
+        send_concurrent(&rpc, 1).await;
+        assert_eq!(mock.connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pool_idle_timeout() {
+        let mock = slow_mock().await;
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        rpc.set_client_settings(ClientSettings {
+            pool_idle_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+
+        send_concurrent(&rpc, 1).await;
+        send_concurrent(&rpc, 1).await;
+        assert_eq!(mock.connections(), 1);
+
+        // The idle connection should've been closed by now
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        send_concurrent(&rpc, 1).await;
+        assert_eq!(mock.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reset_client() {
+        let mock = slow_mock().await;
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        rpc.set_client_settings(ClientSettings {
+            pool_max_connections: Some(1),
+            ..Default::default()
+        });
+
+        send_concurrent(&rpc, 1).await;
+        let mut recovered = rpc.clone();
+        send_concurrent(&recovered, 1).await;
+        assert_eq!(mock.connections(), 1);
+
+        // Fresh pool, same settings
+        recovered.reset_client();
+        assert_eq!(recovered.client_settings, rpc.client_settings);
+        send_concurrent(&recovered, 2).await;
+        assert_eq!(mock.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_max_connections() {
+        let mock = slow_mock().await;
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        rpc.set_client_settings(ClientSettings {
+            pool_max_connections: Some(2),
+            ..Default::default()
+        });
+
+        send_concurrent(&rpc, 6).await;
+        assert_eq!(mock.requests(), 6);
+        assert_eq!(mock.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_settings_are_per_rpc() {
+        let mock = slow_mock().await;
+        let mut capped = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        capped.set_client_settings(ClientSettings {
+            pool_max_connections: Some(1),
+            ..Default::default()
+        });
+        let uncapped = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+
+        send_concurrent(&capped, 3).await;
+        assert_eq!(mock.connections(), 1);
+
+        send_concurrent(&uncapped, 3).await;
+        assert_eq!(mock.connections(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_http2_multiplexing() {
+        let mock = MockRpc::spawn_http2(
+            Duration::from_millis(100),
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        rpc.set_client_settings(ClientSettings {
+            http_version: HttpVersion::Http2,
+            ..Default::default()
+        });
+
+        send_concurrent(&rpc, 8).await;
+        assert_eq!(mock.requests(), 8);
+        assert_eq!(mock.connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_http1_and_auto_fallback() {
+        let mock = slow_mock().await;
+
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        rpc.set_client_settings(ClientSettings {
+            http_version: HttpVersion::Http1,
+            ..Default::default()
+        });
+        send_concurrent(&rpc, 4).await;
+        assert_eq!(mock.connections(), 4);
+
+        // No ALPN over plain HTTP, so `auto` should fall back to HTTP/1.1
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        rpc.set_client_settings(ClientSettings {
+            http_version: HttpVersion::Auto,
+            ..Default::default()
+        });
+        send_concurrent(&rpc, 4).await;
+        assert_eq!(mock.requests(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_url() {
+        let proxy = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let upstream = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x2"}),
+        )
+        .await;
+        let proxied = |url: &str, no_proxy: Option<&str>| {
+            let mut rpc = Rpc::new(url.to_string(), None, 0, 0, 1.0);
+            rpc.set_client_settings(ClientSettings {
+                proxy_url: Some(proxy.url.clone()),
+                no_proxy: no_proxy.map(str::to_string),
+                ..Default::default()
+            });
+            rpc
+        };
+
+        // Requests go through the proxy, even to hosts we can't resolve
+        let rpc = proxied("http://rpc.invalid:8545", None);
+        assert_eq!(rpc.block_number().await.unwrap(), 1);
+        assert_eq!(proxy.requests(), 1);
+
+        // Local endpoints bypass it by default
+        let rpc = proxied(&upstream.url, None);
+        assert_eq!(rpc.block_number().await.unwrap(), 2);
+        assert_eq!(proxy.requests(), 1);
+
+        // Unless `no_proxy` says otherwise
+        let rpc = proxied(&upstream.url, Some("example.com"));
+        assert_eq!(rpc.block_number().await.unwrap(), 1);
+        assert_eq!(proxy.requests(), 2);
+        assert_eq!(upstream.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rpc_headers() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}),
+        )
+        .await;
+        let headers = RpcHeaders(BTreeMap::from([(
+            "x-api-key".to_string(),
+            "hunter2".to_string(),
+        )]));
+        let settings = ClientSettings {
+            headers,
+            ..Default::default()
+        };
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        rpc.set_client_settings(settings.clone());
+
+        assert_eq!(rpc.block_number().await.unwrap(), 1);
+        assert_eq!(mock.last_headers()["x-api-key"], "hunter2");
+
+        // Values stay out of debug output
+        assert!(!format!("{:?}", settings).contains("hunter2"));
+        assert!(!format!("{:?}", rpc).contains("hunter2"));
+
+        let invalid = RpcHeaders(BTreeMap::from([(
+            "x api key".to_string(),
+            "hunter2".to_string(),
+        )]));
+        assert!(invalid.to_header_map().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_response_bytes() {
+        let mock = MockRpc::spawn(
+            Duration::ZERO,
+            |request| json!({"jsonrpc": "2.0", "id": request["id"], "result": format!("0x{}", "0".repeat(1024))}),
+        )
+        .await;
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+
+        assert!(rpc
+            .send_request(json!({"method": "eth_call"}))
+            .await
+            .is_ok());
+
+        rpc.max_response_bytes = 1024;
+        assert!(matches!(
+            rpc.send_request(json!({"method": "eth_call"})).await,
+            Err(RpcError::ResponseTooLarge(1024))
+        ));
+    }
+    #[tokio::test]
+    async fn test_head_method() {
+        let mock = MockRpc::spawn(Duration::ZERO, |request| {
+            let result = match request["method"].as_str() {
+                Some("eth_blockNumber") => json!("0x10"),
+                Some("status") => json!({"sync_info": {"latest_block_height": "42"}}),
+                Some("getSlot") => json!(43),
+                _ => json!(null),
+            };
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+        })
+        .await;
+        let mut rpc = Rpc::new(mock.url.clone(), None, 0, 0, 1.0);
+        assert_eq!(rpc.block_number().await.unwrap(), 16);
+
+        rpc.head_method = Arc::new(HeadMethod {
+            method: "status".to_string(),
+            result_pointer: "/sync_info/latest_block_height".to_string(),
+            format: NumberFormat::Decimal,
+        });
+        assert_eq!(rpc.block_number().await.unwrap(), 42);
+
+        rpc.head_method = Arc::new(HeadMethod {
+            method: "getSlot".to_string(),
+            result_pointer: String::new(),
+            format: NumberFormat::Decimal,
+        });
+        assert_eq!(rpc.block_number().await.unwrap(), 43);
+
+        // Results that don't match the format are errors, not panics
+        rpc.head_method = Arc::new(HeadMethod {
+            method: "getSlot".to_string(),
+            ..Default::default()
+        });
+        assert!(rpc.block_number().await.is_err());
+    }
+}
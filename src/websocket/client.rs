@@ -1,16 +1,33 @@
 use crate::{
     balancer::{
-        format::replace_block_tags,
+        format::{
+            cache_key,
+            replace_block_tags,
+        },
         processing::{
+            cache_lookup_or_miss,
             cache_querry,
+            jsonrpc_error,
             update_rpc_latency,
             CacheArgs,
         },
-        selection::select::pick,
+        selection::select::pick_filtered,
     },
+    config::types::SelectionStrategy,
     rpc::types::Rpc,
     websocket::{
-        error::Error,
+        error::{
+            Error,
+            SUBSCRIPTION_UNAVAILABLE,
+        },
+        subscription_manager::{
+            canonicalize_subscription,
+            move_subscriptions,
+            serve_local_heads,
+            unsubscribe_client,
+            validate_subscription,
+            SubscriptionQueue,
+        },
         types::{
             IncomingResponse,
             SubscriptionData,
@@ -20,27 +37,54 @@ use crate::{
     },
 };
 
+use tracing::{
+    debug,
+    error,
+    info,
+    warn,
+};
+
 use std::{
+    collections::HashMap,
     sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
         Arc,
         RwLock,
     },
-    time::Instant,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 use futures_util::{
     SinkExt,
     StreamExt,
 };
-use serde_json::Value;
+use serde_json::{
+    json,
+    Value,
+};
 use simd_json::{
     from_slice,
     from_str,
 };
 
-use tokio::sync::{
-    broadcast,
-    mpsc,
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc,
+        Notify,
+    },
+    time::{
+        interval_at,
+        sleep_until,
+        Instant as TokioInstant,
+        MissedTickBehavior,
+    },
 };
 use tokio_tungstenite::{
     connect_async,
@@ -53,15 +97,46 @@ use blake3::hash;
 #[cfg(feature = "xxhash")]
 use xxhash_rust::xxh3::xxh3_64;
 
+// Pings WS nodes to catch connections that went half-open without closing.
+//
+// A node that doesn't answer within `pong_timeout` gets reported as closed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WsKeepalive {
+    // Zero disables pings
+    pub ping_interval: Duration,
+    pub pong_timeout: Duration,
+}
+
+// A connection to the WS endpoint of an RPC
+pub struct WsConnection {
+    ws_url: Option<String>,
+    // Position of the RPC in the list, which changes as RPCs before it are removed
+    node_id: Arc<AtomicUsize>,
+    handle: mpsc::UnboundedSender<Value>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn ws_conn_manager(
     rpc_list: Arc<RwLock<Vec<Rpc>>>,
     ws_handles: Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
-    mut incoming_rx: mpsc::UnboundedReceiver<WsconnMessage>,
+    incoming_tx: mpsc::Sender<WsconnMessage>,
+    mut incoming_rx: mpsc::Receiver<WsconnMessage>,
     broadcast_tx: broadcast::Sender<IncomingResponse>,
-    ws_error_tx: mpsc::UnboundedSender<WsChannelErr>,
+    ws_error_tx: mpsc::Sender<WsChannelErr>,
+    sub_data: Arc<SubscriptionData>,
+    keepalive: WsKeepalive,
 ) {
     // Initialize WebSocket connections
-    update_ws_connections(&rpc_list, &ws_handles, &broadcast_tx, &ws_error_tx).await;
+    let mut connections = Vec::new();
+    update_ws_connections(
+        &rpc_list,
+        &ws_handles,
+        &mut connections,
+        &broadcast_tx,
+        &ws_error_tx,
+        keepalive,
+    )
+    .await;
 
     while let Some(message) = incoming_rx.recv().await {
         match message {
@@ -69,21 +144,109 @@ pub async fn ws_conn_manager(
                 handle_incoming_message(&ws_handles, &rpc_list, incoming, specified_index).await;
             }
             WsconnMessage::Reconnect() => {
-                update_ws_connections(&rpc_list, &ws_handles, &broadcast_tx, &ws_error_tx).await;
+                update_ws_connections(
+                    &rpc_list,
+                    &ws_handles,
+                    &mut connections,
+                    &broadcast_tx,
+                    &ws_error_tx,
+                    keepalive,
+                )
+                .await;
+            }
+            WsconnMessage::Sync() => {
+                sync_ws_connections(
+                    &rpc_list,
+                    &ws_handles,
+                    &mut connections,
+                    &incoming_tx,
+                    &broadcast_tx,
+                    &ws_error_tx,
+                    &sub_data,
+                    keepalive,
+                )
+                .await;
             }
         }
     }
 }
 
+fn handles(connections: &[Option<WsConnection>]) -> Vec<Option<mpsc::UnboundedSender<Value>>> {
+    connections
+        .iter()
+        .map(|connection| connection.as_ref().map(|connection| connection.handle.clone()))
+        .collect()
+}
+
 async fn update_ws_connections(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     ws_handles: &Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
+    connections: &mut Vec<Option<WsConnection>>,
     broadcast_tx: &broadcast::Sender<IncomingResponse>,
-    ws_error_tx: &mpsc::UnboundedSender<WsChannelErr>,
+    ws_error_tx: &mpsc::Sender<WsChannelErr>,
+    keepalive: WsKeepalive,
 ) {
-    let ws_vec = create_ws_vec(rpc_list, broadcast_tx, ws_error_tx).await;
+    // Dropping the old connections closes them
+    *connections =
+        create_ws_vec(rpc_list, &mut Vec::new(), broadcast_tx, ws_error_tx, keepalive).await;
     let mut ws_handle_guard = ws_handles.write().unwrap();
-    *ws_handle_guard = ws_vec;
+    *ws_handle_guard = handles(connections);
+}
+
+// Bring the WS connections in line with the RPC list, leaving the ones to
+// endpoints that are still in it connected.
+#[allow(clippy::too_many_arguments)]
+async fn sync_ws_connections(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    ws_handles: &Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
+    connections: &mut Vec<Option<WsConnection>>,
+    incoming_tx: &mpsc::Sender<WsconnMessage>,
+    broadcast_tx: &broadcast::Sender<IncomingResponse>,
+    ws_error_tx: &mpsc::Sender<WsChannelErr>,
+    sub_data: &Arc<SubscriptionData>,
+    keepalive: WsKeepalive,
+) {
+    let mut previous = std::mem::take(connections);
+    *connections = create_ws_vec(
+        rpc_list,
+        &mut previous,
+        broadcast_tx,
+        ws_error_tx,
+        keepalive,
+    )
+    .await;
+
+    // Subscriptions follow their node to its new position. The ones on closed
+    // connections get ids past the end of the list until they're moved away.
+    let mut renumbered = HashMap::new();
+    for (index, connection) in connections.iter().enumerate() {
+        if let Some(connection) = connection {
+            let old = connection.node_id.swap(index, Ordering::Relaxed);
+            if old != index {
+                renumbered.insert(old, index);
+            }
+        }
+    }
+    let mut closed = Vec::new();
+    for connection in previous.into_iter().flatten() {
+        let node_id = connections.len() + closed.len();
+        renumbered.insert(connection.node_id.load(Ordering::Relaxed), node_id);
+        closed.push(node_id);
+    }
+    sub_data.renumber_nodes(&renumbered);
+
+    *ws_handles.write().unwrap() = handles(connections);
+
+    for node_id in closed {
+        let incoming_tx = incoming_tx.clone();
+        let rx = broadcast_tx.subscribe();
+        let sub_data = sub_data.clone();
+        tokio::spawn(async move {
+            if let Err(err) = move_subscriptions(&incoming_tx, rx, &sub_data, node_id).await {
+                warn!("Could not move subscriptions off a closed WS connection: {}", err);
+            }
+        });
+    }
 }
 
 async fn handle_incoming_message(
@@ -95,10 +258,17 @@ async fn handle_incoming_message(
     let rpc_position = if let Some(index) = specified_index {
         index
     } else {
-        match pick(&mut rpc_list.write().unwrap()).1 {
+        // Only RPCs with a working WS endpoint can take it
+        match pick_filtered(
+            &mut rpc_list.write().unwrap(),
+            SelectionStrategy::RoundRobin,
+            |rpc| rpc.ws_url.is_some() && !rpc.status.ws_erroring,
+        )
+        .1
+        {
             Some(position) => position,
             None => {
-                println!("Error: No RPC position available");
+                error!("No RPC position available");
                 return;
             }
         }
@@ -111,36 +281,79 @@ async fn handle_incoming_message(
         .and_then(|handle| handle.as_ref())
     {
         if ws.send(incoming).is_err() {
-            println!("ws_conn_manager error: failed to send message");
+            error!("ws_conn_manager: failed to send message");
         }
     } else {
-        println!("No WS connection at index {}", rpc_position);
+        warn!("No WS connection at index {}", rpc_position);
     }
 }
 
+// Connect to the WS endpoint of every RPC.
+//
+// Connections in `previous` to endpoints that are still in use get reused and
+// taken out of it, so whatever is left in `previous` is no longer needed.
 pub async fn create_ws_vec(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    previous: &mut [Option<WsConnection>],
     broadcast_tx: &broadcast::Sender<IncomingResponse>,
-    ws_error_tx: &mpsc::UnboundedSender<WsChannelErr>,
-) -> Vec<Option<mpsc::UnboundedSender<Value>>> {
+    ws_error_tx: &mpsc::Sender<WsChannelErr>,
+    keepalive: WsKeepalive,
+) -> Vec<Option<WsConnection>> {
     let rpc_list_clone = rpc_list.read().unwrap().clone();
-    let mut ws_handles = Vec::new();
+    let mut connections = Vec::new();
 
     for (index, rpc) in rpc_list_clone.iter().enumerate() {
+        let reused = previous.iter_mut().find(|connection| {
+            connection.as_ref().is_some_and(|connection| {
+                rpc.ws_url.is_some()
+                    && connection.ws_url == rpc.ws_url
+                    && !connection.handle.is_closed()
+            })
+        });
+        if let Some(connection) = reused {
+            connections.push(connection.take());
+            continue;
+        }
+
         let (ws_conn_incoming_tx, ws_conn_incoming_rx) = mpsc::unbounded_channel();
-        ws_handles.push(Some(ws_conn_incoming_tx));
-        ws_conn(
+        let node_id = Arc::new(AtomicUsize::new(index));
+        let connected = match ws_conn(
             rpc.clone(),
             rpc_list.clone(),
             ws_conn_incoming_rx,
             broadcast_tx.clone(),
             ws_error_tx.clone(),
-            index,
+            node_id.clone(),
+            keepalive,
         )
-        .await;
+        .await
+        {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("Could not connect to the WS of {}: {}", rpc.url, err);
+                false
+            }
+        };
+
+        // RPCs without a working WS endpoint keep serving HTTP, just not subscriptions
+        if let Some(rpc) = rpc_list
+            .write()
+            .unwrap()
+            .iter_mut()
+            .find(|list_rpc| list_rpc.url == rpc.url)
+        {
+            rpc.status.ws_erroring = !connected;
+        }
+        connections.push(connected.then(|| {
+            WsConnection {
+                ws_url: rpc.ws_url.clone(),
+                node_id,
+                handle: ws_conn_incoming_tx,
+            }
+        }));
     }
 
-    ws_handles
+    connections
 }
 
 pub async fn ws_conn(
@@ -148,41 +361,37 @@ pub async fn ws_conn(
     rpc_list: Arc<RwLock<Vec<Rpc>>>,
     mut incoming_rx: mpsc::UnboundedReceiver<Value>,
     broadcast_tx: broadcast::Sender<IncomingResponse>,
-    ws_error_tx: mpsc::UnboundedSender<WsChannelErr>,
-    index: usize,
-) {
-    let (ws_stream, _) = connect_async(&rpc.ws_url.unwrap())
-        .await
-        .expect("Failed to connect to WS");
+    ws_error_tx: mpsc::Sender<WsChannelErr>,
+    node_id: Arc<AtomicUsize>,
+    keepalive: WsKeepalive,
+) -> Result<(), Error> {
+    let ws_url = rpc
+        .ws_url
+        .as_ref()
+        .ok_or_else(|| Error::Connection("No WS endpoint".to_string()))?;
+    let (ws_stream, _) = connect_async(ws_url).await?;
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
-    // Thread for sending messages
-    let sender_error_tx = ws_error_tx.clone();
-    tokio::spawn(async move {
-        while let Some(incoming) = incoming_rx.recv().await {
-            #[cfg(feature = "debug-verbose")]
-            println!("ws_conn[{}], send: {:?}", index, incoming);
-
-            if ws_sender
-                .send(Message::Text(incoming.to_string()))
-                .await
-                .is_err()
-            {
-                let _ = sender_error_tx.send(WsChannelErr::Closed(index));
-                break;
-            }
-        }
-    });
+    // Notified whenever we hear back from the node
+    let alive = Arc::new(Notify::new());
 
     // Thread for receiving messages
-    tokio::spawn(async move {
+    let receiver_alive = alive.clone();
+    let receiver_node_id = node_id.clone();
+    let sender_error_tx = ws_error_tx.clone();
+    let receiver = tokio::spawn(async move {
         while let Some(message) = ws_receiver.next().await {
+            receiver_alive.notify_one();
             match message {
+                Ok(message) if message.is_close() => break,
+                // tungstenite answers pings by itself, and pongs only tell us the node is alive
+                Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => {}
                 Ok(message) => {
                     let time = Instant::now();
+                    let index = receiver_node_id.load(Ordering::Relaxed);
                     let rax = unsafe { from_str(&mut message.into_text().unwrap()).unwrap() };
                     #[cfg(feature = "debug-verbose")]
-                    println!("ws_conn[{}], recv: {:?}", index, rax);
+                    tracing::debug!("ws_conn[{}], recv: {:?}", index, rax);
 
                     let incoming = IncomingResponse {
                         node_id: index,
@@ -192,38 +401,112 @@ pub async fn ws_conn(
                     let _ = broadcast_tx.send(incoming);
                     let time = time.elapsed();
                     update_rpc_latency(&rpc_list, index, time);
-                    println!("\x1b[35mInfo:\x1b[0m WS request time: {:?}", time);
+                    info!("WS request time: {:?}", time);
                 }
                 Err(_) => {
-                    let _ = ws_error_tx.send(WsChannelErr::Closed(index));
+                    let index = receiver_node_id.load(Ordering::Relaxed);
+                    let _ = ws_error_tx.send(WsChannelErr::Closed(index)).await;
+                    break;
+                }
+            }
+        }
+    });
+
+    // Thread for sending messages
+    tokio::spawn(async move {
+        let pinging = !keepalive.ping_interval.is_zero();
+        let mut pings = interval_at(
+            TokioInstant::now() + keepalive.ping_interval,
+            keepalive.ping_interval.max(Duration::from_millis(1)),
+        );
+        pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut pong_deadline: Option<TokioInstant> = None;
+
+        loop {
+            tokio::select! {
+                incoming = incoming_rx.recv() => {
+                    let incoming = match incoming {
+                        Some(incoming) => incoming,
+                        None => break,
+                    };
+
+                    #[cfg(feature = "debug-verbose")]
+                    tracing::debug!("ws_conn[{}], send: {:?}", node_id.load(Ordering::Relaxed), incoming);
+
+                    if ws_sender
+                        .send(Message::Text(incoming.to_string()))
+                        .await
+                        .is_err()
+                    {
+                        let _ = sender_error_tx.send(WsChannelErr::Closed(node_id.load(Ordering::Relaxed))).await;
+                        break;
+                    }
+                }
+                _ = pings.tick(), if pinging && pong_deadline.is_none() => {
+                    if ws_sender.send(Message::Ping(Vec::new())).await.is_err() {
+                        let _ = sender_error_tx.send(WsChannelErr::Closed(node_id.load(Ordering::Relaxed))).await;
+                        break;
+                    }
+                    pong_deadline = Some(TokioInstant::now() + keepalive.pong_timeout);
+                }
+                _ = alive.notified() => pong_deadline = None,
+                _ = sleep_until(pong_deadline.unwrap_or_else(TokioInstant::now)), if pong_deadline.is_some() => {
+                    warn!(
+                        "WS node {} didn't answer a ping in {:?}, treating it as disconnected.",
+                        node_id.load(Ordering::Relaxed),
+                        keepalive.pong_timeout
+                    );
+                    receiver.abort();
+                    let _ = sender_error_tx.send(WsChannelErr::Closed(node_id.load(Ordering::Relaxed))).await;
                     break;
                 }
             }
         }
+
+        // Our handle was dropped, close the connection
+        let _ = ws_sender.close().await;
     });
+
+    Ok(())
 }
 
 pub async fn execute_ws_call(
     mut call: Value,
     user_id: u32,
-    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    incoming_tx: &mpsc::Sender<WsconnMessage>,
     broadcast_rx: broadcast::Receiver<IncomingResponse>,
     sub_data: &Arc<SubscriptionData>,
+    sub_queue: &SubscriptionQueue,
     cache_args: &CacheArgs,
 ) -> Result<String, Error> {
     let id = call["id"].take();
+
+    // Reject methods we don't serve before touching the cache or any RPC
+    let method = call["method"].as_str().unwrap_or_default();
+    if !cache_args.method_filter.is_allowed(method) {
+        return Ok(cache_args.method_filter.blocked(&id, method).to_string());
+    }
+
+    // Replace block tags if applicable, so we cache by the block they resolve to
+    call = replace_block_tags(
+        &mut call,
+        &cache_args.named_numbers,
+        &cache_args.block_params,
+    );
+
+    let key = cache_key(&call, &cache_args.block_params);
     let tx_hash = {
         #[cfg(not(feature = "xxhash"))]
         {
-            hash(call.to_string().as_bytes())
+            hash(key.as_bytes())
         }
         #[cfg(feature = "xxhash")]
         {
-            xxh3_64(call.to_string().as_bytes())
+            xxh3_64(key.as_bytes())
         }
     };
 
-    if let Ok(Some(mut rax)) = cache_args.cache.get(tx_hash.as_bytes()) {
+    if let Some(mut rax) = cache_lookup_or_miss(&call, tx_hash.as_bytes(), cache_args) {
         let mut cached: Value = from_slice(&mut rax).unwrap();
         cached["id"] = id;
         return Ok(cached.to_string());
@@ -235,73 +518,80 @@ pub async fn execute_ws_call(
         let subscription_id = match call["params"][0].as_str() {
             Some(subscription_id) => subscription_id.to_string(),
             None => {
-                return Ok(format!(
-                    "{{\"jsonrpc\":\"2.0\", \"id\":{}, \"error\": \"Bad Subscription ID!\"}}",
-                    id
-                ));
-            }
-        };
-        // we have to get the id of the subsctiption and what node is subscribed and send the message
-        let index = match sub_data.get_node_from_id(&subscription_id) {
-            Some(rax) => Some(rax),
-            None => {
-                return Ok(format!(
-                    "{{\"jsonrpc\":\"2.0\", \"id\":{}, \"error\": \"false\"}}",
-                    id
-                ));
+                return Ok(jsonrpc_error(&id, -32602, "Bad Subscription ID!", None).to_string());
             }
         };
-        println!("execute_ws_call: index: {:?}", index);
+        let unsubscribed = unsubscribe_client(incoming_tx, sub_data, user_id, &subscription_id);
 
-        sub_data.unsubscribe_user(user_id, subscription_id);
-
-        return Ok(format!(
-            "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":true}}",
-            id
-        ));
+        return Ok(json!({"jsonrpc": "2.0", "id": id, "result": unsubscribed}).to_string());
     }
 
     let is_subscription = call["method"] == "eth_subscribe";
+    let mut _subscribe_guard = None;
     if is_subscription {
+        // Reject malformed subscriptions instead of letting a node return something confusing
+        if let Err(err) = validate_subscription(&call["params"], sub_queue.unknown_subscriptions) {
+            return Ok(
+                jsonrpc_error(&id, -32602, &format!("invalid params: {}", err), None).to_string(),
+            );
+        }
+        canonicalize_subscription(&mut call["params"]);
+
+        // Serve newHeads ourselves if the health check is following the head
+        if sub_queue.local_newheads
+            && call["params"] == json!(["newHeads"])
+            && sub_data.has_local_heads()
+        {
+            let (subscription_id, heads) = sub_data.add_local_subscription(user_id);
+            serve_local_heads(sub_data, user_id, subscription_id.clone(), heads);
+            return Ok(json!({"jsonrpc": "2.0", "id": id, "result": subscription_id}).to_string());
+        }
+
         // Check if we're already subscribed to this
         // if so return the subscription id and add this user to the dispatch
         // if not continue
+        _subscribe_guard = Some(sub_data.lock_subscription(&call).await);
         if let Ok(rax) = sub_data.subscribe_user(user_id, call.clone()) {
-            println!("has subscription already");
-            return Ok(format!(
-                "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{}}}",
-                id, rax
-            ));
+            debug!("has subscription already");
+            return Ok(json!({"jsonrpc": "2.0", "id": id, "result": rax}).to_string());
+        }
+
+        // If all WS nodes are down, hold `logs` subscriptions until one recovers
+        if call["params"][0] == "logs" && sub_queue.wait_for_node().await.is_err() {
+            return Ok(jsonrpc_error(
+                &id,
+                SUBSCRIPTION_UNAVAILABLE,
+                "error: Subscriptions temporarily unavailable, no WS node available! Try again later...",
+                None,
+            )
+            .to_string());
         }
-    } else {
-        // Replace block tags if applicable
-        call = replace_block_tags(&mut call, &cache_args.named_numbers);
     }
 
     call["id"] = user_id.into();
-    incoming_tx.send(WsconnMessage::Message(call.clone(), None))?;
+    incoming_tx
+        .send(WsconnMessage::Message(call.clone(), None))
+        .await?;
     let mut response = listen_for_response(user_id, broadcast_rx).await?;
 
     if is_subscription {
         #[cfg(feature = "debug-verbose")]
-        println!("is subscription!");
+        tracing::debug!("is subscription!");
         #[cfg(feature = "debug-verbose")]
-        println!("response content: {:?}", response.content);
+        tracing::debug!("response content: {:?}", response.content);
         // add the subscription id and add this user to the dispatch
         let sub_id = match response.content["result"].as_str() {
             Some(sub_id) => sub_id.to_string(),
             None => {
-                return Ok(format!(
-                    "\"jsonrpc\":\"2.0\", \"id\":{}, \"error\": \"Bad Subscription ID!\"",
-                    id
-                ));
+                return Ok(jsonrpc_error(&id, -32603, "Bad Subscription ID!", None).to_string());
             }
         };
 
-        println!("\x1b[35mInfo:\x1b[0m sub_id: {}", sub_id);
+        info!("sub_id: {}", sub_id);
         sub_data.register_subscription(call.clone(), sub_id.clone(), response.node_id);
         sub_data.subscribe_user(user_id, call)?;
     } else {
+        cache_args.response_transform.strip(&mut response.content);
         cache_querry(&mut response.content.to_string(), call, tx_hash, cache_args);
     }
 
@@ -324,7 +614,6 @@ async fn listen_for_response(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_json::json;
     use std::time::Duration;
     use tokio::sync::{
         broadcast,
@@ -343,7 +632,7 @@ mod tests {
     }
 
     async fn create_mock_rpc_list() -> Arc<RwLock<Vec<Rpc>>> {
-        let rpc_list = Arc::new(RwLock::new(vec![
+        Arc::new(RwLock::new(vec![
             Rpc::new(
                 "http://test1".to_string(),
                 Some("ws://test1".to_string()),
@@ -358,25 +647,26 @@ mod tests {
                 0,
                 0.0,
             ),
-        ]));
-        rpc_list
+        ]))
     }
 
-    // Helper function to setup the environment for ws_conn_manager tests
-    fn setup_ws_conn_manager_test() -> (
+    type WsConnManagerTest = (
         Arc<RwLock<Vec<Rpc>>>,
-        mpsc::UnboundedSender<WsconnMessage>,
-        mpsc::UnboundedReceiver<WsconnMessage>,
+        mpsc::Sender<WsconnMessage>,
+        mpsc::Receiver<WsconnMessage>,
         broadcast::Sender<IncomingResponse>,
-        mpsc::UnboundedSender<WsChannelErr>,
-    ) {
+        mpsc::Sender<WsChannelErr>,
+    );
+
+    // Helper function to setup the environment for ws_conn_manager tests
+    fn setup_ws_conn_manager_test() -> WsConnManagerTest {
         let rpc_list = Arc::new(RwLock::new(vec![
             mock_rpc("node1.example.com"),
             mock_rpc("node2.example.com"),
         ]));
-        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming_rx) = mpsc::channel(16);
         let (broadcast_tx, _) = broadcast::channel(10);
-        let (ws_error_tx, _) = mpsc::unbounded_channel();
+        let (ws_error_tx, _) = mpsc::channel(16);
 
         (
             rpc_list,
@@ -410,6 +700,7 @@ mod tests {
         let invalid_message = json!({"invalid": "message"});
         incoming_tx
             .send(WsconnMessage::Message(invalid_message, None))
+            .await
             .unwrap();
 
         // Expecting an error response
@@ -424,9 +715,10 @@ mod tests {
         // Test subscriptions
         //
 
-        let (incoming_tx, _incoming_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, _incoming_rx) = mpsc::channel(16);
         let (broadcast_tx, broadcast_rx) = broadcast::channel(10);
         let sub_data = Arc::new(SubscriptionData::new());
+        let sub_queue = SubscriptionQueue::new(&create_mock_rpc_list().await, Duration::ZERO);
         let cache_args = CacheArgs::default();
 
         let call = json!({
@@ -457,6 +749,7 @@ mod tests {
             &incoming_tx,
             broadcast_rx.resubscribe(),
             &sub_data,
+            &sub_queue,
             &cache_args,
         )
         .await;
@@ -491,8 +784,16 @@ mod tests {
             broadcast_tx.send(response).unwrap();
         });
 
-        let result =
-            execute_ws_call(call, 1, &incoming_tx, broadcast_rx, &sub_data, &cache_args).await;
+        let result = execute_ws_call(
+            call,
+            1,
+            &incoming_tx,
+            broadcast_rx,
+            &sub_data,
+            &sub_queue,
+            &cache_args,
+        )
+        .await;
 
         assert!(result.is_ok());
         assert_eq!(
@@ -501,6 +802,209 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_execute_ws_logs_subscription_queued() {
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(16);
+        let (broadcast_tx, broadcast_rx) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+        let cache_args = CacheArgs::default();
+
+        // All WS nodes are in poverty
+        let rpc_list = Arc::new(RwLock::new(Vec::new()));
+        let sub_queue = SubscriptionQueue::new(&rpc_list, Duration::from_secs(5));
+
+        let call = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_subscribe",
+            "params": ["logs", {"address": "0x4200000000000000000000000000000000000006"}]
+        });
+
+        // Recover a node after a while, and answer the subscription once it reaches it
+        let recovered_list = rpc_list.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            recovered_list
+                .write()
+                .unwrap()
+                .push(mock_rpc("node1.example.com"));
+
+            if let Some(WsconnMessage::Message(message, _)) = incoming_rx.recv().await {
+                assert_eq!(message["method"], "eth_subscribe");
+                let response = IncomingResponse {
+                    content: json!({
+                        "jsonrpc": "2.0",
+                        "id": message["id"],
+                        "result": "0xabc"
+                    }),
+                    node_id: 0,
+                };
+                broadcast_tx.send(response).unwrap();
+            }
+        });
+
+        let start = Instant::now();
+        let result = execute_ws_call(
+            call.clone(),
+            1,
+            &incoming_tx,
+            broadcast_rx,
+            &sub_data,
+            &sub_queue,
+            &cache_args,
+        )
+        .await;
+
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(
+            result.unwrap(),
+            "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"0xabc\"}"
+        );
+        assert_eq!(sub_data.get_node_from_id("0xabc"), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_execute_ws_logs_subscription_shared() {
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(16);
+        let (broadcast_tx, _) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+        let cache_args = CacheArgs::default();
+
+        let rpc_list = Arc::new(RwLock::new(vec![mock_rpc("ws://test1")]));
+        let sub_queue = SubscriptionQueue::new(&rpc_list, Duration::ZERO);
+
+        let subscribe = |id: u32, filter: Value| {
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": "eth_subscribe",
+                "params": ["logs", filter]
+            })
+        };
+
+        // Answer the first subscription we get upstream
+        let responder = broadcast_tx.clone();
+        let upstream = tokio::spawn(async move {
+            if let Some(WsconnMessage::Message(message, _)) = incoming_rx.recv().await {
+                let response = IncomingResponse {
+                    content: json!({"jsonrpc": "2.0", "id": message["id"], "result": "0xabc"}),
+                    node_id: 0,
+                };
+                responder.send(response).unwrap();
+            }
+            incoming_rx
+        });
+
+        let address = "0x4200000000000000000000000000000000000006";
+        let result = execute_ws_call(
+            subscribe(1, json!({"address": address})),
+            1,
+            &incoming_tx,
+            broadcast_tx.subscribe(),
+            &sub_data,
+            &sub_queue,
+            &cache_args,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result,
+            "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"0xabc\"}"
+        );
+
+        // The same filter written differently shares the upstream subscription
+        let result = execute_ws_call(
+            subscribe(2, json!({"address": [address, address], "topics": [null]})),
+            2,
+            &incoming_tx,
+            broadcast_tx.subscribe(),
+            &sub_data,
+            &sub_queue,
+            &cache_args,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result,
+            "{\"id\":2,\"jsonrpc\":\"2.0\",\"result\":\"0xabc\"}"
+        );
+        assert_eq!(sub_data.get_users_for_subscription("0xabc").len(), 2);
+        // Only the first subscription reached the nodes
+        assert!(upstream.await.unwrap().try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_execute_ws_malformed_subscription() {
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(16);
+        let (_broadcast_tx, broadcast_rx) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+        let cache_args = CacheArgs::default();
+
+        let rpc_list = Arc::new(RwLock::new(vec![mock_rpc("ws://test1")]));
+        let sub_queue = SubscriptionQueue::new(&rpc_list, Duration::ZERO);
+
+        let call = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_subscribe",
+            "params": ["logs", {"address": "0xnotanaddress"}]
+        });
+
+        let result = execute_ws_call(
+            call,
+            1,
+            &incoming_tx,
+            broadcast_rx,
+            &sub_data,
+            &sub_queue,
+            &cache_args,
+        )
+        .await
+        .unwrap();
+        let result: Value = serde_json::from_str(&result).unwrap();
+
+        assert_eq!(result["id"], 1);
+        assert_eq!(result["error"]["code"], -32602);
+        // Nothing should've been sent to the nodes
+        assert!(incoming_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_execute_ws_logs_subscription_timeout() {
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(16);
+        let (_broadcast_tx, broadcast_rx) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+        let cache_args = CacheArgs::default();
+
+        let rpc_list = Arc::new(RwLock::new(Vec::new()));
+        let sub_queue = SubscriptionQueue::new(&rpc_list, Duration::from_millis(100));
+
+        let call = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_subscribe",
+            "params": ["logs", {}]
+        });
+
+        let result = execute_ws_call(
+            call,
+            1,
+            &incoming_tx,
+            broadcast_rx,
+            &sub_data,
+            &sub_queue,
+            &cache_args,
+        )
+        .await
+        .unwrap();
+        let result: Value = serde_json::from_str(&result).unwrap();
+
+        assert_eq!(result["id"], 1);
+        assert_eq!(result["error"]["code"], SUBSCRIPTION_UNAVAILABLE);
+        // Nothing should've been sent to the nodes
+        assert!(incoming_rx.try_recv().is_err());
+    }
+
     #[tokio::test]
     async fn test_listen_for_response() {
         let (broadcast_tx, broadcast_rx) = broadcast::channel(10);
@@ -530,4 +1034,261 @@ mod tests {
             })
         );
     }
+
+    #[tokio::test]
+    async fn test_execute_ws_local_newheads() {
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(16);
+        let (_broadcast_tx, broadcast_rx) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+        let cache_args = CacheArgs::default();
+        let (user_tx, mut user_rx) = mpsc::unbounded_channel();
+        sub_data.add_user(1, user_tx);
+        sub_data.publish_head(json!({"number": "0x1"}));
+
+        let rpc_list = Arc::new(RwLock::new(vec![mock_rpc("ws://test1")]));
+        let sub_queue = SubscriptionQueue::new(&rpc_list, Duration::ZERO).with_local_newheads(true);
+
+        let call = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_subscribe",
+            "params": ["newHeads"]
+        });
+        let result = execute_ws_call(
+            call,
+            1,
+            &incoming_tx,
+            broadcast_rx.resubscribe(),
+            &sub_data,
+            &sub_queue,
+            &cache_args,
+        )
+        .await
+        .unwrap();
+        let result: Value = serde_json::from_str(&result).unwrap();
+        let subscription_id = result["result"].as_str().unwrap().to_string();
+
+        // Served without subscribing upstream
+        assert!(incoming_rx.try_recv().is_err());
+        sub_data.publish_head(json!({"number": "0x2"}));
+        let head: Value = user_rx.recv().await.unwrap().into();
+        assert_eq!(head["params"]["subscription"], subscription_id.as_str());
+        assert_eq!(head["params"]["result"]["number"], "0x2");
+
+        let call = json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "eth_unsubscribe",
+            "params": [subscription_id]
+        });
+        let result = execute_ws_call(
+            call,
+            1,
+            &incoming_tx,
+            broadcast_rx.resubscribe(),
+            &sub_data,
+            &sub_queue,
+            &cache_args,
+        )
+        .await
+        .unwrap();
+        let result: Value = serde_json::from_str(&result).unwrap();
+
+        assert_eq!(result["result"], true);
+        assert!(incoming_rx.try_recv().is_err());
+        assert!(!sub_data.is_local_subscription(1, &subscription_id));
+
+        // Once the health check's subscription drops, newHeads go upstream again
+        sub_data.clear_local_heads();
+        let call = json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "method": "eth_subscribe",
+            "params": ["newHeads"]
+        });
+        let _ = tokio::time::timeout(
+            Duration::from_millis(100),
+            execute_ws_call(
+                call,
+                1,
+                &incoming_tx,
+                broadcast_rx.resubscribe(),
+                &sub_data,
+                &sub_queue,
+                &cache_args,
+            ),
+        )
+        .await;
+        assert!(incoming_rx.try_recv().is_ok());
+    }
+
+    // WS server that completes the handshake, and only reads (and so answers pings) if `responsive`
+    async fn spawn_ws_server(responsive: bool) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            if responsive {
+                while let Some(Ok(_)) = ws.next().await {}
+            } else {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                drop(ws);
+            }
+        });
+
+        url
+    }
+
+    // Connect to a WS server, returning the connection handle and its errors
+    async fn ws_conn_keepalive(
+        responsive: bool,
+    ) -> (mpsc::UnboundedSender<Value>, mpsc::Receiver<WsChannelErr>) {
+        let url = spawn_ws_server(responsive).await;
+        let rpc = Rpc::new(url.clone(), Some(url), 0, 0, 0.0);
+        let rpc_list = Arc::new(RwLock::new(vec![rpc.clone()]));
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let (broadcast_tx, _) = broadcast::channel(10);
+        let (ws_error_tx, ws_error_rx) = mpsc::channel(16);
+        let keepalive = WsKeepalive {
+            ping_interval: Duration::from_millis(50),
+            pong_timeout: Duration::from_millis(50),
+        };
+
+        ws_conn(
+            rpc,
+            rpc_list,
+            incoming_rx,
+            broadcast_tx,
+            ws_error_tx,
+            Arc::new(AtomicUsize::new(0)),
+            keepalive,
+        )
+        .await
+        .unwrap();
+
+        (incoming_tx, ws_error_rx)
+    }
+
+    #[tokio::test]
+    async fn test_ws_keepalive_detects_half_open() {
+        let (_handle, mut ws_error_rx) = ws_conn_keepalive(false).await;
+
+        let closed = tokio::time::timeout(Duration::from_millis(500), ws_error_rx.recv())
+            .await
+            .unwrap();
+        assert!(matches!(closed, Some(WsChannelErr::Closed(0))));
+    }
+
+    #[tokio::test]
+    async fn test_ws_keepalive_responsive_node() {
+        let (_handle, mut ws_error_rx) = ws_conn_keepalive(true).await;
+
+        // Pongs keep coming back, so the node stays connected
+        let closed = tokio::time::timeout(Duration::from_millis(300), ws_error_rx.recv()).await;
+        assert!(closed.is_err());
+    }
+    #[tokio::test]
+    async fn test_create_ws_vec_skips_dead_ws() {
+        let url = spawn_ws_server(true).await;
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new("http://alive".to_string(), Some(url), 0, 0, 0.0),
+            Rpc::new(
+                "http://dead".to_string(),
+                Some("ws://127.0.0.1:1".to_string()),
+                0,
+                0,
+                0.0,
+            ),
+        ]));
+        rpc_list.write().unwrap()[0].status.ws_erroring = true;
+        let (broadcast_tx, _) = broadcast::channel(10);
+        let (ws_error_tx, _ws_error_rx) = mpsc::channel(16);
+
+        let connections = create_ws_vec(
+            &rpc_list,
+            &mut [],
+            &broadcast_tx,
+            &ws_error_tx,
+            WsKeepalive::default(),
+        )
+        .await;
+        assert!(connections[0].is_some());
+        assert!(connections[1].is_none());
+
+        // Both keep serving HTTP, only the one we could connect to takes subscriptions
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(rpc_list.len(), 2);
+        assert!(!rpc_list[0].status.ws_erroring);
+        assert!(rpc_list[1].status.ws_erroring);
+    }
+
+    #[tokio::test]
+    async fn test_sync_ws_connections() {
+        let ws_rpc = |url: &str| Rpc::new(url.to_string(), Some(url.to_string()), 0, 0, 0.0);
+        let removed = spawn_ws_server(true).await;
+        let kept = spawn_ws_server(true).await;
+        let added = spawn_ws_server(true).await;
+
+        let rpc_list = Arc::new(RwLock::new(vec![ws_rpc(&removed), ws_rpc(&kept)]));
+        let ws_handles = Arc::new(RwLock::new(Vec::new()));
+        let mut connections = Vec::new();
+        let (incoming_tx, _incoming_rx) = mpsc::channel(16);
+        let (broadcast_tx, _) = broadcast::channel(10);
+        let (ws_error_tx, _ws_error_rx) = mpsc::channel(16);
+        let sub_data = Arc::new(SubscriptionData::new());
+        sub_data.register_subscription(json!({"params": ["newHeads"]}), "sub".to_string(), 1);
+
+        update_ws_connections(
+            &rpc_list,
+            &ws_handles,
+            &mut connections,
+            &broadcast_tx,
+            &ws_error_tx,
+            WsKeepalive::default(),
+        )
+        .await;
+        let kept_handle = ws_handles.read().unwrap()[1].clone().unwrap();
+        let removed_handle = ws_handles.read().unwrap()[0].as_ref().unwrap().downgrade();
+
+        *rpc_list.write().unwrap() = vec![ws_rpc(&kept), ws_rpc(&added)];
+        sync_ws_connections(
+            &rpc_list,
+            &ws_handles,
+            &mut connections,
+            &incoming_tx,
+            &broadcast_tx,
+            &ws_error_tx,
+            &sub_data,
+            WsKeepalive::default(),
+        )
+        .await;
+
+        // The kept connection moved to the front along with its subscriptions
+        let handles = ws_handles.read().unwrap().clone();
+        assert!(handles[0].as_ref().unwrap().same_channel(&kept_handle));
+        assert!(handles[1].is_some());
+        assert_eq!(connections[0].as_ref().unwrap().node_id.load(Ordering::Relaxed), 0);
+        assert_eq!(sub_data.get_node_from_id("sub"), Some(0));
+
+        // Nothing holds on to the connection of the removed RPC, so it got closed
+        assert!(removed_handle.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_skip_ws_erroring() {
+        let rpc_list = create_mock_rpc_list().await;
+        rpc_list.write().unwrap()[0].status.ws_erroring = true;
+        let (first_tx, mut first_rx) = mpsc::unbounded_channel();
+        let (second_tx, mut second_rx) = mpsc::unbounded_channel();
+        let ws_handles = Arc::new(RwLock::new(vec![Some(first_tx), Some(second_tx)]));
+
+        for _ in 0..2 {
+            handle_incoming_message(&ws_handles, &rpc_list, json!({"id": 1}), None).await;
+        }
+        assert!(first_rx.try_recv().is_err());
+        assert!(second_rx.try_recv().is_ok());
+        assert!(second_rx.try_recv().is_ok());
+    }
 }
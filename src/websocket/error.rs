@@ -5,6 +5,10 @@ use tokio::sync::{
 };
 use tokio_tungstenite::tungstenite;
 
+// JSON-RPC error code for subscriptions that can't be established because
+// every WS node is down. Unlike malformed requests, these are worth retrying.
+pub const SUBSCRIPTION_UNAVAILABLE: i64 = -32006;
+
 #[derive(Debug)]
 pub enum Error {
     Ws(String),
@@ -17,6 +21,7 @@ pub enum Error {
     FailedParsing(),
     MissingSubscription(),
     EmptyList(String),
+    NoWsNode,
     // SubscriptionError(String),
     // RpcError(String),
     NoWsResponse,
@@ -41,6 +46,7 @@ impl fmt::Display for Error {
                 write!(f, "Tried to Perform Action On Non-Existing Subscription!")
             }
             Error::EmptyList(msg) => write!(f, "Tried to Access Empty List: {}", msg),
+            Error::NoWsNode => write!(f, "No WS Node Available to Establish Subscription"),
             // Error::SubscriptionError(msg) => write!(f, "Subscription Error: {}", msg),
             // Error::RpcError(msg) => write!(f, "RPC Error: {}", msg),
             Error::NoWsResponse => write!(f, "Failed to Receive Response from WS"),
@@ -1,14 +1,33 @@
-use std::sync::Arc;
+use tracing::{
+    debug,
+    info,
+    warn,
+};
+
+use std::{
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{
-    balancer::processing::CacheArgs,
+    balancer::processing::{
+        jsonrpc_error,
+        CacheArgs,
+    },
     websocket::{
         client::execute_ws_call,
         error::Error,
+        subscription_manager::{
+            expire_subscription,
+            remove_ws_user,
+            SubscriptionQueue,
+        },
         types::{
             IncomingResponse,
+            NotificationQueue,
             RequestResult,
             SubscriptionData,
+            UserData,
             WsconnMessage,
         },
     },
@@ -21,6 +40,7 @@ use tokio::sync::{
     mpsc,
 };
 
+use serde_json::Value;
 use simd_json::from_str;
 
 use futures::{
@@ -35,12 +55,19 @@ use hyper_tungstenite::{
 use tungstenite::Message;
 
 /// Handle a websocket connection.
+///
+/// Subscription notifications are buffered apart from call responses, up to
+/// `notification_buffer` of them, so a slow client only loses its own oldest ones.
+#[allow(clippy::too_many_arguments)]
 pub async fn serve_websocket(
     websocket: HyperWebsocket,
-    incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
+    incoming_tx: mpsc::Sender<WsconnMessage>,
     outgoing_rx: broadcast::Receiver<IncomingResponse>,
     sub_data: Arc<SubscriptionData>,
+    sub_queue: SubscriptionQueue,
     cache_args: CacheArgs,
+    max_subscription_lifetime: Duration,
+    notification_buffer: usize,
 ) -> Result<(), Error> {
     let websocket = websocket.await?;
 
@@ -56,40 +83,69 @@ pub async fn serve_websocket(
     let user_id = random::<u32>();
 
     // Add the user to the sink map
-    println!("\x1b[35mInfo:\x1b[0m Adding user {} to sink map", user_id);
-    let user_data = tx.clone();
+    info!("Adding user {} to sink map", user_id);
+    let notifications = Arc::new(NotificationQueue::new(notification_buffer));
+    let user_data = UserData::new(tx.clone()).with_notification_queue(notifications.clone());
     sub_data.add_user(user_id, user_data);
 
     let sub_data_clone = sub_data.clone();
+    let incoming_tx_clone = incoming_tx.clone();
 
     // Spawn taks for sending messages to the client
     tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => msg,
+                notification = notifications.pop() => Some(RequestResult::Subscription(notification)),
+            };
+            let Some(msg) = msg else {
+                break;
+            };
+
             // Forward the message to the best available RPC
             //
             // If we received a subscription, just send it to the client
             match msg {
                 RequestResult::Call(call) => {
+                    let is_subscription = call["method"] == "eth_subscribe";
                     let resp = match execute_ws_call(
                         call,
                         user_id,
                         &incoming_tx,
                         outgoing_rx.resubscribe(),
                         &sub_data_clone,
+                        &sub_queue,
                         &cache_args,
                     )
                     .await
                     {
                         Ok(rax) => rax,
-                        Err(e) => format!("{{\"error\": \"{}\"}}", e),
+                        Err(e) => {
+                            jsonrpc_error(&Value::Null, -32603, &e.to_string(), None).to_string()
+                        }
                     };
 
+                    // Cancel the subscription after its max lifetime, if set
+                    if is_subscription {
+                        if let Ok(resp) = serde_json::from_str::<Value>(&resp) {
+                            if let Some(subscription_id) = resp["result"].as_str() {
+                                expire_subscription(
+                                    &incoming_tx,
+                                    &sub_data_clone,
+                                    user_id,
+                                    subscription_id.to_string(),
+                                    max_subscription_lifetime,
+                                );
+                            }
+                        }
+                    }
+
                     match websocket_sink.send(Message::text::<String>(resp)).await {
                         Ok(_) => {}
                         Err(e) => {
                             // Remove the user from the sink map
-                            sub_data_clone.remove_user(user_id);
-                            println!("\x1b[93mWrn:\x1b[0m Error sending call: {}", e);
+                            remove_ws_user(&incoming_tx, &sub_data_clone, user_id);
+                            warn!("Error sending call: {}", e);
                             break;
                         }
                     }
@@ -102,7 +158,7 @@ pub async fn serve_websocket(
                         Ok(_) => {}
                         Err(e) => {
                             // Remove the user from the sink map
-                            sub_data_clone.remove_user(user_id);
+                            remove_ws_user(&incoming_tx, &sub_data_clone, user_id);
                             return Err(Error::MessageSendFailed((e).to_string()));
                         }
                     }
@@ -115,7 +171,7 @@ pub async fn serve_websocket(
     while let Some(message) = websocket_stream.next().await {
         match message {
             Ok(Message::Text(mut msg)) => {
-                println!("\x1b[35mInfo:\x1b[0m Received WS text message: {msg}");
+                info!("Received WS text message: {msg}");
                 // Send message to the channel
                 let rax = match unsafe { from_str(&mut msg) } {
                     Ok(rax) => rax,
@@ -126,22 +182,25 @@ pub async fn serve_websocket(
             }
             Ok(Message::Close(msg)) => {
                 if let Some(msg) = &msg {
-                    println!(
-                        "\x1b[35mInfo:\x1b[0m Received close message with code {} and message: {}",
+                    info!(
+                        "Received close message with code {} and message: {}",
                         msg.code, msg.reason
                     );
                 } else {
-                    println!("Received close message");
+                    debug!("Received close message");
                 }
             }
             Err(e) => {
                 // Remove the user from the sink map
-                sub_data.remove_user(user_id);
+                remove_ws_user(&incoming_tx_clone, &sub_data, user_id);
                 return Err(Error::MessageReceptionFailed(e.to_string()));
             }
             _ => {}
         }
     }
 
+    // The client is gone, so drop the subscriptions only it needed
+    remove_ws_user(&incoming_tx_clone, &sub_data, user_id);
+
     Ok(())
 }
@@ -1,12 +1,21 @@
 use crate::{
-    config::setup::{
-        MAGIC,
-        WS_SUB_MANAGER_ID,
+    config::{
+        setup::{
+            MAGIC,
+            WS_SUB_MANAGER_ID,
+        },
+        types::UnknownSubscriptions,
+    },
+    rpc::types::{
+        hex_to_decimal,
+        Rpc,
     },
+    warn_limited,
     websocket::{
         error::Error,
         types::{
             IncomingResponse,
+            NodeSubInfo,
             RequestResult,
             SubscriptionData,
             WsconnMessage,
@@ -14,33 +23,568 @@ use crate::{
     },
 };
 
+use tracing::{
+    error,
+    info,
+    warn,
+};
+
 use std::{
     collections::HashMap,
-    sync::Arc,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
-use tokio::sync::{
-    broadcast::{
-        self,
-        error::RecvError,
+use tokio::{
+    sync::{
+        broadcast::{
+            self,
+            error::RecvError,
+        },
+        mpsc::{
+            self,
+            error::TrySendError,
+        },
     },
-    mpsc,
+    time::sleep,
+};
+
+use serde_json::{
+    json,
+    Value,
 };
 
-use serde_json::json;
+// How often to check if a WS node came back while a subscription is queued
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Unsubscribe from a subscription on the node it lives on
+pub fn unsubscribe_upstream(
+    incoming_tx: &mpsc::Sender<WsconnMessage>,
+    node_sub_info: &NodeSubInfo,
+) {
+    let unsub = json!({"jsonrpc": "2.0","id": WS_SUB_MANAGER_ID,"method": "eth_unsubscribe","params": [node_sub_info.subscription_id]});
+    let message = WsconnMessage::Message(unsub, Some(node_sub_info.node_id));
+    if let Err(TrySendError::Full(_)) = incoming_tx.try_send(message) {
+        warn_limited!(
+            "WS connection manager can't keep up, dropping unsubscribe from node {}",
+            node_sub_info.node_id
+        );
+    }
+}
+
+// Handle a client's `eth_unsubscribe`.
+//
+// Returns false if the user isn't subscribed to `subscription_id`. The upstream
+// subscription is only unsubscribed from once nobody else shares it.
+pub fn unsubscribe_client(
+    incoming_tx: &mpsc::Sender<WsconnMessage>,
+    sub_data: &SubscriptionData,
+    user_id: u32,
+    subscription_id: &str,
+) -> bool {
+    // Local subscriptions never made it upstream
+    if sub_data.remove_local_subscription(user_id, subscription_id) {
+        return true;
+    }
+
+    if !sub_data
+        .get_users_for_subscription(subscription_id)
+        .contains(&user_id)
+    {
+        return false;
+    }
+
+    if let Some(node_sub_info) = sub_data.unsubscribe_user(user_id, subscription_id.to_string()) {
+        unsubscribe_upstream(incoming_tx, &node_sub_info);
+    }
+
+    true
+}
+
+// Remove a user and unsubscribe from everything nobody else is subscribed to
+pub fn remove_ws_user(
+    incoming_tx: &mpsc::Sender<WsconnMessage>,
+    sub_data: &SubscriptionData,
+    user_id: u32,
+) {
+    for node_sub_info in sub_data.remove_user(user_id) {
+        unsubscribe_upstream(incoming_tx, &node_sub_info);
+    }
+}
+
+// Cancel a user's subscription once it's been active for `lifetime`.
+//
+// A `lifetime` of 0 means subscriptions live forever.
+pub fn expire_subscription(
+    incoming_tx: &mpsc::Sender<WsconnMessage>,
+    sub_data: &Arc<SubscriptionData>,
+    user_id: u32,
+    subscription_id: String,
+    lifetime: Duration,
+) {
+    if lifetime.is_zero() {
+        return;
+    }
+
+    let generation = sub_data.start_expiry(user_id, &subscription_id);
+    let incoming_tx = incoming_tx.clone();
+    let sub_data = sub_data.clone();
+    tokio::spawn(async move {
+        sleep(lifetime).await;
+        // Unsubscribed since, maybe subscribing again to the same id
+        if !sub_data.take_expiry(user_id, &subscription_id, generation) {
+            return;
+        }
+
+        if let Some(orphaned) =
+            sub_data.cancel_user_subscription(user_id, &subscription_id, "max lifetime reached")
+        {
+            info!(
+                "Cancelled subscription {} for user {}, max lifetime reached.",
+                subscription_id, user_id
+            );
+            if let Some(node_sub_info) = orphaned {
+                unsubscribe_upstream(&incoming_tx, &node_sub_info);
+            }
+        }
+    });
+}
+
+// Send every head published by the health check to a local newHeads subscription,
+// until it gets unsubscribed or the user leaves.
+//
+// Heads arrive in the order they were seen. If the user falls too far behind to
+// keep up, the subscription gets cancelled instead of silently skipping heads.
+pub fn serve_local_heads(
+    sub_data: &Arc<SubscriptionData>,
+    user_id: u32,
+    subscription_id: String,
+    mut heads: broadcast::Receiver<Value>,
+) {
+    let sub_data = sub_data.clone();
+    tokio::spawn(async move {
+        loop {
+            let header = match heads.recv().await {
+                Ok(header) => header,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "Local newHeads subscription {} fell {} heads behind!",
+                        subscription_id, skipped
+                    );
+                    sub_data.cancel_user_subscription(
+                        user_id,
+                        &subscription_id,
+                        "too many heads missed",
+                    );
+                    return;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            if !sub_data.is_local_subscription(user_id, &subscription_id) {
+                return;
+            }
+
+            let notification = json!({
+                "jsonrpc": "2.0",
+                "method": "eth_subscription",
+                "params": {
+                    "subscription": subscription_id,
+                    "result": header,
+                },
+            });
+            if !sub_data.send_to_user(user_id, RequestResult::Subscription(notification)) {
+                sub_data.remove_local_subscription(user_id, &subscription_id);
+                return;
+            }
+        }
+    });
+}
+
+// Returns true if `value` is a hex string of exactly `len` bytes
+fn is_hex_bytes(value: &Value, len: usize) -> bool {
+    match value.as_str().and_then(|value| value.strip_prefix("0x")) {
+        Some(hex) => hex.len() == len * 2 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+fn validate_logs_filter(filter: &Value) -> Result<(), String> {
+    let filter = match filter {
+        Value::Null => return Ok(()),
+        Value::Object(filter) => filter,
+        _ => return Err("logs filter must be an object".to_string()),
+    };
+
+    match filter.get("address") {
+        None | Some(Value::Null) => {}
+        Some(Value::Array(addresses)) if addresses.iter().all(|a| is_hex_bytes(a, 20)) => {}
+        Some(address) if is_hex_bytes(address, 20) => {}
+        Some(_) => return Err("invalid address in logs filter".to_string()),
+    }
+
+    match filter.get("topics") {
+        None | Some(Value::Null) => {}
+        Some(Value::Array(topics)) if topics.len() <= 4 => {
+            for topic in topics {
+                let valid = match topic {
+                    Value::Null => true,
+                    Value::Array(options) => {
+                        options
+                            .iter()
+                            .all(|option| option.is_null() || is_hex_bytes(option, 32))
+                    }
+                    topic => is_hex_bytes(topic, 32),
+                };
+                if !valid {
+                    return Err("invalid topic in logs filter".to_string());
+                }
+            }
+        }
+        Some(_) => {
+            return Err("topics in logs filter must be an array of up to 4 topics".to_string())
+        }
+    }
+
+    Ok(())
+}
+
+// Lowercase and sort `values`, dropping duplicates. A single value isn't wrapped in an array.
+fn canonical_set(values: &[Value]) -> Value {
+    let mut values: Vec<String> = values
+        .iter()
+        .filter_map(|value| value.as_str().map(str::to_lowercase))
+        .collect();
+    values.sort();
+    values.dedup();
+
+    match values.len() {
+        1 => Value::String(values.remove(0)),
+        _ => values.into(),
+    }
+}
+
+// Rewrite a valid logs filter so filters matching the same logs look the same.
+//
+// Addresses and topics are case insensitive and their order within a set doesn't
+// matter. Topic sets with a wildcard, and trailing wildcards, match anything.
+fn canonical_logs_filter(filter: &Value) -> Value {
+    let mut filter = match filter {
+        Value::Object(filter) => filter.clone(),
+        _ => return json!({}),
+    };
+
+    match filter.remove("address") {
+        Some(Value::Array(addresses)) if !addresses.is_empty() => {
+            filter.insert("address".to_string(), canonical_set(&addresses));
+        }
+        Some(address @ Value::String(_)) => {
+            filter.insert("address".to_string(), canonical_set(&[address]));
+        }
+        _ => {}
+    }
+
+    if let Some(Value::Array(topics)) = filter.remove("topics") {
+        let mut topics: Vec<Value> = topics
+            .iter()
+            .map(|topic| {
+                match topic {
+                    Value::Array(options)
+                        if options.is_empty() || options.contains(&Value::Null) =>
+                    {
+                        Value::Null
+                    }
+                    Value::Array(options) => canonical_set(options),
+                    Value::String(topic) => Value::String(topic.to_lowercase()),
+                    _ => Value::Null,
+                }
+            })
+            .collect();
+        while topics.last() == Some(&Value::Null) {
+            topics.pop();
+        }
+        if !topics.is_empty() {
+            filter.insert("topics".to_string(), topics.into());
+        }
+    }
+
+    Value::Object(filter)
+}
+
+// Rewrite the params of a valid `eth_subscribe` request so identical subscriptions
+// share one upstream subscription, even if clients wrote them differently.
+pub fn canonicalize_subscription(params: &mut Value) {
+    if params[0] == "logs" {
+        if let Some(filter) = params.get_mut(1) {
+            *filter = canonical_logs_filter(filter);
+        }
+    }
+}
+
+// Check the params of an `eth_subscribe` request before we send it to a node.
+//
+// Returns an error message for the client if the params are malformed.
+pub fn validate_subscription(params: &Value, unknown: UnknownSubscriptions) -> Result<(), String> {
+    let params = params
+        .as_array()
+        .ok_or_else(|| "params must be an array".to_string())?;
+    let kind = params
+        .first()
+        .and_then(Value::as_str)
+        .ok_or_else(|| "missing subscription type".to_string())?;
+
+    match kind {
+        "newHeads" | "syncing" => Ok(()),
+        "logs" => params.get(1).map_or(Ok(()), validate_logs_filter),
+        "newPendingTransactions" => {
+            match params.get(1) {
+                None | Some(Value::Bool(_)) => Ok(()),
+                Some(_) => Err("newPendingTransactions option must be a bool".to_string()),
+            }
+        }
+        _ if unknown == UnknownSubscriptions::Forward => Ok(()),
+        _ => Err(format!("unsupported subscription type: {}", kind)),
+    }
+}
+
+// Holds subscriptions while all WS nodes are in poverty.
+//
+// Subscriptions wait until a node recovers, or until `timeout` passes,
+// after which the client should be sent an error.
+#[derive(Debug, Clone)]
+pub struct SubscriptionQueue {
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    timeout: Duration,
+    // What to do with subscriptions we can't validate before they're established
+    pub unknown_subscriptions: UnknownSubscriptions,
+    // Serve newHeads subscriptions from the health check's heads when we can
+    pub local_newheads: bool,
+}
+
+impl SubscriptionQueue {
+    pub fn new(rpc_list: &Arc<RwLock<Vec<Rpc>>>, timeout: Duration) -> Self {
+        SubscriptionQueue {
+            rpc_list: rpc_list.clone(),
+            timeout,
+            unknown_subscriptions: UnknownSubscriptions::default(),
+            local_newheads: false,
+        }
+    }
+
+    pub fn with_unknown_subscriptions(mut self, policy: UnknownSubscriptions) -> Self {
+        self.unknown_subscriptions = policy;
+        self
+    }
+
+    pub fn with_local_newheads(mut self, local_newheads: bool) -> Self {
+        self.local_newheads = local_newheads;
+        self
+    }
+
+    fn has_ws_node(&self) -> bool {
+        self.rpc_list
+            .read()
+            .unwrap()
+            .iter()
+            .any(|rpc| rpc.ws_url.is_some() && !rpc.status.ws_erroring)
+    }
+
+    // Wait until a WS node can take the subscription
+    pub async fn wait_for_node(&self) -> Result<(), Error> {
+        let start = Instant::now();
+        while !self.has_ws_node() {
+            if start.elapsed() >= self.timeout {
+                return Err(Error::NoWsNode);
+            }
+            sleep(QUEUE_POLL_INTERVAL).await;
+        }
+
+        Ok(())
+    }
+}
+
+// Tracks the last head sent for every `newHeads` subscription.
+//
+// Heads can arrive out of order or duplicated when reconnecting or when moving
+// subscriptions between nodes. Heads are keyed by the subscription params
+// so we keep track of them even if the subscription moves to a new node.
+#[derive(Debug, Default)]
+struct HeadOrdering {
+    last_heads: HashMap<String, (u64, Option<String>)>,
+}
+
+impl HeadOrdering {
+    // Returns false if the head is older than, or the same as, the last one we sent.
+    //
+    // Heads with the same number but a different hash are reorgs, so we let them through.
+    fn is_in_order(&mut self, subscription: &str, notification: &Value) -> bool {
+        let head = &notification["params"]["result"];
+        let number = match head["number"].as_str().map(hex_to_decimal) {
+            Some(Ok(number)) => number,
+            // Not a head, nothing to order
+            _ => return true,
+        };
+        let hash = head["hash"].as_str().map(|hash| hash.to_owned());
+
+        if let Some((last_number, last_hash)) = self.last_heads.get(subscription) {
+            if number < *last_number || (number == *last_number && hash == *last_hash) {
+                return false;
+            }
+        }
+
+        self.last_heads
+            .insert(subscription.to_owned(), (number, hash));
+        true
+    }
+}
+
+// Limits on the notifications a single subscription can push through the dispatcher,
+// so one misbehaving node can't flood every client.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NotificationLimits {
+    // Max notifications a subscription gets per second, 0 is unlimited
+    pub max_per_second: u32,
+    // Only deliver the latest head of a newHeads subscription to clients that are behind
+    pub coalesce_new_heads: bool,
+}
+
+// Counts the notifications of every subscription over the current second
+#[derive(Debug, Default)]
+struct NotificationRateCap {
+    max_per_second: u32,
+    windows: HashMap<String, (Instant, u32)>,
+}
+
+impl NotificationRateCap {
+    // Subscriptions we keep counting before forgetting ones that went quiet
+    const MAX_TRACKED: usize = 1024;
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    fn new(max_per_second: u32) -> Self {
+        NotificationRateCap {
+            max_per_second,
+            windows: HashMap::new(),
+        }
+    }
+
+    // Returns false if the subscription already got its share for this second
+    fn allow(&mut self, subscription_id: &str, now: Instant) -> bool {
+        if self.max_per_second == 0 {
+            return true;
+        }
+
+        if self.windows.len() >= Self::MAX_TRACKED {
+            self.windows
+                .retain(|_, (start, _)| now.duration_since(*start) < Self::WINDOW);
+        }
+
+        let (start, count) = self
+            .windows
+            .entry(subscription_id.to_owned())
+            .or_insert((now, 0));
+        if now.duration_since(*start) >= Self::WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.max_per_second {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+fn is_new_heads(subscription: &str) -> bool {
+    serde_json::from_str::<Value>(subscription)
+        .is_ok_and(|params| params[0].as_str() == Some("newHeads"))
+}
+
+// Tracks gaps in the notifications of every subscription.
+//
+// Moving a subscription to another node can take a while, during which
+// notifications are lost. Like `HeadOrdering`, keyed by the subscription params.
+#[derive(Debug, Default)]
+struct GapTracker {
+    // Last notification time and head number
+    last_seen: HashMap<String, (Instant, Option<u64>)>,
+}
+
+impl GapTracker {
+    // Record a notification and log how much was missed if the subscription was moved
+    fn observe(
+        &mut self,
+        subscription: &str,
+        subscription_id: &str,
+        notification: &Value,
+        moved: bool,
+    ) -> Option<(u64, u64)> {
+        let number = notification["params"]["result"]["number"]
+            .as_str()
+            .and_then(|number| hex_to_decimal(number).ok());
+
+        let (last_time, last_number) = self
+            .last_seen
+            .insert(subscription.to_owned(), (Instant::now(), number))?;
+
+        if moved {
+            info!(
+                "Subscription {} resumed on a new node, {}ms since its last notification.",
+                subscription_id,
+                last_time.elapsed().as_millis()
+            );
+        }
+
+        match (last_number, number) {
+            (Some(last_number), Some(number)) if number > last_number + 1 => {
+                warn!(
+                    "Subscription {} missed heads {} to {}!",
+                    subscription_id,
+                    last_number + 1,
+                    number - 1
+                );
+                Some((last_number + 1, number - 1))
+            }
+            _ => None,
+        }
+    }
+}
 
 // Sends all subscriptions to their relevant nodes
+//
+// Notifications are dispatched one at a time, in the order we receive them.
+// If `drop_stale_heads` is set, out of order and duplicate heads are dropped.
+// Notifications over the `limits` of their subscription are dropped too. Sending
+// never waits on clients, so a slow one can't hold up everyone else.
 pub async fn subscription_dispatcher(
     mut rx: broadcast::Receiver<IncomingResponse>,
-    incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
+    incoming_tx: mpsc::Sender<WsconnMessage>,
     sub_data: Arc<SubscriptionData>,
+    drop_stale_heads: bool,
+    limits: NotificationLimits,
 ) -> Result<(), Error> {
+    let mut head_ordering = HeadOrdering::default();
+    let mut gaps = GapTracker::default();
+    let mut rate_cap = NotificationRateCap::new(limits.max_per_second);
+
     loop {
         // Receive the WS response
         let response = match rx.recv().await {
             Ok(rax) => rax,
             Err(RecvError::Closed) => return Err(Error::ChannelClosed()),
-            Err(RecvError::Lagged(_)) => return Err(Error::ReceiverLagged()),
+            // Skip what we missed instead of giving up on every subscription
+            Err(RecvError::Lagged(skipped)) => {
+                warn_limited!(
+                    "Subscription dispatcher fell behind! Skipped {} messages.",
+                    skipped
+                );
+                continue;
+            }
         };
 
         // Check if its a subscription
@@ -49,42 +593,67 @@ pub async fn subscription_dispatcher(
         }
 
         #[cfg(feature = "debug-verbose")]
-        println!(
+        tracing::debug!(
             "subscription_dispatcher: received subscription: {}",
             response.content
         );
 
         // Get the subscription id
-        // this is retarded???
-        let resp_clone = response.clone();
-        let id = match response.content["params"]["subscription"].as_str() {
-            Some(rax) => rax,
+        let mut notification = response.content;
+        let upstream_id = match notification["params"]["subscription"].as_str() {
+            Some(rax) => rax.to_owned(),
             None => continue, // if this doesnt exist something in the pipeline is wrong and should be ignored
         };
 
+        // Moved subscriptions have a new id upstream, clients still know them by the old one
+        let id = sub_data.client_sub_id(response.node_id, &upstream_id);
+        if id != upstream_id {
+            notification["params"]["subscription"] = Value::String(id.clone());
+        }
+
+        let subscription = sub_data
+            .get_params_by_sub_id(&id)
+            .unwrap_or_else(|| id.clone());
+
+        if drop_stale_heads && !head_ordering.is_in_order(&subscription, &notification) {
+            warn!("Dropping out of order head for subscription: {}", id);
+            continue;
+        }
+
+        if !rate_cap.allow(&id, Instant::now()) {
+            warn_limited!(
+                "Subscription {} is over {} notifications per second! Dropping notifications.",
+                id,
+                limits.max_per_second
+            );
+            continue;
+        }
+
+        gaps.observe(&subscription, &id, &notification, sub_data.take_moved(&id));
+
         // Send the response to all the users
         match sub_data
             .dispatch_to_subscribers(
-                id,
+                &id,
                 response.node_id,
-                &RequestResult::Subscription(resp_clone.content),
+                &RequestResult::Subscription(notification),
+                limits.coalesce_new_heads && is_new_heads(&subscription),
             )
             .await
         {
             // Getting true means that we should unsubscribe from the subscription
             // as thre are no more users needing it.
             Ok(true) => {
-                let unsub = json!({"jsonrpc": "2.0","id": WS_SUB_MANAGER_ID,"method": "eth_unsubscribe","params": [id]});
-                let message = WsconnMessage::Message(unsub, Some(response.node_id));
-                let _ = incoming_tx.send(message);
+                let node_sub_info = NodeSubInfo {
+                    node_id: response.node_id,
+                    subscription_id: upstream_id,
+                };
+                unsubscribe_upstream(&incoming_tx, &node_sub_info);
             }
             // False means tht we do not need to do anything
             Ok(false) => {}
             Err(e) => {
-                println!(
-                    "\x1b[31mErr:\x1b[0m Fatal error while trying to send subscriptions: {}",
-                    e
-                )
+                error!("Fatal error while trying to send subscriptions: {}", e)
             }
         };
     }
@@ -93,7 +662,7 @@ pub async fn subscription_dispatcher(
 // Moves all subscriptions from one node to another one.
 // Used during node failiure. Do not use this liberally as it is very heavy.
 pub async fn move_subscriptions(
-    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    incoming_tx: &mpsc::Sender<WsconnMessage>,
     mut rx: broadcast::Receiver<IncomingResponse>,
     sub_data: &Arc<SubscriptionData>,
     node_id: usize,
@@ -103,10 +672,14 @@ pub async fn move_subscriptions(
     let ids = sub_data.get_sub_id_by_node(node_id);
 
     // We want to send unsubscribe messages (for postoriety) to node_id
-    for id in ids {
-        let unsub = json!({"jsonrpc": "2.0","id": WS_SUB_MANAGER_ID,"method": "eth_unsubscribe","params": [id]});
-        let message = WsconnMessage::Message(unsub, Some(node_id));
-        let _ = incoming_tx.send(message);
+    for subscription_id in ids {
+        unsubscribe_upstream(
+            incoming_tx,
+            &NodeSubInfo {
+                node_id,
+                subscription_id,
+            },
+        );
     }
 
     // We want to send subscription messages to `target`, register them, and move over the users
@@ -114,13 +687,18 @@ pub async fn move_subscriptions(
     let mut pairs: HashMap<u32, String> = HashMap::new();
     let mut id = WS_SUB_MANAGER_ID + MAGIC;
     for params in subs {
+        let sub_params: Value = match serde_json::from_str(&params) {
+            Ok(sub_params) => sub_params,
+            Err(_) => continue,
+        };
+
         id += 1;
-        let sub = json!({"jsonrpc": "2.0","id": id,"method": "eth_subscribe","params": vec![params.clone()]});
+        let sub = json!({"jsonrpc": "2.0","id": id,"method": "eth_subscribe","params": sub_params});
         let message = WsconnMessage::Message(sub, None);
 
         pairs.insert(id, params);
 
-        let _ = incoming_tx.send(message);
+        let _ = incoming_tx.send(message).await;
     }
 
     // Listen on `rx` for incoming messages.
@@ -128,27 +706,38 @@ pub async fn move_subscriptions(
     while !pairs.is_empty() {
         let response = rx.recv().await?;
 
-        // Discard any response that does not have a proper ID
+        // Discard notifications and anything else without a proper ID
         let pair_id = match response.content["id"].as_u64() {
             Some(rax) => rax as u32,
-            None => return Err(Error::InvalidData("No ID in response!".to_string())),
-        };
-
-        let params = match pairs.get(&pair_id) {
-            Some(rax) => rax.to_string(),
             None => continue,
         };
 
-        let sub_id = match sub_data.get_sub_id_by_params(&params) {
+        let params = match pairs.remove(&pair_id) {
             Some(rax) => rax,
-            None => return Err(Error::MissingSubscription()),
+            None => continue,
         };
-        match sub_data.move_subscriptions(response.node_id, params, sub_id) {
-            Ok(_) => {}
-            Err(err) => return Err(err),
+
+        // The new node gives the subscription a new id
+        let upstream_id = match response.content["result"].as_str() {
+            Some(rax) => rax.to_owned(),
+            None => {
+                error!("Could not resubscribe to {}: {}", params, response.content);
+                continue;
+            }
         };
 
-        pairs.remove(&pair_id);
+        // Nobody needs it anymore, don't leave it dangling on the new node
+        if let Err(err) = sub_data.move_subscriptions(response.node_id, params, upstream_id.clone())
+        {
+            warn!("Not moving subscription: {}", err);
+            unsubscribe_upstream(
+                incoming_tx,
+                &NodeSubInfo {
+                    node_id: response.node_id,
+                    subscription_id: upstream_id,
+                },
+            );
+        }
     }
 
     Ok(())
@@ -157,19 +746,230 @@ pub async fn move_subscriptions(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::websocket::types::{
+        NotificationQueue,
+        UserData,
+    };
     use rand::Rng;
     use serde_json::json;
     use std::sync::Arc;
-    use std::time::Duration;
     use tokio::sync::{
         broadcast,
         mpsc,
     };
 
+    fn ws_rpc() -> Rpc {
+        Rpc::new(
+            "http://test1".to_string(),
+            Some("ws://test1".to_string()),
+            0,
+            0,
+            0.0,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_subscription_queue_available() {
+        let rpc_list = Arc::new(RwLock::new(vec![ws_rpc()]));
+        let queue = SubscriptionQueue::new(&rpc_list, Duration::ZERO);
+
+        assert!(queue.wait_for_node().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_queue_timeout() {
+        let rpc_list = Arc::new(RwLock::new(Vec::new()));
+        let queue = SubscriptionQueue::new(&rpc_list, Duration::from_millis(100));
+
+        let start = Instant::now();
+        assert!(matches!(queue.wait_for_node().await, Err(Error::NoWsNode)));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_unsubscribe_upstream_when_full() {
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(1);
+        let node_sub_info = |subscription_id: &str| {
+            NodeSubInfo {
+                node_id: 0,
+                subscription_id: subscription_id.to_string(),
+            }
+        };
+
+        // The second unsubscribe gets dropped instead of queued
+        unsubscribe_upstream(&incoming_tx, &node_sub_info("0x1"));
+        unsubscribe_upstream(&incoming_tx, &node_sub_info("0x2"));
+
+        match incoming_rx.try_recv() {
+            Ok(WsconnMessage::Message(unsub, Some(0))) => assert_eq!(unsub["params"][0], "0x1"),
+            _ => panic!("expected an eth_unsubscribe"),
+        }
+        assert!(incoming_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_validate_logs_subscription() {
+        let address = "0x4200000000000000000000000000000000000006";
+        let topic = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+        // Valid
+        for params in [
+            json!(["logs"]),
+            json!(["logs", {}]),
+            json!(["logs", {"address": address, "topics": [topic, null, [topic, null]]}]),
+            json!(["logs", {"address": [address, address]}]),
+        ] {
+            assert!(validate_subscription(&params, UnknownSubscriptions::Forward).is_ok());
+        }
+
+        // Malformed
+        for params in [
+            json!(["logs", "0x1"]),
+            json!(["logs", {"address": "0x42"}]),
+            json!(["logs", {"topics": topic}]),
+            json!(["logs", {"topics": ["0xzz"]}]),
+            json!(["logs", {"topics": [null, null, null, null, null]}]),
+            json!([1]),
+            json!([]),
+        ] {
+            assert!(
+                validate_subscription(&params, UnknownSubscriptions::Forward).is_err(),
+                "{}",
+                params
+            );
+        }
+    }
+
+    #[test]
+    fn test_canonicalize_logs_subscription() {
+        let weth = "0x4200000000000000000000000000000000000006";
+        let usdc = "0x0b2c639c533813f4aa9d7837caf62653d097ff85";
+        let transfer = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+        let approval = "0x8c5be1e5ebec7d5bd14f71427d1e84f3dd0314c0f7b2291e5b200ac8c7c3b925";
+
+        let canonical = |mut params: Value| {
+            canonicalize_subscription(&mut params);
+            params
+        };
+
+        // Same logs, written differently
+        let filters = [
+            json!(["logs", {"address": [usdc, weth], "topics": [[transfer, approval]]}]),
+            json!(["logs", {"address": [weth.to_uppercase().replace("0X", "0x"), usdc, weth], "topics": [[approval, transfer], null]}]),
+            json!(["logs", {"topics": [[transfer, approval, approval], null, null], "address": [weth, usdc]}]),
+        ];
+        for filter in filters {
+            assert_eq!(
+                canonical(filter),
+                json!(["logs", {"address": [usdc, weth], "topics": [[approval, transfer]]}])
+            );
+        }
+
+        assert_eq!(
+            canonical(json!(["logs", {"address": [weth], "topics": [transfer]}])),
+            json!(["logs", {"address": weth, "topics": [transfer]}])
+        );
+        assert_eq!(
+            canonical(json!(["logs", {"address": [], "topics": [null, [transfer, null]]}])),
+            json!(["logs", {}])
+        );
+        assert_eq!(canonical(json!(["logs", null])), json!(["logs", {}]));
+        assert_eq!(canonical(json!(["logs"])), json!(["logs"]));
+        assert_eq!(canonical(json!(["newHeads"])), json!(["newHeads"]));
+    }
+
+    #[test]
+    fn test_validate_unknown_subscription() {
+        let params = json!(["alchemy_minedTransactions"]);
+        assert!(validate_subscription(&params, UnknownSubscriptions::Forward).is_ok());
+        assert!(validate_subscription(&params, UnknownSubscriptions::Reject).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expire_subscription() {
+        let sub_data = Arc::new(SubscriptionData::new());
+        let (user_tx, mut user_rx) = mpsc::unbounded_channel();
+        sub_data.add_user(1, user_tx);
+
+        let subscription = json!({"method": "eth_subscribe", "params": ["newHeads"]});
+        sub_data.register_subscription(subscription.clone(), "sub123".to_string(), 0);
+        sub_data.subscribe_user(1, subscription).unwrap();
+
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(16);
+        expire_subscription(
+            &incoming_tx,
+            &sub_data,
+            1,
+            "sub123".to_string(),
+            Duration::from_millis(50),
+        );
+
+        // Still active before the lifetime is up
+        sleep(Duration::from_millis(20)).await;
+        assert_eq!(sub_data.get_users_for_subscription("sub123"), vec![1]);
+
+        let notice = tokio::time::timeout(Duration::from_millis(100), user_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let notice: Value = notice.into();
+        assert_eq!(notice["params"]["subscription"], "sub123");
+        assert_eq!(notice["params"]["error"]["code"], -32000);
+        assert!(sub_data.get_users_for_subscription("sub123").is_empty());
+
+        // Nobody else needs it, so it gets unsubscribed upstream
+        match incoming_rx.try_recv() {
+            Ok(WsconnMessage::Message(unsub, Some(0))) => {
+                assert_eq!(unsub["method"], "eth_unsubscribe");
+                assert_eq!(unsub["params"][0], "sub123");
+            }
+            other => panic!("expected upstream unsubscribe, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resubscribe_restarts_lifetime() {
+        let sub_data = Arc::new(SubscriptionData::new());
+        let (user_tx, _user_rx) = mpsc::unbounded_channel();
+        sub_data.add_user(1, user_tx);
+        let (incoming_tx, _incoming_rx) = mpsc::channel(16);
+
+        let subscription = json!({"method": "eth_subscribe", "params": ["newHeads"]});
+        sub_data.register_subscription(subscription.clone(), "sub123".to_string(), 0);
+        sub_data.subscribe_user(1, subscription.clone()).unwrap();
+        expire_subscription(
+            &incoming_tx,
+            &sub_data,
+            1,
+            "sub123".to_string(),
+            Duration::from_millis(50),
+        );
+
+        // Unsubscribe and subscribe again to the same upstream subscription
+        sleep(Duration::from_millis(30)).await;
+        assert!(unsubscribe_client(&incoming_tx, &sub_data, 1, "sub123"));
+        sub_data.register_subscription(subscription.clone(), "sub123".to_string(), 0);
+        sub_data.subscribe_user(1, subscription).unwrap();
+        expire_subscription(
+            &incoming_tx,
+            &sub_data,
+            1,
+            "sub123".to_string(),
+            Duration::from_millis(50),
+        );
+
+        // The first timer is up, but it belongs to the old subscription
+        sleep(Duration::from_millis(30)).await;
+        assert_eq!(sub_data.get_users_for_subscription("sub123"), vec![1]);
+
+        sleep(Duration::from_millis(50)).await;
+        assert!(sub_data.get_users_for_subscription("sub123").is_empty());
+    }
+
     #[tokio::test]
     async fn test_subscription_dispatcher() {
         let (tx, rx) = broadcast::channel(10);
-        let (incoming_tx, _incoming_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, _incoming_rx) = mpsc::channel(16);
         let sub_data = Arc::new(SubscriptionData::new());
         let user_id = 1;
         let subscription_id = "sub123";
@@ -190,7 +990,14 @@ mod tests {
             .unwrap();
 
         tokio::spawn(async move {
-            let _ = subscription_dispatcher(rx, incoming_tx, Arc::clone(&sub_data)).await;
+            let _ = subscription_dispatcher(
+                rx,
+                incoming_tx,
+                Arc::clone(&sub_data),
+                false,
+                NotificationLimits::default(),
+            )
+            .await;
         });
 
         let subscription_content =
@@ -212,9 +1019,173 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_notification_rate_cap() {
+        let start = Instant::now();
+        let mut rate_cap = NotificationRateCap::new(2);
+        assert!(rate_cap.allow("0x1", start));
+        assert!(rate_cap.allow("0x1", start));
+        assert!(!rate_cap.allow("0x1", start + Duration::from_millis(500)));
+        // Subscriptions are counted separately
+        assert!(rate_cap.allow("0x2", start));
+        // And get a new share every second
+        assert!(rate_cap.allow("0x1", start + Duration::from_secs(1)));
+
+        let mut unlimited = NotificationRateCap::new(0);
+        assert!((0..1000).all(|_| unlimited.allow("0x1", start)));
+
+        assert!(is_new_heads("[\"newHeads\"]"));
+        assert!(!is_new_heads("[\"logs\",{}]"));
+    }
+
+    #[tokio::test]
+    async fn test_subscription_dispatcher_burst() {
+        let (tx, rx) = broadcast::channel(64);
+        let (incoming_tx, _incoming_rx) = mpsc::channel(16);
+        let sub_data = Arc::new(SubscriptionData::new());
+
+        // A client that never reads its notifications
+        let (slow_tx, _slow_rx) = mpsc::unbounded_channel();
+        let slow_queue = Arc::new(NotificationQueue::new(4));
+        sub_data.add_user(
+            1,
+            UserData::new(slow_tx).with_notification_queue(slow_queue.clone()),
+        );
+        let heads = json!({"method": "eth_subscribe", "params": ["newHeads"]});
+        sub_data.register_subscription(heads.clone(), "0xheads".to_string(), 0);
+        sub_data.subscribe_user(1, heads).unwrap();
+
+        // And one that does, on another subscription
+        let (fast_tx, mut fast_rx) = mpsc::unbounded_channel();
+        sub_data.add_user(2, fast_tx);
+        let logs = json!({"method": "eth_subscribe", "params": ["logs", {}]});
+        sub_data.register_subscription(logs.clone(), "0xlogs".to_string(), 0);
+        sub_data.subscribe_user(2, logs).unwrap();
+
+        tokio::spawn(subscription_dispatcher(
+            rx,
+            incoming_tx,
+            sub_data.clone(),
+            false,
+            NotificationLimits {
+                max_per_second: 100,
+                coalesce_new_heads: true,
+            },
+        ));
+
+        // A node spewing way more heads than the channel can hold
+        for number in 0..10_000 {
+            let _ = tx.send(head_notification("0xheads", number, "0x1"));
+        }
+        tx.send(IncomingResponse {
+            content: json!({
+                "jsonrpc": "2.0",
+                "method": "eth_subscription",
+                "params": {"subscription": "0xlogs", "result": {"logIndex": "0x0"}},
+            }),
+            node_id: 0,
+        })
+        .unwrap();
+
+        // The dispatcher keeps going, and the slow client only holds the latest head
+        let log = tokio::time::timeout(Duration::from_secs(1), fast_rx.recv())
+            .await
+            .expect("dispatcher stalled")
+            .unwrap();
+        let log: Value = log.into();
+        assert_eq!(log["params"]["subscription"], "0xlogs");
+        assert_eq!(slow_queue.len(), 1);
+    }
+
+    fn head_notification(subscription_id: &str, number: u64, hash: &str) -> IncomingResponse {
+        IncomingResponse {
+            content: json!({
+                "jsonrpc": "2.0",
+                "method": "eth_subscription",
+                "params": {
+                    "subscription": subscription_id,
+                    "result": {"number": format!("0x{:x}", number), "hash": hash}
+                }
+            }),
+            node_id: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscription_dispatcher_head_ordering() {
+        let (tx, rx) = broadcast::channel(32);
+        let (incoming_tx, _incoming_rx) = mpsc::channel(16);
+        let sub_data = Arc::new(SubscriptionData::new());
+        let user_id = 1;
+
+        let (user_tx, mut user_rx) = mpsc::unbounded_channel();
+        sub_data.add_user(user_id, user_tx);
+
+        let subscription_request =
+            json!({"jsonrpc":"2.0","id": 1, "method": "eth_subscribe", "params": ["newHeads"]});
+        sub_data.register_subscription(subscription_request.clone(), "sub1".to_string(), 0);
+        sub_data
+            .subscribe_user(user_id, subscription_request)
+            .unwrap();
+
+        let dispatcher_sub_data = Arc::clone(&sub_data);
+        tokio::spawn(async move {
+            let _ = subscription_dispatcher(
+                rx,
+                incoming_tx,
+                dispatcher_sub_data,
+                true,
+                NotificationLimits::default(),
+            )
+            .await;
+        });
+
+        // Heads as they might arrive from the nodes during a reconnect
+        for (number, hash) in [
+            (1, "0x1"),
+            (3, "0x3"),
+            (2, "0x2"),
+            (3, "0x3"),
+            // Reorg at the same height
+            (3, "0x3b"),
+            (4, "0x4"),
+        ] {
+            tx.send(head_notification("sub1", number, hash)).unwrap();
+        }
+
+        let mut received = Vec::new();
+        while received.len() < 4 {
+            if let Some(RequestResult::Subscription(msg)) = user_rx.recv().await {
+                let head = &msg["params"]["result"];
+                received.push(head["hash"].as_str().unwrap().to_string());
+            }
+        }
+        assert_eq!(received, vec!["0x1", "0x3", "0x3b", "0x4"]);
+
+        // Nothing else should've been sent
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(user_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_head_ordering_is_per_subscription() {
+        let mut head_ordering = HeadOrdering::default();
+
+        let head = head_notification("sub1", 5, "0x5").content;
+        assert!(head_ordering.is_in_order("[\"newHeads\"]", &head));
+        assert!(!head_ordering.is_in_order("[\"newHeads\"]", &head));
+        // Other subscriptions are tracked separately
+        assert!(head_ordering.is_in_order("[\"other\"]", &head));
+
+        // Notifications that aren't heads are always in order
+        let log = json!({"params": {"subscription": "sub1", "result": {"data": "0x"}}});
+        assert!(head_ordering.is_in_order("[\"logs\"]", &log));
+        assert!(head_ordering.is_in_order("[\"logs\"]", &log));
+    }
+
     #[tokio::test]
     async fn test_move_subscriptions() {
-        let (incoming_tx, mut incoming_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(16);
         let (tx, rx) = broadcast::channel(10);
         let sub_data = Arc::new(SubscriptionData::new());
         let node_id = 1;
@@ -263,4 +1234,203 @@ mod tests {
             "Subscriptions should have been moved to the new node"
         );
     }
+
+    #[tokio::test]
+    async fn test_serve_local_heads() {
+        let sub_data = Arc::new(SubscriptionData::new());
+        let (user_tx, mut user_rx) = mpsc::unbounded_channel();
+        sub_data.add_user(1, user_tx);
+
+        let (subscription_id, heads) = sub_data.add_local_subscription(1);
+        serve_local_heads(&sub_data, 1, subscription_id.clone(), heads);
+
+        for number in 1..=5u64 {
+            sub_data.publish_head(json!({"number": format!("{:#x}", number)}));
+        }
+
+        // Every head arrives, in order
+        for number in 1..=5u64 {
+            let head = tokio::time::timeout(Duration::from_millis(100), user_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let head: Value = head.into();
+            assert_eq!(head["method"], "eth_subscription");
+            assert_eq!(head["params"]["subscription"], subscription_id.as_str());
+            assert_eq!(head["params"]["result"]["number"], format!("{:#x}", number));
+        }
+
+        // Nothing after unsubscribing
+        assert!(sub_data.remove_local_subscription(1, &subscription_id));
+        sub_data.publish_head(json!({"number": "0x6"}));
+        sleep(Duration::from_millis(20)).await;
+        assert!(user_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_moved_subscription_keeps_its_id() {
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(16);
+        let (tx, rx) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+        let (user_tx, mut user_rx) = mpsc::unbounded_channel();
+        sub_data.add_user(2, user_tx);
+
+        let params = json!(["logs", {"address": "0x4200000000000000000000000000000000000006"}]);
+        let subscription_request = json!({"method": "eth_subscribe", "params": params});
+        sub_data.register_subscription(subscription_request.clone(), "0xold".to_string(), 1);
+        sub_data.subscribe_user(2, subscription_request).unwrap();
+
+        tokio::spawn(subscription_dispatcher(
+            rx.resubscribe(),
+            incoming_tx.clone(),
+            sub_data.clone(),
+            false,
+            NotificationLimits::default(),
+        ));
+
+        // The new node answers with a new id
+        let tx_clone = tx.clone();
+        let resubscribed = tokio::spawn(async move {
+            while let Some(WsconnMessage::Message(message, _)) = incoming_rx.recv().await {
+                if message["method"] == "eth_subscribe" {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    tx_clone
+                        .send(IncomingResponse {
+                            content: json!({"jsonrpc": "2.0", "id": message["id"], "result": "0xnew"}),
+                            node_id: 2,
+                        })
+                        .unwrap();
+                    return message["params"].clone();
+                }
+            }
+            Value::Null
+        });
+
+        move_subscriptions(&incoming_tx, rx, &sub_data, 1)
+            .await
+            .unwrap();
+        assert_eq!(resubscribed.await.unwrap(), params);
+
+        // Notifications from the new node reach the user under the id they know
+        tx.send(IncomingResponse {
+            content: json!({
+                "jsonrpc": "2.0",
+                "method": "eth_subscription",
+                "params": {"subscription": "0xnew", "result": {"logIndex": "0x0"}},
+            }),
+            node_id: 2,
+        })
+        .unwrap();
+
+        let notification = tokio::time::timeout(Duration::from_millis(100), user_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let notification: Value = notification.into();
+        assert_eq!(notification["params"]["subscription"], "0xold");
+
+        // Unsubscribing on the new node uses its own id
+        assert_eq!(
+            sub_data.unsubscribe_user(2, "0xold".to_string()),
+            Some(NodeSubInfo {
+                node_id: 2,
+                subscription_id: "0xnew".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_gap_tracker() {
+        let mut gaps = GapTracker::default();
+        let head = |number: &str| json!({"params": {"result": {"number": number}}});
+
+        assert_eq!(
+            gaps.observe("[\"newHeads\"]", "0x1", &head("0x10"), false),
+            None
+        );
+        assert_eq!(
+            gaps.observe("[\"newHeads\"]", "0x1", &head("0x11"), false),
+            None
+        );
+        assert_eq!(
+            gaps.observe("[\"newHeads\"]", "0x1", &head("0x15"), true),
+            Some((0x12, 0x14))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_cleanup() {
+        use crate::{
+            balancer::processing::CacheArgs,
+            websocket::client::execute_ws_call,
+        };
+
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(16);
+        let (tx, rx) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+        let sub_queue =
+            SubscriptionQueue::new(&Arc::new(RwLock::new(vec![ws_rpc()])), Duration::ZERO);
+        let cache_args = CacheArgs::default();
+        for user_id in [1, 2] {
+            sub_data.add_user(user_id, mpsc::unbounded_channel().0);
+        }
+
+        // Upstream answers subscriptions and records unsubscribes
+        let (unsub_tx, mut unsub_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(WsconnMessage::Message(message, node_id)) = incoming_rx.recv().await {
+                if message["method"] == "eth_subscribe" {
+                    tx.send(IncomingResponse {
+                        content: json!({"jsonrpc": "2.0", "id": message["id"], "result": "0xabc"}),
+                        node_id: 0,
+                    })
+                    .unwrap();
+                } else if message["method"] == "eth_unsubscribe" {
+                    unsub_tx
+                        .send((message["params"][0].clone(), node_id))
+                        .unwrap();
+                }
+            }
+        });
+
+        let call = |user_id: u32, method: &str, params: Value| {
+            execute_ws_call(
+                json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params}),
+                user_id,
+                &incoming_tx,
+                rx.resubscribe(),
+                &sub_data,
+                &sub_queue,
+                &cache_args,
+            )
+        };
+        let result =
+            |response: String| serde_json::from_str::<Value>(&response).unwrap()["result"].clone();
+
+        // Both users share one upstream subscription
+        for user_id in [1, 2] {
+            let response = call(user_id, "eth_subscribe", json!(["newHeads"]))
+                .await
+                .unwrap();
+            assert_eq!(result(response), "0xabc");
+        }
+
+        let response = call(1, "eth_unsubscribe", json!(["0xabc"])).await.unwrap();
+        assert_eq!(result(response), true);
+        let response = call(1, "eth_unsubscribe", json!(["0xabc"])).await.unwrap();
+        assert_eq!(result(response), false);
+        sleep(Duration::from_millis(20)).await;
+        assert!(unsub_rx.try_recv().is_err());
+
+        // The last user disconnecting unsubscribes upstream
+        remove_ws_user(&incoming_tx, &sub_data, 2);
+        let unsub = tokio::time::timeout(Duration::from_millis(100), unsub_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(unsub, (json!("0xabc"), Some(0)));
+
+        remove_ws_user(&incoming_tx, &sub_data, 1);
+        assert!(sub_data.is_empty());
+    }
 }
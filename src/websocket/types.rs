@@ -1,17 +1,39 @@
+use crate::warn_limited;
+use tracing::info;
+
 use std::{
     collections::{
         HashMap,
         HashSet,
+        VecDeque,
     },
     sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU64,
+            Ordering,
+        },
         Arc,
+        Mutex,
         RwLock,
     },
 };
 
 use crate::websocket::error::Error;
-use serde_json::Value;
-use tokio::sync::mpsc;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::sync::{
+    broadcast,
+    mpsc,
+    Mutex as AsyncMutex,
+    Notify,
+    OwnedMutexGuard,
+};
+
+// How many heads a local newHeads subscription can fall behind before it gets cancelled
+const LOCAL_HEADS_CAPACITY: usize = 128;
 
 // RequestResult enum
 #[derive(Debug, Clone)]
@@ -34,14 +56,18 @@ impl From<RequestResult> for Value {
 pub enum WsconnMessage {
     // call received from user and optional node index
     Message(Value, Option<usize>),
+    // Reconnect to every RPC
     Reconnect(),
+    // Connect to RPCs that were added or got a new WS endpoint and close the
+    // connections to ones that are gone, keeping the rest
+    Sync(),
 }
 
 impl From<WsconnMessage> for Value {
     fn from(msg: WsconnMessage) -> Self {
         match msg {
             WsconnMessage::Message(msg, _) => msg,
-            WsconnMessage::Reconnect() => Value::Null,
+            WsconnMessage::Reconnect() | WsconnMessage::Sync() => Value::Null,
         }
     }
 }
@@ -52,7 +78,128 @@ pub enum WsChannelErr {
     Closed(usize),
 }
 
-pub type UserData = mpsc::UnboundedSender<RequestResult>;
+// Subscription notifications waiting to be sent to a client.
+//
+// Bounded so a client that can't keep up doesn't pile up notifications forever.
+// Once full, the oldest notification gets dropped to make room for the new one.
+#[derive(Debug)]
+pub struct NotificationQueue {
+    queue: Mutex<VecDeque<Value>>,
+    notify: Notify,
+    capacity: usize,
+}
+
+impl NotificationQueue {
+    pub fn new(capacity: usize) -> Self {
+        NotificationQueue {
+            queue: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    // Queue a notification. If `coalesce` is set, it replaces the queued notification
+    // of the same subscription instead of going after it.
+    //
+    // Returns false if the oldest notification was dropped to make room.
+    pub fn push(&self, notification: Value, coalesce: bool) -> bool {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        if coalesce {
+            let subscription = &notification["params"]["subscription"];
+            queue.retain(|queued| queued["params"]["subscription"] != *subscription);
+        }
+
+        let dropped = queue.len() >= self.capacity && queue.pop_front().is_some();
+        queue.push_back(notification);
+        drop(queue);
+
+        self.notify.notify_one();
+        !dropped
+    }
+
+    // Wait for the next notification
+    pub async fn pop(&self) -> Value {
+        loop {
+            if let Some(notification) = self
+                .queue
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .pop_front()
+            {
+                return notification;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+// Where messages for a user go. Subscription notifications go through `notifications`
+// if the user has a queue for them, everything else straight to `tx`.
+#[derive(Debug, Clone)]
+pub struct UserData {
+    tx: mpsc::UnboundedSender<RequestResult>,
+    notifications: Option<Arc<NotificationQueue>>,
+}
+
+impl UserData {
+    pub fn new(tx: mpsc::UnboundedSender<RequestResult>) -> Self {
+        UserData {
+            tx,
+            notifications: None,
+        }
+    }
+
+    pub fn with_notification_queue(mut self, notifications: Arc<NotificationQueue>) -> Self {
+        self.notifications = Some(notifications);
+        self
+    }
+
+    pub fn send(
+        &self,
+        message: RequestResult,
+    ) -> Result<(), mpsc::error::SendError<RequestResult>> {
+        self.push(message, false)
+    }
+
+    // Like `send`, but if the user is behind only the latest notification of the
+    // subscription gets delivered
+    pub fn send_latest(
+        &self,
+        message: RequestResult,
+    ) -> Result<(), mpsc::error::SendError<RequestResult>> {
+        self.push(message, true)
+    }
+
+    fn push(
+        &self,
+        message: RequestResult,
+        coalesce: bool,
+    ) -> Result<(), mpsc::error::SendError<RequestResult>> {
+        match (&self.notifications, message) {
+            // A closed `tx` means the user is gone
+            (Some(notifications), RequestResult::Subscription(notification))
+                if !self.tx.is_closed() =>
+            {
+                if !notifications.push(notification, coalesce) {
+                    warn_limited!("Client can't keep up with its subscriptions! Dropping its oldest notification.");
+                }
+                Ok(())
+            }
+            (_, message) => self.tx.send(message),
+        }
+    }
+}
+
+impl From<mpsc::UnboundedSender<RequestResult>> for UserData {
+    fn from(tx: mpsc::UnboundedSender<RequestResult>) -> Self {
+        UserData::new(tx)
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct NodeSubInfo {
@@ -66,13 +213,62 @@ pub struct IncomingResponse {
     pub node_id: usize,
 }
 
+type SubscribeLocks = Arc<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>>;
+
+// Held while subscribing to `params` upstream. See `SubscriptionData::lock_subscription`.
+#[derive(Debug)]
+pub struct SubscribeGuard {
+    params: String,
+    locks: SubscribeLocks,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for SubscribeGuard {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        self.guard.take();
+
+        // Forget the lock unless someone else is waiting for it
+        if locks
+            .get(&self.params)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.params);
+        }
+    }
+}
+
 // Main struct for storing data related to subscriptions and the associated users
+//
+// Users subscribing with identical params share one upstream subscription.
+// `subscriptions` holds the users of every upstream subscription, which doubles
+// as its reference count. Once the last user leaves, it gets unregistered and
+// should be unsubscribed from upstream.
+//
+// Subscriptions are identified by the id clients know them by. When a subscription
+// moves to another node, it gets a new id there, which `upstream_ids` maps back
+// to the one clients know.
 // TODO: we should probably store more data for the sake of compute performance
 #[derive(Debug, Clone)]
 pub struct SubscriptionData {
     users: Arc<RwLock<HashMap<u32, UserData>>>,
     subscriptions: Arc<RwLock<HashMap<NodeSubInfo, HashSet<u32>>>>,
     incoming_subscriptions: Arc<RwLock<HashMap<String, NodeSubInfo>>>,
+    // Params currently being subscribed to upstream
+    subscribing: SubscribeLocks,
+    // Heads seen by the health check, for serving newHeads subscriptions locally
+    local_heads: broadcast::Sender<Value>,
+    has_local_heads: Arc<AtomicBool>,
+    // Local subscription id -> user
+    local_subscriptions: Arc<RwLock<HashMap<String, u32>>>,
+    // Upstream subscription of a moved subscription -> id clients know it by
+    upstream_ids: Arc<RwLock<HashMap<NodeSubInfo, String>>>,
+    // Subscriptions that were moved, until they get their first notification
+    moved: Arc<RwLock<HashSet<String>>>,
+    // Generation of the lifetime timer of each user's subscription. Subscribing
+    // again to the same id starts a new generation, so older timers know they're stale.
+    expiries: Arc<Mutex<HashMap<(u32, String), u64>>>,
+    next_expiry: Arc<AtomicU64>,
 }
 
 impl SubscriptionData {
@@ -81,24 +277,234 @@ impl SubscriptionData {
             users: Arc::new(RwLock::new(HashMap::new())),
             subscriptions: Arc::new(RwLock::new(HashMap::new())),
             incoming_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            subscribing: Arc::new(Mutex::new(HashMap::new())),
+            local_heads: broadcast::channel(LOCAL_HEADS_CAPACITY).0,
+            has_local_heads: Arc::new(AtomicBool::new(false)),
+            local_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            upstream_ids: Arc::new(RwLock::new(HashMap::new())),
+            moved: Arc::new(RwLock::new(HashSet::new())),
+            expiries: Arc::new(Mutex::new(HashMap::new())),
+            next_expiry: Arc::new(AtomicU64::new(0)),
         }
     }
 
-    pub fn add_user(&self, user_id: u32, user_data: UserData) {
-        let mut users = self.users.write().unwrap_or_else(|e| e.into_inner());
+    // Returns the id clients know the upstream subscription `upstream_id` on `node_id` by
+    pub fn client_sub_id(&self, node_id: usize, upstream_id: &str) -> String {
+        let upstream = NodeSubInfo {
+            node_id,
+            subscription_id: upstream_id.to_string(),
+        };
+
+        self.upstream_ids
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&upstream)
+            .cloned()
+            .unwrap_or(upstream.subscription_id)
+    }
+
+    // Returns the upstream subscription behind a subscription
+    pub fn upstream_sub_info(&self, node_sub_info: &NodeSubInfo) -> NodeSubInfo {
+        self.upstream_ids
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find(|(upstream, client_id)| {
+                upstream.node_id == node_sub_info.node_id
+                    && **client_id == node_sub_info.subscription_id
+            })
+            .map(|(upstream, _)| upstream.clone())
+            .unwrap_or_else(|| node_sub_info.clone())
+    }
+
+    // Returns true if the subscription was moved to another node and hasn't
+    // gotten a notification since
+    pub fn take_moved(&self, subscription_id: &str) -> bool {
+        let moved = self.moved.read().unwrap_or_else(|e| e.into_inner());
+        if moved.is_empty() {
+            return false;
+        }
+        drop(moved);
+
+        self.moved
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(subscription_id)
+    }
+
+    // Publish a new head to local newHeads subscriptions
+    pub fn publish_head(&self, header: Value) {
+        self.has_local_heads.store(true, Ordering::Relaxed);
+        // Nobody might be subscribed
+        let _ = self.local_heads.send(header);
+    }
+
+    // Stop serving new newHeads subscriptions locally until we see heads again
+    pub fn clear_local_heads(&self) {
+        self.has_local_heads.store(false, Ordering::Relaxed);
+    }
+
+    // Returns true if we're seeing heads we can serve newHeads subscriptions from
+    pub fn has_local_heads(&self) -> bool {
+        self.has_local_heads.load(Ordering::Relaxed)
+    }
+
+    // Create a newHeads subscription for `user_id` that we serve ourselves.
+    //
+    // Returns its id and the heads to send to the user.
+    pub fn add_local_subscription(&self, user_id: u32) -> (String, broadcast::Receiver<Value>) {
+        let subscription_id = format!("0x{:032x}", rand::random::<u128>());
+        self.local_subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(subscription_id.clone(), user_id);
+
+        (subscription_id, self.local_heads.subscribe())
+    }
+
+    pub fn is_local_subscription(&self, user_id: u32, subscription_id: &str) -> bool {
+        self.local_subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(subscription_id)
+            == Some(&user_id)
+    }
+
+    // Returns false if the user doesn't have that local subscription
+    pub fn remove_local_subscription(&self, user_id: u32, subscription_id: &str) -> bool {
+        let mut local_subscriptions = self
+            .local_subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+
+        if local_subscriptions.get(subscription_id) != Some(&user_id) {
+            return false;
+        }
+        local_subscriptions.remove(subscription_id);
+        drop(local_subscriptions);
+        self.clear_expiry(user_id, subscription_id);
+
+        true
+    }
+
+    // Start a new lifetime for a user's subscription, replacing any earlier one.
+    //
+    // Returns the generation its timer needs to pass to `take_expiry`.
+    pub fn start_expiry(&self, user_id: u32, subscription_id: &str) -> u64 {
+        let generation = self.next_expiry.fetch_add(1, Ordering::Relaxed);
+        self.expiries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert((user_id, subscription_id.to_string()), generation);
+
+        generation
+    }
+
+    // Returns true, and ends the lifetime, if `generation` is still the current
+    // one for the user's subscription
+    pub fn take_expiry(&self, user_id: u32, subscription_id: &str, generation: u64) -> bool {
+        let mut expiries = self.expiries.lock().unwrap_or_else(|e| e.into_inner());
+        let key = (user_id, subscription_id.to_string());
+        if expiries.get(&key) != Some(&generation) {
+            return false;
+        }
+        expiries.remove(&key);
 
-        users.insert(user_id, user_data);
+        true
     }
 
-    pub fn remove_user(&self, user_id: u32) {
+    fn clear_expiry(&self, user_id: u32, subscription_id: &str) {
+        self.expiries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&(user_id, subscription_id.to_string()));
+    }
+
+    // Returns true if we aren't holding on to any users or subscriptions
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.users.read().unwrap().is_empty()
+            && self.subscriptions.read().unwrap().is_empty()
+            && self.incoming_subscriptions.read().unwrap().is_empty()
+            && self.subscribing.lock().unwrap().is_empty()
+            && self.local_subscriptions.read().unwrap().is_empty()
+            && self.upstream_ids.read().unwrap().is_empty()
+            && self.moved.read().unwrap().is_empty()
+    }
+
+    // Send a message to a user. Returns false if the user is gone.
+    pub fn send_to_user(&self, user_id: u32, message: RequestResult) -> bool {
+        let users = self.users.read().unwrap_or_else(|e| e.into_inner());
+        match users.get(&user_id) {
+            Some(user) => user.send(message).is_ok(),
+            None => false,
+        }
+    }
+
+    // Wait until nobody else is subscribing to the same params upstream.
+    //
+    // Users subscribing at the same time would otherwise all miss the shared
+    // subscription and create their own. Hold the guard until the new subscription
+    // is registered, so the others find it once they get the lock.
+    pub async fn lock_subscription(&self, subscription: &Value) -> SubscribeGuard {
+        let params = format!("{}", subscription["params"]);
+        let lock = {
+            let mut locks = self.subscribing.lock().unwrap_or_else(|e| e.into_inner());
+            locks.entry(params.clone()).or_default().clone()
+        };
+
+        SubscribeGuard {
+            params,
+            locks: self.subscribing.clone(),
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+
+    pub fn add_user(&self, user_id: u32, user_data: impl Into<UserData>) {
         let mut users = self.users.write().unwrap_or_else(|e| e.into_inner());
 
-        if users.remove(&user_id).is_some() {
-            let mut subscriptions = self.subscriptions.write().unwrap();
-            for user_subscriptions in subscriptions.values_mut() {
-                user_subscriptions.remove(&user_id);
-            }
+        users.insert(user_id, user_data.into());
+    }
+
+    // Remove a user along with all of its subscriptions.
+    //
+    // Returns the upstream subscriptions the user was the last one on.
+    pub fn remove_user(&self, user_id: u32) -> Vec<NodeSubInfo> {
+        let mut users = self.users.write().unwrap_or_else(|e| e.into_inner());
+        if users.remove(&user_id).is_none() {
+            return Vec::new();
         }
+        drop(users);
+
+        self.local_subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, user| *user != user_id);
+        self.expiries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(user, _), _| *user != user_id);
+
+        let mut orphaned = Vec::new();
+        self.subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|node_sub_info, subscribers| {
+                if subscribers.remove(&user_id) && subscribers.is_empty() {
+                    orphaned.push(node_sub_info.clone());
+                    return false;
+                }
+                true
+            });
+
+        orphaned
+            .iter()
+            .map(|node_sub_info| {
+                let upstream = self.upstream_sub_info(node_sub_info);
+                self.unregister_node_subscription(node_sub_info);
+                upstream
+            })
+            .collect()
     }
 
     // Used to add a new subscription to the active subscription list
@@ -120,8 +526,8 @@ impl SubscriptionData {
             .write()
             .unwrap_or_else(|e| e.into_inner());
 
-        println!(
-            "\x1b[35mInfo:\x1b[0m Register_subscription inserting: {}",
+        info!(
+            "Register_subscription inserting: {}",
             subscription.to_owned()
         );
         incoming_subscriptions.insert(
@@ -133,13 +539,23 @@ impl SubscriptionData {
         );
     }
 
-    pub fn unregister_subscription(&self, subscription_request: String) {
-        let mut incoming_subscriptions = self
-            .incoming_subscriptions
+    // Forget an upstream subscription, so nobody new gets subscribed to it
+    fn unregister_node_subscription(&self, node_sub_info: &NodeSubInfo) {
+        self.incoming_subscriptions
             .write()
-            .unwrap_or_else(|e| e.into_inner());
-
-        incoming_subscriptions.remove(&subscription_request);
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, registered| registered != node_sub_info);
+        self.upstream_ids
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|upstream, client_id| {
+                upstream.node_id != node_sub_info.node_id
+                    || *client_id != node_sub_info.subscription_id
+            });
+        self.moved
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&node_sub_info.subscription_id);
     }
 
     // Subscribe user to existing subscription and return the subscription id
@@ -154,10 +570,7 @@ impl SubscriptionData {
 
         // TODO: pepega
         let subscription = format!("{}", subscription["params"]);
-        println!(
-            "\x1b[35mInfo:\x1b[0m Subscribe_user finding: {}",
-            subscription
-        );
+        info!("Subscribe_user finding: {}", subscription);
 
         self.raw_subscribe(user_id, &subscription)
     }
@@ -183,27 +596,114 @@ impl SubscriptionData {
     }
 
     // Unsubscribe a user from a subscription
-    pub fn unsubscribe_user(&self, user_id: u32, subscription_id: String) {
+    //
+    // Returns the upstream subscription if the user was the last one on it.
+    pub fn unsubscribe_user(&self, user_id: u32, subscription_id: String) -> Option<NodeSubInfo> {
+        self.clear_expiry(user_id, &subscription_id);
+
         let mut subscriptions = self
             .subscriptions
             .write()
             .unwrap_or_else(|e| e.into_inner());
 
-        let mut subscriptions_to_update = Vec::new();
-
-        // Finding all subscriptions matching the subscription_id and user_id
-        for (node_sub_info, subscribers) in subscriptions.iter() {
-            if node_sub_info.subscription_id == subscription_id && subscribers.contains(&user_id) {
-                subscriptions_to_update.push(node_sub_info.clone());
+        let mut orphaned = None;
+        subscriptions.retain(|node_sub_info, subscribers| {
+            if node_sub_info.subscription_id == subscription_id
+                && subscribers.remove(&user_id)
+                && subscribers.is_empty()
+            {
+                orphaned = Some(node_sub_info.clone());
+                return false;
             }
+            true
+        });
+        drop(subscriptions);
+
+        orphaned.map(|node_sub_info| {
+            let upstream = self.upstream_sub_info(&node_sub_info);
+            self.unregister_node_subscription(&node_sub_info);
+            upstream
+        })
+    }
+
+    // Unsubscribe a user from a subscription and notify them that it was cancelled
+    // because of `reason`.
+    //
+    // Returns `None` if the user wasn't subscribed to it. Otherwise returns
+    // the upstream subscription if the user was the last one on it, like `unsubscribe_user`.
+    pub fn cancel_user_subscription(
+        &self,
+        user_id: u32,
+        subscription_id: &str,
+        reason: &str,
+    ) -> Option<Option<NodeSubInfo>> {
+        let orphaned = if self.remove_local_subscription(user_id, subscription_id) {
+            None
+        } else if self
+            .get_users_for_subscription(subscription_id)
+            .contains(&user_id)
+        {
+            self.unsubscribe_user(user_id, subscription_id.to_string())
+        } else {
+            return None;
+        };
+
+        let notice = json!({
+            "jsonrpc": "2.0",
+            "method": "eth_subscription",
+            "params": {
+                "subscription": subscription_id,
+                "error": {
+                    "code": -32000,
+                    "message": format!("error: Subscription cancelled, {}. Please resubscribe.", reason),
+                },
+            },
+        });
+        self.send_to_user(user_id, RequestResult::Subscription(notice));
+
+        Some(orphaned)
+    }
+
+    // Move subscriptions to the new ids of their nodes, after the node list changed.
+    //
+    // `renumbered` maps old node ids to new ones. Nodes not in it keep their id.
+    pub fn renumber_nodes(&self, renumbered: &HashMap<usize, usize>) {
+        if renumbered.is_empty() {
+            return;
         }
 
-        // Unsubscribing the user from the found subscriptions
-        for node_sub_info in subscriptions_to_update {
-            if let Some(subscribers) = subscriptions.get_mut(&node_sub_info) {
-                subscribers.remove(&user_id);
+        let renumber = |node_sub_info: NodeSubInfo| {
+            NodeSubInfo {
+                node_id: *renumbered
+                    .get(&node_sub_info.node_id)
+                    .unwrap_or(&node_sub_info.node_id),
+                subscription_id: node_sub_info.subscription_id,
             }
+        };
+
+        let mut subscriptions = self
+            .subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        *subscriptions = subscriptions
+            .drain()
+            .map(|(node_sub_info, users)| (renumber(node_sub_info), users))
+            .collect();
+
+        for node_sub_info in self
+            .incoming_subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .values_mut()
+        {
+            *node_sub_info = renumber(node_sub_info.clone());
         }
+
+        let mut upstream_ids = self.upstream_ids.write().unwrap_or_else(|e| e.into_inner());
+        *upstream_ids = upstream_ids
+            .drain()
+            .map(|(upstream, client_id)| (renumber(upstream), client_id))
+            .collect();
     }
 
     // Return the node_id for a given subscription_id
@@ -231,15 +731,17 @@ impl SubscriptionData {
             .read()
             .unwrap_or_else(|e| e.into_inner());
 
-        incoming_subscriptions
+        let node_sub_infos: Vec<NodeSubInfo> = incoming_subscriptions
+            .values()
+            .filter(|node_sub_info| node_sub_info.node_id == node_id)
+            .cloned()
+            .collect();
+        drop(incoming_subscriptions);
+
+        // The node knows moved subscriptions by their upstream ids
+        node_sub_infos
             .iter()
-            .filter_map(|(_, node_sub_info)| {
-                if node_sub_info.node_id == node_id {
-                    Some(node_sub_info.subscription_id.to_owned())
-                } else {
-                    None
-                }
-            })
+            .map(|node_sub_info| self.upstream_sub_info(node_sub_info).subscription_id)
             .collect()
     }
 
@@ -254,13 +756,11 @@ impl SubscriptionData {
             .iter()
             .filter_map(|(subscription, node_sub_info)| {
                 if node_sub_info.node_id == node_id {
-                    // Parse the subscription string as a JSON array
-                    serde_json::from_str::<Vec<String>>(subscription)
+                    // Parse the subscription string as JSON params, like `["logs", {...}]`
+                    serde_json::from_str::<Value>(subscription)
                         .ok()
-                        .and_then(|v| {
-                            // Serialize each Vec<String> back into a JSON string format
-                            serde_json::to_string(&v).ok()
-                        })
+                        .filter(Value::is_array)
+                        .map(|params| params.to_string())
                 } else {
                     None
                 }
@@ -268,6 +768,7 @@ impl SubscriptionData {
             .collect()
     }
 
+    #[allow(dead_code)] // allowed for tests
     pub fn get_sub_id_by_params(&self, params: &str) -> Option<String> {
         let incoming_subscriptions = self
             .incoming_subscriptions
@@ -285,6 +786,24 @@ impl SubscriptionData {
             })
     }
 
+    // Return the params of the subscription with `subscription_id`
+    pub fn get_params_by_sub_id(&self, subscription_id: &str) -> Option<String> {
+        let incoming_subscriptions = self
+            .incoming_subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+
+        incoming_subscriptions
+            .iter()
+            .find_map(|(subscription, node_sub_info)| {
+                if node_sub_info.subscription_id == subscription_id {
+                    Some(subscription.to_owned())
+                } else {
+                    None
+                }
+            })
+    }
+
     // Return a Vec of all users subscribed to a subscription
     pub fn get_users_for_subscription(&self, subscription_id: &str) -> Vec<u32> {
         let subscriptions = self.subscriptions.read().unwrap_or_else(|e| e.into_inner());
@@ -301,26 +820,58 @@ impl SubscriptionData {
         users
     }
 
-    // Moves all subscription from one node to another
+    // Moves the subscription with params `request` to `target`, where it's
+    // subscribed to as `upstream_id`.
+    //
+    // Users keep the subscription id they know, notifications from `target`
+    // need to be mapped back to it with `client_sub_id`.
     pub fn move_subscriptions(
         &self,
         target: usize,
         request: String,
-        subscription_id: String,
+        upstream_id: String,
     ) -> Result<(), Error> {
+        let old = match self
+            .incoming_subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&request)
+        {
+            Some(old) => old.clone(),
+            None => return Err(Error::MissingSubscription()),
+        };
+        let subscription_id = old.subscription_id.clone();
+
         // Get all the users that are subscribed to our subscription
         let users = self.get_users_for_subscription(&subscription_id);
         if users.is_empty() {
             return Err(Error::EmptyList("User list empty!".to_string()));
         }
-        // Unsubscribe everyone from the subscription
-        for user_id in users.iter() {
-            self.unsubscribe_user(*user_id, request.clone());
-        }
+        // Everyone sharing the subscription moves together, so drop it as a whole
+        self.subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|node_sub_info, _| node_sub_info.subscription_id != subscription_id);
 
         // Unregister/register
-        self.unregister_subscription(request.clone());
-        self.raw_register(&request, subscription_id, target);
+        self.unregister_node_subscription(&old);
+        self.raw_register(&request, subscription_id.clone(), target);
+        if upstream_id != subscription_id {
+            self.upstream_ids
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(
+                    NodeSubInfo {
+                        node_id: target,
+                        subscription_id: upstream_id,
+                    },
+                    subscription_id.clone(),
+                );
+        }
+        self.moved
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(subscription_id);
 
         // resubscribe all the users now
         for user_id in users.iter() {
@@ -330,11 +881,15 @@ impl SubscriptionData {
         Ok(())
     }
 
+    // Send a notification to every user of a subscription.
+    //
+    // With `coalesce` set, users that are behind only get the latest notification.
     pub async fn dispatch_to_subscribers(
         &self,
         subscription_id: &str,
         node_id: usize,
         message: &RequestResult,
+        coalesce: bool,
     ) -> Result<bool, Error> {
         if let RequestResult::Call(_) = message {
             return Err(Error::InvalidData(
@@ -350,8 +905,8 @@ impl SubscriptionData {
         let users = self.users.read().unwrap_or_else(|e| e.into_inner());
         if let Some(subscribers) = self.subscriptions.read().unwrap().get(&node_sub_info) {
             if subscribers.is_empty() {
-                self.unregister_subscription(subscription_id.to_string());
-                println!(
+                self.unregister_node_subscription(&node_sub_info);
+                info!(
                     "No more users to send subscription to. Unsubscribing from ID: {}",
                     subscription_id
                 );
@@ -359,7 +914,11 @@ impl SubscriptionData {
             }
             for &user_id in subscribers {
                 if let Some(user) = users.get(&user_id) {
-                    user.send(message.clone())?;
+                    if coalesce {
+                        user.send_latest(message.clone())?;
+                    } else {
+                        user.send(message.clone())?;
+                    }
                 }
             }
         }
@@ -491,7 +1050,7 @@ mod tests {
             .subscribe_user(user_id, subscription_request)
             .unwrap();
         subscription_data
-            .dispatch_to_subscribers(&subscription_id, node_id, &message)
+            .dispatch_to_subscribers(&subscription_id, node_id, &message, false)
             .await
             .unwrap();
 
@@ -501,6 +1060,46 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_notification_queue() {
+        let notification = |subscription: &str, number: u64| json!({"params": {"subscription": subscription, "result": number}});
+
+        // The oldest notifications get dropped once full
+        let queue = NotificationQueue::new(2);
+        assert!(queue.push(notification("0x1", 1), false));
+        assert!(queue.push(notification("0x1", 2), false));
+        assert!(!queue.push(notification("0x1", 3), false));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop().await["params"]["result"], 2);
+        assert_eq!(queue.pop().await["params"]["result"], 3);
+
+        // Coalesced notifications replace the queued one of their subscription
+        let queue = NotificationQueue::new(8);
+        queue.push(notification("0x1", 1), true);
+        queue.push(notification("0x2", 1), true);
+        queue.push(notification("0x1", 2), true);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop().await, notification("0x2", 1));
+        assert_eq!(queue.pop().await, notification("0x1", 2));
+
+        // Users with a queue get notifications through it, and calls directly
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let queue = Arc::new(NotificationQueue::new(8));
+        let user = UserData::new(tx).with_notification_queue(queue.clone());
+        user.send(RequestResult::Subscription(notification("0x1", 1)))
+            .unwrap();
+        user.send(RequestResult::Call(json!({"id": 1}))).unwrap();
+        assert_eq!(queue.len(), 1);
+        assert!(matches!(rx.try_recv(), Ok(RequestResult::Call(_))));
+
+        // Nothing gets queued for users that are gone
+        drop(rx);
+        assert!(user
+            .send(RequestResult::Subscription(notification("0x1", 2)))
+            .is_err());
+        assert_eq!(queue.len(), 1);
+    }
+
     #[tokio::test]
     async fn test_remove_nonexistent_user() {
         let (subscription_data, _, _) = setup_user_and_subscription_data();
@@ -559,6 +1158,35 @@ mod tests {
         assert!(sub_ids_for_nonexistent_node.is_empty());
     }
 
+    #[test]
+    fn test_renumber_nodes() {
+        let (subscription_data, user_id, _rx) = setup_user_and_subscription_data();
+        subscription_data.register_subscription(
+            json!({"params": ["newHeads"]}),
+            "sub123".to_string(),
+            1,
+        );
+        subscription_data.register_subscription(
+            json!({"params": ["logs"]}),
+            "sub456".to_string(),
+            2,
+        );
+        subscription_data
+            .subscribe_user(user_id, json!({"params": ["newHeads"]}))
+            .unwrap();
+
+        // Swapping ids must not merge the two nodes
+        subscription_data.renumber_nodes(&HashMap::from([(1, 2), (2, 1)]));
+
+        assert_eq!(subscription_data.get_sub_id_by_node(2), vec!["sub123"]);
+        assert_eq!(subscription_data.get_sub_id_by_node(1), vec!["sub456"]);
+        assert_eq!(subscription_data.get_node_from_id("sub123"), Some(2));
+        assert_eq!(
+            subscription_data.get_users_for_subscription("sub123"),
+            vec![user_id]
+        );
+    }
+
     #[tokio::test]
     async fn test_get_sub_id_by_node_with_multiple_subscriptions() {
         let subscription_data = SubscriptionData::new();
@@ -612,6 +1240,14 @@ mod tests {
             users: Arc::new(RwLock::new(HashMap::new())),
             subscriptions: Arc::new(RwLock::new(HashMap::new())),
             incoming_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            subscribing: Arc::new(Mutex::new(HashMap::new())),
+            local_heads: broadcast::channel(LOCAL_HEADS_CAPACITY).0,
+            has_local_heads: Arc::new(AtomicBool::new(false)),
+            local_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            upstream_ids: Arc::new(RwLock::new(HashMap::new())),
+            moved: Arc::new(RwLock::new(HashSet::new())),
+            expiries: Arc::new(Mutex::new(HashMap::new())),
+            next_expiry: Arc::new(AtomicU64::new(0)),
         };
 
         // Mock subscription data
@@ -749,8 +1385,8 @@ mod tests {
         // Ensure there are no subscribers to the moved subscription
         let subscriptions = subscription_data.subscriptions.read().unwrap();
         assert!(
-            subscriptions.get(&node_sub_info).is_none()
-                || subscriptions.get(&node_sub_info).unwrap().is_empty()
+            subscriptions.get(node_sub_info).is_none()
+                || subscriptions.get(node_sub_info).unwrap().is_empty()
         );
     }
 
@@ -772,7 +1408,7 @@ mod tests {
             empty_node_id,
         );
         let dispatch_result = subscription_data
-            .dispatch_to_subscribers(&empty_subscription_id, empty_node_id, &message)
+            .dispatch_to_subscribers(&empty_subscription_id, empty_node_id, &message, false)
             .await;
         assert!(dispatch_result.is_ok()); // Should succeed even though there are no subscribers
     }
@@ -819,8 +1455,94 @@ mod tests {
         ));
 
         let dispatch_result = subscription_data
-            .dispatch_to_subscribers(&nonexistent_subscription_id, nonexistent_node_id, &message)
+            .dispatch_to_subscribers(
+                &nonexistent_subscription_id,
+                nonexistent_node_id,
+                &message,
+                false,
+            )
             .await;
         assert!(dispatch_result.is_ok()); // Should succeed as it should handle subscriptions with no users gracefully
     }
+
+    #[tokio::test]
+    async fn test_shared_subscription() {
+        let (subscription_data, user_id, _) = setup_user_and_subscription_data();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let other_user_id = 101;
+        subscription_data.add_user(other_user_id, tx);
+
+        let subscription_request =
+            json!({"jsonrpc":"2.0","id": 2, "method": "eth_subscribe", "params": ["newHeads"]});
+        let node_sub_info = NodeSubInfo {
+            node_id: 1,
+            subscription_id: "200".to_string(),
+        };
+        subscription_data.register_subscription(
+            subscription_request.clone(),
+            node_sub_info.subscription_id.clone(),
+            node_sub_info.node_id,
+        );
+
+        // Both users get the same upstream subscription
+        for user in [user_id, other_user_id] {
+            assert_eq!(
+                subscription_data
+                    .subscribe_user(user, subscription_request.clone())
+                    .unwrap(),
+                "200"
+            );
+        }
+
+        // Still needed by the other user
+        assert_eq!(
+            subscription_data.unsubscribe_user(user_id, "200".to_string()),
+            None
+        );
+        assert_eq!(
+            subscription_data.get_users_for_subscription("200"),
+            vec![other_user_id]
+        );
+
+        // The last user leaving orphans it
+        assert_eq!(
+            subscription_data.remove_user(other_user_id),
+            vec![node_sub_info]
+        );
+        assert!(subscription_data.subscriptions.read().unwrap().is_empty());
+        assert!(subscription_data
+            .incoming_subscriptions
+            .read()
+            .unwrap()
+            .is_empty());
+        assert!(subscription_data
+            .subscribe_user(user_id, subscription_request)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lock_subscription() {
+        let subscription_data = SubscriptionData::new();
+        let subscription_request =
+            json!({"jsonrpc":"2.0","id": 2, "method": "eth_subscribe", "params": ["newHeads"]});
+
+        let guard = subscription_data
+            .lock_subscription(&subscription_request)
+            .await;
+
+        // Identical params have to wait, others don't
+        let waiting = tokio::time::timeout(
+            std::time::Duration::from_millis(50),
+            subscription_data.lock_subscription(&subscription_request),
+        )
+        .await;
+        assert!(waiting.is_err());
+        let _other = subscription_data
+            .lock_subscription(&json!({"params": ["logs"]}))
+            .await;
+
+        drop(guard);
+        drop(_other);
+        assert!(subscription_data.subscribing.lock().unwrap().is_empty());
+    }
 }